@@ -33,7 +33,7 @@ impl Arbitrary<'_> for ResponseAndValidPolicy {
         let body = <Vec<u8>>::arbitrary(raw)?;
         let status = {
             let status_code_as_u32: u32 =
-                raw.int_in_range(0..=StatusCode::InternalServerError as u32)?;
+                raw.int_in_range(0..=StatusCode::PolicyRequestSizeViolation as u32)?;
             StatusCode::from_repr(status_code_as_u32).unwrap()
         };
         let body_len = body.len();
@@ -0,0 +1,151 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use oak_channel::frame::{Flags, Framed, InvalidLength, BODY_OFFSET, MAX_BODY_SIZE, MAX_SIZE};
+
+/// A frame as the host may send it.
+#[derive(Arbitrary, Debug)]
+enum FrameSpec {
+    /// A well-formed frame; the body is cut short at `MAX_BODY_SIZE`.
+    Valid { flags: u16, body: Vec<u8> },
+    /// A frame header whose length prefix exceeds the maximum frame size.
+    Oversized { excess: u16, flags: u16 },
+    /// Arbitrary bytes, e.g. a header with a length prefix that's too small.
+    Raw(Vec<u8>),
+}
+
+impl FrameSpec {
+    fn encode(&self, data: &mut Vec<u8>) {
+        match self {
+            FrameSpec::Valid { flags, body } => {
+                let body = &body[..body.len().min(MAX_BODY_SIZE)];
+                encode_header(data, (BODY_OFFSET + body.len()) as u16, *flags);
+                data.extend_from_slice(body);
+            }
+            FrameSpec::Oversized { excess, flags } => {
+                let length = (MAX_SIZE as u16).saturating_add(1).saturating_add(*excess);
+                encode_header(data, length, *flags);
+            }
+            FrameSpec::Raw(bytes) => data.extend_from_slice(bytes),
+        }
+    }
+}
+
+fn encode_header(data: &mut Vec<u8>, length: u16, flags: u16) {
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&length.to_le_bytes());
+    data.extend_from_slice(&flags.to_le_bytes());
+}
+
+/// A stream of concatenated frames, possibly truncated.
+#[derive(Arbitrary, Debug)]
+struct Input {
+    frames: Vec<FrameSpec>,
+    /// The number of bytes to drop from the end of the stream.
+    truncate: u16,
+}
+
+/// What the decoder should make of the start of a stream.
+#[derive(Debug)]
+enum Expected<'a> {
+    /// A frame of `size` bytes in total.
+    Frame { flags: u16, body: &'a [u8], size: usize },
+    /// The stream ends before the frame is complete.
+    Incomplete,
+    /// The length prefix is out of range.
+    Rejected,
+}
+
+/// Independently parses the frame at the start of `data`, following
+/// `oak_channel/SPEC.md`.
+fn expected_frame(data: &[u8]) -> Expected<'_> {
+    // The decoder checks the length as soon as it has read it, before the flags.
+    if data.len() < 6 {
+        return Expected::Incomplete;
+    }
+    let length = u16::from_le_bytes([data[4], data[5]]) as usize;
+    if length <= BODY_OFFSET || length > MAX_SIZE {
+        return Expected::Rejected;
+    }
+    if data.len() < length {
+        return Expected::Incomplete;
+    }
+    Expected::Frame {
+        flags: u16::from_le_bytes([data[6], data[7]]),
+        body: &data[BODY_OFFSET..length],
+        size: length,
+    }
+}
+
+/// Whether the decoder rejected the frame header, rather than running out of
+/// input.
+fn is_rejection(err: &anyhow::Error) -> bool {
+    err.is::<InvalidLength>()
+}
+
+// This fuzz target checks that the frame decoder, which parses data controlled
+// by the host, never panics, never grows its buffer beyond the maximum frame
+// size, rejects out of range length prefixes and reports truncated frames as
+// errors rather than returning partial data.
+fuzz_target!(|input: Input| {
+    let mut data = Vec::new();
+    for frame in &input.frames {
+        frame.encode(&mut data);
+    }
+    data.truncate(data.len().saturating_sub(input.truncate as usize));
+
+    let mut framed = Framed::new(Box::new(Cursor::new(data.clone())));
+    let mut remaining = &data[..];
+    loop {
+        let mut buffer = BytesMut::with_capacity(MAX_BODY_SIZE);
+        let capacity = buffer.capacity();
+        let done = match (expected_frame(remaining), framed.read_frame(&mut buffer)) {
+            (Expected::Frame { flags, body, size }, Ok((frame, _))) => {
+                assert_eq!(frame.flags.bits(), Flags::from_bits_truncate(flags).bits());
+                assert_eq!(frame.body, body);
+                remaining = &remaining[size..];
+                false
+            }
+            (Expected::Incomplete, Err(err)) => {
+                assert!(!is_rejection(&err), "truncated frame was rejected: {:?}", err);
+                true
+            }
+            (Expected::Rejected, Err(err)) => {
+                assert!(is_rejection(&err), "invalid length wasn't rejected: {:?}", err);
+                true
+            }
+            (expected, result) => {
+                panic!(
+                    "expected {:?}, but the decoder returned {:?}",
+                    expected,
+                    result.map(|(frame, _)| frame)
+                )
+            }
+        };
+        assert!(
+            buffer.capacity() <= capacity,
+            "decoder grew the buffer beyond the maximum frame size"
+        );
+        if done {
+            break;
+        }
+    }
+});
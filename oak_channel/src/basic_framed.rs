@@ -64,6 +64,13 @@ pub fn receive_raw<C: Channel + ?Sized>(channel: &mut C) -> Result<vec::Vec<u8>>
 }
 
 pub fn send_raw<C: Channel + ?Sized>(channel: &mut C, payload: &[u8]) -> Result<()> {
+    if channel.max_message_size() < MAX_SIZE {
+        anyhow::bail!(
+            "channel only supports messages of up to {} bytes, but {} are required",
+            channel.max_message_size(),
+            MAX_SIZE
+        );
+    }
     channel
         .write_all(&(payload.len() as u32).to_le_bytes())
         .expect("failed to send application binary length to enclave");
@@ -21,8 +21,9 @@ use alloc::boxed::Box;
 use oak_core::timer::Timer;
 
 use crate::{
+    handshake,
     message::{InvocationId, RequestMessage, ResponseMessage},
-    Channel, InvocationChannel,
+    Channel, ChannelError, InvocationChannel,
 };
 
 pub struct ClientChannelHandle {
@@ -33,6 +34,17 @@ impl ClientChannelHandle {
     pub fn new(socket: Box<dyn Channel>) -> Self {
         Self { inner: InvocationChannel::new(socket) }
     }
+
+    /// Like [`ClientChannelHandle::new`], but first agrees on the protocol
+    /// version with the guest; see [`handshake::accept`].
+    pub fn with_handshake(mut socket: Box<dyn Channel>) -> Result<Self, ChannelError> {
+        handshake::accept(
+            socket.as_mut(),
+            handshake::PROTOCOL_VERSION..=handshake::PROTOCOL_VERSION,
+        )?;
+        Ok(Self::new(socket))
+    }
+
     pub fn write_request(&mut self, request: RequestMessage) -> anyhow::Result<()> {
         self.inner.write_message(request)
     }
@@ -81,6 +81,25 @@ impl Frame<'_> {
     }
 }
 
+/// Error returned by [`Framed::read_frame`] if the length prefix of a frame is
+/// out of range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidLength {
+    /// The frame is too small to hold the header.
+    TooSmall,
+    /// The frame exceeds [`MAX_SIZE`].
+    TooLarge,
+}
+
+impl core::fmt::Display for InvalidLength {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvalidLength::TooSmall => f.write_str("frame is too small"),
+            InvalidLength::TooLarge => f.write_str("frame exceeds the maximum frame size"),
+        }
+    }
+}
+
 pub struct Framed {
     inner: Box<dyn Channel>,
 }
@@ -109,10 +128,10 @@ impl Framed {
             self.inner.read_exact(&mut length_bytes)?;
             let length = Length::from_le_bytes(length_bytes).into();
             if length <= BODY_OFFSET {
-                return Err(anyhow::Error::msg("frame is too small"));
+                return Err(anyhow::Error::msg(InvalidLength::TooSmall));
             };
             if length > MAX_SIZE {
-                return Err(anyhow::Error::msg("frame exceeds the maximum frame size"));
+                return Err(anyhow::Error::msg(InvalidLength::TooLarge));
             };
             length
         };
@@ -135,7 +154,20 @@ impl Framed {
         Ok((Frame { flags, body }, timer))
     }
 
+    /// The maximum size of a frame body that can be sent over the underlying
+    /// channel.
+    pub fn max_body_size(&self) -> usize {
+        self.inner.max_message_size().saturating_sub(BODY_OFFSET).min(MAX_BODY_SIZE)
+    }
+
     pub fn write_frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        if frame.body.len() > self.max_body_size() {
+            anyhow::bail!(
+                "frame body of {} bytes exceeds the maximum of {} bytes supported by the channel",
+                frame.body.len(),
+                self.max_body_size()
+            );
+        }
         let channel: &mut dyn Channel = self.inner.borrow_mut();
         frame.write(channel)?;
         channel.flush()
@@ -143,12 +175,23 @@ impl Framed {
 }
 
 pub fn bytes_into_frames(data: &[u8]) -> anyhow::Result<Vec<Frame<'_>>> {
+    bytes_into_frames_with_max_body(data, MAX_BODY_SIZE)
+}
+
+/// Splits the data into frames with bodies of at most `max_body_size` bytes.
+pub fn bytes_into_frames_with_max_body(
+    data: &[u8],
+    max_body_size: usize,
+) -> anyhow::Result<Vec<Frame<'_>>> {
     if data.is_empty() {
         anyhow::bail!("cannot convert empty payloads into frames")
     }
+    if max_body_size == 0 || max_body_size > MAX_BODY_SIZE {
+        anyhow::bail!("invalid maximum frame body size: {}", max_body_size)
+    }
 
     let mut frames: Vec<Frame> = data
-        .chunks(MAX_BODY_SIZE)
+        .chunks(max_body_size)
         .map(|frame_body| Frame { flags: Flags::default(), body: frame_body })
         .collect();
 
@@ -0,0 +1,101 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Optional handshake that both ends of a channel run before any messages are
+//! exchanged, so that a guest and host built with different versions of the
+//! protocol notice the mismatch instead of misinterpreting each other's frames.
+//!
+//! The guest sends a hello consisting of [`HANDSHAKE_MAGIC`] followed by the
+//! protocol version it speaks. The host replies with the same magic, followed
+//! by that version if it supports it, or by [`INCOMPATIBLE_VERSION`] if not.
+//! All fields are little-endian `u32`-s.
+//!
+//! Whether a channel uses the handshake is configured out of band: as the
+//! peer's bytes would otherwise be read as frames, either both ends must run
+//! it or neither.
+
+use core::ops::RangeInclusive;
+
+use crate::{Channel, ChannelError};
+
+/// Marks the start of a handshake message ("OAKV").
+pub const HANDSHAKE_MAGIC: u32 = 0x4f41_4b56;
+
+/// The version of the framing protocol implemented by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Sent by the host in place of a version if it can't speak the guest's
+/// version.
+pub const INCOMPATIBLE_VERSION: u32 = 0;
+
+const HANDSHAKE_SIZE: usize = 8;
+
+fn encode(version: u32) -> [u8; HANDSHAKE_SIZE] {
+    let mut message = [0u8; HANDSHAKE_SIZE];
+    message[..4].copy_from_slice(&HANDSHAKE_MAGIC.to_le_bytes());
+    message[4..].copy_from_slice(&version.to_le_bytes());
+    message
+}
+
+fn send(channel: &mut dyn Channel, version: u32) -> Result<(), ChannelError> {
+    channel.write_all(&encode(version)).map_err(|_| ChannelError::Io)?;
+    channel.flush().map_err(|_| ChannelError::Io)
+}
+
+/// Reads a handshake message from the peer and returns the version in it.
+///
+/// Fails with [`ChannelError::VersionMismatch`] if the message doesn't start
+/// with [`HANDSHAKE_MAGIC`], as that means the peer doesn't do a handshake.
+fn receive(channel: &mut dyn Channel) -> Result<u32, ChannelError> {
+    let mut message = [0u8; HANDSHAKE_SIZE];
+    channel.read_exact(&mut message).map_err(|_| ChannelError::Io)?;
+    let (magic, version) = message.split_at(4);
+    if u32::from_le_bytes(magic.try_into().unwrap()) != HANDSHAKE_MAGIC {
+        return Err(ChannelError::VersionMismatch);
+    }
+    Ok(u32::from_le_bytes(version.try_into().unwrap()))
+}
+
+/// Runs the guest side of the handshake, proposing `version`.
+///
+/// Returns the agreed version, which is always `version`; if the host can't
+/// speak it, fails with [`ChannelError::VersionMismatch`] and the channel must
+/// not be used for anything else.
+pub fn negotiate(channel: &mut dyn Channel, version: u32) -> Result<u32, ChannelError> {
+    send(channel, version)?;
+    match receive(channel)? {
+        agreed if agreed == version => Ok(agreed),
+        _ => Err(ChannelError::VersionMismatch),
+    }
+}
+
+/// Runs the host side of the handshake, accepting any of the `supported`
+/// versions.
+///
+/// Returns the agreed version. If the guest's version is not supported, the
+/// guest is told so before this fails with [`ChannelError::VersionMismatch`].
+pub fn accept(
+    channel: &mut dyn Channel,
+    supported: RangeInclusive<u32>,
+) -> Result<u32, ChannelError> {
+    let proposed = receive(channel)?;
+    if proposed == INCOMPATIBLE_VERSION || !supported.contains(&proposed) {
+        send(channel, INCOMPATIBLE_VERSION)?;
+        return Err(ChannelError::VersionMismatch);
+    }
+    send(channel, proposed)?;
+    Ok(proposed)
+}
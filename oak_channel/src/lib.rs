@@ -25,7 +25,11 @@ extern crate std;
 pub mod client;
 
 pub mod basic_framed;
+#[cfg(feature = "fuzzing")]
+pub mod frame;
+#[cfg(not(feature = "fuzzing"))]
 mod frame;
+pub mod handshake;
 pub mod message;
 pub mod server;
 
@@ -42,8 +46,37 @@ use oak_core::timer::Timer;
 /// Simple no_std compatible equivalent of [`std::io::Read`].
 ///
 /// [`std::io::Read`]: <https://doc.rust-lang.org/std/io/trait.Read.html>
+///
+/// If the peer closed the channel cleanly before any of the requested data
+/// arrived, `read_exact` fails with [`PeerClosed`] (see [`is_peer_closed`]), so
+/// that callers can tell the end of the stream apart from other errors. If the
+/// peer goes away part way through a read, the partial data is lost and the
+/// read fails with a different error.
 pub trait Read {
     fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()>;
+
+    /// Whether the peer has closed the channel, so no more data will arrive.
+    ///
+    /// Defaults to `false`, for implementations that can't detect this.
+    fn peer_closed(&self) -> bool {
+        false
+    }
+}
+
+/// Error returned by [`Read::read_exact`] if the peer closed the channel before
+/// any of the requested data arrived.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerClosed;
+
+impl core::fmt::Display for PeerClosed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("the peer closed the channel")
+    }
+}
+
+/// Whether `err` signals a clean close of the channel by the peer.
+pub fn is_peer_closed(err: &anyhow::Error) -> bool {
+    err.is::<PeerClosed>()
 }
 
 #[cfg(feature = "std")]
@@ -59,6 +92,51 @@ impl<T: std::io::Read> Read for T {
 pub trait Write {
     fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()>;
     fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// The maximum number of bytes that can be written in a single call to
+    /// `write_all`.
+    ///
+    /// This is a limit of the transport, such as the size of a shared buffer or
+    /// of the peer's receive buffer, not the space currently available:
+    /// flow-controlled implementations (e.g. vsock) wait for the peer instead.
+    /// Defaults to `usize::MAX`, for implementations that don't have a limit.
+    fn max_write_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Performs a device-specific operation, identified by `request`, on the
+    /// underlying transport.
+    ///
+    /// Defaults to rejecting all requests, for implementations that don't
+    /// have any such operations.
+    fn control(&mut self, _request: u64, _arg: usize) -> Result<usize, ChannelError> {
+        Err(ChannelError::Unsupported)
+    }
+}
+
+/// Reasons why a device-specific operation on a channel failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChannelError {
+    /// The channel doesn't know about the request.
+    Unsupported,
+    /// The request is known, but the argument is not valid for it.
+    InvalidArgument,
+    /// The request was valid, but the transport failed to carry it out.
+    Io,
+    /// The peer speaks a protocol version that we don't, so the channel can't
+    /// be used; see [`handshake`].
+    VersionMismatch,
+}
+
+impl core::fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChannelError::Unsupported => f.write_str("unsupported channel request"),
+            ChannelError::InvalidArgument => f.write_str("invalid channel request argument"),
+            ChannelError::Io => f.write_str("channel transport failed"),
+            ChannelError::VersionMismatch => f.write_str("channel protocol version mismatch"),
+        }
+    }
 }
 
 #[cfg(feature = "std")]
@@ -72,9 +150,69 @@ impl<T: std::io::Write> Write for T {
     }
 }
 
-pub trait Channel: Read + Write + Send + Sync {}
+pub trait Channel: Read + Write + Send + Sync {
+    /// The maximum size of a single message (e.g. a frame) that can be sent
+    /// over the channel, as configured for the underlying transport.
+    ///
+    /// Frames are never larger than `frame::MAX_SIZE`, so channels with a
+    /// larger (or no) limit always carry full-sized frames.
+    fn max_message_size(&self) -> usize;
+
+    /// Performs a device-specific operation, such as changing a keepalive
+    /// interval or reconnecting; see [`Write::control`].
+    fn ioctl(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError>;
+}
+
+impl<T: Read + Write + Send + Sync> Channel for T {
+    fn max_message_size(&self) -> usize {
+        self.max_write_size()
+    }
+
+    fn ioctl(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError> {
+        self.control(request, arg)
+    }
+}
+
+/// Adapts a channel for formatted output, so that text can be written to it
+/// directly with `write!` and `writeln!` rather than through the `log` facade.
+///
+/// Text is sent as-is, without any framing, split into pieces that fit within
+/// [`Write::max_write_size`]. As [`core::fmt::Error`] can't carry any details,
+/// the error of a failed write is kept until it is retrieved with
+/// [`ChannelWriter::take_error`].
+pub struct ChannelWriter<'a> {
+    channel: &'a mut dyn Channel,
+    error: Option<anyhow::Error>,
+}
 
-impl<T: Read + Write + Send + Sync> Channel for T {}
+impl<'a> ChannelWriter<'a> {
+    pub fn new(channel: &'a mut dyn Channel) -> Self {
+        Self { channel, error: None }
+    }
+
+    /// Returns the error that made the most recent failed write fail, if any.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    /// Flushes the underlying channel.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.channel.flush()
+    }
+}
+
+impl core::fmt::Write for ChannelWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let max_write_size = self.channel.max_write_size().max(1);
+        for chunk in s.as_bytes().chunks(max_write_size) {
+            if let Err(err) = self.channel.write_all(chunk) {
+                self.error = Some(err);
+                return Err(core::fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
 
 struct InvocationChannel {
     inner: frame::Framed,
@@ -117,7 +255,9 @@ impl InvocationChannel {
         // This likely causes a copy of the pre-existing data, but we needed to read the
         // first frame to figure out how much space we need for the entire
         // message. No more resizes are going to happen from here.
-        message_buffer.reserve(message_length - frame::MAX_BODY_SIZE);
+        // The sender may have used frames smaller than the maximum if its channel
+        // required it.
+        message_buffer.reserve(message_length.saturating_sub(message_buffer.len()));
 
         loop {
             let (frame, _) =
@@ -137,7 +277,15 @@ impl InvocationChannel {
 
     pub fn write_message<M: message::Message>(&mut self, message: M) -> anyhow::Result<()> {
         let encoded_data = message.encode();
-        let frames: Vec<frame::Frame> = frame::bytes_into_frames(&encoded_data[..])?;
+        let max_body_size = self.inner.max_body_size();
+        if max_body_size < message::LENGTH_OFFSET + message::LENGTH_SIZE {
+            anyhow::bail!(
+                "channel can't fit the message length in a frame (max frame body: {} bytes)",
+                max_body_size
+            );
+        }
+        let frames: Vec<frame::Frame> =
+            frame::bytes_into_frames_with_max_body(&encoded_data[..], max_body_size)?;
         for frame in frames.into_iter() {
             self.inner.write_frame(frame).context("couldn't write frame")?
         }
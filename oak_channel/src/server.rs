@@ -18,7 +18,7 @@ use alloc::boxed::Box;
 
 use oak_core::timer::Timer;
 
-use crate::{message, Channel, InvocationChannel};
+use crate::{handshake, message, Channel, ChannelError, InvocationChannel};
 
 pub struct ServerChannelHandle {
     inner: InvocationChannel,
@@ -28,6 +28,14 @@ impl ServerChannelHandle {
     pub fn new(socket: Box<dyn Channel>) -> Self {
         Self { inner: InvocationChannel::new(socket) }
     }
+
+    /// Like [`ServerChannelHandle::new`], but first negotiates the protocol
+    /// version with the host; see [`handshake::negotiate`].
+    pub fn with_handshake(mut socket: Box<dyn Channel>) -> Result<Self, ChannelError> {
+        handshake::negotiate(socket.as_mut(), handshake::PROTOCOL_VERSION)?;
+        Ok(Self::new(socket))
+    }
+
     pub fn read_request(&mut self) -> anyhow::Result<(message::RequestMessage, Timer)> {
         self.inner.read_message()
     }
@@ -131,3 +131,223 @@ fn test_invocation_channel_expected_start_frame() {
 
     invocation_channel.read_message::<message::RequestMessage>().unwrap_err();
 }
+
+/// Message store that only accepts writes of up to `limit` bytes.
+struct LimitedMessageStore {
+    inner: MessageStore,
+    limit: usize,
+}
+
+impl LimitedMessageStore {
+    fn new(limit: usize) -> Self {
+        Self { inner: MessageStore::default(), limit }
+    }
+}
+
+impl Read for LimitedMessageStore {
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.inner.read_exact(buf)
+    }
+}
+
+impl Write for LimitedMessageStore {
+    fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.len() > self.limit {
+            anyhow::bail!("write too large");
+        }
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn max_write_size(&self) -> usize {
+        self.limit
+    }
+}
+
+#[test]
+fn test_max_message_size() {
+    assert_eq!(MessageStore::default().max_message_size(), usize::MAX);
+    assert_eq!(LimitedMessageStore::new(512).max_message_size(), 512);
+    assert_eq!(
+        frame::Framed::new(Box::new(MessageStore::default())).max_body_size(),
+        frame::MAX_BODY_SIZE
+    );
+    assert_eq!(
+        frame::Framed::new(Box::new(LimitedMessageStore::new(512))).max_body_size(),
+        512 - frame::BODY_OFFSET
+    );
+}
+
+#[test]
+fn test_framed_rejects_oversized_frame() {
+    let message = message::RequestMessage { invocation_id: 0, body: mock_payload() }.encode();
+    let start_frame = frame::bytes_into_frames(&message).unwrap().first().unwrap().clone();
+    let mut frame_store = frame::Framed::new(Box::new(LimitedMessageStore::new(512)));
+    assert!(frame_store.write_frame(start_frame).is_err());
+}
+
+#[test]
+fn test_invocation_channel_splits_for_small_channel() {
+    let mut invocation_channel = InvocationChannel::new(Box::new(LimitedMessageStore::new(512)));
+
+    let message = message::RequestMessage { invocation_id: 4, body: mock_payload() };
+
+    invocation_channel.write_message(message.clone()).unwrap();
+
+    let (reconstructed_message, _): (RequestMessage, _) =
+        invocation_channel.read_message().unwrap();
+    assert_eq!(message, reconstructed_message);
+}
+
+#[test]
+fn test_invocation_channel_rejects_tiny_channel() {
+    let mut invocation_channel =
+        InvocationChannel::new(Box::new(LimitedMessageStore::new(frame::BODY_OFFSET + 1)));
+    let message = message::RequestMessage { invocation_id: 4, body: mock_payload() };
+    assert!(invocation_channel.write_message(message).is_err());
+}
+
+/// A channel whose peer has already gone away.
+struct ClosedChannel;
+
+impl Read for ClosedChannel {
+    fn read_exact(&mut self, _buf: &mut [u8]) -> anyhow::Result<()> {
+        Err(anyhow::Error::msg(PeerClosed))
+    }
+
+    fn peer_closed(&self) -> bool {
+        true
+    }
+}
+
+impl Write for ClosedChannel {
+    fn write_all(&mut self, _buf: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_read_message_after_peer_closed() {
+    let channel: Box<dyn Channel> = Box::new(ClosedChannel);
+    assert!(channel.peer_closed());
+    let mut invocation_channel = InvocationChannel::new(channel);
+    let err = invocation_channel.read_message::<RequestMessage>().unwrap_err();
+    // The end of the stream is still recognisable through the added context.
+    assert!(is_peer_closed(&err));
+    assert!(!is_peer_closed(&anyhow::anyhow!("some other failure")));
+    assert!(!MessageStore::default().peer_closed());
+}
+
+#[test]
+fn test_channel_writer_formats_output() {
+    use core::fmt::Write as _;
+
+    let mut channel = MessageStore::default();
+    let mut writer = ChannelWriter::new(&mut channel);
+    writeln!(writer, "free frames: {}/{}", 7, 16).unwrap();
+    write!(writer, "{:#06x}", 0x2a).unwrap();
+    writer.flush().unwrap();
+    assert!(writer.take_error().is_none());
+    assert_eq!(Vec::from(channel.inner), b"free frames: 7/16\n0x002a".to_vec());
+}
+
+#[test]
+fn test_channel_writer_splits_for_small_channel() {
+    use core::fmt::Write as _;
+
+    let mut channel = LimitedMessageStore::new(4);
+    writeln!(ChannelWriter::new(&mut channel), "longer than four bytes").unwrap();
+    assert_eq!(Vec::from(channel.inner.inner), b"longer than four bytes\n".to_vec());
+}
+
+#[test]
+fn test_channel_writer_surfaces_errors() {
+    use core::fmt::Write as _;
+
+    // A write limit of zero makes every write fail.
+    let mut channel = LimitedMessageStore::new(0);
+    let mut writer = ChannelWriter::new(&mut channel);
+    assert!(writeln!(writer, "lost").is_err());
+    assert_eq!(writer.take_error().unwrap().to_string(), "write too large");
+    assert!(writer.take_error().is_none());
+}
+
+/// Transport that hands out a scripted reply from the peer, and records what
+/// was sent to it.
+struct ScriptedPeer {
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl ScriptedPeer {
+    fn new(incoming: &[u8]) -> Self {
+        Self { incoming: incoming.iter().copied().collect(), outgoing: Vec::new() }
+    }
+}
+
+impl Read for ScriptedPeer {
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        if self.incoming.len() < buf.len() {
+            anyhow::bail!("not enough data");
+        }
+        buf.fill_with(|| self.incoming.pop_front().unwrap());
+        Ok(())
+    }
+}
+
+impl Write for ScriptedPeer {
+    fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+fn handshake_message(magic: u32, version: u32) -> Vec<u8> {
+    [magic.to_le_bytes(), version.to_le_bytes()].concat()
+}
+
+#[test]
+fn test_handshake_matching_versions() {
+    let mut guest = ScriptedPeer::new(&handshake_message(handshake::HANDSHAKE_MAGIC, 3));
+    assert_eq!(handshake::negotiate(&mut guest, 3), Ok(3));
+    assert_eq!(guest.outgoing, handshake_message(handshake::HANDSHAKE_MAGIC, 3));
+
+    let mut host = ScriptedPeer::new(&guest.outgoing);
+    assert_eq!(handshake::accept(&mut host, 1..=3), Ok(3));
+    assert_eq!(host.outgoing, handshake_message(handshake::HANDSHAKE_MAGIC, 3));
+}
+
+#[test]
+fn test_handshake_mismatching_versions() {
+    let mut host = ScriptedPeer::new(&handshake_message(handshake::HANDSHAKE_MAGIC, 2));
+    assert_eq!(handshake::accept(&mut host, 1..=1), Err(ChannelError::VersionMismatch));
+    assert_eq!(
+        host.outgoing,
+        handshake_message(handshake::HANDSHAKE_MAGIC, handshake::INCOMPATIBLE_VERSION)
+    );
+
+    let mut guest = ScriptedPeer::new(&host.outgoing);
+    assert_eq!(handshake::negotiate(&mut guest, 2), Err(ChannelError::VersionMismatch));
+}
+
+#[test]
+fn test_handshake_peer_without_handshake() {
+    // A peer that doesn't do the handshake starts sending frames straight away.
+    let mut guest = ScriptedPeer::new(&handshake_message(0x1234_5678, 1));
+    assert_eq!(handshake::negotiate(&mut guest, 1), Err(ChannelError::VersionMismatch));
+
+    // And one that doesn't reply at all leaves us with an I/O error.
+    let mut guest = ScriptedPeer::new(&[]);
+    assert_eq!(handshake::negotiate(&mut guest, 1), Err(ChannelError::Io));
+}
@@ -49,6 +49,7 @@ pub enum StatusCode {
     PolicySizeViolation = 3,
     PolicyTimeViolation = 4,
     InternalServerError = 5,
+    PolicyRequestSizeViolation = 6,
 }
 
 // As defined in REQUEST_RESPONSE_ENCODING.MD in the crate root.
@@ -65,6 +66,11 @@ static_assertions::assert_eq_size!([u8; RESPONSE_LENGTH_SIZE], ResponseLength);
 // As defined in REQUEST_RESPONSE_ENCODING.MD in the crate root.
 pub const RESPONSE_BODY_OFFSET: usize = RESPONSE_STATUS_CODE_SIZE + RESPONSE_LENGTH_SIZE;
 
+// As defined in REQUEST_RESPONSE_ENCODING.MD in the crate root.
+const BATCH_COUNT_SIZE: usize = 8;
+const BATCH_ITEM_SIZE_SIZE: usize = 8;
+const BATCH_ITEMS_OFFSET: usize = BATCH_COUNT_SIZE + BATCH_ITEM_SIZE_SIZE;
+
 impl Response {
     /// Creates a new instance of Response.
     ///
@@ -74,6 +80,29 @@ impl Response {
         Response { status, body: body.clone(), length: body.len() as u64 }
     }
 
+    /// Creates a new instance of Response with an explicit effective length.
+    ///
+    /// Returns an error if `length` is larger than the length of `body`, as
+    /// that would cause readers of the response to over-read the body.
+    pub fn with_length(status: StatusCode, body: Vec<u8>, length: u64) -> anyhow::Result<Self> {
+        let response = Response { status, body, length };
+        response.validate()?;
+        Ok(response)
+    }
+
+    /// Checks that the effective length of the response does not exceed the
+    /// length of the body.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.length > self.body.len() as u64 {
+            anyhow::bail!(
+                "response length {} is larger than the body length {}",
+                self.length,
+                self.body.len()
+            );
+        }
+        Ok(())
+    }
+
     /// Returns the body of the response, excluding any trailing 0s.
     ///
     /// Uses the effective length of the body, in `self.length`, to remove the
@@ -134,8 +163,123 @@ impl Response {
 
         body.extend_from_slice(&bytes[RESPONSE_BODY_OFFSET..bytes.len()]);
 
-        Ok(Self { status, body, length })
+        Self::with_length(status, body, length)
+    }
+}
+
+/// Creates the response to send back to the client, applying the constant
+/// response size policy to it.
+///
+/// The `length` of the incoming response is not trusted: the body of the
+/// response is padded to `constant_response_size_bytes`, and the effective
+/// length of the result is recomputed from the body. If the body is larger
+/// than allowed by the policy, the result is an empty response with
+/// [`StatusCode::PolicySizeViolation`] instead.
+pub fn create_response_and_apply_policy(
+    response: Response,
+    constant_response_size_bytes: usize,
+) -> Response {
+    let response = Response::create(response.status, response.body);
+    response.pad(constant_response_size_bytes).unwrap_or_else(|_| Response {
+        status: StatusCode::PolicySizeViolation,
+        body: alloc::vec![0; constant_response_size_bytes],
+        length: 0,
+    })
+}
+
+/// Checks the size of a request against the request size policy, before the
+/// request is processed.
+///
+/// A `max_request_size_bytes` of 0 means that there is no limit. Requests that
+/// are larger than the limit are rejected with an empty response with
+/// [`StatusCode::PolicyRequestSizeViolation`], which is padded to
+/// `constant_response_size_bytes` like every other response.
+pub fn check_request_size(
+    request: &[u8],
+    max_request_size_bytes: usize,
+    constant_response_size_bytes: usize,
+) -> Result<(), Response> {
+    if max_request_size_bytes == 0 || request.len() <= max_request_size_bytes {
+        return Ok(());
+    }
+    Err(Response {
+        status: StatusCode::PolicyRequestSizeViolation,
+        body: alloc::vec![0; constant_response_size_bytes],
+        length: 0,
+    })
+}
+
+/// The size policy applied to batch responses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchResponsePolicy {
+    /// The size every response body in the batch is padded to.
+    pub item_size_bytes: usize,
+    /// The body of the batch response is padded to a multiple of this size.
+    pub constant_response_size_bytes: usize,
+}
+
+/// Creates a single response to send back to the client that contains all of
+/// `responses`, applying the size policy to it.
+///
+/// Each response in the batch is padded to `policy.item_size_bytes`, as in
+/// [`create_response_and_apply_policy`], so that individual response sizes are
+/// not leaked. The batch as a whole is then padded to a multiple of
+/// `policy.constant_response_size_bytes`, rather than padding every response
+/// to the full constant size. Use [`split_batch_response`] to recover the
+/// individual responses.
+pub fn create_batch_response_and_apply_policy(
+    responses: Vec<Response>,
+    policy: BatchResponsePolicy,
+) -> Response {
+    let item_encoded_size = RESPONSE_BODY_OFFSET + policy.item_size_bytes;
+    let mut body = Vec::with_capacity(BATCH_ITEMS_OFFSET + responses.len() * item_encoded_size);
+    body.extend_from_slice(&(responses.len() as u64).to_le_bytes());
+    body.extend_from_slice(&(policy.item_size_bytes as u64).to_le_bytes());
+    for response in responses {
+        body.extend(
+            create_response_and_apply_policy(response, policy.item_size_bytes).encode_to_vec(),
+        );
+    }
+    let length = body.len() as u64;
+    let padded_size = body
+        .len()
+        .checked_next_multiple_of(policy.constant_response_size_bytes)
+        .unwrap_or(body.len());
+    body.resize(padded_size, 0);
+    Response { status: StatusCode::Success, body, length }
+}
+
+/// Splits a response created by [`create_batch_response_and_apply_policy`]
+/// back into the individual responses.
+///
+/// The individual responses keep the padding of their bodies.
+pub fn split_batch_response(response: &Response) -> anyhow::Result<Vec<Response>> {
+    let body =
+        response.body().map_err(|_| anyhow::Error::msg("batch response length overflows"))?;
+    if body.len() < BATCH_ITEMS_OFFSET {
+        anyhow::bail!("batch response is too short: {} bytes", body.len());
+    }
+    let read_u64 = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&body[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let count = read_u64(0);
+    let item_size = usize::try_from(read_u64(BATCH_COUNT_SIZE))
+        .map_err(|_| anyhow::Error::msg("batch item size overflows"))?;
+    let items = &body[BATCH_ITEMS_OFFSET..];
+    let item_encoded_size = item_size
+        .checked_add(RESPONSE_BODY_OFFSET)
+        .ok_or_else(|| anyhow::Error::msg("batch item size overflows"))?;
+    if (items.len() / item_encoded_size) as u64 != count || items.len() % item_encoded_size != 0 {
+        anyhow::bail!(
+            "batch response length {} does not match {} items of {} bytes",
+            items.len(),
+            count,
+            item_encoded_size
+        );
     }
+    items.chunks_exact(item_encoded_size).map(Response::decode).collect()
 }
 
 // The Oak-Functions ABI primarily consists of a collection of Wasm host
@@ -152,3 +296,136 @@ extern "C" {
         response_len_ptr: *mut usize,
     ) -> u32;
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn with_length_rejects_overlong_length() {
+        assert!(Response::with_length(StatusCode::Success, vec![1, 2, 3], 3).is_ok());
+        assert!(Response::with_length(StatusCode::Success, vec![1, 2, 0], 2).is_ok());
+        assert!(Response::with_length(StatusCode::Success, vec![1, 2, 3], 4).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_overlong_length() {
+        let mut encoded = Response::create(StatusCode::Success, vec![1, 2, 3]).encode_to_vec();
+        encoded[RESPONSE_LENGTH_OFFSET..RESPONSE_LENGTH_OFFSET + RESPONSE_LENGTH_SIZE]
+            .copy_from_slice(&100u64.to_le_bytes());
+        assert!(Response::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn apply_policy_recomputes_length() {
+        let response = Response { status: StatusCode::Success, body: vec![1, 2, 3], length: 1000 };
+
+        let response = create_response_and_apply_policy(response, 10);
+
+        assert_eq!(response.status, StatusCode::Success);
+        assert_eq!(response.body.len(), 10);
+        assert_eq!(response.length, 3);
+        assert!(response.validate().is_ok());
+        assert_eq!(response.body().unwrap(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn apply_policy_size_violation() {
+        let response = Response::create(StatusCode::Success, vec![1; 20]);
+
+        let response = create_response_and_apply_policy(response, 10);
+
+        assert_eq!(response.status, StatusCode::PolicySizeViolation);
+        assert_eq!(response.body.len(), 10);
+        assert_eq!(response.length, 0);
+    }
+
+    #[test]
+    fn request_size_within_limit() {
+        assert_eq!(check_request_size(&[1; 9], 10, 64), Ok(()));
+        assert_eq!(check_request_size(&[1; 10], 10, 64), Ok(()));
+    }
+
+    #[test]
+    fn request_size_over_limit() {
+        let response = check_request_size(&[1; 11], 10, 64).unwrap_err();
+
+        assert_eq!(response.status, StatusCode::PolicyRequestSizeViolation);
+        assert_eq!(response.body.len(), 64);
+        assert_eq!(response.length, 0);
+        // The rejection is indistinguishable by size from any other response.
+        let normal = create_response_and_apply_policy(
+            Response::create(StatusCode::Success, vec![7; 40]),
+            64,
+        );
+        assert_eq!(response.encode_to_vec().len(), normal.encode_to_vec().len());
+    }
+
+    #[test]
+    fn request_size_unlimited() {
+        assert_eq!(check_request_size(&[1; 1000], 0, 64), Ok(()));
+    }
+
+    const BATCH_POLICY: BatchResponsePolicy =
+        BatchResponsePolicy { item_size_bytes: 10, constant_response_size_bytes: 64 };
+
+    #[test]
+    fn batch_of_one() {
+        let response = Response::create(StatusCode::Success, vec![1, 2, 3]);
+
+        let batch = create_batch_response_and_apply_policy(vec![response.clone()], BATCH_POLICY);
+
+        assert_eq!(batch.status, StatusCode::Success);
+        assert_eq!(batch.body.len(), 64);
+        assert_eq!(batch.length as usize, BATCH_ITEMS_OFFSET + RESPONSE_BODY_OFFSET + 10);
+        let items = split_batch_response(&batch).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].status, StatusCode::Success);
+        assert_eq!(items[0].body.len(), 10);
+        assert_eq!(items[0].body().unwrap(), response.body().unwrap());
+    }
+
+    #[test]
+    fn batch_of_three() {
+        let responses = vec![
+            Response::create(StatusCode::Success, vec![1]),
+            Response::create(StatusCode::BadRequest, vec![2; 10]),
+            // Too big for the per-item size.
+            Response::create(StatusCode::Success, vec![3; 11]),
+        ];
+
+        let batch = create_batch_response_and_apply_policy(responses, BATCH_POLICY);
+
+        // 16 bytes of header and 3 * 22 bytes of items, padded to a multiple of 64.
+        assert_eq!(batch.body.len(), 128);
+        let items = split_batch_response(&batch).unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(items.iter().all(|item| item.body.len() == 10));
+        assert_eq!(items[0].body().unwrap(), &[1]);
+        assert_eq!(items[1].status, StatusCode::BadRequest);
+        assert_eq!(items[1].body().unwrap(), &[2; 10]);
+        assert_eq!(items[2].status, StatusCode::PolicySizeViolation);
+        assert_eq!(items[2].length, 0);
+    }
+
+    #[test]
+    fn empty_batch() {
+        let batch = create_batch_response_and_apply_policy(vec![], BATCH_POLICY);
+
+        assert_eq!(batch.body.len(), 64);
+        assert_eq!(batch.length as usize, BATCH_ITEMS_OFFSET);
+        assert!(split_batch_response(&batch).unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_rejects_truncated_batch() {
+        let mut batch = create_batch_response_and_apply_policy(
+            vec![Response::create(StatusCode::Success, vec![1])],
+            BATCH_POLICY,
+        );
+        batch.length -= 1;
+        assert!(split_batch_response(&batch).is_err());
+    }
+}
@@ -35,6 +35,7 @@ use oak_functions_service::{
         InitializeRequest, InitializeResponse, InvokeRequest, InvokeResponse, LookupDataChunk,
         ReserveRequest, ReserveResponse,
     },
+    response_cache::{ResponseCacheConfig, StdClock},
     Handler, Observer,
 };
 use opentelemetry::{
@@ -122,12 +123,20 @@ where
         match self.instance.get() {
             Some(_) => Err(tonic::Status::failed_precondition("already initialized")),
             None => {
-                let instance = OakFunctionsInstance::new(
+                let mut instance = OakFunctionsInstance::new(
                     &request,
                     self.observer.clone(),
                     self.instance_config.clone(),
                 )
                 .map_err(map_status)?;
+                if let Some(config) =
+                    ResponseCacheConfig::from_initialize_request(&request).map_err(map_status)?
+                {
+                    instance = instance.with_response_cache(config, Arc::new(StdClock::default()));
+                }
+                if let Some(policy) = request.server_policy.clone() {
+                    instance = instance.with_server_policy(policy, Arc::new(StdClock::default()));
+                }
                 if self.instance.set(instance).is_err() {
                     return Err(tonic::Status::failed_precondition("already initialized"));
                 }
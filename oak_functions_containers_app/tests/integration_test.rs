@@ -75,6 +75,7 @@ async fn test_lookup() {
         .initialize(InitializeRequest {
             constant_response_size: 1000,
             wasm_module: fs::read(wasm_path).expect("failed to read wasm module"),
+            ..Default::default()
         })
         .await
         .expect("failed to initialize Oak Functions");
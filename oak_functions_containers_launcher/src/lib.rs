@@ -20,6 +20,7 @@ pub mod proto {
             pub mod config {
                 tonic::include_proto!("oak.functions.config");
             }
+            pub use oak_proto_rust::oak::oak_functions::abi;
         }
         pub use oak_proto_rust::oak::{attestation, crypto};
     }
@@ -74,6 +74,14 @@ async fn main() -> Result<(), anyhow::Error> {
         .initialize_enclave(InitializeRequest {
             wasm_module: wasm_bytes,
             constant_response_size: args.functions_args.constant_response_size,
+            response_cache_capacity: args.functions_args.response_cache_capacity,
+            response_cache_processing_time_ms: args
+                .functions_args
+                .response_cache_processing_time_ms,
+            server_policy: None,
+            response_cache_min_processing_time_ms: args
+                .functions_args
+                .response_cache_min_processing_time_ms,
         })
         .await
         .map_err(|error| {
@@ -32,6 +32,7 @@ use oak_functions_service::{
         FinishNextLookupDataResponse, InitializeRequest, InitializeResponse, InvokeRequest,
         InvokeResponse, LookupDataChunk, OakFunctions, ReserveRequest, ReserveResponse,
     },
+    response_cache::{Clock, ResponseCacheConfig},
     Handler, Observer,
 };
 use prost::Message;
@@ -79,6 +80,32 @@ where
     }
 }
 
+/// [`Clock`] based on the TSC, using the time the kernel shares with the
+/// application.
+struct TscClock;
+
+impl TscClock {
+    /// Returns the clock, or an error if the kernel doesn't know the TSC
+    /// frequency, as the processing time floors couldn't be enforced then.
+    fn new() -> Result<Self, micro_rpc::Status> {
+        match oak_restricted_kernel_sdk::utils::time_since_boot() {
+            Some(_) => Ok(Self),
+            None => Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "no clock available: the TSC frequency is unknown",
+            )),
+        }
+    }
+}
+
+impl Clock for TscClock {
+    fn now(&self) -> core::time::Duration {
+        // The TSC frequency doesn't change once the kernel has determined it, which
+        // `new` checked.
+        oak_restricted_kernel_sdk::utils::time_since_boot().expect("TSC frequency is unknown")
+    }
+}
+
 impl<EKH, EP, H> OakFunctions for OakFunctionsService<EKH, EP, H>
 where
     EKH: EncryptionKeyHandle + 'static,
@@ -96,11 +123,17 @@ where
                 "already initialized",
             )),
             None => {
-                let instance = OakFunctionsInstance::new(
+                let mut instance = OakFunctionsInstance::new(
                     &request,
                     self.observer.clone(),
                     self.instance_config.clone(),
                 )?;
+                if let Some(config) = ResponseCacheConfig::from_initialize_request(&request)? {
+                    instance = instance.with_response_cache(config, Arc::new(TscClock::new()?));
+                }
+                if let Some(policy) = request.server_policy.clone() {
+                    instance = instance.with_server_policy(policy, Arc::new(TscClock::new()?));
+                }
                 if self.instance.set(instance).is_err() {
                     return Err(micro_rpc::Status::new_with_message(
                         micro_rpc::StatusCode::FailedPrecondition,
@@ -96,6 +96,7 @@ fn it_should_handle_user_requests_after_initialization() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -130,6 +131,7 @@ fn it_should_only_initialize_once() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
     client.initialize(&request).into_ok().unwrap();
 
@@ -152,6 +154,7 @@ fn it_should_error_on_invalid_wasm_module() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -217,6 +220,7 @@ fn it_should_support_lookup_data() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -290,6 +294,7 @@ fn it_should_handle_wasm_panic() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -91,6 +91,9 @@ fn run_bench(b: &mut Bencher, config: &OakFunctionsTestConfig) {
             lookup_data_config,
             config.wasm_path.to_path_buf(),
             constant_response_size,
+            0,
+            0,
+            0,
         ))
         .expect("Failed to create launcher");
     log::info!("created launcher instance");
@@ -27,6 +27,7 @@ pub mod proto {
             #![allow(dead_code)]
             use prost::Message;
             include!(concat!(env!("OUT_DIR"), "/oak.functions.rs"));
+            pub use oak_proto_rust::oak::oak_functions::abi;
         }
         pub use oak_proto_rust::oak::{attestation, crypto};
         pub mod session {
@@ -60,6 +61,23 @@ pub struct Args {
     #[arg(long, default_value = "1024")]
     pub constant_response_size: u32,
 
+    /// Maximum number of responses to repeated requests the enclave should
+    /// cache; zero disables the response cache
+    #[arg(long, default_value = "0")]
+    pub response_cache_capacity: u32,
+
+    /// Time in milliseconds it should take the enclave to serve every request
+    /// while the response cache is enabled
+    #[arg(long, default_value = "0")]
+    pub response_cache_processing_time_ms: u32,
+
+    /// Minimum time in milliseconds it should take the enclave to serve every
+    /// request while the response cache is enabled; slower requests are
+    /// answered as soon as they are done. Mutually exclusive with
+    /// --response-cache-processing-time-ms
+    #[arg(long, default_value = "0", conflicts_with = "response_cache_processing_time_ms")]
+    pub response_cache_min_processing_time_ms: u32,
+
     #[arg(long, default_value = "8080")]
     pub port: u16,
 
@@ -100,14 +118,24 @@ pub async fn create(
     lookup_data_config: LookupDataConfig,
     wasm_path: PathBuf,
     constant_response_size: u32,
+    response_cache_capacity: u32,
+    response_cache_processing_time_ms: u32,
+    response_cache_min_processing_time_ms: u32,
 ) -> Result<
     (Box<dyn launcher::GuestInstance>, channel::ConnectorHandle, InitializeResponse),
     Box<dyn std::error::Error>,
 > {
     log::info!("creating Oak Functions guest instance");
     let (launched_instance, connector_handle) = launcher::launch(params).await?;
-    let intialize_response =
-        intialize_enclave(connector_handle.clone(), &wasm_path, constant_response_size).await?;
+    let intialize_response = intialize_enclave(
+        connector_handle.clone(),
+        &wasm_path,
+        constant_response_size,
+        response_cache_capacity,
+        response_cache_processing_time_ms,
+        response_cache_min_processing_time_ms,
+    )
+    .await?;
     setup_lookup_data(connector_handle.clone(), lookup_data_config).await?;
     Ok((launched_instance, connector_handle, intialize_response))
 }
@@ -166,6 +194,9 @@ async fn intialize_enclave(
     connector_handle: channel::ConnectorHandle,
     wasm: &PathBuf,
     constant_response_size: u32,
+    response_cache_capacity: u32,
+    response_cache_processing_time_ms: u32,
+    response_cache_min_processing_time_ms: u32,
 ) -> Result<InitializeResponse, Box<dyn std::error::Error>> {
     let wasm_bytes = fs::read(wasm)
         .with_context(|| format!("couldn't read Wasm file {}", wasm.display()))
@@ -176,7 +207,14 @@ async fn intialize_enclave(
         ubyte::ByteUnit::Byte(wasm_bytes.len() as u64)
     );
 
-    let request = InitializeRequest { wasm_module: wasm_bytes, constant_response_size };
+    let request = InitializeRequest {
+        wasm_module: wasm_bytes,
+        constant_response_size,
+        response_cache_capacity,
+        response_cache_processing_time_ms,
+        server_policy: None,
+        response_cache_min_processing_time_ms,
+    };
 
     let mut client = OakFunctionsAsyncClient::new(connector_handle);
     log::info!("sending initialize request");
@@ -58,6 +58,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             lookup_data_config,
             cli.functions_params.wasm,
             cli.functions_params.constant_response_size,
+            cli.functions_params.response_cache_capacity,
+            cli.functions_params.response_cache_processing_time_ms,
+            cli.functions_params.response_cache_min_processing_time_ms,
         )
         .await?;
 
@@ -171,7 +171,8 @@ async fn test_load_large_lookup_data() {
     let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
         .expect("Failed to build Wasm module");
     let status_one_chunk =
-        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024).await;
+        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024, 0, 0, 0)
+            .await;
     assert!(status_one_chunk.is_ok());
 
     let (launched_instance, connector_handle, _) = status_one_chunk.unwrap();
@@ -254,6 +255,7 @@ async fn test_load_two_gib_lookup_data() {
     let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
         .expect("Failed to build Wasm module");
     let status =
-        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024).await;
+        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024, 0, 0, 0)
+            .await;
     assert!(status.is_ok());
 }
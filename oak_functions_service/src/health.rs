@@ -0,0 +1,136 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Liveness probe responses.
+//!
+//! Health responses bypass the Wasm module, but are subject to the same size
+//! and timing policy as every other response, so that probes can't be used to
+//! learn anything about how real requests are processed.
+
+use core::time::Duration;
+
+use oak_functions_abi::{create_response_and_apply_policy, Response, StatusCode};
+use oak_proto_rust::oak::oak_functions::abi::ServerPolicy;
+
+use crate::response_cache::Clock;
+
+/// The body of a health response, before padding.
+pub const HEALTH_RESPONSE_BODY: &[u8] = b"OK";
+
+/// Creates the response to a liveness probe.
+///
+/// The response has a [`StatusCode::Success`] status and its body is padded to
+/// `policy.constant_response_size_bytes`. This function only returns after
+/// `policy.constant_processing_time_ms` has elapsed since it was called.
+pub fn create_health_response(policy: &ServerPolicy, clock: &dyn Clock) -> Response {
+    let start = clock.now();
+    let size = policy.constant_response_size_bytes as usize;
+    // Truncate the body rather than reporting a policy violation if the
+    // constant response size is tiny: the probe itself is still healthy.
+    let body = &HEALTH_RESPONSE_BODY[..HEALTH_RESPONSE_BODY.len().min(size)];
+    let response = create_response_and_apply_policy(
+        Response::create(StatusCode::Success, body.to_vec()),
+        size,
+    );
+    clock.sleep_until(start + Duration::from_millis(policy.constant_processing_time_ms.into()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec};
+
+    use oak_functions_abi::Request;
+
+    use super::*;
+    use crate::{
+        fakes::FakeClock,
+        response_cache::{ResponseCache, ResponseCacheConfig},
+    };
+
+    const POLICY: ServerPolicy = ServerPolicy {
+        constant_response_size_bytes: 64,
+        constant_processing_time_ms: 20,
+        max_request_size_bytes: 0,
+    };
+
+    /// Serves a regular response through the same policy as the health check.
+    fn normal_response(clock: Arc<FakeClock>) -> Response {
+        let cache = ResponseCache::new(
+            ResponseCacheConfig {
+                capacity: 0,
+                constant_processing_time: Duration::from_millis(
+                    POLICY.constant_processing_time_ms.into(),
+                ),
+                min_processing_time: None,
+            },
+            clock,
+        );
+        cache
+            .get_or_compute(&Request { body: b"request".to_vec() }, || {
+                Ok::<_, ()>(create_response_and_apply_policy(
+                    Response::create(StatusCode::Success, vec![7; 40]),
+                    POLICY.constant_response_size_bytes as usize,
+                ))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_health_response_is_ok() {
+        let response = create_health_response(&POLICY, &FakeClock::default());
+        assert_eq!(response.status, StatusCode::Success);
+        assert_eq!(response.body().unwrap(), HEALTH_RESPONSE_BODY);
+    }
+
+    #[test]
+    fn test_health_response_matches_normal_response_size() {
+        let health = create_health_response(&POLICY, &FakeClock::default());
+        let normal = normal_response(Arc::new(FakeClock::default()));
+        assert_eq!(health.body.len(), POLICY.constant_response_size_bytes as usize);
+        assert_eq!(health.encode_to_vec().len(), normal.encode_to_vec().len());
+    }
+
+    #[test]
+    fn test_health_response_respects_processing_time() {
+        let floor = Duration::from_millis(POLICY.constant_processing_time_ms.into());
+
+        let clock = FakeClock::default();
+        let start = clock.now();
+        create_health_response(&POLICY, &clock);
+        let health_elapsed = clock.now() - start;
+
+        let clock = Arc::new(FakeClock::default());
+        let start = clock.now();
+        normal_response(clock.clone());
+        let normal_elapsed = clock.now() - start;
+
+        assert!(health_elapsed >= floor, "health served after {:?}", health_elapsed);
+        assert!(normal_elapsed >= floor, "request served after {:?}", normal_elapsed);
+    }
+
+    #[test]
+    fn test_tiny_response_size_truncates_body() {
+        let policy = ServerPolicy {
+            constant_response_size_bytes: 1,
+            constant_processing_time_ms: 0,
+            max_request_size_bytes: 0,
+        };
+        let response = create_health_response(&policy, &FakeClock::default());
+        assert_eq!(response.status, StatusCode::Success);
+        assert_eq!(response.body.len(), 1);
+    }
+}
@@ -15,9 +15,11 @@
 //
 
 use alloc::{format, sync::Arc};
+use core::time::Duration;
 
 use micro_rpc::{Status, Vec};
-use oak_functions_abi::Request;
+use oak_functions_abi::{create_response_and_apply_policy, Request, Response};
+use oak_proto_rust::oak::oak_functions::abi::ServerPolicy;
 use prost::Message;
 
 use crate::{
@@ -29,12 +31,16 @@ use crate::{
         FinishNextLookupDataResponse, InitializeRequest, LookupDataChunk, LookupDataEntry,
         ReserveRequest, ReserveResponse,
     },
+    request_size::reject_oversized_request,
+    response_cache::{Clock, ResponseCache, ResponseCacheConfig},
     Handler, Observer,
 };
 
 pub struct OakFunctionsInstance<H: Handler> {
     lookup_data_manager: Arc<LookupDataManager<16>>,
     wasm_handler: H::HandlerType,
+    response_cache: Option<ResponseCache>,
+    server_policy: Option<(ServerPolicy, Arc<dyn Clock>)>,
 }
 
 impl<H: Handler> OakFunctionsInstance<H> {
@@ -54,12 +60,68 @@ impl<H: Handler> OakFunctionsInstance<H> {
                         format!("couldn't initialize Wasm handler: {:?}", err),
                     )
                 })?;
-        Ok(Self { lookup_data_manager, wasm_handler })
+        Ok(Self { lookup_data_manager, wasm_handler, response_cache: None, server_policy: None })
+    }
+
+    /// Enables caching of the responses to repeated requests.
+    ///
+    /// The cache is cleared whenever new lookup data is made available.
+    pub fn with_response_cache(
+        mut self,
+        config: ResponseCacheConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        self.response_cache = Some(ResponseCache::new(config, clock));
+        self
+    }
+
+    /// Applies `policy` to user requests and their responses.
+    ///
+    /// Oversized requests are rejected before they reach the Wasm module; see
+    /// [`crate::request_size::reject_oversized_request`]. Every response,
+    /// including the rejections, is padded to
+    /// `policy.constant_response_size_bytes`, served after
+    /// `policy.constant_processing_time_ms` and returned encoded, status code
+    /// included.
+    pub fn with_server_policy(mut self, policy: ServerPolicy, clock: Arc<dyn Clock>) -> Self {
+        self.server_policy = Some((policy, clock));
+        self
     }
     /// See [`crate::proto::oak::functions::OakFunctions::handle_user_request`].
     pub fn handle_user_request(&self, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
-        // TODO(#3442): Implement constant response size policy.
-        self.wasm_handler.handle_invoke(Request { body: request }).map(|response| response.body)
+        let (policy, clock) = match self.server_policy {
+            Some((ref policy, ref clock)) => (policy, clock),
+            None => return self.invoke(request, |response| response).map(|response| response.body),
+        };
+        let start = clock.now();
+        if let Some(rejection) = reject_oversized_request(policy, &request, &**clock) {
+            return Ok(rejection.encode_to_vec());
+        }
+        let constant_response_size = policy.constant_response_size_bytes as usize;
+        let response = self.invoke(request, |response| {
+            create_response_and_apply_policy(response, constant_response_size)
+        })?;
+        clock.sleep_until(start + Duration::from_millis(policy.constant_processing_time_ms.into()));
+        Ok(response.encode_to_vec())
+    }
+
+    /// Passes `request` to the Wasm module, unless the response is cached.
+    ///
+    /// `apply_policy` is applied to the response of the Wasm module before it
+    /// is stored in the cache, so that cached responses are served exactly as
+    /// they were the first time.
+    fn invoke<F: Fn(Response) -> Response>(
+        &self,
+        request: Vec<u8>,
+        apply_policy: F,
+    ) -> Result<Response, micro_rpc::Status> {
+        let request = Request { body: request };
+        match self.response_cache {
+            Some(ref response_cache) => response_cache.get_or_compute(&request, || {
+                self.wasm_handler.handle_invoke(request.clone()).map(&apply_policy)
+            }),
+            None => self.wasm_handler.handle_invoke(request).map(apply_policy),
+        }
     }
     /// See [`crate::proto::oak::functions::OakFunctions::extend_next_lookup_data`].
     pub fn extend_next_lookup_data(
@@ -99,6 +161,10 @@ impl<H: Handler> OakFunctionsInstance<H> {
         _request: FinishNextLookupDataRequest,
     ) -> Result<FinishNextLookupDataResponse, micro_rpc::Status> {
         self.lookup_data_manager.finish_next_lookup_data();
+        // Cached responses may depend on the previous lookup data.
+        if let Some(ref response_cache) = self.response_cache {
+            response_cache.clear();
+        }
         Ok(FinishNextLookupDataResponse {})
     }
     /// See [`crate::proto::oak::functions::OakFunctions::abort_next_lookup_data`].
@@ -131,10 +197,19 @@ fn to_data(chunk: &LookupDataChunk) -> impl Iterator<Item = (&[u8], &[u8])> {
 #[cfg(test)]
 mod tests {
     use bytes::{Bytes, BytesMut};
+    use oak_functions_abi::StatusCode;
 
     use super::*;
     use crate::wasm::{WasmConfig, WasmHandler};
 
+    struct ZeroClock;
+
+    impl Clock for ZeroClock {
+        fn now(&self) -> core::time::Duration {
+            core::time::Duration::ZERO
+        }
+    }
+
     static ITEMS: [LookupDataEntry; 2] = [
         LookupDataEntry { key: Bytes::from_static(b"key1"), value: Bytes::from_static(b"value1") },
         LookupDataEntry { key: Bytes::from_static(b"key2"), value: Bytes::from_static(b"value2") },
@@ -146,7 +221,7 @@ mod tests {
         let wasm_module = std::fs::read(wasm_module_path).unwrap();
 
         let instance = OakFunctionsInstance::<WasmHandler>::new(
-            &InitializeRequest { wasm_module, constant_response_size: 0 },
+            &InitializeRequest { wasm_module, ..Default::default() },
             None,
             WasmConfig::default(),
         )
@@ -171,7 +246,7 @@ mod tests {
         let wasm_module = std::fs::read(wasm_module_path).unwrap();
 
         let instance = OakFunctionsInstance::<WasmHandler>::new(
-            &InitializeRequest { wasm_module, constant_response_size: 0 },
+            &InitializeRequest { wasm_module, ..Default::default() },
             None,
             WasmConfig::default(),
         )
@@ -194,4 +269,112 @@ mod tests {
         }
         assert_eq!(None, lookup_data.get(b"key3"));
     }
+
+    #[test]
+    fn test_response_cache_cleared_on_new_lookup_data() {
+        let wasm_module_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
+        let wasm_module = std::fs::read(wasm_module_path).unwrap();
+
+        let instance = OakFunctionsInstance::<WasmHandler>::new(
+            &InitializeRequest { wasm_module, ..Default::default() },
+            None,
+            WasmConfig::default(),
+        )
+        .unwrap()
+        .with_response_cache(
+            ResponseCacheConfig { capacity: 4, ..Default::default() },
+            Arc::new(ZeroClock),
+        );
+
+        let request = b"hello".to_vec();
+        let first = instance.handle_user_request(request.clone()).unwrap();
+        let second = instance.handle_user_request(request.clone()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(instance.response_cache.as_ref().unwrap().len(), 1);
+
+        instance.finish_next_lookup_data(FinishNextLookupDataRequest {}).unwrap();
+        assert!(instance.response_cache.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_oversized_request_rejected() {
+        let wasm_module_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
+        let wasm_module = std::fs::read(wasm_module_path).unwrap();
+
+        let instance = OakFunctionsInstance::<WasmHandler>::new(
+            &InitializeRequest { wasm_module, ..Default::default() },
+            None,
+            WasmConfig::default(),
+        )
+        .unwrap()
+        .with_server_policy(
+            ServerPolicy {
+                constant_response_size_bytes: 64,
+                constant_processing_time_ms: 0,
+                max_request_size_bytes: 4,
+            },
+            Arc::new(ZeroClock),
+        );
+
+        let accepted =
+            Response::decode(&instance.handle_user_request(b"hi".to_vec()).unwrap()).unwrap();
+        assert_eq!(accepted.status, StatusCode::Success);
+        assert_eq!(accepted.body().unwrap(), b"hi");
+
+        let rejected = instance.handle_user_request(b"hello".to_vec()).unwrap();
+        assert_eq!(rejected.len(), accepted.encode_to_vec().len());
+        let rejected = Response::decode(&rejected).unwrap();
+        assert_eq!(rejected.status, StatusCode::PolicyRequestSizeViolation);
+        assert_eq!(rejected.body.len(), 64);
+    }
+
+    #[test]
+    fn test_response_cache_configured_by_initialize_request() {
+        assert!(ResponseCacheConfig::from_initialize_request(&InitializeRequest::default())
+            .unwrap()
+            .is_none());
+
+        let wasm_module_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
+        let wasm_module = std::fs::read(wasm_module_path).unwrap();
+        let request =
+            InitializeRequest { wasm_module, response_cache_capacity: 2, ..Default::default() };
+        let config = ResponseCacheConfig::from_initialize_request(&request).unwrap().unwrap();
+        assert_eq!(config.capacity, 2);
+
+        let instance =
+            OakFunctionsInstance::<WasmHandler>::new(&request, None, WasmConfig::default())
+                .unwrap()
+                .with_response_cache(config, Arc::new(ZeroClock))
+                .with_server_policy(
+                    ServerPolicy {
+                        constant_response_size_bytes: 64,
+                        constant_processing_time_ms: 0,
+                        max_request_size_bytes: 4,
+                    },
+                    Arc::new(ZeroClock),
+                );
+
+        // Requests rejected by the policy never reach the cache.
+        let rejected =
+            Response::decode(&instance.handle_user_request(b"hello".to_vec()).unwrap()).unwrap();
+        assert_eq!(rejected.status, StatusCode::PolicyRequestSizeViolation);
+        assert!(instance.response_cache.as_ref().unwrap().is_empty());
+
+        let first = instance.handle_user_request(b"hi".to_vec()).unwrap();
+        let second = instance.handle_user_request(b"hi".to_vec()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(Response::decode(&first).unwrap().body().unwrap(), b"hi");
+        assert_eq!(instance.response_cache.as_ref().unwrap().len(), 1);
+
+        // The cache holds the response after the policy has been applied to it.
+        let cached = instance
+            .response_cache
+            .as_ref()
+            .unwrap()
+            .get_or_compute(&Request { body: b"hi".to_vec() }, || -> Result<Response, ()> {
+                panic!("response should have been cached")
+            })
+            .unwrap();
+        assert_eq!(cached.encode_to_vec(), first);
+    }
 }
@@ -40,15 +40,21 @@ pub mod proto {
             }
             use prost::Message;
             include!(concat!(env!("OUT_DIR"), "/oak.functions.rs"));
+            pub use oak_proto_rust::oak::oak_functions::abi;
         }
         pub use oak_proto_rust::oak::{attestation, crypto};
     }
 }
 
+#[cfg(test)]
+mod fakes;
+pub mod health;
 pub mod instance;
 pub mod logger;
 pub mod lookup;
 pub mod lookup_htbl;
+pub mod request_size;
+pub mod response_cache;
 pub mod wasm;
 
 pub trait Observer {
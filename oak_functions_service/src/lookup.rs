@@ -72,12 +72,12 @@ impl DataBuilder {
 }
 
 #[cfg(feature = "std")]
-mod mutexes {
+pub(crate) mod mutexes {
     pub use parking_lot::{Mutex, RwLock};
 }
 
 #[cfg(not(feature = "std"))]
-mod mutexes {
+pub(crate) mod mutexes {
     pub use spinning_top::{RwSpinlock as RwLock, Spinlock as Mutex};
 }
 
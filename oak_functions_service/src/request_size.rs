@@ -0,0 +1,101 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Early rejection of requests that are larger than the policy allows.
+//!
+//! Oversized requests are rejected before they reach the Wasm module, so that
+//! clients can't force huge allocations. The rejection is subject to the same
+//! size and timing policy as every other response, so that it doesn't reveal
+//! that the request was too large.
+
+use core::time::Duration;
+
+use oak_functions_abi::{check_request_size, Response};
+use oak_proto_rust::oak::oak_functions::abi::ServerPolicy;
+
+use crate::response_cache::Clock;
+
+/// Checks the size of `request` against `policy.max_request_size_bytes`.
+///
+/// Returns `None` if the request may be processed. Otherwise, returns the
+/// rejection, whose body is padded to `policy.constant_response_size_bytes`;
+/// in that case this function only returns after
+/// `policy.constant_processing_time_ms` has elapsed since it was called.
+pub fn reject_oversized_request(
+    policy: &ServerPolicy,
+    request: &[u8],
+    clock: &dyn Clock,
+) -> Option<Response> {
+    let start = clock.now();
+    let response = check_request_size(
+        request,
+        policy.max_request_size_bytes as usize,
+        policy.constant_response_size_bytes as usize,
+    )
+    .err()?;
+    clock.sleep_until(start + Duration::from_millis(policy.constant_processing_time_ms.into()));
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use oak_functions_abi::{create_response_and_apply_policy, StatusCode};
+
+    use super::*;
+    use crate::fakes::FakeClock;
+
+    const POLICY: ServerPolicy = ServerPolicy {
+        constant_response_size_bytes: 64,
+        constant_processing_time_ms: 20,
+        max_request_size_bytes: 100,
+    };
+
+    #[test]
+    fn test_under_limit_request_is_accepted() {
+        assert_eq!(reject_oversized_request(&POLICY, &[1; 99], &FakeClock::default()), None);
+    }
+
+    #[test]
+    fn test_at_limit_request_is_accepted() {
+        assert_eq!(reject_oversized_request(&POLICY, &[1; 100], &FakeClock::default()), None);
+    }
+
+    #[test]
+    fn test_over_limit_request_is_rejected() {
+        let clock = FakeClock::default();
+        let start = clock.now();
+        let response = reject_oversized_request(&POLICY, &[1; 101], &clock).unwrap();
+        let elapsed = clock.now() - start;
+
+        assert_eq!(response.status, StatusCode::PolicyRequestSizeViolation);
+        assert_eq!(response.body.len(), POLICY.constant_response_size_bytes as usize);
+        let normal = create_response_and_apply_policy(
+            Response::create(StatusCode::Success, vec![7; 40]),
+            POLICY.constant_response_size_bytes as usize,
+        );
+        assert_eq!(response.encode_to_vec().len(), normal.encode_to_vec().len());
+        let floor = Duration::from_millis(POLICY.constant_processing_time_ms.into());
+        assert!(elapsed >= floor, "rejection served after {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_zero_limit_accepts_any_request() {
+        let policy = ServerPolicy { max_request_size_bytes: 0, ..POLICY };
+        assert_eq!(reject_oversized_request(&policy, &[1; 10000], &FakeClock::default()), None);
+    }
+}
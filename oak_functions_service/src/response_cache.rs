@@ -0,0 +1,491 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Cache of the responses to previously seen requests.
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::sync::Arc;
+use core::time::Duration;
+
+use hashbrown::HashMap;
+use oak_crypto::noise_handshake::sha256;
+use oak_functions_abi::{Request, Response, StatusCode};
+
+use crate::{lookup::mutexes::Mutex, proto::oak::functions::InitializeRequest};
+
+/// Source of monotonic time used to enforce the processing time policies.
+pub trait Clock: Send + Sync {
+    /// Returns the time elapsed since an arbitrary fixed point in the past.
+    fn now(&self) -> Duration;
+
+    /// Blocks until `now()` has reached `deadline`.
+    ///
+    /// The default implementation spins, for clocks that have no way of
+    /// waiting for time to pass.
+    fn sleep_until(&self, deadline: Duration) {
+        while self.now() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// [`Clock`] backed by [`std::time::Instant`].
+#[cfg(feature = "std")]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self { start: std::time::Instant::now() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        if let Some(remaining) = deadline.checked_sub(self.now()) {
+            std::thread::sleep(remaining);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ResponseCacheConfig {
+    /// The maximum number of responses to keep in the cache. Once the cache is
+    /// full, the least recently used entry is evicted.
+    pub capacity: usize,
+    /// The time it takes to serve a request, regardless of whether the response
+    /// was served from the cache or not. Zero disables timing padding.
+    ///
+    /// Responses are never released before this time has elapsed. The Wasm
+    /// module can't be interrupted, so a request that takes longer is only
+    /// answered once the module is done, but with a
+    /// [`StatusCode::PolicyTimeViolation`] error instead of its response,
+    /// which is not cached. The time should therefore leave a margin over the
+    /// slowest expected request.
+    pub constant_processing_time: Duration,
+    /// A minimum time it takes to serve a request, used instead of
+    /// `constant_processing_time`.
+    ///
+    /// Fast requests are padded up to this floor, but requests that take longer
+    /// are answered as soon as they are done. This lowers the average latency
+    /// at the cost of a weaker timing-privacy guarantee: the processing time of
+    /// every request slower than the floor is visible to the client (and to
+    /// anyone observing the traffic), with full resolution.
+    ///
+    /// Must not be combined with a non-zero `constant_processing_time`.
+    pub min_processing_time: Option<Duration>,
+}
+
+impl ResponseCacheConfig {
+    /// Returns the configuration requested by `request`, or `None` if the
+    /// response cache should be disabled.
+    ///
+    /// Fails if `request` asks for both a constant and a minimum processing
+    /// time.
+    pub fn from_initialize_request(
+        request: &InitializeRequest,
+    ) -> Result<Option<Self>, micro_rpc::Status> {
+        if request.response_cache_capacity == 0 {
+            return Ok(None);
+        }
+        if request.response_cache_processing_time_ms != 0
+            && request.response_cache_min_processing_time_ms != 0
+        {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                "response_cache_processing_time_ms and response_cache_min_processing_time_ms are \
+                 mutually exclusive",
+            ));
+        }
+        let min_processing_time = match request.response_cache_min_processing_time_ms {
+            0 => None,
+            millis => Some(Duration::from_millis(millis.into())),
+        };
+        Ok(Some(Self {
+            capacity: request.response_cache_capacity as usize,
+            constant_processing_time: Duration::from_millis(
+                request.response_cache_processing_time_ms.into(),
+            ),
+            min_processing_time,
+        }))
+    }
+
+    /// Returns whether a request that was received at `start` and processed by
+    /// `end` missed the constant processing time.
+    fn missed_deadline(&self, start: Duration, end: Duration) -> bool {
+        self.min_processing_time.is_none()
+            && !self.constant_processing_time.is_zero()
+            && end > start + self.constant_processing_time
+    }
+
+    /// Returns the time at which a request that was received at `start` and
+    /// processed by `end` may be answered.
+    fn release_time(&self, start: Duration, end: Duration) -> Duration {
+        match self.min_processing_time {
+            Some(floor) => end.max(start + floor),
+            None => start + self.constant_processing_time,
+        }
+    }
+}
+
+struct CacheEntry {
+    response: Response,
+    /// Value of the cache's use counter the last time this entry was used.
+    last_used: u64,
+}
+
+/// SHA-256 digest of the body of a request, as handed to the Wasm module.
+type CacheKey = [u8; 32];
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Free-running counter used to track the least recently used entry.
+    use_counter: u64,
+}
+
+/// LRU cache of responses, keyed by the hash of the request.
+///
+/// The request is hashed in the form it is handed to the Wasm module, that is,
+/// after the request policy has been applied, so a request is only ever served
+/// from the cache if the module would have seen exactly the same input. The
+/// cache stores the responses exactly as they are sent to the client, that
+/// is, after the size policy has been applied, so that serving a response from
+/// the cache does not change the observable size of the response. To avoid
+/// creating a timing oracle, the processing time floor is enforced on every
+/// request, both on cache hits and misses.
+pub struct ResponseCache {
+    config: ResponseCacheConfig,
+    clock: Arc<dyn Clock>,
+    state: Mutex<CacheState>,
+}
+
+impl ResponseCache {
+    /// Creates a new cache.
+    ///
+    /// Panics if both `constant_processing_time` and `min_processing_time` are
+    /// set in `config`.
+    pub fn new(config: ResponseCacheConfig, clock: Arc<dyn Clock>) -> Self {
+        assert!(
+            config.min_processing_time.is_none() || config.constant_processing_time.is_zero(),
+            "constant_processing_time and min_processing_time are mutually exclusive"
+        );
+        Self { config, clock, state: Mutex::new(CacheState::default()) }
+    }
+
+    /// Returns the cached response for the request, or computes it using
+    /// `compute` (and caches it, if successful) if the request was not seen
+    /// before.
+    ///
+    /// `request` must be the request as it is passed to the Wasm module, and
+    /// `compute` must return the response after the size policy has been
+    /// applied. This function only returns after at least the configured
+    /// processing time (constant or minimum) has elapsed since it was called.
+    pub fn get_or_compute<E, F: FnOnce() -> Result<Response, E>>(
+        &self,
+        request: &Request,
+        compute: F,
+    ) -> Result<Response, E> {
+        let start = self.clock.now();
+        let key = sha256(&request.body);
+        let result = match self.get(&key) {
+            Some(response) => Ok(response),
+            None => compute().map(|response| {
+                if self.config.missed_deadline(start, self.clock.now()) {
+                    return time_violation(&response);
+                }
+                self.insert(key, response.clone());
+                response
+            }),
+        };
+        let end = self.clock.now();
+        self.clock.sleep_until(self.config.release_time(start, end));
+        result
+    }
+
+    /// Removes all entries from the cache.
+    ///
+    /// This must be called whenever the responses for identical requests might
+    /// change, e.g. after the lookup data was updated.
+    pub fn clear(&self) {
+        self.state.lock().entries.clear();
+    }
+
+    /// Returns the number of responses currently in the cache.
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Response> {
+        let mut state = self.state.lock();
+        state.use_counter += 1;
+        let use_counter = state.use_counter;
+        state.entries.get_mut(key).map(|entry| {
+            entry.last_used = use_counter;
+            entry.response.clone()
+        })
+    }
+
+    fn insert(&self, key: CacheKey, response: Response) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        if state.entries.len() >= self.config.capacity && !state.entries.contains_key(&key) {
+            // Evict the least recently used entry. This is linear in the size of the cache,
+            // but that is cheap compared to running the Wasm module.
+            if let Some(evicted) =
+                state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key)
+            {
+                state.entries.remove(&evicted);
+            }
+        }
+        state.use_counter += 1;
+        let last_used = state.use_counter;
+        state.entries.insert(key, CacheEntry { response, last_used });
+    }
+}
+
+/// Returns the error sent instead of `response` when it was not ready in time.
+///
+/// The body has the same size as that of `response`, so that the error is
+/// padded in the same way.
+fn time_violation(response: &Response) -> Response {
+    Response {
+        status: StatusCode::PolicyTimeViolation,
+        body: alloc::vec![0; response.body.len()],
+        length: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::fakes::FakeClock;
+
+    fn new_cache(
+        capacity: usize,
+        constant_processing_time: Duration,
+    ) -> (ResponseCache, Arc<FakeClock>) {
+        let clock = Arc::new(FakeClock::default());
+        let cache = ResponseCache::new(
+            ResponseCacheConfig { capacity, constant_processing_time, min_processing_time: None },
+            clock.clone(),
+        );
+        (cache, clock)
+    }
+
+    fn request(body: &[u8]) -> Request {
+        Request { body: body.to_vec() }
+    }
+
+    fn unreachable_compute() -> Result<Response, ()> {
+        panic!("response should be cached")
+    }
+
+    fn response(body: &[u8]) -> Result<Response, ()> {
+        Ok(Response::create(StatusCode::Success, body.to_vec()))
+    }
+
+    #[test]
+    fn test_miss_then_hit() {
+        let (cache, _) = new_cache(4, Duration::ZERO);
+        let mut computed = 0;
+        for _ in 0..3 {
+            let result = cache.get_or_compute(&request(b"request"), || {
+                computed += 1;
+                response(b"response")
+            });
+            assert_eq!(result, response(b"response"));
+        }
+        assert_eq!(computed, 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_errors_are_not_cached() {
+        let (cache, _) = new_cache(4, Duration::ZERO);
+        assert_eq!(cache.get_or_compute(&request(b"request"), || Err::<Response, _>(())), Err(()));
+        assert!(cache.is_empty());
+        assert_eq!(cache.get_or_compute(&request(b"request"), || response(b"ok")), response(b"ok"));
+    }
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let (cache, _) = new_cache(2, Duration::ZERO);
+        cache.get_or_compute(&request(b"a"), || response(b"1")).unwrap();
+        cache.get_or_compute(&request(b"b"), || response(b"2")).unwrap();
+        // Use `a` again so that `b` becomes the least recently used entry.
+        cache.get_or_compute(&request(b"a"), unreachable_compute).unwrap();
+        cache.get_or_compute(&request(b"c"), || response(b"3")).unwrap();
+        assert_eq!(cache.len(), 2);
+        cache.get_or_compute(&request(b"a"), unreachable_compute).unwrap();
+        cache.get_or_compute(&request(b"c"), unreachable_compute).unwrap();
+        let mut recomputed = false;
+        cache
+            .get_or_compute(&request(b"b"), || {
+                recomputed = true;
+                response(b"2")
+            })
+            .unwrap();
+        assert!(recomputed);
+    }
+
+    #[test]
+    fn test_clear() {
+        let (cache, _) = new_cache(2, Duration::ZERO);
+        cache.get_or_compute(&request(b"a"), || response(b"1")).unwrap();
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.get_or_compute(&request(b"a"), || response(b"2")), response(b"2"));
+    }
+
+    #[test]
+    fn test_hit_respects_processing_time() {
+        let floor = Duration::from_millis(50);
+        let (cache, clock) = new_cache(2, floor);
+        cache.get_or_compute(&request(b"a"), || response(b"1")).unwrap();
+
+        let start = clock.now();
+        assert_eq!(cache.get_or_compute(&request(b"a"), unreachable_compute), response(b"1"));
+        let end = clock.now();
+        assert!(end - start >= floor, "hit served after {:?}", end - start);
+    }
+
+    #[test]
+    fn test_padded_response_is_cached() {
+        let (cache, _) = new_cache(2, Duration::ZERO);
+        let padded = oak_functions_abi::create_response_and_apply_policy(
+            Response::create(StatusCode::Success, vec![1, 2, 3]),
+            16,
+        );
+        cache.get_or_compute(&request(b"a"), || Ok::<_, ()>(padded.clone())).unwrap();
+        let cached = cache.get_or_compute(&request(b"a"), unreachable_compute).unwrap();
+        assert_eq!(cached, padded);
+        assert_eq!(cached.body.len(), 16);
+    }
+
+    /// Returns a response after advancing `clock` by `millis`, as if computing
+    /// it took that long.
+    fn slow_response(clock: &FakeClock, millis: u64) -> Result<Response, ()> {
+        clock.advance(millis);
+        response(b"slow")
+    }
+
+    fn new_cache_with_floor(floor: Duration) -> (ResponseCache, Arc<FakeClock>) {
+        let clock = Arc::new(FakeClock::default());
+        let cache = ResponseCache::new(
+            ResponseCacheConfig {
+                capacity: 0,
+                constant_processing_time: Duration::ZERO,
+                min_processing_time: Some(floor),
+            },
+            clock.clone(),
+        );
+        (cache, clock)
+    }
+
+    #[test]
+    fn test_min_processing_time_pads_fast_response() {
+        let floor = Duration::from_millis(50);
+        let (cache, clock) = new_cache_with_floor(floor);
+        let start = clock.now();
+        cache.get_or_compute(&request(b"a"), || response(b"1")).unwrap();
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= floor, "served after {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_min_processing_time_does_not_delay_slow_response() {
+        let (cache, clock) = new_cache_with_floor(Duration::from_millis(50));
+        let start = clock.now();
+        cache.get_or_compute(&request(b"a"), || slow_response(&clock, 70)).unwrap();
+        let elapsed = clock.now() - start;
+        // Only the clock reads themselves advance the time further.
+        assert!(elapsed < Duration::from_millis(80), "served after {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_constant_processing_time_pads_fast_response() {
+        let period = Duration::from_millis(50);
+        let (cache, clock) = new_cache(2, period);
+        let start = clock.now();
+        assert_eq!(cache.get_or_compute(&request(b"a"), || response(b"1")), response(b"1"));
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= period, "served after {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_constant_processing_time_replaces_slow_response() {
+        let period = Duration::from_millis(50);
+        let (cache, clock) = new_cache(2, period);
+        let start = clock.now();
+        let result = cache.get_or_compute(&request(b"a"), || slow_response(&clock, 70)).unwrap();
+        let elapsed = clock.now() - start;
+        assert!(elapsed < Duration::from_millis(80), "served after {:?}", elapsed);
+        assert_eq!(result.status, StatusCode::PolicyTimeViolation);
+        assert_eq!(result.body.len(), b"slow".len());
+        // The late response is not cached, so it can't be served in time later on.
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_from_initialize_request() {
+        let request = InitializeRequest {
+            response_cache_capacity: 2,
+            response_cache_min_processing_time_ms: 50,
+            ..Default::default()
+        };
+        let config = ResponseCacheConfig::from_initialize_request(&request).unwrap().unwrap();
+        assert_eq!(config.capacity, 2);
+        assert_eq!(config.min_processing_time, Some(Duration::from_millis(50)));
+        assert!(config.constant_processing_time.is_zero());
+
+        let request = InitializeRequest { response_cache_processing_time_ms: 50, ..request };
+        assert_eq!(
+            ResponseCacheConfig::from_initialize_request(&request).unwrap_err().code,
+            micro_rpc::StatusCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "mutually exclusive")]
+    fn test_processing_time_modes_are_exclusive() {
+        ResponseCache::new(
+            ResponseCacheConfig {
+                capacity: 0,
+                constant_processing_time: Duration::from_millis(50),
+                min_processing_time: Some(Duration::from_millis(50)),
+            },
+            Arc::new(FakeClock::default()),
+        );
+    }
+}
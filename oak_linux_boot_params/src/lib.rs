@@ -552,6 +552,12 @@ impl BootE820Entry {
         E820EntryType::from_repr(self.type_)
     }
 
+    /// Returns the type of the entry as a raw value, which may not be one of
+    /// the known `E820EntryType`s.
+    pub fn raw_type(&self) -> u32 {
+        self.type_
+    }
+
     pub fn addr(&self) -> usize {
         self.addr
     }
@@ -771,7 +777,8 @@ impl BootParams {
     }
 
     pub fn args(&self) -> &CStr {
-        if self.hdr.cmdline_size == 0 {
+        // Some VMMs leave the pointer unset if there is no command line.
+        if self.hdr.cmdline_size == 0 || self.hdr.cmd_line_ptr == 0 {
             Default::default()
         } else {
             // Safety: Linux boot protocol expects the pointer to be valid, even if there
@@ -39,4 +39,12 @@ pub struct ServerPolicy {
     /// this response is equal to the size specified by the previous parameter.
     #[prost(uint32, tag = "2")]
     pub constant_processing_time_ms: u32,
+    /// The maximum size of a request, in bytes.
+    ///
+    /// Requests larger than this are rejected before they reach the Wasm module,
+    /// with a response indicating the policy violation. That response is subject
+    /// to the previous two parameters, like every other response. Zero means that
+    /// there is no limit.
+    #[prost(uint32, tag = "3")]
+    pub max_request_size_bytes: u32,
 }
@@ -14,17 +14,24 @@
 // limitations under the License.
 //
 
-use alloc::{boxed::Box, string::String, vec::Vec};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::ptr::NonNull;
 
-use acpi::{AcpiHandler, AcpiTables, AmlTable, PhysicalMapping};
+use acpi::{AcpiHandler, AcpiTables, AmlTable, InterruptModel, PhysicalMapping};
 use aml::{
     resource::{resource_descriptor_list, MemoryRangeDescriptor, Resource},
     value::Args,
     AmlContext, AmlError, AmlName, AmlValue, LevelType, NamespaceLevel,
 };
 use anyhow::{anyhow, bail, Result};
-use oak_linux_boot_params::BootParams;
+use oak_restricted_kernel_interface::syscalls::{
+    AcpiDeviceInfo, AcpiDeviceKind, ACPI_DEVICE_NO_IRQ,
+};
 use x86_64::PhysAddr;
 
 use crate::{mm::Translator, PAGE_TABLES};
@@ -36,8 +43,24 @@ const SERIAL_PORT: &str = "PNP0501";
 const PCI_BUS: &str = "PNP0A03";
 const PCIE_BUS: &str = "PNP0A08";
 const POWER_BUTTON: &str = "PNP0C0C";
+pub const MEMORY_DEVICE: &str = "PNP0C80";
 const QEMU_FW_CFG_DEVICE_ID: &str = "QEMU0002";
 
+/// Kernel argument that points to a blob of ACPI tables to use instead of the
+/// tables provided by the firmware, in the form `acpi_override=base:len`.
+pub const ACPI_OVERRIDE_ARG: &str = "acpi_override";
+
+/// Signature of the Root System Description Pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Signature of the Extended System Description Table.
+const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+/// Length of the ACPI 1.0 part of the RSDP, covered by the basic checksum.
+const RSDP_V1_LENGTH: usize = 20;
+/// Length of the ACPI 2.0+ RSDP.
+const RSDP_V2_LENGTH: usize = 36;
+/// Length of the common header of all system description tables.
+const SDT_HEADER_LENGTH: usize = 36;
+
 fn description(hid: &str) -> &str {
     match hid {
         ACPI_GED => "ACPI Generic Event Device",
@@ -46,11 +69,25 @@ fn description(hid: &str) -> &str {
         PCI_BUS => "PCI bus",
         PCIE_BUS => "PCI Express bus",
         POWER_BUTTON => "Power Button Device",
+        MEMORY_DEVICE => "Memory Device",
         QEMU_FW_CFG_DEVICE_ID => "QEMU fw_cfg Device",
         _ => "(unknown device)",
     }
 }
 
+fn kind(hid: &str) -> AcpiDeviceKind {
+    match hid {
+        ACPI_GED => AcpiDeviceKind::GenericEventDevice,
+        VIRTIO_MMIO => AcpiDeviceKind::VirtioMmio,
+        SERIAL_PORT => AcpiDeviceKind::SerialPort,
+        PCI_BUS => AcpiDeviceKind::PciBus,
+        PCIE_BUS => AcpiDeviceKind::PcieBus,
+        POWER_BUTTON => AcpiDeviceKind::PowerButton,
+        QEMU_FW_CFG_DEVICE_ID => AcpiDeviceKind::QemuFwCfg,
+        _ => AcpiDeviceKind::Unknown,
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Handler {}
 impl AcpiHandler for Handler {
@@ -314,15 +351,256 @@ impl AcpiDevice {
     }
 }
 
+/// A resource used by an ACPI device, as listed in its `_CRS` object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceResource {
+    Irq(u32),
+    /// A memory range at a fixed location, e.g. the registers of a virtio MMIO
+    /// device.
+    MemoryRange {
+        base: u64,
+        length: u64,
+    },
+    /// Any other resource, as a description for logging.
+    Other(String),
+}
+
+impl From<&Resource> for DeviceResource {
+    fn from(resource: &Resource) -> Self {
+        match resource {
+            Resource::Irq(irq) => DeviceResource::Irq(irq.irq),
+            Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
+                is_writable: _,
+                base_address,
+                range_length,
+            }) => DeviceResource::MemoryRange {
+                base: *base_address as u64,
+                length: *range_length as u64,
+            },
+            Resource::AddressSpace(address) => {
+                DeviceResource::Other(format!("Address space: {:?}", address))
+            }
+            Resource::IOPort(port) => DeviceResource::Other(format!("IO port: {:?}", port)),
+            Resource::Dma(dma) => DeviceResource::Other(format!("DMA: {:?}", dma)),
+        }
+    }
+}
+
+/// A device described in the ACPI namespace.
+///
+/// Unlike <AcpiDevice>, this is a snapshot that doesn't need the AML
+/// interpreter to inspect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub hid: Option<String>,
+    pub kind: AcpiDeviceKind,
+    pub resources: Vec<DeviceResource>,
+}
+
+impl DeviceInfo {
+    fn new(name: String, hid: Option<String>, resources: &[Resource]) -> Self {
+        Self {
+            name,
+            kind: hid.as_deref().map_or(AcpiDeviceKind::Unknown, kind),
+            hid,
+            resources: resources.iter().map(DeviceResource::from).collect(),
+        }
+    }
+
+    /// Returns the first fixed memory range used by the device, as
+    /// `(base, length)`.
+    pub fn memory_range(&self) -> Option<(u64, u64)> {
+        self.resources.iter().find_map(|resource| match resource {
+            DeviceResource::MemoryRange { base, length } => Some((*base, *length)),
+            _ => None,
+        })
+    }
+
+    /// Returns the first interrupt used by the device.
+    pub fn irq(&self) -> Option<u32> {
+        self.resources.iter().find_map(|resource| match resource {
+            DeviceResource::Irq(irq) => Some(*irq),
+            _ => None,
+        })
+    }
+
+    /// Converts the device to the form returned to the payload by
+    /// `Syscall::UnstableGetAcpiDevices`.
+    pub fn to_abi(&self) -> AcpiDeviceInfo {
+        let mut info = AcpiDeviceInfo {
+            kind: self.kind as u32,
+            irq: self.irq().unwrap_or(ACPI_DEVICE_NO_IRQ),
+            ..Default::default()
+        };
+        copy_truncated(&mut info.name, self.name.as_bytes());
+        if let Some(ref hid) = self.hid {
+            copy_truncated(&mut info.hid, hid.as_bytes());
+        }
+        if let Some((base, length)) = self.memory_range() {
+            info.mmio_base = base;
+            info.mmio_size = length;
+        }
+        info
+    }
+}
+
+/// Copies as much of `src` as fits into `dst`, leaving the rest of `dst` as is.
+fn copy_truncated(dst: &mut [u8], src: &[u8]) {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+}
+
+/// Logs the devices, together with the resources they use.
+pub fn print_devices(devices: &[DeviceInfo]) {
+    for device in devices {
+        if let Some(ref hid) = device.hid {
+            log::info!("ACPI device: {} {:7} {}", device.name, hid, description(hid.as_str()));
+        } else {
+            log::info!("ACPI device: {} (no HID)", device.name);
+        }
+
+        for resource in &device.resources {
+            match resource {
+                DeviceResource::Irq(irq) => log::info!("  IRQ: {}", irq),
+                DeviceResource::MemoryRange { base, length } => {
+                    log::info!("  Memory range: [{:#018x}..{:#018x})", base, base + length)
+                }
+                DeviceResource::Other(description) => log::info!("  {}", description),
+            }
+        }
+    }
+}
+
+/// Location of a blob in physical memory that contains an RSDP, an XSDT and
+/// all the tables referenced by the XSDT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcpiOverride {
+    pub base: PhysAddr,
+    pub len: usize,
+}
+
+impl AcpiOverride {
+    /// Parses the value of the `acpi_override` kernel argument.
+    ///
+    /// Both the base and the length can be either hexadecimal numbers
+    /// prefixed with `0x` or decimal numbers.
+    pub fn from_arg(arg: &str) -> Result<Self> {
+        let (base, len) = arg.split_once(':').ok_or_else(|| anyhow!("expected base:len"))?;
+        let base = parse_number(base).ok_or_else(|| anyhow!("invalid base address: {}", base))?;
+        let len = parse_number(len).ok_or_else(|| anyhow!("invalid length: {}", len))?;
+        let base = PhysAddr::try_new(base)
+            .map_err(|_| anyhow!("invalid physical address: {:#x}", base))?;
+        if len < RSDP_V2_LENGTH as u64 {
+            bail!("blob too small to contain an RSDP: {}", len);
+        }
+        let len = usize::try_from(len).map_err(|_| anyhow!("length too large: {}", len))?;
+        Ok(Self { base, len })
+    }
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns the bytes of the system description table at physical address
+/// `address`, after checking that it is fully contained in the blob and that
+/// its checksum is valid.
+fn table_in_blob(blob: &[u8], base: u64, address: u64) -> Result<&[u8]> {
+    let offset = address
+        .checked_sub(base)
+        .and_then(|offset| usize::try_from(offset).ok())
+        .filter(|offset| offset.saturating_add(SDT_HEADER_LENGTH) <= blob.len())
+        .ok_or_else(|| anyhow!("table at {:#x} is outside of the blob", address))?;
+    let length = read_u32(blob, offset + 4) as usize;
+    if length < SDT_HEADER_LENGTH || offset + length > blob.len() {
+        bail!("invalid length for table at {:#x}: {}", address, length);
+    }
+    let table = &blob[offset..offset + length];
+    if !checksum_valid(table) {
+        bail!("invalid checksum for table at {:#x}", address);
+    }
+    Ok(table)
+}
+
+/// Checks that a blob located at physical address `base` contains a valid
+/// RSDP, followed by an XSDT and the tables the XSDT references.
+///
+/// Returns the signatures of the tables referenced by the XSDT.
+fn validate_override_blob(blob: &[u8], base: u64) -> Result<Vec<[u8; 4]>> {
+    if blob.len() < RSDP_V2_LENGTH || &blob[..8] != RSDP_SIGNATURE {
+        bail!("no RSDP found at the start of the blob");
+    }
+    if !checksum_valid(&blob[..RSDP_V1_LENGTH]) {
+        bail!("invalid RSDP checksum");
+    }
+    let revision = blob[15];
+    if revision < 2 {
+        bail!("unsupported RSDP revision {}; an XSDT is required", revision);
+    }
+    let rsdp_length = read_u32(blob, 20) as usize;
+    if !(RSDP_V2_LENGTH..=blob.len()).contains(&rsdp_length)
+        || !checksum_valid(&blob[..rsdp_length])
+    {
+        bail!("invalid extended RSDP");
+    }
+
+    let xsdt = table_in_blob(blob, base, read_u64(blob, 24))?;
+    if &xsdt[..4] != XSDT_SIGNATURE {
+        bail!("RSDP does not point to an XSDT");
+    }
+    xsdt[SDT_HEADER_LENGTH..]
+        .chunks(8)
+        .map(|entry| {
+            let address =
+                u64::from_le_bytes(entry.try_into().map_err(|_| anyhow!("truncated XSDT entry"))?);
+            let table = table_in_blob(blob, base, address)?;
+            Ok(table[..4].try_into().unwrap())
+        })
+        .collect()
+}
+
+/// An I/O APIC, as described in the MADT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    /// Physical address of the memory-mapped registers.
+    pub address: PhysAddr,
+    /// The first global system interrupt handled by the I/O APIC.
+    pub gsi_base: u32,
+}
+
 pub struct Acpi {
     tables: AcpiTables<Handler>,
     pub aml: AmlContext,
 }
 
 impl Acpi {
-    pub fn new(params: &BootParams) -> Result<Self> {
+    /// Loads and parses the ACPI tables.
+    ///
+    /// The tables are found via `rsdp_addr`, the address of the RSDP passed in
+    /// by the bootloader; without it, we search the BIOS area for the RSDP.
+    /// If `acpi_override` is provided the tables are loaded from that blob
+    /// instead of the location provided by the firmware. If the blob is
+    /// malformed, we log the reason and fall back to the normal discovery.
+    pub fn new(rsdp_addr: Option<u64>, acpi_override: Option<AcpiOverride>) -> Result<Self> {
         let mut acpi = Self {
-            tables: find_acpi_tables(params)?,
+            tables: find_acpi_tables(rsdp_addr, acpi_override)?,
             aml: AmlContext::new(Box::new(Handler {}), aml::DebugVerbosity::None),
         };
 
@@ -348,7 +626,39 @@ impl Acpi {
         Ok(acpi)
     }
 
-    pub fn devices(&mut self) -> Result<Vec<AcpiDevice>> {
+    /// Returns the I/O APICs described in the MADT.
+    pub fn io_apics(&self) -> Result<Vec<IoApicInfo>> {
+        let platform_info = self
+            .tables
+            .platform_info()
+            .map_err(|err| anyhow!("failed to read platform info: {:?}", err))?;
+        match platform_info.interrupt_model {
+            InterruptModel::Apic(apic) => Ok(apic
+                .io_apics
+                .iter()
+                .map(|io_apic| IoApicInfo {
+                    id: io_apic.id,
+                    address: PhysAddr::new(io_apic.address.into()),
+                    gsi_base: io_apic.global_system_interrupt_base,
+                })
+                .collect()),
+            _ => bail!("ACPI tables don't describe an APIC interrupt model"),
+        }
+    }
+
+    /// Returns the devices in the ACPI namespace, including the ones we don't
+    /// know about.
+    pub fn devices(&mut self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        for device in self.namespace_devices()? {
+            let hid = device.hid(&mut self.aml)?;
+            let resources = device.crs(&mut self.aml)?.unwrap_or_default();
+            devices.push(DeviceInfo::new(device.name.to_string(), hid, &resources));
+        }
+        Ok(devices)
+    }
+
+    fn namespace_devices(&mut self) -> Result<Vec<AcpiDevice>> {
         self.walk(|_aml, name, _level| Ok(Some(AcpiDevice { name: name.clone() })))
     }
 
@@ -373,53 +683,55 @@ impl Acpi {
             .map_err(|err| anyhow!("failed to walk ACPI tables: {:?}", err))?;
         Ok(results)
     }
+}
 
-    pub fn print_devices(&mut self) -> Result<()> {
-        for device in self.devices()? {
-            if let Some(hid) = device.hid(&mut self.aml)? {
-                log::info!("ACPI device: {} {:7} {}", device.name, hid, description(hid.as_str()));
-            } else {
-                log::info!("ACPI device: {} (no HID)", device.name);
-            }
+fn load_override_tables(acpi_override: AcpiOverride) -> Result<AcpiTables<Handler>> {
+    let virt_addr = PAGE_TABLES
+        .lock()
+        .get()
+        .ok_or_else(|| anyhow!("page tables not initialized"))?
+        .translate_physical(acpi_override.base)
+        .ok_or_else(|| anyhow!("couldn't translate blob address"))?;
+    // Safety: the kernel args specified that the blob is located at this address.
+    // All physical memory is mapped, so in the worst case we read garbage,
+    // which validation will reject.
+    let blob = unsafe { core::slice::from_raw_parts(virt_addr.as_ptr(), acpi_override.len) };
+    let signatures = validate_override_blob(blob, acpi_override.base.as_u64())?;
+    log::info!(
+        "Using ACPI override tables at {:#x}: {:?}",
+        acpi_override.base.as_u64(),
+        signatures.iter().map(|signature| String::from_utf8_lossy(signature)).collect::<Vec<_>>()
+    );
+    // Safety: we've validated that the blob contains an RSDP and the tables it
+    // points to.
+    unsafe { AcpiTables::from_rsdp(Handler {}, acpi_override.base.as_u64() as usize) }
+        .map_err(|err| anyhow!("failed to load ACPI tables from override: {:?}", err))
+}
 
-            if let Some(ref resources) = device.crs(&mut self.aml)? {
-                for resource in resources {
-                    match resource {
-                        Resource::Irq(irq) => log::info!("  IRQ: {}", irq.irq),
-                        Resource::AddressSpace(address) => {
-                            log::info!("  Address space: {:?}", address)
-                        }
-                        Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
-                            is_writable: _,
-                            base_address,
-                            range_length,
-                        }) => log::info!(
-                            "  Memory range: [{:#018x}..{:#018x})",
-                            base_address,
-                            base_address + range_length
-                        ),
-                        Resource::IOPort(port) => log::info!("  IO port: {:?}", port),
-                        Resource::Dma(dma) => log::info!("  DMA: {:?}", dma),
-                    }
-                }
-            }
+fn find_acpi_tables(
+    rsdp_addr: Option<u64>,
+    acpi_override: Option<AcpiOverride>,
+) -> Result<AcpiTables<Handler>> {
+    if let Some(acpi_override) = acpi_override {
+        match load_override_tables(acpi_override) {
+            Ok(tables) => return Ok(tables),
+            Err(err) => log::warn!(
+                "Ignoring ACPI override at {:#x}, falling back to firmware tables: {}",
+                acpi_override.base.as_u64(),
+                err
+            ),
         }
-        Ok(())
     }
-}
 
-fn find_acpi_tables(params: &BootParams) -> Result<AcpiTables<Handler>> {
-    let acpi_rsdp_addr = params.acpi_rsdp_addr;
-    if acpi_rsdp_addr > 0 {
-        // Safety: we trust the boot params to be correct.
-        return unsafe { AcpiTables::from_rsdp(Handler {}, params.acpi_rsdp_addr as usize) }
-            .map_err(|err| {
-                anyhow!(
-                    "failed to load ACPI tables from address {:#x} specified in boot params: {:?}",
-                    acpi_rsdp_addr,
-                    err
-                )
-            });
+    if let Some(rsdp_addr) = rsdp_addr {
+        // Safety: we trust the boot info to be correct.
+        return unsafe { AcpiTables::from_rsdp(Handler {}, rsdp_addr as usize) }.map_err(|err| {
+            anyhow!(
+                "failed to load ACPI tables from address {:#x} specified in boot info: {:?}",
+                rsdp_addr,
+                err
+            )
+        });
     }
 
     // Safety: the EBDA area will be mapped and valid, so this is memory-safe, but
@@ -428,3 +740,166 @@ fn find_acpi_tables(params: &BootParams) -> Result<AcpiTables<Handler>> {
     unsafe { AcpiTables::search_for_rsdp_bios(Handler {}) }
         .map_err(|err| anyhow!("failed to load ACPI tables from EBDA: {:?}", err))
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    const BASE: u64 = 0x10_0000;
+
+    fn fix_checksum(bytes: &mut [u8], checksum_offset: usize) {
+        bytes[checksum_offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[checksum_offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn sdt(signature: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut table = vec![0u8; SDT_HEADER_LENGTH];
+        table[..4].copy_from_slice(signature);
+        table[4..8].copy_from_slice(&((SDT_HEADER_LENGTH + payload.len()) as u32).to_le_bytes());
+        table[8] = 1;
+        table.extend_from_slice(payload);
+        fix_checksum(&mut table, 9);
+        table
+    }
+
+    /// Builds a blob with an RSDP at the start, followed by an XSDT that
+    /// references a single SSDT.
+    fn synthetic_blob() -> Vec<u8> {
+        let xsdt_offset = RSDP_V2_LENGTH as u64;
+        let ssdt_offset = xsdt_offset + SDT_HEADER_LENGTH as u64 + 8;
+
+        let mut rsdp = vec![0u8; RSDP_V2_LENGTH];
+        rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[9..15].copy_from_slice(b"OAKOAK");
+        rsdp[15] = 2;
+        rsdp[20..24].copy_from_slice(&(RSDP_V2_LENGTH as u32).to_le_bytes());
+        rsdp[24..32].copy_from_slice(&(BASE + xsdt_offset).to_le_bytes());
+        fix_checksum(&mut rsdp[..RSDP_V1_LENGTH], 8);
+        fix_checksum(&mut rsdp, 32);
+
+        let mut blob = rsdp;
+        blob.extend(sdt(XSDT_SIGNATURE, &(BASE + ssdt_offset).to_le_bytes()));
+        blob.extend(sdt(b"SSDT", &[]));
+        blob
+    }
+
+    #[test]
+    fn parse_override_arg() {
+        assert_eq!(
+            AcpiOverride::from_arg("0x100000:4096").unwrap(),
+            AcpiOverride { base: PhysAddr::new(0x10_0000), len: 4096 }
+        );
+        assert!(AcpiOverride::from_arg("0x100000").is_err());
+        assert!(AcpiOverride::from_arg("0x100000:8").is_err());
+        assert!(AcpiOverride::from_arg("zz:0x1000").is_err());
+    }
+
+    #[test]
+    fn synthetic_blob_parses() {
+        let blob = synthetic_blob();
+        assert_eq!(validate_override_blob(&blob, BASE).unwrap(), vec![*b"SSDT"]);
+    }
+
+    #[test]
+    fn corrupted_blob_rejected() {
+        let mut blob = synthetic_blob();
+        let last = blob.len() - 1;
+        blob[last] ^= 0xFF;
+        assert!(validate_override_blob(&blob, BASE).is_err());
+    }
+
+    #[test]
+    fn truncated_blob_rejected() {
+        let blob = synthetic_blob();
+        assert!(validate_override_blob(&blob[..blob.len() - 1], BASE).is_err());
+        assert!(validate_override_blob(&blob, BASE + 0x1000).is_err());
+    }
+
+    #[test]
+    fn device_list_from_namespace() {
+        // A virtio MMIO device, a device we don't know about, and one without a HID.
+        let namespace = [
+            (
+                "\\_SB_.VR00",
+                Some(VIRTIO_MMIO),
+                vec![Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
+                    is_writable: true,
+                    base_address: 0xfeb0_0000,
+                    range_length: 0x200,
+                })],
+            ),
+            ("\\_SB_.FOO_", Some("ABCD0001"), vec![]),
+            ("\\_SB_.PCI0.S00_", None, vec![]),
+        ];
+
+        let devices: Vec<DeviceInfo> = namespace
+            .iter()
+            .map(|(name, hid, resources)| {
+                DeviceInfo::new(name.to_string(), hid.map(String::from), resources)
+            })
+            .collect();
+
+        assert_eq!(
+            devices,
+            vec![
+                DeviceInfo {
+                    name: "\\_SB_.VR00".to_string(),
+                    hid: Some(VIRTIO_MMIO.to_string()),
+                    kind: AcpiDeviceKind::VirtioMmio,
+                    resources: vec![DeviceResource::MemoryRange {
+                        base: 0xfeb0_0000,
+                        length: 0x200
+                    }],
+                },
+                DeviceInfo {
+                    name: "\\_SB_.FOO_".to_string(),
+                    hid: Some("ABCD0001".to_string()),
+                    kind: AcpiDeviceKind::Unknown,
+                    resources: vec![],
+                },
+                DeviceInfo {
+                    name: "\\_SB_.PCI0.S00_".to_string(),
+                    hid: None,
+                    kind: AcpiDeviceKind::Unknown,
+                    resources: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn device_to_abi() {
+        let device = DeviceInfo {
+            name: "\\_SB_.VR00".to_string(),
+            hid: Some(VIRTIO_MMIO.to_string()),
+            kind: AcpiDeviceKind::VirtioMmio,
+            resources: vec![
+                DeviceResource::Other("IO port: (none)".to_string()),
+                DeviceResource::MemoryRange { base: 0xfeb0_0000, length: 0x200 },
+                DeviceResource::Irq(5),
+            ],
+        };
+        let info = device.to_abi();
+        assert_eq!(&info.name[..10], b"\\_SB_.VR00");
+        assert!(info.name[10..].iter().all(|byte| *byte == 0));
+        assert_eq!(&info.hid, b"LNRO0005");
+        assert_eq!(info.kind, AcpiDeviceKind::VirtioMmio as u32);
+        assert_eq!(info.irq, 5);
+        assert_eq!((info.mmio_base, info.mmio_size), (0xfeb0_0000, 0x200));
+
+        let device = DeviceInfo {
+            name: "\\_SB_.PCI0.S00_.S01_.S02_.S03_.S04_".to_string(),
+            hid: None,
+            kind: AcpiDeviceKind::Unknown,
+            resources: vec![],
+        };
+        let info = device.to_abi();
+        assert_eq!(&info.name, b"\\_SB_.PCI0.S00_.S01_.S02_.S03_.S");
+        assert_eq!(info.hid, [0; 8]);
+        assert_eq!(info.irq, ACPI_DEVICE_NO_IRQ);
+        assert_eq!((info.mmio_base, info.mmio_size), (0, 0));
+    }
+}
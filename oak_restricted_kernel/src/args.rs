@@ -21,7 +21,33 @@ use core::{cell::LazyCell, ffi::CStr};
 
 use arrayvec::ArrayString;
 
-static mut ARGS: ArrayString<512> = ArrayString::new_const();
+/// Maximum length, in bytes, of the kernel command line that we keep.
+pub const MAX_ARGS_LEN: usize = 512;
+
+static mut ARGS: ArrayString<MAX_ARGS_LEN> = ArrayString::new_const();
+
+/// What to do with a command line that is longer than `MAX_ARGS_LEN`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgsOverflow {
+    /// Refuse the command line altogether.
+    Error,
+    /// Keep as many whole arguments as fit, and warn about the dropped ones.
+    Truncate,
+}
+
+/// Short aliases for kernel arguments, as `(alias, canonical name)` pairs.
+///
+/// Aliases are matched case-insensitively.
+const ALIASES: &[(&str, &str)] = &[("ch", "channel"), ("ll", "log_level")];
+
+/// Returns the canonical name for `key`, which is `key` itself if it is not an
+/// alias.
+fn canonical_name(key: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(key))
+        .map_or(key, |(_, canonical)| canonical)
+}
 
 /// Kernel arguments.
 ///
@@ -34,6 +60,8 @@ pub struct Args {
 
 impl Args {
     /// Returns the full command line argument string.
+    ///
+    /// This is empty if the bootloader didn't pass a command line.
     pub fn args(&self) -> &'static str {
         // Safety: `Args::args()` can only be called after `init_args` is called.
         // Ostensibly, we could cache this in a Lazy as well, but that's
@@ -42,24 +70,99 @@ impl Args {
     }
 
     // Returns the value of the given command line argument.
+    //
+    // Both `key` and the argument on the command line may be given either by
+    // their canonical name or by one of their aliases.
     pub fn get(&self, key: &str) -> Option<&str> {
-        self.args.get(key).copied()
+        let canonical = canonical_name(key);
+        self.args
+            .get(canonical)
+            .or_else(|| {
+                self.args.iter().find_map(|(&arg, value)| {
+                    (arg != canonical && canonical_name(arg) == canonical).then_some(value)
+                })
+            })
+            .copied()
+    }
+
+    /// Returns all command line arguments whose key starts with the given
+    /// prefix, with the prefix stripped from the key.
+    pub fn with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'static str, &'static str)> + 'a {
+        self.args
+            .range::<str, _>(prefix..)
+            .map_while(move |(&key, &value)| Some((key.strip_prefix(prefix)?, value)))
+    }
+}
+
+/// Returns the command line in a form suitable for logging, which is
+/// `<none>` for an empty command line.
+pub fn describe(args: &str) -> &str {
+    if args.trim().is_empty() {
+        "<none>"
+    } else {
+        args
+    }
+}
+
+/// Fits `args` into `max_len` bytes, according to `overflow`.
+///
+/// Truncation only ever happens at whitespace, so that the arguments that are
+/// kept are exactly as they were passed; an argument is either kept whole or
+/// dropped.
+fn fit_args(args: &str, max_len: usize, overflow: ArgsOverflow) -> Result<&str, &'static str> {
+    if args.len() <= max_len {
+        return Ok(args);
+    }
+    match overflow {
+        ArgsOverflow::Error => Err("kernel arguments too long"),
+        ArgsOverflow::Truncate => {
+            // If the cut falls right before whitespace, the last argument is complete;
+            // otherwise, drop the partial argument.
+            let cut_at_boundary =
+                args.is_char_boundary(max_len) && args[max_len..].starts_with(char::is_whitespace);
+            let end = if cut_at_boundary {
+                max_len
+            } else {
+                // `max_len` may fall inside a multi-byte character of the partial argument.
+                let mut end = max_len;
+                while !args.is_char_boundary(end) {
+                    end -= 1;
+                }
+                args[..end].rfind(char::is_whitespace).unwrap_or(0)
+            };
+            Ok(args[..end].trim_end())
+        }
     }
 }
 
 /// Buffers kernel arguments in a static variable.
 ///
+/// An empty command line is valid and results in an empty set of arguments,
+/// so that all arguments take their default values. A command line that is
+/// longer than `MAX_ARGS_LEN` is handled according to `overflow`.
+///
 /// This function is intended to be called fairly early in the boot process,
 /// when we don't even have memory allocation available. This also means that we
 /// can't use anyhow::Result as the return value, as anyhow relies on
 /// allocation.
-pub fn init_args(args: &CStr) -> core::result::Result<Args, &str> {
+pub fn init_args(args: &CStr, overflow: ArgsOverflow) -> core::result::Result<Args, &str> {
     let args = args
         .to_str()
         .map_err(|core::str::Utf8Error { .. }| "kernel arguments are not valid UTF-8")?;
+    let kept = fit_args(args, MAX_ARGS_LEN, overflow)?;
+    if kept.len() < args.len() {
+        log::warn!(
+            "kernel arguments longer than {} bytes; dropping: {}",
+            MAX_ARGS_LEN,
+            args[kept.len()..].trim()
+        );
+    }
     // Safety: this is called once early in the initialization process from a single
     // thread, so there will not be any concurrent writes.
-    unsafe { ARGS.try_push_str(args) }
+    unsafe { ARGS.try_push_str(kept) }
         .map_err(|arrayvec::CapacityError { .. }| "kernel arguments too long")?;
     // Safety: we've just populated ARGS, successfully, in the line just above.
     Ok(Args { args: LazyCell::new(|| split_args(unsafe { ARGS.as_str() })) })
@@ -83,6 +186,18 @@ mod tests {
         assert_eq!(res.len(), 0);
     }
 
+    #[test]
+    fn empty_args() {
+        let args = Args { args: LazyCell::new(|| split_args("")) };
+        assert_eq!(args.get("channel"), None);
+        assert_eq!(args.with_prefix("").count(), 0);
+        assert_eq!(describe(""), "<none>");
+        assert_eq!(describe("  "), "<none>");
+        let args = Args { args: LazyCell::new(|| split_args("channel=serial")) };
+        assert_eq!(args.get("channel"), Some("serial"));
+        assert_eq!(describe("channel=serial"), "channel=serial");
+    }
+
     #[test]
     fn args() {
         let res = split_args("one two=two three=three2=three3");
@@ -92,6 +207,64 @@ mod tests {
         assert_eq!(res.get("three").copied().unwrap(), "three2=three3");
     }
 
+    #[test]
+    fn prefix() {
+        let args =
+            Args { args: LazyCell::new(|| split_args("a.one=1 b.two=2 a.three=3 a=4 ab.five=5")) };
+        let res: alloc::vec::Vec<_> = args.with_prefix("a.").collect();
+        assert_eq!(res, [("one", "1"), ("three", "3")]);
+    }
+
+    #[test]
+    fn aliases() {
+        let args = Args { args: LazyCell::new(|| split_args("ch=serial LL=debug other=1")) };
+        assert_eq!(args.get("channel"), Some("serial"));
+        assert_eq!(args.get("ch"), Some("serial"));
+        assert_eq!(args.get("log_level"), Some("debug"));
+        assert_eq!(args.get("ll"), Some("debug"));
+        assert_eq!(args.get("other"), Some("1"));
+        assert_eq!(args.get("unknown"), None);
+
+        let args = Args { args: LazyCell::new(|| split_args("channel=virtio_console")) };
+        assert_eq!(args.get("channel"), Some("virtio_console"));
+        assert_eq!(args.get("Ch"), Some("virtio_console"));
+        assert_eq!(args.get("ll"), None);
+    }
+
+    #[test]
+    fn short_args_fit() {
+        assert_eq!(fit_args("a=1 b=2", 7, ArgsOverflow::Error), Ok("a=1 b=2"));
+        assert_eq!(fit_args("a=1 b=2", 7, ArgsOverflow::Truncate), Ok("a=1 b=2"));
+    }
+
+    #[test]
+    fn long_args_are_rejected() {
+        assert!(fit_args("a=1 b=2", 6, ArgsOverflow::Error).is_err());
+    }
+
+    #[test]
+    fn long_args_are_truncated_at_whitespace() {
+        // The cut would split `b=2`, so it is dropped entirely.
+        assert_eq!(fit_args("a=1 b=2 c=3", 6, ArgsOverflow::Truncate), Ok("a=1"));
+        // The cut falls right after `b=2`, so it is kept.
+        assert_eq!(fit_args("a=1 b=2 c=3", 7, ArgsOverflow::Truncate), Ok("a=1 b=2"));
+        assert_eq!(fit_args("a=1 b=2 c=3", 8, ArgsOverflow::Truncate), Ok("a=1 b=2"));
+        // Not even the first argument fits.
+        assert_eq!(fit_args("abcdef", 3, ArgsOverflow::Truncate), Ok(""));
+        // Multi-byte characters in the partial argument.
+        assert_eq!(fit_args("a=1 b=\u{e9}\u{e9}", 7, ArgsOverflow::Truncate), Ok("a=1"));
+    }
+
+    #[test]
+    fn truncated_args_are_not_corrupted() {
+        let cmdline = "channel=serial expect_measurement=abcdef log_level=debug";
+        let kept = fit_args(cmdline, 30, ArgsOverflow::Truncate).unwrap();
+        let args = split_args(kept);
+        assert_eq!(args.get("channel").copied(), Some("serial"));
+        assert_eq!(args.get("expect_measurement"), None);
+        assert_eq!(args.len(), 1);
+    }
+
     #[test]
     fn broken_whitespace() {
         let res = split_args("one = two");
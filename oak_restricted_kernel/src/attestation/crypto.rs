@@ -0,0 +1,125 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Cryptographic primitives used to verify attestation reports.
+//!
+//! The implementation is selected at compile time: code that verifies reports
+//! is generic over [`AttestationCrypto`], and [`DefaultCrypto`] is the backend
+//! enabled via crate features.
+
+/// The size of a SHA-384 digest.
+pub const SHA384_DIGEST_SIZE: usize = 48;
+
+/// The size of a P-384 scalar, such as the `r` and `s` components of a
+/// signature.
+pub const P384_SCALAR_SIZE: usize = 48;
+
+/// The primitives needed to verify attestation reports, which are signed with
+/// ECDSA over P-384 with SHA-384.
+pub trait AttestationCrypto {
+    fn sha384(data: &[u8]) -> [u8; SHA384_DIGEST_SIZE];
+
+    /// Verifies an ECDSA P-384 signature over the SHA-384 digest of `message`.
+    ///
+    /// `public_key` is SEC1-encoded; `r` and `s` are big-endian.
+    fn verify_p384(
+        public_key: &[u8],
+        message: &[u8],
+        r: &[u8; P384_SCALAR_SIZE],
+        s: &[u8; P384_SCALAR_SIZE],
+    ) -> Result<(), &'static str>;
+}
+
+/// Backend based on the RustCrypto crates, which work in `no_std`.
+#[cfg(feature = "rust_crypto")]
+pub struct RustCrypto;
+
+#[cfg(feature = "rust_crypto")]
+impl AttestationCrypto for RustCrypto {
+    fn sha384(data: &[u8]) -> [u8; SHA384_DIGEST_SIZE] {
+        use sha2::Digest;
+        sha2::Sha384::digest(data).into()
+    }
+
+    fn verify_p384(
+        public_key: &[u8],
+        message: &[u8],
+        r: &[u8; P384_SCALAR_SIZE],
+        s: &[u8; P384_SCALAR_SIZE],
+    ) -> Result<(), &'static str> {
+        use p384::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+
+        let verifying_key =
+            VerifyingKey::from_sec1_bytes(public_key).map_err(|_| "invalid P-384 public key")?;
+        let signature = Signature::from_scalars(*r, *s).map_err(|_| "invalid P-384 signature")?;
+        verifying_key.verify(message, &signature).map_err(|_| "P-384 signature verification failed")
+    }
+}
+
+#[cfg(feature = "rust_crypto")]
+pub type DefaultCrypto = RustCrypto;
+
+#[cfg(all(test, feature = "rust_crypto"))]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "046b87ed94f6ceeefe8bbecfd07edaaecc9f9b31d454367552187d7f11a9135596018eb2\
+                              2556fa615d545b74de335579ea87f822aebd4590901d26687adc8a5efb41ed21fc7a39\
+                              f13c61dc9c4d0993154a69d4c2a8b9ef547052a91a709a250af0";
+    const MESSAGE: &[u8] = b"Oak attestation report";
+    const R: &str =
+        "ecba7611a68cca034ee7ab47461b38a6b2bf9c3e6a7608145de08dcc9e1cfb936e50964b002b3997\
+                     10f23dc51dab44a1";
+    const S: &str =
+        "0b5040a722f96004c2230fe44d33eb40273f5837cac5d4a6bdb33c571db9c05ce39a7c34722a87d4\
+                     e41633f483243b62";
+
+    fn scalar(value: &str) -> [u8; P384_SCALAR_SIZE] {
+        let mut scalar = [0; P384_SCALAR_SIZE];
+        hex::decode_to_slice(value, &mut scalar).unwrap();
+        scalar
+    }
+
+    #[test]
+    fn sha384_known_answer() {
+        assert_eq!(
+            hex::encode(RustCrypto::sha384(b"abc")),
+            "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+             8086072ba1e7cc2358baeca134c825a7"
+        );
+    }
+
+    #[test]
+    fn valid_signature_verifies() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        assert_eq!(RustCrypto::verify_p384(&public_key, MESSAGE, &scalar(R), &scalar(S)), Ok(()));
+    }
+
+    #[test]
+    fn tampered_signature_fails() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let mut s = scalar(S);
+        s[P384_SCALAR_SIZE - 1] ^= 1;
+        assert!(RustCrypto::verify_p384(&public_key, MESSAGE, &scalar(R), &s).is_err());
+        assert!(RustCrypto::verify_p384(
+            &public_key,
+            b"Oak attestation rep0rt",
+            &scalar(R),
+            &scalar(S)
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Evidence bundles: everything a verifier needs, in a single blob.
+//!
+//! A bundle combines a fresh attestation report with the certificate chain
+//! that endorses the key that signed it, the measured-boot event log and the
+//! values of the runtime measurement registers. The layout is described by
+//! `EvidenceBundleHeader` in the kernel interface, so that the payload can hand
+//! the bundle to a verifier as-is.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use oak_core::sync::OnceCell;
+use oak_restricted_kernel_interface::syscalls::{
+    EvidenceBundleHeader, EVIDENCE_BUNDLE_MAGIC, EVIDENCE_BUNDLE_VERSION,
+};
+use oak_sev_snp_attestation_report::AttestationReport;
+use zerocopy::AsBytes;
+#[cfg(test)]
+use zerocopy::FromBytes;
+
+use super::{measurement_registers, REPORT_DATA_SIZE};
+
+/// The measured-boot event log, recorded once the boot chain is known.
+static EVENT_LOG: OnceCell<Vec<u8>> = OnceCell::new();
+
+/// Records the measured-boot event log to include in evidence bundles.
+pub fn set_event_log(event_log: Vec<u8>) -> Result<(), &'static str> {
+    EVENT_LOG.set(event_log).map_err(|_| "event log already set")
+}
+
+/// An attestation report along with the evidence needed to verify it.
+pub struct EvidenceBundle {
+    pub report: AttestationReport,
+    /// The VCEK, ASK and ARK certificates; empty if not available.
+    pub cert_chain: Vec<u8>,
+    pub event_log: Vec<u8>,
+    /// The values of the measurement registers, back to back; the report's
+    /// report-data binds them (see `measurement_registers::bind_report_data`).
+    pub measurement_registers: Vec<u8>,
+}
+
+impl EvidenceBundle {
+    /// Serializes the bundle, prefixed by its header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        let size = |section: &[u8]| {
+            u32::try_from(section.len()).map_err(|_| "evidence bundle section too large")
+        };
+        let header = EvidenceBundleHeader {
+            magic: EVIDENCE_BUNDLE_MAGIC,
+            version: EVIDENCE_BUNDLE_VERSION,
+            report_size: size(self.report.as_bytes())?,
+            cert_chain_size: size(&self.cert_chain)?,
+            event_log_size: size(&self.event_log)?,
+            measurement_registers_size: size(&self.measurement_registers)?,
+            reserved: 0,
+        };
+        let sections = [
+            self.report.as_bytes(),
+            self.cert_chain.as_slice(),
+            self.event_log.as_slice(),
+            self.measurement_registers.as_slice(),
+        ];
+        let mut bytes =
+            Vec::with_capacity(header_size() + sections.iter().map(|s| s.len()).sum::<usize>());
+        for field in [
+            header.magic,
+            header.version,
+            header.report_size,
+            header.cert_chain_size,
+            header.event_log_size,
+            header.measurement_registers_size,
+            header.reserved,
+        ] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        for section in sections {
+            bytes.extend_from_slice(section);
+        }
+        Ok(bytes)
+    }
+
+    /// Parses a bundle that was serialized with `to_bytes`.
+    ///
+    /// The kernel only produces bundles, so this is only used to check the
+    /// layout in tests; verifiers bring their own parser.
+    #[cfg(test)]
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut fields = bytes
+            .get(..header_size())
+            .ok_or("evidence bundle too short for the header")?
+            .chunks_exact(size_of::<u32>())
+            .map(|field| u32::from_le_bytes(field.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        let header = EvidenceBundleHeader {
+            magic: next(),
+            version: next(),
+            report_size: next(),
+            cert_chain_size: next(),
+            event_log_size: next(),
+            measurement_registers_size: next(),
+            reserved: next(),
+        };
+        if header.magic != EVIDENCE_BUNDLE_MAGIC {
+            return Err("invalid evidence bundle magic");
+        }
+        if header.version != EVIDENCE_BUNDLE_VERSION {
+            return Err("unsupported evidence bundle version");
+        }
+        if header.report_size as usize != size_of::<AttestationReport>() {
+            return Err("invalid attestation report size in evidence bundle");
+        }
+
+        let mut rest = &bytes[header_size()..];
+        let mut take = |len: u32| {
+            let len = len as usize;
+            if rest.len() < len {
+                return Err("evidence bundle is truncated");
+            }
+            let (section, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(section)
+        };
+        let report = AttestationReport::read_from(take(header.report_size)?)
+            .ok_or("invalid attestation report in evidence bundle")?;
+        let cert_chain = take(header.cert_chain_size)?.to_vec();
+        let event_log = take(header.event_log_size)?.to_vec();
+        let measurement_registers = take(header.measurement_registers_size)?.to_vec();
+        Ok(Self { report, cert_chain, event_log, measurement_registers })
+    }
+}
+
+fn header_size() -> usize {
+    size_of::<EvidenceBundleHeader>()
+}
+
+/// Assembles an evidence bundle around an attestation report for
+/// `report_data`, bound to the current values of the measurement registers.
+///
+/// The report comes from the report cache, so asking for the same report-data
+/// again while the registers haven't changed doesn't cost a guest request.
+///
+/// Our GHCB implementation doesn't support extended guest requests yet, so
+/// the certificate chain is always empty; verifiers have to fetch the VCEK
+/// from the AMD Key Distribution Service in the meantime.
+pub fn evidence_bundle(
+    report_data: &[u8; REPORT_DATA_SIZE],
+) -> Result<EvidenceBundle, &'static str> {
+    let registers = measurement_registers::snapshot();
+    let report = super::report(&measurement_registers::bind_report_data(report_data, &registers))?;
+    Ok(EvidenceBundle {
+        report,
+        cert_chain: Vec::new(),
+        event_log: EVENT_LOG.get().cloned().unwrap_or_default(),
+        measurement_registers: registers.to_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use zerocopy::FromZeroes;
+
+    use super::*;
+
+    fn synthetic_bundle() -> EvidenceBundle {
+        let mut report = AttestationReport::new_zeroed();
+        report.data.report_data = [0x11; REPORT_DATA_SIZE];
+        report.data.measurement = [0x42; 48];
+        EvidenceBundle {
+            report,
+            cert_chain: vec![1, 2, 3],
+            event_log: vec![4, 5, 6, 7],
+            measurement_registers: vec![8; 96],
+        }
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let bundle = synthetic_bundle();
+        let bytes = bundle.to_bytes().unwrap();
+        assert_eq!(bytes.len(), header_size() + size_of::<AttestationReport>() + 3 + 4 + 96);
+        assert_eq!(&bytes[..4], b"OEVB");
+
+        let parsed = EvidenceBundle::parse(&bytes).unwrap();
+        assert_eq!(parsed.report.as_bytes(), bundle.report.as_bytes());
+        assert_eq!(parsed.cert_chain, bundle.cert_chain);
+        assert_eq!(parsed.event_log, bundle.event_log);
+        assert_eq!(parsed.measurement_registers, bundle.measurement_registers);
+    }
+
+    #[test]
+    fn empty_sections_round_trip() {
+        let bundle = EvidenceBundle {
+            cert_chain: Vec::new(),
+            event_log: Vec::new(),
+            measurement_registers: Vec::new(),
+            ..synthetic_bundle()
+        };
+        let parsed = EvidenceBundle::parse(&bundle.to_bytes().unwrap()).unwrap();
+        assert!(parsed.cert_chain.is_empty());
+        assert!(parsed.event_log.is_empty());
+        assert!(parsed.measurement_registers.is_empty());
+    }
+
+    #[test]
+    fn malformed_bundles_are_rejected() {
+        let bytes = synthetic_bundle().to_bytes().unwrap();
+        assert!(EvidenceBundle::parse(&bytes[..header_size() - 1]).is_err());
+        assert!(EvidenceBundle::parse(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        assert!(EvidenceBundle::parse(&bad_magic).is_err());
+
+        // Bundles without measurement registers used version 1.
+        let mut bad_version = bytes;
+        bad_version[4] = 1;
+        assert!(EvidenceBundle::parse(&bad_version).is_err());
+    }
+}
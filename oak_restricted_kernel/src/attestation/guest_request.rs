@@ -0,0 +1,267 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Requests to the Secure Processor using the Guest Message Protocol.
+//!
+//! Stage0 wipes VMPCK0 before handing over to the kernel, so that later boot
+//! stages can't request attestation reports for VMPL0. We use VMPCK1 instead,
+//! which means that the reports we get are for VMPL1.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use linked_list_allocator::LockedHeap;
+use oak_sev_guest::{
+    crypto::GuestMessageEncryptor,
+    guest::{AttestationRequest, AttestationResponse, GuestMessage, Message},
+};
+use oak_sev_snp_attestation_report::AttestationReport;
+use spinning_top::Spinlock;
+use x86_64::{PhysAddr, VirtAddr};
+use zerocopy::{AsBytes, FromBytes};
+
+use super::REPORT_DATA_SIZE;
+use crate::{ghcb::GHCB_PROTOCOL, mm::Translator, snp::SECRETS_PAGE, GUEST_HOST_HEAP, PAGE_TABLES};
+
+/// The VMPL that attestation reports are requested for; it must match the
+/// VMPCK we use.
+pub const REPORT_VMPL: u32 = 1;
+
+static GUEST_MESSAGE_ENCRYPTOR: Spinlock<Option<GuestMessageEncryptor>> = Spinlock::new(None);
+
+/// Initializes the guest message encryptor using VMPCK1 from the secrets page.
+pub fn init() -> Result<(), &'static str> {
+    let secrets = SECRETS_PAGE.get().ok_or("secrets page not initialized")?;
+    GUEST_MESSAGE_ENCRYPTOR.lock().replace(GuestMessageEncryptor::new(&secrets.vmpck_1[..])?);
+    Ok(())
+}
+
+/// The request and response buffers for guest messages, in memory shared with
+/// the hypervisor.
+///
+/// The buffers can be reused for any number of requests, as every request
+/// overwrites the previous contents.
+struct SharedMessages {
+    request: Box<GuestMessage, &'static LockedHeap>,
+    response: Box<GuestMessage, &'static LockedHeap>,
+    request_address: PhysAddr,
+    response_address: PhysAddr,
+}
+
+impl SharedMessages {
+    fn new() -> Result<Self, &'static str> {
+        let heap = GUEST_HOST_HEAP.get().ok_or("guest-host heap not initialized")?;
+        let request = Box::new_in(GuestMessage::new(), heap);
+        let response = Box::new_in(GuestMessage::new(), heap);
+        let (request_address, response_address) = {
+            let pt_guard = PAGE_TABLES.lock();
+            let pt = pt_guard.get().ok_or("page tables not initialized")?;
+            let translate = |message: &GuestMessage| {
+                pt.translate_virtual(VirtAddr::from_ptr(message as *const GuestMessage))
+                    .ok_or("couldn't translate guest message address")
+            };
+            (translate(&request)?, translate(&response)?)
+        };
+        Ok(Self { request, response, request_address, response_address })
+    }
+
+    /// Sends `request` to the Secure Processor and waits for the response.
+    fn send<Request, Response>(
+        &mut self,
+        encryptor: &mut GuestMessageEncryptor,
+        request: Request,
+    ) -> Result<Response, &'static str>
+    where
+        Request: AsBytes + FromBytes + Message,
+        Response: AsBytes + FromBytes + Message,
+    {
+        let (request_address, response_address) = (self.request_address, self.response_address);
+        round_trip(encryptor, request, &mut self.request, &mut self.response, |_, _| {
+            GHCB_PROTOCOL
+                .get()
+                .ok_or("GHCB not initialized")?
+                .lock()
+                .do_guest_message_request(request_address, response_address)
+        })
+    }
+}
+
+/// Encrypts `request` into `request_message`, calls `deliver` to have the
+/// Secure Processor handle it, and decrypts the response left in
+/// `response_message`.
+///
+/// The encryptor takes care of the sequence numbers: each request uses the
+/// next one, and the response must carry the one after that.
+fn round_trip<Request, Response, D>(
+    encryptor: &mut GuestMessageEncryptor,
+    request: Request,
+    request_message: &mut GuestMessage,
+    response_message: &mut GuestMessage,
+    deliver: D,
+) -> Result<Response, &'static str>
+where
+    Request: AsBytes + FromBytes + Message,
+    Response: AsBytes + FromBytes + Message,
+    D: FnOnce(&GuestMessage, &mut GuestMessage) -> Result<(), &'static str>,
+{
+    encryptor.encrypt_message(request, request_message)?;
+    deliver(request_message, response_message)?;
+    response_message.validate()?;
+    encryptor.decrypt_message::<Response>(response_message)
+}
+
+/// Requests one attestation report per entry in `report_datas`, in order,
+/// using `send` for the individual requests.
+///
+/// Stops at the first failure: the Secure Processor doesn't advance its
+/// sequence number for a request that failed, so all the following requests
+/// would be rejected anyway.
+fn request_reports_with<S>(
+    report_datas: &[[u8; REPORT_DATA_SIZE]],
+    mut send: S,
+) -> Result<Vec<AttestationReport>, &'static str>
+where
+    S: FnMut(AttestationRequest) -> Result<AttestationResponse, &'static str>,
+{
+    report_datas
+        .iter()
+        .map(|report_data| {
+            let mut request = AttestationRequest::new();
+            request.report_data = *report_data;
+            request.vmpl = REPORT_VMPL;
+            let response = send(request)?;
+            response.validate()?;
+            if response.report.data.report_data != *report_data {
+                return Err("attestation report doesn't contain the requested report-data");
+            }
+            Ok(response.report)
+        })
+        .collect()
+}
+
+/// Requests a new attestation report that includes `report_data`.
+pub fn request_report(
+    report_data: &[u8; REPORT_DATA_SIZE],
+) -> Result<AttestationReport, &'static str> {
+    let mut reports = request_reports(core::slice::from_ref(report_data))?;
+    reports.pop().ok_or("no attestation report returned")
+}
+
+/// Requests a new attestation report for each entry in `report_datas`, in
+/// order.
+///
+/// The requests are sent back-to-back, holding on to the guest message
+/// encryptor and reusing the same shared buffers for the whole batch.
+pub fn request_reports(
+    report_datas: &[[u8; REPORT_DATA_SIZE]],
+) -> Result<Vec<AttestationReport>, &'static str> {
+    let mut guard = GUEST_MESSAGE_ENCRYPTOR.lock();
+    let encryptor = guard.as_mut().ok_or("guest message encryptor is not initialized")?;
+    // The messages have to be in memory shared with the hypervisor.
+    let mut messages = SharedMessages::new()?;
+    request_reports_with(report_datas, |request| messages.send(encryptor, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use oak_sev_guest::guest::ReportStatus;
+    use oak_sev_snp_attestation_report::SigningAlgorithm;
+    use zerocopy::FromZeroes;
+
+    use super::*;
+
+    const KEY: [u8; 32] = [0x5A; 32];
+
+    /// Fake Secure Processor, which shares the key with the guest and returns a
+    /// report containing the requested report-data.
+    struct FakeSecureProcessor {
+        encryptor: GuestMessageEncryptor,
+        /// Sequence numbers of the requests and responses, in order.
+        sequence_numbers: Vec<u64>,
+    }
+
+    impl FakeSecureProcessor {
+        fn new() -> Self {
+            Self {
+                encryptor: GuestMessageEncryptor::new(&KEY).unwrap(),
+                sequence_numbers: Vec::new(),
+            }
+        }
+
+        fn handle(
+            &mut self,
+            request_message: &GuestMessage,
+            response_message: &mut GuestMessage,
+        ) -> Result<(), &'static str> {
+            self.sequence_numbers.push(request_message.header.sequence_number);
+            let request: AttestationRequest = self.encryptor.decrypt_message(request_message)?;
+            assert_eq!(request.vmpl, REPORT_VMPL);
+            let mut response = AttestationResponse::new_zeroed();
+            response.status = ReportStatus::Success as u32;
+            response.report_size = core::mem::size_of::<AttestationReport>() as u32;
+            response.report.data.signature_algo = SigningAlgorithm::EcdsaP384Sha384 as u32;
+            response.report.data.report_data = request.report_data;
+            self.encryptor.encrypt_message(response, response_message)?;
+            self.sequence_numbers.push(response_message.header.sequence_number);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn batch_uses_increasing_sequence_numbers() {
+        let mut encryptor = GuestMessageEncryptor::new(&KEY).unwrap();
+        let mut secure_processor = FakeSecureProcessor::new();
+        let mut request_message = GuestMessage::new();
+        let mut response_message = GuestMessage::new();
+        let report_datas =
+            [[1u8; REPORT_DATA_SIZE], [2u8; REPORT_DATA_SIZE], [3u8; REPORT_DATA_SIZE]];
+
+        let reports = request_reports_with(&report_datas, |request| {
+            round_trip(
+                &mut encryptor,
+                request,
+                &mut request_message,
+                &mut response_message,
+                |request, response| secure_processor.handle(request, response),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(reports.len(), report_datas.len());
+        for (report, report_data) in reports.iter().zip(report_datas.iter()) {
+            assert_eq!(report.data.report_data, *report_data);
+        }
+        assert_eq!(secure_processor.sequence_numbers, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn batch_stops_at_first_failure() {
+        let mut calls = 0;
+        let result = request_reports_with(&[[0u8; REPORT_DATA_SIZE]; 3], |_| {
+            calls += 1;
+            Err("request failed")
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn empty_batch_sends_nothing() {
+        let reports = request_reports_with(&[], |_| panic!("unexpected request")).unwrap();
+        assert!(reports.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Parsing of the SNP ID block.
+//!
+//! A VMM that launches the guest with an ID block can stage a copy of it in
+//! guest memory and pass its physical address in the `snp_id_block` kernel
+//! argument. The block contains the launch digest that the Secure Processor
+//! checked the guest against, so we can use it as the expected measurement
+//! without having to pass the digest on the command line.
+//!
+//! The staged copy comes from the VMM, so on its own it proves nothing. We
+//! only use it if the attestation report shows that the Secure Processor
+//! accepted an ID block signed with a key we trust (given as a digest in the
+//! `expect_id_key_digest` kernel argument), and if the copy lives in reserved,
+//! validated guest memory.
+//!
+//! See the ID block structure in the SEV Secure Nested Paging Firmware ABI
+//! Specification for the layout.
+
+use core::mem::size_of;
+
+use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use oak_sev_guest::msr::PageAssignment;
+use oak_sev_snp_attestation_report::AttestationReportData;
+use x86_64::PhysAddr;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use super::MEASUREMENT_SIZE;
+use crate::{args::Args, mm::Translator, snp, PAGE_TABLES};
+
+/// Name of the kernel argument that contains the physical address of the ID
+/// block, in hex prefixed with `0x`.
+pub const ID_BLOCK_ARG: &str = "snp_id_block";
+
+/// Name of the kernel argument that contains the SHA-384 digest of the key the
+/// ID block must have been signed with (or of the author key that certified
+/// it), encoded as 96 hex characters.
+pub const EXPECT_ID_KEY_DIGEST_ARG: &str = "expect_id_key_digest";
+
+/// The size of the digests of the ID and author keys in an attestation report.
+const KEY_DIGEST_SIZE: usize = 48;
+
+/// The only version of the ID block structure defined by the specification.
+pub const ID_BLOCK_VERSION: u32 = 1;
+
+/// The ID block the guest was launched with.
+#[derive(Clone, Debug, AsBytes, FromZeroes, FromBytes, PartialEq)]
+#[repr(C)]
+pub struct IdBlock {
+    /// The expected launch digest of the guest.
+    pub launch_digest: [u8; MEASUREMENT_SIZE],
+    /// Family ID of the guest, provided by the guest owner.
+    pub family_id: [u8; 16],
+    /// Image ID of the guest, provided by the guest owner.
+    pub image_id: [u8; 16],
+    /// The version of the ID block structure.
+    pub version: u32,
+    /// Security version number of the guest.
+    pub guest_svn: u32,
+    /// The policy of the guest.
+    pub policy: u64,
+}
+
+static_assertions::assert_eq_size!(IdBlock, [u8; 96]);
+
+impl IdBlock {
+    /// Parses an ID block from the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str> {
+        let block = Self::read_from_prefix(bytes).ok_or("ID block is too short")?;
+        if block.version != ID_BLOCK_VERSION {
+            return Err("unsupported ID block version");
+        }
+        Ok(block)
+    }
+
+    /// Reads the ID block staged at the address given in the `snp_id_block`
+    /// kernel argument.
+    ///
+    /// The block is only used if `report` shows that the guest was launched
+    /// with an ID block signed with the key given in the `expect_id_key_digest`
+    /// kernel argument, and if the block lies in an e820 range that is
+    /// reserved and, according to the RMP, validated guest memory. `vmpl` is
+    /// the VMPL we're running at, or `None` if SEV-SNP is not active.
+    ///
+    /// Returns `None` if the argument is missing; a malformed argument or
+    /// block, or a failed check, is logged and treated the same way.
+    pub fn from_kernel_args(
+        args: &Args,
+        memory_map: &[BootE820Entry],
+        report: &AttestationReportData,
+        vmpl: Option<u8>,
+    ) -> Option<Self> {
+        let arg = args.get(ID_BLOCK_ARG)?;
+        let result = args
+            .get(EXPECT_ID_KEY_DIGEST_ARG)
+            .ok_or("no trusted ID key digest was given")
+            .and_then(parse_key_digest)
+            .and_then(|trusted| check_signing_key(&trusted, report))
+            .and_then(|()| parse_address(arg))
+            .and_then(|addr| {
+                check_reserved(memory_map, addr)?;
+                Self::read_validated(addr, vmpl.ok_or("SEV-SNP is not active")?)
+            });
+        match result {
+            Ok(block) => {
+                log::info!("Using the launch digest from the SNP ID block");
+                Some(block)
+            }
+            Err(err) => {
+                log::warn!("Ignoring {} kernel arg: {}", ID_BLOCK_ARG, err);
+                None
+            }
+        }
+    }
+
+    /// Reads the block at `addr` through the direct mapping, after checking
+    /// that the memory is validated guest memory.
+    fn read_validated(addr: PhysAddr, vmpl: u8) -> Result<Self, &'static str> {
+        let pt_guard = PAGE_TABLES.lock();
+        let pt = pt_guard.get().ok_or("page tables not initialized")?;
+        let start = pt.translate_physical(addr).ok_or("ID block address is not mapped")?;
+        let last = pt
+            .translate_physical(addr + (size_of::<Self>() as u64 - 1))
+            .ok_or("ID block address is not mapped")?;
+        for virt_addr in [start, last] {
+            if snp::page_assignment(virt_addr, vmpl) != Some(PageAssignment::Private) {
+                return Err("ID block is not in validated guest memory");
+            }
+        }
+        // Safety: the block is in reserved memory, which the kernel never hands
+        // out, and we've checked that the memory is validated, so reading it
+        // through the direct mapping can't fault.
+        Self::parse(unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), size_of::<Self>()) })
+    }
+}
+
+/// Parses the value of the `expect_id_key_digest` kernel argument.
+fn parse_key_digest(arg: &str) -> Result<[u8; KEY_DIGEST_SIZE], &'static str> {
+    let mut digest = [0u8; KEY_DIGEST_SIZE];
+    hex::decode_to_slice(arg.trim(), &mut digest)
+        .map_err(|_| "ID key digest must be 96 hex characters")?;
+    Ok(digest)
+}
+
+/// Checks that the Secure Processor accepted an ID block signed with the
+/// `trusted` key, either directly or through the author key that certified
+/// the ID key.
+fn check_signing_key(
+    trusted: &[u8; KEY_DIGEST_SIZE],
+    report: &AttestationReportData,
+) -> Result<(), &'static str> {
+    // Without an ID block at launch the digests in the report are all zeroes.
+    if trusted.iter().all(|byte| *byte == 0) {
+        return Err("trusted ID key digest must not be all zeroes");
+    }
+    let author_key_included = report.author_key_en & 1 == 1;
+    if *trusted == report.id_key_digest
+        || (author_key_included && *trusted == report.author_key_digest)
+    {
+        Ok(())
+    } else {
+        Err("the guest was not launched with an ID block signed with the trusted key")
+    }
+}
+
+/// Checks that the block at `addr` lies entirely within a reserved e820
+/// range, so that the kernel never uses the memory for anything else.
+fn check_reserved(memory_map: &[BootE820Entry], addr: PhysAddr) -> Result<(), &'static str> {
+    let start = addr.as_u64() as usize;
+    let end = start.checked_add(size_of::<IdBlock>()).ok_or("ID block address overflows")?;
+    if memory_map.iter().any(|entry| {
+        entry.entry_type() == Some(E820EntryType::RESERVED)
+            && entry.addr() <= start
+            && end <= entry.end()
+    }) {
+        Ok(())
+    } else {
+        Err("ID block is not in reserved memory")
+    }
+}
+
+fn parse_address(value: &str) -> Result<PhysAddr, &'static str> {
+    let digits = value.strip_prefix("0x").ok_or("ID block address must be in hex")?;
+    let addr = u64::from_str_radix(digits, 16).map_err(|_| "invalid hex number")?;
+    PhysAddr::try_new(addr).map_err(|_| "ID block address is not a valid physical address")
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn synthetic_block() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&[0xAB; MEASUREMENT_SIZE]);
+        bytes.extend_from_slice(&[0x01; 16]);
+        bytes.extend_from_slice(&[0x02; 16]);
+        bytes.extend_from_slice(&ID_BLOCK_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&0x3_0000u64.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parses_id_block() {
+        let block = IdBlock::parse(&synthetic_block()).unwrap();
+        assert_eq!(block.launch_digest, [0xAB; MEASUREMENT_SIZE]);
+        assert_eq!(block.family_id, [0x01; 16]);
+        assert_eq!(block.image_id, [0x02; 16]);
+        assert_eq!(block.version, ID_BLOCK_VERSION);
+        assert_eq!(block.guest_svn, 7);
+        assert_eq!(block.policy, 0x3_0000);
+    }
+
+    #[test]
+    fn rejects_malformed_id_block() {
+        let bytes = synthetic_block();
+        assert!(IdBlock::parse(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut bytes = synthetic_block();
+        bytes[0x50] = 2;
+        assert!(IdBlock::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn checks_signing_key() {
+        let mut report = AttestationReportData::new_zeroed();
+        report.id_key_digest = [0x11; KEY_DIGEST_SIZE];
+        report.author_key_digest = [0x22; KEY_DIGEST_SIZE];
+        assert!(check_signing_key(&[0x11; KEY_DIGEST_SIZE], &report).is_ok());
+        assert!(check_signing_key(&[0x33; KEY_DIGEST_SIZE], &report).is_err());
+        // The author key digest only counts if the report says it's included.
+        assert!(check_signing_key(&[0x22; KEY_DIGEST_SIZE], &report).is_err());
+        report.author_key_en = 1;
+        assert!(check_signing_key(&[0x22; KEY_DIGEST_SIZE], &report).is_ok());
+        // Launching without an ID block leaves the digests zeroed.
+        let report = AttestationReportData::new_zeroed();
+        assert!(check_signing_key(&[0; KEY_DIGEST_SIZE], &report).is_err());
+    }
+
+    #[test]
+    fn requires_reserved_memory() {
+        let memory_map = [
+            BootE820Entry::new(0, 0x9_F000, E820EntryType::RAM),
+            BootE820Entry::new(0x9_F000, 0x1000, E820EntryType::RESERVED),
+            BootE820Entry::new(0x10_0000, 0x1000_0000, E820EntryType::RAM),
+        ];
+        assert!(check_reserved(&memory_map, PhysAddr::new(0x9_F000)).is_ok());
+        assert!(check_reserved(&memory_map, PhysAddr::new(0xA_0000 - 96)).is_ok());
+        // Partly outside the reserved range, or in RAM.
+        assert!(check_reserved(&memory_map, PhysAddr::new(0xA_0000 - 95)).is_err());
+        assert!(check_reserved(&memory_map, PhysAddr::new(0x10_0000)).is_err());
+    }
+
+    #[test]
+    fn parses_id_block_address() {
+        assert_eq!(parse_address("0x8000"), Ok(PhysAddr::new(0x8000)));
+        assert!(parse_address("8000").is_err());
+        assert!(parse_address("0xFFFFFFFFFFFFFFFF").is_err());
+    }
+}
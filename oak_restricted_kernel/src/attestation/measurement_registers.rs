@@ -0,0 +1,196 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Measurement registers that are extended at runtime, in the manner of TPM
+//! PCRs or TDX RTMRs.
+//!
+//! SEV-SNP doesn't provide such registers: the only measurement kept by the
+//! PSP is the launch measurement. The registers are instead maintained by the
+//! kernel, in guest memory, so they are only as trustworthy as the kernel that
+//! keeps them, which is covered by the launch measurement. They are attested by
+//! binding them into the report-data of the attestation report in evidence
+//! bundles; see [`bind_report_data`].
+//!
+//! The kernel extends the registers with what it loads at boot (the command
+//! line, the application images and the data ramdisk); the payload can extend
+//! [`WORKLOAD_REGISTER`] with whatever it loads later, e.g. its configuration.
+
+use alloc::vec::Vec;
+
+use oak_restricted_kernel_interface::syscalls::{
+    MEASUREMENT_REGISTER_SIZE, WORKLOAD_MEASUREMENT_REGISTER,
+};
+use sha2::{Digest, Sha384};
+use spinning_top::Spinlock;
+
+use super::{crypto::SHA384_DIGEST_SIZE, REPORT_DATA_SIZE};
+
+/// The number of measurement registers.
+pub const NUM_MEASUREMENT_REGISTERS: usize = 4;
+
+/// The register extended with the digest of each application image, in the
+/// order in which they are loaded.
+pub const APPLICATION_REGISTER: usize = 0;
+
+/// The register extended with the digest of the data ramdisk, if there is one.
+pub const RAMDISK_REGISTER: usize = 1;
+
+/// The register extended with the digest of the kernel command line, which
+/// configures the kernel.
+pub const CONFIG_REGISTER: usize = 2;
+
+/// The register that the payload extends at runtime; see
+/// `Syscall::UnstableExtendMeasurementRegister`.
+pub const WORKLOAD_REGISTER: usize = WORKLOAD_MEASUREMENT_REGISTER;
+
+static_assertions::const_assert!(WORKLOAD_REGISTER < NUM_MEASUREMENT_REGISTERS);
+static_assertions::const_assert_eq!(MEASUREMENT_REGISTER_SIZE, SHA384_DIGEST_SIZE);
+
+/// The value of a measurement register, or a digest to extend it with.
+pub type Measurement = [u8; SHA384_DIGEST_SIZE];
+
+/// A set of measurement registers, all of which start out as zeroes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MeasurementRegisters {
+    values: [Measurement; NUM_MEASUREMENT_REGISTERS],
+}
+
+impl MeasurementRegisters {
+    pub const fn new() -> Self {
+        Self { values: [[0; SHA384_DIGEST_SIZE]; NUM_MEASUREMENT_REGISTERS] }
+    }
+
+    /// Extends register `index` with `digest`, so that its new value is
+    /// `SHA-384(old value || digest)`.
+    pub fn extend(&mut self, index: usize, digest: &Measurement) -> Result<(), &'static str> {
+        let value = self.values.get_mut(index).ok_or("invalid measurement register index")?;
+        *value = Sha384::new().chain_update(&value[..]).chain_update(digest).finalize().into();
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn values(&self) -> &[Measurement; NUM_MEASUREMENT_REGISTERS] {
+        &self.values
+    }
+
+    /// Returns the values of all the registers, back to back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.values.concat()
+    }
+}
+
+impl Default for MeasurementRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static MEASUREMENT_REGISTERS: Spinlock<MeasurementRegisters> =
+    Spinlock::new(MeasurementRegisters::new());
+
+/// Extends register `index` with the SHA-384 digest of `data`.
+pub fn measure(index: usize, data: &[u8]) -> Result<(), &'static str> {
+    extend(index, &Sha384::digest(data).into())
+}
+
+/// Extends register `index` with `digest`, e.g. a digest computed by the
+/// payload.
+pub fn extend(index: usize, digest: &Measurement) -> Result<(), &'static str> {
+    MEASUREMENT_REGISTERS.lock().extend(index, digest)
+}
+
+/// Returns the current values of the registers.
+pub fn snapshot() -> MeasurementRegisters {
+    MEASUREMENT_REGISTERS.lock().clone()
+}
+
+/// Returns the report-data to request an attestation report with, so that
+/// the report covers both `report_data` and `registers`.
+///
+/// This is the SHA-384 digest of `report_data` followed by the values of the
+/// registers, padded with zeroes to the size of the report-data. A verifier
+/// recomputes it from the report-data it asked for and the register values in
+/// the evidence bundle before it can trust either.
+pub fn bind_report_data(
+    report_data: &[u8; REPORT_DATA_SIZE],
+    registers: &MeasurementRegisters,
+) -> [u8; REPORT_DATA_SIZE] {
+    let digest = Sha384::new().chain_update(report_data).chain_update(registers.to_bytes());
+    let mut bound = [0; REPORT_DATA_SIZE];
+    bound[..SHA384_DIGEST_SIZE].copy_from_slice(&digest.finalize());
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(value: &str) -> Measurement {
+        let mut measurement = [0; SHA384_DIGEST_SIZE];
+        hex::decode_to_slice(value, &mut measurement).unwrap();
+        measurement
+    }
+
+    // SHA-384("abc") and SHA-384("").
+    const ABC_DIGEST: &str = "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+                              8086072ba1e7cc2358baeca134c825a7";
+    const EMPTY_DIGEST: &str = "38b060a751ac96384cd9327eb1b1e36a21fdb71114be07434c0cc7bf63f6e1da\
+                                274edebfe76f65fbd51ad2f14898b95b";
+
+    #[test]
+    fn extend_chains_digests() {
+        let mut registers = MeasurementRegisters::new();
+        registers.extend(0, &measurement(ABC_DIGEST)).unwrap();
+        // SHA-384(zeroes || SHA-384("abc"))
+        assert_eq!(
+            hex::encode(registers.values()[0]),
+            "93732e3733514a841c982cfa75ea76ab55fe011acb9cd980ef4523913c65be1b\
+             0998e04d77f8c174f81a82151619ca40"
+        );
+        registers.extend(0, &measurement(EMPTY_DIGEST)).unwrap();
+        assert_eq!(
+            hex::encode(registers.values()[0]),
+            "a4d392030aec0188324dac645cac0391f996c9a913563092cd9d7c55c3d86c0f\
+             109053c37d2974da85660814bf359bed"
+        );
+        // The other registers are untouched.
+        assert!(registers.values()[1..].iter().all(|value| *value == [0; SHA384_DIGEST_SIZE]));
+    }
+
+    #[test]
+    fn extend_rejects_invalid_index() {
+        let mut registers = MeasurementRegisters::new();
+        assert!(registers.extend(NUM_MEASUREMENT_REGISTERS, &measurement(ABC_DIGEST)).is_err());
+        assert_eq!(registers, MeasurementRegisters::new());
+    }
+
+    #[test]
+    fn report_data_covers_registers() {
+        let mut registers = MeasurementRegisters::new();
+        registers.extend(0, &measurement(ABC_DIGEST)).unwrap();
+        registers.extend(0, &measurement(EMPTY_DIGEST)).unwrap();
+        let bound = bind_report_data(&[0x11; REPORT_DATA_SIZE], &registers);
+        assert_eq!(
+            hex::encode(&bound[..SHA384_DIGEST_SIZE]),
+            "1c84e64e25928680b14570e05cfb13d4773e859f97dda4d853cd661691c1af1b\
+             2d07d65aeca3752d86075b4f313e4697"
+        );
+        assert_eq!(bound[SHA384_DIGEST_SIZE..], [0u8; REPORT_DATA_SIZE - SHA384_DIGEST_SIZE]);
+
+        registers.extend(RAMDISK_REGISTER, &measurement(ABC_DIGEST)).unwrap();
+        assert_ne!(bind_report_data(&[0x11; REPORT_DATA_SIZE], &registers), bound);
+    }
+}
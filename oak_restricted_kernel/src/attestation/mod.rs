@@ -0,0 +1,433 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Caching of attestation reports.
+//!
+//! Requesting an attestation report from the Secure Processor requires a guest
+//! message round-trip through the hypervisor, which is expensive. Since the
+//! report is deterministic for fixed report-data within a TCB epoch we can
+//! hand out a previously generated report if the report-data matches and the
+//! report is not too old.
+//!
+//! This module also implements an optional self-check of the launch
+//! measurement reported by the Secure Processor against a digest that is known
+//! ahead of time, which lets the guest refuse to run if the VMM launched an
+//! unexpected image. The digest is either passed as a kernel argument or
+//! taken from the SNP ID block, if the VMM staged one.
+
+pub mod crypto;
+pub mod evidence;
+pub mod guest_request;
+pub mod id_block;
+pub mod measurement_registers;
+pub mod staged;
+
+use alloc::vec::Vec;
+
+use oak_core::{sync::OnceCell, timer::rdtsc};
+use oak_sev_snp_attestation_report::AttestationReport;
+#[cfg(feature = "rust_crypto")]
+use oak_sev_snp_attestation_report::SigningAlgorithm;
+use spinning_top::Spinlock;
+use zerocopy::AsBytes;
+
+#[cfg(feature = "rust_crypto")]
+use self::crypto::{AttestationCrypto, P384_SCALAR_SIZE};
+pub use self::evidence::evidence_bundle;
+use self::id_block::IdBlock;
+
+/// Name of the kernel argument that contains the expected launch measurement,
+/// encoded as 96 hex characters.
+pub const EXPECT_MEASUREMENT_ARG: &str = "expect_measurement";
+
+/// Name of the kernel argument that contains the SEC1-encoded P-384 public key
+/// of the VCEK, hex-encoded. If it is set, the signature of the attestation
+/// report from stage0 must verify against it.
+pub const VCEK_PUBLIC_KEY_ARG: &str = "vcek_public_key";
+
+/// The size of the launch measurement in an attestation report.
+pub const MEASUREMENT_SIZE: usize = 48;
+
+/// The number of bytes of custom data that can be included in the attestation
+/// report.
+pub const REPORT_DATA_SIZE: usize = 64;
+
+/// How long (in seconds) a generated report may be handed out again for the
+/// same report-data.
+const REPORT_MAX_AGE_SECS: u64 = 60;
+
+/// The most recently generated report, once the cache has been initialized.
+static REPORT_CACHE: OnceCell<Spinlock<ReportCache<AttestationReport>>> = OnceCell::new();
+
+/// A cached report along with the information needed to decide whether it can
+/// be reused.
+struct CacheEntry<R> {
+    report_data: [u8; REPORT_DATA_SIZE],
+    report: R,
+    /// The TSC value at the time the report was generated.
+    generated_at: u64,
+}
+
+/// Caches the most recently generated attestation report.
+///
+/// The cache only holds a single report: a request with report-data that
+/// differs from the cached entry always results in a new report being
+/// generated, which then replaces the cached one.
+pub struct ReportCache<R> {
+    entry: Option<CacheEntry<R>>,
+    /// The maximum age (in TSC ticks) of a cached report before it is
+    /// considered stale.
+    max_age_ticks: u64,
+}
+
+impl<R: Clone> ReportCache<R> {
+    pub const fn new(max_age_ticks: u64) -> Self {
+        Self { entry: None, max_age_ticks }
+    }
+
+    /// Returns the cached report for `report_data` if it exists and is not
+    /// stale at time `now`, otherwise calls `fetch` to generate a new report
+    /// and caches it.
+    ///
+    /// Errors returned by `fetch` are passed through and not cached.
+    pub fn get_or_fetch<F>(
+        &mut self,
+        report_data: &[u8; REPORT_DATA_SIZE],
+        now: u64,
+        fetch: F,
+    ) -> Result<R, &'static str>
+    where
+        F: FnOnce(&[u8; REPORT_DATA_SIZE]) -> Result<R, &'static str>,
+    {
+        if let Some(entry) = &self.entry {
+            // A TSC value that went backwards (e.g. after migration) is treated as stale.
+            let fresh =
+                now.checked_sub(entry.generated_at).is_some_and(|age| age <= self.max_age_ticks);
+            if fresh && entry.report_data == *report_data {
+                return Ok(entry.report.clone());
+            }
+        }
+        let report = fetch(report_data)?;
+        self.entry = Some(CacheEntry {
+            report_data: *report_data,
+            report: report.clone(),
+            generated_at: now,
+        });
+        Ok(report)
+    }
+
+    /// Drops the cached report, forcing the next request to generate a new one.
+    #[cfg(test)]
+    pub fn invalidate(&mut self) {
+        self.entry = None;
+    }
+}
+
+/// Returns a report for `report_data`, reusing a cached one from `cache` if
+/// possible.
+///
+/// The current time is read from the TSC.
+pub fn get_cached_report<R, F>(
+    cache: &Spinlock<ReportCache<R>>,
+    report_data: &[u8; REPORT_DATA_SIZE],
+    fetch: F,
+) -> Result<R, &'static str>
+where
+    R: Clone,
+    F: FnOnce(&[u8; REPORT_DATA_SIZE]) -> Result<R, &'static str>,
+{
+    cache.lock().get_or_fetch(report_data, rdtsc(), fetch)
+}
+
+/// Sets up the report cache used by [`report`], given the TSC frequency.
+pub fn init_report_cache(tsc_hz: u64) -> Result<(), &'static str> {
+    REPORT_CACHE
+        .set(Spinlock::new(ReportCache::new(tsc_hz.saturating_mul(REPORT_MAX_AGE_SECS))))
+        .map_err(|_| "report cache already initialized")
+}
+
+/// Returns an attestation report for `report_data`, reusing a recently
+/// generated one if possible.
+///
+/// Without an initialized cache every call results in a new guest request.
+pub fn report(report_data: &[u8; REPORT_DATA_SIZE]) -> Result<AttestationReport, &'static str> {
+    match REPORT_CACHE.get() {
+        Some(cache) => get_cached_report(cache, report_data, guest_request::request_report),
+        None => guest_request::request_report(report_data),
+    }
+}
+
+/// Requests a fresh attestation report for each entry in `report_datas`, in
+/// order.
+///
+/// This is faster than requesting the reports one by one, as the guest
+/// requests are sent back-to-back over the same shared buffers. The reports
+/// bypass (and don't update) any [`ReportCache`].
+pub fn get_attestation_batch(
+    report_datas: &[[u8; REPORT_DATA_SIZE]],
+) -> Result<Vec<AttestationReport>, &'static str> {
+    guest_request::request_reports(report_datas)
+}
+
+/// Parses the value of the `expect_measurement` kernel argument.
+pub fn parse_measurement(arg: &str) -> Result<[u8; MEASUREMENT_SIZE], &'static str> {
+    let mut measurement = [0u8; MEASUREMENT_SIZE];
+    hex::decode_to_slice(arg.trim(), &mut measurement)
+        .map_err(|_| "expected measurement must be 96 hex characters")?;
+    Ok(measurement)
+}
+
+/// Returns the launch measurement to check the attestation report against.
+///
+/// An explicit `expect_measurement` kernel argument takes precedence over the
+/// launch digest in the ID block. If neither is available there is nothing to
+/// check against.
+pub fn expected_measurement(
+    arg: Option<&str>,
+    id_block: Option<&IdBlock>,
+) -> Result<Option<[u8; MEASUREMENT_SIZE]>, &'static str> {
+    match (arg, id_block) {
+        (Some(arg), _) => parse_measurement(arg).map(Some),
+        (None, Some(id_block)) => Ok(Some(id_block.launch_digest)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Compares the measurement in an attestation report against the expected
+/// value.
+pub fn check_measurement(
+    expected: &[u8; MEASUREMENT_SIZE],
+    actual: &[u8; MEASUREMENT_SIZE],
+) -> Result<(), &'static str> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err("launch measurement mismatch")
+    }
+}
+
+/// Verifies that the measurement in `report` matches `expected`.
+///
+/// A mismatch means that the VMM launched a different image than the one we
+/// expected, so we refuse to continue booting.
+pub fn assert_expected_measurement(expected: &[u8; MEASUREMENT_SIZE], report: &AttestationReport) {
+    if let Err(err) = check_measurement(expected, &report.data.measurement) {
+        panic!(
+            "{}: expected {}, got {}",
+            err,
+            hex::encode(expected),
+            hex::encode(report.data.measurement)
+        );
+    }
+    log::info!("Launch measurement matches the expected value");
+}
+
+/// Verifies the signature of `report` against the VCEK public key in the
+/// `vcek_public_key` kernel argument.
+///
+/// A report that doesn't verify wasn't generated by the Secure Processor we
+/// were told to expect, so we refuse to continue booting.
+#[cfg(feature = "rust_crypto")]
+pub fn assert_report_signature(vcek_public_key_arg: &str, report: &AttestationReport) {
+    let vcek_public_key =
+        hex::decode(vcek_public_key_arg.trim()).expect("VCEK public key must be hex-encoded");
+    if let Err(err) = verify_report_signature::<crypto::DefaultCrypto>(report, &vcek_public_key) {
+        panic!("attestation report signature check failed: {}", err);
+    }
+    log::info!("Attestation report signature matches the VCEK");
+}
+
+/// Verifies the signature of `report` against the SEC1-encoded P-384 public
+/// key of the VCEK that signed it.
+#[cfg(feature = "rust_crypto")]
+pub fn verify_report_signature<C: AttestationCrypto>(
+    report: &AttestationReport,
+    vcek_public_key: &[u8],
+) -> Result<(), &'static str> {
+    if report.data.get_signature_algo() != Some(SigningAlgorithm::EcdsaP384Sha384) {
+        return Err("unsupported signature algorithm");
+    }
+    // The signature components are zero-extended little-endian integers, but the
+    // backends expect big-endian scalars.
+    let to_scalar = |value: &[u8]| {
+        let mut scalar = [0u8; P384_SCALAR_SIZE];
+        scalar.copy_from_slice(&value[..P384_SCALAR_SIZE]);
+        scalar.reverse();
+        scalar
+    };
+    C::verify_p384(
+        vcek_public_key,
+        report.data.as_bytes(),
+        &to_scalar(&report.signature.r),
+        &to_scalar(&report.signature.s),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    const MAX_AGE: u64 = 1000;
+
+    fn fetch_counting(
+        calls: &Cell<usize>,
+    ) -> impl FnOnce(&[u8; REPORT_DATA_SIZE]) -> Result<[u8; REPORT_DATA_SIZE], &'static str> + '_
+    {
+        |report_data| {
+            calls.set(calls.get() + 1);
+            Ok(*report_data)
+        }
+    }
+
+    #[test]
+    fn matching_report_data_hits_cache() {
+        let calls = Cell::new(0);
+        let mut cache = ReportCache::new(MAX_AGE);
+        let report_data = [1u8; REPORT_DATA_SIZE];
+
+        assert_eq!(cache.get_or_fetch(&report_data, 0, fetch_counting(&calls)), Ok(report_data));
+        assert_eq!(cache.get_or_fetch(&report_data, 10, fetch_counting(&calls)), Ok(report_data));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn differing_report_data_misses_cache() {
+        let calls = Cell::new(0);
+        let mut cache = ReportCache::new(MAX_AGE);
+        let first = [1u8; REPORT_DATA_SIZE];
+        let second = [2u8; REPORT_DATA_SIZE];
+
+        assert_eq!(cache.get_or_fetch(&first, 0, fetch_counting(&calls)), Ok(first));
+        assert_eq!(cache.get_or_fetch(&second, 10, fetch_counting(&calls)), Ok(second));
+        assert_eq!(calls.get(), 2);
+        // The second report replaced the first one.
+        assert_eq!(cache.get_or_fetch(&first, 20, fetch_counting(&calls)), Ok(first));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn stale_report_is_regenerated() {
+        let calls = Cell::new(0);
+        let mut cache = ReportCache::new(MAX_AGE);
+        let report_data = [1u8; REPORT_DATA_SIZE];
+
+        cache.get_or_fetch(&report_data, 0, fetch_counting(&calls)).unwrap();
+        cache.get_or_fetch(&report_data, MAX_AGE, fetch_counting(&calls)).unwrap();
+        assert_eq!(calls.get(), 1);
+        cache.get_or_fetch(&report_data, MAX_AGE + 1, fetch_counting(&calls)).unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let calls = Cell::new(0);
+        let mut cache = ReportCache::new(MAX_AGE);
+        let report_data = [1u8; REPORT_DATA_SIZE];
+
+        assert!(cache.get_or_fetch(&report_data, 0, |_| Err("failed")).is_err());
+        assert_eq!(cache.get_or_fetch(&report_data, 0, fetch_counting(&calls)), Ok(report_data));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn invalidate_forces_new_report() {
+        let calls = Cell::new(0);
+        let mut cache = ReportCache::new(MAX_AGE);
+        let report_data = [1u8; REPORT_DATA_SIZE];
+
+        cache.get_or_fetch(&report_data, 0, fetch_counting(&calls)).unwrap();
+        cache.invalidate();
+        cache.get_or_fetch(&report_data, 0, fetch_counting(&calls)).unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn matching_measurement_passes() {
+        let expected = [0xAB; MEASUREMENT_SIZE];
+        assert_eq!(check_measurement(&expected, &[0xAB; MEASUREMENT_SIZE]), Ok(()));
+    }
+
+    #[test]
+    fn mismatching_measurement_fails() {
+        let expected = [0xAB; MEASUREMENT_SIZE];
+        let mut actual = expected;
+        actual[MEASUREMENT_SIZE - 1] ^= 1;
+        assert!(check_measurement(&expected, &actual).is_err());
+    }
+
+    #[test]
+    fn parses_measurement_arg() {
+        let arg = "ab".repeat(MEASUREMENT_SIZE);
+        assert_eq!(parse_measurement(&arg), Ok([0xAB; MEASUREMENT_SIZE]));
+        assert!(parse_measurement("abcd").is_err());
+        assert!(parse_measurement(&"zz".repeat(MEASUREMENT_SIZE)).is_err());
+    }
+
+    #[test]
+    fn expected_measurement_defaults_to_id_block() {
+        let id_block = IdBlock {
+            launch_digest: [0xCD; MEASUREMENT_SIZE],
+            family_id: [0; 16],
+            image_id: [0; 16],
+            version: id_block::ID_BLOCK_VERSION,
+            guest_svn: 0,
+            policy: 0,
+        };
+        let arg = "ab".repeat(MEASUREMENT_SIZE);
+
+        assert_eq!(expected_measurement(None, None), Ok(None));
+        assert_eq!(expected_measurement(None, Some(&id_block)), Ok(Some([0xCD; MEASUREMENT_SIZE])));
+        assert_eq!(
+            expected_measurement(Some(&arg), Some(&id_block)),
+            Ok(Some([0xAB; MEASUREMENT_SIZE]))
+        );
+        assert!(expected_measurement(Some("abcd"), Some(&id_block)).is_err());
+    }
+
+    #[cfg(feature = "rust_crypto")]
+    #[test]
+    fn report_signature_verifies_against_vcek() {
+        use p384::ecdsa::{signature::Signer, Signature, SigningKey};
+        use zerocopy::FromZeroes;
+
+        let vcek = SigningKey::from_slice(&[0x01; P384_SCALAR_SIZE]).unwrap();
+        let vcek_public_key = vcek.verifying_key().to_encoded_point(false);
+        let mut report = AttestationReport::new_zeroed();
+        report.data.signature_algo = SigningAlgorithm::EcdsaP384Sha384 as u32;
+        report.data.measurement = [0x42; MEASUREMENT_SIZE];
+        let signature: Signature = vcek.sign(report.data.as_bytes());
+        // The report stores the components as little-endian integers.
+        let (r, s) = signature.split_bytes();
+        report.signature.r[..P384_SCALAR_SIZE].copy_from_slice(&r);
+        report.signature.r[..P384_SCALAR_SIZE].reverse();
+        report.signature.s[..P384_SCALAR_SIZE].copy_from_slice(&s);
+        report.signature.s[..P384_SCALAR_SIZE].reverse();
+
+        assert_eq!(
+            verify_report_signature::<crypto::DefaultCrypto>(&report, vcek_public_key.as_bytes()),
+            Ok(())
+        );
+
+        report.data.measurement[0] ^= 1;
+        assert!(verify_report_signature::<crypto::DefaultCrypto>(
+            &report,
+            vcek_public_key.as_bytes()
+        )
+        .is_err());
+    }
+}
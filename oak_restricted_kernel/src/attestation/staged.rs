@@ -0,0 +1,176 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Attestation report generated at boot and staged for the application.
+//!
+//! Most applications need an attestation report right after they start, so we
+//! request one while booting and map it read-only into the application at
+//! `ATTESTATION_REPORT_ADDR`, announced in the auxiliary vector as
+//! `AT_OAK_ATTESTATION_REPORT`. The report-data binds the key the kernel hands
+//! to the application: the first 32 bytes are the SHA2-256 digest of the
+//! certificate for that key, the rest is zero.
+
+use core::mem::size_of;
+
+use oak_core::sync::OnceCell;
+use oak_crypto::noise_handshake::sha256;
+use oak_restricted_kernel_interface::ATTESTATION_REPORT_ADDR;
+use oak_sev_snp_attestation_report::AttestationReport;
+use x86_64::{
+    structures::paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB},
+    VirtAddr,
+};
+use zerocopy::AsBytes;
+
+use super::REPORT_DATA_SIZE;
+use crate::{
+    mm::{self, Translator},
+    FRAME_ALLOCATOR, PAGE_TABLES,
+};
+
+/// Frame holding the staged report, if there is one.
+static STAGED_REPORT: OnceCell<PhysFrame<Size2MiB>> = OnceCell::new();
+
+/// Computes the report-data that binds the key certified by `certificate`.
+pub fn report_data_for_certificate(certificate: &[u8]) -> [u8; REPORT_DATA_SIZE] {
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    let digest = sha256(certificate);
+    report_data[..digest.len()].copy_from_slice(&digest);
+    report_data
+}
+
+/// Requests a report for `report_data` with `fetch`, and writes it to the
+/// start of `page`, zeroing the rest of the page.
+///
+/// The page is mapped into the application as a whole, so nothing but the
+/// report may be left in it.
+fn stage_into<F>(
+    page: &mut [u8],
+    report_data: &[u8; REPORT_DATA_SIZE],
+    fetch: F,
+) -> Result<(), &'static str>
+where
+    F: FnOnce(&[u8; REPORT_DATA_SIZE]) -> Result<AttestationReport, &'static str>,
+{
+    let report = fetch(report_data)?;
+    if page.len() < size_of::<AttestationReport>() {
+        return Err("page too small for the attestation report");
+    }
+    let (staged, rest) = page.split_at_mut(size_of::<AttestationReport>());
+    staged.copy_from_slice(report.as_bytes());
+    rest.fill(0);
+    Ok(())
+}
+
+/// Requests a report for `report_data` with `fetch`, and stages it for the
+/// application.
+pub fn stage<F>(report_data: &[u8; REPORT_DATA_SIZE], fetch: F) -> Result<(), &'static str>
+where
+    F: FnOnce(&[u8; REPORT_DATA_SIZE]) -> Result<AttestationReport, &'static str>,
+{
+    if STAGED_REPORT.get().is_some() {
+        return Err("attestation report already staged");
+    }
+    let frame: PhysFrame<Size2MiB> = FRAME_ALLOCATOR
+        .lock()
+        .allocate_frame()
+        .ok_or("couldn't allocate a frame for the attestation report")?;
+    let page = PAGE_TABLES
+        .lock()
+        .get()
+        .and_then(|pt| pt.translate_physical_frame(frame))
+        .ok_or("couldn't translate the attestation report frame")?;
+    // Safety: we've just allocated the frame, so nobody else has a reference to
+    // it, and the direct mapping makes it accessible to us.
+    let page = unsafe {
+        core::slice::from_raw_parts_mut(
+            page.start_address().as_mut_ptr::<u8>(),
+            Size2MiB::SIZE as usize,
+        )
+    };
+    stage_into(page, report_data, fetch)?;
+    STAGED_REPORT.set(frame).map_err(|_| "attestation report already staged")
+}
+
+/// Maps the staged report into the address space of the current process.
+///
+/// Returns whether a report was mapped; if no report was staged, there's
+/// nothing to do.
+pub fn map_into_current_process() -> Result<bool, &'static str> {
+    match STAGED_REPORT.get() {
+        Some(frame) => {
+            mm::map_user_read_only(VirtAddr::new(ATTESTATION_REPORT_ADDR), *frame)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::cell::Cell;
+
+    use zerocopy::{FromBytes, FromZeroes};
+
+    use super::*;
+
+    /// Fake Secure Processor that returns a report containing the requested
+    /// report-data.
+    fn fake_report(
+        report_data: &[u8; REPORT_DATA_SIZE],
+    ) -> Result<AttestationReport, &'static str> {
+        let mut report = AttestationReport::new_zeroed();
+        report.data.report_data = *report_data;
+        report.data.measurement = [0x42; 48];
+        Ok(report)
+    }
+
+    #[test]
+    fn staged_report_is_requested_report() {
+        let report_data = report_data_for_certificate(b"certificate");
+        let requested = Cell::new(None);
+        // Stale data left in the page must not reach the application.
+        let mut page = vec![0xFFu8; 4096];
+        stage_into(&mut page, &report_data, |report_data| {
+            requested.set(Some(*report_data));
+            fake_report(report_data)
+        })
+        .unwrap();
+
+        assert_eq!(requested.get(), Some(report_data));
+        let staged = AttestationReport::read_from_prefix(&page[..]).unwrap();
+        assert_eq!(staged.data.report_data, report_data);
+        assert_eq!(staged.data.measurement, [0x42; 48]);
+        assert!(page[size_of::<AttestationReport>()..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn report_data_binds_certificate() {
+        let report_data = report_data_for_certificate(b"certificate");
+        assert_eq!(&report_data[..32], &sha256(b"certificate"));
+        assert_eq!(&report_data[32..], &[0u8; 32]);
+        assert_ne!(report_data, report_data_for_certificate(b"other certificate"));
+    }
+
+    #[test]
+    fn stage_into_propagates_errors() {
+        let mut page = vec![0u8; 4096];
+        assert!(stage_into(&mut page, &[0; REPORT_DATA_SIZE], |_| Err("no report")).is_err());
+        let mut page = vec![0u8; 16];
+        assert!(stage_into(&mut page, &[0; REPORT_DATA_SIZE], fake_report).is_err());
+    }
+}
@@ -14,7 +14,10 @@
 // limitations under the License.
 //
 
-use core::arch::asm;
+use core::arch::{
+    asm,
+    x86_64::{__cpuid, __cpuid_count},
+};
 
 use x86_64::registers::{
     control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
@@ -22,6 +25,58 @@ use x86_64::registers::{
     xcontrol::{XCr0, XCr0Flags},
 };
 
+/// CPUID leaf enumerating the processor extended state (XSAVE) components.
+const CPUID_EXTENDED_STATE: u32 = 0xD;
+
+/// Size of the legacy region (x87 and SSE state) plus the XSAVE header, which
+/// is the smallest valid XSAVE area.
+const XSAVE_MIN_SIZE: u32 = 512 + 64;
+
+/// Largest XSAVE area we're willing to put on the syscall stack, which is only
+/// a page in size. This is enough for the x87, SSE and AVX state we enable.
+const XSAVE_MAX_SIZE: u32 = 1024;
+
+/// Required alignment of an XSAVE area.
+pub const XSAVE_ALIGNMENT: u64 = 64;
+
+/// Computes the number of bytes to reserve for an XSAVE area from the CPUID
+/// extended state leaf.
+///
+/// `ebx` is EBX of CPUID leaf 0xD, sub-leaf 0, which reports the size of the
+/// XSAVE area required by the state components currently enabled in XCR0.
+///
+/// See Intel 64 and IA-32 Architectures Software Developer's Manual, Volume 1,
+/// Section 13.2 for more details.
+fn xsave_area_size_from_cpuid(max_leaf: u32, ebx: u32) -> Result<u64, &'static str> {
+    if max_leaf < CPUID_EXTENDED_STATE {
+        return Err("CPUID does not enumerate the XSAVE components");
+    }
+    if ebx < XSAVE_MIN_SIZE {
+        return Err("XSAVE area size reported by CPUID is too small");
+    }
+    if ebx > XSAVE_MAX_SIZE {
+        return Err("XSAVE area size reported by CPUID is too large for the syscall stack");
+    }
+    Ok(u64::from(ebx).next_multiple_of(XSAVE_ALIGNMENT))
+}
+
+/// Returns the number of bytes to reserve for saving the extended register
+/// state of the payload with `XSAVE`.
+///
+/// This must be called after `enable_avx`, as the size depends on the state
+/// components enabled in XCR0.
+///
+/// Only the syscall entry point needs this: the interrupt and exception
+/// handlers use the `x86-interrupt` calling convention, under which the
+/// compiler preserves all the vector registers the handler (or anything it
+/// calls) may clobber.
+pub fn xsave_area_size() -> Result<u64, &'static str> {
+    // Safety: CPUID is available on all x86-64 CPUs, and we check the maximum leaf
+    // before relying on the result of the extended state leaf.
+    let (max_leaf, ebx) = unsafe { (__cpuid(0).eax, __cpuid_count(CPUID_EXTENDED_STATE, 0).ebx) };
+    xsave_area_size_from_cpuid(max_leaf, ebx)
+}
+
 /// Enables Streaming SIMD Extensions (SEE) and Advanced Vector Extensions
 /// (AVX).
 ///
@@ -56,3 +111,30 @@ pub fn enable_avx() {
         write(mxcsr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xsave_area_size_x87_sse_avx() {
+        // 512 bytes of legacy state, 64 bytes of header and 256 bytes for the upper
+        // halves of YMM0-15.
+        assert_eq!(xsave_area_size_from_cpuid(0x10, 832), Ok(832));
+    }
+
+    #[test]
+    fn xsave_area_size_rounds_up_to_alignment() {
+        assert_eq!(xsave_area_size_from_cpuid(0x10, 840), Ok(896));
+        assert_eq!(xsave_area_size_from_cpuid(0xD, XSAVE_MIN_SIZE), Ok(576));
+    }
+
+    #[test]
+    fn xsave_area_size_invalid() {
+        assert!(xsave_area_size_from_cpuid(0xC, 832).is_err());
+        assert!(xsave_area_size_from_cpuid(0x10, 0).is_err());
+        assert!(xsave_area_size_from_cpuid(0x10, 512).is_err());
+        // AVX-512 state doesn't fit on the syscall stack.
+        assert!(xsave_area_size_from_cpuid(0x10, 2696).is_err());
+    }
+}
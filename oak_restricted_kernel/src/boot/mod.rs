@@ -14,6 +14,212 @@
 // limitations under the License.
 //
 
-use core::arch::global_asm;
+use core::{arch::global_asm, ffi::CStr};
+
+use log::info;
+use oak_linux_boot_params::{BootE820Entry, BootParams, CCBlobSevInfo, Ramdisk};
+
+use crate::{args, snp};
 
 global_asm!(include_str!("boot.s"), options(att_syntax, raw));
+
+/// Machine information handed to the kernel by the bootloader.
+///
+/// Different boot protocols (Linux boot protocol, PVH, multiboot2...) provide
+/// this information in different data structures; this trait exposes the parts
+/// of it that the kernel core relies on, regardless of how they were provided.
+///
+/// Every protocol has to provide the command line and the memory map. The
+/// other pieces of information are optional: `start_kernel` uses the ramdisk,
+/// the ACPI RSDP and (under SEV-SNP) the CC blob if the bootloader passed them
+/// in, so they are part of the trait too, but default to `None`.
+pub trait Protocol {
+    /// Kernel command line.
+    fn args(&self) -> &CStr;
+
+    /// Human-readable name of the boot protocol that was used.
+    fn protocol(&self) -> &'static str;
+
+    /// Physical memory map, in the E820 format.
+    fn e820_table(&self) -> &[BootE820Entry];
+
+    /// Location of the initial ramdisk loaded by the bootloader, if any.
+    fn ramdisk(&self) -> Option<Ramdisk> {
+        None
+    }
+
+    /// Physical address of the ACPI RSDP, if the bootloader passed it in.
+    fn acpi_rsdp_addr(&self) -> Option<u64> {
+        None
+    }
+
+    /// Location of the SEV-SNP `CCBlobSevInfo` structure, if the bootloader
+    /// passed one in.
+    ///
+    /// Only valid while the identity mapping set up by the firmware is still
+    /// in place.
+    fn cc_blob(&self) -> Option<*const CCBlobSevInfo> {
+        None
+    }
+}
+
+impl Protocol for BootParams {
+    fn args(&self) -> &CStr {
+        BootParams::args(self)
+    }
+
+    fn protocol(&self) -> &'static str {
+        BootParams::protocol(self)
+    }
+
+    fn e820_table(&self) -> &[BootE820Entry] {
+        BootParams::e820_table(self)
+    }
+
+    fn ramdisk(&self) -> Option<Ramdisk> {
+        BootParams::ramdisk(self)
+    }
+
+    fn acpi_rsdp_addr(&self) -> Option<u64> {
+        let addr = self.acpi_rsdp_addr;
+        (addr > 0).then_some(addr)
+    }
+
+    fn cc_blob(&self) -> Option<*const CCBlobSevInfo> {
+        snp::find_cc_blob(self)
+    }
+}
+
+/// Caches the kernel arguments from the boot info structure and logs some basic
+/// information about how we were booted.
+///
+/// This needs to be called before memory is initialized, as the boot info
+/// structure may be overwritten afterwards.
+pub fn init_args(info: &dyn Protocol) -> Result<args::Args, &'static str> {
+    // Dropping arguments could silently disable checks such as the expected
+    // measurement, so refuse to boot with a command line that doesn't fit.
+    let kernel_args = args::init_args(info.args(), args::ArgsOverflow::Error)?;
+    info!("Kernel boot args: {}", args::describe(kernel_args.args()));
+    info!("Boot protocol:  {}", info.protocol());
+    Ok(kernel_args)
+}
+
+#[cfg(test)]
+mod tests {
+    use oak_linux_boot_params::E820EntryType;
+    use x86_64::{
+        structures::paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB},
+        PhysAddr,
+    };
+
+    use super::*;
+    use crate::mm::{self, frame_allocator::PhysicalMemoryAllocator};
+
+    struct MockProtocol {
+        e820_table: [BootE820Entry; 2],
+        /// Address and size of the ramdisk, if any.
+        ramdisk: Option<(u32, u32)>,
+    }
+
+    impl Protocol for MockProtocol {
+        fn args(&self) -> &CStr {
+            CStr::from_bytes_with_nul(b"channel=serial --debug\0").unwrap()
+        }
+
+        fn protocol(&self) -> &'static str {
+            "Mock"
+        }
+
+        fn e820_table(&self) -> &[BootE820Entry] {
+            &self.e820_table
+        }
+
+        fn ramdisk(&self) -> Option<Ramdisk> {
+            self.ramdisk.map(|(addr, size)| Ramdisk { addr, size })
+        }
+    }
+
+    fn frame(index: u64) -> PhysFrame<Size2MiB> {
+        PhysFrame::from_start_address(PhysAddr::new(index * Size2MiB::SIZE)).unwrap()
+    }
+
+    #[test]
+    fn mock_protocol() {
+        let info = MockProtocol {
+            e820_table: [
+                BootE820Entry::new(0, 0xA_0000, E820EntryType::RAM),
+                BootE820Entry::new(0x10_0000, 0x1000_0000, E820EntryType::RAM),
+            ],
+            ramdisk: Some((0x410_0000, 0x20_0000)),
+        };
+        let info: &dyn Protocol = &info;
+
+        let kernel_args = init_args(info).unwrap();
+        assert_eq!(kernel_args.args(), "channel=serial --debug");
+        assert_eq!(kernel_args.get("channel"), Some("serial"));
+        assert_eq!(kernel_args.get("--debug"), Some(""));
+        assert_eq!(info.protocol(), "Mock");
+        assert_eq!(info.e820_table().len(), 2);
+        assert_eq!(info.e820_table()[1].end(), 0x1010_0000);
+        assert_eq!(info.acpi_rsdp_addr(), None);
+        assert!(info.cc_blob().is_none());
+
+        // Set up the physical memory the same way `start_kernel` does.
+        let mut allocator = PhysicalMemoryAllocator::<8>::new();
+        mm::init_frame_allocator(&mut allocator, info.e820_table(), &[], info.ramdisk().as_ref());
+        // Only the second entry holds whole frames, [1, 128), as it starts and ends at
+        // 1 MiB offsets. The ramdisk occupies the frames 32 and 33.
+        assert_eq!(allocator.num_valid_frames(), (125, 0));
+        let frames: alloc::vec::Vec<_> =
+            core::iter::from_fn(|| allocator.allocate_frame()).collect();
+        assert_eq!(frames.len(), 125);
+        assert_eq!(frames.first(), Some(&frame(1)));
+        assert_eq!(frames.last(), Some(&frame(127)));
+        assert!(!frames.iter().any(|f| (frame(32)..frame(34)).contains(f)));
+    }
+
+    #[test]
+    fn defaults() {
+        struct Minimal;
+
+        impl Protocol for Minimal {
+            fn args(&self) -> &CStr {
+                CStr::from_bytes_with_nul(b"\0").unwrap()
+            }
+
+            fn protocol(&self) -> &'static str {
+                "Minimal"
+            }
+
+            fn e820_table(&self) -> &[BootE820Entry] {
+                &[]
+            }
+        }
+
+        // Only the command line and the memory map are required.
+        assert!(Minimal.ramdisk().is_none());
+        assert_eq!(Minimal.acpi_rsdp_addr(), None);
+        assert!(Minimal.cc_blob().is_none());
+    }
+
+    #[test]
+    fn boot_params_ramdisk() {
+        let mut params = BootParams::zeroed();
+        assert!(Protocol::ramdisk(&params).is_none());
+
+        params.hdr.ramdisk_image = 0x3F0_0000;
+        params.hdr.ramdisk_size = 0x12_3456;
+        let ramdisk = Protocol::ramdisk(&params).unwrap();
+        assert_eq!(ramdisk.addr, 0x3F0_0000);
+        assert_eq!(ramdisk.size, 0x12_3456);
+    }
+
+    #[test]
+    fn boot_params_acpi_rsdp_addr() {
+        let mut params = BootParams::zeroed();
+        assert_eq!(Protocol::acpi_rsdp_addr(&params), None);
+
+        params.acpi_rsdp_addr = 0xF_5A00;
+        assert_eq!(Protocol::acpi_rsdp_addr(&params), Some(0xF_5A00));
+    }
+}
@@ -0,0 +1,312 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! TSC-based timekeeping.
+//!
+//! The TSC frequency is determined from one of the following sources, in
+//! order of preference:
+//!   1. the `tsc_freq_hz` kernel argument;
+//!   2. CPUID leaf 0x15 (Time Stamp Counter and Core Crystal Clock
+//!      Information);
+//!   3. calibration against the PIT, unless the `no_pit` kernel argument is
+//!      present;
+//!   4. the processor base frequency reported by CPUID leaf 0x16, which is only
+//!      an approximation of the TSC frequency.
+//!
+//! Under SEV-SNP the PIT is emulated by the (untrusted) host, so it may be
+//! desirable to forbid calibrating against it.
+
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+use oak_core::{sync::OnceCell, timer::rdtsc};
+use oak_sev_guest::{
+    io::{IoPortFactory, PortFactoryWrapper, PortReader, PortWrapper, PortWriter},
+    msr::SevStatus,
+};
+
+use crate::args::Args;
+
+/// Frequency of the PIT input clock.
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+/// How long we calibrate the TSC against the PIT for: 1/CALIBRATION_DIVISOR
+/// seconds.
+const CALIBRATION_DIVISOR: u64 = 100;
+
+/// Upper bound for the TSC frequency we're willing to believe in. Calibration
+/// is abandoned once the TSC has advanced by more than the number of ticks that
+/// corresponds to at this frequency.
+const MAX_TSC_FREQUENCY_HZ: u64 = 20_000_000_000;
+
+/// Maximum number of times we poll the PIT during calibration, in case the TSC
+/// does not advance (so the TSC deadline would never be reached).
+const MAX_CALIBRATION_POLLS: u64 = 10_000_000;
+
+/// The TSC frequency, once it has been determined.
+static TSC_FREQUENCY: OnceCell<TscFrequency> = OnceCell::new();
+
+/// Source of the TSC frequency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TscFrequencySource {
+    /// Explicitly set using the `tsc_freq_hz` kernel argument.
+    KernelArg,
+    /// Reported by CPUID leaf 0x15.
+    Cpuid,
+    /// Calibrated against the PIT.
+    Pit,
+    /// Processor base frequency reported by CPUID leaf 0x16.
+    CpuidBaseFrequency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TscFrequency {
+    pub hz: u64,
+    pub source: TscFrequencySource,
+}
+
+/// Picks the TSC frequency from the available sources, in order of preference.
+///
+/// `calibrate` is only invoked if neither an explicit frequency nor CPUID
+/// information is available, and falling back to the PIT is allowed. If
+/// calibration is not allowed or fails, the processor base frequency is used,
+/// if it is known.
+fn select_tsc_frequency<F: FnOnce() -> Result<u64, &'static str>>(
+    arg_hz: Option<u64>,
+    cpuid_hz: Option<u64>,
+    cpuid_base_hz: Option<u64>,
+    allow_pit: bool,
+    calibrate: F,
+) -> Result<TscFrequency, &'static str> {
+    if let Some(hz) = arg_hz {
+        return Ok(TscFrequency { hz, source: TscFrequencySource::KernelArg });
+    }
+    if let Some(hz) = cpuid_hz {
+        return Ok(TscFrequency { hz, source: TscFrequencySource::Cpuid });
+    }
+    let calibrated = if allow_pit {
+        calibrate()
+    } else {
+        Err("TSC frequency not available and calibration against the PIT is disabled")
+    };
+    match (calibrated, cpuid_base_hz) {
+        (Ok(hz), _) => Ok(TscFrequency { hz, source: TscFrequencySource::Pit }),
+        (Err(_), Some(hz)) => {
+            Ok(TscFrequency { hz, source: TscFrequencySource::CpuidBaseFrequency })
+        }
+        (Err(err), None) => Err(err),
+    }
+}
+
+/// Computes the TSC frequency from CPUID leaf 0x15.
+///
+/// Returns `None` if the leaf is not supported or does not enumerate the
+/// frequency.
+fn tsc_frequency_from_cpuid(max_leaf: u32, leaf: CpuidResult) -> Option<u64> {
+    if max_leaf < 0x15 || leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    // EAX: denominator of the TSC/crystal clock ratio; EBX: numerator; ECX: core
+    // crystal clock frequency in Hz.
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+/// Computes the processor base frequency from CPUID leaf 0x16.
+///
+/// Returns `None` if the leaf is not supported or does not enumerate the
+/// frequency.
+fn base_frequency_from_cpuid(max_leaf: u32, leaf: CpuidResult) -> Option<u64> {
+    // EAX[15:0]: processor base frequency in MHz.
+    let mhz = (leaf.eax & 0xFFFF) as u64;
+    if max_leaf < 0x16 || mhz == 0 {
+        return None;
+    }
+    Some(mhz * 1_000_000)
+}
+
+fn cpuid_base_frequency() -> Option<u64> {
+    // Safety: CPUID is available on all x86-64 CPUs.
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 0x16 {
+        return None;
+    }
+    // Safety: we've just checked that the leaf is supported.
+    base_frequency_from_cpuid(max_leaf, unsafe { __cpuid(0x16) })
+}
+
+fn cpuid_tsc_frequency() -> Option<u64> {
+    // Safety: CPUID is available on all x86-64 CPUs.
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 0x15 {
+        return None;
+    }
+    // Safety: we've just checked that the leaf is supported.
+    tsc_frequency_from_cpuid(max_leaf, unsafe { __cpuid(0x15) })
+}
+
+/// Measures the TSC frequency by counting TSC ticks while PIT channel 2 counts
+/// down.
+///
+/// # Safety
+///
+/// This uses raw I/O port access to talk to the PIT; the caller has to
+/// guarantee there will not be any adverse effect if there's no PIT at those
+/// ports.
+unsafe fn calibrate_against_pit(sev_status: SevStatus) -> Result<u64, &'static str> {
+    let factory = if sev_status.contains(SevStatus::SEV_ES_ENABLED) {
+        crate::ghcb::get_ghcb_port_factory()
+    } else {
+        PortFactoryWrapper::new_raw()
+    };
+    let mut gate: PortWrapper<u8> = factory.new_reader(0x61);
+    let mut gate_writer: PortWrapper<u8> = factory.new_writer(0x61);
+    let mut command: PortWrapper<u8> = factory.new_writer(0x43);
+    let mut channel2: PortWrapper<u8> = factory.new_writer(0x42);
+
+    // Enable the gate for channel 2, but disable the speaker output.
+    let value = gate.try_read()?;
+    gate_writer.try_write((value & !0x02) | 0x01)?;
+
+    // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+    command.try_write(0b1011_0000)?;
+    let count = PIT_FREQUENCY_HZ / CALIBRATION_DIVISOR;
+    channel2.try_write(count as u8)?;
+    channel2.try_write((count >> 8) as u8)?;
+
+    let start = rdtsc();
+    let deadline = start.saturating_add(MAX_TSC_FREQUENCY_HZ / CALIBRATION_DIVISOR);
+    // Bit 5 of port 0x61 reflects the channel 2 output, which goes high once the
+    // count reaches zero. If there is no PIT (or the host doesn't emulate it
+    // faithfully), that may never happen.
+    let mut polls = 0;
+    while gate.try_read()? & 0x20 == 0 {
+        polls += 1;
+        if polls >= MAX_CALIBRATION_POLLS || rdtsc() > deadline {
+            return Err("PIT calibration timed out");
+        }
+        core::hint::spin_loop();
+    }
+    let ticks = rdtsc() - start;
+    if ticks == 0 {
+        return Err("TSC did not advance during PIT calibration");
+    }
+
+    Ok(ticks * CALIBRATION_DIVISOR)
+}
+
+/// Determines the TSC frequency based on the kernel arguments and the
+/// capabilities of the machine.
+pub fn init(kernel_args: &Args, sev_status: SevStatus) -> Result<TscFrequency, &'static str> {
+    let arg_hz = kernel_args
+        .get("tsc_freq_hz")
+        .map(|hz| hz.parse::<u64>().map_err(|_| "invalid value for tsc_freq_hz"))
+        .transpose()?;
+    let allow_pit = kernel_args.get("no_pit").is_none();
+
+    // Safety: the PIT is part of the standard PC platform; if the caller doesn't
+    // trust it to be there, they can disable calibration with `no_pit`.
+    let frequency = select_tsc_frequency(
+        arg_hz,
+        cpuid_tsc_frequency(),
+        cpuid_base_frequency(),
+        allow_pit,
+        || unsafe { calibrate_against_pit(sev_status) },
+    )?;
+    TSC_FREQUENCY.set(frequency).map_err(|_| "TSC frequency already initialized")?;
+    Ok(frequency)
+}
+
+/// Returns the TSC frequency, if it has been determined.
+pub fn tsc_frequency() -> Option<TscFrequency> {
+    TSC_FREQUENCY.get().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_calibration() -> Result<u64, &'static str> {
+        panic!("calibration should not have been attempted")
+    }
+
+    #[test]
+    fn kernel_arg_takes_precedence() {
+        assert_eq!(
+            select_tsc_frequency(Some(1_000), Some(2_000), None, true, no_calibration),
+            Ok(TscFrequency { hz: 1_000, source: TscFrequencySource::KernelArg })
+        );
+        assert_eq!(
+            select_tsc_frequency(Some(1_000), None, None, false, no_calibration),
+            Ok(TscFrequency { hz: 1_000, source: TscFrequencySource::KernelArg })
+        );
+    }
+
+    #[test]
+    fn cpuid_before_calibration() {
+        assert_eq!(
+            select_tsc_frequency(None, Some(2_000), None, true, no_calibration),
+            Ok(TscFrequency { hz: 2_000, source: TscFrequencySource::Cpuid })
+        );
+    }
+
+    #[test]
+    fn calibration_as_fallback() {
+        assert_eq!(
+            select_tsc_frequency(None, None, None, true, || Ok(3_000)),
+            Ok(TscFrequency { hz: 3_000, source: TscFrequencySource::Pit })
+        );
+        assert!(select_tsc_frequency(None, None, None, true, || Err("no PIT")).is_err());
+    }
+
+    #[test]
+    fn no_pit_forbids_calibration() {
+        assert!(select_tsc_frequency(None, None, None, false, no_calibration).is_err());
+    }
+
+    #[test]
+    fn base_frequency_as_last_resort() {
+        assert_eq!(
+            select_tsc_frequency(None, None, Some(4_000), true, || Err("timed out")),
+            Ok(TscFrequency { hz: 4_000, source: TscFrequencySource::CpuidBaseFrequency })
+        );
+        assert_eq!(
+            select_tsc_frequency(None, None, Some(4_000), false, no_calibration),
+            Ok(TscFrequency { hz: 4_000, source: TscFrequencySource::CpuidBaseFrequency })
+        );
+        assert_eq!(
+            select_tsc_frequency(None, None, Some(4_000), true, || Ok(3_000)),
+            Ok(TscFrequency { hz: 3_000, source: TscFrequencySource::Pit })
+        );
+    }
+
+    #[test]
+    fn cpuid_leaf_0x16() {
+        let leaf = CpuidResult { eax: 2_100, ebx: 3_700, ecx: 100, edx: 0 };
+        assert_eq!(base_frequency_from_cpuid(0x20, leaf), Some(2_100_000_000));
+        assert_eq!(base_frequency_from_cpuid(0x15, leaf), None);
+        assert_eq!(base_frequency_from_cpuid(0x20, CpuidResult { eax: 0, ..leaf }), None);
+    }
+
+    #[test]
+    fn cpuid_leaf_0x15() {
+        let leaf = CpuidResult { eax: 2, ebx: 166, ecx: 24_000_000, edx: 0 };
+        assert_eq!(tsc_frequency_from_cpuid(0x20, leaf), Some(1_992_000_000));
+        assert_eq!(tsc_frequency_from_cpuid(0x14, leaf), None);
+        // Crystal clock frequency not enumerated.
+        assert_eq!(tsc_frequency_from_cpuid(0x20, CpuidResult { ecx: 0, ..leaf }), None);
+        // TSC/crystal ratio not enumerated.
+        assert_eq!(tsc_frequency_from_cpuid(0x20, CpuidResult { ebx: 0, ..leaf }), None);
+    }
+}
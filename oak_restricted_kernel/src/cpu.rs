@@ -0,0 +1,214 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! CPU vendor, family and memory encryption capability detection.
+//!
+//! This is used to refuse running without SEV-SNP when the `require_snp`
+//! kernel argument asks for it, instead of silently continuing on a host that
+//! doesn't (or claims not to) provide memory encryption.
+
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+use oak_sev_guest::msr::SevStatus;
+
+/// Kernel argument that makes the kernel refuse to run unless SEV-SNP is
+/// active.
+pub const REQUIRE_SNP_ARG: &str = "require_snp";
+
+/// CPUID leaf reporting the extended feature leaves supported by the CPU.
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+
+/// CPUID leaf reporting AMD memory encryption capabilities.
+const CPUID_MEMORY_ENCRYPTION: u32 = 0x8000_001F;
+
+/// Bits of EAX of `CPUID_MEMORY_ENCRYPTION`.
+const SEV_SUPPORTED: u32 = 1 << 1;
+const SEV_ES_SUPPORTED: u32 = 1 << 3;
+const SEV_SNP_SUPPORTED: u32 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Vendor {
+    Amd,
+    Intel,
+    /// Any other vendor, identified by its CPUID vendor string.
+    Other([u8; 12]),
+}
+
+impl Vendor {
+    /// Decodes the vendor string in EBX, EDX and ECX of CPUID leaf 0.
+    fn from_cpuid(leaf: CpuidResult) -> Self {
+        let mut id = [0u8; 12];
+        id[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        id[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+        id[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+        match &id {
+            b"AuthenticAMD" => Vendor::Amd,
+            b"GenuineIntel" => Vendor::Intel,
+            _ => Vendor::Other(id),
+        }
+    }
+}
+
+/// The CPU vendor and memory encryption capabilities, as reported by CPUID.
+///
+/// Note that under SEV-SNP CPUID results come from the CPUID page, and the
+/// hypervisor can lie about them otherwise, so these are only a hint; whether
+/// memory encryption is actually active is determined by the SEV status MSR.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    /// Family, including the extended family.
+    pub family: u32,
+    /// Model, including the extended model.
+    pub model: u32,
+    pub sev: bool,
+    pub sev_es: bool,
+    pub sev_snp: bool,
+}
+
+impl CpuInfo {
+    /// Reads the CPU information using CPUID.
+    pub fn read() -> Self {
+        // Safety: CPUID is available on all x86-64 CPUs, and we only read extended
+        // leaves that the CPU reports as supported.
+        unsafe {
+            let max_extended_leaf = __cpuid(CPUID_MAX_EXTENDED_LEAF).eax;
+            Self::decode(
+                __cpuid(0),
+                __cpuid(1),
+                (max_extended_leaf >= CPUID_MEMORY_ENCRYPTION)
+                    .then(|| __cpuid(CPUID_MEMORY_ENCRYPTION)),
+            )
+        }
+    }
+
+    /// Decodes CPUID leaves 0, 1 and 0x8000001F (if supported).
+    fn decode(
+        leaf_0: CpuidResult,
+        leaf_1: CpuidResult,
+        memory_encryption: Option<CpuidResult>,
+    ) -> Self {
+        let vendor = Vendor::from_cpuid(leaf_0);
+        let base_family = (leaf_1.eax >> 8) & 0xF;
+        let base_model = (leaf_1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((leaf_1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0xF || (vendor == Vendor::Intel && base_family == 0x6) {
+            (((leaf_1.eax >> 16) & 0xF) << 4) | base_model
+        } else {
+            base_model
+        };
+        // The memory encryption leaf is only meaningful on AMD CPUs.
+        let capabilities =
+            memory_encryption.filter(|_| vendor == Vendor::Amd).map_or(0, |leaf| leaf.eax);
+        Self {
+            vendor,
+            family,
+            model,
+            sev: capabilities & SEV_SUPPORTED != 0,
+            sev_es: capabilities & SEV_ES_SUPPORTED != 0,
+            sev_snp: capabilities & SEV_SNP_SUPPORTED != 0,
+        }
+    }
+}
+
+/// Checks that SEV-SNP is actually active, for use when the `require_snp`
+/// kernel argument is set.
+pub fn check_snp_active(cpu: &CpuInfo, sev_status: SevStatus) -> Result<(), &'static str> {
+    if cpu.vendor != Vendor::Amd {
+        return Err("SEV-SNP is required, but the CPU is not an AMD CPU");
+    }
+    if !cpu.sev_snp {
+        return Err("SEV-SNP is required, but the CPU does not support it");
+    }
+    if !sev_status.contains(SevStatus::SNP_ACTIVE) {
+        return Err("SEV-SNP is required, but it is not active");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vendor_leaf(id: &[u8; 12]) -> CpuidResult {
+        let word = |i: usize| u32::from_le_bytes(id[i..i + 4].try_into().unwrap());
+        CpuidResult { eax: 0x10, ebx: word(0), edx: word(4), ecx: word(8) }
+    }
+
+    fn leaf(eax: u32) -> CpuidResult {
+        CpuidResult { eax, ebx: 0, ecx: 0, edx: 0 }
+    }
+
+    /// AMD EPYC (Milan): family 0x19, model 0x01.
+    const MILAN_SIGNATURE: u32 = 0x00A0_0F11;
+
+    /// Intel Sapphire Rapids: family 6, model 0x8F.
+    const SAPPHIRE_RAPIDS_SIGNATURE: u32 = 0x0008_06F8;
+
+    #[test]
+    fn decode_amd_with_snp() {
+        let info = CpuInfo::decode(
+            vendor_leaf(b"AuthenticAMD"),
+            leaf(MILAN_SIGNATURE),
+            Some(leaf(0x0001_007F)),
+        );
+        assert_eq!(info.vendor, Vendor::Amd);
+        assert_eq!(info.family, 0x19);
+        assert_eq!(info.model, 0x01);
+        assert!(info.sev && info.sev_es && info.sev_snp);
+        assert_eq!(check_snp_active(&info, SevStatus::all()), Ok(()));
+        // The CPU supports SNP, but the guest isn't running under it.
+        assert!(check_snp_active(&info, SevStatus::empty()).is_err());
+    }
+
+    #[test]
+    fn decode_amd_without_snp() {
+        let info =
+            CpuInfo::decode(vendor_leaf(b"AuthenticAMD"), leaf(MILAN_SIGNATURE), Some(leaf(0x0B)));
+        assert!(info.sev && info.sev_es && !info.sev_snp);
+        assert!(check_snp_active(&info, SevStatus::all()).is_err());
+
+        let info = CpuInfo::decode(vendor_leaf(b"AuthenticAMD"), leaf(MILAN_SIGNATURE), None);
+        assert!(!info.sev && !info.sev_es && !info.sev_snp);
+        assert!(check_snp_active(&info, SevStatus::all()).is_err());
+    }
+
+    #[test]
+    fn decode_intel() {
+        let info = CpuInfo::decode(
+            vendor_leaf(b"GenuineIntel"),
+            leaf(SAPPHIRE_RAPIDS_SIGNATURE),
+            // Ignored, as the leaf is reserved on Intel CPUs.
+            Some(leaf(0x1F)),
+        );
+        assert_eq!(info.vendor, Vendor::Intel);
+        assert_eq!(info.family, 6);
+        assert_eq!(info.model, 0x8F);
+        assert!(!info.sev_snp);
+        assert!(check_snp_active(&info, SevStatus::all()).is_err());
+    }
+
+    #[test]
+    fn decode_other_vendor() {
+        let info = CpuInfo::decode(vendor_leaf(b"KVMKVMKVM\0\0\0"), leaf(0), None);
+        assert_eq!(info.vendor, Vendor::Other(*b"KVMKVMKVM\0\0\0"));
+        assert!(check_snp_active(&info, SevStatus::all()).is_err());
+    }
+}
@@ -14,22 +14,257 @@
 // limitations under the License.
 //
 
-use goblin::elf64::program_header::ProgramHeader;
+use core::fmt;
+
+use goblin::{
+    elf::header::{
+        header64::SIZEOF_EHDR, EI_CLASS, EI_DATA, EI_NIDENT, ELFCLASS64, ELFDATA2LSB, ELFMAG,
+        EM_X86_64, SELFMAG,
+    },
+    elf64::program_header::{ProgramHeader, PF_X, PT_LOAD},
+};
 use x86_64::VirtAddr;
 
+/// Reasons why the program headers of an ELF file are rejected by the loader.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// Two `PT_LOAD` segments (identified by their program header indices)
+    /// would occupy the same virtual memory.
+    OverlappingSegments(usize, usize),
+    /// The end of a `PT_LOAD` segment does not fit in the address space.
+    SegmentOverflow(usize),
+    /// A `PT_LOAD` segment's virtual address and file offset are not congruent
+    /// modulo its alignment, or the alignment is not a power of two.
+    MisalignedSegment(usize),
+    /// There is no valid ELF header for a 64-bit little-endian x86-64 binary
+    /// where we expected one.
+    InvalidHeader(&'static str),
+    /// The entry point is not within any `PT_LOAD` segment.
+    EntryNotLoaded(u64),
+    /// The entry point is within a `PT_LOAD` segment (identified by its program
+    /// header index) that is not executable.
+    EntryNotExecutable(u64, usize),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::OverlappingSegments(first, second) => {
+                write!(f, "program headers {} and {} overlap", first, second)
+            }
+            ElfError::SegmentOverflow(index) => {
+                write!(f, "program header {} extends beyond the address space", index)
+            }
+            ElfError::MisalignedSegment(index) => {
+                write!(f, "program header {} is not consistently aligned", index)
+            }
+            ElfError::InvalidHeader(reason) => write!(f, "invalid ELF header: {}", reason),
+            ElfError::EntryNotLoaded(entry) => {
+                write!(f, "entry point {:#x} is not in a loadable segment", entry)
+            }
+            ElfError::EntryNotExecutable(entry, index) => {
+                write!(f, "entry point {:#x} is in non-executable program header {}", entry, index)
+            }
+        }
+    }
+}
+
+/// Checks that `raw_header` starts with the ELF identification of a 64-bit
+/// little-endian binary for x86-64.
+fn check_header(raw_header: &[u8]) -> Result<(), ElfError> {
+    if raw_header.len() < SIZEOF_EHDR {
+        return Err(ElfError::InvalidHeader("header is truncated"));
+    }
+    if raw_header[..SELFMAG] != ELFMAG[..] {
+        return Err(ElfError::InvalidHeader("bad magic"));
+    }
+    if raw_header[EI_CLASS] != ELFCLASS64 {
+        return Err(ElfError::InvalidHeader("not a 64-bit binary"));
+    }
+    if raw_header[EI_DATA] != ELFDATA2LSB {
+        return Err(ElfError::InvalidHeader("not a little-endian binary"));
+    }
+    // `e_machine` follows the identification and `e_type`.
+    let machine = u16::from_le_bytes([raw_header[EI_NIDENT + 2], raw_header[EI_NIDENT + 3]]);
+    if machine != EM_X86_64 {
+        return Err(ElfError::InvalidHeader("not an x86-64 binary"));
+    }
+    Ok(())
+}
+
 /// Interpret raw memory at the given address as an ELF header and return the
 /// program headers.
 ///
-/// Safety: this virtual address must be valid and contain ELF headers and
-/// program headers.
-pub unsafe fn get_phdrs(addr: VirtAddr) -> &'static [ProgramHeader] {
-    let raw_header = core::slice::from_raw_parts(
-        addr.as_u64() as *const u8,
-        goblin::elf::header::header64::SIZEOF_EHDR,
-    );
-    let header = goblin::elf::Elf::parse_header(raw_header).unwrap();
-    ProgramHeader::from_raw_parts(
+/// Fails if there is no valid ELF header at the address, for example because
+/// the bootloader didn't put us where the linker script expects.
+///
+/// Safety: this virtual address must be valid and readable for the size of an
+/// ELF header; if it contains an ELF header, the program headers it points at
+/// must be valid too.
+pub unsafe fn get_phdrs(addr: VirtAddr) -> Result<&'static [ProgramHeader], ElfError> {
+    let raw_header = core::slice::from_raw_parts(addr.as_u64() as *const u8, SIZEOF_EHDR);
+    check_header(raw_header)?;
+    let header = goblin::elf::Elf::parse_header(raw_header)
+        .map_err(|_| ElfError::InvalidHeader("couldn't parse header"))?;
+    Ok(ProgramHeader::from_raw_parts(
         (addr.as_u64() + header.e_phoff) as *const ProgramHeader,
         header.e_phnum as usize,
-    )
+    ))
+}
+
+/// Checks that the loadable segments described by `program_headers` can be
+/// mapped without clobbering each other.
+///
+/// This must be called before mapping anything, as the loader maps segments
+/// one after another and a later segment would silently replace an earlier one.
+pub fn check_segments(program_headers: &[ProgramHeader]) -> Result<(), ElfError> {
+    let loadable = || program_headers.iter().enumerate().filter(|(_, phdr)| phdr.p_type == PT_LOAD);
+    for (index, phdr) in loadable() {
+        // Alignments of 0 and 1 both mean that there is no alignment requirement.
+        if phdr.p_align > 1
+            && (!phdr.p_align.is_power_of_two()
+                || phdr.p_vaddr % phdr.p_align != phdr.p_offset % phdr.p_align)
+        {
+            return Err(ElfError::MisalignedSegment(index));
+        }
+        if phdr.p_vaddr.checked_add(phdr.p_memsz).is_none() {
+            return Err(ElfError::SegmentOverflow(index));
+        }
+    }
+    for (i, first) in loadable() {
+        for (j, second) in loadable().filter(|(j, _)| *j > i) {
+            if first.p_vaddr < second.p_vaddr + second.p_memsz
+                && second.p_vaddr < first.p_vaddr + first.p_memsz
+            {
+                return Err(ElfError::OverlappingSegments(i, j));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `entry` lies within an executable `PT_LOAD` segment, so that
+/// jumping to it doesn't fault right away.
+///
+/// Call this after [`check_segments`], which ensures that the segments don't
+/// overflow and that at most one of them contains `entry`.
+pub fn check_entry(entry: u64, program_headers: &[ProgramHeader]) -> Result<(), ElfError> {
+    let (index, phdr) = program_headers
+        .iter()
+        .enumerate()
+        .filter(|(_, phdr)| phdr.p_type == PT_LOAD)
+        .find(|(_, phdr)| phdr.p_vaddr <= entry && entry - phdr.p_vaddr < phdr.p_memsz)
+        .ok_or(ElfError::EntryNotLoaded(entry))?;
+    if phdr.p_flags & PF_X == 0 {
+        return Err(ElfError::EntryNotExecutable(entry, index));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal ELF header for an x86-64 binary without program headers.
+    fn elf_header() -> [u8; SIZEOF_EHDR] {
+        let mut header = [0u8; SIZEOF_EHDR];
+        header[..SELFMAG].copy_from_slice(ELFMAG);
+        header[EI_CLASS] = ELFCLASS64;
+        header[EI_DATA] = ELFDATA2LSB;
+        header[EI_NIDENT + 2..EI_NIDENT + 4].copy_from_slice(&EM_X86_64.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn valid_header_is_accepted() {
+        let header = elf_header();
+        assert_eq!(check_header(&header), Ok(()));
+        let phdrs = unsafe { get_phdrs(VirtAddr::from_ptr(header.as_ptr())) }.unwrap();
+        assert!(phdrs.is_empty());
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let mut header = elf_header();
+        header[1] = b'X';
+        assert_eq!(check_header(&header), Err(ElfError::InvalidHeader("bad magic")));
+        assert!(unsafe { get_phdrs(VirtAddr::from_ptr(header.as_ptr())) }.is_err());
+
+        let mut header = elf_header();
+        header[EI_CLASS] = goblin::elf::header::ELFCLASS32;
+        assert_eq!(check_header(&header), Err(ElfError::InvalidHeader("not a 64-bit binary")));
+
+        let mut header = elf_header();
+        header[EI_DATA] = goblin::elf::header::ELFDATA2MSB;
+        assert!(check_header(&header).is_err());
+
+        let mut header = elf_header();
+        header[EI_NIDENT + 2] = 0x28; // EM_ARM
+        assert_eq!(check_header(&header), Err(ElfError::InvalidHeader("not an x86-64 binary")));
+
+        assert!(check_header(&elf_header()[..16]).is_err());
+        assert!(check_header(&[0u8; SIZEOF_EHDR]).is_err());
+    }
+
+    fn load_segment(vaddr: u64, offset: u64, memsz: u64) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            p_vaddr: vaddr,
+            p_offset: offset,
+            p_memsz: memsz,
+            p_filesz: memsz,
+            p_align: 0x1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn non_overlapping_segments_pass() {
+        let phdrs =
+            [load_segment(0x20_0000, 0x1000, 0x1000), load_segment(0x40_0000, 0x2000, 0x1000)];
+        assert_eq!(check_segments(&phdrs), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_segments_are_rejected() {
+        let phdrs =
+            [load_segment(0x20_0000, 0x1000, 0x2000), load_segment(0x20_1000, 0x2000, 0x1000)];
+        assert_eq!(check_segments(&phdrs), Err(ElfError::OverlappingSegments(0, 1)));
+    }
+
+    #[test]
+    fn non_loadable_segments_are_ignored() {
+        let mut note = load_segment(0x20_0000, 0x1000, 0x1000);
+        note.p_type = goblin::elf64::program_header::PT_NOTE;
+        let phdrs = [load_segment(0x20_0000, 0x1000, 0x1000), note];
+        assert_eq!(check_segments(&phdrs), Ok(()));
+    }
+
+    #[test]
+    fn misaligned_segment_is_rejected() {
+        let phdrs =
+            [load_segment(0x20_0000, 0x1000, 0x1000), load_segment(0x40_0000, 0x2100, 0x1000)];
+        assert_eq!(check_segments(&phdrs), Err(ElfError::MisalignedSegment(1)));
+    }
+
+    #[test]
+    fn overflowing_segment_is_rejected() {
+        let phdrs = [load_segment(u64::MAX - 0xFFF, 0x1000, 0x2000)];
+        assert_eq!(check_segments(&phdrs), Err(ElfError::SegmentOverflow(0)));
+    }
+
+    #[test]
+    fn entry_must_be_in_executable_segment() {
+        const PF_R: u32 = 4;
+        const PF_W: u32 = 2;
+        let phdrs = [
+            ProgramHeader { p_flags: PF_R | PF_X, ..load_segment(0x20_0000, 0x1000, 0x1000) },
+            ProgramHeader { p_flags: PF_R | PF_W, ..load_segment(0x40_0000, 0x2000, 0x1000) },
+        ];
+        assert_eq!(check_entry(0x20_0000, &phdrs), Ok(()));
+        assert_eq!(check_entry(0x20_0FFF, &phdrs), Ok(()));
+        assert_eq!(check_entry(0x40_0100, &phdrs), Err(ElfError::EntryNotExecutable(0x40_0100, 1)));
+        assert_eq!(check_entry(0x20_1000, &phdrs), Err(ElfError::EntryNotLoaded(0x20_1000)));
+        assert_eq!(check_entry(0, &phdrs), Err(ElfError::EntryNotLoaded(0)));
+    }
 }
@@ -0,0 +1,219 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A single error type for everything that can go wrong while bringing up the
+//! kernel.
+//!
+//! Subsystems keep their own error types (or plain messages); [`KernelError`]
+//! wraps them and records which subsystem failed, so that errors from
+//! different parts of the kernel can be propagated with `?` and reported in a
+//! uniform way.
+
+use core::fmt;
+
+use oak_channel::ChannelError;
+use strum::Display;
+
+use crate::{
+    elf::ElfError, ghcb::GhcbError, memory::GuestHostAllocError,
+    mm::virtual_address_allocator::VaError, vc::VcError,
+};
+
+/// The part of the kernel an error originated in.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Subsystem {
+    Acpi,
+    Boot,
+    Channel,
+    Elf,
+    Ghcb,
+    Memory,
+    Snp,
+    Syscall,
+    Vc,
+}
+
+#[derive(Debug)]
+pub enum KernelError {
+    Channel(ChannelError),
+    Elf(ElfError),
+    Ghcb(GhcbError),
+    Vc(VcError),
+    VirtualAddress(VaError),
+    GuestHostAlloc(GuestHostAllocError),
+    /// A subsystem that reports errors as plain messages failed.
+    Message {
+        subsystem: Subsystem,
+        message: &'static str,
+    },
+    /// A subsystem that reports errors through `anyhow` failed.
+    Other {
+        subsystem: Subsystem,
+        error: anyhow::Error,
+    },
+}
+
+impl KernelError {
+    /// Returns the subsystem the error originated in.
+    pub fn subsystem(&self) -> Subsystem {
+        match self {
+            KernelError::Channel(_) => Subsystem::Channel,
+            KernelError::Elf(_) => Subsystem::Elf,
+            KernelError::Ghcb(_) => Subsystem::Ghcb,
+            KernelError::Vc(_) => Subsystem::Vc,
+            KernelError::VirtualAddress(_) | KernelError::GuestHostAlloc(_) => Subsystem::Memory,
+            KernelError::Message { subsystem, .. } | KernelError::Other { subsystem, .. } => {
+                *subsystem
+            }
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.subsystem())?;
+        match self {
+            KernelError::Channel(err) => write!(f, "{}", err),
+            KernelError::Elf(err) => write!(f, "{}", err),
+            KernelError::Ghcb(err) => write!(f, "{}", err),
+            KernelError::Vc(err) => write!(f, "{}", err),
+            KernelError::VirtualAddress(err) => write!(f, "{}", err),
+            KernelError::GuestHostAlloc(err) => write!(f, "{}", err),
+            KernelError::Message { message, .. } => write!(f, "{}", message),
+            KernelError::Other { error, .. } => write!(f, "{:#}", error),
+        }
+    }
+}
+
+impl From<ChannelError> for KernelError {
+    fn from(err: ChannelError) -> Self {
+        KernelError::Channel(err)
+    }
+}
+
+impl From<ElfError> for KernelError {
+    fn from(err: ElfError) -> Self {
+        KernelError::Elf(err)
+    }
+}
+
+impl From<GhcbError> for KernelError {
+    fn from(err: GhcbError) -> Self {
+        KernelError::Ghcb(err)
+    }
+}
+
+impl From<VcError> for KernelError {
+    fn from(err: VcError) -> Self {
+        KernelError::Vc(err)
+    }
+}
+
+impl From<VaError> for KernelError {
+    fn from(err: VaError) -> Self {
+        KernelError::VirtualAddress(err)
+    }
+}
+
+impl From<GuestHostAllocError> for KernelError {
+    fn from(err: GuestHostAllocError) -> Self {
+        KernelError::GuestHostAlloc(err)
+    }
+}
+
+/// Converts untyped errors into a [`KernelError`] by tagging them with the
+/// subsystem they came from.
+pub trait InSubsystem<T> {
+    fn in_subsystem(self, subsystem: Subsystem) -> Result<T, KernelError>;
+}
+
+impl<T> InSubsystem<T> for Result<T, &'static str> {
+    fn in_subsystem(self, subsystem: Subsystem) -> Result<T, KernelError> {
+        self.map_err(|message| KernelError::Message { subsystem, message })
+    }
+}
+
+impl<T> InSubsystem<T> for anyhow::Result<T> {
+    fn in_subsystem(self, subsystem: Subsystem) -> Result<T, KernelError> {
+        self.map_err(|error| KernelError::Other { subsystem, error })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn typed_errors_convert() {
+        let err: KernelError = ChannelError::Unsupported.into();
+        assert!(matches!(err, KernelError::Channel(ChannelError::Unsupported)));
+        assert_eq!(err.subsystem(), Subsystem::Channel);
+
+        let err: KernelError = ElfError::SegmentOverflow(3).into();
+        assert!(matches!(err, KernelError::Elf(ElfError::SegmentOverflow(3))));
+        assert_eq!(err.subsystem(), Subsystem::Elf);
+
+        let err: KernelError = GhcbError::NotMapped.into();
+        assert!(matches!(err, KernelError::Ghcb(GhcbError::NotMapped)));
+        assert_eq!(err.subsystem(), Subsystem::Ghcb);
+
+        let err: KernelError = VcError::UnsupportedExitCode(0x72).into();
+        assert!(matches!(err, KernelError::Vc(VcError::UnsupportedExitCode(0x72))));
+        assert_eq!(err.subsystem(), Subsystem::Vc);
+
+        let err: KernelError = VaError::Overlapping.into();
+        assert!(matches!(err, KernelError::VirtualAddress(VaError::Overlapping)));
+        assert_eq!(err.subsystem(), Subsystem::Memory);
+
+        let err: KernelError = GuestHostAllocError { needed: 2, largest_available: 1 }.into();
+        assert!(matches!(err, KernelError::GuestHostAlloc(_)));
+        assert_eq!(err.subsystem(), Subsystem::Memory);
+    }
+
+    #[test]
+    fn untyped_errors_convert() {
+        let result: Result<(), &'static str> = Err("missing SNP CPUID page");
+        let err = result.in_subsystem(Subsystem::Snp).unwrap_err();
+        assert!(matches!(
+            err,
+            KernelError::Message { subsystem: Subsystem::Snp, message: "missing SNP CPUID page" }
+        ));
+
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("no RSDP"));
+        let err = result.in_subsystem(Subsystem::Acpi).unwrap_err();
+        assert!(matches!(err, KernelError::Other { subsystem: Subsystem::Acpi, .. }));
+
+        assert_eq!(Ok::<_, &'static str>(7).in_subsystem(Subsystem::Boot).unwrap(), 7);
+    }
+
+    #[test]
+    fn display_names_subsystem() {
+        let err: KernelError = VaError::OutOfBounds.into();
+        assert_eq!(err.to_string(), "memory: requested range is out of bounds");
+
+        let err = KernelError::Message { subsystem: Subsystem::Channel, message: "closed" };
+        assert_eq!(err.to_string(), "channel: closed");
+
+        let err: KernelError = VcError::UnsupportedExitCode(0x72).into();
+        assert_eq!(err.to_string(), "vc: unsupported exit code 0x72");
+
+        let err: KernelError = ChannelError::InvalidArgument.into();
+        assert_eq!(err.to_string(), "channel: invalid channel request argument");
+    }
+}
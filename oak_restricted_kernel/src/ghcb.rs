@@ -14,14 +14,15 @@
 // limitations under the License.
 //
 
-use core::ptr::addr_of;
+use core::{fmt, ptr::addr_of};
 
 use oak_core::sync::OnceCell;
 use oak_sev_guest::{
     ghcb::{Ghcb, GhcbProtocol},
     io::{GhcbIoFactory, PortFactoryWrapper},
     msr::{
-        change_snp_state_for_frame, register_ghcb_location, PageAssignment, RegisterGhcbGpaRequest,
+        change_snp_state_for_frame, register_ghcb_location, PageAssignment, RegisterGhcbGpaError,
+        RegisterGhcbGpaRequest,
     },
 };
 use spinning_top::Spinlock;
@@ -29,14 +30,15 @@ use x86_64::{
     addr::VirtAddr,
     registers::control::Cr3,
     structures::paging::{
-        mapper::PageTableFrameMapping, MappedPageTable, Page, PageSize, PhysFrame, Size2MiB,
-        Size4KiB,
+        mapper::{FlagUpdateError, PageTableFrameMapping},
+        MappedPageTable, Page, PageSize, PhysFrame, Size2MiB, Size4KiB,
     },
 };
 
 use crate::mm::{
+    encrypted_bit_position,
     encrypted_mapper::{EncryptedPageTable, MemoryEncryption, PhysOffset},
-    Mapper, PageTableFlags, Translator, ENCRYPTED_BIT_POSITION,
+    Mapper, PageTableFlags, Translator,
 };
 
 /// A wrapper to ensure that the GHCB is alone in a 2MiB page.
@@ -54,6 +56,45 @@ static_assertions::assert_eq_size!(GhcbAlignmentWrapper, [u8; Size2MiB::SIZE as
 
 static mut GHCB_WRAPPER: GhcbAlignmentWrapper = GhcbAlignmentWrapper { ghcb: Ghcb::new() };
 
+/// Reasons why setting up the GHCB can fail.
+#[derive(Debug)]
+pub enum GhcbError {
+    AlreadyInitialized,
+    NotInitialized,
+    /// The page table entry for the GHCB page could not be updated.
+    UpdateFlags(FlagUpdateError),
+    /// The GHCB page is not mapped.
+    NotMapped,
+    /// The GHCB is not located at the start of a 2 MiB frame.
+    Misaligned,
+    /// The hypervisor refused to mark the GHCB frame as shared in the RMP.
+    PageStateChange(&'static str),
+    /// The hypervisor refused to register the GHCB location.
+    Register(RegisterGhcbGpaError),
+}
+
+impl fmt::Display for GhcbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GhcbError::AlreadyInitialized => write!(f, "GHCB already initialized"),
+            GhcbError::NotInitialized => write!(f, "GHCB not initialized"),
+            GhcbError::UpdateFlags(err) => {
+                write!(f, "couldn't update page table flags for GHCB: {:?}", err)
+            }
+            GhcbError::NotMapped => write!(f, "couldn't find the physical address for the GHCB"),
+            GhcbError::Misaligned => {
+                write!(f, "the GHCB physical address is not correctly aligned")
+            }
+            GhcbError::PageStateChange(err) => {
+                write!(f, "couldn't change SNP state for the GHCB frame: {}", err)
+            }
+            GhcbError::Register(err) => {
+                write!(f, "couldn't register the GHCB address with the hypervisor: {:?}", err)
+            }
+        }
+    }
+}
+
 pub fn get_ghcb_port_factory() -> PortFactoryWrapper {
     PortFactoryWrapper::Ghcb(GhcbIoFactory::new(GHCB_PROTOCOL.get().expect("GHCB not initialized")))
 }
@@ -62,24 +103,23 @@ pub static GHCB_PROTOCOL: OnceCell<Spinlock<GhcbProtocol<'static, Ghcb>>> = Once
 
 /// Initializes the GHCB.
 ///
-/// Will panic if the GHCB has already been initalized.
-pub fn init(snp_enabled: bool) {
-    let ghcb_protocol = init_ghcb_early(snp_enabled);
-    if GHCB_PROTOCOL.set(Spinlock::new(ghcb_protocol)).is_err() {
-        panic!("ghcb already initialized");
-    }
+/// Fails if the GHCB has already been initalized.
+pub fn init(snp_enabled: bool) -> Result<(), GhcbError> {
+    let ghcb_protocol = init_ghcb_early(snp_enabled)?;
+    GHCB_PROTOCOL.set(Spinlock::new(ghcb_protocol)).map_err(|_| GhcbError::AlreadyInitialized)
 }
 
 /// Shares the page containing the GHCB with the hypervisor again.
 ///
 /// This should be called as soon as the kernel memory has been initialised, as
 /// that would have caused the page to be marked as encrypted.
-pub fn reshare_ghcb<M: Mapper<Size4KiB>>(mapper: &M) {
+pub fn reshare_ghcb<M: Mapper<Size4KiB>>(mapper: &M) -> Result<(), GhcbError> {
+    let ghcb_protocol = GHCB_PROTOCOL.get().ok_or(GhcbError::NotInitialized)?;
     let ghcb_page = get_ghcb_page();
     // Safety: we only change the encrypted flag, all other flags for the GHCB pages
     // are as they were set during the kernel memory initialisation.
-    unsafe {
-        match mapper.update_flags(
+    let mapper_flush = unsafe {
+        mapper.update_flags(
             // Turn the 2M page into a 4K page. This unwrap will not fail, as 2M pages are
             // 4K-aligned by definition.
             Page::from_start_address(ghcb_page.start_address()).unwrap(),
@@ -87,18 +127,18 @@ pub fn reshare_ghcb<M: Mapper<Size4KiB>>(mapper: &M) {
                 | PageTableFlags::WRITABLE
                 | PageTableFlags::GLOBAL
                 | PageTableFlags::NO_EXECUTE,
-        ) {
-            Ok(mapper_flush) => mapper_flush.flush(),
-            Err(error) => panic!("couldn't update page table flags for GHCB: {:?}", error),
-        };
+        )
     }
+    .map_err(GhcbError::UpdateFlags)?;
+    mapper_flush.flush();
 
     // Reset the GHCB in case something touched it while it was marked as encrypted.
-    GHCB_PROTOCOL.get().expect("GHCB not initialized").lock().reset();
+    ghcb_protocol.lock().reset();
+    Ok(())
 }
 
 /// Initializes the GHCB and shares it with the hypervisor during early boot.
-fn init_ghcb_early(snp_enabled: bool) -> GhcbProtocol<'static, Ghcb> {
+fn init_ghcb_early(snp_enabled: bool) -> Result<GhcbProtocol<'static, Ghcb>, GhcbError> {
     // Safety: This is called only during early boot, so there is only a single
     // execution context.
     let ghcb = unsafe { &mut GHCB_WRAPPER.ghcb };
@@ -108,39 +148,47 @@ fn init_ghcb_early(snp_enabled: bool) -> GhcbProtocol<'static, Ghcb> {
     // Safety: we only remove the encrypted bit as the initial pages created by the
     // stage 0 firmware are only marked as present and writable, and possibly
     // encrypted.
-    unsafe {
-        match mapper.update_flags(ghcb_page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE) {
-            Ok(mapper_flush) => mapper_flush.flush(),
-            Err(error) => panic!("couldn't update page table flags for GHCB: {:?}", error),
-        };
+    let mapper_flush = unsafe {
+        mapper.update_flags(ghcb_page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
     }
+    .map_err(GhcbError::UpdateFlags)?;
+    mapper_flush.flush();
     if snp_enabled {
-        // It is OK to crash if we cannot find the physical frame that contains the
-        // GHCB.
         let ghcb_frame = PhysFrame::<Size2MiB>::from_start_address(
-            mapper
-                .translate_virtual(ghcb_page.start_address())
-                .expect("couldn't find the physical address for the GHCB"),
+            mapper.translate_virtual(ghcb_page.start_address()).ok_or(GhcbError::NotMapped)?,
         )
-        .expect("the GHCB physical address is not correctly aligned");
+        .map_err(|_| GhcbError::Misaligned)?;
 
         // Since we don't have the GHCB set up already we need to use the MSR protocol
         // to mark every individual 4KiB area in the 2MiB page as shared in the
-        // RMP. It is OK to crash if we cannot share the GHCB with the
-        // hypervisor.
-        change_snp_state_for_frame(&ghcb_frame, PageAssignment::Shared)
-            .expect("couldn't change SNP state for frame");
-
-        let ghcb_location_request =
-            RegisterGhcbGpaRequest::new(ghcb_frame.start_address().as_u64() as usize)
-                .expect("invalid address for GHCB location");
-        register_ghcb_location(ghcb_location_request)
-            .expect("couldn't register the GHCB address with the hypervisor");
+        // RMP.
+        share_with_hypervisor(
+            ghcb_frame,
+            |frame| change_snp_state_for_frame(frame, PageAssignment::Shared),
+            register_ghcb_location,
+        )?;
     }
 
     ghcb.reset();
 
-    GhcbProtocol::new(ghcb, |virt_addr: VirtAddr| mapper.translate_virtual(virt_addr))
+    Ok(GhcbProtocol::new(ghcb, |virt_addr: VirtAddr| mapper.translate_virtual(virt_addr)))
+}
+
+/// Marks the GHCB frame as shared in the RMP and registers its location with
+/// the hypervisor, using the provided MSR protocol operations.
+fn share_with_hypervisor<C, R>(
+    ghcb_frame: PhysFrame<Size2MiB>,
+    change_state: C,
+    register: R,
+) -> Result<(), GhcbError>
+where
+    C: FnOnce(&PhysFrame<Size2MiB>) -> Result<(), &'static str>,
+    R: FnOnce(RegisterGhcbGpaRequest) -> Result<(), RegisterGhcbGpaError>,
+{
+    change_state(&ghcb_frame).map_err(GhcbError::PageStateChange)?;
+    let request = RegisterGhcbGpaRequest::new(ghcb_frame.start_address().as_u64() as usize)
+        .map_err(GhcbError::Register)?;
+    register(request).map_err(GhcbError::Register)
 }
 
 /// Gets a mapper that understands encrypted pages and assumes an identity
@@ -154,7 +202,7 @@ fn get_identity_mapped_encrypted_page_table<'a>()
     // We assume an identity mapping, so the offset is zero.
     let offset = VirtAddr::new(0);
     // We assume that this will only be used if memory encryption is enabled.
-    let encryption = MemoryEncryption::Encrypted(ENCRYPTED_BIT_POSITION);
+    let encryption = MemoryEncryption::Encrypted(encrypted_bit_position());
     let offset_mapper = PhysOffset::new(offset, encryption);
     // Find the level 4 page table that is currently in use.
     let (l4_frame, _) = Cr3::read();
@@ -172,3 +220,42 @@ fn get_ghcb_page() -> Page<Size2MiB> {
     let ghcb_address = VirtAddr::from_ptr(ghcb_pointer);
     Page::<Size2MiB>::from_start_address(ghcb_address).expect("invalid start address for GHCB page")
 }
+
+#[cfg(test)]
+mod tests {
+    use x86_64::PhysAddr;
+
+    use super::*;
+
+    fn ghcb_frame() -> PhysFrame<Size2MiB> {
+        PhysFrame::from_start_address(PhysAddr::new(0x20_0000)).unwrap()
+    }
+
+    #[test]
+    fn successful_registration() {
+        assert!(share_with_hypervisor(ghcb_frame(), |_| Ok(()), |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn page_state_change_failure() {
+        let result = share_with_hypervisor(
+            ghcb_frame(),
+            |_| Err("page state change failed"),
+            |_| panic!("registration should not be attempted"),
+        );
+        assert!(matches!(result, Err(GhcbError::PageStateChange("page state change failed"))));
+    }
+
+    #[test]
+    fn registration_failure() {
+        let result = share_with_hypervisor(
+            ghcb_frame(),
+            |_| Ok(()),
+            |_| Err(RegisterGhcbGpaError::GhcbLocationNotAccepted),
+        );
+        assert!(matches!(
+            result,
+            Err(GhcbError::Register(RegisterGhcbGpaError::GhcbLocationNotAccepted))
+        ));
+    }
+}
@@ -14,30 +14,80 @@
 // limitations under the License.
 //
 
-use core::{arch::asm, ops::Deref};
+use alloc::vec::Vec;
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    ops::Deref,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use log::error;
+use oak_core::timer::rdtsc;
+use oak_restricted_kernel_interface::syscalls::INTERRUPT_VECTORS;
 use oak_sev_guest::{
+    cpuid::{CpuidInput, CpuidOutput},
+    ghcb::{Ghcb, GhcbProtocol},
     interrupts::{mutable_interrupt_handler_with_error_code, MutableInterruptStackFrame},
     io::{IoPortFactory, PortFactoryWrapper, PortWrapper, PortWriter},
-    msr::{get_cpuid_for_vc_exception, SevStatus},
+    msr::{get_cpuid, CpuidRegister, CpuidRequest, SevStatus},
 };
 use spinning_top::Spinlock;
 use x86_64::{
-    registers::{control::Cr2, mxcsr::read},
+    registers::{control::Cr2, model_specific::Msr, mxcsr::read},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
     VirtAddr,
 };
 
-use crate::{shutdown, snp::CPUID_PAGE};
+use crate::{
+    acpi::IoApicInfo,
+    ghcb::GHCB_PROTOCOL,
+    ioapic::{IoApic, MmioRegisters, RedirectionEntry},
+    mm,
+    register_snapshot::{self, save_registers_and_jump},
+    shutdown, smap,
+    snp::CPUID_PAGE,
+    syscall,
+    vc::{self, IoSize, VmmCommunication},
+};
 
 static IDT: Spinlock<InterruptDescriptorTable> = Spinlock::new(InterruptDescriptorTable::new());
 
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times each interrupt vector has been handled.
+static INTERRUPT_COUNTS: [AtomicU64; INTERRUPT_VECTORS] = [ZERO; INTERRUPT_VECTORS];
+
+fn record_interrupt(counts: &[AtomicU64], vector: u8) {
+    counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+fn copy_counts(counts: &[AtomicU64], dst: &mut [u64]) -> usize {
+    dst.iter_mut().zip(counts).map(|(entry, count)| *entry = count.load(Ordering::Relaxed)).count()
+}
+
+/// Records that an interrupt on `vector` is being handled.
+///
+/// Every interrupt handler calls this on entry, so it is kept to a single
+/// relaxed increment.
+#[inline]
+pub(crate) fn count_interrupt(vector: u8) {
+    record_interrupt(&INTERRUPT_COUNTS, vector)
+}
+
+/// Copies the per-vector interrupt counts into `dst`, starting at vector 0, and
+/// returns the number of entries written.
+pub fn copy_interrupt_counts(dst: &mut [u64]) -> usize {
+    copy_counts(&INTERRUPT_COUNTS, dst)
+}
+
 #[naked]
 extern "x86-interrupt" fn general_protection_fault_handler(_: InterruptStackFrame, _: u64) {
     unsafe {
         asm! {
             "push %rax",            // save old rax value
+            "testb $3, 24(%rsp)",   // did the fault come from user mode (CS RPL != 0)?
+            "jnz 2f",               // if so, don't touch user memory; jump to label 2
             "mov 16(%rsp), %rax",   // rax = rsp + 16 (address of the return RIP)
             "cmpw $0x320F, (%rax)", // is RIP pointing to 0x320F (RDMSR)?
             "jne 2f",               // if not, jump to label 2
@@ -51,37 +101,97 @@ extern "x86-interrupt" fn general_protection_fault_handler(_: InterruptStackFram
             "pop %rax",             // restore old rax value. We're now back at the initial state.
             "jmp {}",               // Let the Rust code take care of it. We jmp instead of call, as the
                                     // Rust function will call `iretq` instead of `ret` at the end.
-            sym general_protection_fault_handler_inner,
+            sym general_protection_fault_handler_save_registers,
             options(att_syntax, noreturn)
         }
     }
 }
 
+#[naked]
+extern "x86-interrupt" fn general_protection_fault_handler_save_registers(
+    _: InterruptStackFrame,
+    _: u64,
+) {
+    unsafe { save_registers_and_jump!(general_protection_fault_handler_inner) }
+}
+
 extern "x86-interrupt" fn general_protection_fault_handler_inner(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    smap::deny_user_access();
+    // `rdmsr` faults that are handled by the fast path above are not counted.
+    count_interrupt(13);
+    debug_check_stack_alignment(&stack_frame);
     error!("KERNEL PANIC: GENERAL PROTECTION FAULT!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Error code: {:?}", error_code);
+    log_exception_registers();
     shutdown::shutdown();
 }
 
+/// Checks, in debug builds only, that the stack pointer interrupted by an
+/// exception or interrupt is sanely aligned.
+///
+/// The CPU aligns the handler's own stack to 16 bytes before pushing the stack
+/// frame, so only the interrupted stack pointer needs checking.
+pub(crate) fn debug_check_stack_alignment(stack_frame: &InterruptStackFrame) {
+    debug_check_interrupted_stack(stack_frame.stack_pointer, stack_frame.instruction_pointer);
+}
+
+/// Same as [`debug_check_stack_alignment`], for handlers that don't get an
+/// [`InterruptStackFrame`].
+fn debug_check_interrupted_stack(stack_pointer: VirtAddr, instruction_pointer: VirtAddr) {
+    debug_assert!(
+        syscall::is_stack_aligned(stack_pointer, syscall::INTERRUPTED_STACK_ALIGNMENT),
+        "stack misaligned on interrupt entry: {:?} (RIP: {:?})",
+        stack_pointer,
+        instruction_pointer
+    );
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(3);
+    debug_check_stack_alignment(&stack_frame);
     log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
+#[naked]
+extern "x86-interrupt" fn page_fault_handler(_: InterruptStackFrame, _: PageFaultErrorCode) {
+    unsafe { save_registers_and_jump!(page_fault_handler_inner) }
+}
+
+extern "x86-interrupt" fn page_fault_handler_inner(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    smap::deny_user_access();
+    count_interrupt(14);
+    debug_check_stack_alignment(&stack_frame);
+    // Writes to copy-on-write pages are expected; see <mm::cow>.
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && mm::cow::handle_write_fault(Cr2::read())
+    {
+        return;
+    }
+
     error!("KERNEL PANIC: PAGE FAULT");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Faulting virtual address: {:#018x}", Cr2::read());
     error!("Error code: {:?}", error_code);
+    log_exception_registers();
     shutdown::shutdown();
 }
 
+/// Logs the register state captured on entry to the exception handler.
+fn log_exception_registers() {
+    if let Some(registers) = register_snapshot::exception_registers() {
+        error!("Registers at the time of the exception:\n{}", registers);
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
@@ -92,117 +202,203 @@ extern "x86-interrupt" fn double_fault_handler(
     // fault, and (c) we want to be sure that we shut down the machine after us.
     // Note that for double fault handlers the error code will always be 0, so
     // there's no point in logging that.
+    smap::deny_user_access();
+    count_interrupt(8);
     error!("KERNEL PANIC: DOUBLE FAULT");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Code segment: {:#x}", stack_frame.deref().code_segment);
     error!("Stack pointer: {:#016x}", stack_frame.deref().stack_pointer.as_u64());
     error!("Stack segment: {:#x}", stack_frame.deref().stack_segment);
     error!("CPU flags: {:#x}", stack_frame.deref().cpu_flags);
+    // Only checked once everything has been logged, as a failed check panics.
+    debug_check_stack_alignment(&stack_frame);
     shutdown::shutdown();
 }
 
+/// Forwards `#VC` requests to the hypervisor using whichever mechanism is
+/// available at the time of the exception.
+struct KernelVmmCommunication;
+
+impl KernelVmmCommunication {
+    /// Runs `f` with the GHCB, if it has been initialized and isn't already in
+    /// use by the code that raised the exception.
+    fn with_ghcb<T>(
+        f: impl FnOnce(&mut GhcbProtocol<'static, Ghcb>) -> Result<T, &'static str>,
+    ) -> Result<T, &'static str> {
+        let mut ghcb = GHCB_PROTOCOL
+            .get()
+            .ok_or("GHCB not initialized")?
+            .try_lock()
+            .ok_or("GHCB is already in use")?;
+        f(&mut ghcb)
+    }
+}
+
+impl VmmCommunication for KernelVmmCommunication {
+    fn cpuid(&mut self, input: CpuidInput) -> Result<CpuidOutput, &'static str> {
+        if let Some(cpuid_page) = CPUID_PAGE.get() {
+            let count = cpuid_page.count as usize;
+            // TODO(#3470): Improve handling of incorrect/missing CPUID requests.
+            let found = cpuid_page.cpuid_data[0..count]
+                .iter()
+                .find(|item| item.input == input)
+                .ok_or("requested CPUID not present in CPUID page")?;
+            return Ok(CpuidOutput {
+                eax: found.output.eax,
+                ebx: found.output.ebx,
+                ecx: found.output.ecx,
+                edx: found.output.edx,
+            });
+        }
+        let CpuidInput { eax, ecx, xcr0, xss } = input;
+        if let Ok(output) =
+            Self::with_ghcb(|ghcb| ghcb.get_cpuid(CpuidInput { eax, ecx, xcr0, xss }))
+        {
+            return Ok(output);
+        }
+        // Fall back to the MSR protocol, which does not support sub-leaf requests or
+        // leaf 0x0000_000D.
+        // See section 2.3.1 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>
+        // TODO(#3470): Improve handling of incorrect/missing CPUID requests.
+        if ecx != 0 || eax == 0x0000_000D {
+            return Err("CPUID sub-leaf or invalid leaf requested");
+        }
+        let get = |register| {
+            get_cpuid(CpuidRequest { leaf: eax, register }).map(|response| response.value)
+        };
+        Ok(CpuidOutput {
+            eax: get(CpuidRegister::Eax)?,
+            ebx: get(CpuidRegister::Ebx)?,
+            ecx: get(CpuidRegister::Ecx)?,
+            edx: get(CpuidRegister::Edx)?,
+        })
+    }
+
+    fn msr_read(&mut self, msr: u32) -> Result<u64, &'static str> {
+        Self::with_ghcb(|ghcb| ghcb.msr_read(msr))
+    }
+
+    fn msr_write(&mut self, msr: u32, value: u64) -> Result<(), &'static str> {
+        Self::with_ghcb(|ghcb| ghcb.msr_write(msr, value))
+    }
+
+    fn io_read(&mut self, port: u16, size: IoSize) -> Result<u32, &'static str> {
+        Self::with_ghcb(|ghcb| VmmCommunication::io_read(ghcb, port, size))
+    }
+
+    fn io_write(&mut self, port: u16, size: IoSize, value: u32) -> Result<(), &'static str> {
+        Self::with_ghcb(|ghcb| VmmCommunication::io_write(ghcb, port, size, value))
+    }
+}
+
+/// Maximum length of an x86 instruction.
+const MAX_INSTRUCTION_LENGTH: u64 = 15;
+
+/// Checks that the #VC handler may fetch the instruction at `rip` that was
+/// executed from code segment `cs`, and returns whether it was executed in
+/// user mode.
+///
+/// Instructions executed in user mode must lie entirely within user space; the
+/// handler reads them with user access enabled, so it must not be tricked into
+/// reading kernel memory on the payload's behalf.
+fn check_instruction_fetch(cs: u64, rip: VirtAddr) -> Result<bool, &'static str> {
+    if cs & 3 == 0 {
+        return Ok(false);
+    }
+    match rip.as_u64().checked_add(MAX_INSTRUCTION_LENGTH) {
+        Some(end) if end <= syscall::USER_SPACE_LIMIT => Ok(true),
+        _ => Err("user mode instruction pointer is not in user space"),
+    }
+}
+
 mutable_interrupt_handler_with_error_code!(
     unsafe fn vmm_communication_exception_handler(
         stack_frame: &mut MutableInterruptStackFrame,
         error_code: u64,
     ) {
-        match error_code {
-            0x72 => {
-                // Make sure it was triggered from a CPUID instruction.
-                const CPUID_INSTRUCTION: u16 = 0xa20f;
-                // Safety: we are copying two bytes and interpreting it as a
-                // 16-bit number without making any other assumptions about
-                // the layout.
-                let instruction: u16 =
-                    unsafe { core::ptr::read_unaligned(stack_frame.rip.as_ptr()) };
-                if instruction != CPUID_INSTRUCTION {
-                    panic!("INSTRUCTION WAS NOT CPUID");
-                }
-
-                if let Some(cpuid_page) = CPUID_PAGE.get() {
-                    let target = stack_frame.into();
-                    let count = cpuid_page.count as usize;
-                    if let Some(found) =
-                        cpuid_page.cpuid_data[0..count].iter().find(|item| item.input == target)
-                    {
-                        stack_frame.rax = found.output.eax as u64;
-                        stack_frame.rbx = found.output.ebx as u64;
-                        stack_frame.rcx = found.output.ecx as u64;
-                        stack_frame.rdx = found.output.edx as u64;
-                    } else {
-                        // For now we just log the error so that we can see when this happens.
-                        // TODO(#3470): Improve handling of incorrect/missing CPUID requests.
-                        error!("KERNEL PANIC: REQUESTED CPUID NOT PRESENT IN CPUID PAGE");
-                        error!("Instruction pointer: {:#016x}", stack_frame.rip.as_u64());
-                        error!("RAX: {:#016x}", stack_frame.rax);
-                        error!("RCX: {:#016x}", stack_frame.rcx);
-                        shutdown::shutdown();
-                    }
-                } else {
-                    let leaf = stack_frame.rax as u32;
-                    // The MSR protocol does not support sub-leaf requests or leaf 0x0000_000D.
-                    // See section 2.3.1 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>
-                    // TODO(#3470): Improve handling of incorrect/missing CPUID requests.
-                    if stack_frame.rcx != 0 || leaf == 0x0000_000D {
-                        error!("KERNEL PANIC: CPUID SUB-LEAF OR INVALID LEAD REQUESTED");
-                        error!("Instruction pointer: {:#016x}", stack_frame.rip.as_u64());
-                        error!("RAX: {:#016x}", stack_frame.rax);
-                        error!("RCX: {:#016x}", stack_frame.rcx);
-                        shutdown::shutdown();
-                    }
-                    get_cpuid_for_vc_exception(leaf, stack_frame).expect("error reading CPUID");
-                }
-                // CPUID instruction is 2 bytes long, so we advance the instruction pointer by
-                // 2.
-                stack_frame.rip += 2u64;
-            }
-            _ => {
-                error!("KERNEL PANIC: UNHANDLED #VC EXCEPTION");
-                error!("Instruction pointer: {:#016x}", stack_frame.rip.as_u64());
-                error!("Error code: {:#x}", error_code);
-                shutdown::shutdown();
+        smap::deny_user_access();
+        count_interrupt(29);
+        debug_check_interrupted_stack(stack_frame.rsp, stack_frame.rip);
+        let rip = stack_frame.rip;
+        let from_user = match check_instruction_fetch(stack_frame.cs, rip) {
+            Ok(from_user) => from_user,
+            Err(err) => panic!("rejecting #VC exception at {:#016x}: {}", rip.as_u64(), err),
+        };
+        // Safety: the instruction pointer points at the instruction that raised the
+        // exception, and we only read as many bytes as are needed to decode it. If the
+        // instruction was executed in user mode, we've checked that it lies in user
+        // space.
+        let read = |offset: usize| unsafe { rip.as_ptr::<u8>().add(offset).read() };
+        let fetch = |offset: usize| {
+            debug_assert!((offset as u64) < MAX_INSTRUCTION_LENGTH);
+            if from_user {
+                smap::with_user_access(|| read(offset))
+            } else {
+                read(offset)
             }
+        };
+        if let Err(err) = vc::emulate(error_code, stack_frame, fetch, &mut KernelVmmCommunication) {
+            panic!(
+                "unhandled #VC exception with exit code {:#x} at {:#016x}: {}",
+                error_code,
+                rip.as_u64(),
+                err
+            );
         }
     }
 );
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(0);
     error!("KERNEL PANIC: DIVIDE BY ZERO!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(2);
     error!("KERNEL PANIC: NON-MASKABLE INTERRUPT!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(4);
     error!("KERNEL PANIC: OVERFLOW!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn bound_range_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(5);
     error!("KERNEL PANIC: BOUND RANGE EXCEEDED!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(6);
     error!("KERNEL PANIC: INVALID OPCODE!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(7);
     error!("KERNEL PANIC: DEVICE NOT AVAILABLE!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    smap::deny_user_access();
+    count_interrupt(10);
     error!("KERNEL PANIC: INVALID TSS!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Error code: {:?}", error_code);
@@ -213,6 +409,8 @@ extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    smap::deny_user_access();
+    count_interrupt(11);
     error!("KERNEL PANIC: SEGMENT NOT PRESENT!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Error code: {:?}", error_code);
@@ -223,6 +421,8 @@ extern "x86-interrupt" fn stack_exception_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    smap::deny_user_access();
+    count_interrupt(12);
     error!("KERNEL PANIC: STACK EXCEPTION!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Error code: {:?}", error_code);
@@ -230,6 +430,8 @@ extern "x86-interrupt" fn stack_exception_handler(
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(16);
     error!("KERNEL PANIC: X87 FLOATING POINT EXCEPTION!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
@@ -239,6 +441,8 @@ extern "x86-interrupt" fn alignment_check_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    smap::deny_user_access();
+    count_interrupt(17);
     error!("KERNEL PANIC: ALIGNMENT CHECK EXCEPTION!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     error!("Error code: {:?}", error_code);
@@ -246,12 +450,16 @@ extern "x86-interrupt" fn alignment_check_handler(
 }
 
 extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    smap::deny_user_access();
+    count_interrupt(18);
     error!("KERNEL PANIC: MACHINE CHECK EXCEPTION!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     shutdown::shutdown();
 }
 
 extern "x86-interrupt" fn simd_fp_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(19);
     error!("KERNEL PANIC: SIMD FLOATING POINT EXCEPTION!");
     error!("Instruction pointer: {:#016x}", stack_frame.deref().instruction_pointer.as_u64());
     let mxcsr = read();
@@ -293,6 +501,7 @@ pub fn init_idt_early() {
     unsafe {
         idt.vmm_communication_exception.set_handler_addr(vc_handler_address); // vector 29
     }
+    idt[TIMER_VECTOR as usize].set_handler_fn(timer_handler);
 
     // Safety: unfortunately we have to escape from the borrow checker here, as we
     // know the IDT is 'static but the `idt` variable (the mutex lock) is not
@@ -371,3 +580,227 @@ pub unsafe fn init_pic8259(sev_status: SevStatus) -> Result<(), &'static str> {
 
     Ok(())
 }
+
+/// The vector that device interrupts for GSI 0 are routed to; GSI `n` is
+/// routed to vector `IRQ_VECTOR_BASE + n`.
+///
+/// Vectors 0x20..0x30 are left for the (masked) legacy PICs.
+pub const IRQ_VECTOR_BASE: u8 = 0x30;
+
+/// The I/O APICs, with all their interrupts masked unless enabled by a driver.
+static IO_APICS: Spinlock<Vec<IoApic<MmioRegisters>>> = Spinlock::new(Vec::new());
+
+/// Sets up the I/O APICs described by the ACPI tables, masking all their
+/// interrupts.
+///
+/// # Safety
+///
+/// The caller has to guarantee that the I/O APIC descriptions are accurate
+/// and that nothing else accesses the I/O APIC registers.
+pub unsafe fn init_io_apics(io_apics: &[IoApicInfo]) -> Result<(), &'static str> {
+    let mut initialized = IO_APICS.lock();
+    for info in io_apics {
+        let base = mm::map_mmio(info.address)?;
+        let mut io_apic = IoApic::new(MmioRegisters::new(base), info.gsi_base);
+        io_apic.mask_all();
+        initialized.push(io_apic);
+    }
+    Ok(())
+}
+
+/// Returns the vector that interrupts for `gsi` are delivered to.
+pub fn irq_vector(gsi: u32) -> Option<u8> {
+    u8::try_from(gsi)
+        .ok()
+        .and_then(|gsi| IRQ_VECTOR_BASE.checked_add(gsi))
+        .filter(|vector| *vector < TIMER_VECTOR)
+}
+
+/// Installs `handler` for device interrupt vector `vector`.
+///
+/// The interrupt is not delivered until the corresponding GSI is enabled with
+/// [`enable_irq`].
+pub fn register_irq(
+    vector: u8,
+    handler: extern "x86-interrupt" fn(InterruptStackFrame),
+) -> Result<(), &'static str> {
+    if vector < IRQ_VECTOR_BASE || vector >= TIMER_VECTOR {
+        return Err("vector is reserved");
+    }
+    // The IDT is already loaded, and the CPU reads the entries from memory, so
+    // there is no need to reload it.
+    IDT.lock()[vector as usize].set_handler_fn(handler);
+    Ok(())
+}
+
+/// Routes `gsi` to its vector (see [`irq_vector`]) on the bootstrap processor
+/// and unmasks it.
+///
+/// Interrupts are configured as edge-triggered and active-high, which is what
+/// virtio-mmio devices use.
+pub fn enable_irq(gsi: u32) -> Result<(), &'static str> {
+    let vector = irq_vector(gsi).ok_or("no vector available for GSI")?;
+    with_io_apic(gsi, |io_apic| {
+        io_apic.set_entry(
+            gsi,
+            RedirectionEntry {
+                vector,
+                destination: 0,
+                level_triggered: false,
+                active_low: false,
+                masked: false,
+            },
+        )
+    })
+}
+
+/// The IA32_APIC_BASE MSR.
+const APIC_BASE_MSR: u32 = 0x1B;
+
+/// Bit in IA32_APIC_BASE that is set if the local APIC is in x2APIC mode.
+const APIC_BASE_X2APIC_ENABLED: u64 = 1 << 10;
+
+/// The x2APIC end-of-interrupt register.
+const X2APIC_EOI_MSR: u32 = 0x80B;
+
+/// Returns whether the local APIC is in x2APIC mode.
+///
+/// Stage 0 switches the local APIC to x2APIC mode if the CPU supports it. We
+/// only know how to acknowledge device interrupts in that mode (see
+/// [`end_of_interrupt`]), so drivers should fall back to polling otherwise.
+pub fn x2apic_enabled() -> bool {
+    // Safety: reading IA32_APIC_BASE has no side effects.
+    unsafe { Msr::new(APIC_BASE_MSR).read() & APIC_BASE_X2APIC_ENABLED != 0 }
+}
+
+/// Signals the end of a device interrupt to the local APIC, so that it will
+/// deliver further interrupts of the same or lower priority.
+///
+/// Must only be called from device interrupt handlers, and only if
+/// [`x2apic_enabled`] returns true.
+pub fn end_of_interrupt() {
+    // Safety: writing zero to the EOI register only acknowledges the interrupt
+    // that is currently being serviced.
+    unsafe { Msr::new(X2APIC_EOI_MSR).write(0) }
+}
+
+/// The vector the local APIC timer interrupt is delivered on; see
+/// [`sleep_until`]. Device interrupts are only routed to vectors below it.
+const TIMER_VECTOR: u8 = 0xEF;
+
+/// The x2APIC LVT timer register.
+const X2APIC_LVT_TIMER_MSR: u32 = 0x832;
+
+/// Timer mode bits in the LVT timer register selecting TSC-deadline mode.
+const LVT_TIMER_TSC_DEADLINE: u64 = 0b10 << 17;
+
+/// The IA32_TSC_DEADLINE MSR.
+const TSC_DEADLINE_MSR: u32 = 0x6E0;
+
+/// Bit in ECX of CPUID leaf 1 that is set if the local APIC timer supports
+/// TSC-deadline mode.
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
+    smap::deny_user_access();
+    count_interrupt(TIMER_VECTOR);
+    debug_check_stack_alignment(&stack_frame);
+    // The timer only exists to wake up `sleep_until`, which checks the time itself.
+    end_of_interrupt();
+}
+
+/// Waits until the TSC reaches `deadline`.
+///
+/// If the local APIC is in x2APIC mode and supports TSC-deadline mode, the CPU
+/// is halted until the timer fires (or any other interrupt arrives). Otherwise
+/// nothing would wake us up from `hlt`, so we spin with `pause` instead.
+pub fn sleep_until(deadline: u64) {
+    // Safety: CPUID is available on all x86-64 CPUs, and leaf 1 always exists.
+    let tsc_deadline = unsafe { __cpuid(1) }.ecx & CPUID_TSC_DEADLINE != 0;
+    if !tsc_deadline || !x2apic_enabled() {
+        while rdtsc() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+    // Safety: the timer interrupt is delivered on a vector that has a handler, and
+    // the handler only acknowledges the interrupt.
+    unsafe { Msr::new(X2APIC_LVT_TIMER_MSR).write(LVT_TIMER_TSC_DEADLINE | TIMER_VECTOR as u64) };
+    while rdtsc() < deadline {
+        // Safety: (re)arming the timer has no effect other than the interrupt. A
+        // deadline that has already passed fires immediately.
+        unsafe { Msr::new(TSC_DEADLINE_MSR).write(deadline) };
+        // `sti; hlt` doesn't let an interrupt in between the two instructions, so
+        // the timer firing right after it was armed still wakes us up.
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
+    }
+    if enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+}
+
+/// Masks `gsi`, leaving its routing in place.
+pub fn disable_irq(gsi: u32) -> Result<(), &'static str> {
+    with_io_apic(gsi, |io_apic| io_apic.set_masked(gsi, true))
+}
+
+fn with_io_apic<F>(gsi: u32, f: F) -> Result<(), &'static str>
+where
+    F: FnOnce(&mut IoApic<MmioRegisters>) -> Result<(), &'static str>,
+{
+    let mut io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or("no I/O APIC handles the GSI")?;
+    f(io_apic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instruction_fetch_checked() {
+        let kernel_rip = VirtAddr::new(0xFFFF_FFFF_8020_1000);
+        let user_rip = VirtAddr::new(0x20_1000);
+        // Kernel code segment (RPL 0) and user code segment (RPL 3).
+        assert_eq!(check_instruction_fetch(0x08, kernel_rip), Ok(false));
+        assert_eq!(check_instruction_fetch(0x23, user_rip), Ok(true));
+        assert!(check_instruction_fetch(0x23, kernel_rip).is_err());
+        // The whole instruction has to be in user space.
+        assert!(check_instruction_fetch(0x23, VirtAddr::new(0x7FFF_FFFF_FFF8)).is_err());
+        assert_eq!(check_instruction_fetch(0x23, VirtAddr::new(0x7FFF_FFFF_FFF1)), Ok(true));
+    }
+
+    #[test]
+    fn counts_interrupts_per_vector() {
+        let counts: [AtomicU64; INTERRUPT_VECTORS] = [ZERO; INTERRUPT_VECTORS];
+        record_interrupt(&counts, 14);
+        record_interrupt(&counts, 14);
+        record_interrupt(&counts, IRQ_VECTOR_BASE);
+        record_interrupt(&counts, 255);
+
+        let mut dst = [u64::MAX; INTERRUPT_VECTORS];
+        assert_eq!(copy_counts(&counts, &mut dst), INTERRUPT_VECTORS);
+        assert_eq!(dst[14], 2);
+        assert_eq!(dst[IRQ_VECTOR_BASE as usize], 1);
+        assert_eq!(dst[255], 1);
+        assert_eq!(dst.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn copies_only_as_many_counts_as_fit() {
+        let counts: [AtomicU64; INTERRUPT_VECTORS] = [ZERO; INTERRUPT_VECTORS];
+        record_interrupt(&counts, 3);
+        record_interrupt(&counts, 4);
+
+        let mut dst = [0; 4];
+        assert_eq!(copy_counts(&counts, &mut dst), 4);
+        assert_eq!(dst, [0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,269 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Driver for the I/O APIC, which routes device interrupts (identified by
+//! their global system interrupt number, or GSI) to interrupt vectors.
+//!
+//! The I/O APIC is accessed indirectly: the index of a register is written to
+//! `IOREGSEL`, after which the register can be accessed via `IOWIN`. See the
+//! Intel 82093AA I/O APIC datasheet for more details.
+
+use x86_64::VirtAddr;
+
+/// Offset of the register select register from the I/O APIC base address.
+const IOREGSEL: usize = 0x00;
+/// Offset of the register window register from the I/O APIC base address.
+const IOWIN: usize = 0x10;
+
+/// Index of the version register, which contains the number of redirection
+/// entries.
+const IOAPICVER: u8 = 0x01;
+/// Index of the low half of the first redirection entry. Every entry occupies
+/// two consecutive registers.
+const IOREDTBL: u8 = 0x10;
+
+/// Access to the indirectly addressed I/O APIC registers.
+pub trait IoApicRegisters {
+    fn read(&mut self, index: u8) -> u32;
+    fn write(&mut self, index: u8, value: u32);
+}
+
+/// I/O APIC registers accessed through memory-mapped I/O.
+pub struct MmioRegisters {
+    base: *mut u32,
+}
+
+impl MmioRegisters {
+    /// # Safety
+    ///
+    /// The caller has to guarantee that `base` is the virtual address at which
+    /// the registers of an I/O APIC are mapped, and that nothing else accesses
+    /// them.
+    pub unsafe fn new(base: VirtAddr) -> Self {
+        Self { base: base.as_mut_ptr() }
+    }
+
+    fn select(&mut self, index: u8) {
+        // Safety: the constructor guarantees the registers are mapped at `base`.
+        unsafe { self.base.byte_add(IOREGSEL).write_volatile(index as u32) }
+    }
+}
+
+impl IoApicRegisters for MmioRegisters {
+    fn read(&mut self, index: u8) -> u32 {
+        self.select(index);
+        // Safety: the constructor guarantees the registers are mapped at `base`.
+        unsafe { self.base.byte_add(IOWIN).read_volatile() }
+    }
+
+    fn write(&mut self, index: u8, value: u32) {
+        self.select(index);
+        // Safety: the constructor guarantees the registers are mapped at `base`.
+        unsafe { self.base.byte_add(IOWIN).write_volatile(value) }
+    }
+}
+
+// Safety: the registers are only accessed through `&mut self`, so moving them
+// to another thread can't lead to concurrent access.
+unsafe impl Send for MmioRegisters {}
+
+/// Contents of a redirection table entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    /// The interrupt vector delivered to the CPU.
+    pub vector: u8,
+    /// APIC ID of the CPU the interrupt is delivered to.
+    pub destination: u8,
+    pub level_triggered: bool,
+    pub active_low: bool,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+    const DESTINATION_SHIFT: u64 = 56;
+
+    /// Returns the raw value of the entry. The delivery mode is always fixed
+    /// and the destination mode is always physical.
+    pub fn to_raw(self) -> u64 {
+        let mut raw = self.vector as u64 | ((self.destination as u64) << Self::DESTINATION_SHIFT);
+        if self.active_low {
+            raw |= Self::ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            raw |= Self::LEVEL_TRIGGERED;
+        }
+        if self.masked {
+            raw |= Self::MASKED;
+        }
+        raw
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            vector: raw as u8,
+            destination: (raw >> Self::DESTINATION_SHIFT) as u8,
+            level_triggered: raw & Self::LEVEL_TRIGGERED != 0,
+            active_low: raw & Self::ACTIVE_LOW != 0,
+            masked: raw & Self::MASKED != 0,
+        }
+    }
+}
+
+pub struct IoApic<R> {
+    registers: R,
+    /// The first GSI handled by this I/O APIC.
+    gsi_base: u32,
+    /// The number of redirection entries, and thus GSIs, of this I/O APIC.
+    entries: u32,
+}
+
+impl<R: IoApicRegisters> IoApic<R> {
+    pub fn new(mut registers: R, gsi_base: u32) -> Self {
+        let entries = ((registers.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        Self { registers, gsi_base, entries }
+    }
+
+    /// Returns whether `gsi` is routed by this I/O APIC.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi.checked_sub(self.gsi_base).is_some_and(|index| index < self.entries)
+    }
+
+    fn entry_index(&self, gsi: u32) -> Result<u8, &'static str> {
+        if !self.handles(gsi) {
+            return Err("GSI not handled by this I/O APIC");
+        }
+        // There are at most 256 entries, so this fits.
+        Ok(IOREDTBL + 2 * (gsi - self.gsi_base) as u8)
+    }
+
+    pub fn entry(&mut self, gsi: u32) -> Result<RedirectionEntry, &'static str> {
+        let index = self.entry_index(gsi)?;
+        let low = self.registers.read(index) as u64;
+        let high = self.registers.read(index + 1) as u64;
+        Ok(RedirectionEntry::from_raw((high << 32) | low))
+    }
+
+    pub fn set_entry(&mut self, gsi: u32, entry: RedirectionEntry) -> Result<(), &'static str> {
+        let index = self.entry_index(gsi)?;
+        let raw = entry.to_raw();
+        // Write the half containing the mask last, so that the interrupt is not
+        // delivered to the wrong destination while we are updating the entry.
+        self.registers.write(index + 1, (raw >> 32) as u32);
+        self.registers.write(index, raw as u32);
+        Ok(())
+    }
+
+    /// Masks or unmasks the interrupt for `gsi`, leaving the rest of the entry
+    /// as it was.
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) -> Result<(), &'static str> {
+        let entry = self.entry(gsi)?;
+        self.set_entry(gsi, RedirectionEntry { masked, ..entry })
+    }
+
+    /// Masks all interrupts handled by this I/O APIC.
+    pub fn mask_all(&mut self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            // The GSI is in range, so this can't fail.
+            let _ = self.set_masked(gsi, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    /// Fake register file that records all writes.
+    struct MockRegisters {
+        registers: Vec<u32>,
+        writes: Vec<(u8, u32)>,
+    }
+
+    impl MockRegisters {
+        fn new(entries: u32) -> Self {
+            let mut registers = vec![0; 0x10 + 2 * entries as usize];
+            registers[IOAPICVER as usize] = ((entries - 1) << 16) | 0x11;
+            Self { registers, writes: Vec::new() }
+        }
+    }
+
+    impl IoApicRegisters for MockRegisters {
+        fn read(&mut self, index: u8) -> u32 {
+            self.registers[index as usize]
+        }
+
+        fn write(&mut self, index: u8, value: u32) {
+            self.registers[index as usize] = value;
+            self.writes.push((index, value));
+        }
+    }
+
+    #[test]
+    fn reads_number_of_entries() {
+        let io_apic = IoApic::new(MockRegisters::new(24), 16);
+        assert!(!io_apic.handles(15));
+        assert!(io_apic.handles(16));
+        assert!(io_apic.handles(39));
+        assert!(!io_apic.handles(40));
+    }
+
+    #[test]
+    fn programs_redirection_entry() {
+        let mut io_apic = IoApic::new(MockRegisters::new(24), 0);
+        let entry = RedirectionEntry {
+            vector: 0x35,
+            destination: 2,
+            level_triggered: true,
+            active_low: true,
+            masked: false,
+        };
+        io_apic.set_entry(5, entry).unwrap();
+
+        // GSI 5 is at registers 0x1A (low) and 0x1B (high); the high half is
+        // written first.
+        assert_eq!(io_apic.registers.writes, [(0x1B, 0x0200_0000), (0x1A, 0x0000_A035)]);
+        assert_eq!(io_apic.entry(5), Ok(entry));
+    }
+
+    #[test]
+    fn masking_preserves_entry() {
+        let mut io_apic = IoApic::new(MockRegisters::new(24), 0);
+        let entry = RedirectionEntry {
+            vector: 0x40,
+            destination: 0,
+            level_triggered: false,
+            active_low: false,
+            masked: false,
+        };
+        io_apic.set_entry(3, entry).unwrap();
+        io_apic.set_masked(3, true).unwrap();
+        assert_eq!(io_apic.registers.registers[0x16], 0x0001_0040);
+        io_apic.set_masked(3, false).unwrap();
+        assert_eq!(io_apic.entry(3), Ok(entry));
+    }
+
+    #[test]
+    fn rejects_unhandled_gsi() {
+        let mut io_apic = IoApic::new(MockRegisters::new(24), 0);
+        assert!(io_apic.set_masked(24, false).is_err());
+        assert!(io_apic.registers.writes.is_empty());
+    }
+}
@@ -39,24 +39,47 @@
 
 mod acpi;
 mod args;
+mod attestation;
 mod avx;
 mod boot;
+mod clock;
+mod cpu;
 mod descriptors;
 mod elf;
+// Only the early boot path propagates its errors so far.
+#[allow(dead_code)]
+mod error;
 mod ghcb;
 mod interrupts;
+mod ioapic;
 mod libm;
 mod logging;
 mod memory;
 mod mm;
+pub mod panic_reporter;
 mod payload;
+mod percpu;
+#[cfg(not(feature = "initrd"))]
+mod ramdisk;
+mod rate_limit;
+mod ready;
+mod register_snapshot;
+mod rng;
 #[cfg(feature = "serial_channel")]
 mod serial;
+mod shared_log;
+#[cfg(feature = "shmem_channel")]
+mod shmem_channel;
 pub mod shutdown;
 #[cfg(feature = "simple_io_channel")]
 mod simpleio;
+mod smap;
 mod snp;
 mod syscall;
+mod tee;
+mod util;
+mod vc;
+mod vdso;
 #[cfg(feature = "vsock_channel")]
 mod virtio;
 #[cfg(feature = "virtio_console_channel")]
@@ -67,30 +90,31 @@ extern crate std;
 
 extern crate alloc;
 
-use alloc::{alloc::Allocator, boxed::Box};
+use alloc::{alloc::Allocator, boxed::Box, vec::Vec};
 use core::{panic::PanicInfo, pin::Pin, str::FromStr};
 
 use linked_list_allocator::LockedHeap;
-use log::{error, info};
+use log::{debug, error, info};
 use mm::{
     frame_allocator::PhysicalMemoryAllocator, page_tables::CurrentRootPageTable,
     virtual_address_allocator::VirtualAddressAllocator,
 };
 use oak_channel::Channel;
 use oak_core::sync::OnceCell;
-use oak_linux_boot_params::BootParams;
 use oak_sev_guest::msr::{change_snp_state_for_frame, get_sev_status, PageAssignment, SevStatus};
 use spinning_top::Spinlock;
-use strum::{EnumIter, EnumString, IntoEnumIterator};
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
 use x86_64::{
-    structures::paging::{Page, PageTable, Size2MiB},
+    structures::paging::{PageTable, PhysFrame, Size2MiB},
     PhysAddr, VirtAddr,
 };
-use zerocopy::FromBytes;
+use zerocopy::{AsBytes, FromBytes};
 use zeroize::Zeroize;
 
 use crate::{
     acpi::Acpi,
+    boot::Protocol,
+    error::{InSubsystem, KernelError, Subsystem},
     mm::Translator,
     payload::Process,
     snp::{get_snp_page_addresses, init_snp_pages},
@@ -115,30 +139,53 @@ pub static PAGE_TABLES: Spinlock<CurrentRootPageTable> =
 pub static BASE_L4_PAGE_TABLE: OnceCell<Pin<Box<PageTable>>> = OnceCell::new();
 
 /// Allocator for long-lived pages in the kernel.
-pub static VMA_ALLOCATOR: Spinlock<VirtualAddressAllocator<Size2MiB>> =
-    Spinlock::new(VirtualAddressAllocator::new(Page::range(
-        // Assign 32 TB of virtual memory for this allocator.
-        // Safety: these addresses are constants and thus we know they're page-aligned.
-        unsafe {
-            Page::from_start_address_unchecked(VirtAddr::new_truncate(0xFFFF_C900_0000_0000))
-        },
-        unsafe {
-            Page::from_start_address_unchecked(VirtAddr::new_truncate(0xFFFF_E900_0000_0000))
-        },
-    )));
+///
+/// Successive allocations are separated by an unmapped guard page, so that
+/// overrunning one allocation causes a page fault rather than corrupting the
+/// next allocation.
+pub static VMA_ALLOCATOR: Spinlock<VirtualAddressAllocator<Size2MiB>> = Spinlock::new(
+    VirtualAddressAllocator::with_guard_pages(mm::virtual_address_allocator::default_window(), 1),
+);
+
+/// Determines the position of the encrypted bit if memory encryption is
+/// enabled, and sets up the GHCB under SEV-ES.
+///
+/// Returns the position of the encrypted bit, if any.
+fn init_memory_encryption(sev_status: SevStatus) -> Result<Option<u8>, KernelError> {
+    let encrypted_bit = sev_status
+        .contains(SevStatus::SEV_ENABLED)
+        .then(mm::init_encrypted_bit_position)
+        .transpose()
+        .in_subsystem(Subsystem::Memory)?;
+    if sev_status.contains(SevStatus::SEV_ES_ENABLED) {
+        // The GHCB is required for I/O, so there is no point in continuing without it.
+        ghcb::init(sev_status.contains(SevStatus::SNP_ACTIVE))?;
+    }
+    Ok(encrypted_bit)
+}
 
 /// Main entry point for the kernel, to be called from bootloader.
-pub fn start_kernel(info: &BootParams) -> ! {
+///
+/// Everything the kernel needs from the bootloader is read via the
+/// protocol-agnostic `boot::Protocol` trait. `info` has to be a self-contained
+/// structure in identity-mapped memory, as we keep using it through the direct
+/// mapping once we have set up our own page tables.
+pub fn start_kernel<P: Protocol>(info: &P) -> ! {
     avx::enable_avx();
     descriptors::init_gdt_early();
     interrupts::init_idt_early();
     let sev_status = get_sev_status().unwrap_or(SevStatus::empty());
     let sev_es_enabled = sev_status.contains(SevStatus::SEV_ES_ENABLED);
     let sev_snp_enabled = sev_status.contains(SevStatus::SNP_ACTIVE);
-    if sev_es_enabled {
-        ghcb::init(sev_snp_enabled);
-    }
+    // We can't log yet, so we'll report the encrypted bit once logging is set up.
+    let encrypted_bit = init_memory_encryption(sev_status)
+        .unwrap_or_else(|err| panic!("failed to set up memory encryption: {}", err));
     logging::init_logging(sev_es_enabled);
+    if let Some(encrypted_bit) = encrypted_bit {
+        info!("Memory encryption enabled, encrypted bit: {}", encrypted_bit);
+    }
+    let cpu_info = cpu::CpuInfo::read();
+    debug!("CPU: {:?}", cpu_info);
 
     // Safety: we shouldn't have anything else but the PICs on the I/O ports.
     // If we get an error, we will still try to continue.
@@ -150,13 +197,53 @@ pub fn start_kernel(info: &BootParams) -> ! {
     // example, the multiboot protocol explicitly states data can be placed
     // anywhere in memory; therefore, it's highly likely we will overwrite some
     // data after we initialize the heap. args::init_args() caches the arguments
-    // (as long they are of reasonable length) in a static variable, allowing us
-    // to refer to the args in the future.
-    let kernel_args = args::init_args(info.args()).unwrap();
-    info!("Kernel boot args: {}", kernel_args.args());
+    // (as long as they fit in `args::MAX_ARGS_LEN` bytes) in a static variable,
+    // allowing us to refer to the args in the future.
+    let kernel_args = boot::init_args(info).unwrap();
+    // The command line configures the kernel, so record it in the runtime
+    // measurement registers as well.
+    attestation::measurement_registers::measure(
+        attestation::measurement_registers::CONFIG_REGISTER,
+        kernel_args.args().as_bytes(),
+    )
+    .expect("failed to measure the kernel command line");
+    logging::set_log_cpu_id(kernel_args.get(logging::LOG_CPU_ID_ARG).is_some());
+    match kernel_args.get(shutdown::SHUTDOWN_MODE_ARG).map(shutdown::ShutdownMode::from_arg) {
+        Some(Ok(mode)) => shutdown::set_shutdown_mode(mode),
+        Some(Err(err)) => {
+            log::warn!("Ignoring invalid {} kernel arg: {}", shutdown::SHUTDOWN_MODE_ARG, err)
+        }
+        None => {}
+    }
+
+    if kernel_args.get(cpu::REQUIRE_SNP_ARG).is_some() {
+        if let Err(err) = cpu::check_snp_active(&cpu_info, sev_status) {
+            error!("{}; refusing to continue", err);
+            shutdown::shutdown();
+        }
+        info!("SEV-SNP is active, as required");
+    }
+
+    if let Some(arg) = kernel_args.get(snp::REQUIRE_VMPL_ARG) {
+        let required = snp::parse_vmpl(arg).unwrap_or_else(|err| {
+            error!("invalid {} kernel arg: {}; refusing to continue", snp::REQUIRE_VMPL_ARG, err);
+            shutdown::shutdown();
+        });
+        let actual = sev_snp_enabled.then(snp::current_vmpl);
+        if let Err(err) = snp::check_vmpl(required, actual) {
+            error!("{} (required: {}, actual: {:?}); refusing to continue", err, required, actual);
+            shutdown::shutdown();
+        }
+        info!("Running at VMPL {}, as required", required);
+    }
+
+    match clock::init(&kernel_args, sev_status) {
+        Ok(clock::TscFrequency { hz, source }) => {
+            info!("TSC frequency: {} Hz (source: {:?})", hz, source)
+        }
+        Err(err) => log::warn!("unable to determine TSC frequency: {}", err),
+    }
 
-    let protocol = info.protocol();
-    info!("Boot protocol:  {}", protocol);
     let snp_pages = if sev_snp_enabled {
         // We have to get the physical addresses of the CPUID pages now while the
         // identity mapping is still in place, but we can only initialize the
@@ -167,23 +254,85 @@ pub fn start_kernel(info: &BootParams) -> ! {
     };
 
     // Safety: in the linker script we specify that the ELF header should be placed
-    // at 0x200000.
-    let program_headers = unsafe { elf::get_phdrs(VirtAddr::new(0x20_0000)) };
+    // at 0x200000; `get_phdrs` checks that the header is actually there.
+    let program_headers = unsafe { elf::get_phdrs(VirtAddr::new(0x20_0000)) }
+        .unwrap_or_else(|err| panic!("kernel ELF header not found at 0x200000: {}", err));
 
+    // With the `initrd` feature the ramdisk holds the application; otherwise it
+    // is an optional data blob for the application (see `ramdisk`).
+    let ramdisk = info.ramdisk();
     #[cfg(feature = "initrd")]
-    let ramdisk = info.ramdisk().expect("expected to find a ramdisk");
+    let ramdisk = ramdisk.expect("expected to find a ramdisk");
 
     // Physical frame allocator
     mm::init(
         info.e820_table(),
         program_headers,
         #[cfg(feature = "initrd")]
-        &ramdisk,
+        Some(&ramdisk),
+        #[cfg(not(feature = "initrd"))]
+        ramdisk.as_ref(),
     );
 
+    let payload_images_arg = kernel_args.get(payload::PAYLOAD_IMAGES_ARG).unwrap_or_default();
+
+    // Make sure the frames containing the SNP pages and the GHCB are never handed
+    // out, even if they would otherwise look like usable memory.
+    mm::with_frame_allocator(|frame_allocator| {
+        let reserved = snp_pages
+            .iter()
+            .flat_map(|pages| [pages.cpuid_page_address, pages.secrets_page_address])
+            .chain(ghcb::GHCB_PROTOCOL.get().map(|ghcb| ghcb.lock().get_gpa()));
+        for address in reserved {
+            let frame = PhysFrame::<Size2MiB>::containing_address(address);
+            frame_allocator
+                .reserve(PhysFrame::range(frame, frame + 1))
+                .expect("couldn't reserve frame");
+        }
+        // Additional payload images staged by the VMM must survive until we have
+        // copied them; their frames are never given back.
+        for location in payload::parse_image_locations(payload_images_arg) {
+            let location = location.expect("invalid payload image location");
+            frame_allocator
+                .reserve(location.frames())
+                .expect("payload image overlaps memory that is already in use");
+        }
+    });
+
+    // Allocate a section for guest-host communication (without the `ENCRYPTED` bit
+    // set). If memory is fragmented, we can get by with less, depending on the
+    // channel. This happens before we create the page tables, as the direct
+    // mapping of these frames must not use 1 GiB pages: we'll clear the
+    // `ENCRYPTED` bit on their 2 MiB pages later.
+    let min_guest_host_frames = ChannelType::min_guest_host_frames_for(&kernel_args);
+    let guest_host_frames = mm::with_frame_allocator(|frame_allocator| {
+        memory::allocate_guest_host_frames(
+            frame_allocator,
+            memory::GUEST_HOST_FRAMES,
+            min_guest_host_frames,
+            kernel_args.get(memory::GUEST_HOST_DMA32_ARG).map(|_| mm::frame_allocator::DMA32_LIMIT),
+        )
+    })
+    .unwrap_or_else(|err| panic!("{}", err));
+
+    let direct_map_pages =
+        match kernel_args.get(mm::DIRECT_MAP_PAGES_ARG).map(mm::DirectMapPages::from_arg) {
+            Some(Ok(pages)) => pages,
+            Some(Err(err)) => {
+                log::warn!("Ignoring invalid {} kernel arg: {}", mm::DIRECT_MAP_PAGES_ARG, err);
+                mm::DirectMapPages::default()
+            }
+            None => mm::DirectMapPages::default(),
+        };
+
     // Note: `info` will not be valid after calling this!
     {
-        let pml4_frame = mm::initial_pml4(program_headers).unwrap();
+        let pml4_frame = mm::initial_pml4(
+            program_headers,
+            direct_map_pages,
+            guest_host_frames.start.start_address()..guest_host_frames.end.start_address(),
+        )
+        .unwrap();
         // Prevent execution code in data only memory pages.
         // Safety: executeable memory is assumed to be appropiately marked in the page
         // table.
@@ -192,6 +341,15 @@ pub fn start_kernel(info: &BootParams) -> ! {
                 flags.insert(x86_64::registers::model_specific::EferFlags::NO_EXECUTE_ENABLE)
             });
         };
+        // Make the kernel honour read-only mappings as well, so that kernel writes to
+        // copy-on-write pages in user space fault instead of modifying the shared
+        // frame.
+        // Safety: the kernel only writes to memory that is mapped as writable.
+        unsafe {
+            x86_64::registers::control::Cr0::update(|flags| {
+                flags.insert(x86_64::registers::control::Cr0Flags::WRITE_PROTECT)
+            });
+        };
         // Safety: the new page tables keep the identity mapping at -2GB intact, so it's
         // safe to load the new page tables.
         let prev_page_table = unsafe { PAGE_TABLES.lock().replace(pml4_frame) };
@@ -203,12 +361,10 @@ pub fn start_kernel(info: &BootParams) -> ! {
         // Safety: We just created a page table at this location on the heap.
         let pml4: PageTable = unsafe {
             *Box::from_raw(
-                (PAGE_TABLES
-                    .lock()
-                    .get()
-                    .unwrap()
-                    .translate_physical(PhysAddr::new(pml4_frame.start_address().as_u64()))
-                    .expect("page table must map to virtual address"))
+                mm::with_page_tables(|pt| {
+                    pt.translate_physical(PhysAddr::new(pml4_frame.start_address().as_u64()))
+                })
+                .expect("page table must map to virtual address")
                 // Safety: We get a mut pointer here to satisfy the type system. However, the pml4
                 // will not be mutated. This is since while using this pml4 the kernel will not
                 // allocate memory in application space, and since all the kernel space entries of
@@ -220,84 +376,175 @@ pub fn start_kernel(info: &BootParams) -> ! {
         BASE_L4_PAGE_TABLE.set(Box::pin(pml4)).expect("base pml4 not unset");
     };
 
+    // With our own page tables in place, nothing below the kernel half of the
+    // address space is mapped, so the kernel can stop itself from executing or
+    // accessing user memory by accident.
+    let smap_support = smap::init();
+    info!("SMEP enabled: {}, SMAP enabled: {}", smap_support.smep, smap_support.smap);
+    if kernel_args.get(smap::REQUIRE_SMEP_SMAP_ARG).is_some() {
+        if let Err(err) = smap_support.check_complete() {
+            error!("{}; refusing to continue", err);
+            shutdown::shutdown();
+        }
+    }
+
     // Re-map boot params to the new virtual address.
     // Safety: we know we're addressing valid memory that contains the correct data
     // structure, as we're just translating addresses differently due to the new
     // page tables.
     let info = unsafe {
         #[allow(clippy::unnecessary_cast)]
-        (PAGE_TABLES
-            .lock()
-            .get()
-            .unwrap()
-            .translate_physical(PhysAddr::new(info as *const _ as u64))
+        (mm::with_page_tables(|pt| pt.translate_physical(PhysAddr::new(info as *const _ as u64)))
             .unwrap()
-            .as_ptr() as *const BootParams)
+            .as_ptr() as *const P)
             .as_ref()
             .unwrap()
     };
 
     if sev_es_enabled {
-        let pt_guard = PAGE_TABLES.lock();
-        let mapper = pt_guard.get().unwrap();
-        // Now that the page tables have been updated, we have to re-share the GHCB with
-        // the hypervisor.
-        ghcb::reshare_ghcb(mapper);
-        if sev_snp_enabled {
-            // We must also initialise the CPUID and secrets pages and the guest message
-            // encryptor when SEV-SNP is active. Panicking is OK at this point,
-            // because these pages are required to support the full features and
-            // we don't want to run without them.
-            init_snp_pages(snp_pages.expect("missing SNP CPUID and secrets pages"), mapper);
-        }
+        mm::with_page_tables(|mapper| {
+            // Now that the page tables have been updated, we have to re-share the GHCB with
+            // the hypervisor.
+            if let Err(err) = ghcb::reshare_ghcb(mapper) {
+                panic!("failed to re-share the GHCB: {}", err);
+            }
+            if sev_snp_enabled {
+                // We must also initialise the CPUID and secrets pages and the guest message
+                // encryptor when SEV-SNP is active. Panicking is OK at this point,
+                // because these pages are required to support the full features and
+                // we don't want to run without them.
+                init_snp_pages(snp_pages.expect("missing SNP CPUID and secrets pages"), mapper);
+            }
+        });
     }
 
-    // Allocate a section for guest-host communication (without the `ENCRYPTED` bit
-    // set) We'll allocate 2*2MiB, as virtio needs more than 2 MiB for its data
-    // structures.
-    let guest_host_frames = FRAME_ALLOCATOR.lock().allocate_contiguous(2).unwrap();
-
-    let guest_host_pages = {
-        let pt_guard = PAGE_TABLES.lock();
-        let pt = pt_guard.get().unwrap();
-        Page::range(
-            pt.translate_physical_frame(guest_host_frames.start).unwrap(),
-            pt.translate_physical_frame(guest_host_frames.end).unwrap(),
-        )
-    };
+    mm::encrypted_mapper::set_shared_frames(
+        guest_host_frames.start.start_address()..guest_host_frames.end.start_address(),
+    )
+    .unwrap();
+
+    let guest_host_pages =
+        mm::with_page_tables(|pt| mm::translate_frame_range(pt, guest_host_frames))
+            .unwrap_or_else(|err| panic!("couldn't find the guest-host pages: {}", err));
 
     // If we are running on SNP we have to mark the guest-host frames as shared in
     // the RMP. It is OK to crash if we cannot mark the pages as shared in the
     // RMP.
     if sev_snp_enabled {
+        let backoff = kernel_args
+            .get(snp::PSC_BACKOFF_ARG)
+            .map(|arg| {
+                snp::PscBackoff::from_arg(arg)
+                    .unwrap_or_else(|err| panic!("invalid {}: {}", snp::PSC_BACKOFF_ARG, err))
+            })
+            .unwrap_or_default();
+        let clock = clock::tsc_frequency().map(|frequency| rate_limit::TscClock::new(frequency.hz));
         // TODO(#3414): Use the GHCB protocol when it is available.
-        for frame in guest_host_frames {
-            change_snp_state_for_frame(&frame, PageAssignment::Shared)
+        for (index, frame) in guest_host_frames.enumerate() {
+            if index > 0 {
+                backoff.pace(clock.as_ref());
+            }
+            backoff
+                .retry(clock.as_ref(), || {
+                    change_snp_state_for_frame(&frame, PageAssignment::Shared)
+                })
                 .expect("couldn't change SNP state for frame");
         }
     }
 
+    if let Some(arg) = kernel_args.get(mm::deferred_flush::TLB_FLUSH_THRESHOLD_ARG) {
+        match mm::deferred_flush::parse_threshold(arg) {
+            Ok(threshold) => mm::deferred_flush::set_threshold(threshold),
+            Err(err) => log::warn!(
+                "Ignoring invalid {} kernel arg: {}",
+                mm::deferred_flush::TLB_FLUSH_THRESHOLD_ARG,
+                err
+            ),
+        }
+    }
+
+    // If requested, keep the end of the guest-host memory out of the heap for the
+    // shared log ring.
+    let shared_log_size = if kernel_args.get(shared_log::SHARED_LOG_ARG).is_some() {
+        shared_log::LOG_RING_SIZE
+    } else {
+        0
+    };
+
     // Safety: initializing the new heap is safe as the frame allocator guarantees
     // we're not overwriting any other memory; writing to the static mut is safe
     // as we're in the initialization code and thus there can be no concurrent
     // access.
-    if GUEST_HOST_HEAP
-        .set(
-            unsafe {
-                memory::init_guest_host_heap(guest_host_pages, PAGE_TABLES.lock().get().unwrap())
-            }
-            .unwrap(),
-        )
-        .is_err()
-    {
+    let guest_host_heap = mm::with_page_tables(|pt| unsafe {
+        memory::init_guest_host_heap(guest_host_pages, shared_log_size, pt)
+    })
+    .unwrap();
+    if GUEST_HOST_HEAP.set(guest_host_heap).is_err() {
         panic!("couldn't initialize the guest-host heap");
     }
 
+    // Safety: the guest-host pages were mapped just above, and nothing is using the
+    // guest-host heap yet.
+    let vmpl = sev_snp_enabled.then(snp::current_vmpl);
+    if let Err(err) = mm::with_page_tables(|pt| unsafe {
+        memory::verify_guest_host_pages(guest_host_pages, pt, |addr| {
+            vmpl.and_then(|vmpl| snp::page_assignment(addr, vmpl))
+        })
+    }) {
+        panic!("guest-host memory is not shared with the host: {}", err);
+    }
+
+    if shared_log_size > 0 {
+        let base = guest_host_pages.end.start_address() - shared_log_size as u64;
+        // Safety: the memory is shared with the host and was excluded from the
+        // guest-host heap above.
+        match unsafe { logging::init_shared_log(base, shared_log_size) } {
+            Ok(()) => info!(
+                "Logging to shared memory at {:#018x}",
+                mm::with_page_tables(|pt| pt.translate_virtual(base)).unwrap().as_u64()
+            ),
+            Err(err) => log::warn!("couldn't set up the shared log: {}", err),
+        }
+    }
+
     // If we don't find memory for heap, it's ok to panic.
-    // We'll let the heap to grow to 1 TB (1 << 19 * 2 MiB pages), max.
-    let heap_page_range = VMA_ALLOCATOR.lock().allocate(1 << 19).unwrap();
+    // We'll let the heap to grow to 1 TB (1 << 19 * 2 MiB pages) by default, max.
+    // This only reserves the virtual memory: frames are mapped as the heap grows.
+    // The heap stays on 2 MiB pages, even if the CPU supports 1 GiB pages, as it
+    // grows one (not necessarily contiguous) frame at a time.
+    let heap_pages =
+        match kernel_args.get(memory::KERNEL_HEAP_ARG).map(memory::kernel_heap_pages_from_arg) {
+            Some(Ok(pages)) => pages,
+            Some(Err(err)) => {
+                log::warn!("Ignoring invalid {} kernel arg: {}", memory::KERNEL_HEAP_ARG, err);
+                memory::DEFAULT_KERNEL_HEAP_PAGES
+            }
+            None => memory::DEFAULT_KERNEL_HEAP_PAGES,
+        };
+    let heap_page_range = {
+        let mut vma_allocator = VMA_ALLOCATOR.lock();
+        match vma_allocator.allocate(heap_pages) {
+            Some(range) => Some(range),
+            // A size from the kernel args may be more than the address space can hold.
+            None if heap_pages != memory::DEFAULT_KERNEL_HEAP_PAGES => {
+                log::warn!(
+                    "Ignoring {} kernel arg: couldn't reserve {} 2 MiB pages of virtual memory; \
+                     using the default size",
+                    memory::KERNEL_HEAP_ARG,
+                    heap_pages
+                );
+                vma_allocator.allocate(memory::DEFAULT_KERNEL_HEAP_PAGES)
+            }
+            None => None,
+        }
+        .expect("couldn't reserve virtual memory for the kernel heap")
+    };
     memory::init_kernel_heap(heap_page_range).unwrap();
 
+    if kernel_args.get(mm::memtest::MEMTEST_ARG).is_some() {
+        mm::memtest::run();
+    }
+
     let stage0_dice_data = {
         let dice_memory_slice = {
             let dice_data_phys_addr = {
@@ -332,12 +579,9 @@ pub fn start_kernel(info: &BootParams) -> ! {
                     && dice_data_fully_contained_in_segment
             }));
 
-            let dice_data_virt_addr = {
-                let pt_guard = PAGE_TABLES.lock();
-                let pt = pt_guard.get().expect("failed to get page tables");
-                pt.translate_physical(dice_data_phys_addr)
-                    .expect("failed to translate physical dice address")
-            };
+            let dice_data_virt_addr =
+                mm::with_page_tables(|pt| pt.translate_physical(dice_data_phys_addr))
+                    .expect("failed to translate physical dice address");
 
             // Safety: the E820 table indicated that this is the corrct memory segment.
             unsafe {
@@ -360,6 +604,49 @@ pub fn start_kernel(info: &BootParams) -> ! {
         dice_data
     };
 
+    let report = oak_sev_snp_attestation_report::AttestationReport::read_from_prefix(
+        &stage0_dice_data.root_layer_evidence.remote_attestation_report[..],
+    )
+    .expect("attestation report in the dice data is too short");
+    debug!("Stage0 attestation report:\n{}", util::HexDump(report.as_bytes()));
+    let id_block = attestation::id_block::IdBlock::from_kernel_args(
+        &kernel_args,
+        info.e820_table(),
+        &report.data,
+        vmpl,
+    );
+    if let Some(expected) = attestation::expected_measurement(
+        kernel_args.get(attestation::EXPECT_MEASUREMENT_ARG),
+        id_block.as_ref(),
+    )
+    .unwrap()
+    {
+        attestation::assert_expected_measurement(&expected, &report);
+    }
+    if let Some(vcek_public_key) = kernel_args.get(attestation::VCEK_PUBLIC_KEY_ARG) {
+        #[cfg(feature = "rust_crypto")]
+        attestation::assert_report_signature(vcek_public_key, &report);
+        #[cfg(not(feature = "rust_crypto"))]
+        panic!(
+            "can't check the attestation report against {}: no crypto backend enabled",
+            vcek_public_key
+        );
+    }
+
+    // Without SEV-SNP, stage0 can't get a report from the Secure Processor, so the
+    // platform info in it is meaningless.
+    let platform_info = sev_snp_enabled.then(|| snp::platform_info(&report.data));
+    if let Some(platform_info) = &platform_info {
+        info!("Platform info: {:#x}", platform_info.bits());
+    }
+    if kernel_args.get(snp::REQUIRE_SMT_DISABLED_ARG).is_some() {
+        if let Err(err) = snp::check_smt_disabled(platform_info.as_ref()) {
+            error!("{}; refusing to continue", err);
+            shutdown::shutdown();
+        }
+        info!("SMT is disabled, as required");
+    }
+
     // Okay. We've got page tables and a heap. Set up the "late" IDT, this time with
     // descriptors for user mode.
     let double_fault_stack = mm::allocate_stack();
@@ -373,16 +660,46 @@ pub fn start_kernel(info: &BootParams) -> ! {
     }
 
     // Init ACPI, if available.
-    let mut acpi = match acpi::Acpi::new(info) {
+    let acpi_override =
+        match kernel_args.get(acpi::ACPI_OVERRIDE_ARG).map(acpi::AcpiOverride::from_arg) {
+            Some(Ok(acpi_override)) => Some(acpi_override),
+            Some(Err(err)) => {
+                log::warn!("Ignoring invalid {} kernel arg: {}", acpi::ACPI_OVERRIDE_ARG, err);
+                None
+            }
+            None => None,
+        };
+    let mut acpi = match acpi::Acpi::new(info.acpi_rsdp_addr(), acpi_override) {
         Err(ref err) => {
             log::warn!("Failed to load ACPI tables: {}", err);
             None
         }
         Ok(mut acpi) => {
-            acpi.print_devices().unwrap();
+            let devices = acpi.devices().unwrap();
+            acpi::print_devices(&devices);
+            mm::hotplug::add_memory_devices(
+                &devices,
+                info.e820_table(),
+                #[cfg(feature = "initrd")]
+                Some(&ramdisk),
+                #[cfg(not(feature = "initrd"))]
+                ramdisk.as_ref(),
+            );
+            syscall::devices::register(&devices);
             Some(acpi)
         }
     };
+    match acpi.as_ref().map(acpi::Acpi::io_apics) {
+        // Safety: the I/O APICs are described by the ACPI tables, and this is the only place
+        // where we access them.
+        Some(Ok(io_apics)) => {
+            if let Err(err) = unsafe { interrupts::init_io_apics(&io_apics) } {
+                log::warn!("Failed to set up I/O APICs: {}", err);
+            }
+        }
+        Some(Err(err)) => log::warn!("Failed to find I/O APICs: {}", err),
+        None => {}
+    }
 
     #[cfg(not(feature = "initrd"))]
     let mut channel =
@@ -394,12 +711,9 @@ pub fn start_kernel(info: &BootParams) -> ! {
 
     #[cfg(feature = "initrd")]
     let application_bytes: Box<[u8]> = {
-        let virt_addr = {
-            let pt_guard = PAGE_TABLES.lock();
-            let pt = pt_guard.get().expect("failed to get page tables");
-            pt.translate_physical(PhysAddr::new(ramdisk.addr.into()))
-                .expect("failed to translate physical dice address")
-        };
+        let virt_addr =
+            mm::with_page_tables(|pt| pt.translate_physical(PhysAddr::new(ramdisk.addr.into())))
+                .expect("failed to translate physical dice address");
 
         // Safety:
         // We rely on the firmware to ensure this range is valid and backed by physical
@@ -411,7 +725,7 @@ pub fn start_kernel(info: &BootParams) -> ! {
         let slice: &[u8] = unsafe {
             core::slice::from_raw_parts::<u8>(
                 virt_addr.as_mut_ptr(),
-                info.hdr.ramdisk_size.try_into().unwrap(),
+                ramdisk.size.try_into().unwrap(),
             )
         };
 
@@ -425,7 +739,7 @@ pub fn start_kernel(info: &BootParams) -> ! {
             ramdisk_range.start.start_address().as_u64(),
             ramdisk_range.end.start_address().as_u64()
         );
-        FRAME_ALLOCATOR.lock().mark_valid(ramdisk_range, true);
+        mm::with_frame_allocator(|frame_allocator| frame_allocator.mark_valid(ramdisk_range, true));
 
         owned_slice
     };
@@ -442,10 +756,60 @@ pub fn start_kernel(info: &BootParams) -> ! {
 
     log::info!("Binary loaded, size: {}", application_bytes.len());
 
+    // Copy any additional payload images onto the heap.
+    let payload_images: Vec<Box<[u8]>> = payload::parse_image_locations(payload_images_arg)
+        .map(|location| {
+            let location = location.expect("invalid payload image location");
+            let virt_addr = mm::with_page_tables(|pt| pt.translate_physical(location.address))
+                .expect("failed to translate payload image address");
+            info!(
+                "Copying payload image from {:#018x} ({} bytes)",
+                location.address.as_u64(),
+                location.len
+            );
+            // Safety: the VMM staged the image at this location, and we reserved the frames
+            // containing it before any memory was allocated.
+            Box::<[u8]>::from(unsafe {
+                core::slice::from_raw_parts::<u8>(virt_addr.as_ptr(), location.len as usize)
+            })
+        })
+        .collect();
+
+    // Record the images in the runtime measurement registers too, so that they are
+    // covered by the evidence bundles handed to verifiers.
+    for image in payload_images.iter().chain(core::iter::once(&application_bytes)) {
+        attestation::measurement_registers::measure(
+            attestation::measurement_registers::APPLICATION_REGISTER,
+            image,
+        )
+        .expect("failed to measure application image");
+    }
+
+    #[cfg(not(feature = "initrd"))]
+    if let Some(ramdisk) = ramdisk.as_ref() {
+        ramdisk::init(ramdisk).expect("failed to set up the data ramdisk");
+    }
+
     #[cfg(not(feature = "initrd"))]
     let (derived_key, restricted_kernel_dice_data) = {
-        let app_digest =
-            oak_restricted_kernel_dice::measure_app_digest_sha2_256(&application_bytes);
+        // If there are additional payload images, the measurement covers all images in
+        // the order in which they are loaded, each prefixed by its length. If there is
+        // a data ramdisk, its SHA2-256 digest follows the images, prefixed by its
+        // length as well, so that it is recorded in the DICE evidence that
+        // doubles as the measured-boot event log.
+        let ramdisk_digest = ramdisk::digest();
+        let app_digest = if payload_images.is_empty() && ramdisk_digest.is_none() {
+            oak_restricted_kernel_dice::measure_app_digest_sha2_256(&application_bytes)
+        } else {
+            let payload = payload::measured_images(
+                payload_images
+                    .iter()
+                    .chain(core::iter::once(&application_bytes))
+                    .map(|image| &**image)
+                    .chain(ramdisk_digest.as_ref().map(|digest| &digest[..])),
+            );
+            oak_restricted_kernel_dice::measure_app_digest_sha2_256(&payload)
+        };
         log::info!(
             "Application digest (sha2-256): {}",
             app_digest.map(|x| alloc::format!("{:02x}", x)).join("")
@@ -459,9 +823,62 @@ pub fn start_kernel(info: &BootParams) -> ! {
         (derived_key, restricted_kernel_dice_data)
     };
 
-    let application =
-        payload::Application::new(application_bytes).expect("failed to parse application");
+    payload::set_limits(payload::PayloadLimits::from_kernel_args(&kernel_args));
+    let applications: Vec<payload::Application> = payload_images
+        .into_iter()
+        .chain(core::iter::once(application_bytes))
+        .map(|image| payload::Application::new(image).expect("failed to parse application"))
+        .collect();
+
+    // Request an attestation report binding the key we hand to the application, so
+    // that the application doesn't have to request one itself right after it
+    // starts.
+    if sev_snp_enabled {
+        #[cfg(feature = "initrd")]
+        let certificate = &stage0_dice_data.layer_1_evidence.eca_certificate[..];
+        #[cfg(not(feature = "initrd"))]
+        let certificate = &restricted_kernel_dice_data
+            .evidence
+            .application_keys
+            .encryption_public_key_certificate[..];
+        let report_data = attestation::staged::report_data_for_certificate(certificate);
+        if let Err(err) = attestation::guest_request::init().and_then(|()| {
+            attestation::staged::stage(&report_data, attestation::guest_request::request_report)
+        }) {
+            log::warn!("couldn't stage an attestation report for the application: {}", err);
+        }
+        match clock::tsc_frequency() {
+            Some(frequency) => attestation::init_report_cache(frequency.hz).unwrap(),
+            None => log::warn!("TSC frequency unknown, not caching attestation reports"),
+        }
 
+        // The DICE evidence doubles as the measured-boot event log in evidence bundles.
+        #[cfg(feature = "initrd")]
+        let event_log = [
+            stage0_dice_data.root_layer_evidence.as_bytes(),
+            stage0_dice_data.layer_1_evidence.as_bytes(),
+        ]
+        .concat();
+        #[cfg(not(feature = "initrd"))]
+        let event_log = restricted_kernel_dice_data.evidence.as_bytes().to_vec();
+        attestation::evidence::set_event_log(event_log).unwrap();
+    }
+
+    // The payload runs in ring 3, and may only reach the kernel through system
+    // calls and faults: make sure that none of the kernel's code, data or heap
+    // is accessible to it.
+    mm::with_page_tables(|pt| {
+        for addr in [
+            VirtAddr::new(start_kernel::<P> as usize as u64),
+            VirtAddr::from_ptr(&PAGE_TABLES),
+            VirtAddr::from_ptr(applications.as_ptr()),
+        ] {
+            mm::check_supervisor_only(pt, addr)
+                .unwrap_or_else(|err| panic!("{}: {:#018x}", err, addr.as_u64()));
+        }
+    });
+
+    ready::kernel_ready(sev_status);
     syscall::enable_syscalls(
         channel,
         #[cfg(feature = "initrd")]
@@ -472,16 +889,51 @@ pub fn start_kernel(info: &BootParams) -> ! {
         derived_key,
     );
 
+    if kernel_args.get("debug_syscall_stats").is_some() {
+        info!("Enabling syscall statistics for the application");
+        syscall::enable_stats_syscall();
+    }
+
+    if kernel_args.get(syscall::diagnostics::DIAGNOSTICS_ARG).is_some() {
+        info!("Enabling memory and interrupt diagnostics for the application");
+        syscall::diagnostics::enable_diagnostics_syscall();
+    }
+
+    if kernel_args.get(syscall::channel::LOG_LEVEL_IOCTL_ARG).is_some() {
+        info!("Allowing the application to change the log level");
+        syscall::channel::enable_log_level_ioctl();
+    }
+
+    rng::init(sev_snp_enabled).expect("failed to set up random number generation");
+    vdso::init().expect("failed to set up the vDSO page");
+
+    let entry_args = payload::EntryArgs::from_kernel_args(&kernel_args);
+
     // Ensure new process is not dropped.
     // Safety: The application is assumed to be a valid ELF file.
     let process = Box::leak(Box::new(unsafe {
-        Process::from_application(&application).expect("failed to create process")
+        Process::from_applications(&applications, entry_args.as_ref())
+            .expect("failed to create process")
     }));
 
     process.execute()
 }
 
-#[derive(EnumIter, EnumString)]
+// Without any channel the kernel has no way of talking to the host, and
+// `ChannelType` would be an empty enum.
+#[cfg(not(any(
+    feature = "virtio_console_channel",
+    feature = "vsock_channel",
+    feature = "serial_channel",
+    feature = "simple_io_channel",
+    feature = "shmem_channel"
+)))]
+compile_error!(
+    "at least one channel feature must be enabled: virtio_console_channel, vsock_channel, \
+     serial_channel, simple_io_channel or shmem_channel"
+);
+
+#[derive(Clone, Copy, Debug, Display, EnumIter, EnumString, PartialEq)]
 #[strum(ascii_case_insensitive, serialize_all = "snake_case")]
 enum ChannelType {
     #[cfg(feature = "virtio_console_channel")]
@@ -492,24 +944,138 @@ enum ChannelType {
     Serial,
     #[cfg(feature = "simple_io_channel")]
     SimpleIo,
+    #[cfg(feature = "shmem_channel")]
+    Shmem,
+}
+
+impl ChannelType {
+    /// Returns the channel type requested on the kernel command line.
+    fn from_kernel_args(kernel_args: &args::Args) -> Self {
+        Self::from_arg(kernel_args.get("channel"))
+    }
+
+    /// Parses the value of the `channel` kernel argument.
+    ///
+    /// If we weren't told which channel to use, or don't know the requested
+    /// one, arbitrarily pick the first one in the `ChannelType` enum.
+    /// Depending on features that are enabled, this means that the enum
+    /// acts as kind of a reverse priority list for defaults.
+    fn from_arg(arg: Option<&str>) -> Self {
+        // The `compile_error!` above guarantees that there is at least one channel
+        // type.
+        let default = ChannelType::iter().next().expect(
+            "no channel types are enabled; the kernel must be built with at least one channel \
+             feature",
+        );
+        match arg {
+            Some(name) => ChannelType::from_str(name).unwrap_or_else(|_| {
+                log::warn!("unknown channel type {:?}; falling back to {}", name, default);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    /// Returns the type of the channel that the data sent over the main channel
+    /// should be mirrored to, if any.
+    ///
+    /// As this is only used for diagnostics, an unknown type disables the
+    /// mirror instead of falling back to a default.
+    fn tee_from_kernel_args(kernel_args: &args::Args) -> Option<Self> {
+        let name = kernel_args.get(tee::TEE_CHANNEL_ARG)?;
+        match ChannelType::from_str(name) {
+            Ok(chan_type) => Some(chan_type),
+            Err(_) => {
+                log::warn!(
+                    "unknown channel type {:?} for {}; not mirroring",
+                    name,
+                    tee::TEE_CHANNEL_ARG
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the minimum number of contiguous 2 MiB frames of guest-host
+    /// memory that the channels requested on the kernel command line need.
+    fn min_guest_host_frames_for(kernel_args: &args::Args) -> usize {
+        ChannelType::from_kernel_args(kernel_args).min_guest_host_frames()
+            + ChannelType::tee_from_kernel_args(kernel_args)
+                .map_or(0, |chan_type| chan_type.min_guest_host_frames())
+    }
+
+    /// Returns the minimum number of contiguous 2 MiB frames of guest-host
+    /// memory the channel needs.
+    fn min_guest_host_frames(&self) -> usize {
+        match self {
+            // virtio needs more than 2 MiB for its data structures.
+            #[cfg(feature = "virtio_console_channel")]
+            ChannelType::VirtioConsole => 2,
+            #[cfg(feature = "vsock_channel")]
+            ChannelType::VirtioVsock => 2,
+            #[cfg(feature = "serial_channel")]
+            ChannelType::Serial => 1,
+            #[cfg(feature = "simple_io_channel")]
+            ChannelType::SimpleIo => 1,
+            #[cfg(feature = "shmem_channel")]
+            ChannelType::Shmem => 1,
+        }
+    }
 }
 
 /// Create a channel for communicating with the Untrusted Launcher.
-#[allow(unused_variables)]
 fn get_channel<'a, A: Allocator + Sync>(
     kernel_args: &args::Args,
     alloc: &'a A,
-    acpi: Option<&mut Acpi>,
+    mut acpi: Option<&mut Acpi>,
     sev_status: SevStatus,
 ) -> Box<dyn Channel + 'a> {
-    // If we weren't told which channel to use, arbitrarily pick the first one in
-    // the `ChannelType` enum. Depending on features that are enabled, this
-    // means that the enum acts as kind of a reverse priority list for defaults.
-    let chan_type = kernel_args
-        .get("channel")
-        .map(|chan_type| ChannelType::from_str(chan_type).unwrap())
-        .unwrap_or_else(|| ChannelType::iter().next().unwrap());
+    let chan_type = ChannelType::from_kernel_args(kernel_args);
+    info!("Using the {} channel", chan_type);
+    let mut channel =
+        create_channel(chan_type, kernel_args, alloc, acpi.as_deref_mut(), sev_status);
+
+    match ChannelType::tee_from_kernel_args(kernel_args) {
+        Some(tee_type) if tee_type == chan_type => {
+            log::warn!("Not mirroring the {} channel to itself", chan_type);
+        }
+        Some(tee_type) => {
+            info!("Mirroring sent data to the {} channel", tee_type);
+            let secondary = create_channel(tee_type, kernel_args, alloc, acpi, sev_status);
+            channel = Box::new(tee::TeeChannel::new(channel, secondary));
+        }
+        None => {}
+    }
+
+    match kernel_args.get(rate_limit::CHANNEL_RATE_LIMIT_ARG) {
+        Some(arg) => {
+            let bytes_per_second = arg.parse().expect("invalid channel rate limit");
+            let hz = clock::tsc_frequency()
+                .expect("channel rate limiting requires a known TSC frequency")
+                .hz;
+            info!("Limiting the channel to {} bytes per second", bytes_per_second);
+            Box::new(
+                rate_limit::RateLimitedChannel::new(
+                    channel,
+                    bytes_per_second,
+                    rate_limit::TscClock::new(hz),
+                )
+                .unwrap(),
+            )
+        }
+        None => channel,
+    }
+}
 
+/// Creates a channel of the given type.
+#[allow(unused_variables)]
+fn create_channel<'a, A: Allocator + Sync>(
+    chan_type: ChannelType,
+    kernel_args: &args::Args,
+    alloc: &'a A,
+    acpi: Option<&mut Acpi>,
+    sev_status: SevStatus,
+) -> Box<dyn Channel + 'a> {
     match chan_type {
         #[cfg(feature = "virtio_console_channel")]
         ChannelType::VirtioConsole => Box::new(virtio_console::get_console_channel(
@@ -518,15 +1084,69 @@ fn get_channel<'a, A: Allocator + Sync>(
         #[cfg(feature = "vsock_channel")]
         ChannelType::VirtioVsock => Box::new(virtio::get_vsock_channel(alloc)),
         #[cfg(feature = "serial_channel")]
-        ChannelType::Serial => Box::new(serial::Serial::new()),
+        ChannelType::Serial => {
+            let base = match kernel_args.get(serial::SERIAL_INDEX_ARG) {
+                Some(index) => {
+                    let index: usize = index.parse().expect("invalid serial port index");
+                    let base = serial::available_ports()
+                        .nth(index)
+                        .expect("no serial port with the requested index");
+                    info!("Using serial port at {:#x} for the channel", base);
+                    base
+                }
+                None => serial::COM2_BASE,
+            };
+            // Safety: either the port was probed successfully, or it's the port our
+            // contract with the loader requires to be available, so there is a
+            // UART at `base`.
+            let port = unsafe { serial::Serial::new_at(base) };
+            match kernel_args.get(serial::SERIAL_RX_ARG) {
+                // Safety: `port` was opened at `base` above.
+                Some("interrupt") => Box::new(unsafe { port.with_interrupt_rx(base) }),
+                Some("poll") | None => Box::new(port),
+                Some(other) => panic!("invalid {} kernel arg: {}", serial::SERIAL_RX_ARG, other),
+            }
+        }
         #[cfg(feature = "simple_io_channel")]
         ChannelType::SimpleIo => Box::new(simpleio::SimpleIoChannel::new(alloc, sev_status)),
+        #[cfg(feature = "shmem_channel")]
+        ChannelType::Shmem => Box::new(
+            shmem_channel::ShmemChannel::new(alloc).expect("couldn't create shared memory channel"),
+        ),
     }
 }
 
 /// Common panic routine for the kernel. This needs to be wrapped in a
 /// panic_handler function in individual bootloader crates.
+///
+/// After logging the panic, the reporter installed with
+/// `panic_reporter::set_panic_reporter` decides what happens next; by default,
+/// the machine is shut down.
 pub fn panic(info: &PanicInfo) -> ! {
-    error!("PANIC: {}", info);
+    panic_reporter::report_panic(info, panic_reporter::panic_reporter());
     shutdown::shutdown();
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn channel_type_round_trip() {
+        for chan_type in ChannelType::iter() {
+            let name = chan_type.to_string();
+            assert_eq!(ChannelType::from_str(&name), Ok(chan_type));
+            assert_eq!(ChannelType::from_str(&name.to_ascii_uppercase()), Ok(chan_type));
+            assert_eq!(ChannelType::from_arg(Some(&name)), chan_type);
+        }
+    }
+
+    #[test]
+    fn channel_type_fallback() {
+        let default = ChannelType::iter().next().unwrap();
+        assert_eq!(ChannelType::from_arg(None), default);
+        assert_eq!(ChannelType::from_arg(Some("carrier_pigeon")), default);
+    }
+}
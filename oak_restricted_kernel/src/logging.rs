@@ -14,12 +14,19 @@
 // limitations under the License.
 //
 
-use core::fmt::Write;
+use core::{
+    fmt::{self, Display, Write},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use log::info;
+use log::{info, LevelFilter};
 use oak_sev_guest::io::PortFactoryWrapper;
 use sev_serial::SerialPort;
-use spinning_top::Spinlock;
+use spinning_top::{Spinlock, SpinlockGuard};
+use x86_64::VirtAddr;
+
+use crate::{percpu, shared_log::LogRing, syscall::payload_log::PAYLOAD_LOG_TARGET};
 
 extern crate log;
 
@@ -29,6 +36,77 @@ const COM1_BASE: u16 = 0x3f8;
 
 pub static SERIAL1: Spinlock<Option<SerialPort>> = Spinlock::new(None);
 
+/// Log ring in memory shared with the host, if enabled.
+static SHARED_LOG: Spinlock<Option<LogRing>> = Spinlock::new(None);
+
+/// Kernel argument that prefixes every log line with the id of the CPU that
+/// logged it.
+pub const LOG_CPU_ID_ARG: &str = "log_cpu_id";
+
+static LOG_CPU_ID: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables prefixing log lines with the CPU id.
+pub fn set_log_cpu_id(enabled: bool) {
+    LOG_CPU_ID.store(enabled, Ordering::Relaxed);
+}
+
+/// Set once the kernel has started reporting a panic.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Switches the logger to its panic path, which doesn't wait for the locks
+/// that guard the log outputs.
+///
+/// Code that panicked may still hold one of them (for example, if writing to
+/// the serial port faulted in the middle of logging a message), so waiting for
+/// it would deadlock before the panic is ever reported.
+pub fn set_panicking() {
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
+/// Locks one of the log outputs.
+///
+/// While panicking, a lock that is already held is broken instead of waited
+/// for: garbled output is better than no output at all. This is best-effort,
+/// so if the lock is taken again before we get to it, `None` is returned.
+fn lock_for_logging<T>(lock: &Spinlock<T>, panicking: bool) -> Option<SpinlockGuard<'_, T>> {
+    if !panicking {
+        return Some(lock.lock());
+    }
+    lock.try_lock().or_else(|| {
+        // Safety: the holder of the lock may access the output concurrently with us,
+        // but as we're about to stop the machine anyway, getting the panic
+        // message out matters more than keeping the output intact.
+        unsafe { lock.force_unlock() };
+        lock.try_lock()
+    })
+}
+
+/// Prefix of a log line: the CPU id, if enabled, and where the message came
+/// from.
+struct Prefix {
+    cpu_id: Option<u32>,
+    source: &'static str,
+}
+
+impl Prefix {
+    fn new(record: &log::Record) -> Self {
+        // Messages from the payload are tagged as such, everything else comes from the
+        // kernel.
+        let source = if record.target() == PAYLOAD_LOG_TARGET { "payload" } else { "kernel" };
+        let cpu_id = LOG_CPU_ID.load(Ordering::Relaxed).then(|| percpu::this_cpu().id());
+        Self { cpu_id, source }
+    }
+}
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cpu_id) = self.cpu_id {
+            write!(f, "[cpu{}] ", cpu_id)?;
+        }
+        f.write_str(self.source)
+    }
+}
+
 struct Logger {}
 
 impl log::Log for Logger {
@@ -37,8 +115,24 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        writeln!(SERIAL1.lock().as_mut().unwrap(), "kernel {}: {}", record.level(), record.args())
-            .unwrap();
+        let panicking = PANICKING.load(Ordering::SeqCst);
+        let prefix = Prefix::new(record);
+        if let Some(mut serial) = lock_for_logging(&SERIAL1, panicking) {
+            let result = match serial.as_mut() {
+                Some(port) => writeln!(port, "{} {}: {}", prefix, record.level(), record.args()),
+                None => Err(fmt::Error),
+            };
+            // Failing to log while panicking mustn't cause another panic.
+            if !panicking {
+                result.expect("couldn't write to the serial port");
+            }
+        }
+        if let Some(mut ring) = lock_for_logging(&SHARED_LOG, panicking) {
+            if let Some(ring) = ring.as_mut() {
+                // Writing to the ring can't fail.
+                let _ = writeln!(ring, "{} {}: {}", prefix, record.level(), record.args());
+            }
+        }
     }
 
     fn flush(&self) {
@@ -48,6 +142,20 @@ impl log::Log for Logger {
 
 static LOGGER: Logger = Logger {};
 
+/// Writes an error message straight to the serial port, bypassing the logger.
+///
+/// This neither allocates nor waits for the serial port lock, so it can be
+/// used when panicking early during boot, or while the panicking code holds
+/// the lock. If the serial port isn't set up yet, or is busy, the message is
+/// lost.
+pub fn write_early(message: &str) {
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        if let Some(port) = serial.as_mut() {
+            let _ = writeln!(port, "kernel ERROR: {}", message);
+        }
+    }
+}
+
 pub fn init_logging(sev_es_enabled: bool) {
     let port_factory = if sev_es_enabled {
         crate::ghcb::get_ghcb_port_factory()
@@ -68,3 +176,90 @@ pub fn init_logging(sev_es_enabled: bool) {
     // Log a message to ensure the serial logging channel is intialized.
     info!("Logging initialised.");
 }
+
+/// Additionally sends the logs to a ring in the `len` bytes of memory at
+/// `base`, which the host can poll.
+///
+/// # Safety
+///
+/// The memory must be shared with the host, be valid for writes and not be
+/// used by anything else in the guest.
+pub unsafe fn init_shared_log(base: VirtAddr, len: usize) -> Result<(), &'static str> {
+    let base = NonNull::new(base.as_mut_ptr()).ok_or("invalid address for the shared log")?;
+    let ring = LogRing::new(base, len).ok_or("not enough memory for the shared log")?;
+    if SHARED_LOG.lock().replace(ring).is_some() {
+        return Err("shared log is already initialized");
+    }
+    Ok(())
+}
+
+/// Decodes a maximum log level as passed by the payload: the value of a
+/// `LogLevel`, or 0 to turn logging off.
+pub fn level_filter_from_repr(level: usize) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Changes the maximum log level at runtime, returning the previous one.
+pub fn set_max_level(level: LevelFilter) -> LevelFilter {
+    let previous = log::max_level();
+    log::set_max_level(level);
+    // Log at the lower of both levels, so that the change shows up either way.
+    log::log!(
+        previous.min(level).to_level().unwrap_or(log::Level::Error),
+        "Log level changed from {} to {}",
+        previous,
+        level
+    );
+    previous
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test]
+    fn prefix_without_cpu_id() {
+        let prefix = Prefix { cpu_id: None, source: "kernel" };
+        assert_eq!(format!("{} {}: {}", prefix, log::Level::Info, "hello"), "kernel INFO: hello");
+    }
+
+    #[test]
+    fn normal_path_takes_free_lock() {
+        let lock = Spinlock::new(0);
+        assert!(lock_for_logging(&lock, false).is_some());
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn panic_path_bypasses_held_lock() {
+        let lock = Spinlock::new(0);
+        // Simulate a panic in the middle of logging, which never releases the lock.
+        core::mem::forget(lock.lock());
+
+        // Waiting for the lock would never return, so this only finishes if the lock is
+        // bypassed.
+        let guard = lock_for_logging(&lock, true);
+        assert!(guard.is_some());
+        drop(guard);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn prefix_with_cpu_id() {
+        let prefix = Prefix { cpu_id: Some(3), source: "payload" };
+        assert_eq!(
+            format!("{} {}: {}", prefix, log::Level::Warn, "hello"),
+            "[cpu3] payload WARN: hello"
+        );
+    }
+}
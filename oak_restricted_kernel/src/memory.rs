@@ -16,23 +16,30 @@
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    ops::Deref,
+    fmt,
+    ops::{Deref, Range},
     ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use linked_list_allocator::{Heap, LockedHeap};
 use log::info;
+use oak_core::sync::OnceCell;
+use oak_sev_guest::msr::PageAssignment;
 use spinning_top::Spinlock;
 use x86_64::{
     structures::paging::{
-        mapper::FlagUpdateError, page::PageRange, FrameAllocator, Page, PageSize, PhysFrame,
-        Size2MiB,
+        frame::PhysFrameRange, mapper::FlagUpdateError, page::PageRange, FrameAllocator, Page,
+        PageSize, PhysFrame, Size2MiB,
     },
-    VirtAddr,
+    PhysAddr, VirtAddr,
 };
 
 use crate::{
-    mm::{Mapper, PageTableFlags},
+    mm::{
+        deferred_flush::DeferredFlush, frame_allocator::PhysicalMemoryAllocator, Mapper,
+        PageTableFlags, Translator,
+    },
     FRAME_ALLOCATOR, PAGE_TABLES,
 };
 
@@ -43,6 +50,17 @@ static ALLOCATOR: LockedGrowableHeap = LockedGrowableHeap::empty();
 #[cfg(test)]
 static ALLOCATOR: LockedGrowableHeap = LockedGrowableHeap::empty();
 
+/// Flags for the pages of the kernel heap, which are never accessible from
+/// user mode.
+pub(crate) fn kernel_heap_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::HUGE_PAGE
+        | PageTableFlags::ENCRYPTED
+}
+
 /// Heap allocator that requests more physical memory as required.
 struct GrowableHeap {
     /// Underlying heap allocator implementation.
@@ -85,12 +103,7 @@ impl GrowableHeap {
                 .map_to_with_table_flags(
                     self.available.next().ok_or("kernel heap exhausted")?,
                     frame,
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::GLOBAL
-                        | PageTableFlags::NO_EXECUTE
-                        | PageTableFlags::HUGE_PAGE
-                        | PageTableFlags::ENCRYPTED,
+                    kernel_heap_flags(),
                     PageTableFlags::PRESENT
                         | PageTableFlags::WRITABLE
                         | PageTableFlags::NO_EXECUTE
@@ -169,6 +182,89 @@ unsafe impl GlobalAlloc for LockedGrowableHeap {
     }
 }
 
+/// Number of 2 MiB frames we'd like to use for guest-host communication, as
+/// virtio needs more than 2 MiB for its data structures.
+pub const GUEST_HOST_FRAMES: usize = 2;
+
+/// Kernel argument that keeps guest-host memory below 4 GiB, for devices that
+/// only support 32-bit DMA; for example, `guest_host_dma32`.
+pub const GUEST_HOST_DMA32_ARG: &str = "guest_host_dma32";
+
+/// Error returned if not even the minimum amount of guest-host memory could be
+/// allocated.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GuestHostAllocError {
+    /// Number of contiguous 2 MiB frames that were needed at least.
+    pub needed: usize,
+    /// Number of 2 MiB frames in the largest contiguous run of free memory.
+    pub largest_available: usize,
+}
+
+impl fmt::Display for GuestHostAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "couldn't allocate guest-host memory: needed {} contiguous 2 MiB frames, but the \
+             largest contiguous run of free memory is {} frames",
+            self.needed, self.largest_available
+        )
+    }
+}
+
+/// Allocates contiguous frames for guest-host communication.
+///
+/// Tries to allocate `preferred` frames first, falling back to smaller
+/// allocations if there isn't enough contiguous memory, down to `minimum`
+/// frames. If `limit` is set, all the frames will be below that physical
+/// address.
+pub fn allocate_guest_host_frames<const N: usize>(
+    frame_allocator: &mut PhysicalMemoryAllocator<N>,
+    preferred: usize,
+    minimum: usize,
+    limit: Option<PhysAddr>,
+) -> Result<PhysFrameRange<Size2MiB>, GuestHostAllocError> {
+    for count in (minimum.max(1)..=preferred).rev() {
+        let frames = match limit {
+            Some(limit) => frame_allocator.allocate_below(count, limit),
+            None => frame_allocator.allocate_contiguous(count),
+        };
+        if let Some(frames) = frames {
+            if count < preferred {
+                log::warn!(
+                    "only {} contiguous 2 MiB frames available for guest-host memory instead of {}",
+                    count,
+                    preferred
+                );
+            }
+            return Ok(frames);
+        }
+    }
+    Err(GuestHostAllocError {
+        needed: minimum,
+        largest_available: frame_allocator.largest_available().map_or(0, |frames| frames.count()),
+    })
+}
+
+/// Kernel argument that sets the amount of virtual memory reserved for the
+/// kernel heap, in MiB; for example, `kernel_heap_mib=4096`.
+///
+/// This only limits how far the heap may grow; frames are still only mapped
+/// into the heap one 2 MiB page at a time, as it needs them.
+pub const KERNEL_HEAP_ARG: &str = "kernel_heap_mib";
+
+/// Number of 2 MiB pages reserved for the kernel heap by default (1 TiB).
+pub const DEFAULT_KERNEL_HEAP_PAGES: u64 = 1 << 19;
+
+/// Converts the value of [`KERNEL_HEAP_ARG`] to the number of 2 MiB pages to
+/// reserve, rounding up to whole pages.
+pub fn kernel_heap_pages_from_arg(arg: &str) -> Result<u64, &'static str> {
+    let mib: u64 = arg.parse().map_err(|_| "expected the heap size in MiB")?;
+    if mib == 0 {
+        return Err("the kernel heap can't be empty");
+    }
+    Ok(mib.div_ceil(Size2MiB::SIZE >> 20))
+}
+
 /// Initializes the global allocator from the largest contiguous slice of
 /// available memory.
 ///
@@ -180,36 +276,453 @@ pub fn init_kernel_heap(range: PageRange<Size2MiB>) -> Result<(), &'static str>
     unsafe {
         ALLOCATOR.lock().init(range);
     }
+    KERNEL_HEAP_INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether `init_kernel_heap` has completed.
+static KERNEL_HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the kernel heap is ready for allocations.
+pub fn kernel_heap_initialized() -> bool {
+    KERNEL_HEAP_INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Returns the virtual memory range currently backing the kernel heap.
+///
+/// Returns `None` if the heap is locked.
+pub fn kernel_heap_range() -> Option<Range<VirtAddr>> {
+    let heap = ALLOCATOR.try_lock()?;
+    Some(heap.base.start_address()..heap.available.start.start_address())
+}
+
+/// Runs `f` over the kernel heap allocator.
+///
+/// Returns `None` if the heap is locked.
+pub fn with_kernel_heap<T>(f: impl FnOnce(&Heap) -> T) -> Option<T> {
+    ALLOCATOR.try_lock().map(|heap| f(&heap.heap))
+}
+
+/// Bounds of the virtual memory region shared with the host, as set up by
+/// `init_guest_host_heap`.
+static SHARED_REGION: OnceCell<Range<VirtAddr>> = OnceCell::new();
+
+/// Returns the bounds of the virtual memory region shared with the host.
+///
+/// Returns `None` if the guest-host heap has not been initialized yet.
+pub fn shared_region_bounds() -> Option<Range<VirtAddr>> {
+    SHARED_REGION.get().cloned()
+}
+
+/// Checks that the `len` bytes starting at `addr` lie within `bounds`.
+fn check_in_region(
+    bounds: &Range<VirtAddr>,
+    addr: VirtAddr,
+    len: usize,
+) -> Result<(), &'static str> {
+    let end =
+        addr.as_u64().checked_add(len as u64).ok_or("buffer wraps around the address space")?;
+    if addr < bounds.start || end > bounds.end.as_u64() {
+        return Err("buffer is outside the memory shared with the host");
+    }
     Ok(())
 }
 
+/// Panics in debug builds if the `len` bytes starting at `addr` are not within
+/// the memory shared with the host.
+///
+/// Buffers handed to a device outside of the shared region would end up in
+/// encrypted memory, which silently breaks I/O with the host. The check is
+/// skipped if the shared region has not been set up yet.
+pub fn debug_assert_shared(addr: VirtAddr, len: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    if let Some(bounds) = shared_region_bounds() {
+        if let Err(err) = check_in_region(&bounds, addr, len) {
+            panic!(
+                "{}: [{:#018x}..{:#018x}) is not within [{:#018x}..{:#018x})",
+                err,
+                addr.as_u64(),
+                addr.as_u64().wrapping_add(len as u64),
+                bounds.start.as_u64(),
+                bounds.end.as_u64()
+            );
+        }
+    }
+}
+
 /// Initializes an allocator for guest-host communication on unencrypted memory.
 ///
+/// The last `reserved` bytes of the page range are shared with the host, but
+/// are not handed out by the allocator.
+///
 /// # Safety
 ///
 /// The caller has to guarantee that the page range is valid and not in use, as
 /// we will change page table flags for pages in that range.
 pub unsafe fn init_guest_host_heap<S: PageSize, M: Mapper<S>>(
     pages: PageRange<S>,
+    reserved: usize,
     mapper: &M,
 ) -> Result<LockedHeap, FlagUpdateError> {
+    let mut flushes = DeferredFlush::new();
     for page in pages {
-        mapper
-            .update_flags(
-                page,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::GLOBAL
-                    | PageTableFlags::NO_EXECUTE,
-            )?
-            .flush();
+        let flush = mapper.update_flags(
+            page,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::GLOBAL
+                | PageTableFlags::NO_EXECUTE,
+        )?;
+        flushes.defer(page, flush);
     }
+    drop(flushes);
 
     info!(
         "Marking [{:#018x}..{:#018x}) for guest-host communication.",
         pages.start.start_address().as_u64(),
         pages.end.start_address().as_u64()
     );
+    if SHARED_REGION.set(pages.start.start_address()..pages.end.start_address()).is_err() {
+        log::warn!("guest-host heap was initialized more than once");
+    }
 
-    Ok(LockedHeap::new(pages.start.start_address().as_mut_ptr(), pages.count() * S::SIZE as usize))
+    Ok(LockedHeap::new(
+        pages.start.start_address().as_mut_ptr(),
+        (pages.count() * S::SIZE as usize).saturating_sub(reserved),
+    ))
+}
+
+// The identity-mapping helpers have no callers until a device needs them.
+
+/// End of the lower half of the canonical address space. Identity mappings
+/// have to stay below this, as virtual addresses above it aren't canonical.
+#[allow(dead_code)]
+const IDENTITY_MAPPING_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+/// Returns the pages that map `frames` at the same virtual addresses.
+#[allow(dead_code)]
+fn identity_pages<S: PageSize>(frames: PhysFrameRange<S>) -> Result<PageRange<S>, &'static str> {
+    if frames.end.start_address().as_u64() > IDENTITY_MAPPING_LIMIT {
+        return Err("physical range can't be identity-mapped in the canonical lower half");
+    }
+    let page = |frame: PhysFrame<S>| {
+        Page::<S>::from_start_address(VirtAddr::new(frame.start_address().as_u64())).unwrap()
+    };
+    Ok(Page::range(page(frames.start), page(frames.end)))
+}
+
+/// Maps `frames` at virtual addresses equal to their physical addresses.
+///
+/// Some devices and firmware interfaces insist on memory where virtual and
+/// physical addresses match, even after we've switched away from the early
+/// boot page tables. The mapping is only accessible from the kernel. Memory
+/// shared with the host (such as device memory) must be mapped without
+/// `ENCRYPTED` in `flags`; the mapper adds the C-bit for everything else. As
+/// the range must sit below 128 TiB, the physical addresses can never overlap
+/// the C-bit itself.
+///
+/// Fails without touching the page tables if any of the pages is already
+/// mapped, which guards against clobbering kernel or user mappings.
+///
+/// # Safety
+///
+/// The caller has to guarantee that the frames are not in use elsewhere in a
+/// way that conflicts with `flags`.
+#[allow(dead_code)]
+pub unsafe fn map_identity<S: PageSize, M: Mapper<S> + Translator>(
+    frames: PhysFrameRange<S>,
+    flags: PageTableFlags,
+    mapper: &M,
+) -> Result<PageRange<S>, &'static str> {
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err("identity mappings are for kernel use only");
+    }
+    let pages = identity_pages(frames)?;
+    if pages.into_iter().any(|page| mapper.translate_virtual(page.start_address()).is_some()) {
+        return Err("identity range overlaps an existing mapping");
+    }
+    for (page, frame) in pages.into_iter().zip(frames) {
+        let result = mapper.map_to_with_table_flags(
+            page,
+            frame,
+            flags | PageTableFlags::PRESENT,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+        // None of the pages were mapped before, so there are no stale TLB entries
+        // to flush.
+        match result {
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                // Roll back what we've mapped so far.
+                for mapped in Page::range(pages.start, page) {
+                    if let Ok((_, flush)) = mapper.unmap(mapped) {
+                        flush.ignore();
+                    }
+                }
+                return Err("couldn't create identity mapping");
+            }
+        }
+    }
+    Ok(pages)
+}
+
+/// Removes an identity mapping created by `map_identity`.
+///
+/// Fails without touching the page tables if any of the pages is not
+/// identity-mapped.
+///
+/// The TLB is not flushed; the caller has to flush it before the memory is
+/// reused.
+///
+/// # Safety
+///
+/// The caller has to guarantee that nothing accesses the memory through the
+/// identity mapping anymore.
+#[allow(dead_code)]
+pub unsafe fn unmap_identity<S: PageSize, M: Mapper<S> + Translator>(
+    frames: PhysFrameRange<S>,
+    mapper: &M,
+) -> Result<(), &'static str> {
+    let pages = identity_pages(frames)?;
+    let is_identity = |page: Page<S>| {
+        mapper.translate_virtual(page.start_address())
+            == Some(PhysAddr::new(page.start_address().as_u64()))
+    };
+    if !pages.into_iter().all(is_identity) {
+        return Err("range is not identity-mapped");
+    }
+    for page in pages {
+        mapper.unmap(page).map_err(|_| "couldn't remove identity mapping")?.1.ignore();
+    }
+    Ok(())
+}
+
+/// Known pattern written to the guest-host pages when verifying them.
+const GUEST_HOST_TEST_PATTERN: u64 = 0x5A5A_A5A5_0F0F_F0F0;
+
+/// Checks that the page table entry of a guest-host page is consistent with
+/// the state of the page in the RMP.
+///
+/// `encrypted` is the state of the encrypted bit in the page table entry (or
+/// `None` if the page is not mapped); `assignment` is the page state in the
+/// RMP as probed by the caller, or `None` if it can't be probed (e.g. because
+/// we're not running under SEV-SNP).
+fn check_guest_host_page(
+    encrypted: Option<bool>,
+    assignment: Option<PageAssignment>,
+) -> Result<(), &'static str> {
+    match (encrypted, assignment) {
+        (None, _) => Err("guest-host page is not mapped"),
+        (Some(true), _) => Err("guest-host page is mapped with the encrypted bit set"),
+        (Some(false), Some(PageAssignment::Private)) => {
+            Err("guest-host page is mapped as shared, but is private in the RMP")
+        }
+        (Some(false), Some(PageAssignment::Shared) | None) => Ok(()),
+    }
+}
+
+/// Verifies that the memory used for guest-host communication is actually
+/// shared with the host.
+///
+/// A mapping bug here would silently break all I/O with the host, so we check
+/// that none of the pages are mapped with the encrypted bit set, that this is
+/// consistent with the RMP state of the pages, and that the pages retain a
+/// known pattern written through the mapping. The original contents of the
+/// pages are restored afterwards.
+///
+/// `probe` returns the RMP state of the page at the given address, as
+/// reported by the hardware (see [`crate::snp::page_assignment`]).
+///
+/// # Safety
+///
+/// The caller has to guarantee that the page range is mapped and that nothing
+/// else accesses the memory concurrently.
+pub unsafe fn verify_guest_host_pages<S: PageSize, T: Translator>(
+    pages: PageRange<S>,
+    translator: &T,
+    probe: impl Fn(VirtAddr) -> Option<PageAssignment>,
+) -> Result<(), &'static str> {
+    for page in pages {
+        check_guest_host_page(
+            translator.is_encrypted(page.start_address()),
+            probe(page.start_address()),
+        )?;
+
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        let original = ptr.read_volatile();
+        ptr.write_volatile(GUEST_HOST_TEST_PATTERN);
+        let read_back = ptr.read_volatile();
+        ptr.write_volatile(original);
+        if read_back != GUEST_HOST_TEST_PATTERN {
+            return Err("guest-host page did not retain the test pattern");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::fakes::{frame, FakePageTable};
+
+    #[test]
+    fn kernel_heap_size_arg() {
+        assert_eq!(kernel_heap_pages_from_arg("1048576"), Ok(DEFAULT_KERNEL_HEAP_PAGES));
+        assert_eq!(kernel_heap_pages_from_arg("4096"), Ok(2048));
+        // Sizes are rounded up to whole 2 MiB pages.
+        assert_eq!(kernel_heap_pages_from_arg("1"), Ok(1));
+        assert_eq!(kernel_heap_pages_from_arg("5"), Ok(3));
+        assert!(kernel_heap_pages_from_arg("0").is_err());
+        assert!(kernel_heap_pages_from_arg("1G").is_err());
+    }
+
+    #[test]
+    fn guest_host_page_shared() {
+        assert!(check_guest_host_page(Some(false), Some(PageAssignment::Shared)).is_ok());
+        // Without SEV-SNP there is no RMP to check against.
+        assert!(check_guest_host_page(Some(false), None).is_ok());
+    }
+
+    #[test]
+    fn guest_host_page_encrypted() {
+        assert!(check_guest_host_page(Some(true), Some(PageAssignment::Shared)).is_err());
+        assert!(check_guest_host_page(Some(true), None).is_err());
+    }
+
+    #[test]
+    fn guest_host_page_private() {
+        assert!(check_guest_host_page(Some(false), Some(PageAssignment::Private)).is_err());
+    }
+
+    fn fragmented_allocator(free: &[u64]) -> PhysicalMemoryAllocator<1> {
+        let mut alloc = PhysicalMemoryAllocator::<1>::new();
+        for &index in free {
+            let frame =
+                PhysFrame::from_start_address(x86_64::PhysAddr::new(index * Size2MiB::SIZE))
+                    .unwrap();
+            alloc.mark_valid(PhysFrame::range(frame, frame + 1), true);
+        }
+        alloc
+    }
+
+    #[test]
+    fn guest_host_frames_preferred() {
+        let mut alloc = fragmented_allocator(&[1, 3, 4, 6]);
+        let frames = allocate_guest_host_frames(&mut alloc, 2, 1, None).unwrap();
+        assert_eq!(frames.count(), 2);
+        assert_eq!(frames.start.start_address().as_u64(), 3 * Size2MiB::SIZE);
+    }
+
+    #[test]
+    fn guest_host_frames_fallback() {
+        // No two free frames are adjacent.
+        let mut alloc = fragmented_allocator(&[1, 3, 5]);
+        let frames = allocate_guest_host_frames(&mut alloc, 2, 1, None).unwrap();
+        assert_eq!(frames.count(), 1);
+        assert_eq!(frames.start.start_address().as_u64(), Size2MiB::SIZE);
+    }
+
+    #[test]
+    fn guest_host_frames_minimum_not_available() {
+        let mut alloc = fragmented_allocator(&[1, 3, 5]);
+        assert_eq!(
+            allocate_guest_host_frames(&mut alloc, 4, 2, None),
+            Err(GuestHostAllocError { needed: 2, largest_available: 1 })
+        );
+        let mut alloc = fragmented_allocator(&[]);
+        assert_eq!(
+            allocate_guest_host_frames(&mut alloc, 2, 1, None),
+            Err(GuestHostAllocError { needed: 1, largest_available: 0 })
+        );
+    }
+
+    #[test]
+    fn guest_host_frames_below_limit() {
+        let mut alloc = fragmented_allocator(&[1, 2, 4, 5]);
+        let limit = PhysAddr::new(4 * Size2MiB::SIZE);
+        let frames = allocate_guest_host_frames(&mut alloc, 2, 1, Some(limit)).unwrap();
+        assert_eq!(frames.start.start_address().as_u64(), Size2MiB::SIZE);
+        assert_eq!(
+            allocate_guest_host_frames(&mut alloc, 2, 1, Some(limit)),
+            Err(GuestHostAllocError { needed: 1, largest_available: 2 })
+        );
+    }
+
+    #[test]
+    fn shared_region_check() {
+        let bounds = VirtAddr::new(0x20_0000)..VirtAddr::new(0x60_0000);
+        assert!(check_in_region(&bounds, VirtAddr::new(0x20_0000), 0x1000).is_ok());
+        assert!(check_in_region(&bounds, VirtAddr::new(0x5F_F000), 0x1000).is_ok());
+        assert!(check_in_region(&bounds, VirtAddr::new(0x1F_F000), 0x1000).is_err());
+        assert!(check_in_region(&bounds, VirtAddr::new(0x5F_F000), 0x1001).is_err());
+        assert!(check_in_region(&bounds, VirtAddr::new(0x60_0000), 1).is_err());
+        assert!(check_in_region(&bounds, VirtAddr::new(0x30_0000), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn guest_host_page_unmapped() {
+        assert!(check_guest_host_page(None, Some(PageAssignment::Shared)).is_err());
+        assert!(check_guest_host_page(None, None).is_err());
+    }
+
+    #[test]
+    fn identity_mapping() {
+        let page_table = FakePageTable::new(8);
+        let frames = PhysFrame::range(frame(2), frame(4));
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let pages = unsafe { map_identity(frames, flags, &page_table) }.unwrap();
+        assert_eq!(pages.count(), 2);
+
+        for addr in [0x40_0000, 0x40_1234, 0x7F_FFFF] {
+            assert_eq!(
+                page_table.translate_virtual(VirtAddr::new(addr)),
+                Some(PhysAddr::new(addr))
+            );
+        }
+        assert_eq!(page_table.translate_virtual(VirtAddr::new(0x80_0000)), None);
+        assert!(!page_table.is_encrypted(VirtAddr::new(0x40_0000)).unwrap());
+
+        unsafe { unmap_identity(frames, &page_table) }.unwrap();
+        assert_eq!(page_table.translate_virtual(VirtAddr::new(0x40_0000)), None);
+    }
+
+    #[test]
+    fn identity_mapping_collision() {
+        let page_table = FakePageTable::new(8);
+        let existing = Page::<Size2MiB>::from_start_address(VirtAddr::new(0x60_0000)).unwrap();
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    existing,
+                    frame(5),
+                    PageTableFlags::PRESENT,
+                    PageTableFlags::PRESENT,
+                )
+                .unwrap()
+                .ignore();
+        }
+
+        let frames = PhysFrame::range(frame(2), frame(4));
+        assert!(unsafe { map_identity(frames, PageTableFlags::empty(), &page_table) }.is_err());
+        // Nothing was mapped.
+        assert_eq!(page_table.translate_virtual(VirtAddr::new(0x40_0000)), None);
+        // The existing mapping isn't an identity mapping, so it can't be removed.
+        assert!(unsafe { unmap_identity(frames, &page_table) }.is_err());
+        assert_eq!(page_table.mapping(existing).0, frame(5));
+    }
+
+    #[test]
+    fn identity_mapping_invalid() {
+        let page_table = FakePageTable::new(1);
+        let frames = PhysFrame::range(frame(1), frame(2));
+        assert!(
+            unsafe { map_identity(frames, PageTableFlags::USER_ACCESSIBLE, &page_table) }.is_err()
+        );
+
+        let high = frame(IDENTITY_MAPPING_LIMIT / Size2MiB::SIZE);
+        let frames = PhysFrame::range(high, high + 1);
+        assert!(unsafe { map_identity(frames, PageTableFlags::empty(), &page_table) }.is_err());
+    }
 }
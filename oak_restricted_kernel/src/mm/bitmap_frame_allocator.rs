@@ -14,11 +14,17 @@
 // limitations under the License.
 //
 
-use core::ops::{BitAnd, Not};
+use core::{
+    cmp::{max, min},
+    ops::{BitAnd, Not},
+};
 
 use bitvec::{order::Lsb0, prelude::BitArray};
-use x86_64::structures::paging::{
-    frame::PhysFrameRange, page::PageSize, FrameAllocator, FrameDeallocator, PhysFrame,
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, page::PageSize, FrameAllocator, FrameDeallocator, PhysFrame,
+    },
+    PhysAddr,
 };
 
 /// Basic frame allocator implementation that keeps track of PageSize-sized
@@ -29,6 +35,10 @@ use x86_64::structures::paging::{
 ///     physical frames are usable memory)
 ///   - `allocated`, which tracks whether a frame is currently allocated or not.
 ///
+/// Additionally, frames that have been permanently removed with `reserve` are
+/// tracked in a third bitmap, `reserved`, so that they can't be added back
+/// with `add_range`.
+///
 /// A frame can be in one of three states:
 ///  valid = 0, allocated = _ -- the allocator will never hand out said frame.
 ///  valid = 1, allocated = 0 -- the frame is eligible for allocation.
@@ -43,6 +53,7 @@ use x86_64::structures::paging::{
 pub(crate) struct BitmapAllocator<S: PageSize, const N: usize> {
     allocated: BitArray<[u64; N], Lsb0>,
     valid: BitArray<[u64; N], Lsb0>,
+    reserved: BitArray<[u64; N], Lsb0>,
     range: PhysFrameRange<S>,
 }
 
@@ -62,7 +73,7 @@ impl<S: PageSize, const N: usize> BitmapAllocator<S, N> {
             panic!("BitmapAllocator bitmap size does not match FrameRange size",);
         }
 
-        Self { allocated: BitArray::ZERO, valid: BitArray::ZERO, range }
+        Self { allocated: BitArray::ZERO, valid: BitArray::ZERO, reserved: BitArray::ZERO, range }
     }
 
     /// Marks a region of memory as either valid or invalid.
@@ -82,6 +93,54 @@ impl<S: PageSize, const N: usize> BitmapAllocator<S, N> {
         }
     }
 
+    /// Permanently marks a region of memory as invalid, so that it will never
+    /// be allocated.
+    ///
+    /// Unlike `mark_valid`, this fails (without changing anything) if any frame
+    /// in the range is currently allocated. Parts of the range that are outside
+    /// the range of the allocator are ignored.
+    pub fn reserve(&mut self, range: PhysFrameRange<S>) -> Result<(), &'static str> {
+        let start = max(range.start, self.range.start);
+        let end = min(range.end, self.range.end);
+        if start >= end {
+            return Ok(());
+        }
+        // Safety: unwrapping these indexes is safe as we've clamped the frames to our
+        // range.
+        let (start_idx, end_idx) = (self.frame_idx(start).unwrap(), self.frame_idx(end).unwrap());
+        if self.valid.bitand(self.allocated)[start_idx..end_idx].any() {
+            return Err("can't reserve frames that have already been allocated");
+        }
+        self.valid[start_idx..end_idx].fill(false);
+        self.reserved[start_idx..end_idx].fill(true);
+        Ok(())
+    }
+
+    /// Marks a region of memory that was not present before as valid, e.g.
+    /// after it was hotplugged.
+    ///
+    /// Fails (without changing anything) if the range is empty or not entirely
+    /// within the range of the allocator, or if any frame in the range is
+    /// already valid or has been reserved.
+    pub fn add_range(&mut self, range: PhysFrameRange<S>) -> Result<(), &'static str> {
+        if range.is_empty() {
+            return Err("can't add an empty range");
+        }
+        let (Some(start_idx), Some(end_idx)) =
+            (self.frame_idx(range.start), self.frame_idx(range.end))
+        else {
+            return Err("range exceeds the capacity of the allocator");
+        };
+        if self.reserved[start_idx..end_idx].any() {
+            return Err("range overlaps reserved memory");
+        }
+        if self.valid[start_idx..end_idx].any() {
+            return Err("range overlaps memory that is already present");
+        }
+        self.valid[start_idx..end_idx].fill(true);
+        Ok(())
+    }
+
     /// Returns a BitArray where 1 denotes a frame that's eligible for
     /// allocation.
     ///
@@ -137,8 +196,32 @@ impl<S: PageSize, const N: usize> BitmapAllocator<S, N> {
 
     /// Attempts to allocate `num` contiguous physical frames.
     pub fn allocate_contiguous(&mut self, num: usize) -> Option<PhysFrameRange<S>> {
-        let (start_idx, end_idx) = self
-            .available()
+        self.allocate_contiguous_before(num, N * 64)
+    }
+
+    /// Attempts to allocate `num` contiguous physical frames that all end at or
+    /// below `limit`.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        num: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange<S>> {
+        let start = self.range.start.start_address();
+        if limit <= start {
+            return None;
+        }
+        let limit_idx = ((limit - start) / S::SIZE).min((N * 64) as u64) as usize;
+        self.allocate_contiguous_before(num, limit_idx)
+    }
+
+    /// Attempts to allocate `num` contiguous frames from the first `limit_idx`
+    /// frames of the map.
+    fn allocate_contiguous_before(
+        &mut self,
+        num: usize,
+        limit_idx: usize,
+    ) -> Option<PhysFrameRange<S>> {
+        let (start_idx, end_idx) = self.available()[..limit_idx]
             // split into overlapping views of length n
             .windows(num)
             // keep an index (the index of the first bit)
@@ -215,7 +298,7 @@ mod tests {
     use std::{format, prelude::rust_2021::*, vec};
 
     use assertables::*;
-    use x86_64::{structures::paging::Size4KiB, PhysAddr};
+    use x86_64::structures::paging::Size4KiB;
 
     use super::*;
 
@@ -395,4 +478,51 @@ mod tests {
         assert_eq!(None, alloc.allocate_contiguous(2));
         assert_eq!(None, alloc.allocate_frame());
     }
+
+    #[test]
+    fn test_allocate_contiguous_below() {
+        let mut alloc = create_allocator::<1>(0x1000, 0x5000);
+        alloc.mark_valid(create_frame_range(0x1000, 0x5000), true);
+        assert_eq!(None, alloc.allocate_contiguous_below(1, PhysAddr::new(0x1000)));
+        // A frame that straddles the limit doesn't count as being below it.
+        assert_eq!(None, alloc.allocate_contiguous_below(2, PhysAddr::new(0x2fff)));
+        assert_eq!(
+            Some(create_frame_range(0x1000, 0x3000)),
+            alloc.allocate_contiguous_below(2, PhysAddr::new(0x3000))
+        );
+        assert_eq!(None, alloc.allocate_contiguous_below(1, PhysAddr::new(0x3000)));
+        // Limits beyond the end of the range are clamped to it.
+        assert_eq!(
+            Some(create_frame_range(0x3000, 0x5000)),
+            alloc.allocate_contiguous_below(2, PhysAddr::new(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn add_range_to_empty_region() {
+        let mut alloc = BitmapAllocator::<Size4KiB, 1>::new(create_frame_range(0x0, 0x40000));
+        alloc.mark_valid(create_frame_range(0x0, 0x10000), true);
+        alloc.add_range(create_frame_range(0x20000, 0x28000)).unwrap();
+        assert_eq!(alloc.num_valid(), 0x18);
+        assert_eq!(
+            alloc.allocate(create_frame_range(0x20000, 0x28000)),
+            Some(create_frame_range(0x20000, 0x28000))
+        );
+    }
+
+    #[test]
+    fn add_range_rejects_invalid_ranges() {
+        let mut alloc = BitmapAllocator::<Size4KiB, 1>::new(create_frame_range(0x0, 0x40000));
+        alloc.mark_valid(create_frame_range(0x0, 0x10000), true);
+        alloc.reserve(create_frame_range(0x30000, 0x31000)).unwrap();
+        // Already present.
+        assert!(alloc.add_range(create_frame_range(0x8000, 0x18000)).is_err());
+        // Reserved.
+        assert!(alloc.add_range(create_frame_range(0x2f000, 0x32000)).is_err());
+        // Beyond the capacity of the allocator.
+        assert!(alloc.add_range(create_frame_range(0x3f000, 0x41000)).is_err());
+        // Empty.
+        assert!(alloc.add_range(create_frame_range(0x20000, 0x20000)).is_err());
+        assert_eq!(alloc.num_valid(), 0x10);
+    }
 }
@@ -0,0 +1,293 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Copy-on-write mappings for user space.
+//!
+//! Pages marked with `COPY_ON_WRITE` are mapped read-only and may share their
+//! physical frame with other mappings. The first write to such a page causes a
+//! page fault, which we resolve by copying the contents into a private frame
+//! and mapping that frame writable in place of the shared one.
+//!
+//! Physical frames are not reference counted, so the shared frame is never
+//! returned to the frame allocator.
+
+use core::ptr::copy_nonoverlapping;
+
+use x86_64::{
+    structures::paging::{
+        mapper::MapperFlush, FrameAllocator, FrameDeallocator, Page, PageSize, PhysFrame, Size2MiB,
+    },
+    VirtAddr,
+};
+
+use super::{Mapper, PageTableFlags, Translator};
+use crate::{FRAME_ALLOCATOR, PAGE_TABLES};
+
+/// Removes write access from already-mapped pages, making them shareable.
+///
+/// If `copy_on_write` is set the pages are additionally marked as
+/// copy-on-write, so that writes to them will get a private copy instead of
+/// failing.
+///
+/// # Safety
+///
+/// The caller must ensure nothing relies on the pages being writable
+/// (other than through the copy-on-write mechanism).
+pub unsafe fn share_read_only<M: Mapper<Size2MiB> + Translator>(
+    mapper: &M,
+    pages: impl Iterator<Item = Page<Size2MiB>>,
+    copy_on_write: bool,
+) -> Result<(), &'static str> {
+    for page in pages {
+        let mut flags = mapper.flags(page.start_address()).ok_or("page is not mapped")?;
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.set(PageTableFlags::COPY_ON_WRITE, copy_on_write);
+        mapper.update_flags(page, flags).map_err(|_| "couldn't update page table flags")?.flush();
+    }
+    Ok(())
+}
+
+/// Returns a newly allocated frame to the frame allocator when dropped, unless
+/// it has been disarmed because the frame ended up mapped.
+struct FrameGuard<'a, A: FrameDeallocator<Size2MiB>> {
+    frame_allocator: &'a mut A,
+    frame: Option<PhysFrame<Size2MiB>>,
+}
+
+impl<'a, A: FrameDeallocator<Size2MiB>> FrameGuard<'a, A> {
+    fn disarm(mut self) {
+        self.frame = None;
+    }
+}
+
+impl<'a, A: FrameDeallocator<Size2MiB>> Drop for FrameGuard<'a, A> {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            // Safety: the frame was allocated by this allocator and hasn't been mapped.
+            unsafe { self.frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Replaces a copy-on-write page that contains `addr` with a private, writable
+/// copy.
+///
+/// The new frame is always mapped encrypted: even if the shared frame was
+/// visible to the host, the private copy holds data written by the guest.
+///
+/// Returns `Ok(None)` if the page is not a copy-on-write page, in which case
+/// the fault is genuine. Otherwise returns the TLB flush the caller must
+/// perform before returning to the faulting code.
+///
+/// On error the private frame is returned to `frame_allocator`, and the page
+/// keeps its shared mapping.
+///
+/// # Safety
+///
+/// `mapper` must be the page table that was active when the fault occurred,
+/// and the physical frames must be accessible through its direct mapping.
+pub unsafe fn resolve_fault<
+    M: Mapper<Size2MiB> + Translator,
+    A: FrameAllocator<Size2MiB> + FrameDeallocator<Size2MiB>,
+>(
+    mapper: &M,
+    frame_allocator: &mut A,
+    addr: VirtAddr,
+) -> Result<Option<MapperFlush<Size2MiB>>, &'static str> {
+    let page = Page::<Size2MiB>::containing_address(addr);
+    let flags = match mapper.flags(page.start_address()) {
+        Some(flags) if flags.contains(PageTableFlags::COPY_ON_WRITE) => flags,
+        _ => return Ok(None),
+    };
+    let shared_frame = PhysFrame::<Size2MiB>::containing_address(
+        mapper.translate_virtual(page.start_address()).ok_or("page is not mapped")?,
+    );
+    let private_frame =
+        frame_allocator.allocate_frame().ok_or("couldn't allocate a frame for the copy")?;
+    let guard = FrameGuard { frame_allocator, frame: Some(private_frame) };
+    let source = mapper
+        .translate_physical_frame(shared_frame)
+        .ok_or("couldn't translate the shared frame")?;
+    let destination = mapper
+        .translate_physical_frame(private_frame)
+        .ok_or("couldn't translate the private frame")?;
+    copy_nonoverlapping(
+        source.start_address().as_ptr::<u8>(),
+        destination.start_address().as_mut_ptr::<u8>(),
+        Size2MiB::SIZE as usize,
+    );
+
+    // We flush the TLB entry once the new mapping is in place, so there's no
+    // need to flush after unmapping.
+    mapper.unmap(page).map_err(|_| "couldn't unmap the shared frame")?.1.ignore();
+    let private_flags = (flags - PageTableFlags::COPY_ON_WRITE)
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::ENCRYPTED;
+    match mapper.map_to_with_table_flags(
+        page,
+        private_frame,
+        private_flags,
+        PageTableFlags::empty(),
+    ) {
+        Ok(flush) => {
+            guard.disarm();
+            Ok(Some(flush))
+        }
+        Err(_) => {
+            // Put the shared frame back so that the page doesn't disappear. The
+            // TLB can only hold the old mapping, so there's nothing to flush.
+            if let Ok(flush) =
+                mapper.map_to_with_table_flags(page, shared_frame, flags, PageTableFlags::empty())
+            {
+                flush.ignore();
+            }
+            Err("couldn't map the private frame")
+        }
+    }
+}
+
+/// Handles a write page fault at `addr`, resolving it if it hit a
+/// copy-on-write page.
+///
+/// Returns `true` if the fault has been resolved and the faulting instruction
+/// can be retried.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    // Don't risk deadlocking if the fault happened while either lock was held.
+    let (Some(pt_guard), Some(mut frame_allocator)) =
+        (PAGE_TABLES.try_lock(), FRAME_ALLOCATOR.try_lock())
+    else {
+        return false;
+    };
+    let Some(mapper) = pt_guard.get() else {
+        return false;
+    };
+    // Safety: PAGE_TABLES holds the currently active page tables, which contain
+    // the direct mapping of physical memory.
+    match unsafe { resolve_fault(mapper, &mut *frame_allocator, addr) } {
+        Ok(Some(flush)) => {
+            flush.flush();
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            log::error!("failed to resolve copy-on-write fault at {:#018x}: {}", addr, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::mm::fakes::{frame, FakeFrameAllocator, FakePageTable};
+
+    const USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::USER_ACCESSIBLE)
+        .union(PageTableFlags::NO_EXECUTE);
+
+    #[test]
+    fn write_fault_replaces_shared_frame_with_private_copy() {
+        let mut page_table = FakePageTable::new(2);
+        let page = Page::<Size2MiB>::from_start_address(VirtAddr::new(0x4000_0000)).unwrap();
+        page_table.frame_contents(frame(0)).fill(0xAB);
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    page,
+                    frame(0),
+                    USER_FLAGS | PageTableFlags::WRITABLE,
+                    PageTableFlags::empty(),
+                )
+                .unwrap()
+                .ignore();
+            page_table
+                .update_flags(page, USER_FLAGS | PageTableFlags::COPY_ON_WRITE)
+                .unwrap()
+                .ignore();
+        }
+        let mut allocator = FakeFrameAllocator(vec![frame(1)]);
+
+        let flush =
+            unsafe { resolve_fault(&page_table, &mut allocator, page.start_address() + 0x10u64) }
+                .unwrap();
+        assert!(flush.is_some());
+        flush.unwrap().ignore();
+
+        let (private_frame, flags) = page_table.mapping(page);
+        assert_eq!(private_frame, frame(1));
+        assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::ENCRYPTED | USER_FLAGS));
+        assert!(!flags.contains(PageTableFlags::COPY_ON_WRITE));
+        assert!(page_table.frame_contents(frame(1)).iter().all(|&byte| byte == 0xAB));
+
+        // Writes to the private copy must not be visible through the shared frame.
+        page_table.frame_contents(frame(1))[0] = 0;
+        assert_eq!(page_table.frame_contents(frame(0))[0], 0xAB);
+    }
+
+    #[test]
+    fn failed_map_returns_private_frame_to_allocator() {
+        let page_table = FakePageTable::new(2);
+        let page = Page::<Size2MiB>::from_start_address(VirtAddr::new(0x4000_0000)).unwrap();
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    page,
+                    frame(0),
+                    USER_FLAGS | PageTableFlags::COPY_ON_WRITE,
+                    PageTableFlags::empty(),
+                )
+                .unwrap()
+                .ignore();
+        }
+        let mut allocator = FakeFrameAllocator(vec![frame(1)]);
+
+        page_table.fail_next_map();
+        assert!(
+            unsafe { resolve_fault(&page_table, &mut allocator, page.start_address()) }.is_err()
+        );
+        assert_eq!(allocator.0, vec![frame(1)]);
+        let (shared_frame, flags) = page_table.mapping(page);
+        assert_eq!(shared_frame, frame(0));
+        assert_eq!(flags.bits(), (USER_FLAGS | PageTableFlags::COPY_ON_WRITE).bits());
+    }
+
+    #[test]
+    fn write_fault_on_regular_page_is_not_resolved() {
+        let page_table = FakePageTable::new(2);
+        let page = Page::<Size2MiB>::from_start_address(VirtAddr::new(0x4000_0000)).unwrap();
+        unsafe {
+            page_table
+                .map_to_with_table_flags(page, frame(0), USER_FLAGS, PageTableFlags::empty())
+                .unwrap()
+                .ignore();
+        }
+        let mut allocator = FakeFrameAllocator(vec![frame(1)]);
+
+        assert!(unsafe { resolve_fault(&page_table, &mut allocator, page.start_address()) }
+            .unwrap()
+            .is_none());
+        // Unmapped addresses aren't copy-on-write either.
+        assert!(unsafe { resolve_fault(&page_table, &mut allocator, VirtAddr::new(0x8000_0000)) }
+            .unwrap()
+            .is_none());
+        let (shared_frame, flags) = page_table.mapping(page);
+        assert_eq!(shared_frame, frame(0));
+        assert_eq!(flags.bits(), USER_FLAGS.bits());
+        assert_eq!(allocator.0.len(), 1);
+    }
+}
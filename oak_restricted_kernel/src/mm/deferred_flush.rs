@@ -0,0 +1,226 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Batched TLB flushes for operations that change many mappings at once.
+//!
+//! Flushing each page with `invlpg` as it is mapped gets slow for large ranges,
+//! such as when loading a big payload. A [`DeferredFlush`] collects the pages
+//! instead, and when it is dropped either flushes them one by one or, if there
+//! are more than the threshold, flushes the whole TLB once.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr4, Cr4Flags},
+    structures::paging::{mapper::MapperFlush, Page, PageSize},
+    VirtAddr,
+};
+
+/// Kernel argument that sets the number of pages above which a bulk mapping
+/// operation flushes the whole TLB instead of the individual pages; for
+/// example, `tlb_flush_threshold=16`.
+pub const TLB_FLUSH_THRESHOLD_ARG: &str = "tlb_flush_threshold";
+
+/// The largest threshold we support; this is how many pages a
+/// [`DeferredFlush`] can keep track of.
+pub const MAX_TLB_FLUSH_THRESHOLD: usize = 64;
+
+/// The threshold used if the kernel argument is not set.
+pub const DEFAULT_TLB_FLUSH_THRESHOLD: usize = 32;
+
+static THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_TLB_FLUSH_THRESHOLD);
+
+/// Parses the value of [`TLB_FLUSH_THRESHOLD_ARG`].
+pub fn parse_threshold(arg: &str) -> Result<usize, &'static str> {
+    match arg.parse() {
+        Ok(threshold) if threshold <= MAX_TLB_FLUSH_THRESHOLD => Ok(threshold),
+        _ => Err("expected a number of pages between 0 and 64"),
+    }
+}
+
+/// Sets the threshold for all subsequent bulk mapping operations.
+pub fn set_threshold(threshold: usize) {
+    THRESHOLD.store(threshold.min(MAX_TLB_FLUSH_THRESHOLD), Ordering::Relaxed);
+}
+
+/// Invalidates TLB entries.
+pub trait Tlb {
+    /// Invalidates the entry for the page containing `addr`.
+    fn flush_page(&mut self, addr: VirtAddr);
+
+    /// Invalidates all entries.
+    fn flush_all(&mut self);
+}
+
+/// The TLB of the current CPU.
+pub struct CpuTlb;
+
+impl Tlb for CpuTlb {
+    fn flush_page(&mut self, addr: VirtAddr) {
+        tlb::flush(addr);
+    }
+
+    fn flush_all(&mut self) {
+        // Reloading CR3 leaves the entries for global pages (such as the kernel
+        // heap) in place, so toggle global pages off and on again instead if they
+        // are enabled; that invalidates everything.
+        let cr4 = Cr4::read();
+        if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            // Safety: global pages are only an optimization, turning them off for a
+            // moment doesn't change any mappings.
+            unsafe {
+                Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+                Cr4::write(cr4);
+            }
+        } else {
+            tlb::flush_all();
+        }
+    }
+}
+
+/// Collects the TLB flushes of a bulk mapping operation, and performs them
+/// when dropped.
+pub struct DeferredFlush<T: Tlb = CpuTlb> {
+    tlb: T,
+    threshold: usize,
+    pages: [VirtAddr; MAX_TLB_FLUSH_THRESHOLD],
+    /// Number of pages deferred so far; only the first `threshold` are kept in
+    /// `pages`.
+    count: usize,
+}
+
+impl DeferredFlush {
+    /// Creates a batch for the current CPU, using the configured threshold.
+    pub fn new() -> Self {
+        Self::with_tlb(CpuTlb, THRESHOLD.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for DeferredFlush {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Tlb> DeferredFlush<T> {
+    pub fn with_tlb(tlb: T, threshold: usize) -> Self {
+        Self {
+            tlb,
+            threshold: threshold.min(MAX_TLB_FLUSH_THRESHOLD),
+            pages: [VirtAddr::zero(); MAX_TLB_FLUSH_THRESHOLD],
+            count: 0,
+        }
+    }
+
+    /// Takes over the flush for `page`, whose mapping has just changed.
+    pub fn defer<S: PageSize>(&mut self, page: Page<S>, flush: MapperFlush<S>) {
+        flush.ignore();
+        self.push(page.start_address());
+    }
+
+    fn push(&mut self, addr: VirtAddr) {
+        if let Some(slot) = self.pages[..self.threshold].get_mut(self.count) {
+            *slot = addr;
+        }
+        self.count += 1;
+    }
+}
+
+impl<T: Tlb> Drop for DeferredFlush<T> {
+    fn drop(&mut self) {
+        if self.count > self.threshold {
+            self.tlb.flush_all();
+        } else {
+            self.pages[..self.count].iter().for_each(|addr| self.tlb.flush_page(*addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use x86_64::structures::paging::Size2MiB;
+
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Flushes {
+        pages: Vec<VirtAddr>,
+        full: usize,
+    }
+
+    /// Records the flushes it is asked to perform.
+    struct FakeTlb<'a>(&'a mut Flushes);
+
+    impl Tlb for FakeTlb<'_> {
+        fn flush_page(&mut self, addr: VirtAddr) {
+            self.0.pages.push(addr);
+        }
+
+        fn flush_all(&mut self) {
+            self.0.full += 1;
+        }
+    }
+
+    /// Defers the flush of `count` pages, and returns the flushes that were
+    /// performed.
+    fn flush_pages(count: u64, threshold: usize) -> Flushes {
+        let mut flushes = Flushes::default();
+        {
+            let mut batch = DeferredFlush::with_tlb(FakeTlb(&mut flushes), threshold);
+            for i in 1..=count {
+                batch.push(VirtAddr::new(i * Size2MiB::SIZE));
+            }
+        }
+        flushes
+    }
+
+    #[test]
+    fn flushes_pages_individually_up_to_threshold() {
+        let flushes = flush_pages(3, 4);
+        assert_eq!(flushes.pages, [1, 2, 3].map(|i| VirtAddr::new(i * Size2MiB::SIZE)).to_vec());
+        assert_eq!(flushes.full, 0);
+
+        let flushes = flush_pages(4, 4);
+        assert_eq!(flushes.pages.len(), 4);
+        assert_eq!(flushes.full, 0);
+    }
+
+    #[test]
+    fn flushes_everything_once_above_threshold() {
+        assert_eq!(flush_pages(5, 4), Flushes { pages: Vec::new(), full: 1 });
+        // Far more pages than can be tracked still result in a single flush.
+        assert_eq!(
+            flush_pages(1000, MAX_TLB_FLUSH_THRESHOLD),
+            Flushes { pages: Vec::new(), full: 1 }
+        );
+    }
+
+    #[test]
+    fn nothing_to_flush() {
+        assert_eq!(flush_pages(0, 4), Flushes::default());
+    }
+
+    #[test]
+    fn parses_threshold_arg() {
+        assert_eq!(parse_threshold("0"), Ok(0));
+        assert_eq!(parse_threshold("64"), Ok(64));
+        assert!(parse_threshold("65").is_err());
+        assert!(parse_threshold("many").is_err());
+    }
+}
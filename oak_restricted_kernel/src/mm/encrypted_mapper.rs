@@ -14,8 +14,9 @@
 // limitations under the License.
 //
 
-use core::ops::DerefMut;
+use core::ops::{DerefMut, Range};
 
+use oak_core::sync::OnceCell;
 use spinning_top::Spinlock;
 use x86_64::{
     structures::paging::{
@@ -24,13 +25,63 @@ use x86_64::{
             UnmapError,
         },
         FrameAllocator, FrameDeallocator, MappedPageTable, Mapper as BaseMapper, Page, PageSize,
-        PageTable, PhysFrame, Size4KiB, Translate as BaseTranslate,
+        PageTable, PhysFrame, Size4KiB, Translate as BaseTranslate, TranslateResult,
     },
     PhysAddr, VirtAddr,
 };
 
-use super::{Mapper, PageTableFlags, Translator};
-use crate::FRAME_ALLOCATOR;
+use super::{
+    page_tables::RootPageTable,
+    translation_cache::{self, TranslationCache},
+    Mapper, PageTableFlags, Translator,
+};
+use crate::{FRAME_ALLOCATOR, PAGE_TABLES};
+
+/// Index of the first PML4 entry that maps the upper (kernel) half of the
+/// canonical address space.
+const KERNEL_PML4_START: usize = 256;
+
+/// Physical frames shared with the host, i.e. the guest-host region.
+///
+/// Debug builds check every mapping made through `EncryptedPageTable` against
+/// it; see `check_encryption`.
+static SHARED_FRAMES: OnceCell<Range<PhysAddr>> = OnceCell::new();
+
+/// Records the physical range of the guest-host region.
+///
+/// This must be called before the region is remapped without the `ENCRYPTED`
+/// bit, as the checks only take effect once the region is known.
+pub fn set_shared_frames(range: Range<PhysAddr>) -> Result<(), &'static str> {
+    SHARED_FRAMES.set(range).map_err(|_| "shared frames were already set")
+}
+
+/// Checks that the encryption state of a mapping matches the region it maps.
+///
+/// Pages backed by shared frames must never have the encryption bit set, as
+/// the host can't read memory encrypted with the guest key. If memory
+/// encryption is in use, the direct mapping of any other frame is private
+/// memory and must have the encryption bit set; other mappings (e.g. MMIO or
+/// the GHCB) are not checked.
+fn check_encryption(
+    shared: &Range<PhysAddr>,
+    encryption: MemoryEncryption,
+    direct_mapping_offset: VirtAddr,
+    page: VirtAddr,
+    frame: PhysAddr,
+    encrypted: bool,
+) -> Result<(), &'static str> {
+    if shared.contains(&frame) {
+        if encrypted {
+            return Err("guest-host page mapped with the encryption bit set");
+        }
+    } else if matches!(encryption, MemoryEncryption::Encrypted(_))
+        && direct_mapping_offset.as_u64().checked_add(frame.as_u64()) == Some(page.as_u64())
+        && !encrypted
+    {
+        return Err("private page mapped without the encryption bit set");
+    }
+    Ok(())
+}
 
 #[derive(Clone, Copy, Debug)]
 pub enum MemoryEncryption {
@@ -138,9 +189,15 @@ pub struct EncryptedPageTable<N: MapperAllSizes> {
     encryption: MemoryEncryption,
     offset: VirtAddr,
     inner: Spinlock<N>,
+    translations: TranslationCache,
 }
 
 impl<N: MapperAllSizes> EncryptedPageTable<N> {
+    /// Returns the underlying page table.
+    ///
+    /// Changes made to mappings through it bypass the translation cache, so
+    /// callers that change mappings have to call
+    /// `translation_cache::invalidate_all` afterwards.
     pub fn inner(&mut self) -> &mut Spinlock<N> {
         &mut self.inner
     }
@@ -154,6 +211,60 @@ impl<'a> EncryptedPageTable<MappedPageTable<'a, PhysOffset>> {
             inner: Spinlock::new(unsafe {
                 MappedPageTable::new(pml4, PhysOffset { offset, encryption })
             }),
+            translations: TranslationCache::new(),
+        }
+    }
+
+    /// Creates a new address space that shares the kernel mappings of this
+    /// page table.
+    ///
+    /// The upper half of the new PML4 points to the same lower-level tables as
+    /// this page table, so any kernel mappings are shared between the two;
+    /// the lower (user) half of the new PML4 is empty.
+    pub fn new_user_space(
+        &self,
+    ) -> Result<EncryptedPageTable<MappedPageTable<'static, PhysOffset>>, &'static str> {
+        let frame = EncryptedFrameAllocator::new(self.encryption)
+            .allocate_frame()
+            .ok_or("couldn't allocate frame for the PML4")?;
+        let pml4_ptr = PhysOffset::new(self.offset, self.encryption).frame_to_pointer(frame);
+        // Safety: the frame was just allocated, so nothing else references it, and all
+        // physical memory is mapped at `offset`.
+        let pml4 = unsafe {
+            pml4_ptr.write(PageTable::new());
+            &mut *pml4_ptr
+        };
+        share_kernel_entries(self.inner.lock().level_4_table(), pml4);
+        Ok(EncryptedPageTable::new(pml4, self.offset, self.encryption))
+    }
+
+    /// Returns the physical frame of the PML4, suitable for loading into CR3.
+    pub fn pml4_frame(&self) -> PhysFrame {
+        let pml4 = VirtAddr::from_ptr(self.inner.lock().level_4_table() as *const PageTable);
+        PhysFrame::containing_address(PhysAddr::new(pml4 - self.offset))
+    }
+
+    /// Loads this page table into CR3, making it the current address space.
+    ///
+    /// Returns the previous page table, if there was one.
+    ///
+    /// # Safety
+    ///
+    /// The page table must keep the kernel mappings intact, e.g. because it was
+    /// created using `new_user_space`.
+    pub unsafe fn switch_to(&self) -> Option<RootPageTable> {
+        PAGE_TABLES.lock().replace(self.pml4_frame())
+    }
+}
+
+/// Points the upper half of `user` at the same lower-level tables as the upper
+/// half of `kernel`, and clears the lower half of `user`.
+fn share_kernel_entries(kernel: &PageTable, user: &mut PageTable) {
+    for (index, (entry, kernel_entry)) in user.iter_mut().zip(kernel.iter()).enumerate() {
+        if index < KERNEL_PML4_START {
+            entry.set_unused();
+        } else {
+            *entry = kernel_entry.clone();
         }
     }
 }
@@ -166,6 +277,21 @@ impl<S: PageSize, N: MapperAllSizes + BaseMapper<S>> Mapper<S> for EncryptedPage
         flags: PageTableFlags,
         parent_table_flags: PageTableFlags,
     ) -> Result<MapperFlush<S>, MapToError<S>> {
+        if cfg!(debug_assertions) {
+            if let Some(shared) = SHARED_FRAMES.get() {
+                if let Err(err) = check_encryption(
+                    shared,
+                    self.encryption,
+                    self.offset,
+                    page.start_address(),
+                    frame.start_address(),
+                    flags.contains(PageTableFlags::ENCRYPTED),
+                ) {
+                    panic!("{} at {:?} (frame {:?})", err, page.start_address(), frame);
+                }
+            }
+        }
+
         // Set the encrypted bit in the physical frame, if needed.
         let frame = if flags.contains(PageTableFlags::ENCRYPTED) {
             PhysFrame::from_start_address(frame.start_address() + self.encryption.bit()).unwrap()
@@ -173,17 +299,25 @@ impl<S: PageSize, N: MapperAllSizes + BaseMapper<S>> Mapper<S> for EncryptedPage
             frame
         };
 
-        self.inner.lock().map_to_with_table_flags(
+        let mut inner = self.inner.lock();
+        let result = inner.map_to_with_table_flags(
             page,
             frame,
             flags.into(),
             parent_table_flags.into(),
             &mut EncryptedFrameAllocator::new(self.encryption),
-        )
+        );
+        translation_cache::invalidate_all();
+        result
     }
 
     unsafe fn unmap(&self, page: Page<S>) -> Result<(PhysFrame<S>, MapperFlush<S>), UnmapError> {
-        let (frame, flush) = self.inner.lock().unmap(page)?;
+        let (frame, flush) = {
+            let mut inner = self.inner.lock();
+            let result = inner.unmap(page);
+            translation_cache::invalidate_all();
+            result?
+        };
         // if the frame had the encrypted bit set, strip it
         let frame = PhysFrame::from_start_address(PhysAddr::new(
             frame.start_address().as_u64() & !self.encryption.bit(),
@@ -223,11 +357,17 @@ impl<S: PageSize, N: MapperAllSizes + BaseMapper<S>> Mapper<S> for EncryptedPage
     }
 }
 
+impl<N: MapperAllSizes + BaseTranslate> EncryptedPageTable<N> {
+    /// Translates `addr` to the physical address in the page table entry,
+    /// including the encrypted bit.
+    fn translate_cached(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.translations.translate(addr, |page| self.inner.lock().translate_addr(page))
+    }
+}
+
 impl<N: MapperAllSizes + BaseTranslate> Translator for EncryptedPageTable<N> {
     fn translate_virtual(&self, addr: VirtAddr) -> Option<PhysAddr> {
-        Some(PhysAddr::new(
-            self.inner.lock().translate_addr(addr)?.as_u64() & !self.encryption.bit(),
-        ))
+        Some(PhysAddr::new(self.translate_cached(addr)?.as_u64() & !self.encryption.bit()))
     }
 
     fn translate_physical(&self, addr: PhysAddr) -> Option<VirtAddr> {
@@ -237,6 +377,24 @@ impl<N: MapperAllSizes + BaseTranslate> Translator for EncryptedPageTable<N> {
     fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>> {
         Page::from_start_address(self.translate_physical(frame.start_address())?).ok()
     }
+
+    fn is_encrypted(&self, addr: VirtAddr) -> Option<bool> {
+        Some(self.translate_cached(addr)?.as_u64() & self.encryption.bit() != 0)
+    }
+
+    fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.inner.lock().translate(addr) {
+            TranslateResult::Mapped { frame, flags, .. } => {
+                // The C-bit is part of the frame address, not of the flags.
+                let mut flags = PageTableFlags::from_bits_truncate(flags.bits());
+                if frame.start_address().as_u64() & self.encryption.bit() != 0 {
+                    flags |= PageTableFlags::ENCRYPTED;
+                }
+                Some(flags)
+            }
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -579,6 +737,7 @@ mod tests {
                 expected_phys_frame: PhysFrame::from_start_address(PhysAddr::new(0x12341000))
                     .unwrap(),
             }),
+            translations: TranslationCache::new(),
         };
 
         unsafe {
@@ -610,6 +769,7 @@ mod tests {
                 ))
                 .unwrap(),
             }),
+            translations: TranslationCache::new(),
         };
 
         unsafe {
@@ -641,6 +801,7 @@ mod tests {
                 ))
                 .unwrap(),
             }),
+            translations: TranslationCache::new(),
         };
 
         unsafe {
@@ -660,6 +821,7 @@ mod tests {
                 expected_phys_frame: PhysFrame::from_start_address(PhysAddr::new(0x12341000))
                     .unwrap(),
             }),
+            translations: TranslationCache::new(),
         };
 
         unsafe {
@@ -682,6 +844,7 @@ mod tests {
                 expected_phys_frame: PhysFrame::from_start_address(PhysAddr::new(0x12341000))
                     .unwrap(),
             }),
+            translations: TranslationCache::new(),
         };
 
         unsafe {
@@ -702,4 +865,165 @@ mod tests {
                 .ignore();
         }
     }
+
+    #[test]
+    fn user_space_shares_kernel_entries() {
+        let mut kernel = PageTable::new();
+        kernel[0].set_addr(PhysAddr::new(0x1000), BasePageTableFlags::PRESENT);
+        kernel[KERNEL_PML4_START].set_addr(
+            PhysAddr::new(0x2000),
+            BasePageTableFlags::PRESENT | BasePageTableFlags::WRITABLE,
+        );
+        kernel[511].set_addr(PhysAddr::new(0x3000), BasePageTableFlags::PRESENT);
+        let mut user = PageTable::new();
+        user[1].set_addr(PhysAddr::new(0x4000), BasePageTableFlags::PRESENT);
+
+        share_kernel_entries(&kernel, &mut user);
+
+        assert!(user.iter().take(KERNEL_PML4_START).all(|entry| entry.is_unused()));
+        for (user_entry, kernel_entry) in user.iter().zip(kernel.iter()).skip(KERNEL_PML4_START) {
+            assert_eq!(user_entry.addr(), kernel_entry.addr());
+            assert_eq!(user_entry.flags(), kernel_entry.flags());
+        }
+        assert_eq!(user[511].addr(), PhysAddr::new(0x3000));
+    }
+
+    #[test]
+    fn is_encrypted() {
+        let fake_mapper = || {
+            Spinlock::new(FakeMapper {
+                expected_phys_frame: PhysFrame::from_start_address(PhysAddr::new(0x12341000))
+                    .unwrap(),
+            })
+        };
+
+        // Use a low C-bit position so that we can control it through the fake
+        // translation: 0x12341000 translates to 0x1000, which has bit 12 set.
+        let mapper = EncryptedPageTable {
+            encryption: MemoryEncryption::Encrypted(12),
+            offset: VirtAddr::new(0x1234000),
+            inner: fake_mapper(),
+            translations: TranslationCache::new(),
+        };
+        assert_eq!(mapper.is_encrypted(VirtAddr::new(0x12341000)), Some(true));
+        assert_eq!(mapper.is_encrypted(VirtAddr::new(0x12342000)), Some(false));
+
+        let mapper = EncryptedPageTable {
+            encryption: MemoryEncryption::NoEncryption,
+            offset: VirtAddr::new(0x1234000),
+            inner: fake_mapper(),
+            translations: TranslationCache::new(),
+        };
+        assert_eq!(mapper.is_encrypted(VirtAddr::new(0x12341000)), Some(false));
+    }
+
+    #[test]
+    fn check_encryption_rules() {
+        let shared = PhysAddr::new(0x100000)..PhysAddr::new(0x200000);
+        let encryption = MemoryEncryption::Encrypted(51);
+        let offset = VirtAddr::new(0xffff_8880_0000_0000);
+        let shared_frame = PhysAddr::new(0x100000);
+        let private_frame = PhysAddr::new(0x300000);
+
+        // Shared frames must be unencrypted, wherever they're mapped.
+        assert!(check_encryption(
+            &shared,
+            encryption,
+            offset,
+            offset + 0x100000u64,
+            shared_frame,
+            false
+        )
+        .is_ok());
+        assert!(check_encryption(
+            &shared,
+            encryption,
+            offset,
+            offset + 0x100000u64,
+            shared_frame,
+            true
+        )
+        .is_err());
+        assert!(check_encryption(
+            &shared,
+            MemoryEncryption::NoEncryption,
+            offset,
+            VirtAddr::new(0x1000),
+            shared_frame,
+            true
+        )
+        .is_err());
+
+        // The direct mapping of private frames must be encrypted.
+        assert!(check_encryption(
+            &shared,
+            encryption,
+            offset,
+            offset + 0x300000u64,
+            private_frame,
+            true
+        )
+        .is_ok());
+        assert!(check_encryption(
+            &shared,
+            encryption,
+            offset,
+            offset + 0x300000u64,
+            private_frame,
+            false
+        )
+        .is_err());
+        // ...unless there is no memory encryption at all.
+        assert!(check_encryption(
+            &shared,
+            MemoryEncryption::NoEncryption,
+            offset,
+            offset + 0x300000u64,
+            private_frame,
+            false
+        )
+        .is_ok());
+        // Other mappings of private frames are not checked.
+        assert!(check_encryption(
+            &shared,
+            encryption,
+            offset,
+            VirtAddr::new(0x1000),
+            private_frame,
+            false
+        )
+        .is_ok());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "guest-host page mapped with the encryption bit set")]
+    fn mapper_encrypted_shared_page() {
+        // No other test maps frames in this range, so registering it here doesn't
+        // affect them.
+        set_shared_frames(PhysAddr::new(0x4000_0000)..PhysAddr::new(0x4001_0000)).unwrap();
+        let mapper = EncryptedPageTable {
+            encryption: MemoryEncryption::Encrypted(51),
+            offset: VirtAddr::new(0x1234000),
+            inner: Spinlock::new(FakeMapper {
+                expected_phys_frame: PhysFrame::from_start_address(PhysAddr::new(
+                    0x4000_0000 + (1u64 << 51),
+                ))
+                .unwrap(),
+            }),
+            translations: TranslationCache::new(),
+        };
+
+        unsafe {
+            mapper
+                .map_to_with_table_flags(
+                    Page::<Size4KiB>::from_start_address(VirtAddr::new(0x1000)).unwrap(),
+                    PhysFrame::from_start_address(PhysAddr::new(0x4000_0000)).unwrap(),
+                    PageTableFlags::PRESENT | PageTableFlags::ENCRYPTED,
+                    PageTableFlags::empty(),
+                )
+                .unwrap()
+                .ignore();
+        }
+    }
 }
@@ -0,0 +1,152 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Fake page tables and frame allocators for testing memory management code.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
+        FrameAllocator, FrameDeallocator, Page, PageSize, PhysFrame, Size2MiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{Mapper, PageTableFlags, Translator};
+
+/// Page table that keeps track of 2 MiB mappings in a map, with "physical"
+/// memory backed by a buffer.
+pub struct FakePageTable {
+    mappings: Spinlock<BTreeMap<Page<Size2MiB>, (PhysFrame<Size2MiB>, PageTableFlags)>>,
+    // Allocated with an extra frame so that we can align the "physical" memory to 2 MiB.
+    _buffer: Vec<u8>,
+    memory: VirtAddr,
+    fail_next_map: AtomicBool,
+}
+
+impl FakePageTable {
+    pub fn new(frames: usize) -> Self {
+        let buffer = vec![0; (frames + 1) * Size2MiB::SIZE as usize];
+        let memory = VirtAddr::from_ptr(buffer.as_ptr()).align_up(Size2MiB::SIZE);
+        Self {
+            mappings: Spinlock::new(BTreeMap::new()),
+            _buffer: buffer,
+            memory,
+            fail_next_map: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes the next call to `map_to_with_table_flags` fail.
+    pub fn fail_next_map(&self) {
+        self.fail_next_map.store(true, Ordering::Relaxed);
+    }
+
+    pub fn frame_contents(&mut self, frame: PhysFrame<Size2MiB>) -> &mut [u8] {
+        let start = self.translate_physical(frame.start_address()).unwrap();
+        // Safety: the frame is within the buffer, which we borrow mutably.
+        unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), Size2MiB::SIZE as usize) }
+    }
+
+    pub fn mapping(&self, page: Page<Size2MiB>) -> (PhysFrame<Size2MiB>, PageTableFlags) {
+        *self.mappings.lock().get(&page).unwrap()
+    }
+}
+
+impl Mapper<Size2MiB> for FakePageTable {
+    unsafe fn map_to_with_table_flags(
+        &self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        _parent_table_flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError<Size2MiB>> {
+        if self.fail_next_map.swap(false, Ordering::Relaxed) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let mut mappings = self.mappings.lock();
+        if mappings.contains_key(&page) {
+            return Err(MapToError::PageAlreadyMapped(frame));
+        }
+        mappings.insert(page, (frame, flags));
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn unmap(
+        &self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        let (frame, _) = self.mappings.lock().remove(&page).ok_or(UnmapError::PageNotMapped)?;
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    unsafe fn update_flags(
+        &self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        let mut mappings = self.mappings.lock();
+        let entry = mappings.get_mut(&page).ok_or(FlagUpdateError::PageNotMapped)?;
+        entry.1 = flags;
+        Ok(MapperFlush::new(page))
+    }
+}
+
+impl Translator for FakePageTable {
+    fn translate_virtual(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let page = Page::<Size2MiB>::containing_address(addr);
+        let (frame, _) = *self.mappings.lock().get(&page)?;
+        Some(frame.start_address() + (addr - page.start_address()))
+    }
+
+    fn translate_physical(&self, addr: PhysAddr) -> Option<VirtAddr> {
+        Some(self.memory + addr.as_u64())
+    }
+
+    fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>> {
+        Page::from_start_address(self.translate_physical(frame.start_address())?).ok()
+    }
+
+    fn is_encrypted(&self, addr: VirtAddr) -> Option<bool> {
+        Some(self.flags(addr)?.contains(PageTableFlags::ENCRYPTED))
+    }
+
+    fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        let (_, flags) = *self.mappings.lock().get(&Page::containing_address(addr))?;
+        Some(flags)
+    }
+}
+
+/// Hands out the frames in the given order.
+pub struct FakeFrameAllocator(pub Vec<PhysFrame<Size2MiB>>);
+
+unsafe impl FrameAllocator<Size2MiB> for FakeFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.0.pop()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for FakeFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.0.push(frame);
+    }
+}
+
+pub fn frame(index: u64) -> PhysFrame<Size2MiB> {
+    PhysFrame::from_start_address(PhysAddr::new(index * Size2MiB::SIZE)).unwrap()
+}
@@ -24,6 +24,9 @@ use x86_64::{
 
 use super::bitmap_frame_allocator::BitmapAllocator;
 
+/// Upper bound of the memory that devices limited to 32-bit DMA can address.
+pub const DMA32_LIMIT: PhysAddr = PhysAddr::new_truncate(1 << 32);
+
 /// Allocator to track physical memory frames.
 ///
 /// The basic unit we track is a 2 MiB frame. If necessary, we will take one 2
@@ -70,6 +73,24 @@ impl<const N: usize> PhysicalMemoryAllocator<N> {
         self.large_frames.mark_valid(range, valid)
     }
 
+    /// Permanently removes a range of frames from the pool of frames that can
+    /// be allocated, e.g. because it was found to be in use by firmware
+    /// after boot.
+    ///
+    /// Returns an error if any of the frames has already been allocated.
+    pub fn reserve(&mut self, range: PhysFrameRange<Size2MiB>) -> Result<(), &'static str> {
+        self.large_frames.reserve(range)
+    }
+
+    /// Adds a range of memory that was not present at boot, e.g. because it
+    /// was hotplugged, to the pool of frames that can be allocated.
+    ///
+    /// Returns an error if the range is beyond the capacity of the allocator,
+    /// or if it overlaps memory that is already present or reserved.
+    pub fn add_range(&mut self, range: PhysFrameRange<Size2MiB>) -> Result<(), &'static str> {
+        self.large_frames.add_range(range)
+    }
+
     pub fn largest_available(&mut self) -> Option<PhysFrameRange<Size2MiB>> {
         self.large_frames.largest_available()
     }
@@ -82,6 +103,20 @@ impl<const N: usize> PhysicalMemoryAllocator<N> {
         self.large_frames.allocate_contiguous(num)
     }
 
+    /// Allocate `num` contiguous 2 MiB pages that lie entirely below the
+    /// physical address `limit`.
+    ///
+    /// This is for memory that is handed to devices that can only address part
+    /// of physical memory, e.g. [`DMA32_LIMIT`] for devices that only support
+    /// 32-bit DMA.
+    pub fn allocate_below(
+        &mut self,
+        num: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange<Size2MiB>> {
+        self.large_frames.allocate_contiguous_below(num, limit)
+    }
+
     /// Returns the number of valid 2 MiB and 4 KiB frames.
     pub fn num_valid_frames(&self) -> (usize, usize) {
         (
@@ -177,4 +212,57 @@ mod tests {
         // and the next one shouldn't, as we've filled the page.
         assert_eq!(None, alloc_ref.allocate_frame());
     }
+
+    #[test]
+    fn reserved_frames_are_not_allocated() {
+        let mut allocator =
+            PhysicalMemoryAllocator::<1>::new_range(create_frame_range(0, 8 * Size2MiB::SIZE));
+        allocator.mark_valid(create_frame_range(0, 8 * Size2MiB::SIZE), true);
+        allocator.reserve(create_frame_range(2 * Size2MiB::SIZE, 4 * Size2MiB::SIZE)).unwrap();
+        assert_eq!(allocator.num_valid_frames(), (6, 0));
+
+        // Only 4 contiguous frames remain after the reserved range.
+        assert_eq!(None, allocator.allocate_contiguous(5));
+        let reserved = create_frame_range(2 * Size2MiB::SIZE, 4 * Size2MiB::SIZE);
+        while let Some(range) = allocator.allocate_contiguous(1) {
+            assert!(range.start < reserved.start || range.start >= reserved.end);
+        }
+        assert_eq!(allocator.num_allocated_frames(), (6, 0));
+    }
+
+    #[test]
+    fn reserve_allocated_frames() {
+        let mut allocator =
+            PhysicalMemoryAllocator::<1>::new_range(create_frame_range(0, 4 * Size2MiB::SIZE));
+        allocator.mark_valid(create_frame_range(0, 4 * Size2MiB::SIZE), true);
+        let allocated = allocator.allocate_contiguous(2).unwrap();
+
+        assert!(allocator.reserve(create_frame_range(Size2MiB::SIZE, 3 * Size2MiB::SIZE)).is_err());
+        assert_eq!(allocator.num_valid_frames(), (4, 0));
+        // Frames outside the allocator's range or that are already invalid are fine.
+        allocator.reserve(create_frame_range(3 * Size2MiB::SIZE, 6 * Size2MiB::SIZE)).unwrap();
+        allocator.reserve(create_frame_range(3 * Size2MiB::SIZE, 4 * Size2MiB::SIZE)).unwrap();
+        assert_eq!(allocator.num_valid_frames(), (3, 0));
+        assert_eq!(allocated, create_frame_range(0, 2 * Size2MiB::SIZE));
+    }
+
+    #[test]
+    fn allocate_below_dma32_limit() {
+        let limit = DMA32_LIMIT.as_u64();
+        let free = create_frame_range(limit - 4 * Size2MiB::SIZE, limit + 4 * Size2MiB::SIZE);
+        let mut allocator = PhysicalMemoryAllocator::<1>::new_range(free);
+        allocator.mark_valid(free, true);
+
+        // Only 4 of the free frames are below the limit.
+        assert_eq!(None, allocator.allocate_below(5, DMA32_LIMIT));
+        let frames = allocator.allocate_below(3, DMA32_LIMIT).unwrap();
+        assert_eq!(frames.count(), 3);
+        assert!(frames.end.start_address() <= DMA32_LIMIT);
+        let frames = allocator.allocate_below(1, DMA32_LIMIT).unwrap();
+        assert!(frames.end.start_address() <= DMA32_LIMIT);
+        assert_eq!(None, allocator.allocate_below(1, DMA32_LIMIT));
+
+        // The frames above the limit are still there for everyone else.
+        assert_eq!(allocator.allocate_contiguous(4).unwrap().start.start_address(), DMA32_LIMIT);
+    }
 }
@@ -0,0 +1,284 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Support for memory that becomes available after boot.
+//!
+//! When the VMM hotplugs memory it notifies the guest (via ACPI) about the new
+//! physical address range. The range is added to the frame allocator, which
+//! checks that it is within the allocator's capacity and that it doesn't
+//! overlap memory that is already present or that was reserved during boot.
+//! Ranges that the memory map excludes, or that hold the ramdisk, are refused
+//! as well.
+//!
+//! Memory that was plugged in before boot is described by ACPI memory devices
+//! rather than the memory map; we add it with [`add_memory_devices`] once the
+//! ACPI namespace has been parsed. Notifications about memory plugged in at
+//! runtime need SCI/GPE handling, which we don't implement yet.
+//!
+//! Under SEV-SNP hotplugged memory would first have to be validated with
+//! `PVALIDATE`, which we don't support; we refuse such events instead.
+
+use core::ops::Range;
+
+use oak_linux_boot_params::{BootE820Entry, Ramdisk};
+use oak_sev_guest::msr::{get_sev_status, SevStatus};
+use x86_64::{
+    addr::{align_down, align_up},
+    structures::paging::{frame::PhysFrameRange, PageSize, PhysFrame, Size2MiB},
+    PhysAddr,
+};
+
+use super::{
+    classify_e820_entry, frame_allocator::PhysicalMemoryAllocator, ramdisk_range, MemoryClass,
+};
+use crate::{
+    acpi::{DeviceInfo, MEMORY_DEVICE},
+    FRAME_ALLOCATOR,
+};
+
+/// Memory hotplug event, as reported by the platform.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HotplugEvent {
+    /// A new range of physical memory is available for use.
+    Add(Range<PhysAddr>),
+}
+
+/// Converts an arbitrary physical address range into the 2 MiB frames that
+/// are fully contained within it.
+fn frames_within(range: &Range<PhysAddr>) -> Result<PhysFrameRange<Size2MiB>, &'static str> {
+    let start = align_up(range.start.as_u64(), Size2MiB::SIZE);
+    let end = align_down(range.end.as_u64(), Size2MiB::SIZE);
+    if start >= end {
+        return Err("hotplugged range doesn't contain a full 2 MiB frame");
+    }
+    Ok(PhysFrame::range(
+        PhysFrame::from_start_address(PhysAddr::new(start)).unwrap(),
+        PhysFrame::from_start_address(PhysAddr::new(end)).unwrap(),
+    ))
+}
+
+/// Checks that `frames` don't overlap memory that the frame allocator must
+/// never hand out: memory excluded by the memory map (see
+/// [`classify_e820_entry`]) and the ramdisk.
+///
+/// The other ranges reserved by `mm::init` are tracked by the frame allocator
+/// itself.
+fn check_excluded(
+    frames: PhysFrameRange<Size2MiB>,
+    memory_map: &[BootE820Entry],
+    ramdisk: Option<&Ramdisk>,
+) -> Result<(), &'static str> {
+    let (start, end) = (frames.start.start_address().as_u64(), frames.end.start_address().as_u64());
+    let overlaps = |other_start: u64, other_end: u64| other_start < end && start < other_end;
+    if memory_map.iter().any(|entry| {
+        classify_e820_entry(entry) == MemoryClass::Excluded
+            && overlaps(entry.addr() as u64, entry.end() as u64)
+    }) {
+        return Err("hotplugged range overlaps memory excluded by the memory map");
+    }
+    if let Some(ramdisk) = ramdisk {
+        let ramdisk = ramdisk_range(ramdisk);
+        if overlaps(ramdisk.start.start_address().as_u64(), ramdisk.end.start_address().as_u64()) {
+            return Err("hotplugged range overlaps the ramdisk");
+        }
+    }
+    Ok(())
+}
+
+/// Applies a hotplug event to `frame_allocator`.
+///
+/// Returns the frames that were added to the allocator.
+pub fn handle_event<const N: usize>(
+    frame_allocator: &mut PhysicalMemoryAllocator<N>,
+    event: &HotplugEvent,
+    memory_map: &[BootE820Entry],
+    ramdisk: Option<&Ramdisk>,
+    snp_active: bool,
+) -> Result<PhysFrameRange<Size2MiB>, &'static str> {
+    match event {
+        HotplugEvent::Add(range) => {
+            if snp_active {
+                return Err("memory hotplug is not supported under SEV-SNP");
+            }
+            let frames = frames_within(range)?;
+            check_excluded(frames, memory_map, ramdisk)?;
+            frame_allocator.add_range(frames)?;
+            Ok(frames)
+        }
+    }
+}
+
+/// Applies a hotplug event to the global frame allocator.
+pub fn hotplug(
+    event: &HotplugEvent,
+    memory_map: &[BootE820Entry],
+    ramdisk: Option<&Ramdisk>,
+) -> Result<PhysFrameRange<Size2MiB>, &'static str> {
+    let snp_active = get_sev_status().unwrap_or(SevStatus::empty()).contains(SevStatus::SNP_ACTIVE);
+    let frames = handle_event(&mut FRAME_ALLOCATOR.lock(), event, memory_map, ramdisk, snp_active)?;
+    log::info!(
+        "hotplugged memory: [{:#018x}..{:#018x})",
+        frames.start.start_address().as_u64(),
+        frames.end.start_address().as_u64()
+    );
+    Ok(frames)
+}
+
+/// Returns the hotplug event for the memory described by an ACPI memory device.
+fn memory_device_event(device: &DeviceInfo) -> Option<Result<HotplugEvent, &'static str>> {
+    if device.hid.as_deref() != Some(MEMORY_DEVICE) {
+        return None;
+    }
+    let (base, length) = device.memory_range()?;
+    Some(
+        base.checked_add(length)
+            .and_then(|end| PhysAddr::try_new(end).ok())
+            .map(|end| HotplugEvent::Add(PhysAddr::new(base)..end))
+            .ok_or("memory device range is outside of physical memory"),
+    )
+}
+
+/// Adds the memory described by ACPI memory devices to the global frame
+/// allocator.
+///
+/// Memory that can't be added is logged and skipped.
+pub fn add_memory_devices(
+    devices: &[DeviceInfo],
+    memory_map: &[BootE820Entry],
+    ramdisk: Option<&Ramdisk>,
+) {
+    for (device, event) in
+        devices.iter().filter_map(|device| Some((device, memory_device_event(device)?)))
+    {
+        if let Err(err) = event.and_then(|event| hotplug(&event, memory_map, ramdisk)) {
+            log::warn!("Ignoring memory device {}: {}", device.name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use oak_linux_boot_params::E820EntryType;
+
+    use super::*;
+    use crate::acpi::DeviceResource;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn create_allocator() -> PhysicalMemoryAllocator<1> {
+        // 64 frames of 2 MiB, of which only the first 16 are present at boot, and the
+        // first one is reserved.
+        let mut alloc = PhysicalMemoryAllocator::<1>::new();
+        let present = frames_within(&(PhysAddr::new(0)..PhysAddr::new(32 * MIB))).unwrap();
+        alloc.mark_valid(present, true);
+        alloc.reserve(frames_within(&(PhysAddr::new(0)..PhysAddr::new(2 * MIB))).unwrap()).unwrap();
+        alloc
+    }
+
+    fn add(start: u64, end: u64) -> HotplugEvent {
+        HotplugEvent::Add(PhysAddr::new(start)..PhysAddr::new(end))
+    }
+
+    #[test]
+    fn hotplug_increases_free_frames() {
+        let mut alloc = create_allocator();
+        let (before, _) = alloc.num_valid_frames();
+
+        // The range isn't 2 MiB aligned; only the 4 full frames are added.
+        let frames =
+            handle_event(&mut alloc, &add(64 * MIB - 4096, 72 * MIB + 4096), &[], None, false)
+                .unwrap();
+
+        assert_eq!(
+            frames,
+            frames_within(&(PhysAddr::new(64 * MIB)..PhysAddr::new(72 * MIB))).unwrap()
+        );
+        let (after, _) = alloc.num_valid_frames();
+        assert_eq!(after, before + 4);
+    }
+
+    #[test]
+    fn hotplug_rejects_present_memory() {
+        let mut alloc = create_allocator();
+        assert!(handle_event(&mut alloc, &add(16 * MIB, 48 * MIB), &[], None, false).is_err());
+    }
+
+    #[test]
+    fn hotplug_rejects_reserved_memory() {
+        let mut alloc = create_allocator();
+        alloc.mark_valid(
+            frames_within(&(PhysAddr::new(0)..PhysAddr::new(32 * MIB))).unwrap(),
+            false,
+        );
+        assert!(handle_event(&mut alloc, &add(0, 4 * MIB), &[], None, false).is_err());
+        // Memory that isn't reserved can be added back.
+        assert!(handle_event(&mut alloc, &add(2 * MIB, 4 * MIB), &[], None, false).is_ok());
+    }
+
+    #[test]
+    fn hotplug_rejects_memory_beyond_capacity() {
+        let mut alloc = create_allocator();
+        assert!(handle_event(&mut alloc, &add(126 * MIB, 130 * MIB), &[], None, false).is_err());
+        assert!(handle_event(&mut alloc, &add(256 * MIB, 260 * MIB), &[], None, false).is_err());
+    }
+
+    #[test]
+    fn hotplug_rejects_partial_frames() {
+        let mut alloc = create_allocator();
+        assert!(handle_event(&mut alloc, &add(65 * MIB, 67 * MIB), &[], None, false).is_err());
+    }
+
+    #[test]
+    fn hotplug_rejects_excluded_memory() {
+        let mut alloc = create_allocator();
+        let memory_map = [
+            BootE820Entry::new(64 * MIB as usize, 8 * MIB as usize, E820EntryType::RAM),
+            BootE820Entry::new(72 * MIB as usize, 4096, E820EntryType::RESERVED),
+        ];
+        let ramdisk = Some(&Ramdisk { addr: (81 * MIB) as u32, size: 4096 });
+
+        // Overlaps the reserved entry.
+        assert!(handle_event(&mut alloc, &add(70 * MIB, 74 * MIB), &memory_map, ramdisk, false)
+            .is_err());
+        // Overlaps the frame containing the ramdisk.
+        assert!(handle_event(&mut alloc, &add(80 * MIB, 84 * MIB), &memory_map, ramdisk, false)
+            .is_err());
+        assert!(
+            handle_event(&mut alloc, &add(64 * MIB, 72 * MIB), &memory_map, ramdisk, false).is_ok()
+        );
+    }
+
+    #[test]
+    fn memory_devices_become_events() {
+        let device = |hid: &str| DeviceInfo {
+            name: "MP00".into(),
+            hid: Some(hid.into()),
+            kind: Default::default(),
+            resources: vec![DeviceResource::MemoryRange { base: 64 * MIB, length: 8 * MIB }],
+        };
+
+        assert_eq!(memory_device_event(&device(MEMORY_DEVICE)), Some(Ok(add(64 * MIB, 72 * MIB))));
+        assert_eq!(memory_device_event(&device("LNRO0005")), None);
+    }
+
+    #[test]
+    fn hotplug_rejected_under_snp() {
+        let mut alloc = create_allocator();
+        assert!(handle_event(&mut alloc, &add(64 * MIB, 72 * MIB), &[], None, true).is_err());
+    }
+}
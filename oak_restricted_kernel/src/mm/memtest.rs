@@ -0,0 +1,367 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Boot-time self-test of the memory subsystem.
+//!
+//! The unit tests can't catch problems that only show up on a particular VMM
+//! or on real hardware. With the `memtest` kernel argument, the kernel
+//! exercises the frame allocator, the page tables, the guest-host heap and the
+//! encryption bit right after they have been set up, and refuses to boot if
+//! any of them misbehave.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PhysFrame, Size2MiB},
+    VirtAddr,
+};
+
+use super::{
+    encrypted_mapper::MemoryEncryption, encryption, with_frame_allocator, with_page_tables, Mapper,
+    PageTableFlags, Translator,
+};
+use crate::{GUEST_HOST_HEAP, VMA_ALLOCATOR};
+
+/// Kernel argument that runs the memory self-test at boot.
+pub const MEMTEST_ARG: &str = "memtest";
+
+/// Number of frames to allocate in the frame allocation sub-test.
+const TEST_FRAMES: usize = 4;
+
+/// Size of the buffer in the guest-host heap that the patterns are written to.
+const PATTERN_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Fixed patterns written to memory; an address-dependent one, which catches
+/// aliasing, is tested in addition.
+const PATTERNS: [u8; 4] = [0x00, 0xFF, 0xAA, 0x55];
+
+type Outcome = Result<(), &'static str>;
+
+/// Allocates `count` frames, checks that they are all different and frees
+/// them again. One more frame is allocated afterwards to check that freed
+/// frames are usable.
+fn check_frame_allocation<A>(allocator: &mut A, count: usize) -> Outcome
+where
+    A: FrameAllocator<Size2MiB> + FrameDeallocator<Size2MiB>,
+{
+    let mut frames: Vec<PhysFrame<Size2MiB>> = Vec::with_capacity(count);
+    let mut result = Ok(());
+    for _ in 0..count {
+        match allocator.allocate_frame() {
+            Some(frame) if frames.contains(&frame) => {
+                result = Err("the same frame was allocated twice");
+                break;
+            }
+            Some(frame) => frames.push(frame),
+            None => {
+                result = Err("couldn't allocate a frame");
+                break;
+            }
+        }
+    }
+    for frame in frames {
+        // Safety: we allocated the frame above and never used it.
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    result?;
+
+    let frame = allocator.allocate_frame().ok_or("couldn't allocate a frame after freeing some")?;
+    // Safety: as above.
+    unsafe { allocator.deallocate_frame(frame) };
+    Ok(())
+}
+
+/// Maps `frame` at `page`, checks the translation and the encrypted bit of the
+/// mapping, and unmaps it again.
+///
+/// # Safety
+///
+/// `page` must not be in use, and `frame` must not be used by anything else.
+unsafe fn check_mapping<M: Mapper<Size2MiB> + Translator>(
+    mapper: &M,
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    encrypted: bool,
+) -> Outcome {
+    mapper
+        .map_to_with_table_flags(
+            page,
+            frame,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_EXECUTE
+                | PageTableFlags::ENCRYPTED,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::ENCRYPTED,
+        )
+        .map_err(|_| "couldn't map the page")?
+        .flush();
+
+    // An offset within the page must translate to the same offset in the frame.
+    let offset = 0x1234;
+    let translated = mapper.translate_virtual(page.start_address() + offset);
+    let encryption_matches = mapper.is_encrypted(page.start_address()) == Some(encrypted);
+
+    let (unmapped, flush) = mapper.unmap(page).map_err(|_| "couldn't unmap the page")?;
+    flush.flush();
+
+    if translated != Some(frame.start_address() + offset) {
+        return Err("the page doesn't translate to the frame it was mapped to");
+    }
+    if !encryption_matches {
+        return Err("the page is mapped with the wrong encrypted bit");
+    }
+    if unmapped != frame {
+        return Err("unmapping the page returned the wrong frame");
+    }
+    if mapper.translate_virtual(page.start_address()).is_some() {
+        return Err("the page still translates after unmapping it");
+    }
+    Ok(())
+}
+
+/// Returns the index of the first byte of `buffer` that differs from
+/// `expected(index)`, if any.
+fn first_mismatch(buffer: &[u8], expected: impl Fn(usize) -> u8) -> Option<usize> {
+    (0..buffer.len()).find(|&index| {
+        // Safety: the index is within the buffer.
+        let value = unsafe { buffer.as_ptr().add(index).read_volatile() };
+        value != expected(index)
+    })
+}
+
+/// Fills `buffer` with `pattern(index)` and checks that it reads back the same.
+fn check_pattern(buffer: &mut [u8], pattern: impl Fn(usize) -> u8) -> Outcome {
+    for index in 0..buffer.len() {
+        // Safety: the index is within the buffer.
+        unsafe { buffer.as_mut_ptr().add(index).write_volatile(pattern(index)) };
+    }
+    match first_mismatch(buffer, pattern) {
+        Some(_) => Err("memory didn't retain the pattern written to it"),
+        None => Ok(()),
+    }
+}
+
+/// Writes the test patterns to `buffer` and reads them back.
+fn check_patterns(buffer: &mut [u8]) -> Outcome {
+    for pattern in PATTERNS {
+        check_pattern(buffer, |_| pattern)?;
+    }
+    check_pattern(buffer, |index| (index ^ (index >> 8)) as u8)
+}
+
+/// Checks that `addr` is mapped with the encrypted bit set if, and only if,
+/// `encrypted` is true.
+fn check_encryption<T: Translator>(translator: &T, addr: VirtAddr, encrypted: bool) -> Outcome {
+    match translator.is_encrypted(addr) {
+        Some(actual) if actual == encrypted => Ok(()),
+        Some(true) => Err("guest-host page mapped with the encryption bit set"),
+        Some(false) => Err("private page mapped without the encryption bit set"),
+        None => Err("memory is not mapped"),
+    }
+}
+
+fn frame_allocation() -> Outcome {
+    with_frame_allocator(|frame_allocator| check_frame_allocation(frame_allocator, TEST_FRAMES))
+}
+
+fn page_mapping(encrypted: bool) -> Outcome {
+    // There's no way to give the virtual memory back, but a single page is a small
+    // price to pay.
+    let page = VMA_ALLOCATOR.lock().allocate(1).ok_or("couldn't allocate a page")?.start;
+    let frame: PhysFrame<Size2MiB> = with_frame_allocator(|frame_allocator| {
+        frame_allocator.allocate_frame().ok_or("couldn't allocate a frame")
+    })?;
+    // Safety: the page and the frame were just allocated for this test.
+    let result = with_page_tables(|pt| unsafe { check_mapping(pt, page, frame, encrypted) });
+    // Safety: the frame is no longer mapped.
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+    result
+}
+
+fn guest_host_patterns() -> Outcome {
+    let heap = GUEST_HOST_HEAP.get().ok_or("guest-host heap not initialized")?;
+    let mut buffer = Vec::with_capacity_in(PATTERN_BUFFER_SIZE, heap);
+    buffer.resize(PATTERN_BUFFER_SIZE, 0u8);
+    check_patterns(&mut buffer)
+}
+
+fn encryption_bit(encrypted: bool) -> Outcome {
+    let heap = GUEST_HOST_HEAP.get().ok_or("guest-host heap not initialized")?;
+    let private = Box::new(0u64);
+    let shared = Box::new_in(0u64, heap);
+    with_page_tables(|pt| {
+        check_encryption(pt, VirtAddr::from_ptr(&*private), encrypted)?;
+        check_encryption(pt, VirtAddr::from_ptr(&*shared), false)
+    })
+}
+
+/// Logs the outcome of each sub-test and returns the number of failures.
+fn report(results: &[(&str, Outcome)]) -> usize {
+    results
+        .iter()
+        .filter(|(name, outcome)| match outcome {
+            Ok(()) => {
+                log::info!("memtest: {}: pass", name);
+                false
+            }
+            Err(err) => {
+                log::error!("memtest: {}: FAIL: {}", name, err);
+                true
+            }
+        })
+        .count()
+}
+
+/// Runs the memory self-test.
+///
+/// This must only be called once the page tables, the kernel heap and the
+/// guest-host heap have been set up. Panics if any sub-test fails, so that the
+/// panic reporter can signal the failure.
+pub fn run() {
+    let encrypted = matches!(encryption(), MemoryEncryption::Encrypted(_));
+    let results = [
+        ("frame allocation", frame_allocation()),
+        ("page mapping", page_mapping(encrypted)),
+        ("guest-host heap patterns", guest_host_patterns()),
+        ("encryption bit", encryption_bit(encrypted)),
+    ];
+    let failures = report(&results);
+    if failures > 0 {
+        panic!("memtest: {} of {} sub-tests failed", failures, results.len());
+    }
+    log::info!("memtest: all {} sub-tests passed", results.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::mm::fakes::{frame, FakeFrameAllocator, FakePageTable};
+
+    /// Hands out the same frame over and over again.
+    struct BrokenFrameAllocator;
+
+    unsafe impl FrameAllocator<Size2MiB> for BrokenFrameAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+            Some(frame(1))
+        }
+    }
+
+    impl FrameDeallocator<Size2MiB> for BrokenFrameAllocator {
+        unsafe fn deallocate_frame(&mut self, _frame: PhysFrame<Size2MiB>) {}
+    }
+
+    #[test]
+    fn frame_allocation_passes() {
+        let mut allocator = FakeFrameAllocator((0..TEST_FRAMES as u64).map(frame).collect());
+        assert_eq!(check_frame_allocation(&mut allocator, TEST_FRAMES), Ok(()));
+        // All the frames were freed.
+        assert_eq!(allocator.0.len(), TEST_FRAMES);
+    }
+
+    #[test]
+    fn frame_allocation_fails() {
+        let mut allocator = FakeFrameAllocator(vec![frame(1)]);
+        assert!(check_frame_allocation(&mut allocator, TEST_FRAMES).is_err());
+        assert_eq!(allocator.0, vec![frame(1)]);
+        assert!(check_frame_allocation(&mut BrokenFrameAllocator, TEST_FRAMES).is_err());
+    }
+
+    #[test]
+    fn mapping_passes() {
+        let page_table = FakePageTable::new(4);
+        let page = Page::containing_address(VirtAddr::new(0x8000_0000));
+        // The fake page table reports the `ENCRYPTED` flag as the encrypted bit.
+        assert_eq!(unsafe { check_mapping(&page_table, page, frame(2), true) }, Ok(()));
+    }
+
+    #[test]
+    fn mapping_fails() {
+        let page_table = FakePageTable::new(4);
+        let page = Page::containing_address(VirtAddr::new(0x8000_0000));
+        assert!(unsafe { check_mapping(&page_table, page, frame(2), false) }.is_err());
+        // The page was unmapped even though the check failed.
+        assert_eq!(page_table.translate_virtual(page.start_address()), None);
+
+        // A page that is already in use can't be mapped.
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    page,
+                    frame(3),
+                    PageTableFlags::PRESENT,
+                    PageTableFlags::PRESENT,
+                )
+                .unwrap()
+                .ignore();
+        }
+        assert!(unsafe { check_mapping(&page_table, page, frame(2), true) }.is_err());
+    }
+
+    #[test]
+    fn patterns_pass() {
+        let mut buffer = vec![0u8; 4096];
+        assert_eq!(check_patterns(&mut buffer), Ok(()));
+    }
+
+    #[test]
+    fn corrupted_pattern_is_detected() {
+        let mut buffer = vec![0xAAu8; 4096];
+        assert_eq!(first_mismatch(&buffer, |_| 0xAA), None);
+        buffer[1000] = 0xAB;
+        assert_eq!(first_mismatch(&buffer, |_| 0xAA), Some(1000));
+    }
+
+    #[test]
+    fn encryption_check() {
+        let page_table = FakePageTable::new(4);
+        let private = Page::containing_address(VirtAddr::new(0x8000_0000));
+        let shared = private + 1;
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    private,
+                    frame(1),
+                    PageTableFlags::PRESENT | PageTableFlags::ENCRYPTED,
+                    PageTableFlags::PRESENT,
+                )
+                .unwrap()
+                .ignore();
+            page_table
+                .map_to_with_table_flags(
+                    shared,
+                    frame(2),
+                    PageTableFlags::PRESENT,
+                    PageTableFlags::PRESENT,
+                )
+                .unwrap()
+                .ignore();
+        }
+
+        assert_eq!(check_encryption(&page_table, private.start_address(), true), Ok(()));
+        assert_eq!(check_encryption(&page_table, shared.start_address(), false), Ok(()));
+        assert!(check_encryption(&page_table, private.start_address(), false).is_err());
+        assert!(check_encryption(&page_table, shared.start_address(), true).is_err());
+        assert!(check_encryption(&page_table, (shared + 1).start_address(), false).is_err());
+    }
+
+    #[test]
+    fn failures_are_counted() {
+        assert_eq!(report(&[("a", Ok(())), ("b", Ok(()))]), 0);
+        assert_eq!(report(&[("a", Err("broken")), ("b", Ok(())), ("c", Err("broken"))]), 2);
+    }
+}
@@ -0,0 +1,252 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Locking of user memory, the equivalent of `mlock()`.
+//!
+//! Locking a range guarantees that every page in it is backed by a private
+//! frame: copy-on-write pages are copied right away instead of on the first
+//! write, so that code touching locked memory never takes a page fault. Locked
+//! pages are marked with `LOCKED` so that they are exempt from any future
+//! reclaiming of memory.
+
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::{
+    page::PageRange, FrameAllocator, FrameDeallocator, Page, Size2MiB,
+};
+
+use super::{cow::resolve_fault, Mapper, PageTableFlags, Translator};
+
+/// Checks that all pages in `pages` are mapped, so that we don't leave a range
+/// partially locked or unlocked.
+fn check_mapped<M: Translator>(mapper: &M, pages: PageRange<Size2MiB>) -> Result<(), &'static str> {
+    for page in pages {
+        mapper.flags(page.start_address()).ok_or("page is not mapped")?;
+    }
+    Ok(())
+}
+
+/// Pre-faults and locks `page`, returning whether it wasn't locked before.
+///
+/// # Safety
+///
+/// See [`lock_pages`].
+unsafe fn lock_page<
+    M: Mapper<Size2MiB> + Translator,
+    A: FrameAllocator<Size2MiB> + FrameDeallocator<Size2MiB>,
+>(
+    mapper: &M,
+    frame_allocator: &mut A,
+    page: Page<Size2MiB>,
+) -> Result<bool, &'static str> {
+    if let Some(flush) = resolve_fault(mapper, frame_allocator, page.start_address())? {
+        flush.ignore();
+    }
+    let flags = mapper.flags(page.start_address()).ok_or("page is not mapped")?;
+    if flags.contains(PageTableFlags::LOCKED) {
+        return Ok(false);
+    }
+    mapper
+        .update_flags(page, flags | PageTableFlags::LOCKED)
+        .map_err(|_| "couldn't update page table flags")?
+        .ignore();
+    Ok(true)
+}
+
+/// Pre-faults and locks all pages in `pages`.
+///
+/// If a page can't be locked (e.g. because we ran out of frames for the
+/// private copies), the pages this call locked are unlocked again, so that the
+/// range is either locked in full or not locked by this call at all. Private
+/// copies that were already made stay in place; user code can't tell them
+/// apart from the shared frames they replaced.
+///
+/// The TLB is not flushed; the caller has to flush it before returning to
+/// user space.
+///
+/// # Safety
+///
+/// `mapper` must be the active page table, and the physical frames must be
+/// accessible through its direct mapping.
+pub unsafe fn lock_pages<
+    M: Mapper<Size2MiB> + Translator,
+    A: FrameAllocator<Size2MiB> + FrameDeallocator<Size2MiB>,
+>(
+    mapper: &M,
+    frame_allocator: &mut A,
+    pages: PageRange<Size2MiB>,
+) -> Result<(), &'static str> {
+    check_mapped(mapper, pages)?;
+    // Reserve the bookkeeping up front, so that we can always unwind.
+    let mut locked = Vec::new();
+    locked
+        .try_reserve_exact(pages.count())
+        .map_err(|_| "couldn't allocate memory to track locked pages")?;
+    for page in pages {
+        match lock_page(mapper, frame_allocator, page) {
+            Ok(true) => locked.push(page),
+            Ok(false) => {}
+            Err(err) => {
+                for page in locked {
+                    // The page was mapped a moment ago, so this can't fail.
+                    let _ = unlock_page(mapper, page);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unlocks `page`.
+///
+/// # Safety
+///
+/// See [`unlock_pages`].
+unsafe fn unlock_page<M: Mapper<Size2MiB> + Translator>(
+    mapper: &M,
+    page: Page<Size2MiB>,
+) -> Result<(), &'static str> {
+    let flags = mapper.flags(page.start_address()).ok_or("page is not mapped")?;
+    mapper
+        .update_flags(page, flags - PageTableFlags::LOCKED)
+        .map_err(|_| "couldn't update page table flags")?
+        .ignore();
+    Ok(())
+}
+
+/// Unlocks all pages in `pages`.
+///
+/// The pages stay mapped to their private frames.
+///
+/// # Safety
+///
+/// `mapper` must be the active page table.
+pub unsafe fn unlock_pages<M: Mapper<Size2MiB> + Translator>(
+    mapper: &M,
+    pages: PageRange<Size2MiB>,
+) -> Result<(), &'static str> {
+    check_mapped(mapper, pages)?;
+    for page in pages {
+        unlock_page(mapper, page)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use x86_64::{
+        structures::paging::{Page, PageSize},
+        VirtAddr,
+    };
+
+    use super::*;
+    use crate::mm::fakes::{frame, FakeFrameAllocator, FakePageTable};
+
+    const USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::USER_ACCESSIBLE)
+        .union(PageTableFlags::NO_EXECUTE);
+
+    fn page(index: u64) -> Page<Size2MiB> {
+        Page::from_start_address(VirtAddr::new(0x4000_0000 + index * Size2MiB::SIZE)).unwrap()
+    }
+
+    fn map(page_table: &FakePageTable, index: u64, flags: PageTableFlags) {
+        unsafe {
+            page_table
+                .map_to_with_table_flags(page(index), frame(index), flags, PageTableFlags::empty())
+                .unwrap()
+                .ignore();
+        }
+    }
+
+    #[test]
+    fn lock_prefaults_copy_on_write_pages() {
+        let page_table = FakePageTable::new(5);
+        map(&page_table, 0, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        map(&page_table, 1, USER_FLAGS | PageTableFlags::WRITABLE);
+        map(&page_table, 2, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        let mut allocator = FakeFrameAllocator(vec![frame(4), frame(3)]);
+
+        let pages = Page::range(page(0), page(3));
+        unsafe { lock_pages(&page_table, &mut allocator, pages) }.unwrap();
+
+        for page in pages {
+            let (_, flags) = page_table.mapping(page);
+            assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+            assert!(flags.contains(PageTableFlags::LOCKED));
+            assert!(!flags.contains(PageTableFlags::COPY_ON_WRITE));
+        }
+        // The copy-on-write pages got private frames, the private page kept its own.
+        assert_eq!(page_table.mapping(page(0)).0, frame(3));
+        assert_eq!(page_table.mapping(page(1)).0, frame(1));
+        assert_eq!(page_table.mapping(page(2)).0, frame(4));
+
+        unsafe { unlock_pages(&page_table, pages) }.unwrap();
+        for page in pages {
+            let (_, flags) = page_table.mapping(page);
+            assert!(!flags.contains(PageTableFlags::LOCKED));
+            assert!(flags.contains(PageTableFlags::WRITABLE));
+        }
+    }
+
+    #[test]
+    fn lock_fails_on_unmapped_page() {
+        let page_table = FakePageTable::new(3);
+        map(&page_table, 0, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        let mut allocator = FakeFrameAllocator(vec![frame(2)]);
+
+        let pages = Page::range(page(0), page(2));
+        assert!(unsafe { lock_pages(&page_table, &mut allocator, pages) }.is_err());
+        // Nothing was changed.
+        let (shared_frame, flags) = page_table.mapping(page(0));
+        assert_eq!(shared_frame, frame(0));
+        assert!(flags.contains(PageTableFlags::COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::LOCKED));
+        assert_eq!(allocator.0.len(), 1);
+    }
+
+    #[test]
+    fn failed_lock_unlocks_pages_it_locked() {
+        let page_table = FakePageTable::new(6);
+        map(&page_table, 0, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        map(&page_table, 1, USER_FLAGS | PageTableFlags::WRITABLE | PageTableFlags::LOCKED);
+        map(&page_table, 2, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        map(&page_table, 3, USER_FLAGS | PageTableFlags::COPY_ON_WRITE);
+        // There is no frame left for the copy of the last page.
+        let mut allocator = FakeFrameAllocator(vec![frame(5), frame(4)]);
+
+        let pages = Page::range(page(0), page(4));
+        assert!(unsafe { lock_pages(&page_table, &mut allocator, pages) }.is_err());
+
+        // The pages locked by the failed call are unlocked again, but keep their
+        // private copies.
+        for index in [0, 2] {
+            let (_, flags) = page_table.mapping(page(index));
+            assert!(!flags.contains(PageTableFlags::LOCKED));
+            assert!(flags.contains(PageTableFlags::WRITABLE));
+        }
+        // The page that was locked before stays locked.
+        assert!(page_table.mapping(page(1)).1.contains(PageTableFlags::LOCKED));
+        // The page that couldn't be copied is untouched.
+        let (shared_frame, flags) = page_table.mapping(page(3));
+        assert_eq!(shared_frame, frame(3));
+        assert!(flags.contains(PageTableFlags::COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::LOCKED));
+    }
+}
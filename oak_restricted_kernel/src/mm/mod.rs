@@ -14,31 +14,46 @@
 // limitations under the License.
 //
 
+use core::{arch::x86_64::__cpuid, ops::Range};
+
 use goblin::{elf32::program_header::PT_LOAD, elf64::program_header::ProgramHeader};
 use log::info;
-#[cfg(feature = "initrd")]
-use oak_linux_boot_params::Ramdisk;
-use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use oak_core::sync::OnceCell;
+use oak_linux_boot_params::{BootE820Entry, E820EntryType, Ramdisk};
 use oak_sev_guest::msr::{get_sev_status, SevStatus};
-#[cfg(feature = "initrd")]
-use x86_64::structures::paging::frame::PhysFrameRange;
+use spinning_top::Spinlock;
 use x86_64::{
     addr::{align_down, align_up},
     structures::paging::{
+        frame::PhysFrameRange,
         mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
+        page::PageRange,
         FrameAllocator, Page, PageSize, PageTable, PageTableFlags as BasePageTableFlags, PhysFrame,
-        Size2MiB, Size4KiB,
+        Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-use self::encrypted_mapper::{EncryptedPageTable, MemoryEncryption};
+use self::{
+    encrypted_mapper::{EncryptedPageTable, MemoryEncryption},
+    frame_allocator::PhysicalMemoryAllocator,
+    page_tables::RootPageTable,
+    virtual_address_allocator::VirtualAddressAllocator,
+};
 use crate::{FRAME_ALLOCATOR, PAGE_TABLES, VMA_ALLOCATOR};
 
 mod bitmap_frame_allocator;
+pub mod cow;
+pub mod deferred_flush;
 pub mod encrypted_mapper;
+#[cfg(test)]
+pub mod fakes;
 pub mod frame_allocator;
+pub mod hotplug;
+pub mod memtest;
+pub mod mlock;
 pub mod page_tables;
+pub mod translation_cache;
 pub mod virtual_address_allocator;
 
 /// The start of kernel memory.
@@ -47,9 +62,157 @@ pub const KERNEL_OFFSET: u64 = 0xFFFF_FFFF_8000_0000;
 /// The offset used for the direct mapping of all physical memory.
 const DIRECT_MAPPING_OFFSET: VirtAddr = VirtAddr::new_truncate(0xFFFF_8800_0000_0000);
 
-/// For now we use a fixed position for the encrypted bit. For now we assume
-/// that we will be running on AMD Arcadia-Milan CPUs, which use bit 51.
-pub const ENCRYPTED_BIT_POSITION: u8 = 51;
+/// CPUID function that reports the memory encryption capabilities. EBX\[5:0\]
+/// contains the position of the encrypted bit (C-bit) in page table entries.
+const CPUID_MEMORY_ENCRYPTION: u32 = 0x8000_001F;
+
+/// Mask for the C-bit position in EBX of `CPUID_MEMORY_ENCRYPTION`.
+const C_BIT_POSITION_MASK: u32 = 0x3F;
+
+/// Highest bit of a page table entry that can hold a physical address bit.
+const MAX_PHYSICAL_ADDRESS_BIT: u8 = 51;
+
+/// Position of the encrypted bit in page table entries, as reported by CPUID.
+static ENCRYPTED_BIT_POSITION: OnceCell<u8> = OnceCell::new();
+
+/// Extracts the position of the encrypted bit from EBX of
+/// `CPUID_MEMORY_ENCRYPTION`.
+fn encrypted_bit_from_cpuid(ebx: u32) -> Result<u8, &'static str> {
+    let position = (ebx & C_BIT_POSITION_MASK) as u8;
+    // The C-bit is one of the (otherwise unused) upper physical address bits, so it
+    // can never be in the lower half of the entry.
+    if !(32..=MAX_PHYSICAL_ADDRESS_BIT).contains(&position) {
+        return Err("invalid encrypted bit position reported by CPUID");
+    }
+    Ok(position)
+}
+
+/// Reads the position of the encrypted bit from CPUID.
+///
+/// This must be called once during early boot, before any page tables that
+/// use memory encryption are created, if memory encryption is enabled.
+pub fn init_encrypted_bit_position() -> Result<u8, &'static str> {
+    // Safety: CPUID is available on all x86-64 CPUs; the memory encryption leaf is
+    // available as memory encryption is enabled.
+    let ebx = unsafe { __cpuid(CPUID_MEMORY_ENCRYPTION) }.ebx;
+    let position = encrypted_bit_from_cpuid(ebx)?;
+    ENCRYPTED_BIT_POSITION
+        .set(position)
+        .map_err(|_| "encrypted bit position already initialized")?;
+    Ok(position)
+}
+
+/// Returns the position of the encrypted bit in page table entries.
+///
+/// Panics if `init_encrypted_bit_position` has not been called.
+pub fn encrypted_bit_position() -> u8 {
+    *ENCRYPTED_BIT_POSITION.get().expect("encrypted bit position not initialized")
+}
+
+/// CPUID function that reports the highest supported extended function.
+const CPUID_MAX_EXTENDED_FUNCTION: u32 = 0x8000_0000;
+
+/// CPUID function for the extended processor feature flags.
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+
+/// Bit in EDX of `CPUID_EXTENDED_FEATURES` that is set if the CPU supports 1
+/// GiB pages.
+const PAGE_1GB_SUPPORT: u32 = 1 << 26;
+
+/// Decides whether 1 GiB pages are supported, based on the highest extended
+/// CPUID function and EDX of `CPUID_EXTENDED_FEATURES`.
+fn gigabyte_pages_from_cpuid(max_extended_function: u32, edx: u32) -> bool {
+    max_extended_function >= CPUID_EXTENDED_FEATURES && edx & PAGE_1GB_SUPPORT != 0
+}
+
+/// Returns whether the CPU supports mapping memory with 1 GiB pages.
+pub fn gigabyte_pages_supported() -> bool {
+    // Safety: CPUID is available on all x86-64 CPUs; we ignore the feature flags if
+    // the CPU doesn't report the extended features function.
+    let (max_extended_function, edx) =
+        unsafe { (__cpuid(CPUID_MAX_EXTENDED_FUNCTION).eax, __cpuid(CPUID_EXTENDED_FEATURES).edx) };
+    gigabyte_pages_from_cpuid(max_extended_function, edx)
+}
+
+/// Kernel argument that selects the page sizes for the direct mapping of
+/// physical memory.
+pub const DIRECT_MAP_PAGES_ARG: &str = "direct_map_pages";
+
+/// Page sizes that may be used for the direct mapping of physical memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectMapPages {
+    /// Use 1 GiB pages if the CPU supports them and alignment permits, 2 MiB
+    /// pages otherwise.
+    #[default]
+    Auto,
+    /// Only use 2 MiB pages.
+    Only2MiB,
+}
+
+impl DirectMapPages {
+    /// Parses the value of `DIRECT_MAP_PAGES_ARG`.
+    pub fn from_arg(arg: &str) -> Result<Self, &'static str> {
+        match arg {
+            "auto" => Ok(Self::Auto),
+            "2m" => Ok(Self::Only2MiB),
+            _ => Err("expected one of auto or 2m"),
+        }
+    }
+}
+
+/// A part of the direct mapping that is mapped with a single page size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DirectMapChunk {
+    Huge(PhysFrameRange<Size1GiB>),
+    Large(PhysFrameRange<Size2MiB>),
+}
+
+/// Splits `range` into chunks that can each be mapped at `offset` with a single
+/// page size.
+///
+/// 1 GiB pages are only used if `gigabyte_pages` is set, the physical and the
+/// virtual address are both 1 GiB-aligned and the whole 1 GiB lies within
+/// `range`. The encrypted bit applies to the whole page, so 1 GiB pages are
+/// also not used for memory overlapping `keep_small`, as the flags of those 2
+/// MiB pages are changed after the mapping has been created.
+fn direct_map_chunks(
+    range: PhysFrameRange<Size2MiB>,
+    offset: VirtAddr,
+    gigabyte_pages: bool,
+    keep_small: Range<PhysAddr>,
+) -> impl Iterator<Item = DirectMapChunk> {
+    let end = range.end.start_address();
+    let mut start = range.start.start_address();
+    let huge = move |addr: PhysAddr| {
+        let huge_end = addr + Size1GiB::SIZE;
+        gigabyte_pages
+            && addr.is_aligned(Size1GiB::SIZE)
+            && (offset + addr.as_u64()).is_aligned(Size1GiB::SIZE)
+            && huge_end <= end
+            && (keep_small.is_empty() || keep_small.end <= addr || huge_end <= keep_small.start)
+    };
+    core::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+        let chunk_start = start;
+        if huge(start) {
+            while start < end && huge(start) {
+                start += Size1GiB::SIZE;
+            }
+            Some(DirectMapChunk::Huge(PhysFrame::range(
+                PhysFrame::containing_address(chunk_start),
+                PhysFrame::containing_address(start),
+            )))
+        } else {
+            start = PhysAddr::new(align_up(start.as_u64() + 1, Size1GiB::SIZE)).min(end);
+            Some(DirectMapChunk::Large(PhysFrame::range(
+                PhysFrame::containing_address(chunk_start),
+                PhysFrame::containing_address(start),
+            )))
+        }
+    })
+}
 
 // TODO(#3394): Move to a shared crate.
 pub trait Translator {
@@ -69,6 +232,18 @@ pub trait Translator {
     /// Translate a physical frame to virtual page, using the directly mapped
     /// region.
     fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>>;
+
+    /// Checks whether the given virtual address is mapped with the encrypted
+    /// bit set in the page table entry.
+    ///
+    /// Returns `None` if there is no valid mapping for the given address.
+    fn is_encrypted(&self, addr: VirtAddr) -> Option<bool>;
+
+    /// Returns the flags of the page table entry that maps the given virtual
+    /// address.
+    ///
+    /// Returns `None` if there is no valid mapping for the given address.
+    fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags>;
 }
 
 bitflags::bitflags! {
@@ -86,6 +261,13 @@ bitflags::bitflags! {
         const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
         const GLOBAL = 1 << 8;
+        /// Software-defined bit (ignored by the CPU) marking a read-only page as copy-on-write.
+        ///
+        /// A write to such a page is resolved by the page fault handler; see <cow>.
+        const COPY_ON_WRITE = 1 << 9;
+        /// Software-defined bit (ignored by the CPU) marking a page as locked in memory by
+        /// `mlock()`; see <mlock>.
+        const LOCKED = 1 << 10;
         /// Marks the page as encrypted. Ignored under <NoEncryption>.
         ///
         /// The bit value is hardcoded to be 51 here, but that's because it's not possible to
@@ -125,6 +307,12 @@ impl From<PageTableFlags> for BasePageTableFlags {
         if value.contains(PageTableFlags::GLOBAL) {
             flags |= BasePageTableFlags::GLOBAL
         }
+        if value.contains(PageTableFlags::COPY_ON_WRITE) {
+            flags |= BasePageTableFlags::BIT_9
+        }
+        if value.contains(PageTableFlags::LOCKED) {
+            flags |= BasePageTableFlags::BIT_10
+        }
         // There is no equivalent of ENCRYPTED in BasePageTableFlags.
         if value.contains(PageTableFlags::NO_EXECUTE) {
             flags |= BasePageTableFlags::NO_EXECUTE
@@ -168,26 +356,56 @@ pub trait Mapper<S: PageSize> {
     ) -> Result<MapperFlush<S>, FlagUpdateError>;
 }
 
-pub fn init(
-    memory_map: &[BootE820Entry],
-    program_headers: &[ProgramHeader],
-    #[cfg(feature = "initrd")] ramdisk: &Ramdisk,
-) {
-    let mut alloc = FRAME_ALLOCATOR.lock();
+/// How the frame allocator treats the memory described by an e820 entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryClass {
+    /// The memory can be handed out by the frame allocator.
+    Usable,
+    /// The memory must never be handed out by the frame allocator.
+    Excluded,
+}
 
-    /* Step 1: mark all RAM as available (event though it may contain data!) */
+/// Classifies an e820 entry based on its type.
+///
+/// Only `RAM` is usable. Everything else is excluded: reserved memory, ACPI
+/// NVS, unusable and disabled memory for obvious reasons; ACPI reclaimable
+/// memory as we never reclaim it after parsing the tables; persistent memory
+/// as we don't support it. Types we don't know about are excluded as well, as
+/// we can't tell whether it's safe to use the memory or not.
+pub fn classify_e820_entry(entry: &BootE820Entry) -> MemoryClass {
+    match entry.entry_type() {
+        Some(E820EntryType::RAM) => MemoryClass::Usable,
+        Some(
+            E820EntryType::INVALID
+            | E820EntryType::RESERVED
+            | E820EntryType::ACPI
+            | E820EntryType::NVS
+            | E820EntryType::UNUSABLE
+            | E820EntryType::DISABLED
+            | E820EntryType::PMEM,
+        ) => MemoryClass::Excluded,
+        None => {
+            log::warn!(
+                "excluding e820 entry [{:#018x}..{:#018x}) with unknown type {}",
+                entry.addr(),
+                entry.end(),
+                entry.raw_type()
+            );
+            MemoryClass::Excluded
+        }
+    }
+}
+
+/// Returns the 2 MiB frames that are usable according to the memory map.
+///
+/// Usable entries are clipped to 2 MiB boundaries; entries that don't contain
+/// a full 2 MiB frame are skipped.
+fn usable_frames(
+    memory_map: &[BootE820Entry],
+) -> impl Iterator<Item = PhysFrameRange<Size2MiB>> + '_ {
     memory_map
         .iter()
-        .inspect(|e| {
-            info!(
-                "E820 entry: [{:#018x}..{:#018x}) ({}), type {}",
-                e.addr(),
-                e.addr() + e.size(),
-                e.size(),
-                e.entry_type().unwrap()
-            );
-        })
-        .filter(|e| e.entry_type() == Some(E820EntryType::RAM))
+        .filter(|e| classify_e820_entry(e) == MemoryClass::Usable)
         .map(|e| {
             // Clip both ends, if necessary, to make sure that we are aligned with 2 MiB
             // pages.
@@ -205,19 +423,55 @@ pub fn init(
                 PhysFrame::from_start_address(limit).unwrap(),
             )
         })
-        .for_each(|range| alloc.mark_valid(range, true));
+}
+
+pub fn init(
+    memory_map: &[BootE820Entry],
+    program_headers: &[ProgramHeader],
+    ramdisk: Option<&Ramdisk>,
+) {
+    init_frame_allocator(&mut FRAME_ALLOCATOR.lock(), memory_map, program_headers, ramdisk)
+}
+
+/// Makes the RAM in `memory_map` available in `alloc`, except for the memory
+/// used by the kernel and the ramdisk.
+pub fn init_frame_allocator<const N: usize>(
+    alloc: &mut PhysicalMemoryAllocator<N>,
+    memory_map: &[BootE820Entry],
+    program_headers: &[ProgramHeader],
+    ramdisk: Option<&Ramdisk>,
+) {
+    /* Step 1: mark all RAM as available (event though it may contain data!) */
+    for e in memory_map {
+        match e.entry_type() {
+            Some(entry_type) => info!(
+                "E820 entry: [{:#018x}..{:#018x}) ({}), type {}",
+                e.addr(),
+                e.end(),
+                e.size(),
+                entry_type
+            ),
+            None => info!(
+                "E820 entry: [{:#018x}..{:#018x}) ({}), unknown type {}",
+                e.addr(),
+                e.end(),
+                e.size(),
+                e.raw_type()
+            ),
+        }
+    }
+    usable_frames(memory_map).for_each(|range| alloc.mark_valid(range, true));
 
     // Step 2: mark known in-use regions as not available.
 
     // First, leave out the first 2 MiB as there be dragons (and bootloader data
     // structures)
-    alloc.mark_valid(
-        PhysFrame::range(
+    alloc
+        .reserve(PhysFrame::range(
             PhysFrame::from_start_address(PhysAddr::new(0x0)).unwrap(),
             PhysFrame::from_start_address(PhysAddr::new(Size2MiB::SIZE)).unwrap(),
-        ),
-        false,
-    );
+        ))
+        .unwrap();
 
     // Second, mark every `PT_LOAD` section from the phdrs as used.
     program_headers
@@ -245,12 +499,11 @@ pub fn init(
                 range.start.start_address().as_u64(),
                 range.end.start_address().as_u64()
             );
-            alloc.mark_valid(range, false)
+            alloc.reserve(range).expect("couldn't reserve kernel memory")
         });
 
     // Thirdly, mark the ramdisk as reserved.
-    #[cfg(feature = "initrd")]
-    {
+    if let Some(ramdisk) = ramdisk {
         let ramdisk_range = ramdisk_range(ramdisk);
         info!(
             "marking [{:#018x}..{:#018x}) as reserved (ramdisk)",
@@ -261,7 +514,6 @@ pub fn init(
     };
 }
 
-#[cfg(feature = "initrd")]
 pub fn ramdisk_range(ramdisk: &Ramdisk) -> PhysFrameRange<Size2MiB> {
     PhysFrame::range(
         PhysFrame::<x86_64::structures::paging::Size2MiB>::from_start_address(PhysAddr::new(
@@ -276,11 +528,65 @@ pub fn ramdisk_range(ramdisk: &Ramdisk) -> PhysFrameRange<Size2MiB> {
     )
 }
 
+/// Translates a range of physical frames to the pages of the direct mapping
+/// that they are mapped at.
+///
+/// The direct mapping is contiguous, so the pages should form a range of the
+/// same length, in the same order. We check that anyway, as a paging bug would
+/// otherwise result in an empty or wrapped range without anyone noticing.
+pub fn translate_frame_range<S: PageSize, T: Translator>(
+    translator: &T,
+    frames: PhysFrameRange<S>,
+) -> Result<PageRange<S>, &'static str> {
+    let translate = |frame| {
+        translator.translate_physical_frame(frame).ok_or("frame is not in the direct mapping")
+    };
+    let start = translate(frames.start)?;
+    let end = translate(frames.end)?;
+    if start > end {
+        return Err("translated pages are in the wrong order");
+    }
+    if end - start != frames.end - frames.start {
+        return Err("translated page range doesn't match the size of the frame range");
+    }
+    for (index, frame) in frames.enumerate() {
+        if translate(frame)? != start + index as u64 {
+            return Err("translated pages are not contiguous");
+        }
+    }
+    Ok(Page::range(start, end))
+}
+
+/// Checks that the page containing `addr` is mapped, but not accessible from
+/// user mode.
+///
+/// The payload runs in ring 3 and must only be able to reach the kernel
+/// through system calls and faults, so none of the kernel's memory may be
+/// mapped `USER_ACCESSIBLE`.
+pub fn check_supervisor_only<T: Translator>(
+    translator: &T,
+    addr: VirtAddr,
+) -> Result<(), &'static str> {
+    let flags = translator.flags(addr).ok_or("address is not mapped")?;
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err("kernel memory is accessible from user mode");
+    }
+    Ok(())
+}
+
+/// Flags for the direct mapping of all physical memory.
+fn direct_mapping_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::ENCRYPTED
+}
+
 pub fn encryption() -> MemoryEncryption {
-    // Should we set the C-bit (encrypted memory for SEV)? For now, let's assume
-    // it's bit 51.
+    // Should we set the C-bit (encrypted memory for SEV)?
     if get_sev_status().unwrap_or(SevStatus::empty()).contains(SevStatus::SEV_ENABLED) {
-        MemoryEncryption::Encrypted(ENCRYPTED_BIT_POSITION)
+        MemoryEncryption::Encrypted(encrypted_bit_position())
     } else {
         MemoryEncryption::NoEncryption
     }
@@ -301,7 +607,15 @@ pub fn encryption() -> MemoryEncryption {
 /// |                     |          |                     |         | physical memory             |
 /// | FFFF_8820_0000_0000 | ~-120 TB | FFFF_FFFF_7FFF_FFFF | ~120 TB | ... unused hole             |
 /// | FFFF_FFFF_8000_0000 |    -2 GB | FFFF_FFFF_FFFF_FFFF |    2 GB | Kernel code                 |
-pub fn initial_pml4(program_headers: &[ProgramHeader]) -> Result<PhysFrame, &'static str> {
+///
+/// The direct mapping uses 1 GiB pages where `pages` and the CPU allow it,
+/// except for the memory in `keep_small`, which stays mapped with 2 MiB pages
+/// so that the flags of individual pages can be changed later.
+pub fn initial_pml4(
+    program_headers: &[ProgramHeader],
+    pages: DirectMapPages,
+    keep_small: Range<PhysAddr>,
+) -> Result<PhysFrame, &'static str> {
     // Safety: this expects the frame allocator to be initialized and the memory
     // region it's handing memory out of to be identity mapped. This is true for
     // the lower 2 GiB after we boot. This reference will no longer be valid
@@ -335,20 +649,38 @@ pub fn initial_pml4(program_headers: &[ProgramHeader]) -> Result<PhysFrame, &'st
         // Create a direct map for all physical memory, marking it NO_EXECUTE. The size
         // (128 GB) has been chosen go coincide with the amout of memory our
         // frame allocator can track.
-        page_tables::create_offset_map(
-            PhysFrame::<Size2MiB>::range(
+        let flags = direct_mapping_flags();
+        let gigabyte_pages = pages == DirectMapPages::Auto && gigabyte_pages_supported();
+        info!(
+            "Using {} pages for the direct mapping",
+            if gigabyte_pages { "1 GiB" } else { "2 MiB" }
+        );
+        for chunk in direct_map_chunks(
+            PhysFrame::range(
                 PhysFrame::from_start_address(PhysAddr::new(0x00_0000_0000)).unwrap(),
                 PhysFrame::from_start_address(PhysAddr::new(0x20_0000_0000)).unwrap(),
             ),
             DIRECT_MAPPING_OFFSET,
-            PageTableFlags::PRESENT
-                | PageTableFlags::GLOBAL
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_EXECUTE
-                | PageTableFlags::ENCRYPTED,
-            &mut page_table,
-        )
-        .map_err(|_| "couldn't set up paging for physical memory")?;
+            gigabyte_pages,
+            keep_small,
+        ) {
+            match chunk {
+                DirectMapChunk::Huge(frames) => page_tables::create_offset_map(
+                    frames,
+                    DIRECT_MAPPING_OFFSET + frames.start.start_address().as_u64(),
+                    flags,
+                    &mut page_table,
+                )
+                .map_err(|_| "couldn't set up paging for physical memory")?,
+                DirectMapChunk::Large(frames) => page_tables::create_offset_map(
+                    frames,
+                    DIRECT_MAPPING_OFFSET + frames.start.start_address().as_u64(),
+                    flags,
+                    &mut page_table,
+                )
+                .map_err(|_| "couldn't set up paging for physical memory")?,
+            }
+        }
 
         // Mapping for the kernel itself in the upper -2G of memory, based on the
         // mappings (and permissions) in the program header.
@@ -359,6 +691,112 @@ pub fn initial_pml4(program_headers: &[ProgramHeader]) -> Result<PhysFrame, &'st
     Ok(pml4_frame)
 }
 
+/// Runs `f` with `lock` held, releasing the lock as soon as `f` returns.
+fn with_locked<T, R>(lock: &Spinlock<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut lock.lock())
+}
+
+/// Runs `f` with the active page tables.
+///
+/// The `PAGE_TABLES` lock is only held while `f` runs, so keep `f` short. `f`
+/// may use [`with_frame_allocator`], but not the other way around: if both
+/// locks are needed, `PAGE_TABLES` must be taken first.
+///
+/// Panics if the page tables haven't been set up yet.
+pub fn with_page_tables<R>(f: impl FnOnce(&RootPageTable) -> R) -> R {
+    with_locked(&PAGE_TABLES, |pt| f(pt.get().expect("page tables not initialized")))
+}
+
+/// Runs `f` with the frame allocator, holding the `FRAME_ALLOCATOR` lock only
+/// while `f` runs.
+///
+/// `f` must not use [`with_page_tables`], as `PAGE_TABLES` has to be locked
+/// before `FRAME_ALLOCATOR`.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut PhysicalMemoryAllocator<4096>) -> R) -> R {
+    with_locked(&FRAME_ALLOCATOR, f)
+}
+
+/// Maps `frame` into the address space of the current process at `addr`,
+/// read-only and non-executable.
+///
+/// This is used for pages the kernel shares with the application; the kernel
+/// writes to them through the direct mapping.
+pub fn map_user_read_only(addr: VirtAddr, frame: PhysFrame<Size2MiB>) -> Result<(), &'static str> {
+    let pt_guard = PAGE_TABLES.lock();
+    let pt = pt_guard.get().ok_or("page tables not initialized")?;
+    // Safety: the application can't write to the page, and the caller decides
+    // what it may read.
+    unsafe {
+        pt.map_to_with_table_flags(
+            Page::containing_address(addr),
+            frame,
+            PageTableFlags::PRESENT
+                | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::ENCRYPTED
+                | PageTableFlags::NO_EXECUTE,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::ENCRYPTED
+                | PageTableFlags::USER_ACCESSIBLE,
+        )
+    }
+    .map_err(|_| "couldn't map the page into user space")?
+    .flush();
+    Ok(())
+}
+
+/// 4 KiB pages for device registers, carved out of `VMA_ALLOCATOR` one 2 MiB
+/// page at a time so that each MMIO mapping doesn't use up a whole 2 MiB page.
+static MMIO_PAGES: Spinlock<Option<VirtualAddressAllocator<Size4KiB>>> = Spinlock::new(None);
+
+/// Allocates a 4 KiB page from `pages`, first replacing it with an allocator
+/// for the 2 MiB page returned by `new_window` if it is missing or exhausted.
+fn allocate_mmio_page<F>(
+    pages: &mut Option<VirtualAddressAllocator<Size4KiB>>,
+    new_window: F,
+) -> Result<Page<Size4KiB>, &'static str>
+where
+    F: FnOnce() -> Option<Page<Size2MiB>>,
+{
+    if let Some(range) = pages.as_mut().and_then(|pages| pages.allocate(1)) {
+        return Ok(range.start);
+    }
+    let window = new_window().ok_or("couldn't allocate virtual memory for MMIO")?;
+    let mut window_pages =
+        VirtualAddressAllocator::from_window(window.start_address().as_u64(), Size2MiB::SIZE, 0)
+            .map_err(|_| "invalid virtual memory window for MMIO")?;
+    let page = window_pages.allocate(1).ok_or("couldn't allocate virtual memory for MMIO")?.start;
+    *pages = Some(window_pages);
+    Ok(page)
+}
+
+/// Maps the 4 KiB page of device registers containing `addr` into kernel
+/// memory, uncached and unencrypted, and returns the virtual address of `addr`.
+///
+/// The direct mapping is write-back and encrypted, which is wrong for MMIO.
+pub fn map_mmio(addr: PhysAddr) -> Result<VirtAddr, &'static str> {
+    let page = allocate_mmio_page(&mut MMIO_PAGES.lock(), || {
+        VMA_ALLOCATOR.lock().allocate(1).map(|pages| pages.start)
+    })?;
+    let frame = PhysFrame::<Size4KiB>::containing_address(addr);
+    // Safety: the page was just allocated, so nothing else refers to it.
+    with_page_tables(|pt| unsafe {
+        pt.map_to_with_table_flags(
+            page,
+            frame,
+            PageTableFlags::GLOBAL
+                | PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE,
+            PageTableFlags::ENCRYPTED | PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        )
+    })
+    .map_err(|_| "couldn't map the MMIO page")?
+    .flush();
+    Ok(page.start_address() + (addr - frame.start_address()))
+}
+
 /// Allocates memory usable as a stack.
 ///
 /// The stack will be one page (2 MiB) in size, will be allocated in the
@@ -375,29 +813,304 @@ pub fn allocate_stack() -> VirtAddr {
         .lock()
         .allocate(2)
         .expect("unable to allocate virtual memory for syscall stack");
-    let frame = FRAME_ALLOCATOR
-        .lock()
-        .allocate_frame()
+    let frame: PhysFrame<Size2MiB> = with_frame_allocator(|fa| fa.allocate_frame())
         .expect("unable to allocate physical memory for syscall stack");
     let stack_page = pages.start + 1;
-    unsafe {
-        PAGE_TABLES
-            .lock()
-            .get()
-            .unwrap()
-            .map_to_with_table_flags(
-                stack_page,
-                frame,
-                PageTableFlags::GLOBAL
-                    | PageTableFlags::PRESENT
-                    | PageTableFlags::ENCRYPTED
-                    | PageTableFlags::NO_EXECUTE
-                    | PageTableFlags::WRITABLE,
-                PageTableFlags::ENCRYPTED | PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-            )
-            .expect("failed to update page tables for syscall stack")
-            .flush();
-    };
+    with_page_tables(|pt| unsafe {
+        pt.map_to_with_table_flags(
+            stack_page,
+            frame,
+            PageTableFlags::GLOBAL
+                | PageTableFlags::PRESENT
+                | PageTableFlags::ENCRYPTED
+                | PageTableFlags::NO_EXECUTE
+                | PageTableFlags::WRITABLE,
+            PageTableFlags::ENCRYPTED | PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        )
+        .expect("failed to update page tables for syscall stack")
+        .flush();
+    });
 
     (stack_page + 1).start_address()
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn encrypted_bit_from_cpuid_ebx() {
+        // Bits above EBX[5:0] contain other information and must be ignored.
+        let position = encrypted_bit_from_cpuid(0x0000_0173).unwrap();
+        assert_eq!(position, 51);
+        assert_eq!(MemoryEncryption::Encrypted(position).bit(), 1 << 51);
+
+        let position = encrypted_bit_from_cpuid(0x0000_016F).unwrap();
+        assert_eq!(position, 47);
+        assert_eq!(MemoryEncryption::Encrypted(position).bit(), 0x8000_0000_0000);
+    }
+
+    #[test]
+    fn with_locked_releases_lock() {
+        let lock = Spinlock::new(1);
+        let value = with_locked(&lock, |value| {
+            *value += 1;
+            *value
+        });
+        assert_eq!(value, 2);
+        // The lock must be free again, so that it can be re-acquired.
+        assert_eq!(*lock.try_lock().expect("lock still held after the closure"), 2);
+    }
+
+    /// Translates frames to the pages given by `page_index`, to simulate a
+    /// broken direct mapping.
+    struct ScatteredTranslator(fn(u64) -> u64);
+
+    impl Translator for ScatteredTranslator {
+        fn translate_virtual(&self, _addr: VirtAddr) -> Option<PhysAddr> {
+            None
+        }
+
+        fn translate_physical(&self, addr: PhysAddr) -> Option<VirtAddr> {
+            let index = addr.as_u64() / Size2MiB::SIZE;
+            Some(VirtAddr::new(DIRECT_MAPPING_OFFSET.as_u64() + self.0(index) * Size2MiB::SIZE))
+        }
+
+        fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>> {
+            Page::from_start_address(self.translate_physical(frame.start_address())?).ok()
+        }
+
+        fn is_encrypted(&self, _addr: VirtAddr) -> Option<bool> {
+            None
+        }
+
+        fn flags(&self, _addr: VirtAddr) -> Option<PageTableFlags> {
+            None
+        }
+    }
+
+    #[test]
+    fn translates_contiguous_frame_range() {
+        let frames = PhysFrame::range(fakes::frame(4), fakes::frame(8));
+        let pages = translate_frame_range(&ScatteredTranslator(|index| index), frames).unwrap();
+        assert_eq!(pages.count(), 4);
+        assert_eq!(pages.start.start_address(), DIRECT_MAPPING_OFFSET + 4 * Size2MiB::SIZE);
+    }
+
+    #[test]
+    fn rejects_broken_frame_range_translation() {
+        let frames = PhysFrame::range(fakes::frame(4), fakes::frame(8));
+        // The end comes before the start.
+        assert!(translate_frame_range(&ScatteredTranslator(|index| 100 - index), frames).is_err());
+        // The range shrinks.
+        assert!(translate_frame_range(
+            &ScatteredTranslator(|index| if index == 8 { 6 } else { index }),
+            frames
+        )
+        .is_err());
+        // The endpoints match, but a frame in the middle is elsewhere.
+        assert!(translate_frame_range(
+            &ScatteredTranslator(|index| if index == 5 { 42 } else { index }),
+            frames
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn e820_classification() {
+        use frame_allocator::PhysicalMemoryAllocator;
+
+        const MIB: usize = 1024 * 1024;
+        let entry = |index: usize, type_: u32| {
+            let mut entry = BootE820Entry::new(index * 2 * MIB, 2 * MIB, E820EntryType::RAM);
+            // `BootE820Entry::new` only takes known types, so patch the raw value for the
+            // unknown one.
+            zerocopy::AsBytes::as_bytes_mut(&mut entry)[16..20]
+                .copy_from_slice(&type_.to_le_bytes());
+            entry
+        };
+        // One 2 MiB frame per standard type, 0 through 7, and one more of an unknown
+        // type.
+        let memory_map: [BootE820Entry; 9] =
+            core::array::from_fn(|index| entry(index, index as u32));
+        assert_eq!(classify_e820_entry(&memory_map[1]), MemoryClass::Usable);
+        for index in [0, 2, 3, 4, 5, 6, 7, 8] {
+            assert_eq!(classify_e820_entry(&memory_map[index]), MemoryClass::Excluded);
+        }
+
+        let mut alloc = PhysicalMemoryAllocator::<1>::new();
+        usable_frames(&memory_map).for_each(|range| alloc.mark_valid(range, true));
+        assert_eq!(alloc.num_valid_frames(), (1, 0));
+        let frame: PhysFrame<Size2MiB> = alloc.allocate_frame().unwrap();
+        assert_eq!(frame.start_address(), PhysAddr::new(2 * MIB as u64));
+    }
+
+    #[test]
+    fn encrypted_bit_from_cpuid_ebx_invalid() {
+        assert!(encrypted_bit_from_cpuid(0).is_err());
+        assert!(encrypted_bit_from_cpuid(31).is_err());
+        assert!(encrypted_bit_from_cpuid(52).is_err());
+    }
+
+    #[test]
+    fn gigabyte_pages_from_cpuid_edx() {
+        assert!(gigabyte_pages_from_cpuid(0x8000_0008, PAGE_1GB_SUPPORT));
+        assert!(!gigabyte_pages_from_cpuid(0x8000_0008, !PAGE_1GB_SUPPORT));
+        // The feature flags are meaningless if the function isn't supported.
+        assert!(!gigabyte_pages_from_cpuid(0x8000_0000, PAGE_1GB_SUPPORT));
+    }
+
+    fn frames_2m(start: u64, end: u64) -> PhysFrameRange<Size2MiB> {
+        PhysFrame::range(
+            PhysFrame::from_start_address(PhysAddr::new(start)).unwrap(),
+            PhysFrame::from_start_address(PhysAddr::new(end)).unwrap(),
+        )
+    }
+
+    fn frames_1g(start: u64, end: u64) -> PhysFrameRange<Size1GiB> {
+        PhysFrame::range(
+            PhysFrame::from_start_address(PhysAddr::new(start)).unwrap(),
+            PhysFrame::from_start_address(PhysAddr::new(end)).unwrap(),
+        )
+    }
+
+    const GIB: u64 = Size1GiB::SIZE;
+    const NOTHING: Range<PhysAddr> = PhysAddr::zero()..PhysAddr::zero();
+
+    #[test]
+    fn direct_map_chunks_use_gigabyte_pages_where_aligned() {
+        let range = frames_2m(0x20_0000, 4 * GIB + 0x40_0000);
+        let chunks: Vec<_> =
+            direct_map_chunks(range, DIRECT_MAPPING_OFFSET, true, NOTHING).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                DirectMapChunk::Large(frames_2m(0x20_0000, GIB)),
+                DirectMapChunk::Huge(frames_1g(GIB, 4 * GIB)),
+                DirectMapChunk::Large(frames_2m(4 * GIB, 4 * GIB + 0x40_0000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn direct_map_chunks_fall_back_to_2mib() {
+        let range = frames_2m(0, 2 * GIB);
+        let expected = vec![
+            DirectMapChunk::Large(frames_2m(0, GIB)),
+            DirectMapChunk::Large(frames_2m(GIB, 2 * GIB)),
+        ];
+        // Not supported by the CPU.
+        let chunks: Vec<_> =
+            direct_map_chunks(range, DIRECT_MAPPING_OFFSET, false, NOTHING).collect();
+        assert_eq!(chunks, expected);
+        // The virtual address is not 1 GiB-aligned.
+        let chunks: Vec<_> =
+            direct_map_chunks(range, DIRECT_MAPPING_OFFSET + 0x20_0000u64, true, NOTHING).collect();
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn direct_map_chunks_keep_small_pages_small() {
+        let range = frames_2m(0, 3 * GIB);
+        let keep_small = PhysAddr::new(GIB + 0x20_0000)..PhysAddr::new(GIB + 0x60_0000);
+        let chunks: Vec<_> =
+            direct_map_chunks(range, DIRECT_MAPPING_OFFSET, true, keep_small).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                DirectMapChunk::Huge(frames_1g(0, GIB)),
+                DirectMapChunk::Large(frames_2m(GIB, 2 * GIB)),
+                DirectMapChunk::Huge(frames_1g(2 * GIB, 3 * GIB)),
+            ]
+        );
+    }
+
+    #[test]
+    fn user_supervisor_split() {
+        use goblin::elf64::program_header::{PF_R, PF_W, PF_X};
+        use oak_restricted_kernel_interface::syscalls::MmapProtection;
+
+        use self::fakes::{frame, FakePageTable};
+
+        let text = ProgramHeader { p_type: PT_LOAD, p_flags: PF_R | PF_X, ..Default::default() };
+        let data = ProgramHeader { p_type: PT_LOAD, p_flags: PF_R | PF_W, ..Default::default() };
+        let heap = VirtAddr::new(KERNEL_OFFSET - 0x4000_0000);
+        let user = VirtAddr::new(0x20_0000);
+        let user_code = VirtAddr::new(0x40_0000);
+        let mappings = [
+            (VirtAddr::new(KERNEL_OFFSET), page_tables::kernel_segment_flags(&text)),
+            (VirtAddr::new(KERNEL_OFFSET + 0x20_0000), page_tables::kernel_segment_flags(&data)),
+            (heap, crate::memory::kernel_heap_flags()),
+            (DIRECT_MAPPING_OFFSET, direct_mapping_flags()),
+            (
+                user,
+                crate::syscall::mmap::user_page_flags(
+                    MmapProtection::PROT_READ | MmapProtection::PROT_WRITE,
+                ),
+            ),
+            (
+                user_code,
+                crate::syscall::mmap::user_page_flags(
+                    MmapProtection::PROT_READ | MmapProtection::PROT_EXEC,
+                ),
+            ),
+        ];
+        let pt = FakePageTable::new(0);
+        for (index, (addr, flags)) in mappings.into_iter().enumerate() {
+            // Safety: the fake page tables aren't used for anything.
+            unsafe {
+                pt.map_to_with_table_flags(
+                    Page::<Size2MiB>::containing_address(addr),
+                    frame(index as u64),
+                    flags,
+                    PageTableFlags::PRESENT,
+                )
+            }
+            .unwrap()
+            .ignore();
+        }
+
+        for (addr, _) in &mappings[..4] {
+            assert_eq!(check_supervisor_only(&pt, *addr), Ok(()), "{:?}", addr);
+        }
+        for addr in [user, user_code] {
+            assert!(pt.flags(addr).unwrap().contains(PageTableFlags::USER_ACCESSIBLE));
+            assert!(check_supervisor_only(&pt, addr).is_err());
+        }
+        assert!(check_supervisor_only(&pt, VirtAddr::new(0x60_0000)).is_err());
+    }
+
+    #[test]
+    fn direct_map_pages_from_arg() {
+        assert_eq!(DirectMapPages::from_arg("auto"), Ok(DirectMapPages::Auto));
+        assert_eq!(DirectMapPages::from_arg("2m"), Ok(DirectMapPages::Only2MiB));
+        assert!(DirectMapPages::from_arg("1g").is_err());
+    }
+
+    #[test]
+    fn mmio_pages_share_a_window() {
+        let window = |addr: u64| {
+            move || Some(Page::<Size2MiB>::from_start_address(VirtAddr::new(addr)).unwrap())
+        };
+        let no_window = || -> Option<Page<Size2MiB>> { panic!("unexpected new window") };
+        let mut pages = None;
+
+        let first = allocate_mmio_page(&mut pages, window(0x20_0000)).unwrap();
+        let second = allocate_mmio_page(&mut pages, no_window).unwrap();
+        assert_eq!(first.start_address(), VirtAddr::new(0x20_0000));
+        assert_eq!(second.start_address(), VirtAddr::new(0x20_1000));
+
+        // The allocator keeps the last page of each window back.
+        for _ in 2..511 {
+            allocate_mmio_page(&mut pages, no_window).unwrap();
+        }
+        let next = allocate_mmio_page(&mut pages, window(0x60_0000)).unwrap();
+        assert_eq!(next.start_address(), VirtAddr::new(0x60_0000));
+
+        let mut pages = None;
+        assert!(allocate_mmio_page(&mut pages, || None).is_err());
+        assert!(pages.is_none());
+    }
+}
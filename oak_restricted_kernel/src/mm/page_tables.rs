@@ -32,7 +32,8 @@ use super::{
     Mapper, PageTableFlags, Translator, KERNEL_OFFSET,
 };
 
-/// Map a region of physical memory to a virtual address using 2 MiB pages.
+/// Map a region of physical memory to a virtual address using pages of size
+/// `S`.
 ///
 /// ## Safety
 ///
@@ -114,25 +115,28 @@ pub unsafe fn create_kernel_map<M: Mapper<Size2MiB> + Mapper<Size4KiB>>(
                     .unwrap(),
                 ),
                 VirtAddr::new(phdr.p_vaddr),
-                /* It's not possible to mark a page not readable, so we ignore PF_R. */
-                PageTableFlags::PRESENT
-                    | PageTableFlags::GLOBAL
-                    | PageTableFlags::ENCRYPTED
-                    | if phdr.p_flags & PF_W > 0 {
-                        PageTableFlags::WRITABLE
-                    } else {
-                        PageTableFlags::empty()
-                    }
-                    | if phdr.p_flags & PF_X == 0 {
-                        PageTableFlags::NO_EXECUTE
-                    } else {
-                        PageTableFlags::empty()
-                    },
+                kernel_segment_flags(phdr),
             )
         })
         .try_for_each(|(range, offset, flags)| create_offset_map(range, offset, flags, mapper))
 }
 
+/// Returns the flags for mapping a segment of the kernel ELF file.
+///
+/// Kernel segments are never accessible from user mode.
+pub(crate) fn kernel_segment_flags(phdr: &ProgramHeader) -> PageTableFlags {
+    // It's not possible to mark a page not readable, so we ignore PF_R.
+    PageTableFlags::PRESENT
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::ENCRYPTED
+        | if phdr.p_flags & PF_W > 0 { PageTableFlags::WRITABLE } else { PageTableFlags::empty() }
+        | if phdr.p_flags & PF_X == 0 {
+            PageTableFlags::NO_EXECUTE
+        } else {
+            PageTableFlags::empty()
+        }
+}
+
 pub struct RootPageTable {
     inner: EncryptedPageTable<MappedPageTable<'static, PhysOffset>>,
 }
@@ -276,6 +280,14 @@ impl Translator for RootPageTable {
     fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>> {
         self.inner.translate_physical_frame(frame)
     }
+
+    fn is_encrypted(&self, addr: VirtAddr) -> Option<bool> {
+        self.inner.is_encrypted(addr)
+    }
+
+    fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        self.inner.flags(addr)
+    }
 }
 
 /// Wrapper struct that holds the current page table is there one.
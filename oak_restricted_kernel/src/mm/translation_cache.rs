@@ -0,0 +1,161 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Caching of virtual-to-physical translations.
+//!
+//! Translating a virtual address requires a walk over all levels of the page
+//! tables, and drivers that bounce data through guest-host memory translate
+//! the same handful of pages over and over. The cache keeps the most recent
+//! translations in a small direct-mapped table.
+//!
+//! Page tables share their kernel half between address spaces, so a mapping
+//! changed through one page table can change translations in another. Instead
+//! of tracking which caches could be affected, every change to any page table
+//! bumps a global generation counter, and cached entries from an older
+//! generation are ignored.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Number of entries in the cache.
+const CACHE_SIZE: usize = 16;
+
+/// Generation of the page tables; bumped after every change to any mapping.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidates all cached translations in all caches.
+///
+/// Has to be called after the page tables were changed (but before the lock
+/// on the page tables is released, so that no walk can observe the change
+/// before the generation is bumped).
+pub fn invalidate_all() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Start address of the virtual 4 KiB page.
+    page: VirtAddr,
+    /// The physical address `page` translates to, as it appears in the page
+    /// table (i.e., including the encrypted bit, if set).
+    frame: PhysAddr,
+    /// The generation at the time of the page walk.
+    generation: u64,
+}
+
+/// A small direct-mapped cache of translations of 4 KiB virtual pages.
+///
+/// Only successful translations are cached.
+pub struct TranslationCache {
+    entries: Spinlock<[Option<Entry>; CACHE_SIZE]>,
+}
+
+impl TranslationCache {
+    pub const fn new() -> Self {
+        Self { entries: Spinlock::new([None; CACHE_SIZE]) }
+    }
+
+    /// Translates `addr`, either from the cache or by calling `walk` on the
+    /// start of the 4 KiB page that contains it.
+    ///
+    /// If the cache is in use (e.g., because we interrupted a lookup), it is
+    /// bypassed rather than waited for.
+    pub fn translate<F>(&self, addr: VirtAddr, walk: F) -> Option<PhysAddr>
+    where
+        F: FnOnce(VirtAddr) -> Option<PhysAddr>,
+    {
+        let page = addr.align_down(Size4KiB::SIZE);
+        let offset = addr - page;
+        let index = (page.as_u64() / Size4KiB::SIZE) as usize % CACHE_SIZE;
+        // Read the generation before walking, so that a change made during the walk
+        // makes the new entry stale.
+        let generation = GENERATION.load(Ordering::Acquire);
+
+        let Some(mut entries) = self.entries.try_lock() else {
+            return walk(page).map(|frame| frame + offset);
+        };
+        if let Some(entry) = entries[index] {
+            if entry.page == page && entry.generation == generation {
+                return Some(entry.frame + offset);
+            }
+        }
+        let frame = walk(page)?;
+        entries[index] = Some(Entry { page, frame, generation });
+        Some(frame + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn repeated_translations_hit_cache() {
+        let cache = TranslationCache::new();
+        let walks = Cell::new(0);
+        let walk = |page: VirtAddr| {
+            walks.set(walks.get() + 1);
+            Some(PhysAddr::new(page.as_u64() + 0x10_0000))
+        };
+
+        assert_eq!(cache.translate(VirtAddr::new(0x1234), walk), Some(PhysAddr::new(0x10_1234)));
+        assert_eq!(cache.translate(VirtAddr::new(0x1FF8), walk), Some(PhysAddr::new(0x10_1FF8)));
+        // Other tests may change the generation concurrently, which only costs us
+        // another walk.
+        assert!(walks.get() >= 1 && walks.get() <= 2);
+    }
+
+    #[test]
+    fn changed_mapping_is_not_stale() {
+        let cache = TranslationCache::new();
+        let mapping = Cell::new(PhysAddr::new(0x10_0000));
+        let walk = |_: VirtAddr| Some(mapping.get());
+
+        assert_eq!(cache.translate(VirtAddr::new(0x1008), walk), Some(PhysAddr::new(0x10_0008)));
+        mapping.set(PhysAddr::new(0x20_0000));
+        invalidate_all();
+        assert_eq!(cache.translate(VirtAddr::new(0x1008), walk), Some(PhysAddr::new(0x20_0008)));
+    }
+
+    #[test]
+    fn failed_translations_are_not_cached() {
+        let cache = TranslationCache::new();
+        let mapping = Cell::new(None);
+        let walk = |_: VirtAddr| mapping.get();
+
+        assert_eq!(cache.translate(VirtAddr::new(0x1000), walk), None);
+        mapping.set(Some(PhysAddr::new(0x10_0000)));
+        assert_eq!(cache.translate(VirtAddr::new(0x1000), walk), Some(PhysAddr::new(0x10_0000)));
+    }
+
+    #[test]
+    fn colliding_pages_evict_each_other() {
+        let cache = TranslationCache::new();
+        let walk = |page: VirtAddr| Some(PhysAddr::new(page.as_u64() + 0x10_0000));
+        let other = VirtAddr::new(0x1000 + CACHE_SIZE as u64 * Size4KiB::SIZE);
+
+        assert_eq!(cache.translate(VirtAddr::new(0x1000), walk), Some(PhysAddr::new(0x10_1000)));
+        assert_eq!(cache.translate(other, walk), Some(PhysAddr::new(other.as_u64() + 0x10_0000)));
+        assert_eq!(cache.translate(VirtAddr::new(0x1000), walk), Some(PhysAddr::new(0x10_1000)));
+    }
+}
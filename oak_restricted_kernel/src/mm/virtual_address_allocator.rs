@@ -14,7 +14,69 @@
 // limitations under the License.
 //
 
-use x86_64::structures::paging::{page::PageRange, Page, PageSize};
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageSize},
+    VirtAddr,
+};
+
+/// Start of the default window used for long-lived kernel allocations.
+pub const DEFAULT_WINDOW_START: u64 = 0xFFFF_C900_0000_0000;
+
+/// Size of the default window: 32 TB of virtual memory.
+pub const DEFAULT_WINDOW_SIZE: u64 = 0x2000_0000_0000;
+
+/// Returns the default window as a page range.
+///
+/// The bounds are 1 GiB aligned, so this is valid for every page size.
+pub const fn default_window<S: PageSize>() -> PageRange<S> {
+    // Safety: both bounds are constants that are canonical and aligned to the
+    // largest supported page size.
+    unsafe {
+        Page::range(
+            Page::from_start_address_unchecked(VirtAddr::new_truncate(DEFAULT_WINDOW_START)),
+            Page::from_start_address_unchecked(VirtAddr::new_truncate(
+                DEFAULT_WINDOW_START + DEFAULT_WINDOW_SIZE,
+            )),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VaError {
+    /// The requested range is empty or not entirely within the allocator's
+    /// range.
+    #[allow(dead_code)]
+    OutOfBounds,
+    /// The requested range overlaps a range that was already allocated.
+    #[allow(dead_code)]
+    Overlapping,
+    /// The window bounds are not canonical addresses, or the window crosses the
+    /// non-canonical hole.
+    NotCanonical,
+    /// The window bounds are not aligned to the page size.
+    NotAligned,
+    /// The window doesn't contain any pages.
+    EmptyWindow,
+}
+
+impl fmt::Display for VaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaError::OutOfBounds => write!(f, "requested range is out of bounds"),
+            VaError::Overlapping => write!(f, "requested range is already allocated"),
+            VaError::NotCanonical => write!(f, "window is not in canonical address space"),
+            VaError::NotAligned => write!(f, "window is not page-aligned"),
+            VaError::EmptyWindow => write!(f, "window is empty"),
+        }
+    }
+}
+
+fn overlaps<S: PageSize>(a: &PageRange<S>, b: &PageRange<S>) -> bool {
+    a.start < b.end && b.start < a.end
+}
 
 /// Extremely simple virtual memory address allocator using the bump algorithm.
 ///
@@ -24,23 +86,212 @@ use x86_64::structures::paging::{page::PageRange, Page, PageSize};
 ///
 /// This allocator only hands out pages; mapping the pages to frames, as
 /// necessary, is for the caller.
+///
+/// Optionally, a gap of unallocated pages can be left between successive
+/// allocations, so that overrunning one allocation results in a page fault
+/// instead of silently corrupting the next one.
+///
+/// Ranges at specific addresses can be reserved with `allocate_at`; the bump
+/// allocator skips over them.
 pub struct VirtualAddressAllocator<S: PageSize> {
     range: PageRange<S>,
     cursor: Page<S>,
+    guard_pages: u64,
+    /// Ranges reserved via `allocate_at` above the cursor.
+    fixed: Vec<PageRange<S>>,
 }
 
 impl<S: PageSize> VirtualAddressAllocator<S> {
     pub const fn new(range: PageRange<S>) -> Self {
-        Self { range, cursor: range.start }
+        Self::with_guard_pages(range, 0)
+    }
+
+    /// Creates an allocator that leaves `guard_pages` unallocated pages after
+    /// every allocation.
+    pub const fn with_guard_pages(range: PageRange<S>, guard_pages: u64) -> Self {
+        Self { range, cursor: range.start, guard_pages, fixed: Vec::new() }
+    }
+
+    /// Creates an allocator for the `size` bytes of virtual memory starting at
+    /// `start`, leaving `guard_pages` unallocated pages after every allocation.
+    ///
+    /// Both ends of the window have to be canonical and aligned to the page
+    /// size, and the window must not cross the non-canonical hole.
+    pub fn from_window(start: u64, size: u64, guard_pages: u64) -> Result<Self, VaError> {
+        if size == 0 {
+            return Err(VaError::EmptyWindow);
+        }
+        let end = start.checked_add(size).ok_or(VaError::NotCanonical)?;
+        let start_addr = VirtAddr::try_new(start).map_err(|_| VaError::NotCanonical)?;
+        let end_addr = VirtAddr::try_new(end).map_err(|_| VaError::NotCanonical)?;
+        // Both ends being canonical is not enough: a window starting in the lower
+        // half and ending in the upper half would contain the hole.
+        if (start >> 63) != ((end - 1) >> 63) {
+            return Err(VaError::NotCanonical);
+        }
+        let start_page =
+            Page::<S>::from_start_address(start_addr).map_err(|_| VaError::NotAligned)?;
+        let end_page = Page::<S>::from_start_address(end_addr).map_err(|_| VaError::NotAligned)?;
+        Ok(Self::with_guard_pages(Page::range(start_page, end_page), guard_pages))
     }
 
     pub fn allocate(&mut self, count: u64) -> Option<PageRange<S>> {
-        if self.cursor + count >= self.range.end {
-            None
-        } else {
+        loop {
+            let remaining = self.range.end - self.cursor;
+            if count >= remaining {
+                return None;
+            }
             let cur = self.cursor;
-            self.cursor += count;
-            Some(Page::range(cur, self.cursor))
+            let candidate = Page::range(cur, cur + count);
+            if let Some(fixed) = self.fixed.iter().find(|fixed| overlaps(fixed, &candidate)) {
+                // Skip over the reserved range and try again.
+                self.cursor = fixed.end;
+                continue;
+            }
+            // The guard gap doesn't have to fit into the range, as nothing will be
+            // allocated after it anyway.
+            self.cursor += count.saturating_add(self.guard_pages).min(remaining);
+            return Some(candidate);
+        }
+    }
+
+    /// Reserves `count` pages starting at `start`.
+    ///
+    /// Fails if the range is not within the allocator's range, or if it
+    /// overlaps a previous allocation. Everything below the bump allocator's
+    /// cursor is considered to be allocated.
+    // Nothing in the kernel needs pages at a fixed address yet.
+    #[allow(dead_code)]
+    pub fn allocate_at(&mut self, start: Page<S>, count: u64) -> Result<PageRange<S>, VaError> {
+        if count == 0
+            || start < self.range.start
+            || start >= self.range.end
+            || count > self.range.end - start
+        {
+            return Err(VaError::OutOfBounds);
+        }
+        let range = Page::range(start, start + count);
+        if start < self.cursor || self.fixed.iter().any(|fixed| overlaps(fixed, &range)) {
+            return Err(VaError::Overlapping);
         }
+        self.fixed.push(range);
+        Ok(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::{structures::paging::Size4KiB, VirtAddr};
+
+    use super::*;
+
+    fn range(start: u64, end: u64) -> PageRange<Size4KiB> {
+        Page::range(
+            Page::from_start_address(VirtAddr::new(start)).unwrap(),
+            Page::from_start_address(VirtAddr::new(end)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn allocations_are_adjacent_without_guard() {
+        let mut allocator = VirtualAddressAllocator::new(range(0x10000, 0x20000));
+        let first = allocator.allocate(2).unwrap();
+        let second = allocator.allocate(3).unwrap();
+        assert_eq!(first, range(0x10000, 0x12000));
+        assert_eq!(second, range(0x12000, 0x15000));
+    }
+
+    #[test]
+    fn allocations_are_separated_by_guard() {
+        let mut allocator = VirtualAddressAllocator::with_guard_pages(range(0x10000, 0x20000), 4);
+        let first = allocator.allocate(2).unwrap();
+        let second = allocator.allocate(3).unwrap();
+        assert_eq!(first, range(0x10000, 0x12000));
+        assert_eq!(second, range(0x16000, 0x19000));
+        assert_eq!(second.start - first.end, 4);
+    }
+
+    #[test]
+    fn guard_accounted_for_at_end_of_range() {
+        let mut allocator = VirtualAddressAllocator::with_guard_pages(range(0x10000, 0x18000), 4);
+        assert!(allocator.allocate(2).is_some());
+        // 6 pages are used, so there are 2 left, which is not enough for 2 (the check
+        // is exclusive, like for allocators without a guard).
+        assert!(allocator.allocate(2).is_none());
+        assert_eq!(allocator.allocate(1).unwrap(), range(0x16000, 0x17000));
+        // An allocation's guard gap is allowed to be cut short by the end of the range.
+        assert!(allocator.allocate(1).is_none());
+        assert!(allocator.allocate(0).is_none());
+    }
+
+    fn page(addr: u64) -> Page<Size4KiB> {
+        Page::from_start_address(VirtAddr::new(addr)).unwrap()
+    }
+
+    #[test]
+    fn from_window_validates_bounds() {
+        type Allocator = VirtualAddressAllocator<Size4KiB>;
+        assert!(Allocator::from_window(0x10000, 0x4000, 0).is_ok());
+        assert!(Allocator::from_window(DEFAULT_WINDOW_START, DEFAULT_WINDOW_SIZE, 1).is_ok());
+        assert_eq!(Allocator::from_window(0x10000, 0, 0).err(), Some(VaError::EmptyWindow));
+        assert_eq!(Allocator::from_window(0x10800, 0x4000, 0).err(), Some(VaError::NotAligned));
+        assert_eq!(Allocator::from_window(0x10000, 0x4800, 0).err(), Some(VaError::NotAligned));
+        assert_eq!(
+            Allocator::from_window(0x0000_8000_0000_0000, 0x4000, 0).err(),
+            Some(VaError::NotCanonical)
+        );
+        // Crosses the non-canonical hole.
+        assert_eq!(
+            Allocator::from_window(0x0000_7FFF_FFFF_0000, 0xFFFF_0000_0001_0000, 0).err(),
+            Some(VaError::NotCanonical)
+        );
+        // Wraps around the end of the address space.
+        assert_eq!(
+            Allocator::from_window(0xFFFF_FFFF_FFFF_0000, 0x20000, 0).err(),
+            Some(VaError::NotCanonical)
+        );
+    }
+
+    #[test]
+    fn small_window_is_exhausted() {
+        let mut allocator =
+            VirtualAddressAllocator::<Size4KiB>::from_window(0x10000, 0x4000, 0).unwrap();
+        assert_eq!(allocator.allocate(2).unwrap(), range(0x10000, 0x12000));
+        assert_eq!(allocator.allocate(1).unwrap(), range(0x12000, 0x13000));
+        // Allocations can't use up the last page of the window.
+        assert!(allocator.allocate(1).is_none());
+        assert_eq!(allocator.allocate_at(page(0x13000), 2), Err(VaError::OutOfBounds));
+        assert_eq!(allocator.allocate_at(page(0x13000), 1), Ok(range(0x13000, 0x14000)));
+        assert!(allocator.allocate(1).is_none());
+    }
+
+    #[test]
+    fn allocate_at_rejects_overlaps() {
+        let mut allocator = VirtualAddressAllocator::new(range(0x10000, 0x20000));
+        assert_eq!(allocator.allocate_at(page(0x14000), 4), Ok(range(0x14000, 0x18000)));
+        assert_eq!(allocator.allocate_at(page(0x16000), 4), Err(VaError::Overlapping));
+        assert_eq!(allocator.allocate_at(page(0x12000), 3), Err(VaError::Overlapping));
+        assert_eq!(allocator.allocate_at(page(0x18000), 2), Ok(range(0x18000, 0x1a000)));
+        assert_eq!(allocator.allocate_at(page(0x10000), 4), Ok(range(0x10000, 0x14000)));
+    }
+
+    #[test]
+    fn allocate_at_checks_bounds() {
+        let mut allocator = VirtualAddressAllocator::new(range(0x10000, 0x20000));
+        assert_eq!(allocator.allocate_at(page(0x8000), 4), Err(VaError::OutOfBounds));
+        assert_eq!(allocator.allocate_at(page(0x1e000), 4), Err(VaError::OutOfBounds));
+        assert_eq!(allocator.allocate_at(page(0x1e000), 0), Err(VaError::OutOfBounds));
+        assert_eq!(allocator.allocate_at(page(0x1e000), 2), Ok(range(0x1e000, 0x20000)));
+    }
+
+    #[test]
+    fn allocate_skips_fixed_ranges() {
+        let mut allocator = VirtualAddressAllocator::new(range(0x10000, 0x20000));
+        allocator.allocate_at(page(0x12000), 2).unwrap();
+        assert_eq!(allocator.allocate(1).unwrap(), range(0x10000, 0x11000));
+        // The next two pages would overlap the reserved range.
+        assert_eq!(allocator.allocate(2).unwrap(), range(0x14000, 0x16000));
+        // Bump allocations can't be reserved again.
+        assert_eq!(allocator.allocate_at(page(0x10000), 1), Err(VaError::Overlapping));
     }
 }
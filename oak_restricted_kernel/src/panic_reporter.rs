@@ -0,0 +1,144 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Customizable end-of-panic behaviour.
+//!
+//! By default the kernel shuts down the machine after reporting a panic.
+//! Bootloaders and test harnesses can install their own [`PanicReporter`] to,
+//! for example, exit via a debug port with a failure code, reboot, or wait for
+//! a debugger to attach.
+
+use core::{fmt::Write, panic::PanicInfo};
+
+use log::error;
+use oak_core::sync::OnceCell;
+
+use crate::{logging, memory, register_snapshot, shutdown, util::FixedBuffer, GUEST_HOST_HEAP};
+
+/// Size of the stack buffers that early panic messages are formatted into.
+const EARLY_PANIC_BUFFER_SIZE: usize = 512;
+
+/// Decides what happens after the kernel has logged a panic.
+pub trait PanicReporter: Sync {
+    /// Called with the details of the panic, after they have been logged.
+    ///
+    /// This is expected not to return; if it does, the machine is shut down.
+    fn report(&self, info: &PanicInfo);
+}
+
+/// The default reporter, which shuts down the machine.
+pub struct ShutdownReporter;
+
+impl PanicReporter for ShutdownReporter {
+    fn report(&self, _info: &PanicInfo) {
+        shutdown::shutdown();
+    }
+}
+
+static PANIC_REPORTER: OnceCell<&'static dyn PanicReporter> = OnceCell::new();
+
+/// Installs the reporter that is invoked by `panic()`.
+///
+/// The reporter can only be set once, preferably before calling
+/// `start_kernel`.
+pub fn set_panic_reporter(reporter: &'static dyn PanicReporter) -> Result<(), &'static str> {
+    PANIC_REPORTER.set(reporter).map_err(|_| "panic reporter already set")
+}
+
+/// Returns the installed reporter, or [`ShutdownReporter`] if none was set.
+pub fn panic_reporter() -> &'static dyn PanicReporter {
+    PANIC_REPORTER.get().copied().unwrap_or(&ShutdownReporter)
+}
+
+/// Logs the panic and the register state, and hands it over to `reporter`.
+///
+/// If the panic was caused by a fatal exception, the register state at the
+/// time of the exception is reported; otherwise, the registers at the time
+/// this function was called are.
+///
+/// From here on the logger won't wait for the serial port lock, in case the
+/// panic happened while it was held.
+///
+/// Before the heaps are set up, the details are formatted into fixed-size
+/// stack buffers (and truncated if necessary) and written straight to the
+/// serial port, so that early panics can't fault again while being reported.
+pub fn report_panic(info: &PanicInfo, reporter: &dyn PanicReporter) {
+    logging::set_panicking();
+    let registers =
+        register_snapshot::exception_registers().unwrap_or_else(register_snapshot::capture);
+    if memory::kernel_heap_initialized() && GUEST_HOST_HEAP.get().is_some() {
+        error!("PANIC: {}", info);
+        error!("Registers:\n{}", registers);
+    } else {
+        let mut message = FixedBuffer::<EARLY_PANIC_BUFFER_SIZE>::new();
+        let _ = write!(message, "PANIC: {}", info);
+        write_early(&message);
+        let mut message = FixedBuffer::<EARLY_PANIC_BUFFER_SIZE>::new();
+        let _ = write!(message, "Registers:\n{}", registers);
+        write_early(&message);
+    }
+    reporter.report(info);
+}
+
+fn write_early(message: &FixedBuffer<EARLY_PANIC_BUFFER_SIZE>) {
+    logging::write_early(message.as_str());
+    if message.truncated() {
+        logging::write_early("(message truncated)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        boxed::Box,
+        string::{String, ToString},
+        vec::Vec,
+    };
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records the messages of all panics it is asked to report.
+    struct MockReporter {
+        reports: Mutex<Vec<String>>,
+    }
+
+    impl PanicReporter for MockReporter {
+        fn report(&self, info: &PanicInfo) {
+            self.reports.lock().unwrap().push(info.to_string());
+        }
+    }
+
+    static MOCK_REPORTER: MockReporter = MockReporter { reports: Mutex::new(Vec::new()) };
+
+    #[test]
+    fn reporter_receives_panic_info() {
+        // In tests the standard library's panic machinery is in charge, so route panics
+        // through the panic core via a hook.
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|info| report_panic(info, &MOCK_REPORTER)));
+        let result = std::panic::catch_unwind(|| panic!("reporter test panic"));
+        std::panic::set_hook(previous_hook);
+
+        assert!(result.is_err());
+        let reports = MOCK_REPORTER.reports.lock().unwrap();
+        assert!(
+            reports.iter().any(|report| report.contains("reporter test panic")),
+            "reports: {:?}",
+            reports
+        );
+    }
+}
@@ -14,22 +14,33 @@
 // limitations under the License.
 //
 
-use alloc::boxed::Box;
-use core::{arch::asm, pin::Pin};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::{
+    arch::asm,
+    cmp::{max, min},
+    ops::Range,
+    pin::Pin,
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use goblin::{
     elf::{Elf, ProgramHeader, ProgramHeaders},
     elf64::program_header::{PF_W, PF_X, PT_LOAD},
 };
-use oak_restricted_kernel_interface::syscalls::{MmapFlags, MmapProtection};
+use oak_core::sync::OnceCell;
+use oak_restricted_kernel_interface::{
+    syscalls::{MmapFlags, MmapProtection},
+    ATTESTATION_REPORT_ADDR, AT_OAK_ATTESTATION_REPORT, AT_OAK_PAYLOAD_IMAGE_ENTRY, AT_OAK_RAMDISK,
+    AT_OAK_RAMDISK_SIZE,
+};
 use self_cell::self_cell;
 use x86_64::{
-    structures::paging::{PageSize, Size2MiB},
-    VirtAddr,
+    align_down, align_up,
+    structures::paging::{frame::PhysFrameRange, Page, PageSize, PhysFrame, Size2MiB},
+    PhysAddr, VirtAddr,
 };
 
-use crate::syscall::mmap::mmap;
+use crate::{args::Args, elf, mm::cow::share_read_only, syscall::mmap::mmap, PAGE_TABLES};
 
 // Set up the userspace stack at the end of the lower half of the virtual
 // address space. Well... almost. It's one page lower than the very end, as
@@ -37,6 +48,274 @@ use crate::syscall::mmap::mmap;
 // so let's avoid that whole mess by moving down a bit.
 static APPLICATION_STACK_VIRT_ADDR: u64 = 0x8000_0000_0000 - Size2MiB::SIZE;
 
+/// Kernel arguments with this prefix are passed to the application in `argv`.
+///
+/// The remainder of the key has to be the (zero-based) position of the argument
+/// in `argv`; for example, `payload.argv.0=app payload.argv.1=--verbose`.
+const ARGV_PREFIX: &str = "payload.argv.";
+
+/// Kernel arguments with this prefix are passed to the application in `envp`.
+///
+/// The remainder of the key is used as the name of the environment variable;
+/// for example, `payload.env.RUST_LOG=debug`.
+const ENV_PREFIX: &str = "payload.env.";
+
+/// Kernel argument that limits the total memory, in MiB, that the loadable
+/// segments of an application may occupy.
+const MAX_PAYLOAD_MIB_ARG: &str = "max_payload_mib";
+
+/// Default limit for the total memory of the loadable segments of an
+/// application.
+const DEFAULT_MAX_PAYLOAD_MIB: u64 = 1024;
+
+/// Maximum number of loadable segments an application may have.
+const MAX_LOAD_SEGMENTS: usize = 32;
+
+/// Kernel argument listing additional payload images staged in memory by the
+/// VMM, as comma-separated `<physical address>:<length>` pairs in hexadecimal;
+/// for example, `payload_images=0x8000000:0x12000`.
+///
+/// The images are loaded in that order ahead of the application, and control is
+/// transferred to the first image.
+pub const PAYLOAD_IMAGES_ARG: &str = "payload_images";
+
+/// Limits enforced on an application before any memory is allocated for it.
+static LIMITS: OnceCell<PayloadLimits> = OnceCell::new();
+
+/// Auxiliary vector entry types; see the System V ABI, AMD64 supplement.
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Arguments and environment passed to the application on its initial stack.
+#[derive(Default)]
+pub struct EntryArgs {
+    argv: Vec<String>,
+    envp: Vec<String>,
+}
+
+impl EntryArgs {
+    /// Collects the application arguments from the kernel command line.
+    ///
+    /// Returns `None` if there are no arguments meant for the application, in
+    /// which case the application is started without an initial stack layout.
+    pub fn from_kernel_args(kernel_args: &Args) -> Option<Self> {
+        let mut argv: Vec<(usize, &str)> = kernel_args
+            .with_prefix(ARGV_PREFIX)
+            .filter_map(|(position, value)| match position.parse() {
+                Ok(position) => Some((position, value)),
+                Err(_) => {
+                    log::warn!(
+                        "ignoring invalid application argument: {}{}",
+                        ARGV_PREFIX,
+                        position
+                    );
+                    None
+                }
+            })
+            .collect();
+        argv.sort_unstable_by_key(|(position, _)| *position);
+        let envp: Vec<String> = kernel_args
+            .with_prefix(ENV_PREFIX)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+
+        if argv.is_empty() && envp.is_empty() {
+            return None;
+        }
+        Some(Self { argv: argv.into_iter().map(|(_, value)| String::from(value)).collect(), envp })
+    }
+}
+
+/// Limits on the memory an application may request when it is loaded.
+#[derive(Clone, Copy, Debug)]
+pub struct PayloadLimits {
+    /// Maximum total size (in bytes) of the memory backing the loadable
+    /// segments.
+    max_size: u64,
+    /// Maximum number of loadable segments.
+    max_segments: usize,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self { max_size: DEFAULT_MAX_PAYLOAD_MIB << 20, max_segments: MAX_LOAD_SEGMENTS }
+    }
+}
+
+impl PayloadLimits {
+    /// Determines the limits from the kernel command line, falling back to the
+    /// defaults if no (valid) limits were given.
+    pub fn from_kernel_args(kernel_args: &Args) -> Self {
+        let mut limits = Self::default();
+        match kernel_args.get(MAX_PAYLOAD_MIB_ARG).map(str::parse::<u64>) {
+            Some(Ok(mib)) => match mib.checked_mul(1 << 20) {
+                Some(max_size) => limits.max_size = max_size,
+                None => log::warn!("ignoring too large {}: {}", MAX_PAYLOAD_MIB_ARG, mib),
+            },
+            Some(Err(err)) => log::warn!("ignoring invalid {}: {}", MAX_PAYLOAD_MIB_ARG, err),
+            None => {}
+        }
+        limits
+    }
+
+    /// Checks that the loadable segments described by the program headers fit
+    /// within the limits.
+    ///
+    /// The size of a segment is the memory it occupies once loaded (based on
+    /// `p_memsz`, not `p_filesz`), including the padding needed to align it to
+    /// 2 MiB pages.
+    fn check(&self, program_headers: &[ProgramHeader]) -> Result<()> {
+        let mut segments = 0;
+        let mut total_size: u64 = 0;
+        for phdr in program_headers.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+            segments += 1;
+            if segments > self.max_segments {
+                bail!("application has more than {} loadable segments", self.max_segments);
+            }
+            let size = (phdr.p_vaddr % Size2MiB::SIZE)
+                .checked_add(phdr.p_memsz)
+                .filter(|size| *size <= u64::MAX - Size2MiB::SIZE)
+                .map(|size| align_up(size, Size2MiB::SIZE))
+                .context("application segment size overflows")?;
+            total_size = total_size.checked_add(size).context("application size overflows")?;
+            if total_size > self.max_size {
+                bail!(
+                    "application segments need more than the maximum of {} bytes of memory",
+                    self.max_size
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the limits enforced on applications when they are loaded.
+///
+/// If this is not called, the default limits apply.
+pub fn set_limits(limits: PayloadLimits) {
+    if LIMITS.set(limits).is_err() {
+        log::warn!("payload limits have already been set");
+    }
+}
+
+/// Location of a payload image in guest-physical memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageLocation {
+    pub address: PhysAddr,
+    pub len: u64,
+}
+
+impl ImageLocation {
+    /// Returns the 2 MiB frames that contain the image.
+    pub fn frames(&self) -> PhysFrameRange<Size2MiB> {
+        PhysFrame::range(
+            PhysFrame::containing_address(self.address),
+            PhysFrame::containing_address(PhysAddr::new(align_up(
+                self.address.as_u64() + self.len,
+                Size2MiB::SIZE,
+            ))),
+        )
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u64, &'static str> {
+    let digits = value.strip_prefix("0x").ok_or("payload image location must be in hex")?;
+    u64::from_str_radix(digits, 16).map_err(|_| "invalid hex number in payload image location")
+}
+
+/// Parses the value of the `payload_images` kernel argument.
+///
+/// This doesn't allocate, so that it can be used before the kernel heap is set
+/// up.
+pub fn parse_image_locations(
+    arg: &str,
+) -> impl Iterator<Item = Result<ImageLocation, &'static str>> + '_ {
+    arg.split(',').filter(|location| !location.is_empty()).map(|location| {
+        let (address, len) =
+            location.split_once(':').ok_or("payload image location must be <address>:<length>")?;
+        let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+        if len == 0 {
+            return Err("payload image is empty");
+        }
+        // Make sure the frames containing the image are addressable as well.
+        address
+            .checked_add(len)
+            .and_then(|end| end.checked_add(Size2MiB::SIZE))
+            .and_then(|end| PhysAddr::try_new(end).ok())
+            .ok_or("payload image is outside of physical memory")?;
+        Ok(ImageLocation { address: PhysAddr::new(address), len })
+    })
+}
+
+/// Concatenates payload images for measurement.
+///
+/// Each image is preceded by its length as a little-endian `u64`, so that
+/// moving bytes from the end of one image to the start of the next changes the
+/// measurement.
+pub fn measured_images<'a>(images: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    images.into_iter().fold(Vec::new(), |mut measured, image| {
+        measured.extend_from_slice(&(image.len() as u64).to_le_bytes());
+        measured.extend_from_slice(image);
+        measured
+    })
+}
+
+/// Lays out a System V-style initial process stack at the top of `stack`.
+///
+/// `stack_top` is the virtual address just past the end of `stack`, as seen by
+/// the application. From the top of the stack downwards, the layout is:
+///   - the argument and environment strings, NUL-terminated
+///   - padding to keep the stack pointer 16-byte aligned
+///   - the auxiliary vector, terminated by `AT_NULL`
+///   - `envp` pointers, terminated by a null pointer
+///   - `argv` pointers, terminated by a null pointer
+///   - `argc`
+///
+/// Returns the initial stack pointer, which points to `argc`.
+fn build_initial_stack(
+    stack: &mut [u8],
+    stack_top: VirtAddr,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr> {
+    let stack_base = stack_top - stack.len() as u64;
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let mut cursor = stack.len().checked_sub(strings_size).context("initial stack too large")?;
+
+    // Copies a string to the top of the stack, returning its address.
+    let mut copy_string = |s: &str| {
+        stack[cursor..cursor + s.len()].copy_from_slice(s.as_bytes());
+        stack[cursor + s.len()] = 0;
+        let addr = stack_base + cursor as u64;
+        cursor += s.len() + 1;
+        addr.as_u64()
+    };
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv.iter().map(|s| copy_string(s)));
+    words.push(0);
+    words.extend(envp.iter().map(|s| copy_string(s)));
+    words.push(0);
+    for (key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(*key);
+        words.push(*value);
+    }
+
+    let words_size = words.len() * core::mem::size_of::<u64>();
+    let stack_pointer = stack
+        .len()
+        .checked_sub(strings_size + words_size)
+        .map(|offset| align_down(offset as u64, 16) as usize)
+        .context("initial stack too large")?;
+    for (chunk, word) in stack[stack_pointer..].array_chunks_mut::<8>().zip(words) {
+        *chunk = word.to_le_bytes();
+    }
+
+    Ok(stack_base + stack_pointer as u64)
+}
+
 self_cell!(
     /// Self-referential struct so that we don't have to parse the ELF file
     /// multiple times.
@@ -57,13 +336,31 @@ pub struct Application {
 impl Application {
     /// Attempts to parse the provided binary blob as an ELF file representing
     /// an Restricted Application.
+    ///
+    /// Fails if the application would exceed the payload limits once loaded.
     pub fn new(blob: Box<[u8]>) -> Result<Self> {
-        Ok(Application {
+        let application = Application {
             binary: Binary::try_new(blob, |boxed| {
                 goblin::elf::Elf::parse(boxed)
                     .map_err(|err| anyhow!("failed to parse ELF file: {}", err))
             })?,
-        })
+        };
+        elf::check_segments(application.program_headers())
+            .map_err(anyhow::Error::msg)
+            .context("invalid program headers")?;
+        elf::check_entry(
+            application.binary.borrow_dependent().entry,
+            application.program_headers(),
+        )
+        .map_err(anyhow::Error::msg)
+        .context("invalid entry point")?;
+        LIMITS
+            .get()
+            .copied()
+            .unwrap_or_default()
+            .check(application.program_headers())
+            .context("application exceeds payload limits")?;
+        Ok(application)
     }
 
     fn program_headers(&self) -> &ProgramHeaders {
@@ -74,11 +371,28 @@ impl Application {
         VirtAddr::new(self.binary.borrow_dependent().entry)
     }
 
+    /// Returns the virtual address ranges the loadable segments occupy once
+    /// loaded, rounded out to 2 MiB pages.
+    fn memory_ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.program_headers().iter().filter(|phdr| phdr.p_type == PT_LOAD).map(|phdr| {
+            align_down(phdr.p_vaddr, Size2MiB::SIZE)
+                ..align_up(phdr.p_vaddr.saturating_add(phdr.p_memsz), Size2MiB::SIZE)
+        })
+    }
+
     fn slice(&self, start: u64, limit: u64) -> &[u8] {
         &self.binary.borrow_owner()[start as usize..(start + limit) as usize]
     }
 
     fn load_segment(&self, phdr: &ProgramHeader) -> Result<()> {
+        if phdr.p_filesz > phdr.p_memsz {
+            bail!(
+                "segment at {:#x} has a file size ({:#x}) larger than its memory size ({:#x})",
+                phdr.p_vaddr,
+                phdr.p_filesz,
+                phdr.p_memsz
+            );
+        }
         // In Oak Restricted Kernel, we prefer 2 MiB pages, so round down the segment
         // address if it isn't aligned on a 2 MiB page boundary.
         let vaddr = VirtAddr::new(phdr.p_vaddr).align_down(Size2MiB::SIZE);
@@ -100,48 +414,186 @@ impl Application {
         // will allocate physical frames and sets up user-accessible page tables
         // for us. Note that the expectation here is that all the sections are
         // nicely 2 MiB-aligned, otherwise the mmap() will fail.
-        mmap(
+        // The memory is mapped writable so that we can copy the segment contents; we
+        // drop write access below.
+        let memory = mmap(
             Some(vaddr),
             size,
-            prot,
+            prot | MmapProtection::PROT_WRITE,
             MmapFlags::MAP_ANONYMOUS | MmapFlags::MAP_PRIVATE | MmapFlags::MAP_FIXED,
         )
         .expect("failed to allocate user memory");
 
         // Safety: we know the target memory is valid as we've just allocated it with
-        // mmap().
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                self.slice(phdr.p_offset, phdr.p_filesz).as_ptr(),
-                phdr.p_vaddr as *mut u8,
-                phdr.p_filesz as usize,
-            );
-        }
+        // mmap(); it starts at or below `p_vaddr` and extends `p_memsz` bytes past it.
+        let segment = unsafe {
+            core::slice::from_raw_parts_mut(phdr.p_vaddr as *mut u8, phdr.p_memsz as usize)
+        };
+        fill_segment(segment, self.slice(phdr.p_offset, phdr.p_filesz));
+
+        // Code and read-only data are mapped read-only, and writable data is mapped
+        // copy-on-write, so that the frames can be shared between instances of the
+        // same application.
+        let pages = Page::<Size2MiB>::range(
+            Page::containing_address(vaddr),
+            Page::containing_address(vaddr + memory.len()),
+        );
+        let pt_guard = PAGE_TABLES.lock();
+        let pt = pt_guard.get().context("failed to get page tables")?;
+        // Safety: the pages were just mapped by us, and the only writes the
+        // application may do to them go through the copy-on-write mechanism.
+        unsafe { share_read_only(pt, pages, prot.contains(MmapProtection::PROT_WRITE)) }
+            .map_err(anyhow::Error::msg)
+            .context("failed to remap application segment")?;
 
         Ok(())
     }
 
-    /// Maps the application into virtual memory and returns the entrypoint.
-    ///
-    /// # Safety
-    ///
-    /// The application must be built from a valid ELF file representing an Oak
-    /// Restricted Application.
-    pub(self) unsafe fn map_into_memory(&self) -> VirtAddr {
+    /// Maps the loadable segments of the application into virtual memory.
+    fn load(&self) -> Result<()> {
         for phdr in self.program_headers().iter().filter(|&phdr| phdr.p_type == PT_LOAD) {
-            self.load_segment(phdr).unwrap();
+            self.load_segment(phdr)?;
         }
+        Ok(())
+    }
+}
 
-        mmap(
-            Some(VirtAddr::new(APPLICATION_STACK_VIRT_ADDR) - Size2MiB::SIZE),
-            Size2MiB::SIZE as usize,
-            MmapProtection::PROT_READ | MmapProtection::PROT_WRITE,
-            MmapFlags::MAP_ANONYMOUS | MmapFlags::MAP_PRIVATE | MmapFlags::MAP_FIXED,
-        )
-        .expect("failed to allocate memory for user stack");
+/// Copies the file contents of a segment to the start of `segment`, which
+/// covers the whole segment in memory (`p_memsz` bytes), and zeroes the rest
+/// (the BSS).
+///
+/// The frames backing the segment may have been used before, so we can't rely
+/// on them being zeroed already: stale data left in the BSS would leak to the
+/// application.
+///
+/// Panics if `contents` is longer than `segment`.
+fn fill_segment(segment: &mut [u8], contents: &[u8]) {
+    let (data, bss) = segment.split_at_mut(contents.len());
+    data.copy_from_slice(contents);
+    bss.fill(0);
+}
+
+/// Checks that no two images would occupy the same virtual memory.
+fn check_no_collisions(applications: &[Application]) -> Result<()> {
+    for (i, first) in applications.iter().enumerate() {
+        for (j, second) in applications.iter().enumerate().skip(i + 1) {
+            for a in first.memory_ranges() {
+                if let Some(b) = second.memory_ranges().find(|b| a.start < b.end && b.start < a.end)
+                {
+                    bail!(
+                        "payload images {} and {} overlap at [{:#x}..{:#x})",
+                        i,
+                        j,
+                        max(a.start, b.start),
+                        min(a.end, b.end)
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the auxiliary vector for a process running the given images, with
+/// control being transferred to the first one.
+fn auxiliary_vector(applications: &[Application]) -> Vec<(u64, u64)> {
+    let mut auxv = vec![(AT_PAGESZ, Size2MiB::SIZE)];
+    let mut entries = applications.iter().map(Application::entry);
+    if let Some(entry) = entries.next() {
+        auxv.push((AT_ENTRY, entry.as_u64()));
+    }
+    auxv.extend(entries.map(|entry| (AT_OAK_PAYLOAD_IMAGE_ENTRY, entry.as_u64())));
+    auxv
+}
+
+/// Maps the images into virtual memory and returns the entrypoint of the first
+/// one and the initial stack pointer.
+///
+/// If `entry_args` are provided, or if there is more than one image, a System
+/// V-style initial stack is set up for the process.
+///
+/// # Safety
+///
+/// The applications must be built from valid ELF files representing Oak
+/// Restricted Applications.
+unsafe fn map_into_memory(
+    applications: &[Application],
+    entry_args: Option<&EntryArgs>,
+) -> Result<(VirtAddr, VirtAddr)> {
+    let first = applications.first().context("no payload images to load")?;
+    check_no_collisions(applications)?;
+    for application in applications {
+        application.load()?;
+    }
+
+    // The heap starts after the highest loadable segment, and may use whatever
+    // is left of the payload memory limit.
+    let segments = || applications.iter().flat_map(Application::memory_ranges);
+    let segments_end = segments().map(|range| range.end).max().unwrap_or_default();
+    let segments_size: u64 = segments().map(|range| range.end - range.start).sum();
+    let max_heap_size =
+        LIMITS.get().copied().unwrap_or_default().max_size.saturating_sub(segments_size);
+    crate::syscall::brk::init(crate::syscall::brk::ProgramBreak::new(
+        VirtAddr::new(segments_end),
+        max_heap_size,
+    ));
+
+    let stack = mmap(
+        Some(VirtAddr::new(APPLICATION_STACK_VIRT_ADDR) - Size2MiB::SIZE),
+        Size2MiB::SIZE as usize,
+        MmapProtection::PROT_READ | MmapProtection::PROT_WRITE,
+        MmapFlags::MAP_ANONYMOUS | MmapFlags::MAP_PRIVATE | MmapFlags::MAP_FIXED,
+    )
+    .expect("failed to allocate memory for user stack");
+
+    crate::vdso::map_into_current_process()
+        .map_err(anyhow::Error::msg)
+        .context("failed to map the vDSO page")?;
+    let report_staged = crate::attestation::staged::map_into_current_process()
+        .map_err(anyhow::Error::msg)
+        .context("failed to map the staged attestation report")?;
 
-        self.entry()
+    #[cfg(not(feature = "initrd"))]
+    let ramdisk = crate::ramdisk::map_into_current_process()
+        .map_err(anyhow::Error::msg)
+        .context("failed to map the data ramdisk")?;
+    // With the `initrd` feature, the ramdisk held the application itself.
+    #[cfg(feature = "initrd")]
+    let ramdisk: Option<(u64, u64)> = None;
+
+    let mut auxv = auxiliary_vector(applications);
+    if report_staged {
+        auxv.push((AT_OAK_ATTESTATION_REPORT, ATTESTATION_REPORT_ADDR));
+    }
+    if let Some((address, size)) = ramdisk {
+        auxv.push((AT_OAK_RAMDISK, address));
+        auxv.push((AT_OAK_RAMDISK_SIZE, size));
     }
+
+    // The entry points of the other images, the attestation report and the
+    // ramdisk are passed in the auxiliary vector, so we need an initial stack
+    // layout even if there are no arguments.
+    let no_args = EntryArgs::default();
+    let entry_args = entry_args
+        .or((applications.len() > 1 || report_staged || ramdisk.is_some()).then_some(&no_args));
+    let stack_pointer = match entry_args {
+        Some(entry_args) => {
+            let argv: Vec<&str> = entry_args.argv.iter().map(String::as_str).collect();
+            let envp: Vec<&str> = entry_args.envp.iter().map(String::as_str).collect();
+            build_initial_stack(
+                stack,
+                VirtAddr::new(APPLICATION_STACK_VIRT_ADDR),
+                &argv,
+                &envp,
+                &auxv,
+            )?
+        }
+        // Without an initial stack layout, the entry point is treated like a regular
+        // function that was just called, so account for the return address.
+        None => VirtAddr::new(APPLICATION_STACK_VIRT_ADDR - 8),
+    };
+
+    Ok((first.entry(), stack_pointer))
 }
 
 pub fn identify_pml4_frame(
@@ -162,16 +614,42 @@ pub fn identify_pml4_frame(
 pub struct Process {
     pml4: Pin<Box<x86_64::structures::paging::PageTable, alloc::alloc::Global>>,
     entry: VirtAddr,
+    stack_pointer: VirtAddr,
 }
 
 impl Process {
     /// Creates a process from the application, without executing it.
     ///
+    /// If `entry_args` are provided, they are passed to the application on its
+    /// initial stack.
+    ///
     /// # Safety
     ///
     /// The application must be built from a valid ELF file representing an Oak
     /// Restricted Application.
-    pub unsafe fn from_application(application: &Application) -> Result<Self, anyhow::Error> {
+    pub unsafe fn from_application(
+        application: &Application,
+        entry_args: Option<&EntryArgs>,
+    ) -> Result<Self, anyhow::Error> {
+        // Safety: the caller ensured the application is a valid ELF file.
+        unsafe { Self::from_applications(core::slice::from_ref(application), entry_args) }
+    }
+
+    /// Creates a process from several images, without executing it.
+    ///
+    /// All images are loaded into the same address space, and control is
+    /// transferred to the first one; the entry points of the others are passed
+    /// in the auxiliary vector as `AT_OAK_PAYLOAD_IMAGE_ENTRY`. Fails if the
+    /// images would overlap in virtual memory.
+    ///
+    /// # Safety
+    ///
+    /// The applications must be built from valid ELF files representing Oak
+    /// Restricted Applications.
+    pub unsafe fn from_applications(
+        applications: &[Application],
+        entry_args: Option<&EntryArgs>,
+    ) -> Result<Self, anyhow::Error> {
         let pml4 = crate::BASE_L4_PAGE_TABLE.get().context("base l4 table should be set")?.clone();
         // Load the process's page table, so the application can be loaded into its
         // memory. Hold onto the previous PT, so we can revert to it once the
@@ -184,9 +662,12 @@ impl Process {
                 .into_inner()
         };
 
-        // Safety: caller ensured the application is a valid ELF file representing an
-        // Oak Restricted Application.
-        let entry = unsafe { application.map_into_memory() };
+        // Safety: caller ensured the applications are valid ELF files representing
+        // Oak Restricted Applications.
+        // Loading the images and setting up the stack writes to user memory.
+        let (entry, stack_pointer) =
+            crate::smap::with_user_access(|| unsafe { map_into_memory(applications, entry_args) })
+                .context("failed to map application into memory")?;
 
         // We've mapped the memory into the process page tables. Let's revert to the
         // previous page table.
@@ -199,7 +680,7 @@ impl Process {
             unsafe { crate::PAGE_TABLES.lock().replace(pml4_frame) };
         }
 
-        Ok(Self { pml4, entry })
+        Ok(Self { pml4, entry, stack_pointer })
     }
     /// Executes the process.
     pub fn execute(&self) -> ! {
@@ -216,7 +697,7 @@ impl Process {
             asm! {
                 "mov rsp, {}", // user stack
                 "sysretq",
-                in(reg) APPLICATION_STACK_VIRT_ADDR - 8,
+                in(reg) self.stack_pointer.as_u64(),
                 in("rcx") entry.as_u64(), // initial RIP
                 in("r11") 0x202, // initial RFLAGS (interrupts enabled)
                 options(noreturn)
@@ -224,3 +705,230 @@ impl Process {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::ffi::CStr;
+
+    use super::*;
+
+    const STACK_TOP: u64 = 0x7000_0000;
+
+    fn read_word(stack: &[u8], addr: u64) -> u64 {
+        let offset = (addr - (STACK_TOP - stack.len() as u64)) as usize;
+        u64::from_le_bytes(stack[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn read_str(stack: &[u8], addr: u64) -> &str {
+        let offset = (addr - (STACK_TOP - stack.len() as u64)) as usize;
+        CStr::from_bytes_until_nul(&stack[offset..]).unwrap().to_str().unwrap()
+    }
+
+    fn load_segment(vaddr: u64, memsz: u64) -> ProgramHeader {
+        ProgramHeader { p_type: PT_LOAD, p_vaddr: vaddr, p_memsz: memsz, ..Default::default() }
+    }
+
+    #[test]
+    fn payload_within_limits() {
+        let limits = PayloadLimits { max_size: 4 * Size2MiB::SIZE, max_segments: 2 };
+        let phdrs = [load_segment(0x20_0000, 0x1000), load_segment(0x40_0000, 0x20_0000)];
+        assert!(limits.check(&phdrs).is_ok());
+    }
+
+    #[test]
+    fn payload_exceeds_size_limit() {
+        let limits = PayloadLimits { max_size: 4 * Size2MiB::SIZE, max_segments: 8 };
+        // The file size is small, but the segments need more memory than allowed.
+        let phdrs = [
+            ProgramHeader { p_filesz: 0x1000, ..load_segment(0x20_0000, 3 * Size2MiB::SIZE) },
+            ProgramHeader { p_filesz: 0x1000, ..load_segment(0x80_0000, 2 * Size2MiB::SIZE) },
+        ];
+        assert!(limits.check(&phdrs).is_err());
+        // Alignment padding counts towards the limit as well.
+        let phdrs = [load_segment(0x20_1000, 4 * Size2MiB::SIZE)];
+        assert!(limits.check(&phdrs).is_err());
+    }
+
+    #[test]
+    fn payload_size_overflow() {
+        let limits = PayloadLimits { max_size: u64::MAX, max_segments: 8 };
+        let phdrs = [load_segment(0x20_0000, u64::MAX - 0x1000)];
+        assert!(limits.check(&phdrs).is_err());
+        let phdrs = [load_segment(0x20_0000, 1 << 63), load_segment(0x40_0000, 1 << 63)];
+        assert!(limits.check(&phdrs).is_err());
+    }
+
+    #[test]
+    fn payload_exceeds_segment_limit() {
+        let limits = PayloadLimits { max_size: u64::MAX, max_segments: 2 };
+        let mut phdrs: Vec<ProgramHeader> =
+            (1..=3).map(|i| load_segment(i * Size2MiB::SIZE, 0x1000)).collect();
+        assert!(limits.check(&phdrs).is_err());
+        // Only loadable segments count.
+        phdrs[2].p_type = goblin::elf64::program_header::PT_NOTE;
+        assert!(limits.check(&phdrs).is_ok());
+    }
+
+    /// Builds a minimal ELF file with a single loadable segment.
+    fn synthetic_image(entry: u64, vaddr: u64, memsz: u64) -> Box<[u8]> {
+        // The file offset has to be congruent to the virtual address modulo the
+        // alignment.
+        let offset = vaddr % Size2MiB::SIZE;
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: ET_EXEC
+        elf.extend_from_slice(&0x3eu16.to_le_bytes()); // e_machine: x86-64
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&entry.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+        elf.extend_from_slice(&PT_LOAD.to_le_bytes()); // p_type
+        elf.extend_from_slice(&(PF_X | 4).to_le_bytes()); // p_flags: R+X
+        elf.extend_from_slice(&offset.to_le_bytes()); // p_offset
+        elf.extend_from_slice(&vaddr.to_le_bytes()); // p_vaddr
+        elf.extend_from_slice(&vaddr.to_le_bytes()); // p_paddr
+        elf.extend_from_slice(&0u64.to_le_bytes()); // p_filesz
+        elf.extend_from_slice(&memsz.to_le_bytes()); // p_memsz
+        elf.extend_from_slice(&Size2MiB::SIZE.to_le_bytes()); // p_align
+        elf.into_boxed_slice()
+    }
+
+    #[test]
+    fn chained_images() {
+        let images = [
+            Application::new(synthetic_image(0x20_0100, 0x20_0000, 0x1000)).unwrap(),
+            Application::new(synthetic_image(0x40_0200, 0x40_0000, 0x20_0000)).unwrap(),
+        ];
+
+        assert!(check_no_collisions(&images).is_ok());
+        // Both images are mapped, each at its own virtual address.
+        let ranges: Vec<_> = images.iter().flat_map(Application::memory_ranges).collect();
+        assert_eq!(ranges, [0x20_0000..0x40_0000, 0x40_0000..0x60_0000]);
+        // Control goes to the first image, the second is discoverable in the auxv.
+        assert_eq!(
+            auxiliary_vector(&images),
+            [
+                (AT_PAGESZ, Size2MiB::SIZE),
+                (AT_ENTRY, 0x20_0100),
+                (AT_OAK_PAYLOAD_IMAGE_ENTRY, 0x40_0200)
+            ]
+        );
+    }
+
+    #[test]
+    fn entry_outside_segments_rejected() {
+        assert!(Application::new(synthetic_image(0x20_0100, 0x20_0000, 0x1000)).is_ok());
+        assert!(Application::new(synthetic_image(0x20_1000, 0x20_0000, 0x1000)).is_err());
+        assert!(Application::new(synthetic_image(0x10_0000, 0x20_0000, 0x1000)).is_err());
+    }
+
+    #[test]
+    fn overlapping_images() {
+        let images = [
+            Application::new(synthetic_image(0x20_0000, 0x20_0000, 0x30_0000)).unwrap(),
+            Application::new(synthetic_image(0x40_1000, 0x40_1000, 0x1000)).unwrap(),
+        ];
+        assert!(check_no_collisions(&images).is_err());
+    }
+
+    #[test]
+    fn image_locations() {
+        let locations: Vec<_> =
+            parse_image_locations("0x8000000:0x12000,0xa234000:0x1000").collect();
+        assert_eq!(
+            locations,
+            [
+                Ok(ImageLocation { address: PhysAddr::new(0x800_0000), len: 0x12000 }),
+                Ok(ImageLocation { address: PhysAddr::new(0xa23_4000), len: 0x1000 }),
+            ]
+        );
+        let frames = locations[1].unwrap().frames();
+        assert_eq!(frames.start.start_address().as_u64(), 0xa20_0000);
+        assert_eq!(frames.end.start_address().as_u64(), 0xa40_0000);
+
+        assert_eq!(parse_image_locations("").count(), 0);
+        for invalid in ["0x1000", "1000:0x10", "0x1000:0x0", "0xffffffffffffff:0x10", "0x1:zz"] {
+            assert!(
+                parse_image_locations(invalid).next().unwrap().is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn initial_stack_layout() {
+        let mut stack = vec![0xFFu8; 4096];
+        let sp = build_initial_stack(
+            &mut stack,
+            VirtAddr::new(STACK_TOP),
+            &["app", "--verbose"],
+            &["RUST_LOG=debug"],
+            &[(AT_PAGESZ, 0x20_0000)],
+        )
+        .unwrap()
+        .as_u64();
+
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| read_word(&stack, sp + i * 8);
+        assert_eq!(word(0), 2);
+        assert_eq!(read_str(&stack, word(1)), "app");
+        assert_eq!(read_str(&stack, word(2)), "--verbose");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_str(&stack, word(4)), "RUST_LOG=debug");
+        assert_eq!(word(5), 0);
+        assert_eq!((word(6), word(7)), (AT_PAGESZ, 0x20_0000));
+        assert_eq!((word(8), word(9)), (AT_NULL, 0));
+        // The strings are stored above the auxiliary vector.
+        assert!(word(1) >= sp + 10 * 8);
+    }
+
+    #[test]
+    fn initial_stack_empty() {
+        let mut stack = vec![0xFFu8; 64];
+        let sp = build_initial_stack(&mut stack, VirtAddr::new(STACK_TOP), &[], &[], &[])
+            .unwrap()
+            .as_u64();
+
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| read_word(&stack, sp + i * 8);
+        // argc, argv terminator, envp terminator, AT_NULL.
+        assert_eq!([word(0), word(1), word(2), word(3), word(4)], [0; 5]);
+    }
+
+    #[test]
+    fn initial_stack_too_large() {
+        let mut stack = vec![0u8; 32];
+        assert!(build_initial_stack(
+            &mut stack,
+            VirtAddr::new(STACK_TOP),
+            &["a-very-long-argument-that-does-not-fit"],
+            &[],
+            &[],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn segment_bss_is_zeroed() {
+        // The frame backing the segment still holds data from a previous user.
+        let mut segment = vec![0xAA; 64];
+        let contents = [1; 16];
+        fill_segment(&mut segment, &contents);
+        assert_eq!(&segment[..16], &contents[..]);
+        assert!(segment[16..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn measured_images_are_length_prefixed() {
+        let measured = measured_images([&b"ab"[..], &b"c"[..]]);
+        assert_eq!(measured, [&2u64.to_le_bytes()[..], b"ab", &1u64.to_le_bytes(), b"c"].concat());
+        // The same bytes split differently must not measure the same.
+        assert_ne!(measured, measured_images([&b"a"[..], &b"bc"[..]]));
+    }
+}
@@ -0,0 +1,41 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Per-CPU data.
+//!
+//! We only ever run on the bootstrap processor, so there is exactly one
+//! instance of the per-CPU data. Once application processors are brought up,
+//! each of them will get its own instance, and `this_cpu` will have to look it
+//! up through a per-CPU register.
+
+/// Data that is private to a CPU.
+pub struct PerCpu {
+    /// Sequential id of the CPU; the bootstrap processor is CPU 0.
+    id: u32,
+}
+
+impl PerCpu {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+static BOOTSTRAP_CPU: PerCpu = PerCpu { id: 0 };
+
+/// Returns the data of the CPU we're running on.
+pub fn this_cpu() -> &'static PerCpu {
+    &BOOTSTRAP_CPU
+}
@@ -0,0 +1,142 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Data ramdisk staged by the VMM for the application.
+//!
+//! Unless the kernel is built to load the application itself from the
+//! ramdisk, the VMM can use the ramdisk for a read-only data blob the
+//! application needs, such as model weights. We copy the ramdisk into freshly
+//! zeroed frames, so that the application can't see whatever shares the 2 MiB
+//! frames the VMM staged it in, map the copy read-only into the application at
+//! `RAMDISK_ADDR` and announce its address and size in the auxiliary vector as
+//! `AT_OAK_RAMDISK` and `AT_OAK_RAMDISK_SIZE`.
+
+use alloc::vec::Vec;
+
+use oak_core::sync::OnceCell;
+use oak_crypto::noise_handshake::sha256;
+use oak_linux_boot_params::Ramdisk;
+use oak_restricted_kernel_interface::RAMDISK_ADDR;
+use x86_64::{
+    structures::paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    attestation,
+    mm::{self, Translator},
+};
+
+/// A ramdisk that has been measured and can be mapped into the application.
+struct StagedRamdisk {
+    /// Frames holding the copy of the ramdisk, starting at the beginning of the
+    /// first frame; the rest of the last frame is zero.
+    frames: Vec<PhysFrame<Size2MiB>>,
+    size: u64,
+    digest: [u8; 32],
+}
+
+static RAMDISK: OnceCell<StagedRamdisk> = OnceCell::new();
+
+/// Returns the number of 2 MiB frames needed to hold `size` bytes.
+fn frame_count(size: u64) -> usize {
+    size.div_ceil(Size2MiB::SIZE) as usize
+}
+
+/// Copies `contents` into `frame`, zeroing the rest of the frame.
+fn fill_frame(frame: PhysFrame<Size2MiB>, contents: &[u8]) -> Result<(), &'static str> {
+    let addr = mm::with_page_tables(|pt| pt.translate_physical(frame.start_address()))
+        .ok_or("couldn't translate the ramdisk frame")?;
+    // Safety: we just allocated the frame, so nothing else refers to it, and the
+    // direct mapping covers all of it.
+    let frame = unsafe {
+        core::slice::from_raw_parts_mut::<u8>(addr.as_mut_ptr(), Size2MiB::SIZE as usize)
+    };
+    let (data, slack) = frame.split_at_mut(contents.len());
+    data.copy_from_slice(contents);
+    slack.fill(0);
+    Ok(())
+}
+
+/// Measures the ramdisk and copies it for mapping into the application.
+///
+/// The frames containing the ramdisk must have been reserved when the frame
+/// allocator was set up (see `mm::init`). They stay reserved, as the VMM may
+/// have staged other data in the same frames.
+pub fn init(ramdisk: &Ramdisk) -> Result<(), &'static str> {
+    let contents =
+        mm::with_page_tables(|pt| pt.translate_physical(PhysAddr::new(ramdisk.addr.into())))
+            .ok_or("couldn't translate the ramdisk address")?;
+    // Safety: the VMM staged the ramdisk at this location, and we reserved the
+    // frames containing it before any memory was allocated.
+    let contents =
+        unsafe { core::slice::from_raw_parts::<u8>(contents.as_ptr(), ramdisk.size as usize) };
+    let digest = sha256(contents);
+    attestation::measurement_registers::measure(
+        attestation::measurement_registers::RAMDISK_REGISTER,
+        contents,
+    )?;
+    log::info!(
+        "Data ramdisk: {} bytes, digest (sha2-256): {}",
+        ramdisk.size,
+        digest.map(|x| alloc::format!("{:02x}", x)).join("")
+    );
+    let mut frames = Vec::new();
+    frames
+        .try_reserve_exact(frame_count(ramdisk.size.into()))
+        .map_err(|_| "couldn't allocate memory for the ramdisk frames")?;
+    for chunk in contents.chunks(Size2MiB::SIZE as usize) {
+        let frame = mm::with_frame_allocator(|fa| fa.allocate_frame())
+            .ok_or("couldn't allocate a frame for the ramdisk")?;
+        frames.push(frame);
+        fill_frame(frame, chunk)?;
+    }
+    RAMDISK
+        .set(StagedRamdisk { frames, size: ramdisk.size.into(), digest })
+        .map_err(|_| "ramdisk already initialized")
+}
+
+/// Returns the SHA2-256 digest of the ramdisk, if there is one.
+pub fn digest() -> Option<[u8; 32]> {
+    RAMDISK.get().map(|ramdisk| ramdisk.digest)
+}
+
+/// Maps the ramdisk into the address space of the current process.
+///
+/// Returns the address and size of the ramdisk in the application's address
+/// space; if there is no ramdisk, there's nothing to do.
+pub fn map_into_current_process() -> Result<Option<(u64, u64)>, &'static str> {
+    let Some(ramdisk) = RAMDISK.get() else {
+        return Ok(None);
+    };
+    for (i, frame) in ramdisk.frames.iter().enumerate() {
+        mm::map_user_read_only(VirtAddr::new(RAMDISK_ADDR + i as u64 * Size2MiB::SIZE), *frame)?;
+    }
+    Ok(Some((RAMDISK_ADDR, ramdisk.size)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_count_rounds_up() {
+        assert_eq!(frame_count(0), 0);
+        assert_eq!(frame_count(1), 1);
+        assert_eq!(frame_count(Size2MiB::SIZE), 1);
+        assert_eq!(frame_count(Size2MiB::SIZE + 0x1234), 2);
+    }
+}
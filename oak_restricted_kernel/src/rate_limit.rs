@@ -0,0 +1,300 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Throttling of the data sent by the payload over the host channel.
+//!
+//! The limit is enforced with a token bucket that is refilled, based on the
+//! TSC, at the configured rate. The bucket holds at most one second worth of
+//! tokens, which bounds the size of bursts.
+
+use alloc::boxed::Box;
+use core::cmp::min;
+
+use oak_channel::{Channel, ChannelError, Read, Write};
+use oak_core::timer::rdtsc;
+
+/// Kernel argument that limits the rate, in bytes per second, at which data
+/// can be sent over the channel.
+pub const CHANNEL_RATE_LIMIT_ARG: &str = "channel_rate_limit";
+
+/// Source of time for the rate limiter.
+pub trait Clock {
+    /// Returns the current time, in ticks.
+    fn now(&self) -> u64;
+
+    /// Returns the number of ticks per second.
+    fn ticks_per_second(&self) -> u64;
+
+    /// Waits until the clock reaches `deadline`, in ticks.
+    fn wait_until(&self, deadline: u64);
+}
+
+/// Clock based on the TSC.
+pub struct TscClock {
+    hz: u64,
+}
+
+impl TscClock {
+    pub fn new(hz: u64) -> Self {
+        Self { hz }
+    }
+}
+
+impl Clock for TscClock {
+    fn now(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn ticks_per_second(&self) -> u64 {
+        self.hz
+    }
+
+    fn wait_until(&self, deadline: u64) {
+        crate::interrupts::sleep_until(deadline);
+    }
+}
+
+struct TokenBucket {
+    /// Refill rate, in tokens (bytes) per second.
+    rate: u64,
+    /// The maximum number of tokens in the bucket.
+    capacity: u64,
+    tokens: u64,
+    /// The time up to which tokens have been added to the bucket.
+    refilled_at: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: u64) -> Self {
+        Self { rate, capacity: rate, tokens: rate, refilled_at: now }
+    }
+
+    fn refill(&mut self, now: u64, ticks_per_second: u64) {
+        let elapsed = now.saturating_sub(self.refilled_at) as u128;
+        let new_tokens = elapsed * self.rate as u128 / ticks_per_second as u128;
+        if self.tokens as u128 + new_tokens >= self.capacity as u128 {
+            self.tokens = self.capacity;
+            self.refilled_at = now;
+        } else if new_tokens > 0 {
+            self.tokens += new_tokens as u64;
+            // Only account for the time that was converted into whole tokens, so
+            // that the remainder is not lost.
+            self.refilled_at += (new_tokens * ticks_per_second as u128 / self.rate as u128) as u64;
+        }
+    }
+
+    /// Returns the time at which the bucket will hold `wanted` tokens.
+    fn ready_at(&self, wanted: u64, ticks_per_second: u64) -> u64 {
+        let missing = wanted.saturating_sub(self.tokens) as u128;
+        let ticks = (missing * ticks_per_second as u128).div_ceil(self.rate as u128);
+        self.refilled_at.saturating_add(ticks.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// Takes `wanted` tokens out of the bucket, if there are enough of them.
+    fn try_take(&mut self, wanted: u64) -> bool {
+        if self.tokens < wanted {
+            return false;
+        }
+        self.tokens -= wanted;
+        true
+    }
+}
+
+/// A channel that limits the rate at which data can be written to the
+/// underlying channel.
+///
+/// Writes that exceed the budget block until enough tokens are available.
+/// Writes larger than the capacity of the bucket are split into chunks of at
+/// most that size, so that the underlying channel doesn't see a flood of tiny
+/// writes while we are being throttled.
+pub struct RateLimitedChannel<'a, C> {
+    inner: Box<dyn Channel + 'a>,
+    bucket: TokenBucket,
+    clock: C,
+}
+
+impl<'a, C: Clock> RateLimitedChannel<'a, C> {
+    /// Wraps `inner`, limiting writes to `bytes_per_second`.
+    pub fn new(
+        inner: Box<dyn Channel + 'a>,
+        bytes_per_second: u64,
+        clock: C,
+    ) -> Result<Self, &'static str> {
+        if bytes_per_second == 0 {
+            return Err("channel rate limit must be positive");
+        }
+        if clock.ticks_per_second() == 0 {
+            return Err("clock frequency must be positive");
+        }
+        let bucket = TokenBucket::new(bytes_per_second, clock.now());
+        Ok(Self { inner, bucket, clock })
+    }
+}
+
+impl<C> Read for RateLimitedChannel<'_, C> {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        self.inner.read_exact(data)
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.inner.peer_closed()
+    }
+}
+
+impl<C: Clock> Write for RateLimitedChannel<'_, C> {
+    fn write_all(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            self.bucket.refill(self.clock.now(), self.clock.ticks_per_second());
+            let len = min(data.len() as u64, self.bucket.capacity);
+            if !self.bucket.try_take(len) {
+                self.clock.wait_until(self.bucket.ready_at(len, self.clock.ticks_per_second()));
+                continue;
+            }
+            let (chunk, rest) = data.split_at(len as usize);
+            self.inner.write_all(chunk)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn max_write_size(&self) -> usize {
+        self.inner.max_message_size()
+    }
+
+    fn control(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError> {
+        self.inner.ioctl(request, arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec::Vec};
+    use core::cell::Cell;
+
+    use spinning_top::Spinlock;
+
+    use super::*;
+
+    const TICKS_PER_SECOND: u64 = 1000;
+
+    /// Clock that only advances when we wait, jumping straight to the deadline.
+    struct MockClock {
+        now: Cell<u64>,
+    }
+
+    impl Clock for &MockClock {
+        fn now(&self) -> u64 {
+            self.now.get()
+        }
+
+        fn ticks_per_second(&self) -> u64 {
+            TICKS_PER_SECOND
+        }
+
+        fn wait_until(&self, deadline: u64) {
+            self.now.set(self.now.get().max(deadline));
+        }
+    }
+
+    /// Channel that records the time at which each write happened.
+    struct RecordingChannel<'a> {
+        clock: &'a MockClock,
+        writes: Arc<Spinlock<Vec<(u64, usize)>>>,
+    }
+
+    // Safety: the tests are single-threaded.
+    unsafe impl Send for RecordingChannel<'_> {}
+    unsafe impl Sync for RecordingChannel<'_> {}
+
+    impl Read for RecordingChannel<'_> {
+        fn read_exact(&mut self, _data: &mut [u8]) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("the recording channel is write-only"))
+        }
+    }
+
+    impl Write for RecordingChannel<'_> {
+        fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            self.writes.lock().push((self.clock.now.get(), data.len()));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn channel<'a>(
+        clock: &'a MockClock,
+        bytes_per_second: u64,
+    ) -> (RateLimitedChannel<'a, &'a MockClock>, Arc<Spinlock<Vec<(u64, usize)>>>) {
+        let writes = Arc::new(Spinlock::new(Vec::new()));
+        let inner = Box::new(RecordingChannel { clock, writes: writes.clone() });
+        (RateLimitedChannel::new(inner, bytes_per_second, clock).unwrap(), writes)
+    }
+
+    #[test]
+    fn burst_within_budget_is_not_throttled() {
+        let clock = MockClock { now: Cell::new(0) };
+        let (mut channel, writes) = channel(&clock, 100);
+        channel.write_all(&[0; 60]).unwrap();
+        channel.write_all(&[0; 40]).unwrap();
+        assert_eq!(*writes.lock(), [(0, 60), (0, 40)]);
+    }
+
+    #[test]
+    fn sends_are_throttled_to_rate() {
+        let clock = MockClock { now: Cell::new(0) };
+        let (mut channel, writes) = channel(&clock, 100);
+        // The first 100 bytes go out immediately, the rest have to wait for the
+        // bucket to be refilled.
+        channel.write_all(&[0; 300]).unwrap();
+        assert_eq!(
+            *writes.lock(),
+            [(0, 100), (TICKS_PER_SECOND, 100), (2 * TICKS_PER_SECOND, 100)]
+        );
+    }
+
+    #[test]
+    fn idle_time_does_not_exceed_capacity() {
+        let clock = MockClock { now: Cell::new(0) };
+        let (mut channel, writes) = channel(&clock, 100);
+        channel.write_all(&[0; 100]).unwrap();
+        // After being idle for a long time, only one second worth of data can be
+        // sent immediately.
+        clock.now.set(10 * TICKS_PER_SECOND);
+        channel.write_all(&[0; 150]).unwrap();
+        assert_eq!(
+            *writes.lock(),
+            [
+                (0, 100),
+                (10 * TICKS_PER_SECOND, 100),
+                (10 * TICKS_PER_SECOND + TICKS_PER_SECOND / 2, 50)
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_rate_is_rejected() {
+        let clock = MockClock { now: Cell::new(0) };
+        let writes = Arc::new(Spinlock::new(Vec::new()));
+        let inner = Box::new(RecordingChannel { clock: &clock, writes });
+        assert!(RateLimitedChannel::new(inner, 0, &clock).is_err());
+    }
+}
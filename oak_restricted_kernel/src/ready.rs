@@ -0,0 +1,111 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Checks that the kernel globals have been initialized before we hand control
+//! to the payload.
+//!
+//! Subsystems that run late (ACPI, attestation, channels, system calls) rely on
+//! globals set up by `start_kernel` in sequence. If the initialization order is
+//! ever broken we want a clear assertion naming the missing subsystem, rather
+//! than an `unwrap` on an empty `OnceCell` deep inside one of them.
+
+use oak_sev_guest::msr::SevStatus;
+
+use crate::{
+    ghcb::GHCB_PROTOCOL, memory::kernel_heap_range, snp::CPUID_PAGE, BASE_L4_PAGE_TABLE,
+    FRAME_ALLOCATOR, GUEST_HOST_HEAP, PAGE_TABLES,
+};
+
+/// Globals that need to be initialized before the kernel is ready.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, strum::Display)]
+pub enum Subsystem {
+    FrameAllocator,
+    PageTables,
+    BasePageTable,
+    GuestHostHeap,
+    KernelHeap,
+    Ghcb,
+    SnpPages,
+}
+
+/// Returns the first subsystem, in initialization order, that has not been
+/// initialized.
+fn check_ready(subsystems: impl IntoIterator<Item = (Subsystem, bool)>) -> Result<(), Subsystem> {
+    subsystems
+        .into_iter()
+        .find(|(_, initialized)| !initialized)
+        .map_or(Ok(()), |(subsystem, _)| Err(subsystem))
+}
+
+/// Asserts that all the globals required by the rest of the kernel have been
+/// initialized.
+///
+/// Panics, naming the missing subsystem, if any of them is not.
+pub fn kernel_ready(sev_status: SevStatus) {
+    let sev_es_enabled = sev_status.contains(SevStatus::SEV_ES_ENABLED);
+    let snp_enabled = sev_status.contains(SevStatus::SNP_ACTIVE);
+    let subsystems = [
+        (Subsystem::FrameAllocator, FRAME_ALLOCATOR.lock().num_valid_frames().0 > 0),
+        (Subsystem::PageTables, PAGE_TABLES.lock().get().is_some()),
+        (Subsystem::BasePageTable, BASE_L4_PAGE_TABLE.get().is_some()),
+        (Subsystem::GuestHostHeap, GUEST_HOST_HEAP.get().is_some()),
+        (Subsystem::KernelHeap, kernel_heap_range().is_some_and(|range| !range.is_empty())),
+        (Subsystem::Ghcb, !sev_es_enabled || GHCB_PROTOCOL.get().is_some()),
+        (Subsystem::SnpPages, !snp_enabled || CPUID_PAGE.get().is_some()),
+    ];
+    if let Err(subsystem) = check_ready(subsystems) {
+        panic!("kernel not ready: {} has not been initialized", subsystem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_initialized() {
+        assert_eq!(
+            check_ready([(Subsystem::FrameAllocator, true), (Subsystem::PageTables, true)]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reports_missing_subsystem() {
+        assert_eq!(
+            check_ready([
+                (Subsystem::FrameAllocator, true),
+                (Subsystem::PageTables, true),
+                (Subsystem::GuestHostHeap, false),
+                (Subsystem::KernelHeap, false),
+            ]),
+            Err(Subsystem::GuestHostHeap)
+        );
+    }
+
+    #[test]
+    fn guest_host_heap_unset() {
+        // The guest-host heap is never initialized in unit tests.
+        assert!(GUEST_HOST_HEAP.get().is_none());
+        assert_eq!(
+            check_ready([
+                (Subsystem::FrameAllocator, true),
+                (Subsystem::GuestHostHeap, GUEST_HOST_HEAP.get().is_some()),
+            ]),
+            Err(Subsystem::GuestHostHeap)
+        );
+    }
+}
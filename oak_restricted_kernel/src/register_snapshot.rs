@@ -0,0 +1,312 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Snapshots of the CPU register state, for post-mortem debugging of panics.
+
+use core::{arch::asm, fmt};
+
+use spinning_top::Spinlock;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+/// Snapshot of the general-purpose registers and the most relevant control
+/// registers.
+///
+/// The layout of this struct is relied upon by the assembly in
+/// `capture_registers`, so the order of the fields must not be changed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+/// General-purpose registers as pushed onto the stack by
+/// `save_registers_and_jump!`.
+#[repr(C)]
+pub struct SavedRegisters {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+}
+
+impl fmt::Display for RegisterSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:#018x} RDI={:#018x} RBP={:#018x} RSP={:#018x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:#018x} R9 ={:#018x} R10={:#018x} R11={:#018x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:#018x} R13={:#018x} R14={:#018x} R15={:#018x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        write!(
+            f,
+            "RIP={:#018x} RFL={:#018x} CR2={:#018x} CR3={:#018x}",
+            self.rip, self.rflags, self.cr2, self.cr3
+        )
+    }
+}
+
+/// Register state captured on entry to the most recent fatal exception
+/// handler, if any.
+static EXCEPTION_REGISTERS: Spinlock<Option<RegisterSnapshot>> = Spinlock::new(None);
+
+/// Stores the register values of the code that was running when an exception
+/// occurred.
+///
+/// Called from the assembly stub generated by `save_registers_and_jump!`,
+/// with pointers to the saved general-purpose registers and the interrupt
+/// stack frame.
+pub extern "C" fn record_exception_registers(
+    saved: &SavedRegisters,
+    frame: &InterruptStackFrameValue,
+) {
+    let (cr2, cr3) = read_control_registers();
+    let snapshot = RegisterSnapshot {
+        rax: saved.rax,
+        rbx: saved.rbx,
+        rcx: saved.rcx,
+        rdx: saved.rdx,
+        rsi: saved.rsi,
+        rdi: saved.rdi,
+        rbp: saved.rbp,
+        rsp: frame.stack_pointer.as_u64(),
+        r8: saved.r8,
+        r9: saved.r9,
+        r10: saved.r10,
+        r11: saved.r11,
+        r12: saved.r12,
+        r13: saved.r13,
+        r14: saved.r14,
+        r15: saved.r15,
+        rip: frame.instruction_pointer.as_u64(),
+        rflags: frame.cpu_flags,
+        cr2,
+        cr3,
+    };
+    // Don't risk deadlocking if we faulted while the lock was held.
+    if let Some(mut registers) = EXCEPTION_REGISTERS.try_lock() {
+        registers.replace(snapshot);
+    }
+}
+
+/// Returns the register state captured by the most recent fatal exception
+/// handler, if any.
+pub fn exception_registers() -> Option<RegisterSnapshot> {
+    EXCEPTION_REGISTERS.try_lock().and_then(|registers| *registers)
+}
+
+/// Captures the current register state.
+///
+/// `rip` and `rsp` refer to the location this function was called from.
+#[inline(never)]
+pub fn capture() -> RegisterSnapshot {
+    let mut snapshot = RegisterSnapshot::default();
+    // Safety: `capture_registers` only writes to the struct it was given a pointer
+    // to.
+    unsafe { capture_registers(&mut snapshot) };
+    snapshot
+}
+
+#[naked]
+unsafe extern "C" fn capture_registers(_snapshot: *mut RegisterSnapshot) {
+    asm! {
+        "mov %rax, 0(%rdi)",
+        "mov %rbx, 8(%rdi)",
+        "mov %rcx, 16(%rdi)",
+        "mov %rdx, 24(%rdi)",
+        "mov %rsi, 32(%rdi)",
+        "mov %rdi, 40(%rdi)",
+        "mov %rbp, 48(%rdi)",
+        "lea 8(%rsp), %rax",    // the caller's RSP, before pushing the return address
+        "mov %rax, 56(%rdi)",
+        "mov %r8, 64(%rdi)",
+        "mov %r9, 72(%rdi)",
+        "mov %r10, 80(%rdi)",
+        "mov %r11, 88(%rdi)",
+        "mov %r12, 96(%rdi)",
+        "mov %r13, 104(%rdi)",
+        "mov %r14, 112(%rdi)",
+        "mov %r15, 120(%rdi)",
+        "mov (%rsp), %rax",     // the return address
+        "mov %rax, 128(%rdi)",
+        "pushfq",
+        "pop %rax",
+        "mov %rax, 136(%rdi)",
+        "mov %cr2, %rax",
+        "mov %rax, 144(%rdi)",
+        "mov %cr3, %rax",
+        "mov %rax, 152(%rdi)",
+        "ret",
+        options(att_syntax, noreturn)
+    }
+}
+
+fn read_control_registers() -> (u64, u64) {
+    let cr2: u64;
+    let cr3: u64;
+    // Safety: reading CR2 and CR3 has no side effects.
+    unsafe {
+        asm!("mov %cr2, {}", out(reg) cr2, options(att_syntax, nomem, nostack, preserves_flags));
+        asm!("mov %cr3, {}", out(reg) cr3, options(att_syntax, nomem, nostack, preserves_flags));
+    }
+    (cr2, cr3)
+}
+
+/// Generates the body of a naked exception handler (for exceptions that push
+/// an error code) that records the register state using
+/// `record_exception_registers` and then jumps to `$inner`, with the stack
+/// and registers restored to the state they were in on entry.
+macro_rules! save_registers_and_jump {
+    ($inner:path) => {
+        core::arch::asm! {
+            "push %r15",
+            "push %r14",
+            "push %r13",
+            "push %r12",
+            "push %r11",
+            "push %r10",
+            "push %r9",
+            "push %r8",
+            "push %rbp",
+            "push %rdi",
+            "push %rsi",
+            "push %rdx",
+            "push %rcx",
+            "push %rbx",
+            "push %rax",
+            "mov %rsp, %rdi",       // first argument: the saved registers
+            "lea 128(%rsp), %rsi",  // second argument: the interrupt stack frame, after the error code
+            "sub $8, %rsp",         // align the stack to 16 bytes for the call
+            "call {record}",
+            "add $8, %rsp",
+            "pop %rax",
+            "pop %rbx",
+            "pop %rcx",
+            "pop %rdx",
+            "pop %rsi",
+            "pop %rdi",
+            "pop %rbp",
+            "pop %r8",
+            "pop %r9",
+            "pop %r10",
+            "pop %r11",
+            "pop %r12",
+            "pop %r13",
+            "pop %r14",
+            "pop %r15",
+            "jmp {inner}",
+            record = sym $crate::register_snapshot::record_exception_registers,
+            inner = sym $inner,
+            options(att_syntax, noreturn)
+        }
+    };
+}
+pub(crate) use save_registers_and_jump;
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use core::mem::{offset_of, size_of};
+
+    use super::*;
+
+    #[test]
+    fn layout_matches_assembly() {
+        assert_eq!(offset_of!(RegisterSnapshot, rsp), 56);
+        assert_eq!(offset_of!(RegisterSnapshot, r15), 120);
+        assert_eq!(offset_of!(RegisterSnapshot, rip), 128);
+        assert_eq!(offset_of!(RegisterSnapshot, cr3), 152);
+        assert_eq!(size_of::<SavedRegisters>(), 15 * 8);
+        assert_eq!(offset_of!(SavedRegisters, r8), 56);
+    }
+
+    #[test]
+    fn format_snapshot() {
+        let snapshot = RegisterSnapshot {
+            rax: 0x1,
+            rbx: 0x2,
+            rcx: 0x3,
+            rdx: 0x4,
+            rsi: 0x5,
+            rdi: 0x6,
+            rbp: 0x7,
+            rsp: 0x8,
+            r8: 0x9,
+            r9: 0xa,
+            r10: 0xb,
+            r11: 0xc,
+            r12: 0xd,
+            r13: 0xe,
+            r14: 0xf,
+            r15: 0x10,
+            rip: 0xffff_ffff_8000_1234,
+            rflags: 0x246,
+            cr2: 0xdead_b000,
+            cr3: 0x1000,
+        };
+        assert_eq!(
+            format!("{}", snapshot),
+            "RAX=0x0000000000000001 RBX=0x0000000000000002 RCX=0x0000000000000003 RDX=0x0000000000000004\n\
+             RSI=0x0000000000000005 RDI=0x0000000000000006 RBP=0x0000000000000007 RSP=0x0000000000000008\n\
+             R8 =0x0000000000000009 R9 =0x000000000000000a R10=0x000000000000000b R11=0x000000000000000c\n\
+             R12=0x000000000000000d R13=0x000000000000000e R14=0x000000000000000f R15=0x0000000000000010\n\
+             RIP=0xffffffff80001234 RFL=0x0000000000000246 CR2=0x00000000deadb000 CR3=0x0000000000001000"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Random number generation for the kernel and the payload.
+//!
+//! We prefer the hardware random number generator (`RDRAND`, or `RDSEED` if
+//! only that is available). Some SEV-ES/SNP VMs hide both via CPUID; in that
+//! case we fall back to a ChaCha20 DRBG that is seeded, and periodically
+//! reseeded, from attestation reports requested over the SNP guest message
+//! protocol: the report signature uses a fresh nonce chosen by the Secure
+//! Processor, and the response is encrypted, so the hypervisor can't observe
+//! it. Without SEV-SNP there is no trustworthy seed, so we fail.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+
+use oak_core::{sync::OnceCell, timer::rdtsc};
+use oak_crypto::noise_handshake::sha256_two_part;
+use spinning_top::Spinlock;
+
+use crate::attestation::{guest_request, REPORT_DATA_SIZE};
+
+/// Number of times we retry `RDRAND`/`RDSEED` before giving up, as recommended
+/// by Intel.
+const HARDWARE_RETRIES: usize = 10;
+
+/// Size of the seed of <ChaCha20Drbg>, in bytes.
+pub const SEED_SIZE: usize = 32;
+
+/// Size of the nonce of <ChaCha20Drbg>, in bytes.
+pub const NONCE_SIZE: usize = 12;
+
+/// Domain separation label for deriving DRBG seeds from attestation report
+/// signatures, so that the seed is never the same value as anything else
+/// derived from the signature.
+const SEED_DOMAIN: &[u8] = b"Oak Restricted Kernel DRBG seed v1";
+
+/// Number of bytes the DRBG generates before it is reseeded.
+const RESEED_INTERVAL: u64 = 16 << 20;
+
+/// The "expand 32-byte k" constant of ChaCha20.
+const CHACHA20_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// Size of a ChaCha20 block, in bytes.
+const BLOCK_SIZE: usize = 64;
+
+/// The sources of random numbers, in order of preference.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    Rdrand,
+    Rdseed,
+    /// The ChaCha20 DRBG seeded via the SNP guest message protocol.
+    SnpSeededDrbg,
+}
+
+/// Picks the best available source of random numbers.
+fn select_source(rdrand: bool, rdseed: bool, sev_snp_enabled: bool) -> Option<Source> {
+    if rdrand {
+        Some(Source::Rdrand)
+    } else if rdseed {
+        Some(Source::Rdseed)
+    } else if sev_snp_enabled {
+        Some(Source::SnpSeededDrbg)
+    } else {
+        None
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function, as specified in RFC 8439, section 2.3.
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; BLOCK_SIZE] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CHACHA20_CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        // Column rounds.
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        // Diagonal rounds.
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; BLOCK_SIZE];
+    for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0u32; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// Deterministic random bit generator based on the ChaCha20 keystream.
+///
+/// Uses fast key erasure: every request starts a fresh keystream, the first
+/// 32 bytes of which replace the key before any output is returned, so a
+/// later compromise of the state doesn't reveal earlier output.
+pub struct ChaCha20Drbg {
+    key: [u32; 8],
+    nonce: [u32; 3],
+    /// Number of bytes generated since the DRBG was last (re)seeded.
+    generated: u64,
+}
+
+impl ChaCha20Drbg {
+    pub fn new(seed: &[u8; SEED_SIZE], nonce: &[u8; NONCE_SIZE]) -> Self {
+        Self { key: words(seed), nonce: words(nonce), generated: 0 }
+    }
+
+    /// Mixes fresh seed material into the key.
+    pub fn reseed(&mut self, seed: &[u8; SEED_SIZE]) {
+        for (key, seed) in self.key.iter_mut().zip(words::<8>(seed)) {
+            *key ^= seed;
+        }
+        self.generated = 0;
+    }
+
+    /// Whether the DRBG has generated enough output that it should be
+    /// reseeded.
+    pub fn needs_reseed(&self) -> bool {
+        self.generated >= RESEED_INTERVAL
+    }
+
+    pub fn fill_bytes(&mut self, dst: &mut [u8]) {
+        let first = chacha20_block(&self.key, 0, &self.nonce);
+        let (next_key, rest) = first.split_at(SEED_SIZE);
+        let (head, tail) = dst.split_at_mut(dst.len().min(rest.len()));
+        head.copy_from_slice(&rest[..head.len()]);
+        for (counter, chunk) in (1..).zip(tail.chunks_mut(BLOCK_SIZE)) {
+            let block = chacha20_block(&self.key, counter, &self.nonce);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.key = words(next_key);
+        self.generated = self.generated.saturating_add(dst.len() as u64);
+    }
+}
+
+static SOURCE: OnceCell<Source> = OnceCell::new();
+
+static DRBG: Spinlock<Option<ChaCha20Drbg>> = Spinlock::new(None);
+
+/// Requests fresh seed material from the Secure Processor.
+fn snp_seed() -> Result<[u8; SEED_SIZE], &'static str> {
+    // The report data doesn't matter, but we might as well make it unique.
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    report_data[..8].copy_from_slice(&rdtsc().to_le_bytes());
+    let report = guest_request::request_report(&report_data)?;
+    Ok(seed_from_signature_r(&report.signature.r))
+}
+
+/// Derives a DRBG seed from the R component of a report signature, which is
+/// derived from the nonce, i.e. the part that changes with every report.
+///
+/// R is a point coordinate rather than a uniformly random string, so we hash
+/// all of it instead of using its bytes directly.
+fn seed_from_signature_r(r: &[u8]) -> [u8; SEED_SIZE] {
+    sha256_two_part(SEED_DOMAIN, r)
+}
+
+/// Selects the source of random numbers, seeding the DRBG if that's what we
+/// end up with.
+///
+/// The guest message protocol must already be initialized if SEV-SNP is
+/// enabled, in case we have to fall back to the DRBG.
+pub fn init(sev_snp_enabled: bool) -> Result<Source, &'static str> {
+    // Safety: CPUID leaves 1 and 7 are available on every x86-64 CPU we support.
+    let rdrand = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+    let rdseed = unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0;
+    let source = select_source(rdrand, rdseed, sev_snp_enabled)
+        .ok_or("no source of random numbers: RDRAND and RDSEED are not available")?;
+    if source == Source::SnpSeededDrbg {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&rdtsc().to_le_bytes());
+        DRBG.lock().replace(ChaCha20Drbg::new(&snp_seed()?, &nonce));
+        log::warn!("RDRAND and RDSEED are not available, using a ChaCha20 DRBG seeded via SEV-SNP");
+    } else {
+        log::info!("Using {:?} as the source of random numbers", source);
+    }
+    SOURCE.set(source).map_err(|_| "random number source already initialized")?;
+    Ok(source)
+}
+
+fn hardware_u64(step: unsafe fn(&mut u64) -> i32) -> Result<u64, &'static str> {
+    let mut value = 0;
+    for _ in 0..HARDWARE_RETRIES {
+        // Safety: the CPU supports the instruction, as checked in `init`, and we
+        // check whether it succeeded.
+        if unsafe { step(&mut value) } == 1 {
+            return Ok(value);
+        }
+    }
+    Err("hardware random number generator failed to return a value")
+}
+
+/// Fills `dst` with random words from the selected source.
+pub fn fill_u64(dst: &mut [u64]) -> Result<(), &'static str> {
+    match SOURCE.get().ok_or("random number source not initialized")? {
+        Source::Rdrand => dst.iter_mut().try_for_each(|word| {
+            *word = hardware_u64(_rdrand64_step)?;
+            Ok(())
+        }),
+        Source::Rdseed => dst.iter_mut().try_for_each(|word| {
+            *word = hardware_u64(_rdseed64_step)?;
+            Ok(())
+        }),
+        Source::SnpSeededDrbg => {
+            let mut guard = DRBG.lock();
+            let drbg = guard.as_mut().ok_or("DRBG not seeded")?;
+            if drbg.needs_reseed() {
+                // Keep going with the old key if this fails; it's still secret.
+                match snp_seed() {
+                    Ok(seed) => drbg.reseed(&seed),
+                    Err(err) => log::warn!("couldn't reseed the DRBG: {}", err),
+                }
+            }
+            // Every call starts a fresh keystream, so generate all the words in one go
+            // rather than spending a block on each of them.
+            drbg.fill_bytes(<[u64] as zerocopy::AsBytes>::as_bytes_mut(dst));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &str) -> alloc::vec::Vec<u8> {
+        hex::decode(bytes).unwrap()
+    }
+
+    #[test]
+    fn chacha20_block_test_vector() {
+        // RFC 8439, section 2.3.2.
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce = words(&hex("000000090000004a00000000"));
+        let block = chacha20_block(&words(&key), 1, &nonce);
+        assert_eq!(
+            block[..],
+            hex("10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+                 d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e")[..]
+        );
+    }
+
+    #[test]
+    fn seed_is_domain_separated_hash_of_r() {
+        let r: [u8; 72] = core::array::from_fn(|i| i as u8);
+        // SHA-256("Oak Restricted Kernel DRBG seed v1" || r)
+        assert_eq!(
+            seed_from_signature_r(&r)[..],
+            hex("453d15240b68e1be5970ead89ef28b13a61e1d0561ac10021c92972198bb7a1d")[..]
+        );
+    }
+
+    #[test]
+    fn drbg_known_seed() {
+        let mut drbg = ChaCha20Drbg::new(&[0; SEED_SIZE], &[0; NONCE_SIZE]);
+        // The first output is the second half of the all-zero keystream block (RFC
+        // 8439, appendix A.1, test vector #1); the first half becomes the next key.
+        let mut output = [0u8; 32];
+        drbg.fill_bytes(&mut output);
+        assert_eq!(
+            output[..],
+            hex("da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586")[..]
+        );
+        drbg.fill_bytes(&mut output);
+        assert_eq!(
+            output[..],
+            hex("afbdad2845b93cdbb2fe6463d2fe162adae0f6e676f0494218f5ce0596e79f5c")[..]
+        );
+    }
+
+    #[test]
+    fn drbg_output_spans_blocks() {
+        let mut drbg = ChaCha20Drbg::new(&[0; SEED_SIZE], &[0; NONCE_SIZE]);
+        let mut output = [0u8; 100];
+        drbg.fill_bytes(&mut output);
+        assert_eq!(
+            output[..],
+            hex("da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586\
+                 9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+                 29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f\
+                 2d09a0e6")[..]
+        );
+        assert!(!drbg.needs_reseed());
+    }
+
+    #[test]
+    fn drbg_reseed() {
+        let mut drbg = ChaCha20Drbg::new(&[0; SEED_SIZE], &[0; NONCE_SIZE]);
+        let mut reseeded = ChaCha20Drbg::new(&[0; SEED_SIZE], &[0; NONCE_SIZE]);
+        reseeded.generated = RESEED_INTERVAL;
+        assert!(reseeded.needs_reseed());
+        reseeded.reseed(&[1; SEED_SIZE]);
+        assert!(!reseeded.needs_reseed());
+
+        let mut before = [0u8; 32];
+        drbg.fill_bytes(&mut before);
+        let mut after = [0u8; 32];
+        reseeded.fill_bytes(&mut after);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn source_selection_order() {
+        assert_eq!(select_source(true, true, true), Some(Source::Rdrand));
+        assert_eq!(select_source(false, true, true), Some(Source::Rdseed));
+        assert_eq!(select_source(false, false, true), Some(Source::SnpSeededDrbg));
+        assert_eq!(select_source(false, false, false), None);
+    }
+}
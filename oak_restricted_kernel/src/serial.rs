@@ -14,25 +14,308 @@
 // limitations under the License.
 //
 
+use core::sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering};
+
 use atomic_refcell::AtomicRefCell;
+use oak_sev_guest::io::{PortReader, PortWriter};
 use uart_16550::SerialPort;
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+/// Kernel argument that selects the serial port to use for the channel, as an
+/// index into the ports found by [`available_ports`].
+pub const SERIAL_INDEX_ARG: &str = "serial_index";
+
+/// Kernel argument that selects how the channel receives data: `poll` (the
+/// default) busy-waits on the line status register, `interrupt` waits for the
+/// UART's received-data interrupt.
+pub const SERIAL_RX_ARG: &str = "serial_rx";
 
 pub struct Serial {
     port: AtomicRefCell<SerialPort>,
+    /// Whether received bytes are delivered to `RX_RING` by the interrupt
+    /// handler, rather than read from the UART directly.
+    interrupt_rx: bool,
+}
+
+/// Base I/O port for the second serial port in the system (colloquially known
+/// as COM2). Our contract with the loader requires it to be available.
+pub const COM2_BASE: u16 = 0x2f8;
+
+/// Base I/O ports of the standard serial ports, COM1 to COM4.
+const COM_BASES: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+/// Offset of the receiver buffer register from the base I/O port of a UART.
+const RECEIVER_BUFFER_OFFSET: u16 = 0;
+
+/// Offset of the interrupt enable register from the base I/O port of a UART.
+const INTERRUPT_ENABLE_OFFSET: u16 = 1;
+
+/// Offset of the modem control register from the base I/O port of a UART.
+const MODEM_CONTROL_OFFSET: u16 = 4;
+
+/// Offset of the line status register from the base I/O port of a UART.
+const LINE_STATUS_OFFSET: u16 = 5;
+
+/// Offset of the scratch register from the base I/O port of a UART.
+const SCRATCH_REGISTER_OFFSET: u16 = 7;
+
+/// Interrupt enable register bit for the received-data-available interrupt.
+const RECEIVED_DATA_INTERRUPT: u8 = 1 << 0;
+
+/// Modem control register bits for DTR, RTS and OUT2. On PCs, OUT2 gates the
+/// UART's interrupt line.
+const MODEM_CONTROL_DTR_RTS_OUT2: u8 = 0x0B;
+
+/// Line status register bit that is set when there is a byte to be read.
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+
+/// Returns the ISA IRQ that the standard serial port at `base` uses.
+fn isa_irq(base: u16) -> Option<u32> {
+    match base {
+        0x3f8 | 0x3e8 => Some(4),
+        0x2f8 | 0x2e8 => Some(3),
+        _ => None,
+    }
+}
+
+/// Size of the buffer for bytes received by the interrupt handler.
+const RX_RING_SIZE: usize = 256;
+
+/// Single-producer, single-consumer ring of received bytes.
+///
+/// The interrupt handler is the only producer and the channel the only
+/// consumer, so no locking is needed; in particular, the interrupt handler
+/// can never deadlock on a lock held by the code it interrupted.
+struct RxRing {
+    buffer: [AtomicU8; RX_RING_SIZE],
+    /// Number of bytes pushed so far, modulo 2^64.
+    head: AtomicUsize,
+    /// Number of bytes popped so far, modulo 2^64.
+    tail: AtomicUsize,
+    /// Number of bytes dropped because the ring was full.
+    dropped: AtomicUsize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU8 = AtomicU8::new(0);
+        Self {
+            buffer: [ZERO; RX_RING_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds a byte to the ring, dropping it if the ring is full.
+    fn push(&self, byte: u8) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == RX_RING_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.buffer[head % RX_RING_SIZE].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Removes the oldest byte from the ring.
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.buffer[tail % RX_RING_SIZE].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Relaxed) == self.head.load(Ordering::Acquire)
+    }
+}
+
+/// The bytes received by [`serial_rx_handler`].
+static RX_RING: RxRing = RxRing::new();
+
+/// Base I/O port of the UART that [`serial_rx_handler`] reads from, or zero if
+/// interrupt-driven receive hasn't been set up.
+static RX_BASE: AtomicU16 = AtomicU16::new(0);
+
+/// Interrupt vector that [`serial_rx_handler`] is installed for.
+static RX_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// The receive side of a UART.
+trait UartRx {
+    /// Returns the next received byte, if there is one.
+    fn try_receive(&mut self) -> Option<u8>;
+}
+
+/// The receive registers of the UART at a given base I/O port.
+struct UartRxPorts {
+    line_status: Port<u8>,
+    receiver_buffer: Port<u8>,
 }
 
-// Base I/O port for the second serial port in the system (colloquially known as
-// COM2)
-static COM2_BASE: u16 = 0x2f8;
+impl UartRxPorts {
+    fn new(base: u16) -> Self {
+        Self {
+            line_status: Port::new(base + LINE_STATUS_OFFSET),
+            receiver_buffer: Port::new(base + RECEIVER_BUFFER_OFFSET),
+        }
+    }
+}
+
+impl UartRx for UartRxPorts {
+    fn try_receive(&mut self) -> Option<u8> {
+        // Safety: the ports belong to the UART set up for interrupt-driven
+        // receive, which only the interrupt handler reads from.
+        unsafe {
+            if self.line_status.read() & LINE_STATUS_DATA_READY == 0 {
+                return None;
+            }
+            Some(self.receiver_buffer.read())
+        }
+    }
+}
+
+/// Moves all bytes the UART has received into `ring`.
+///
+/// The UART keeps its interrupt asserted until all data has been read, and the
+/// interrupt is edge-triggered, so we have to read everything even if the
+/// ring is full.
+fn drain_uart<U: UartRx>(uart: &mut U, ring: &RxRing) {
+    while let Some(byte) = uart.try_receive() {
+        ring.push(byte);
+    }
+}
+
+/// Returns the next byte from `ring`, calling `park` to wait while the ring is
+/// empty.
+fn receive_byte(ring: &RxRing, mut park: impl FnMut(&RxRing)) -> u8 {
+    loop {
+        if let Some(byte) = ring.pop() {
+            return byte;
+        }
+        park(ring);
+    }
+}
+
+/// Halts the CPU until the next interrupt, unless `ring` has become non-empty
+/// in the meantime.
+fn wait_for_byte(ring: &RxRing) {
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+    if ring.is_empty() {
+        // `sti; hlt` doesn't let an interrupt in between the two instructions,
+        // so a byte arriving after the check still wakes us up.
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+    if enabled {
+        x86_64::instructions::interrupts::enable();
+    } else {
+        x86_64::instructions::interrupts::disable();
+    }
+}
+
+extern "x86-interrupt" fn serial_rx_handler(stack_frame: InterruptStackFrame) {
+    crate::smap::deny_user_access();
+    crate::interrupts::count_interrupt(RX_VECTOR.load(Ordering::Relaxed));
+    crate::interrupts::debug_check_stack_alignment(&stack_frame);
+    let base = RX_BASE.load(Ordering::Acquire);
+    if base != 0 {
+        drain_uart(&mut UartRxPorts::new(base), &RX_RING);
+    }
+    crate::interrupts::end_of_interrupt();
+}
+
+/// Sets up the received-data interrupt of the UART at `base`.
+///
+/// # Safety
+///
+/// The caller has to guarantee that there is a UART at `base` that has been
+/// initialized.
+unsafe fn enable_rx_interrupt(base: u16) -> Result<(), &'static str> {
+    if !crate::interrupts::x2apic_enabled() {
+        return Err("the local APIC is not in x2APIC mode");
+    }
+    let gsi = isa_irq(base).ok_or("not a standard serial port")?;
+    let vector = crate::interrupts::irq_vector(gsi).ok_or("no vector available for the IRQ")?;
+    RX_BASE
+        .compare_exchange(0, base, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| "interrupt-driven receive is already set up for another port")?;
+    RX_VECTOR.store(vector, Ordering::Relaxed);
+    let result = crate::interrupts::register_irq(vector, serial_rx_handler)
+        .and_then(|()| crate::interrupts::enable_irq(gsi));
+    if result.is_err() {
+        RX_BASE.store(0, Ordering::Release);
+        return result;
+    }
+    Port::<u8>::new(base + MODEM_CONTROL_OFFSET).write(MODEM_CONTROL_DTR_RTS_OUT2);
+    Port::<u8>::new(base + INTERRUPT_ENABLE_OFFSET).write(RECEIVED_DATA_INTERRUPT);
+    Ok(())
+}
 
 impl Serial {
-    pub fn new() -> Serial {
-        // Our contract with the loader requires the second serial port to be
-        // available, so assuming the loader adheres to it, this is safe.
-        let mut port = unsafe { SerialPort::new(COM2_BASE) };
+    /// Opens the serial port with the given base I/O port.
+    ///
+    /// # Safety
+    ///
+    /// The caller has to guarantee that there is a UART at `base`, for example
+    /// by getting the port from [`available_ports`].
+    pub unsafe fn new_at(base: u16) -> Serial {
+        let mut port = SerialPort::new(base);
         port.init();
-        Serial { port: AtomicRefCell::new(port) }
+        // The UART may have been left with interrupts enabled; we poll unless
+        // interrupt-driven receive is set up explicitly.
+        Port::<u8>::new(base + INTERRUPT_ENABLE_OFFSET).write(0);
+        Serial { port: AtomicRefCell::new(port), interrupt_rx: false }
     }
+
+    /// Switches to receiving data through the UART's received-data interrupt,
+    /// falling back to polling if the interrupt can't be set up.
+    ///
+    /// # Safety
+    ///
+    /// The caller has to guarantee that `base` is the base I/O port the serial
+    /// port was opened with.
+    pub unsafe fn with_interrupt_rx(mut self, base: u16) -> Serial {
+        match enable_rx_interrupt(base) {
+            Ok(()) => self.interrupt_rx = true,
+            Err(err) => log::warn!("falling back to polling the serial port: {}", err),
+        }
+        self
+    }
+}
+
+/// Checks whether there is a UART behind `scratch` by writing test patterns
+/// to its scratch register and reading them back. Reads from ports that don't
+/// exist return all ones, so the patterns don't survive the round trip.
+///
+/// The original value of the scratch register is restored afterwards.
+///
+/// # Safety
+///
+/// Writing to the port must not have any adverse effects.
+unsafe fn scratch_register_present<P: PortReader<u8> + PortWriter<u8>>(scratch: &mut P) -> bool {
+    let Ok(original) = scratch.try_read() else {
+        return false;
+    };
+    let present = [0x55, 0xAA]
+        .into_iter()
+        .all(|pattern| scratch.try_write(pattern).is_ok() && scratch.try_read() == Ok(pattern));
+    let _ = scratch.try_write(original);
+    present
+}
+
+/// Returns the base I/O ports of the standard serial ports that are present.
+pub fn available_ports() -> impl Iterator<Item = u16> {
+    COM_BASES.into_iter().filter(|base| {
+        let mut scratch = Port::<u8>::new(base + SCRATCH_REGISTER_OFFSET);
+        // Safety: these are the standard locations for serial ports, and the
+        // scratch register has no side effects.
+        unsafe { scratch_register_present(&mut scratch) }
+    })
 }
 
 impl oak_channel::Write for Serial {
@@ -46,10 +329,22 @@ impl oak_channel::Write for Serial {
     fn flush(&mut self) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// There is no buffer to overflow: the UART is a byte stream, and
+    /// `send_raw` waits for the transmitter to be ready before every byte.
+    fn max_write_size(&self) -> usize {
+        usize::MAX
+    }
 }
 
 impl oak_channel::Read for Serial {
     fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        if self.interrupt_rx {
+            for byte in data.iter_mut() {
+                *byte = receive_byte(&RX_RING, wait_for_byte);
+            }
+            return Ok(());
+        }
         #[allow(clippy::needless_range_loop)]
         for i in 0..data.len() {
             data[i] = self.port.borrow_mut().receive();
@@ -57,3 +352,124 @@ impl oak_channel::Read for Serial {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A port that either behaves like a scratch register, or reads as all ones
+    /// like a port with nothing behind it.
+    struct MockPort {
+        present: bool,
+        value: u8,
+    }
+
+    impl PortReader<u8> for MockPort {
+        unsafe fn try_read(&mut self) -> Result<u8, &'static str> {
+            Ok(if self.present { self.value } else { 0xFF })
+        }
+    }
+
+    impl PortWriter<u8> for MockPort {
+        unsafe fn try_write(&mut self, value: u8) -> Result<(), &'static str> {
+            self.value = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn detects_present_port() {
+        let mut port = MockPort { present: true, value: 0x42 };
+        assert!(unsafe { scratch_register_present(&mut port) });
+        // The original value is restored.
+        assert_eq!(port.value, 0x42);
+    }
+
+    #[test]
+    fn detects_absent_port() {
+        let mut port = MockPort { present: false, value: 0 };
+        assert!(!unsafe { scratch_register_present(&mut port) });
+    }
+
+    /// A UART with a fixed sequence of received bytes.
+    struct MockUart(alloc::collections::VecDeque<u8>);
+
+    impl UartRx for MockUart {
+        fn try_receive(&mut self) -> Option<u8> {
+            self.0.pop_front()
+        }
+    }
+
+    #[test]
+    fn drains_uart_into_ring() {
+        let ring = RxRing::new();
+        let mut uart = MockUart((0..10).collect());
+        drain_uart(&mut uart, &ring);
+        assert!(uart.0.is_empty());
+        for expected in 0..10 {
+            assert_eq!(ring.pop(), Some(expected));
+        }
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn drops_bytes_when_ring_full() {
+        let ring = RxRing::new();
+        let mut uart = MockUart((0..RX_RING_SIZE + 5).map(|i| i as u8).collect());
+        drain_uart(&mut uart, &ring);
+        // The UART is drained even though the ring is full.
+        assert!(uart.0.is_empty());
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 5);
+        for expected in 0..RX_RING_SIZE {
+            assert_eq!(ring.pop(), Some(expected as u8));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn parks_until_byte_arrives() {
+        let ring = RxRing::new();
+        let mut uart = MockUart([0x42, 0x43].into_iter().collect());
+        let mut parked = 0;
+        // Each time we park, one interrupt delivers the next byte; the first few
+        // interrupts are spurious.
+        let byte = receive_byte(&ring, |ring| {
+            parked += 1;
+            if parked > 2 {
+                drain_uart(&mut uart, ring);
+            }
+        });
+        assert_eq!(byte, 0x42);
+        assert_eq!(parked, 3);
+
+        // The second byte is already buffered, so we don't park again.
+        assert_eq!(receive_byte(&ring, |_| panic!("parked with data available")), 0x43);
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let ring = RxRing::new();
+        for i in 0..3 * RX_RING_SIZE {
+            ring.push(i as u8);
+            assert_eq!(ring.pop(), Some(i as u8));
+        }
+        assert!(ring.is_empty());
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn max_write_size_is_unbounded() {
+        use oak_channel::Write;
+        // Safety: creating the port doesn't access it.
+        let port = unsafe { SerialPort::new(COM2_BASE) };
+        let serial = Serial { port: AtomicRefCell::new(port), interrupt_rx: false };
+        assert_eq!(serial.max_write_size(), usize::MAX);
+    }
+
+    #[test]
+    fn com_irqs() {
+        assert_eq!(isa_irq(0x3f8), Some(4));
+        assert_eq!(isa_irq(0x2f8), Some(3));
+        assert_eq!(isa_irq(0x1234), None);
+    }
+}
@@ -0,0 +1,189 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Log ring in memory shared with the host.
+//!
+//! This allows the host to collect kernel logs out-of-band (by polling the
+//! memory) when there is no serial port and before a channel is established.
+//!
+//! The ring starts with a header that is followed by the data area. The
+//! header contains a magic value (so that the host can find the ring), the
+//! capacity of the data area and the producer index, which is the total number
+//! of bytes written so far. The byte at producer index `i` is stored at offset
+//! `i % capacity` in the data area.
+//!
+//! The guest only ever writes to the ring and keeps its own copy of the
+//! producer index, so the host can't influence the guest by modifying the
+//! shared memory; at worst it garbles its own view of the logs.
+
+use core::{
+    fmt,
+    mem::{offset_of, size_of},
+    ptr::NonNull,
+    sync::atomic::{fence, Ordering},
+};
+
+/// Kernel argument that enables logging to the shared log ring.
+pub const SHARED_LOG_ARG: &str = "shared_log";
+
+/// Magic value identifying the log ring header ("OAKLOGRG").
+pub const LOG_RING_MAGIC: u64 = u64::from_le_bytes(*b"OAKLOGRG");
+
+/// Size of the memory reserved for the log ring, including the header.
+pub const LOG_RING_SIZE: usize = 64 * 1024;
+
+/// Header of the shared log ring.
+#[repr(C)]
+struct RingHeader {
+    magic: u64,
+    /// Size of the data area following the header, in bytes.
+    capacity: u64,
+    /// Total number of bytes written to the ring.
+    producer: u64,
+}
+
+/// Producer side of a log ring.
+pub struct LogRing {
+    base: NonNull<u8>,
+    capacity: usize,
+    producer: u64,
+}
+
+// Safety: the ring owns the memory it points to; the only other party accessing
+// it is the host, which we don't trust anyway.
+unsafe impl Send for LogRing {}
+
+impl LogRing {
+    /// Sets up a log ring in the `len` bytes of memory at `base`.
+    ///
+    /// Returns `None` if the memory is too small to hold the header and any
+    /// data.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for writes, aligned for `u64` and not be used
+    /// by anything else in the guest for as long as the ring exists.
+    pub unsafe fn new(base: NonNull<u8>, len: usize) -> Option<Self> {
+        let capacity = len.checked_sub(size_of::<RingHeader>()).filter(|capacity| *capacity > 0)?;
+        let ring = Self { base, capacity, producer: 0 };
+        ring.write_header_field(offset_of!(RingHeader, capacity), capacity as u64);
+        ring.write_header_field(offset_of!(RingHeader, producer), 0);
+        // Publish the magic last so that the host never sees a partially set up header.
+        fence(Ordering::Release);
+        ring.write_header_field(offset_of!(RingHeader, magic), LOG_RING_MAGIC);
+        Some(ring)
+    }
+
+    fn write_header_field(&self, offset: usize, value: u64) {
+        // Safety: the offset is within the header, which is within the memory given
+        // to `new` and aligned for u64.
+        unsafe { self.base.as_ptr().add(offset).cast::<u64>().write_volatile(value) }
+    }
+
+    /// Appends the bytes to the ring, overwriting the oldest data if the ring
+    /// is full.
+    pub fn write(&mut self, bytes: &[u8]) {
+        // If there is more data than fits, only the tail of it would survive anyway.
+        let skip = bytes.len().saturating_sub(self.capacity);
+        self.producer = self.producer.wrapping_add(skip as u64);
+        for &byte in &bytes[skip..] {
+            let offset = size_of::<RingHeader>() + (self.producer % self.capacity as u64) as usize;
+            // Safety: the offset is within the memory given to `new`.
+            unsafe { self.base.as_ptr().add(offset).write_volatile(byte) };
+            self.producer = self.producer.wrapping_add(1);
+        }
+        // Make sure the data is visible before the host sees the new producer index.
+        fence(Ordering::Release);
+        self.write_header_field(offset_of!(RingHeader, producer), self.producer);
+    }
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    const HEADER_SIZE: usize = size_of::<RingHeader>();
+
+    fn header_field(memory: &[u64], offset: usize) -> u64 {
+        memory[offset / size_of::<u64>()]
+    }
+
+    fn data(memory: &[u64]) -> Vec<u8> {
+        memory.iter().flat_map(|word| word.to_ne_bytes()).skip(HEADER_SIZE).collect()
+    }
+
+    fn ring(memory: &mut [u64]) -> LogRing {
+        let len = memory.len() * size_of::<u64>();
+        unsafe { LogRing::new(NonNull::new(memory.as_mut_ptr().cast()).unwrap(), len) }.unwrap()
+    }
+
+    #[test]
+    fn header_initialized() {
+        let mut memory = vec![0xFFFF_FFFF_FFFF_FFFFu64; 5];
+        let _ring = ring(&mut memory);
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, magic)), LOG_RING_MAGIC);
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, capacity)), 16);
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, producer)), 0);
+    }
+
+    #[test]
+    fn too_small() {
+        let mut memory = [0u64; 3];
+        let len = memory.len() * size_of::<u64>();
+        assert!(unsafe { LogRing::new(NonNull::new(memory.as_mut_ptr().cast()).unwrap(), len) }
+            .is_none());
+    }
+
+    #[test]
+    fn write_updates_producer() {
+        let mut memory = vec![0u64; 5];
+        let mut ring = ring(&mut memory);
+        ring.write(b"hello");
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, producer)), 5);
+        assert_eq!(&data(&memory)[..5], b"hello");
+    }
+
+    #[test]
+    fn write_wraps_around() {
+        let mut memory = vec![0u64; 5];
+        let mut ring = ring(&mut memory);
+        ring.write(b"0123456789");
+        ring.write(b"abcdefghij");
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, producer)), 20);
+        // Bytes 16..20 overwrote the start of the data area.
+        assert_eq!(data(&memory), b"ghij456789abcdef");
+    }
+
+    #[test]
+    fn write_larger_than_capacity() {
+        let mut memory = vec![0u64; 5];
+        let mut ring = ring(&mut memory);
+        ring.write(b"xy");
+        ring.write(b"0123456789abcdefghij");
+        assert_eq!(header_field(&memory, offset_of!(RingHeader, producer)), 22);
+        // Only the last 16 bytes survive, with the oldest one at offset 22 % 16.
+        assert_eq!(data(&memory), b"efghij456789abcd");
+    }
+}
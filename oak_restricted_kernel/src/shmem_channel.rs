@@ -0,0 +1,387 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Channel over a pair of rings in memory shared with the host.
+//!
+//! This is much cheaper than going through a virtio device, as neither side has
+//! to notify the other; both simply poll the rings. The memory starts with a
+//! header containing a magic value (so that the host can find the channel), the
+//! capacity of each ring, and the producer and consumer indices of the
+//! guest-to-host ring and the host-to-guest ring. The header is followed by the
+//! data area of the guest-to-host ring and then the one of the host-to-guest
+//! ring.
+//!
+//! The indices are the total number of bytes written to or read from the ring;
+//! the byte at index `i` is stored at offset `i % capacity` in the data area.
+//! Each side only ever writes its own index, and we keep our own copy of our
+//! indices, so the host can't make us read or write outside of the rings.
+
+use core::{
+    alloc::{Allocator, Layout},
+    cmp::min,
+    mem::size_of,
+    ptr::NonNull,
+    slice,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use x86_64::VirtAddr;
+
+use crate::{memory::debug_assert_shared, mm::Translator, PAGE_TABLES};
+
+/// Magic value identifying the channel header ("OAKSHMCH").
+pub const SHMEM_CHANNEL_MAGIC: u64 = u64::from_le_bytes(*b"OAKSHMCH");
+
+/// Size of the shared memory used for the channel, including the header.
+pub const SHMEM_CHANNEL_SIZE: usize = 64 * 1024;
+
+/// Producer and consumer indices of a ring.
+#[repr(C)]
+struct RingIndices {
+    producer: AtomicU64,
+    consumer: AtomicU64,
+}
+
+/// Header of the shared memory.
+#[repr(C)]
+struct ChannelHeader {
+    magic: AtomicU64,
+    /// Size of the data area of each ring, in bytes.
+    capacity: AtomicU64,
+    guest_to_host: RingIndices,
+    host_to_guest: RingIndices,
+}
+
+/// Writing end of a ring.
+struct Producer<'a> {
+    indices: &'a RingIndices,
+    data: &'a [AtomicU8],
+    producer: u64,
+}
+
+impl<'a> Producer<'a> {
+    fn new(indices: &'a RingIndices, data: &'a [AtomicU8]) -> Self {
+        indices.producer.store(0, Ordering::Relaxed);
+        Self { indices, data, producer: 0 }
+    }
+
+    /// Writes as many bytes as currently fit into the ring.
+    ///
+    /// Returns the number of bytes written.
+    fn push(&mut self, bytes: &[u8]) -> Result<usize, &'static str> {
+        let capacity = self.data.len() as u64;
+        let consumer = self.indices.consumer.load(Ordering::Acquire);
+        let used = self.producer.wrapping_sub(consumer);
+        if used > capacity {
+            return Err("invalid consumer index in shared memory ring");
+        }
+        let count = min((capacity - used) as usize, bytes.len());
+        for (index, byte) in bytes[..count].iter().enumerate() {
+            let offset = (self.producer.wrapping_add(index as u64) % capacity) as usize;
+            self.data[offset].store(*byte, Ordering::Relaxed);
+        }
+        self.producer = self.producer.wrapping_add(count as u64);
+        // Make sure the data is visible before the consumer sees the new index.
+        self.indices.producer.store(self.producer, Ordering::Release);
+        Ok(count)
+    }
+}
+
+/// Reading end of a ring.
+struct Consumer<'a> {
+    indices: &'a RingIndices,
+    data: &'a [AtomicU8],
+    consumer: u64,
+}
+
+impl<'a> Consumer<'a> {
+    fn new(indices: &'a RingIndices, data: &'a [AtomicU8]) -> Self {
+        indices.consumer.store(0, Ordering::Relaxed);
+        Self { indices, data, consumer: 0 }
+    }
+
+    /// Reads as many bytes as are currently available, up to the size of
+    /// `buf`.
+    ///
+    /// Returns the number of bytes read.
+    fn pop(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let capacity = self.data.len() as u64;
+        let producer = self.indices.producer.load(Ordering::Acquire);
+        let available = producer.wrapping_sub(self.consumer);
+        if available > capacity {
+            return Err("invalid producer index in shared memory ring");
+        }
+        let count = min(available as usize, buf.len());
+        for (index, byte) in buf[..count].iter_mut().enumerate() {
+            let offset = (self.consumer.wrapping_add(index as u64) % capacity) as usize;
+            *byte = self.data[offset].load(Ordering::Relaxed);
+        }
+        self.consumer = self.consumer.wrapping_add(count as u64);
+        // Make sure we're done reading before the producer may reuse the space.
+        self.indices.consumer.store(self.consumer, Ordering::Release);
+        Ok(count)
+    }
+}
+
+/// Communication channel over rings in memory shared with the host.
+pub struct ShmemChannel<'a> {
+    tx: Producer<'a>,
+    rx: Consumer<'a>,
+}
+
+impl<'a> ShmemChannel<'a> {
+    /// Allocates the shared memory for the channel from `alloc`, which has to
+    /// hand out memory that is shared with the host.
+    pub fn new<A: Allocator>(alloc: &'a A) -> Result<Self, &'static str> {
+        let layout = Layout::from_size_align(SHMEM_CHANNEL_SIZE, size_of::<u64>()).unwrap();
+        let memory = alloc
+            .allocate_zeroed(layout)
+            .map_err(|_| "couldn't allocate memory for the shared memory channel")?;
+        let base = VirtAddr::from_ptr(memory.as_ptr() as *const u8);
+        debug_assert_shared(base, SHMEM_CHANNEL_SIZE);
+        log::info!(
+            "Shared memory channel at {:#018x}",
+            PAGE_TABLES
+                .lock()
+                .get()
+                .and_then(|pt| pt.translate_virtual(base))
+                .ok_or("couldn't translate the shared memory channel address")?
+                .as_u64()
+        );
+        // Safety: the memory was just allocated for us, is aligned for u64 and is never
+        // freed.
+        unsafe { Self::from_memory(memory.cast(), SHMEM_CHANNEL_SIZE) }
+            .ok_or("shared memory channel too small")
+    }
+
+    /// Sets up the channel in the `len` bytes of memory at `base`.
+    ///
+    /// Returns `None` if the memory is too small to hold the header and two
+    /// non-empty rings.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes, aligned for `u64` and not
+    /// be used by anything else in the guest for the lifetime of the channel.
+    unsafe fn from_memory(base: NonNull<u8>, len: usize) -> Option<Self> {
+        let capacity = len.checked_sub(size_of::<ChannelHeader>())? / 2;
+        if capacity == 0 {
+            return None;
+        }
+        let header = &*base.as_ptr().cast::<ChannelHeader>();
+        let data = base.as_ptr().add(size_of::<ChannelHeader>()).cast::<AtomicU8>();
+        let guest_to_host = slice::from_raw_parts(data, capacity);
+        let host_to_guest = slice::from_raw_parts(data.add(capacity), capacity);
+
+        let channel = Self {
+            tx: Producer::new(&header.guest_to_host, guest_to_host),
+            rx: Consumer::new(&header.host_to_guest, host_to_guest),
+        };
+        header.capacity.store(capacity as u64, Ordering::Relaxed);
+        // Publish the magic last, so that the host never sees a partially set up
+        // header.
+        header.magic.store(SHMEM_CHANNEL_MAGIC, Ordering::Release);
+        Some(channel)
+    }
+}
+
+impl<'a> oak_channel::Write for ShmemChannel<'a> {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut start = 0;
+        while start < data.len() {
+            let count = self.tx.push(&data[start..]).map_err(anyhow::Error::msg)?;
+            if count == 0 {
+                core::hint::spin_loop();
+            }
+            start += count;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        // The data is visible to the host as soon as it's written, so do nothing.
+        Ok(())
+    }
+
+    /// A write of up to the ring size fits once the host has drained the ring.
+    fn max_write_size(&self) -> usize {
+        self.tx.data.len()
+    }
+}
+
+impl<'a> oak_channel::Read for ShmemChannel<'a> {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let mut start = 0;
+        while start < data.len() {
+            let count = self.rx.pop(&mut data[start..]).map_err(anyhow::Error::msg)?;
+            if count == 0 {
+                core::hint::spin_loop();
+            }
+            start += count;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use oak_channel::{Read, Write};
+
+    use super::*;
+
+    fn indices() -> RingIndices {
+        RingIndices { producer: AtomicU64::new(0), consumer: AtomicU64::new(0) }
+    }
+
+    fn data(capacity: usize) -> Vec<AtomicU8> {
+        (0..capacity).map(|_| AtomicU8::new(0)).collect()
+    }
+
+    #[test]
+    fn empty_ring() {
+        let (indices, data) = (indices(), data(8));
+        let mut consumer = Consumer::new(&indices, &data);
+        let mut buf = [0u8; 4];
+        assert_eq!(consumer.pop(&mut buf), Ok(0));
+    }
+
+    #[test]
+    fn full_ring() {
+        let (indices, data) = (indices(), data(8));
+        let mut producer = Producer::new(&indices, &data);
+        assert_eq!(producer.push(b"0123456789"), Ok(8));
+        assert_eq!(producer.push(b"x"), Ok(0));
+
+        let mut consumer = Consumer::new(&indices, &data);
+        let mut buf = [0u8; 3];
+        assert_eq!(consumer.pop(&mut buf), Ok(3));
+        assert_eq!(&buf, b"012");
+        assert_eq!(producer.push(b"abcd"), Ok(3));
+    }
+
+    #[test]
+    fn wraparound() {
+        let (indices, data) = (indices(), data(8));
+        let mut producer = Producer::new(&indices, &data);
+        let mut consumer = Consumer::new(&indices, &data);
+        let mut buf = [0u8; 8];
+        for round in 0..5u8 {
+            let message = [round, round + 1, round + 2, round + 3, round + 4];
+            assert_eq!(producer.push(&message), Ok(5));
+            assert_eq!(consumer.pop(&mut buf), Ok(5));
+            assert_eq!(&buf[..5], &message);
+        }
+        assert_eq!(indices.producer.load(Ordering::Relaxed), 25);
+        assert_eq!(indices.consumer.load(Ordering::Relaxed), 25);
+    }
+
+    #[test]
+    fn invalid_indices_from_peer() {
+        let (indices, data) = (indices(), data(8));
+        let mut producer = Producer::new(&indices, &data);
+        let mut consumer = Consumer::new(&indices, &data);
+        // The peer claims to have written more than fits in the ring.
+        indices.producer.store(9, Ordering::Relaxed);
+        assert!(consumer.pop(&mut [0u8; 4]).is_err());
+        // The peer claims to have read data that was never written.
+        indices.consumer.store(1, Ordering::Relaxed);
+        assert!(producer.push(b"x").is_err());
+    }
+
+    #[test]
+    fn channel_over_memory() {
+        let mut memory = vec![0u64; 16];
+        let len = memory.len() * size_of::<u64>();
+        let base = NonNull::new(memory.as_mut_ptr().cast::<u8>()).unwrap();
+        let mut channel = unsafe { ShmemChannel::from_memory(base, len) }.unwrap();
+        let header = unsafe { &*base.as_ptr().cast::<ChannelHeader>() };
+        assert_eq!(header.magic.load(Ordering::Relaxed), SHMEM_CHANNEL_MAGIC);
+        let capacity = header.capacity.load(Ordering::Relaxed) as usize;
+        assert_eq!(capacity, (len - size_of::<ChannelHeader>()) / 2);
+        assert_eq!(channel.max_write_size(), capacity);
+        let data = unsafe {
+            slice::from_raw_parts(
+                base.as_ptr().add(size_of::<ChannelHeader>()).cast::<AtomicU8>(),
+                2 * capacity,
+            )
+        };
+
+        // Act as the host on the other end of both rings.
+        channel.write_all(b"ping").unwrap();
+        let mut host_rx =
+            Consumer { indices: &header.guest_to_host, data: &data[..capacity], consumer: 0 };
+        let mut buf = [0u8; 4];
+        assert_eq!(host_rx.pop(&mut buf), Ok(4));
+        assert_eq!(&buf, b"ping");
+
+        let mut host_tx =
+            Producer { indices: &header.host_to_guest, data: &data[capacity..], producer: 0 };
+        assert_eq!(host_tx.push(b"pong"), Ok(4));
+        channel.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    /// The byte at position `index` of the stress test stream. The period isn't
+    /// a divisor of the ring capacity, so stale data from an earlier lap
+    /// around the ring doesn't look valid.
+    fn pattern(index: usize) -> u8 {
+        (index % 251) as u8
+    }
+
+    #[test]
+    fn concurrent_host_sees_ordered_data() {
+        const TOTAL: usize = 1 << 20;
+        let (to_host, to_guest) = ((indices(), data(61)), (indices(), data(61)));
+        let mut guest_tx = Producer::new(&to_host.0, &to_host.1);
+        let mut guest_rx = Consumer::new(&to_guest.0, &to_guest.1);
+
+        std::thread::scope(|scope| {
+            // The mock host echoes everything back, checking the data on the way.
+            scope.spawn(|| {
+                let mut host_rx = Consumer { indices: &to_host.0, data: &to_host.1, consumer: 0 };
+                let mut host_tx = Producer { indices: &to_guest.0, data: &to_guest.1, producer: 0 };
+                let mut buf = [0u8; 17];
+                let mut received = 0;
+                while received < TOTAL {
+                    let count = host_rx.pop(&mut buf).unwrap();
+                    for (offset, byte) in buf[..count].iter().enumerate() {
+                        assert_eq!(*byte, pattern(received + offset), "at {}", received + offset);
+                    }
+                    received += count;
+                    let mut sent = 0;
+                    while sent < count {
+                        sent += host_tx.push(&buf[sent..count]).unwrap();
+                    }
+                }
+            });
+
+            let message: Vec<u8> = (0..TOTAL).map(pattern).collect();
+            let (mut sent, mut received) = (0, 0);
+            let mut buf = [0u8; 23];
+            while received < TOTAL {
+                if sent < TOTAL {
+                    let end = min(sent + 13, TOTAL);
+                    sent += guest_tx.push(&message[sent..end]).unwrap();
+                }
+                let count = guest_rx.pop(&mut buf).unwrap();
+                assert_eq!(&buf[..count], &message[received..received + count]);
+                received += count;
+            }
+        });
+    }
+}
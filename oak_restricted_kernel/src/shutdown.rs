@@ -14,13 +14,205 @@
 // limitations under the License.
 //
 
-use core::arch::asm;
+use core::{
+    arch::asm,
+    ops::Range,
+    ptr::write_bytes,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use oak_sev_guest::{
     io::{IoPortFactory, PortFactoryWrapper, PortWrapper, PortWriter},
     msr::{get_sev_status, request_termination, SevStatus, TerminationReason, TerminationRequest},
 };
-use x86_64::{instructions::tables::lidt, structures::DescriptorTablePointer, VirtAddr};
+use strum::FromRepr;
+use x86_64::{
+    instructions::tables::lidt,
+    registers::control::Cr3,
+    structures::{
+        paging::{Page, PageSize, PhysFrame, Size4KiB},
+        DescriptorTablePointer,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    memory::kernel_heap_range,
+    mm::{encrypted_mapper::MemoryEncryption, encryption, Translator},
+    syscall::mmap::with_mappings,
+    PAGE_TABLES,
+};
+
+/// Kernel argument that selects what to do if none of the ways to power off
+/// the machine worked; see [`ShutdownMode::from_arg`].
+pub const SHUTDOWN_MODE_ARG: &str = "shutdown_mode";
+
+/// I/O port of QEMU's `isa-debug-exit` device, when configured with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Value written to the debug-exit port. QEMU exits with status
+/// `(value << 1) | 1`.
+const DEBUG_EXIT_VALUE: u8 = 0;
+
+/// What to do if none of the ways to power off the machine worked.
+///
+/// Which one is cheapest depends on the host: on some hypervisors, every `hlt`
+/// causes a VM exit, so a halted guest keeps the host busy.
+#[derive(Clone, Copy, Debug, Default, Eq, FromRepr, PartialEq)]
+#[repr(u8)]
+pub enum ShutdownMode {
+    /// Load an empty IDT and cause a triple fault.
+    TripleFault,
+    /// Execute `hlt` in a loop, with interrupts disabled.
+    #[default]
+    Halt,
+    /// Spin in a loop of `pause` instructions.
+    Pause,
+    /// Write to the `isa-debug-exit` port once, then behave like `Halt`.
+    DebugExit,
+}
+
+impl ShutdownMode {
+    /// Parses the value of the `shutdown_mode` kernel argument.
+    pub fn from_arg(arg: &str) -> Result<Self, &'static str> {
+        match arg {
+            "triple_fault" => Ok(Self::TripleFault),
+            "hlt" => Ok(Self::Halt),
+            "pause" => Ok(Self::Pause),
+            "debug_exit" => Ok(Self::DebugExit),
+            _ => Err("expected one of triple_fault, hlt, pause or debug_exit"),
+        }
+    }
+}
+
+static SHUTDOWN_MODE: AtomicU8 = AtomicU8::new(ShutdownMode::Halt as u8);
+
+/// Sets what [`shutdown`] does if powering off the machine fails.
+pub fn set_shutdown_mode(mode: ShutdownMode) {
+    SHUTDOWN_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+fn shutdown_mode() -> ShutdownMode {
+    ShutdownMode::from_repr(SHUTDOWN_MODE.load(Ordering::Relaxed)).unwrap_or_default()
+}
+
+/// The instruction to loop on once the machine is parked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ParkInstruction {
+    Hlt,
+    Pause,
+}
+
+/// Performs the I/O port writes `mode` calls for, using writers created by
+/// `new_writer`, and returns the instruction to loop on afterwards; `None`
+/// means that we should cause a triple fault instead.
+fn prepare_park<W: PortWriter<u8>>(
+    mode: ShutdownMode,
+    new_writer: impl FnOnce(u16) -> W,
+) -> Option<ParkInstruction> {
+    match mode {
+        ShutdownMode::TripleFault => None,
+        ShutdownMode::Halt => Some(ParkInstruction::Hlt),
+        ShutdownMode::Pause => Some(ParkInstruction::Pause),
+        ShutdownMode::DebugExit => {
+            let mut port = new_writer(DEBUG_EXIT_PORT);
+            // Safety: if the device doesn't exist, the write is ignored.
+            let _ = unsafe { port.try_write(DEBUG_EXIT_VALUE) };
+            Some(ParkInstruction::Hlt)
+        }
+    }
+}
+
+/// Zeroes the memory backing `regions` through the direct mapping, and
+/// returns the number of bytes wiped.
+///
+/// Unmapped pages are skipped, as are pages backed by one of the frames in
+/// `keep`. If memory encryption is enabled, pages that aren't encrypted are
+/// shared with the host and are skipped as well: they never held private data,
+/// and writing to them through the encrypted direct mapping would fail.
+///
+/// # Safety
+///
+/// Nothing may use the memory in `regions` (except for the frames in `keep`)
+/// afterwards, and the physical frames must be accessible through the direct
+/// mapping of `translator`.
+unsafe fn wipe_regions<T: Translator>(
+    translator: &T,
+    regions: impl IntoIterator<Item = Range<VirtAddr>>,
+    encryption_enabled: bool,
+    keep: &[PhysFrame],
+) -> u64 {
+    let mut wiped = 0;
+    for region in regions {
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(region.start),
+            Page::containing_address(region.end.align_up(Size4KiB::SIZE)),
+        );
+        for page in pages {
+            match translator.is_encrypted(page.start_address()) {
+                None => continue,
+                Some(false) if encryption_enabled => continue,
+                Some(_) => {}
+            }
+            let Some(frame) = translator
+                .translate_virtual(page.start_address())
+                .map(PhysFrame::<Size4KiB>::containing_address)
+            else {
+                continue;
+            };
+            if keep.contains(&frame) {
+                continue;
+            }
+            let Some(target) = translator.translate_physical_frame(frame) else {
+                continue;
+            };
+            write_bytes(target.start_address().as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+            wiped += Size4KiB::SIZE;
+        }
+    }
+    wiped
+}
+
+/// Zeroes the payload memory and the kernel heap.
+fn wipe_memory() {
+    let Some(pt_guard) = PAGE_TABLES.try_lock() else {
+        log::warn!("page tables are locked, not wiping memory before shutdown");
+        return;
+    };
+    let Some(mapper) = pt_guard.get() else {
+        return;
+    };
+    let encryption_enabled = !matches!(encryption(), MemoryEncryption::NoEncryption);
+    // The page table we're running on was allocated from the kernel heap.
+    let keep = [Cr3::read().0];
+
+    // Safety: we're shutting down, so the payload memory won't be used again.
+    let payload_bytes = with_mappings(|mappings| unsafe {
+        wipe_regions(mapper, mappings.iter().cloned(), encryption_enabled, &keep)
+    });
+    let heap = kernel_heap_range();
+    log::info!(
+        "Wiped {} bytes of payload memory, wiping kernel heap {:?} before shutdown",
+        payload_bytes.unwrap_or(0),
+        heap
+    );
+    // Safety: apart from the active page table, nothing on the shutdown path uses
+    // the heap. Nothing may allocate after this point.
+    if let Some(heap) = heap {
+        unsafe { wipe_regions(mapper, [heap], encryption_enabled, &keep) };
+    }
+}
+
+/// Wipes the kernel heap and the payload memory so that nothing is left behind
+/// in frames the host may reclaim, and then shuts down the machine.
+///
+/// This relies on the page tables and the heap being intact, so panics should
+/// use [`shutdown`] instead.
+pub fn secure_shutdown() -> ! {
+    wipe_memory();
+    shutdown()
+}
 
 /// Tries various ways to shut down the machine.
 pub fn shutdown() -> ! {
@@ -42,8 +234,20 @@ pub fn shutdown() -> ! {
         let _ = port.try_write(0xFE_u8);
     }
 
-    // 3. If we're still here, the gloves come off. Load a garbage IDT and cause
-    //    #UD.
+    // 3. If we're still here, park the machine in the way the operator asked for.
+    match prepare_park::<PortWrapper<u8>>(shutdown_mode(), |port| port_factory.new_writer(port)) {
+        Some(ParkInstruction::Hlt) => loop {
+            x86_64::instructions::interrupts::disable();
+            x86_64::instructions::hlt();
+        },
+        Some(ParkInstruction::Pause) => loop {
+            core::hint::spin_loop();
+        },
+        None => {}
+    }
+
+    // 4. If the operator asked for it, the gloves come off. Load a garbage IDT and
+    //    cause #UD.
     let idt = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0x0) };
     // Safety: this is technically safe, as it will cause the machine to crash, and
     // that's the intent.
@@ -55,3 +259,155 @@ pub fn shutdown() -> ! {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, vec, vec::Vec};
+    use core::cell::RefCell;
+
+    use x86_64::PhysAddr;
+
+    use super::*;
+    use crate::mm::PageTableFlags;
+
+    #[repr(C, align(4096))]
+    struct AlignedPage([u8; Size4KiB::SIZE as usize]);
+
+    /// Maps virtual pages to frames in `memory`, starting at physical address
+    /// 0.
+    struct FakeMappings {
+        memory: Vec<AlignedPage>,
+        base: VirtAddr,
+        pages: BTreeMap<u64, (u64, bool)>,
+    }
+
+    impl FakeMappings {
+        fn new(frames: usize) -> Self {
+            let mut memory: Vec<AlignedPage> =
+                (0..frames).map(|_| AlignedPage([0xAA; Size4KiB::SIZE as usize])).collect();
+            let base = VirtAddr::from_ptr(memory.as_mut_ptr());
+            Self { memory, base, pages: BTreeMap::new() }
+        }
+
+        fn map(&mut self, virt: u64, frame: usize, encrypted: bool) {
+            self.pages.insert(virt, (frame as u64 * Size4KiB::SIZE, encrypted));
+        }
+
+        fn is_zeroed(&self, frame: usize) -> bool {
+            self.memory[frame].0.iter().all(|&byte| byte == 0)
+        }
+    }
+
+    impl Translator for FakeMappings {
+        fn translate_virtual(&self, addr: VirtAddr) -> Option<PhysAddr> {
+            let page = addr.align_down(Size4KiB::SIZE);
+            self.pages.get(&page.as_u64()).map(|(phys, _)| PhysAddr::new(phys + (addr - page)))
+        }
+
+        fn translate_physical(&self, addr: PhysAddr) -> Option<VirtAddr> {
+            Some(self.base + addr.as_u64())
+        }
+
+        fn translate_physical_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> Option<Page<S>> {
+            self.translate_physical(frame.start_address()).map(Page::containing_address)
+        }
+
+        fn is_encrypted(&self, addr: VirtAddr) -> Option<bool> {
+            self.pages.get(&addr.align_down(Size4KiB::SIZE).as_u64()).map(|(_, enc)| *enc)
+        }
+
+        fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+            self.is_encrypted(addr).map(|encrypted| {
+                if encrypted {
+                    PageTableFlags::PRESENT | PageTableFlags::ENCRYPTED
+                } else {
+                    PageTableFlags::PRESENT
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn wipes_mapped_private_pages() {
+        let mut mappings = FakeMappings::new(5);
+        mappings.map(0x20_0000, 0, true);
+        mappings.map(0x20_1000, 1, true);
+        // 0x20_2000 is not mapped.
+        mappings.map(0x20_3000, 2, false);
+        mappings.map(0x40_0000, 3, true);
+        mappings.map(0x40_1000, 4, true);
+        let keep = [PhysFrame::containing_address(PhysAddr::new(4 * Size4KiB::SIZE))];
+
+        let regions = vec![
+            VirtAddr::new(0x20_0000)..VirtAddr::new(0x20_4000),
+            VirtAddr::new(0x40_0000)..VirtAddr::new(0x40_1800),
+        ];
+        let wiped = unsafe { wipe_regions(&mappings, regions, true, &keep) };
+
+        assert_eq!(wiped, 3 * Size4KiB::SIZE);
+        assert!(mappings.is_zeroed(0));
+        assert!(mappings.is_zeroed(1));
+        assert!(!mappings.is_zeroed(2), "shared page must not be wiped");
+        assert!(mappings.is_zeroed(3));
+        assert!(!mappings.is_zeroed(4), "kept frame must not be wiped");
+    }
+
+    #[test]
+    fn wipes_all_pages_without_encryption() {
+        let mut mappings = FakeMappings::new(2);
+        mappings.map(0x20_0000, 0, false);
+        mappings.map(0x20_1000, 1, false);
+
+        let regions = [VirtAddr::new(0x20_0000)..VirtAddr::new(0x20_2000)];
+        let wiped = unsafe { wipe_regions(&mappings, regions, false, &[]) };
+
+        assert_eq!(wiped, 2 * Size4KiB::SIZE);
+        assert!(mappings.is_zeroed(0));
+        assert!(mappings.is_zeroed(1));
+    }
+
+    /// Records the writes to a port.
+    struct MockPort<'a> {
+        port: u16,
+        writes: &'a RefCell<Vec<(u16, u8)>>,
+    }
+
+    impl PortWriter<u8> for MockPort<'_> {
+        unsafe fn try_write(&mut self, value: u8) -> Result<(), &'static str> {
+            self.writes.borrow_mut().push((self.port, value));
+            Ok(())
+        }
+    }
+
+    fn park(mode: ShutdownMode) -> (Option<ParkInstruction>, Vec<(u16, u8)>) {
+        let writes = RefCell::new(Vec::new());
+        let instruction = prepare_park(mode, |port| MockPort { port, writes: &writes });
+        (instruction, writes.into_inner())
+    }
+
+    #[test]
+    fn shutdown_modes_map_to_sequences() {
+        assert_eq!(park(ShutdownMode::TripleFault), (None, vec![]));
+        assert_eq!(park(ShutdownMode::Halt), (Some(ParkInstruction::Hlt), vec![]));
+        assert_eq!(park(ShutdownMode::Pause), (Some(ParkInstruction::Pause), vec![]));
+        assert_eq!(
+            park(ShutdownMode::DebugExit),
+            (Some(ParkInstruction::Hlt), vec![(DEBUG_EXIT_PORT, DEBUG_EXIT_VALUE)])
+        );
+    }
+
+    #[test]
+    fn shutdown_mode_defaults_to_hlt() {
+        assert_eq!(ShutdownMode::default(), ShutdownMode::Halt);
+        assert_eq!(shutdown_mode(), ShutdownMode::Halt);
+    }
+
+    #[test]
+    fn shutdown_mode_from_arg() {
+        assert_eq!(ShutdownMode::from_arg("hlt"), Ok(ShutdownMode::Halt));
+        assert_eq!(ShutdownMode::from_arg("pause"), Ok(ShutdownMode::Pause));
+        assert_eq!(ShutdownMode::from_arg("debug_exit"), Ok(ShutdownMode::DebugExit));
+        assert_eq!(ShutdownMode::from_arg("triple_fault"), Ok(ShutdownMode::TripleFault));
+        assert!(ShutdownMode::from_arg("reboot").is_err());
+    }
+}
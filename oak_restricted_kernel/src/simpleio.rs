@@ -21,7 +21,11 @@ use oak_sev_guest::{io::PortFactoryWrapper, msr::SevStatus};
 use oak_simple_io::SimpleIo;
 use x86_64::VirtAddr;
 
-use crate::{mm::Translator, PAGE_TABLES};
+use crate::{memory::debug_assert_shared, mm::Translator, PAGE_TABLES};
+
+/// The most data the device hands to the host in one go: the size of the
+/// buffer shared with the host.
+const MAX_WRITE_SIZE: usize = oak_simple_io::OUTPUT_BUFFER_LENGTH;
 
 /// A communications channel using a simple IO device.
 pub struct SimpleIoChannel<'a, A: Allocator> {
@@ -44,7 +48,11 @@ impl<'a, A: Allocator> SimpleIoChannel<'a, A> {
             let pt_guard = PAGE_TABLES.lock();
             SimpleIo::new_with_defaults(
                 io_port_factory,
-                |vaddr: VirtAddr| pt_guard.get().unwrap().translate_virtual(vaddr),
+                |vaddr: VirtAddr| {
+                    // Only memory shared with the host is ever handed to the device.
+                    debug_assert_shared(vaddr, 1);
+                    pt_guard.get().unwrap().translate_virtual(vaddr)
+                },
                 alloc,
             )
             .expect("couldn't create IO device")
@@ -101,6 +109,10 @@ impl<'a, A: Allocator> oak_channel::Write for SimpleIoChannel<'a, A> {
         // We always flush on write, so do nothing.
         Ok(())
     }
+
+    fn max_write_size(&self) -> usize {
+        MAX_WRITE_SIZE
+    }
 }
 
 impl<'a, A: Allocator> oak_channel::Read for SimpleIoChannel<'a, A> {
@@ -108,9 +120,38 @@ impl<'a, A: Allocator> oak_channel::Read for SimpleIoChannel<'a, A> {
         let len = data.len();
         let mut count = 0;
         while count < len {
-            count += self.read_partial(&mut data[count..]).unwrap_or(0);
+            match self.read_partial(&mut data[count..]) {
+                Some(read) => count += read,
+                None if self.device.peer_closed() => {
+                    if count > 0 {
+                        anyhow::bail!(
+                            "host closed the channel during read; partial data discarded"
+                        );
+                    }
+                    return Err(anyhow::Error::msg(oak_channel::PeerClosed));
+                }
+                None => {}
+            }
         }
 
         Ok(())
     }
+
+    fn peer_closed(&self) -> bool {
+        self.device.peer_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The device talks to the host through I/O ports, so we can only check the
+    // limit itself rather than exercise a channel.
+    #[test]
+    fn max_write_size_is_bounded_by_output_buffer() {
+        assert_eq!(MAX_WRITE_SIZE, oak_simple_io::OUTPUT_BUFFER_LENGTH);
+        // `basic_framed` sends 4 KiB chunks, which have to fit into a single write.
+        assert!(MAX_WRITE_SIZE >= 4096);
+    }
 }
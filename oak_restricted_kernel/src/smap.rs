@@ -0,0 +1,180 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Supervisor mode execution and access prevention (SMEP and SMAP).
+//!
+//! The payload runs in ring 3 and can only reach the kernel through system
+//! calls, interrupts and faults; none of the kernel's own pages are mapped
+//! `USER_ACCESSIBLE`. SMEP and SMAP close the gap in the other direction: with
+//! them enabled, the kernel faults if it executes user pages, or if it touches
+//! user memory other than through [`with_user_access`], instead of silently
+//! following a pointer the payload handed it.
+//!
+//! System calls only enable user access while copying to or from a user
+//! buffer they've checked lies in user space. `SYSCALL` clears `RFLAGS.AC` on
+//! entry, but interrupts and faults do not, and the payload can set it with
+//! `POPF`, so their handlers clear it with [`deny_user_access`] first thing.
+
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::registers::{
+    control::{Cr4, Cr4Flags},
+    model_specific::SFMask,
+    rflags::{self, RFlags},
+};
+
+/// Kernel argument that makes the kernel refuse to run unless both SMEP and
+/// SMAP are available.
+pub const REQUIRE_SMEP_SMAP_ARG: &str = "require_smep_smap";
+
+/// CPUID leaf reporting the structured extended features.
+const CPUID_EXTENDED_FEATURES: u32 = 7;
+
+/// Bits of EBX of `CPUID_EXTENDED_FEATURES`, subleaf 0.
+const SMEP_SUPPORTED: u32 = 1 << 7;
+const SMAP_SUPPORTED: u32 = 1 << 20;
+
+/// Whether SMAP has been enabled, in which case user memory can only be
+/// accessed within [`with_user_access`].
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Which of SMEP and SMAP the CPU supports.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Support {
+    pub smep: bool,
+    pub smap: bool,
+}
+
+impl Support {
+    /// Reads the supported features using CPUID.
+    pub fn read() -> Self {
+        // Safety: CPUID is available on all x86-64 CPUs, and we only read the
+        // extended features leaf if the CPU reports it as supported.
+        unsafe {
+            if __cpuid(0).eax < CPUID_EXTENDED_FEATURES {
+                return Self::default();
+            }
+            Self::decode(__cpuid_count(CPUID_EXTENDED_FEATURES, 0).ebx)
+        }
+    }
+
+    /// Decodes EBX of CPUID leaf 7, subleaf 0.
+    fn decode(ebx: u32) -> Self {
+        Self { smep: ebx & SMEP_SUPPORTED != 0, smap: ebx & SMAP_SUPPORTED != 0 }
+    }
+
+    /// Checks that both features are supported, for use when the
+    /// `require_smep_smap` kernel argument is set.
+    pub fn check_complete(&self) -> Result<(), &'static str> {
+        match (self.smep, self.smap) {
+            (true, true) => Ok(()),
+            (false, _) => Err("SMEP is required, but the CPU doesn't support it"),
+            (true, false) => Err("SMAP is required, but the CPU doesn't support it"),
+        }
+    }
+}
+
+/// Enables whichever of SMEP and SMAP are supported, and makes `SYSCALL`
+/// clear `RFLAGS.AC`.
+///
+/// Returns the features that were enabled.
+pub fn init() -> Support {
+    let support = Support::read();
+    if support.smap {
+        // Clear AC on entry to system calls, so that the payload can't leave user
+        // access enabled for the kernel.
+        SFMask::write(SFMask::read() | RFlags::ALIGNMENT_CHECK);
+        clac();
+    }
+    let mut cr4 = Cr4::read();
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, support.smep);
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, support.smap);
+    // Safety: the kernel never executes user pages, and AC is clear, so the only
+    // accesses to user memory that now fault are those outside `with_user_access`.
+    unsafe { Cr4::write(cr4) };
+    SMAP_ENABLED.store(support.smap, Ordering::Release);
+    support
+}
+
+/// Runs `f` with access to user memory enabled.
+///
+/// Calls may be nested; access stays enabled until the outermost call
+/// returns.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    if !SMAP_ENABLED.load(Ordering::Acquire) || rflags::read().contains(RFlags::ALIGNMENT_CHECK) {
+        return f();
+    }
+    stac();
+    let result = f();
+    clac();
+    result
+}
+
+/// Disables access to user memory on entry to an interrupt or exception
+/// handler.
+///
+/// `IRETQ` restores the interrupted flags, so if the handler interrupted a
+/// [`with_user_access`] call, access is enabled again on return.
+#[inline]
+pub fn deny_user_access() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        clac();
+    }
+}
+
+fn stac() {
+    // Safety: setting AC only allows the kernel to access user pages.
+    unsafe { asm!("stac", options(nostack, preserves_flags)) };
+}
+
+fn clac() {
+    // Safety: clearing AC only prevents the kernel from accessing user pages.
+    unsafe { asm!("clac", options(nostack, preserves_flags)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_support() {
+        assert_eq!(Support::decode(0), Support { smep: false, smap: false });
+        assert_eq!(Support::decode(SMEP_SUPPORTED), Support { smep: true, smap: false });
+        // Other feature bits, e.g. FSGSBASE, don't matter.
+        assert_eq!(
+            Support::decode(SMEP_SUPPORTED | SMAP_SUPPORTED | 1),
+            Support { smep: true, smap: true }
+        );
+    }
+
+    #[test]
+    fn requires_both_features() {
+        assert!(Support { smep: true, smap: true }.check_complete().is_ok());
+        assert!(Support { smep: true, smap: false }.check_complete().is_err());
+        assert!(Support { smep: false, smap: true }.check_complete().is_err());
+    }
+
+    #[test]
+    fn user_access_without_smap_runs_closure() {
+        assert_eq!(with_user_access(|| with_user_access(|| 42)), 42);
+    }
+}
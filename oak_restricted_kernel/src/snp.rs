@@ -18,14 +18,44 @@ use core::{panic, slice::from_raw_parts};
 
 use oak_core::sync::OnceCell;
 use oak_linux_boot_params::{BootParams, CCBlobSevInfo, CCSetupData, SetupDataType};
-use oak_sev_guest::{cpuid::CpuidPage, secrets::SecretsPage};
+use oak_sev_guest::{
+    cpuid::CpuidPage,
+    instructions::{rmpadjust, InstructionError, PageSize as RmpPageSize},
+    msr::PageAssignment,
+    secrets::SecretsPage,
+};
+use oak_sev_snp_attestation_report::{AttestationReportData, PlatformInfo};
 use x86_64::{
-    structures::paging::{PageSize, Size4KiB},
+    addr::align_down,
+    structures::paging::{PageSize, Size2MiB, Size4KiB},
     PhysAddr, VirtAddr,
 };
 use zerocopy::FromBytes;
 
-use crate::mm::Translator;
+use crate::{boot::Protocol, mm::Translator, rate_limit::Clock};
+
+/// Kernel argument that paces the page state changes for the guest-host
+/// memory, as `<delay in microseconds>[:<retries>]`; for example,
+/// `snp_psc_backoff=50:3`.
+///
+/// Some hosts throttle or fail page state change requests that arrive back to
+/// back. With this argument we wait for the delay between frames, and retry a
+/// failed frame up to `retries` times, doubling the delay each time.
+pub const PSC_BACKOFF_ARG: &str = "snp_psc_backoff";
+
+/// Kernel argument that makes the kernel refuse to run unless it runs at the
+/// given VM Privilege Level (VMPL); for example, `require_vmpl=0`.
+pub const REQUIRE_VMPL_ARG: &str = "require_vmpl";
+
+/// Kernel argument that makes the kernel refuse to run if the host reports
+/// that simultaneous multi-threading (SMT) is enabled.
+///
+/// Some threat models require SMT to be disabled, as sibling threads share
+/// core resources that can leak data through side channels.
+pub const REQUIRE_SMT_DISABLED_ARG: &str = "require_smt_disabled";
+
+/// The least privileged VMPL.
+const MAX_VMPL: u8 = 3;
 
 /// The exclusive upper limit of the address range where we expect the
 /// SNP-specific pages to reside.
@@ -41,6 +71,67 @@ pub static SECRETS_PAGE: OnceCell<SecretsPage> = OnceCell::new();
 /// The SEV-SNP CPUID page.
 pub static CPUID_PAGE: OnceCell<CpuidPage> = OnceCell::new();
 
+/// Pacing of page state change requests; see [`PSC_BACKOFF_ARG`].
+///
+/// The default issues requests back to back without retrying.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PscBackoff {
+    delay_us: u64,
+    retries: u32,
+}
+
+impl PscBackoff {
+    /// Parses the value of [`PSC_BACKOFF_ARG`].
+    pub fn from_arg(arg: &str) -> Result<Self, &'static str> {
+        let (delay, retries) = arg.split_once(':').unwrap_or((arg, "0"));
+        Ok(Self {
+            delay_us: delay.parse().map_err(|_| "invalid page state change delay")?,
+            retries: retries.parse().map_err(|_| "invalid page state change retry count")?,
+        })
+    }
+
+    /// Waits for `delay_us` microseconds, if we have a clock to measure them.
+    fn delay<C: Clock>(clock: Option<&C>, delay_us: u64) {
+        let Some(clock) = clock else {
+            return;
+        };
+        let ticks = delay_us.saturating_mul(clock.ticks_per_second()) / 1_000_000;
+        clock.wait_until(clock.now().saturating_add(ticks));
+    }
+
+    /// Waits between two consecutive frames.
+    pub fn pace<C: Clock>(&self, clock: Option<&C>) {
+        Self::delay(clock, self.delay_us);
+    }
+
+    /// Calls `change` until it succeeds, but at most `1 + retries` times,
+    /// backing off exponentially between attempts.
+    ///
+    /// The hypervisor doesn't tell us whether a failure was transient, so every
+    /// failure is retried; requesting a state the pages are already in is
+    /// harmless.
+    pub fn retry<C: Clock>(
+        &self,
+        clock: Option<&C>,
+        mut change: impl FnMut() -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let mut delay_us = self.delay_us;
+        let mut attempt = 0;
+        loop {
+            match change() {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt >= self.retries => return Err(err),
+                Err(err) => {
+                    log::warn!("page state change failed ({}), retrying", err);
+                    Self::delay(clock, delay_us);
+                    delay_us = delay_us.saturating_mul(2);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
 /// Wrapper for the guest-physical addresses of the secrets page and the CPUID
 /// page.
 pub struct SnpPageAddresses {
@@ -48,15 +139,16 @@ pub struct SnpPageAddresses {
     pub cpuid_page_address: PhysAddr,
 }
 
-/// Tries to extract the guest-physical addresses of the SEV-SNP secrets page
-/// and CPUID page.
+/// Finds the SEV-SNP `CCBlobSevInfo` structure in the setup data of the Linux
+/// boot parameters.
 ///
 /// This function must only be used while the identity mapping is still in
 /// place, since the pointers in the boot parameter page was constructed by the
 /// Stage 0 firmware with an identity mapping.
 ///
-/// This function will panic if the boot parameter page is not valid.
-pub fn get_snp_page_addresses(info: &BootParams) -> SnpPageAddresses {
+/// Returns `None` if there is no setup data of type `CCBlob`. This function
+/// will panic if the boot parameter page is not valid.
+pub fn find_cc_blob(info: &BootParams) -> Option<*const CCBlobSevInfo> {
     // Check that the address of the boot parameter page looks OK.
     let base_address = VirtAddr::from_ptr(info as *const BootParams);
     // We assume an identity mapping from virtual to physical address.
@@ -79,14 +171,27 @@ pub fn get_snp_page_addresses(info: &BootParams) -> SnpPageAddresses {
     }
 
     if setup_data_ptr.is_null() {
-        panic!("couldn't find setup data of type CCBlob");
+        return None;
     }
 
     // Safety: we have checked that the pointer is not null and at least points to
     // memory within the expected valid range.
-    let cc_blob_address =
-        unsafe { &*(setup_data_ptr as *const CCSetupData) }.cc_blob_address as *const CCBlobSevInfo;
+    Some(
+        unsafe { &*(setup_data_ptr as *const CCSetupData) }.cc_blob_address as *const CCBlobSevInfo,
+    )
+}
 
+/// Tries to extract the guest-physical addresses of the SEV-SNP secrets page
+/// and CPUID page.
+///
+/// This function must only be used while the identity mapping is still in
+/// place, since the pointers in the boot info were constructed by the Stage
+/// 0 firmware with an identity mapping.
+///
+/// This function will panic if the boot info doesn't point to a valid
+/// `CCBlobSevInfo` structure.
+pub fn get_snp_page_addresses(info: &dyn Protocol) -> SnpPageAddresses {
+    let cc_blob_address = info.cc_blob().expect("couldn't find setup data of type CCBlob");
     assert_pointer_in_valid_range(cc_blob_address);
     // Safety: we have checked that the pointer is not null and at least points to
     // memory within the expected valid range. We also validate the magic number
@@ -159,6 +264,127 @@ fn assert_page_in_valid_range(page: PhysAddr) {
     );
 }
 
+/// Parses the value of [`REQUIRE_VMPL_ARG`].
+pub fn parse_vmpl(arg: &str) -> Result<u8, &'static str> {
+    match arg.parse() {
+        Ok(vmpl) if vmpl <= MAX_VMPL => Ok(vmpl),
+        _ => Err("expected a VMPL between 0 and 3"),
+    }
+}
+
+/// Derives the current VMPL from `may_adjust`, which tells whether we're
+/// allowed to change the RMP permissions of a target VMPL.
+///
+/// RMPADJUST only lets us change the permissions of VMPLs that are less
+/// privileged than ours, so the first target VMPL we may adjust is the one
+/// right after ours. If we can't adjust any of them, we're running at the
+/// least privileged VMPL.
+fn vmpl_from_probe(mut may_adjust: impl FnMut(u8) -> bool) -> u8 {
+    (1..=MAX_VMPL).find(|&target| may_adjust(target)).map_or(MAX_VMPL, |target| target - 1)
+}
+
+/// A page that is only used to probe which VMPLs we may adjust.
+#[repr(align(4096))]
+struct ProbePage([u8; Size4KiB::SIZE as usize]);
+
+static VMPL_PROBE_PAGE: ProbePage = ProbePage([0; Size4KiB::SIZE as usize]);
+
+/// Returns the VMPL the guest is running at.
+///
+/// The attestation report can't tell us this, as its VMPL field just echoes the
+/// VMPL of the request, so we probe with RMPADJUST instead. This takes away all
+/// access to the probe page from the less privileged VMPLs. If the RMP maps the
+/// probe page as part of a 2 MiB page, that means the whole 2 MiB page, which
+/// also holds other kernel data; that is harmless, as no less privileged VMPL
+/// has any business accessing kernel memory.
+///
+/// Must only be called if SEV-SNP is active, as RMPADJUST is not available
+/// otherwise.
+pub fn current_vmpl() -> u8 {
+    let address = VirtAddr::from_ptr(&VMPL_PROBE_PAGE);
+    vmpl_from_probe(|target| revoke_access(address, target).is_ok())
+}
+
+/// Takes away all access to the page containing `address` from `target_vmpl`
+/// with RMPADJUST.
+///
+/// This adjusts the 4 KiB page if the RMP maps it as such, and the whole 2 MiB
+/// page containing it otherwise.
+fn revoke_access(address: VirtAddr, target_vmpl: u8) -> Result<(), InstructionError> {
+    // The empty permission mask and VMSA flag are always valid.
+    let permission = || (target_vmpl as u64).try_into().unwrap();
+    match rmpadjust(
+        address.align_down(Size4KiB::SIZE).as_u64() as usize,
+        RmpPageSize::Page4KiB,
+        permission(),
+    ) {
+        // The page may be part of a 2 MiB page in the RMP.
+        Err(InstructionError::FailSizeMismatch) => rmpadjust(
+            align_down(address.as_u64(), Size2MiB::SIZE) as usize,
+            RmpPageSize::Page2MiB,
+            permission(),
+        ),
+        result => result,
+    }
+}
+
+/// Probes the RMP state of the page containing `address`, for a guest running
+/// at `vmpl`.
+///
+/// RMPADJUST only succeeds on pages that are assigned to the guest and
+/// validated, so the page is reported as private if it does and shared
+/// otherwise. The probe takes away all access to the page (possibly the whole
+/// 2 MiB page, see [`revoke_access`]) from the VMPL after ours, so it must
+/// only be used on memory that no less privileged VMPL needs.
+///
+/// Returns `None` at the least privileged VMPL, as there is no VMPL to target.
+pub fn page_assignment(address: VirtAddr, vmpl: u8) -> Option<PageAssignment> {
+    if vmpl >= MAX_VMPL {
+        return None;
+    }
+    match revoke_access(address, vmpl + 1) {
+        Ok(()) => Some(PageAssignment::Private),
+        Err(_) => Some(PageAssignment::Shared),
+    }
+}
+
+/// Checks that the guest runs at the `required` VMPL, for use when the
+/// `require_vmpl` kernel argument is set.
+///
+/// `actual` is `None` if SEV-SNP is not active, in which case there are no
+/// VMPLs to speak of.
+pub fn check_vmpl(required: u8, actual: Option<u8>) -> Result<(), &'static str> {
+    match actual {
+        None => Err("a VMPL is required, but SEV-SNP is not active"),
+        Some(actual) if actual != required => Err("the guest is not running at the required VMPL"),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Decodes the platform configuration that the host reported in an attestation
+/// report.
+///
+/// Bits that are not known to us are ignored; `AttestationReportData::validate`
+/// rejects reports that set any.
+pub fn platform_info(report: &AttestationReportData) -> PlatformInfo {
+    PlatformInfo::from_bits_truncate(report.platform_info)
+}
+
+/// Checks that SMT is disabled, for use when the `require_smt_disabled` kernel
+/// argument is set.
+///
+/// `info` is `None` if SEV-SNP is not active, in which case there is no
+/// trustworthy platform info to go by.
+pub fn check_smt_disabled(info: Option<&PlatformInfo>) -> Result<(), &'static str> {
+    match info {
+        None => Err("SMT must be disabled, but SEV-SNP is not active to attest to it"),
+        Some(info) if info.contains(PlatformInfo::SMT_EN) => {
+            Err("the host reports that SMT is enabled")
+        }
+        Some(_) => Ok(()),
+    }
+}
+
 /// Panics if the pointer is null or points to an address that falls outside the
 /// expected range.
 fn assert_pointer_in_valid_range<T>(pointer: *const T) {
@@ -171,3 +397,143 @@ fn assert_pointer_in_valid_range<T>(pointer: *const T) {
         MAX_ADDRESS
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    use zerocopy::FromZeroes;
+
+    use super::*;
+
+    /// A clock at 1 MHz (so a tick is a microsecond) that only advances when we
+    /// wait, jumping straight to the deadline.
+    #[derive(Default)]
+    struct MockClock {
+        now: Cell<u64>,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.now.get()
+        }
+
+        fn ticks_per_second(&self) -> u64 {
+            1_000_000
+        }
+
+        fn wait_until(&self, deadline: u64) {
+            self.now.set(self.now.get().max(deadline));
+        }
+    }
+
+    /// Returns a state change function that fails `failures` times, and the
+    /// number of times it was called.
+    fn flaky(failures: u32) -> (impl FnMut() -> Result<(), &'static str>, Rc<Cell<u32>>) {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let change = move || {
+            counter.set(counter.get() + 1);
+            if counter.get() <= failures {
+                Err("page state change failed")
+            } else {
+                Ok(())
+            }
+        };
+        (change, calls)
+    }
+
+    #[test]
+    fn parses_backoff_arg() {
+        assert_eq!(PscBackoff::from_arg("50"), Ok(PscBackoff { delay_us: 50, retries: 0 }));
+        assert_eq!(PscBackoff::from_arg("50:3"), Ok(PscBackoff { delay_us: 50, retries: 3 }));
+        assert!(PscBackoff::from_arg("fast").is_err());
+        assert!(PscBackoff::from_arg("50:many").is_err());
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let clock = MockClock::default();
+        let backoff = PscBackoff { delay_us: 10, retries: 3 };
+        let (change, calls) = flaky(2);
+        assert_eq!(backoff.retry(Some(&clock), change), Ok(()));
+        assert_eq!(calls.get(), 3);
+        // Backed off for 10 and then 20 microseconds.
+        assert_eq!(clock.now(), 30);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let clock = MockClock::default();
+        let backoff = PscBackoff { delay_us: 10, retries: 2 };
+        let (change, calls) = flaky(5);
+        assert!(backoff.retry(Some(&clock), change).is_err());
+        assert_eq!(calls.get(), 3);
+        assert_eq!(clock.now(), 30);
+    }
+
+    #[test]
+    fn default_does_not_retry_or_wait() {
+        let clock = MockClock::default();
+        let (change, calls) = flaky(1);
+        assert!(PscBackoff::default().retry(Some(&clock), change).is_err());
+        assert_eq!(calls.get(), 1);
+        PscBackoff::default().pace(Some(&clock));
+        assert_eq!(clock.now(), 0);
+    }
+
+    #[test]
+    fn paces_frames() {
+        let clock = MockClock::default();
+        PscBackoff { delay_us: 25, retries: 0 }.pace(Some(&clock));
+        assert_eq!(clock.now(), 25);
+        // Without a clock there is nothing to measure the delay with.
+        PscBackoff { delay_us: 25, retries: 0 }.pace(None::<&MockClock>);
+    }
+
+    #[test]
+    fn vmpl_from_rmpadjust_probe() {
+        // At VMPL n we may only adjust the permissions of VMPLs above n.
+        for vmpl in 0..=MAX_VMPL {
+            assert_eq!(vmpl_from_probe(|target| target > vmpl), vmpl);
+        }
+    }
+
+    #[test]
+    fn require_vmpl() {
+        assert_eq!(parse_vmpl("0"), Ok(0));
+        assert_eq!(parse_vmpl("3"), Ok(3));
+        assert!(parse_vmpl("4").is_err());
+        assert!(parse_vmpl("zero").is_err());
+
+        assert_eq!(check_vmpl(0, Some(0)), Ok(()));
+        assert!(check_vmpl(0, Some(1)).is_err());
+        assert!(check_vmpl(1, Some(0)).is_err());
+        assert!(check_vmpl(0, None).is_err());
+    }
+
+    #[test]
+    fn decodes_platform_info() {
+        let mut report = AttestationReportData::new_zeroed();
+        let info = platform_info(&report);
+        assert!(!info.contains(PlatformInfo::SMT_EN));
+        assert!(!info.contains(PlatformInfo::TSME_EN));
+        assert_eq!(check_smt_disabled(Some(&info)), Ok(()));
+
+        report.platform_info = 0b01;
+        let info = platform_info(&report);
+        assert!(info.contains(PlatformInfo::SMT_EN));
+        assert!(!info.contains(PlatformInfo::TSME_EN));
+        assert!(check_smt_disabled(Some(&info)).is_err());
+
+        // Reserved bits are ignored.
+        report.platform_info = 0b1010;
+        let info = platform_info(&report);
+        assert!(!info.contains(PlatformInfo::SMT_EN));
+        assert!(info.contains(PlatformInfo::TSME_EN));
+        assert_eq!(check_smt_disabled(Some(&info)), Ok(()));
+
+        assert!(check_smt_disabled(None).is_err());
+    }
+}
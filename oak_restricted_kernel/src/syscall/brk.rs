@@ -0,0 +1,281 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Program break (`brk()`) support for payloads with a classic heap.
+
+use core::ffi::c_void;
+
+use oak_restricted_kernel_interface::Errno;
+use spinning_top::Spinlock;
+use x86_64::{
+    align_up,
+    instructions::tlb,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageSize, Size2MiB},
+    VirtAddr,
+};
+
+use super::USER_SPACE_LIMIT;
+use crate::{
+    mm::{Mapper, PageTableFlags, Translator},
+    FRAME_ALLOCATOR, PAGE_TABLES,
+};
+
+/// The heap of a process, which ends at the program break.
+#[derive(Debug)]
+pub struct ProgramBreak {
+    /// Start of the heap; the break can't be moved below this.
+    start: VirtAddr,
+    /// The current program break.
+    current: VirtAddr,
+    /// The highest address the break may be moved to.
+    limit: VirtAddr,
+}
+
+impl ProgramBreak {
+    /// Creates an empty heap at `start`, which may grow by up to `max_size`
+    /// bytes.
+    pub fn new(start: VirtAddr, max_size: u64) -> Self {
+        let start = start.align_up(Size2MiB::SIZE);
+        let limit = start.as_u64().saturating_add(max_size).min(USER_SPACE_LIMIT);
+        Self { start, current: start, limit: VirtAddr::new(limit) }
+    }
+}
+
+/// The heap of the current process, if it has been set up.
+static PROGRAM_BREAK: Spinlock<Option<ProgramBreak>> = Spinlock::new(None);
+
+/// Sets up the heap of a process that was just loaded, replacing the heap of
+/// the previous process (if any).
+pub fn init(program_break: ProgramBreak) {
+    *PROGRAM_BREAK.lock() = Some(program_break);
+}
+
+/// Returns the page following the last page needed to back memory up to `addr`.
+fn page_end(addr: VirtAddr) -> Page<Size2MiB> {
+    Page::containing_address(VirtAddr::new(align_up(addr.as_u64(), Size2MiB::SIZE)))
+}
+
+/// Unmaps the pages in `[start, end)` and returns their frames to the
+/// allocator.
+///
+/// # Safety
+///
+/// The pages must belong to the heap, and nothing may use them anymore.
+unsafe fn release<M: Mapper<Size2MiB>, A: FrameDeallocator<Size2MiB>>(
+    start: Page<Size2MiB>,
+    end: Page<Size2MiB>,
+    mapper: &M,
+    frame_allocator: &mut A,
+) {
+    for page in Page::range(start, end) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.ignore();
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// Moves the program break to `new`, mapping zeroed pages when the heap grows
+/// and unmapping (and freeing) pages when it shrinks.
+///
+/// The program break is left untouched on failure.
+///
+/// The TLB is not flushed; the caller has to flush it before returning to
+/// user space.
+///
+/// # Safety
+///
+/// `mapper` must be the active page table of the process that owns the heap,
+/// and the physical frames must be accessible through its direct mapping.
+unsafe fn set_break<M, A>(
+    program_break: &mut ProgramBreak,
+    new: VirtAddr,
+    mapper: &M,
+    frame_allocator: &mut A,
+) -> Result<(), &'static str>
+where
+    M: Mapper<Size2MiB> + Translator,
+    A: FrameAllocator<Size2MiB> + FrameDeallocator<Size2MiB>,
+{
+    if new < program_break.start || new > program_break.limit {
+        return Err("program break outside of the heap limits");
+    }
+    let old_end = page_end(program_break.current);
+    let new_end = page_end(new);
+
+    if new_end < old_end {
+        release(new_end, old_end, mapper, frame_allocator);
+    } else if new_end > old_end {
+        let pages = Page::range(old_end, new_end);
+        if pages.into_iter().any(|page| mapper.translate_virtual(page.start_address()).is_some()) {
+            return Err("heap would overlap an existing mapping");
+        }
+        for page in pages {
+            let mapped =
+                frame_allocator.allocate_frame().ok_or("out of memory").and_then(|frame| {
+                    let memory = mapper
+                        .translate_physical(frame.start_address())
+                        .ok_or("couldn't translate frame address")?;
+                    memory.as_mut_ptr::<u8>().write_bytes(0, Size2MiB::SIZE as usize);
+                    mapper
+                        .map_to_with_table_flags(
+                            page,
+                            frame,
+                            PageTableFlags::PRESENT
+                                | PageTableFlags::WRITABLE
+                                | PageTableFlags::USER_ACCESSIBLE
+                                | PageTableFlags::ENCRYPTED
+                                | PageTableFlags::NO_EXECUTE,
+                            PageTableFlags::PRESENT
+                                | PageTableFlags::WRITABLE
+                                | PageTableFlags::ENCRYPTED
+                                | PageTableFlags::USER_ACCESSIBLE,
+                        )
+                        .map_err(|_| {
+                            frame_allocator.deallocate_frame(frame);
+                            "couldn't map heap page"
+                        })
+                });
+            match mapped {
+                // The page wasn't mapped before, so there's no stale TLB entry.
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    release(old_end, page, mapper, frame_allocator);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    program_break.current = new;
+    Ok(())
+}
+
+pub fn syscall_brk(addr: *const c_void) -> isize {
+    let mut guard = PROGRAM_BREAK.lock();
+    let Some(program_break) = guard.as_mut() else {
+        return Errno::ENOMEM as isize;
+    };
+    // Like Linux, `brk(NULL)` (or any other invalid address) returns the current
+    // break.
+    if let Some(new) = VirtAddr::try_new(addr as u64).ok().filter(|addr| !addr.is_null()) {
+        let pt_guard = PAGE_TABLES.lock();
+        let pt = pt_guard.get().unwrap();
+        // Safety: PAGE_TABLES holds the currently active page tables, which contain
+        // the direct mapping of physical memory, and the heap belongs to the
+        // current process.
+        let result = unsafe { set_break(program_break, new, pt, &mut *FRAME_ALLOCATOR.lock()) };
+        tlb::flush_all();
+        if let Err(err) = result {
+            log::warn!("brk: couldn't move the program break to {:?}: {}", new, err);
+        }
+    }
+    program_break.current.as_u64() as isize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::fakes::{frame, FakeFrameAllocator, FakePageTable};
+
+    const HEAP_START: u64 = 0x40_0000;
+
+    fn heap_page(index: u64) -> Page<Size2MiB> {
+        Page::containing_address(VirtAddr::new(HEAP_START + index * Size2MiB::SIZE))
+    }
+
+    #[test]
+    fn grow_and_shrink() {
+        let mut page_table = FakePageTable::new(8);
+        let mut frame_allocator = FakeFrameAllocator((1..5).map(frame).collect());
+        let mut program_break = ProgramBreak::new(VirtAddr::new(HEAP_START), 8 * Size2MiB::SIZE);
+
+        // Grow by a bit more than three pages; this needs four.
+        let new = VirtAddr::new(HEAP_START + 3 * Size2MiB::SIZE + 0x10);
+        unsafe { set_break(&mut program_break, new, &page_table, &mut frame_allocator) }.unwrap();
+        assert_eq!(program_break.current, new);
+        assert!(frame_allocator.0.is_empty());
+        for index in 0..4 {
+            let (frame, flags) = page_table.mapping(heap_page(index));
+            assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+            assert!(page_table.frame_contents(frame).iter().all(|byte| *byte == 0));
+        }
+        assert!(page_table.translate_virtual(heap_page(4).start_address()).is_none());
+
+        // Shrinking within the last page keeps it mapped.
+        let new = VirtAddr::new(HEAP_START + 3 * Size2MiB::SIZE + 0x08);
+        unsafe { set_break(&mut program_break, new, &page_table, &mut frame_allocator) }.unwrap();
+        assert!(frame_allocator.0.is_empty());
+
+        // Shrinking back to the start frees all frames.
+        let new = VirtAddr::new(HEAP_START);
+        unsafe { set_break(&mut program_break, new, &page_table, &mut frame_allocator) }.unwrap();
+        assert_eq!(frame_allocator.0.len(), 4);
+        for index in 0..4 {
+            assert!(page_table.translate_virtual(heap_page(index).start_address()).is_none());
+        }
+    }
+
+    #[test]
+    fn out_of_memory_rolls_back() {
+        let page_table = FakePageTable::new(8);
+        let mut frame_allocator = FakeFrameAllocator((1..3).map(frame).collect());
+        let mut program_break = ProgramBreak::new(VirtAddr::new(HEAP_START), 8 * Size2MiB::SIZE);
+
+        let new = VirtAddr::new(HEAP_START + 3 * Size2MiB::SIZE);
+        assert!(unsafe { set_break(&mut program_break, new, &page_table, &mut frame_allocator) }
+            .is_err());
+        assert_eq!(program_break.current, VirtAddr::new(HEAP_START));
+        assert_eq!(frame_allocator.0.len(), 2);
+        assert!(page_table.translate_virtual(heap_page(0).start_address()).is_none());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let page_table = FakePageTable::new(8);
+        let mut frame_allocator = FakeFrameAllocator((1..5).map(frame).collect());
+        let mut program_break = ProgramBreak::new(VirtAddr::new(HEAP_START), 2 * Size2MiB::SIZE);
+
+        let too_far = VirtAddr::new(HEAP_START + 2 * Size2MiB::SIZE + 1);
+        assert!(unsafe {
+            set_break(&mut program_break, too_far, &page_table, &mut frame_allocator)
+        }
+        .is_err());
+        let too_low = VirtAddr::new(HEAP_START - 1);
+        assert!(unsafe {
+            set_break(&mut program_break, too_low, &page_table, &mut frame_allocator)
+        }
+        .is_err());
+        assert_eq!(frame_allocator.0.len(), 4);
+
+        // The heap can't grow into memory that is mapped already.
+        unsafe {
+            page_table
+                .map_to_with_table_flags(
+                    heap_page(1),
+                    frame(6),
+                    PageTableFlags::PRESENT,
+                    PageTableFlags::PRESENT,
+                )
+                .unwrap()
+                .ignore();
+        }
+        let new = VirtAddr::new(HEAP_START + 2 * Size2MiB::SIZE);
+        assert!(unsafe { set_break(&mut program_break, new, &page_table, &mut frame_allocator) }
+            .is_err());
+        assert_eq!(frame_allocator.0.len(), 4);
+    }
+}
@@ -15,12 +15,29 @@
 //
 
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use oak_channel::Channel;
-use oak_restricted_kernel_interface::{Errno, OAK_CHANNEL_FD};
+use oak_channel::{is_peer_closed, Channel, ChannelError};
+use oak_restricted_kernel_interface::{
+    syscalls::{IOCTL_FLUSH, IOCTL_MAX_MESSAGE_SIZE, IOCTL_SET_LOG_LEVEL},
+    Errno, OAK_CHANNEL_FD,
+};
 
 use super::fd::FileDescriptor;
 
+/// Kernel argument that allows the payload to change the kernel's log level
+/// with `IOCTL_SET_LOG_LEVEL`.
+pub const LOG_LEVEL_IOCTL_ARG: &str = "log_level_ioctl";
+
+/// Whether the payload is allowed to change the kernel's log level.
+static LOG_LEVEL_IOCTL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allows the payload to change the kernel's log level via
+/// `IOCTL_SET_LOG_LEVEL`.
+pub fn enable_log_level_ioctl() {
+    LOG_LEVEL_IOCTL_ENABLED.store(true, Ordering::Relaxed);
+}
+
 #[repr(transparent)]
 pub struct ChannelDescriptor {
     channel: Box<dyn Channel>,
@@ -33,9 +50,15 @@ impl ChannelDescriptor {
 }
 
 impl FileDescriptor for ChannelDescriptor {
+    /// Returns 0 (end of file) if the peer closed the channel before any data
+    /// arrived.
     fn read(&mut self, buf: &mut [u8]) -> Result<isize, Errno> {
         let size: isize = buf.len().try_into().map_err(|_| Errno::EINVAL)?;
-        self.channel.read_exact(buf).map_err(|_| Errno::EIO).map(|_| size)
+        match self.channel.read_exact(buf) {
+            Ok(()) => Ok(size),
+            Err(err) if is_peer_closed(&err) => Ok(0),
+            Err(_) => Err(Errno::EIO),
+        }
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<isize, Errno> {
@@ -46,6 +69,27 @@ impl FileDescriptor for ChannelDescriptor {
     fn sync(&mut self) -> Result<(), Errno> {
         self.channel.flush().map_err(|_| Errno::EIO)
     }
+
+    fn ioctl(&mut self, request: u64, arg: usize) -> Result<isize, Errno> {
+        let result = match request {
+            IOCTL_FLUSH => self.sync().map(|()| 0)?,
+            IOCTL_MAX_MESSAGE_SIZE => self.channel.max_message_size().min(isize::MAX as usize),
+            IOCTL_SET_LOG_LEVEL => {
+                if !LOG_LEVEL_IOCTL_ENABLED.load(Ordering::Relaxed) {
+                    return Err(Errno::EPERM);
+                }
+                let level = crate::logging::level_filter_from_repr(arg).ok_or(Errno::EINVAL)?;
+                crate::logging::set_max_level(level) as usize
+            }
+            _ => self.channel.ioctl(request, arg).map_err(|err| match err {
+                ChannelError::Unsupported => Errno::ENOTTY,
+                ChannelError::InvalidArgument => Errno::EINVAL,
+                ChannelError::Io | ChannelError::VersionMismatch => Errno::EIO,
+            })?,
+        };
+        // Results that don't fit would be mistaken for an error.
+        result.try_into().map_err(|_| Errno::EINVAL)
+    }
 }
 
 /// Registers a handler for the Oak communication channel file descriptor
@@ -0,0 +1,53 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Enumeration of the ACPI devices by the payload.
+
+use alloc::vec::Vec;
+use core::ffi::{c_size_t, c_ssize_t, c_void};
+
+use oak_core::sync::OnceCell;
+use oak_restricted_kernel_interface::syscalls::AcpiDeviceInfo;
+
+use super::{check_user_buffer, copy_to_user};
+use crate::acpi::DeviceInfo;
+
+/// The devices found in the ACPI namespace at boot.
+static DEVICES: OnceCell<Vec<AcpiDeviceInfo>> = OnceCell::new();
+
+/// Makes the devices available to the payload via
+/// `Syscall::UnstableGetAcpiDevices`.
+///
+/// If this is never called (e.g. because there are no ACPI tables), the
+/// payload gets an empty list.
+pub fn register(devices: &[DeviceInfo]) {
+    if DEVICES.set(devices.iter().map(DeviceInfo::to_abi).collect()).is_err() {
+        log::warn!("ACPI devices were registered more than once");
+    }
+}
+
+pub fn syscall_unstable_get_acpi_devices(buf: *mut c_void, count: c_size_t) -> c_ssize_t {
+    if let Err(err) = check_user_buffer::<AcpiDeviceInfo>(buf, count) {
+        return err as isize;
+    }
+    let devices = DEVICES.get().map_or(&[][..], Vec::as_slice);
+
+    // Copy as many devices as fit into the buffer.
+    let count = count.min(devices.len());
+    // Safety: we've checked that the buffer is aligned and in user space.
+    unsafe { copy_to_user(buf, &devices[..count]) };
+    count as isize
+}
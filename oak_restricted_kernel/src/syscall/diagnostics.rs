@@ -0,0 +1,195 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Memory usage and interrupt diagnostics for debugging payloads that run out
+//! of memory or get swamped by interrupts.
+
+use core::{
+    ffi::{c_size_t, c_ssize_t, c_void},
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use linked_list_allocator::Heap;
+use oak_restricted_kernel_interface::{
+    syscalls::{MemoryStats, INTERRUPT_VECTORS},
+    Errno,
+};
+use x86_64::VirtAddr;
+
+use super::{check_user_buffer, copy_to_user, mmap::with_mappings};
+use crate::{
+    interrupts::copy_interrupt_counts, memory::with_kernel_heap,
+    mm::frame_allocator::PhysicalMemoryAllocator,
+};
+
+/// Kernel argument that enables `Syscall::UnstableGetMemoryStats` and
+/// `Syscall::UnstableGetInterruptCounts`.
+pub const DIAGNOSTICS_ARG: &str = "diagnostics";
+
+/// Whether the payload is allowed to retrieve the memory statistics and
+/// interrupt counts.
+static DIAGNOSTICS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allows the payload to retrieve memory statistics via
+/// `Syscall::UnstableGetMemoryStats` and interrupt counts via
+/// `Syscall::UnstableGetInterruptCounts`.
+pub fn enable_diagnostics_syscall() {
+    DIAGNOSTICS_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Assembles a memory usage snapshot from the individual allocators.
+pub fn memory_stats<const N: usize>(
+    frame_allocator: &mut PhysicalMemoryAllocator<N>,
+    kernel_heap: &Heap,
+    guest_host_heap: &Heap,
+    mappings: &[Range<VirtAddr>],
+) -> MemoryStats {
+    let (total_frames, _) = frame_allocator.num_valid_frames();
+    let (allocated_frames, _) = frame_allocator.num_allocated_frames();
+    MemoryStats {
+        total_frames: total_frames as u64,
+        free_frames: total_frames.saturating_sub(allocated_frames) as u64,
+        largest_free_run: frame_allocator
+            .largest_available()
+            .map_or(0, |range| range.end - range.start),
+        kernel_heap_used: kernel_heap.used() as u64,
+        kernel_heap_free: kernel_heap.free() as u64,
+        guest_host_heap_used: guest_host_heap.used() as u64,
+        guest_host_heap_free: guest_host_heap.free() as u64,
+        payload_mapped_bytes: mappings.iter().map(|range| range.end - range.start).sum(),
+    }
+}
+
+pub fn syscall_unstable_get_memory_stats(buf: *mut c_void) -> c_ssize_t {
+    if !DIAGNOSTICS_ENABLED.load(Ordering::Relaxed) {
+        return Errno::ENOSYS as isize;
+    }
+    if let Err(err) = check_user_buffer::<MemoryStats>(buf, 1) {
+        return err as isize;
+    }
+
+    // Don't block on any of the locks: the stats are only a debugging aid, and the
+    // payload can simply try again.
+    let stats = with_kernel_heap(|kernel_heap| {
+        let guest_host_heap = crate::GUEST_HOST_HEAP.get()?.try_lock()?;
+        let mut frame_allocator = crate::FRAME_ALLOCATOR.try_lock()?;
+        with_mappings(|mappings| {
+            memory_stats(&mut frame_allocator, kernel_heap, &guest_host_heap, mappings)
+        })
+    })
+    .flatten();
+    let Some(stats) = stats else {
+        return Errno::EAGAIN as isize;
+    };
+
+    // Safety: we've checked that the buffer is aligned and in user space.
+    unsafe { copy_to_user(buf, &[stats]) };
+    0
+}
+
+pub fn syscall_unstable_get_interrupt_counts(buf: *mut c_void, count: c_size_t) -> c_ssize_t {
+    if !DIAGNOSTICS_ENABLED.load(Ordering::Relaxed) {
+        return Errno::ENOSYS as isize;
+    }
+    if let Err(err) = check_user_buffer::<u64>(buf, count) {
+        return err as isize;
+    }
+
+    let mut counts = [0u64; INTERRUPT_VECTORS];
+    let written = copy_interrupt_counts(&mut counts[..count.min(INTERRUPT_VECTORS)]);
+    // Safety: we've checked that the buffer is aligned and in user space.
+    unsafe { copy_to_user(buf, &counts[..written]) };
+    written as isize
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{alloc::Layout, vec, vec::Vec};
+
+    use x86_64::{
+        structures::paging::{
+            frame::PhysFrameRange, FrameAllocator, PageSize, PhysFrame, Size2MiB,
+        },
+        PhysAddr,
+    };
+
+    use super::*;
+
+    fn frame_range(start: u64, end: u64) -> PhysFrameRange<Size2MiB> {
+        PhysFrame::range(
+            PhysFrame::from_start_address(PhysAddr::new(start * Size2MiB::SIZE)).unwrap(),
+            PhysFrame::from_start_address(PhysAddr::new(end * Size2MiB::SIZE)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn memory_stats_aggregates_allocators() {
+        let mut frame_allocator = PhysicalMemoryAllocator::<1>::new_range(frame_range(0, 16));
+        frame_allocator.mark_valid(frame_range(0, 16), true);
+        // Allocate the first two frames, and punch a hole in the middle of the rest so
+        // that the longest run is [9, 16).
+        let _: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+        let _: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+        frame_allocator.reserve(frame_range(8, 9)).unwrap();
+
+        let mut kernel_memory: Vec<u64> = vec![0; 512];
+        let mut kernel_heap = unsafe { Heap::new(kernel_memory.as_mut_ptr() as *mut u8, 4096) };
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        kernel_heap.allocate_first_fit(layout).unwrap();
+
+        let mut guest_host_memory: Vec<u64> = vec![0; 256];
+        let guest_host_heap = unsafe { Heap::new(guest_host_memory.as_mut_ptr() as *mut u8, 2048) };
+
+        let mappings = [
+            VirtAddr::new(0x20_0000)..VirtAddr::new(0x60_0000),
+            VirtAddr::new(0x100_0000)..VirtAddr::new(0x120_0000),
+        ];
+
+        let stats = memory_stats(&mut frame_allocator, &kernel_heap, &guest_host_heap, &mappings);
+        assert_eq!(
+            stats,
+            MemoryStats {
+                total_frames: 15,
+                free_frames: 13,
+                largest_free_run: 7,
+                kernel_heap_used: 256,
+                kernel_heap_free: 4096 - 256,
+                guest_host_heap_used: 0,
+                guest_host_heap_free: 2048,
+                payload_mapped_bytes: 6 * Size2MiB::SIZE,
+            }
+        );
+    }
+
+    #[test]
+    fn syscall_disabled_by_default() {
+        let mut stats = MemoryStats::default();
+        assert_eq!(
+            syscall_unstable_get_memory_stats(&mut stats as *mut MemoryStats as *mut c_void),
+            Errno::ENOSYS as isize
+        );
+    }
+
+    #[test]
+    fn interrupt_counts_syscall_disabled_by_default() {
+        let mut counts = [0u64; 4];
+        assert_eq!(
+            syscall_unstable_get_interrupt_counts(counts.as_mut_ptr() as *mut c_void, counts.len()),
+            Errno::ENOSYS as isize
+        );
+    }
+}
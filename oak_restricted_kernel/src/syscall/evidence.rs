@@ -0,0 +1,125 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Retrieval of evidence bundles and attestation reports by the payload, and
+//! extension of the measurement registers they cover.
+
+use alloc::vec::Vec;
+use core::ffi::{c_ssize_t, c_void};
+
+use oak_restricted_kernel_interface::{
+    syscalls::{
+        ATTESTATION_REPORT_SIZE, EVIDENCE_REPORT_DATA_SIZE, MAX_ATTESTATION_REPORTS,
+        MEASUREMENT_REGISTER_SIZE,
+    },
+    Errno,
+};
+use oak_sev_snp_attestation_report::AttestationReport;
+use zerocopy::AsBytes;
+
+use super::{check_user_buffer, copy_from_user, copy_to_user};
+use crate::attestation::{self, measurement_registers};
+
+pub fn syscall_unstable_get_evidence_bundle(
+    report_data: *const c_void,
+    buf: *mut c_void,
+    count: usize,
+) -> c_ssize_t {
+    let checked = check_user_buffer::<[u8; EVIDENCE_REPORT_DATA_SIZE]>(report_data, 1)
+        .and_then(|()| check_user_buffer::<u8>(buf, count));
+    if let Err(err) = checked {
+        return err as isize;
+    }
+    let report_data = {
+        let mut data = [0u8; EVIDENCE_REPORT_DATA_SIZE];
+        // Safety: we've checked that the report data is in user space.
+        unsafe { copy_from_user(report_data, &mut data) };
+        data
+    };
+    let bundle =
+        match attestation::evidence_bundle(&report_data).and_then(|bundle| bundle.to_bytes()) {
+            Ok(bundle) => bundle,
+            Err(err) => {
+                log::warn!("couldn't assemble an evidence bundle: {}", err);
+                return Errno::EIO as isize;
+            }
+        };
+    if bundle.len() > count {
+        return Errno::ERANGE as isize;
+    }
+    // Safety: the bundle fits in the buffer, which we've checked is in user space.
+    unsafe { copy_to_user(buf, &bundle) };
+    bundle.len() as isize
+}
+
+static_assertions::assert_eq_size!(AttestationReport, [u8; ATTESTATION_REPORT_SIZE]);
+
+pub fn syscall_unstable_get_attestation_reports(
+    report_datas: *const c_void,
+    buf: *mut c_void,
+    count: usize,
+) -> c_ssize_t {
+    if count > MAX_ATTESTATION_REPORTS {
+        return Errno::EINVAL as isize;
+    }
+    let checked = check_user_buffer::<[u8; EVIDENCE_REPORT_DATA_SIZE]>(report_datas, count)
+        .and_then(|()| check_user_buffer::<[u8; ATTESTATION_REPORT_SIZE]>(buf, count));
+    if let Err(err) = checked {
+        return err as isize;
+    }
+    let mut data = [[0u8; EVIDENCE_REPORT_DATA_SIZE]; MAX_ATTESTATION_REPORTS];
+    // Safety: we've checked that the `count` report-data values are in user space.
+    unsafe { copy_from_user(report_datas, &mut data[..count]) };
+    let reports = match attestation::get_attestation_batch(&data[..count]) {
+        Ok(reports) => reports,
+        Err(err) => {
+            log::warn!("couldn't request attestation reports: {}", err);
+            return Errno::EIO as isize;
+        }
+    };
+    let bytes: Vec<u8> = reports.iter().flat_map(|report| report.as_bytes()).copied().collect();
+    // Safety: there is one report per report-data value, and we've checked that
+    // the buffer for that many reports is in user space.
+    unsafe { copy_to_user(buf, &bytes) };
+    reports.len() as isize
+}
+
+pub fn syscall_unstable_extend_measurement_register(
+    index: usize,
+    digest: *const c_void,
+) -> c_ssize_t {
+    if index >= measurement_registers::NUM_MEASUREMENT_REGISTERS {
+        return Errno::EINVAL as isize;
+    }
+    // The other registers record what the kernel loaded; letting the payload
+    // extend them would make their values meaningless to a verifier.
+    if index != measurement_registers::WORKLOAD_REGISTER {
+        return Errno::EPERM as isize;
+    }
+    if let Err(err) = check_user_buffer::<[u8; MEASUREMENT_REGISTER_SIZE]>(digest, 1) {
+        return err as isize;
+    }
+    let mut value = [0u8; MEASUREMENT_REGISTER_SIZE];
+    // Safety: we've checked that the digest is in user space.
+    unsafe { copy_from_user(digest, &mut value) };
+    match measurement_registers::extend(index, &value) {
+        Ok(()) => 0,
+        Err(err) => {
+            log::warn!("couldn't extend measurement register {}: {}", index, err);
+            Errno::EINVAL as isize
+        }
+    }
+}
@@ -17,7 +17,7 @@
 use alloc::{
     boxed::Box,
     collections::{btree_map::Entry, BTreeMap},
-    slice,
+    vec::Vec,
 };
 use core::{
     cmp::min,
@@ -26,11 +26,21 @@ use core::{
 
 use oak_restricted_kernel_interface::Errno;
 use spinning_top::Spinlock;
+use zeroize::Zeroize;
+
+use super::{check_user_buffer, copy_from_user, copy_to_user};
 
 pub trait FileDescriptor: Send {
     fn read(&mut self, buf: &mut [u8]) -> Result<isize, Errno>;
     fn write(&mut self, buf: &[u8]) -> Result<isize, Errno>;
     fn sync(&mut self) -> Result<(), Errno>;
+
+    /// Performs a device-specific operation; see `Syscall::Ioctl`.
+    ///
+    /// Defaults to rejecting all requests.
+    fn ioctl(&mut self, _request: u64, _arg: usize) -> Result<isize, Errno> {
+        Err(Errno::ENOTTY)
+    }
 }
 
 type Fd = c_int;
@@ -76,30 +86,53 @@ pub fn unregister(fd: Fd) -> Option<Box<dyn FileDescriptor>> {
     FILE_DESCRIPTORS.lock().remove(&fd)
 }
 
+/// Allocates a zeroed kernel buffer of `count` bytes.
+///
+/// Reads and writes go through such a buffer rather than the user buffer
+/// itself, so that user access only needs to be enabled while copying.
+fn bounce_buffer(count: usize) -> Result<Vec<u8>, Errno> {
+    let mut data = Vec::new();
+    data.try_reserve_exact(count).map_err(|_| Errno::ENOMEM)?;
+    data.resize(count, 0);
+    Ok(data)
+}
+
 pub fn syscall_read(fd: c_int, buf: *mut c_void, count: c_size_t) -> c_ssize_t {
-    // We should validate that the pointer and count are valid, as these come from
-    // userspace and therefore are not to be trusted, but right now everything
-    // is in kernel space so there is nothing to check.
-    let data = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+    let mut data = match check_user_buffer::<u8>(buf, count).and_then(|()| bounce_buffer(count)) {
+        Ok(data) => data,
+        Err(err) => return err as isize,
+    };
 
-    FILE_DESCRIPTORS
+    let result = FILE_DESCRIPTORS
         .lock()
         .get_mut(&fd)
-        .map(|channel| channel.read(data).unwrap_or_else(|err| err as isize))
-        .unwrap_or(Errno::EBADF as isize)
+        .map(|channel| channel.read(&mut data).unwrap_or_else(|err| err as isize))
+        .unwrap_or(Errno::EBADF as isize);
+    if result > 0 {
+        // Safety: we've checked that the buffer is in user space, and the descriptor
+        // can't have read more than `count` bytes.
+        unsafe { copy_to_user(buf, &data[..result as usize]) };
+    }
+    // The data may be secret, e.g. a derived key.
+    data.zeroize();
+    result
 }
 
 pub fn syscall_write(fd: c_int, buf: *const c_void, count: c_size_t) -> c_ssize_t {
-    // We should validate that the pointer and count are valid, as these come from
-    // userspace and therefore are not to be trusted, but right now everything
-    // is in kernel space so there is nothing to check.
-    let data = unsafe { slice::from_raw_parts(buf as *mut u8, count) };
+    let mut data = match check_user_buffer::<u8>(buf, count).and_then(|()| bounce_buffer(count)) {
+        Ok(data) => data,
+        Err(err) => return err as isize,
+    };
+    // Safety: we've checked that the buffer is in user space.
+    unsafe { copy_from_user(buf, &mut data) };
 
-    FILE_DESCRIPTORS
+    let result = FILE_DESCRIPTORS
         .lock()
         .get_mut(&fd)
-        .map(|channel| channel.write(data).unwrap_or_else(|err| err as isize))
-        .unwrap_or(Errno::EBADF as isize)
+        .map(|channel| channel.write(&data).unwrap_or_else(|err| err as isize))
+        .unwrap_or(Errno::EBADF as isize);
+    data.zeroize();
+    result
 }
 
 pub fn syscall_fsync(fd: c_int) -> c_ssize_t {
@@ -109,3 +142,11 @@ pub fn syscall_fsync(fd: c_int) -> c_ssize_t {
         .map(|channel| channel.sync().map_or_else(|err| err as isize, |()| 0))
         .unwrap_or(Errno::EBADF as isize)
 }
+
+pub fn syscall_ioctl(fd: c_int, request: u64, arg: usize) -> c_ssize_t {
+    FILE_DESCRIPTORS
+        .lock()
+        .get_mut(&fd)
+        .map(|descriptor| descriptor.ioctl(request, arg).unwrap_or_else(|err| err as isize))
+        .unwrap_or(Errno::EBADF as isize)
+}
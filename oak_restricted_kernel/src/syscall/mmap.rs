@@ -14,10 +14,12 @@
 // limitations under the License.
 //
 
+use alloc::vec::Vec;
 use core::{
     cmp::max,
     ffi::{c_int, c_size_t, c_void},
     iter::repeat_with,
+    ops::Range,
     slice,
 };
 
@@ -25,17 +27,55 @@ use oak_restricted_kernel_interface::{
     syscalls::{MmapFlags, MmapProtection},
     Errno,
 };
+use spinning_top::Spinlock;
 use x86_64::{
     align_up,
-    structures::paging::{FrameAllocator, Page, PageSize, Size2MiB},
+    instructions::tlb,
+    structures::paging::{page::PageRange, FrameAllocator, Page, PageSize, Size2MiB},
     VirtAddr,
 };
 
+use super::USER_SPACE_LIMIT;
 use crate::{
-    mm::{Mapper, PageTableFlags},
+    mm::{
+        deferred_flush::DeferredFlush,
+        mlock::{lock_pages, unlock_pages},
+        Mapper, PageTableFlags,
+    },
     FRAME_ALLOCATOR, PAGE_TABLES,
 };
 
+/// Virtual memory ranges handed out by `mmap()`, so that they can be wiped on
+/// shutdown.
+static MAPPINGS: Spinlock<Vec<Range<VirtAddr>>> = Spinlock::new(Vec::new());
+
+/// Runs `f` over the memory ranges that have been mapped by `mmap()`.
+///
+/// Returns `None` if the list of mappings is locked.
+pub fn with_mappings<T>(f: impl FnOnce(&[Range<VirtAddr>]) -> T) -> Option<T> {
+    MAPPINGS.try_lock().map(|mappings| f(&mappings))
+}
+
+/// Returns the flags for user pages mapped with the protection `prot`.
+///
+/// All of them are accessible from user mode, unlike any of the kernel's own
+/// pages.
+pub(crate) fn user_page_flags(prot: MmapProtection) -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::ENCRYPTED
+        | if prot.contains(MmapProtection::PROT_EXEC) {
+            PageTableFlags::empty()
+        } else {
+            PageTableFlags::NO_EXECUTE
+        }
+        | if prot.contains(MmapProtection::PROT_WRITE) {
+            PageTableFlags::WRITABLE
+        } else {
+            PageTableFlags::empty()
+        }
+}
+
 pub fn mmap(
     addr: Option<VirtAddr>,
     size: usize,
@@ -76,19 +116,7 @@ pub fn mmap(
     // Iterator that keeps allocating physical frames.
     let frames = repeat_with(|| FRAME_ALLOCATOR.lock().allocate_frame());
 
-    let pt_flags = PageTableFlags::PRESENT
-        | PageTableFlags::USER_ACCESSIBLE
-        | PageTableFlags::ENCRYPTED
-        | if prot.contains(MmapProtection::PROT_EXEC) {
-            PageTableFlags::empty()
-        } else {
-            PageTableFlags::NO_EXECUTE
-        }
-        | if prot.contains(MmapProtection::PROT_WRITE) {
-            PageTableFlags::WRITABLE
-        } else {
-            PageTableFlags::empty()
-        };
+    let pt_flags = user_page_flags(prot);
 
     let pages = {
         // This critical section is rather long...
@@ -112,11 +140,13 @@ pub fn mmap(
         })?;
 
         // For each page we also need a physical frame to back it to create a mapping.
+        // Large mappings (such as payload segments) are flushed in one go.
+        let mut flushes = DeferredFlush::new();
         for (page, frame) in pages.zip(frames) {
             // Safety: find_unallocated_pages returns, well, unallocated pages and we've
             // held the lock all the time so we can be sure that nobody else has
             // mapped those pages.
-            unsafe {
+            let flush = unsafe {
                 pt.map_to_with_table_flags(
                     page,
                     frame.ok_or_else(|| {
@@ -152,18 +182,19 @@ pub fn mmap(
                     );
                     Errno::ENOMEM
                 })?
-                .flush();
-            }
+            };
+            flushes.defer(page, flush);
         }
 
         pages
     };
+    MAPPINGS.lock().push(pages.start.start_address()..pages.end.start_address());
 
     // Safety: we've just allocated and mapped that chunk of memory, so (a) we know
     // it's valid and (b) nobody else can have a reference to it yet.
     let buf = unsafe { slice::from_raw_parts_mut(pages.start.start_address().as_mut_ptr(), size) };
     // Zero out the memory, as required by mmap() semantics.
-    buf.fill(0u8);
+    crate::smap::with_user_access(|| buf.fill(0u8));
     Ok(buf)
 }
 
@@ -195,3 +226,56 @@ pub fn syscall_mmap(
     mmap(Some(VirtAddr::from_ptr(addr)), size, prot, flags)
         .map_or_else(|err| err as isize, |ptr| ptr.as_ptr() as isize)
 }
+
+/// Returns the 2 MiB pages that cover `len` bytes starting at `addr`, if they
+/// are all in user space.
+fn user_pages(addr: *const c_void, len: usize) -> Result<PageRange<Size2MiB>, Errno> {
+    let start = VirtAddr::try_new(addr as u64).map_err(|_| Errno::EINVAL)?;
+    let end = start
+        .as_u64()
+        .checked_add(len as u64)
+        .filter(|end| *end <= USER_SPACE_LIMIT)
+        .ok_or(Errno::EINVAL)?;
+    Ok(Page::range(
+        Page::containing_address(start),
+        Page::containing_address(VirtAddr::new(align_up(end, Size2MiB::SIZE))),
+    ))
+}
+
+pub fn syscall_mlock(addr: *const c_void, len: c_size_t) -> isize {
+    let pages = match user_pages(addr, len) {
+        Ok(pages) => pages,
+        Err(err) => return err as isize,
+    };
+    let pt_guard = PAGE_TABLES.lock();
+    let pt = pt_guard.get().unwrap();
+    // Safety: PAGE_TABLES holds the currently active page tables, which contain
+    // the direct mapping of physical memory.
+    let result = unsafe { lock_pages(pt, &mut *FRAME_ALLOCATOR.lock(), pages) };
+    // Copy-on-write pages may have been replaced by private copies.
+    tlb::flush_all();
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            log::warn!("mlock: {}", err);
+            Errno::ENOMEM as isize
+        }
+    }
+}
+
+pub fn syscall_munlock(addr: *const c_void, len: c_size_t) -> isize {
+    let pages = match user_pages(addr, len) {
+        Ok(pages) => pages,
+        Err(err) => return err as isize,
+    };
+    let pt_guard = PAGE_TABLES.lock();
+    let pt = pt_guard.get().unwrap();
+    // Safety: PAGE_TABLES holds the currently active page tables.
+    match unsafe { unlock_pages(pt, pages) } {
+        Ok(()) => 0,
+        Err(err) => {
+            log::warn!("munlock: {}", err);
+            Errno::ENOMEM as isize
+        }
+    }
+}
@@ -14,13 +14,20 @@
 // limitations under the License.
 //
 
-mod channel;
+pub mod brk;
+pub mod channel;
+pub mod devices;
+pub mod diagnostics;
 pub mod dice_data;
+mod evidence;
 mod fd;
 mod key;
 pub mod mmap;
+pub mod payload_log;
 mod process;
+mod stats;
 mod stdio;
+mod vsock;
 
 #[cfg(feature = "initrd")]
 mod switch_process;
@@ -32,6 +39,7 @@ use alloc::boxed::Box;
 use core::{arch::asm, ffi::c_void};
 
 use oak_channel::Channel;
+use oak_core::timer::Timer;
 #[cfg(not(feature = "initrd"))]
 use oak_restricted_kernel_dice::DerivedKey;
 use oak_restricted_kernel_interface::{Errno, Syscall};
@@ -39,6 +47,7 @@ use x86_64::{
     registers::{
         control::Efer,
         model_specific::{EferFlags, GsBase, KernelGsBase, LStar},
+        rflags::RFlags,
     },
     VirtAddr,
 };
@@ -46,11 +55,21 @@ use x86_64::{
 #[cfg(feature = "initrd")]
 use self::switch_process::syscall_unstable_switch_proccess;
 use self::{
-    fd::{syscall_fsync, syscall_read, syscall_write},
-    mmap::syscall_mmap,
+    brk::syscall_brk,
+    devices::syscall_unstable_get_acpi_devices,
+    diagnostics::{syscall_unstable_get_interrupt_counts, syscall_unstable_get_memory_stats},
+    evidence::{
+        syscall_unstable_extend_measurement_register, syscall_unstable_get_attestation_reports,
+        syscall_unstable_get_evidence_bundle,
+    },
+    fd::{syscall_fsync, syscall_ioctl, syscall_read, syscall_write},
+    mmap::{syscall_mlock, syscall_mmap, syscall_munlock},
+    payload_log::syscall_unstable_log,
     process::syscall_exit,
+    stats::syscall_unstable_get_syscall_stats,
+    vsock::syscall_unstable_get_vsock_guest_cid,
 };
-use crate::mm;
+use crate::{avx, mm};
 
 /// State we need to track for system calls.
 ///
@@ -71,6 +90,142 @@ struct GsData {
 
     /// User flags. Saved from R11.
     user_flags: usize,
+
+    /// Kernel stack pointer right before `syscall_entrypoint` calls into
+    /// `syscall_handler`. Only used for the stack alignment check.
+    handler_sp: VirtAddr,
+
+    /// Number of bytes to reserve on the kernel stack for saving the extended
+    /// register state of the user code with `XSAVE`.
+    xsave_size: u64,
+}
+
+/// Upper limit (exclusive) of the user space part of the virtual address space.
+pub(crate) const USER_SPACE_LIMIT: u64 = 0x8000_0000_0000;
+
+/// Checks that a buffer of `count` values of type `T` passed in by the payload
+/// is suitably aligned and lies entirely within user space.
+fn check_user_buffer<T>(buf: *const c_void, count: usize) -> Result<(), Errno> {
+    if buf.is_null() || buf as usize % core::mem::align_of::<T>() != 0 {
+        return Err(Errno::EFAULT);
+    }
+    let end = count
+        .checked_mul(core::mem::size_of::<T>())
+        .and_then(|size| (buf as u64).checked_add(size as u64));
+    match end {
+        Some(end) if end <= USER_SPACE_LIMIT => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copies `src` into the user buffer at `dst`.
+///
+/// # Safety
+///
+/// The caller must have checked `dst` with `check_user_buffer` for `src.len()`
+/// values of type `T`.
+unsafe fn copy_to_user<T: Copy>(dst: *mut c_void, src: &[T]) {
+    // Safety: the buffer is in user space, which is mapped in the current address
+    // space, and can't overlap with kernel memory.
+    crate::smap::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut T, src.len())
+    })
+}
+
+/// Copies the user buffer at `src` into `dst`.
+///
+/// # Safety
+///
+/// The caller must have checked `src` with `check_user_buffer` for `dst.len()`
+/// values of type `T`.
+unsafe fn copy_from_user<T: Copy>(src: *const c_void, dst: &mut [T]) {
+    // Safety: the buffer is in user space, which is mapped in the current address
+    // space, and can't overlap with kernel memory.
+    crate::smap::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src as *const T, dst.as_mut_ptr(), dst.len())
+    })
+}
+
+/// Checks that the state saved on syscall entry is consistent with the syscall
+/// having been invoked from user mode.
+///
+/// `SYSCALL` does not save the code segment, so we can't check the CPL of the
+/// caller directly. However, user mode code always lives in the lower half of
+/// the address space and can't raise its I/O privilege level, so a saved
+/// instruction pointer, stack pointer or flags register that doesn't match
+/// that indicates that `SYSCALL` was executed from kernel mode (or that the
+/// saved state was tampered with).
+fn check_user_context(
+    user_ip: VirtAddr,
+    user_sp: VirtAddr,
+    user_flags: usize,
+) -> Result<(), &'static str> {
+    if user_ip.as_u64() >= USER_SPACE_LIMIT {
+        return Err("syscall instruction pointer is not in user space");
+    }
+    if user_sp.as_u64() >= USER_SPACE_LIMIT {
+        return Err("syscall stack pointer is not in user space");
+    }
+    if RFlags::from_bits_truncate(user_flags as u64)
+        .intersects(RFlags::IOPL_HIGH | RFlags::IOPL_LOW)
+    {
+        return Err("syscall invoked with a non-zero I/O privilege level");
+    }
+    Ok(())
+}
+
+/// Alignment of the kernel stack pointer at the `call` into the syscall
+/// handler, as required by the SysV ABI.
+const KERNEL_STACK_ALIGNMENT: u64 = 16;
+
+/// Alignment of a stack pointer interrupted by a syscall or an interrupt.
+///
+/// Neither `SYSCALL` nor an interrupt is a `call`, so the ABI doesn't promise
+/// 16 bytes here; but compiled code only ever moves RSP in whole words, so
+/// anything that isn't 8-byte aligned means the stack has been corrupted.
+pub(crate) const INTERRUPTED_STACK_ALIGNMENT: u64 = 8;
+
+/// Returns whether `sp` is a multiple of `align`, which must be a power of two.
+pub(crate) fn is_stack_aligned(sp: VirtAddr, align: u64) -> bool {
+    debug_assert!(align.is_power_of_two());
+    sp.as_u64() & (align - 1) == 0
+}
+
+/// Reads the user state saved in `GsData` by `syscall_entrypoint`.
+fn saved_user_context() -> (VirtAddr, VirtAddr, usize) {
+    let user_sp: u64;
+    let user_ip: u64;
+    let user_flags: usize;
+    // Safety: GS points to `GsData` while we're handling a syscall; see the offsets
+    // in `syscall_entrypoint`.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0x8]",
+            "mov {}, gs:[0x10]",
+            "mov {}, gs:[0x18]",
+            out(reg) user_sp,
+            out(reg) user_ip,
+            out(reg) user_flags,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    (VirtAddr::new_truncate(user_ip), VirtAddr::new_truncate(user_sp), user_flags)
+}
+
+/// Reads the kernel stack pointer saved in `GsData` by `syscall_entrypoint`
+/// right before calling the handler.
+fn saved_handler_sp() -> VirtAddr {
+    let handler_sp: u64;
+    // Safety: GS points to `GsData` while we're handling a syscall; see the offsets
+    // in `syscall_entrypoint`.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0x20]",
+            out(reg) handler_sp,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    VirtAddr::new_truncate(handler_sp)
 }
 
 pub fn enable_syscalls(
@@ -97,6 +252,8 @@ pub fn enable_syscalls(
         user_sp: VirtAddr::zero(),
         user_ip: VirtAddr::zero(),
         user_flags: 0,
+        handler_sp: VirtAddr::zero(),
+        xsave_size: avx::xsave_area_size().expect("couldn't size the XSAVE area"),
     }));
 
     KernelGsBase::write(VirtAddr::from_ptr(gsdata));
@@ -108,6 +265,12 @@ pub fn enable_syscalls(
     }
 }
 
+/// Allows the payload to retrieve per-syscall statistics using
+/// `Syscall::UnstableGetSyscallStats`.
+pub fn enable_stats_syscall() {
+    stats::enable_stats_syscall();
+}
+
 /// Rust wrapper for system calls.
 ///
 /// This function assumes the 64-bit SysV ABI, not the syscall ABI!
@@ -122,22 +285,93 @@ extern "sysv64" fn syscall_handler(
 ) -> isize {
     // SysV ABI: arguments are in RDI, RSI, RDX, RCX, R8, R9, top of stack; return
     // value in RAX (which matches SYSRET!)
-    match Syscall::from_repr(syscall) {
-        Some(Syscall::Read) => syscall_read(arg1 as i32, arg2 as *mut c_void, arg3),
-        Some(Syscall::Write) => syscall_write(arg1 as i32, arg2 as *const c_void, arg3),
-        Some(Syscall::Exit) => syscall_exit(arg1 as i32),
-        Some(Syscall::Mmap) => {
-            syscall_mmap(arg1 as *const c_void, arg2, arg3, arg4, arg5 as i32, arg6)
-        }
-        Some(Syscall::Fsync) => syscall_fsync(arg1 as i32),
+    let (user_ip, user_sp, user_flags) = saved_user_context();
+    if let Err(err) = check_user_context(user_ip, user_sp, user_flags) {
+        // Something has gone very wrong; don't let the caller proceed.
+        panic!(
+            "rejecting syscall {} from unexpected context: {} (RIP: {:?})",
+            syscall, err, user_ip
+        );
+    }
+    // A misaligned stack breaks aligned SSE/AVX spills in the handler in ways that
+    // are hard to trace back, so catch it here in debug builds.
+    debug_assert!(
+        is_stack_aligned(saved_handler_sp(), KERNEL_STACK_ALIGNMENT),
+        "kernel stack misaligned on syscall entry: {:?}",
+        saved_handler_sp()
+    );
+    debug_assert!(
+        is_stack_aligned(user_sp, INTERRUPTED_STACK_ALIGNMENT),
+        "user stack misaligned on syscall entry: {:?}",
+        user_sp
+    );
+    let result = dispatch(syscall, arg1, arg2, arg3, arg4, arg5, arg6);
+    crate::vdso::update();
+    result
+}
+
+/// Dispatches a system call to its implementation, keeping track of how often
+/// each system call is invoked and how long it takes.
+fn dispatch(
+    syscall: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    arg6: usize,
+) -> isize {
+    let syscall = match Syscall::from_repr(syscall) {
+        Some(syscall) => syscall,
+        None => return Errno::ENOSYS as isize,
+    };
+    let slot = stats::slot(&syscall);
+    stats::record_dispatch(slot);
+    let timer = Timer::new_rdtsc();
+
+    let result = match syscall {
+        Syscall::Read => syscall_read(arg1 as i32, arg2 as *mut c_void, arg3),
+        Syscall::Write => syscall_write(arg1 as i32, arg2 as *const c_void, arg3),
+        Syscall::Exit => syscall_exit(arg1 as i32),
+        Syscall::Mmap => syscall_mmap(arg1 as *const c_void, arg2, arg3, arg4, arg5 as i32, arg6),
+        Syscall::Brk => syscall_brk(arg1 as *const c_void),
+        Syscall::Fsync => syscall_fsync(arg1 as i32),
+        Syscall::Ioctl => syscall_ioctl(arg1 as i32, arg2 as u64, arg3),
+        Syscall::Mlock => syscall_mlock(arg1 as *const c_void, arg2),
+        Syscall::Munlock => syscall_munlock(arg1 as *const c_void, arg2),
         #[cfg(feature = "initrd")]
-        Some(Syscall::UnstableSwitchProcess) => {
+        Syscall::UnstableSwitchProcess => {
             syscall_unstable_switch_proccess(arg1 as *mut c_void, arg2)
         }
         #[cfg(not(feature = "initrd"))]
-        Some(Syscall::UnstableSwitchProcess) => Errno::ENOSYS as isize,
-        None => Errno::ENOSYS as isize,
-    }
+        Syscall::UnstableSwitchProcess => Errno::ENOSYS as isize,
+        Syscall::UnstableGetSyscallStats => {
+            syscall_unstable_get_syscall_stats(arg1 as *mut c_void, arg2)
+        }
+        Syscall::UnstableLog => syscall_unstable_log(arg1, arg2 as *const c_void, arg3),
+        Syscall::UnstableGetMemoryStats => syscall_unstable_get_memory_stats(arg1 as *mut c_void),
+        Syscall::UnstableGetEvidenceBundle => {
+            syscall_unstable_get_evidence_bundle(arg1 as *const c_void, arg2 as *mut c_void, arg3)
+        }
+        Syscall::UnstableGetAcpiDevices => {
+            syscall_unstable_get_acpi_devices(arg1 as *mut c_void, arg2)
+        }
+        Syscall::UnstableGetVsockGuestCid => syscall_unstable_get_vsock_guest_cid(),
+        Syscall::UnstableGetInterruptCounts => {
+            syscall_unstable_get_interrupt_counts(arg1 as *mut c_void, arg2)
+        }
+        Syscall::UnstableGetAttestationReports => syscall_unstable_get_attestation_reports(
+            arg1 as *const c_void,
+            arg2 as *mut c_void,
+            arg3,
+        ),
+        Syscall::UnstableExtendMeasurementRegister => {
+            syscall_unstable_extend_measurement_register(arg1, arg2 as *const c_void)
+        }
+    };
+
+    stats::record_ticks(slot, timer.elapsed());
+    result
 }
 
 /// Main entry point for system calls in the Oak Restricted Kernel.
@@ -196,28 +430,31 @@ extern "C" fn syscall_entrypoint() {
             "push r9",
             "push r10",
 
-            // Make sure the stack is 16-byte aligned.
-            "sub rsp, 8",
+            // XSAVE needs RAX and RDX, so keep the syscall number on the stack as well.
+            "push rax",
 
-            // Back up the AVX registers.
-            // TODO(#3329): Update interrupt handler macro to support AVX, SSE or neither.
-            "sub rsp, 16*32",
-            "vmovups [rsp + 0*32], YMM0",
-            "vmovups [rsp + 1*32], YMM1",
-            "vmovups [rsp + 2*32], YMM2",
-            "vmovups [rsp + 3*32], YMM3",
-            "vmovups [rsp + 4*32], YMM4",
-            "vmovups [rsp + 5*32], YMM5",
-            "vmovups [rsp + 6*32], YMM6",
-            "vmovups [rsp + 7*32], YMM7",
-            "vmovups [rsp + 8*32], YMM8",
-            "vmovups [rsp + 9*32], YMM9",
-            "vmovups [rsp + 10*32], YMM10",
-            "vmovups [rsp + 11*32], YMM11",
-            "vmovups [rsp + 12*32], YMM12",
-            "vmovups [rsp + 13*32], YMM13",
-            "vmovups [rsp + 14*32], YMM14",
-            "vmovups [rsp + 15*32], YMM15",
+            // Back up the extended (x87, SSE, AVX) state, as the kernel is free to use those
+            // registers. The save area is sized from CPUID in `enable_syscalls`, and needs to be
+            // 64-byte aligned. RBX is callee-saved, so it keeps the stack pointer from before
+            // the save area across the call to the handler.
+            "mov rbx, rsp",
+            "sub rsp, gs:[0x28]",
+            "and rsp, -64",
+            // XRSTOR faults unless the reserved bytes of the XSAVE header are zero, and XSAVE
+            // doesn't write them, so clear the header first. RCX has already been saved.
+            "xor ecx, ecx",
+            "mov [rsp + 512 + 0*8], rcx",
+            "mov [rsp + 512 + 1*8], rcx",
+            "mov [rsp + 512 + 2*8], rcx",
+            "mov [rsp + 512 + 3*8], rcx",
+            "mov [rsp + 512 + 4*8], rcx",
+            "mov [rsp + 512 + 5*8], rcx",
+            "mov [rsp + 512 + 6*8], rcx",
+            "mov [rsp + 512 + 7*8], rcx",
+            "xgetbv", // EDX:EAX = XCR0, i.e. save all enabled state components
+            "xsave64 [rsp]",
+            "mov rax, [rbx]", // restore the syscall number
+            "mov rdx, [rbx + 5*8]", // restore the third argument
 
             // Shuffle around register values to match sysv calling convention, and escape into
             // proper Rust code from the assembly.
@@ -229,30 +466,21 @@ extern "C" fn syscall_entrypoint() {
             "mov rdx, rsi",
             "mov rsi, rdi",
             "mov rdi, rax",
+            "mov gs:[0x20], rsp", // save kernel RSP for the alignment check in the handler
             "call {HANDLER}",
             "pop r9",
             "add rsp, 8",
 
-            // Restore AVX registers.
-            "vmovups YMM0, [rsp + 0*32]",
-            "vmovups YMM1, [rsp + 1*32]",
-            "vmovups YMM2, [rsp + 2*32]",
-            "vmovups YMM3, [rsp + 3*32]",
-            "vmovups YMM4, [rsp + 4*32]",
-            "vmovups YMM5, [rsp + 5*32]",
-            "vmovups YMM6, [rsp + 6*32]",
-            "vmovups YMM7, [rsp + 7*32]",
-            "vmovups YMM8, [rsp + 8*32]",
-            "vmovups YMM9, [rsp + 9*32]",
-            "vmovups YMM10, [rsp + 10*32]",
-            "vmovups YMM11, [rsp + 11*32]",
-            "vmovups YMM12, [rsp + 12*32]",
-            "vmovups YMM13, [rsp + 13*32]",
-            "vmovups YMM14, [rsp + 14*32]",
-            "vmovups YMM15, [rsp + 15*32]",
-            "add rsp, 16*32",
-
-            // Undo stack alignment.
+            // Restore the extended state. RAX holds the return value, so stash it in RDI (which
+            // is restored below) while XRSTOR needs RAX and RDX.
+            "mov rdi, rax",
+            "xor ecx, ecx",
+            "xgetbv",
+            "xrstor64 [rsp]",
+            "mov rax, rdi",
+            "mov rsp, rbx",
+
+            // Drop the saved syscall number.
             "add rsp, 8",
             // Restore scratch registers.
             "pop r10",
@@ -0,0 +1,66 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use alloc::string::String;
+use core::{
+    cmp::min,
+    ffi::{c_size_t, c_ssize_t, c_void},
+};
+
+use oak_restricted_kernel_interface::{
+    syscalls::{LogLevel, MAX_LOG_MESSAGE_SIZE},
+    Errno,
+};
+
+use super::{copy_from_user, USER_SPACE_LIMIT};
+
+/// Log target used for messages logged by the payload.
+pub const PAYLOAD_LOG_TARGET: &str = "payload";
+
+fn to_log_level(level: LogLevel) -> log::Level {
+    match level {
+        LogLevel::Error => log::Level::Error,
+        LogLevel::Warn => log::Level::Warn,
+        LogLevel::Info => log::Level::Info,
+        LogLevel::Debug => log::Level::Debug,
+        LogLevel::Trace => log::Level::Trace,
+    }
+}
+
+pub fn syscall_unstable_log(level: usize, buf: *const c_void, count: c_size_t) -> c_ssize_t {
+    let Some(level) = LogLevel::from_repr(level) else {
+        return Errno::EINVAL as isize;
+    };
+    let count = min(count, MAX_LOG_MESSAGE_SIZE);
+    if count == 0 {
+        return 0;
+    }
+    // The message must be entirely within user space.
+    match (buf as u64).checked_add(count as u64) {
+        Some(end) if !buf.is_null() && end <= USER_SPACE_LIMIT => {}
+        _ => return Errno::EFAULT as isize,
+    }
+
+    // Copy the message before looking at it, so that the payload can't change it
+    // while we're logging it.
+    let mut message = [0u8; MAX_LOG_MESSAGE_SIZE];
+    // Safety: we've checked that the buffer is in user space.
+    unsafe { copy_from_user(buf, &mut message[..count]) };
+    let message = String::from_utf8_lossy(&message[..count]);
+
+    log::log!(target: PAYLOAD_LOG_TARGET, to_log_level(level), "{}", message);
+    count as isize
+}
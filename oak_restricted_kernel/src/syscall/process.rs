@@ -14,6 +14,9 @@
 // limitations under the License.
 //
 
+use crate::shutdown::secure_shutdown;
+
 pub fn syscall_exit(status: i32) -> isize {
-    panic!("User code terminated with status code: {}", status);
+    log::info!("User code terminated with status code: {}", status);
+    secure_shutdown();
 }
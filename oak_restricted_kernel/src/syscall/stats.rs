@@ -0,0 +1,143 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use core::{
+    ffi::{c_size_t, c_ssize_t, c_void},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use oak_restricted_kernel_interface::{syscalls::SyscallStats, Errno, Syscall};
+
+use super::{check_user_buffer, copy_to_user};
+
+/// Number of system calls we keep statistics for.
+pub const NUM_SYSCALLS: usize = 19;
+
+/// System call numbers, in the order they are stored in the counter tables.
+///
+/// See `slot()` for the reverse mapping.
+pub const SYSCALL_NUMBERS: [usize; NUM_SYSCALLS] = [
+    Syscall::Read as usize,
+    Syscall::Write as usize,
+    Syscall::Mmap as usize,
+    Syscall::Exit as usize,
+    Syscall::Fsync as usize,
+    Syscall::UnstableSwitchProcess as usize,
+    Syscall::UnstableGetSyscallStats as usize,
+    Syscall::UnstableLog as usize,
+    Syscall::Mlock as usize,
+    Syscall::Munlock as usize,
+    Syscall::UnstableGetMemoryStats as usize,
+    Syscall::Brk as usize,
+    Syscall::Ioctl as usize,
+    Syscall::UnstableGetEvidenceBundle as usize,
+    Syscall::UnstableGetAcpiDevices as usize,
+    Syscall::UnstableGetVsockGuestCid as usize,
+    Syscall::UnstableGetInterruptCounts as usize,
+    Syscall::UnstableGetAttestationReports as usize,
+    Syscall::UnstableExtendMeasurementRegister as usize,
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times each system call has been invoked.
+static COUNTS: [AtomicU64; NUM_SYSCALLS] = [ZERO; NUM_SYSCALLS];
+
+/// Cumulative number of TSC ticks spent handling each system call.
+static TICKS: [AtomicU64; NUM_SYSCALLS] = [ZERO; NUM_SYSCALLS];
+
+/// Whether the payload is allowed to retrieve the statistics.
+static STATS_SYSCALL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the index of the system call in the counter tables.
+///
+/// System call numbers are sparse (the unstable ones start at
+/// `UNSTABLE_SYSCALL_SPACE`), so we can't use them as indices directly.
+pub fn slot(syscall: &Syscall) -> usize {
+    match syscall {
+        Syscall::Read => 0,
+        Syscall::Write => 1,
+        Syscall::Mmap => 2,
+        Syscall::Exit => 3,
+        Syscall::Fsync => 4,
+        Syscall::UnstableSwitchProcess => 5,
+        Syscall::UnstableGetSyscallStats => 6,
+        Syscall::UnstableLog => 7,
+        Syscall::Mlock => 8,
+        Syscall::Munlock => 9,
+        Syscall::UnstableGetMemoryStats => 10,
+        Syscall::Brk => 11,
+        Syscall::Ioctl => 12,
+        Syscall::UnstableGetEvidenceBundle => 13,
+        Syscall::UnstableGetAcpiDevices => 14,
+        Syscall::UnstableGetVsockGuestCid => 15,
+        Syscall::UnstableGetInterruptCounts => 16,
+        Syscall::UnstableGetAttestationReports => 17,
+        Syscall::UnstableExtendMeasurementRegister => 18,
+    }
+}
+
+/// Records that a system call was dispatched.
+///
+/// This needs to be done before the system call is handled, as some system
+/// calls (eg `exit`) will never return.
+#[inline]
+pub fn record_dispatch(slot: usize) {
+    COUNTS[slot].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the time it took to handle a system call.
+#[inline]
+pub fn record_ticks(slot: usize, ticks: u64) {
+    TICKS[slot].fetch_add(ticks, Ordering::Relaxed);
+}
+
+/// Allows the payload to retrieve the statistics via
+/// `Syscall::UnstableGetSyscallStats`.
+pub fn enable_stats_syscall() {
+    STATS_SYSCALL_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Copies the current statistics into `dst`, returning the number of entries
+/// written.
+pub fn copy_stats(dst: &mut [SyscallStats]) -> usize {
+    dst.iter_mut()
+        .zip(SYSCALL_NUMBERS.iter().zip(COUNTS.iter().zip(TICKS.iter())))
+        .map(|(entry, (syscall, (count, ticks)))| {
+            *entry = SyscallStats {
+                syscall: *syscall,
+                count: count.load(Ordering::Relaxed),
+                ticks: ticks.load(Ordering::Relaxed),
+            }
+        })
+        .count()
+}
+
+pub fn syscall_unstable_get_syscall_stats(buf: *mut c_void, count: c_size_t) -> c_ssize_t {
+    if !STATS_SYSCALL_ENABLED.load(Ordering::Relaxed) {
+        return Errno::ENOSYS as isize;
+    }
+    if let Err(err) = check_user_buffer::<SyscallStats>(buf, count) {
+        return err as isize;
+    }
+
+    let mut entries = [SyscallStats::default(); NUM_SYSCALLS];
+    let written = copy_stats(&mut entries[..count.min(NUM_SYSCALLS)]);
+    // Safety: we've checked that the buffer is aligned and in user space.
+    unsafe { copy_to_user(buf, &entries[..written]) };
+    written as isize
+}
@@ -14,22 +14,21 @@
 // limitations under the License.
 //
 
-use alloc::boxed::Box;
-use core::{
-    ffi::{c_size_t, c_void},
-    slice,
-};
+use alloc::{boxed::Box, vec};
+use core::ffi::{c_size_t, c_void};
 
+use super::{check_user_buffer, copy_from_user};
 use crate::payload::Process;
 
 pub fn syscall_unstable_switch_proccess(buf: *mut c_void, count: c_size_t) -> ! {
-    // We should validate that the pointer and count are valid, as these come from
-    // userspace and therefore are not to be trusted, but right now everything
-    // is in kernel space so there is nothing to check.
-    let elf_binary_buffer = unsafe { slice::from_raw_parts(buf as *mut u8, count) };
+    if let Err(err) = check_user_buffer::<u8>(buf, count) {
+        panic!("invalid ELF binary buffer: {}", err);
+    }
 
     // Copy the ELF file into kernel space.
-    let copied_elf_binary = elf_binary_buffer.to_vec();
+    let mut copied_elf_binary = vec![0u8; count];
+    // Safety: we've checked that the buffer is in user space.
+    unsafe { copy_from_user(buf, &mut copied_elf_binary) };
 
     let application = crate::payload::Application::new(copied_elf_binary.into_boxed_slice())
         .expect("failed to parse application");
@@ -37,7 +36,7 @@ pub fn syscall_unstable_switch_proccess(buf: *mut c_void, count: c_size_t) -> !
     // Ensure the new process is not dropped.
     let process = Box::leak(Box::new(
         // Safety: application is assumed to be a valid ELF file.
-        unsafe { Process::from_application(&application).expect("failed to create process") },
+        unsafe { Process::from_application(&application, None).expect("failed to create process") },
     ));
 
     process.execute()
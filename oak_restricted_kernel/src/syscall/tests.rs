@@ -14,7 +14,39 @@
 // limitations under the License.
 //
 
-use super::fd::copy_max_slice;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use oak_channel::{ChannelError, PeerClosed, Read, Write};
+use oak_restricted_kernel_interface::{
+    syscalls::{
+        LogLevel, SyscallStats, ATTESTATION_REPORT_SIZE, EVIDENCE_REPORT_DATA_SIZE, IOCTL_FLUSH,
+        IOCTL_MAX_MESSAGE_SIZE, IOCTL_SET_LOG_LEVEL, MAX_ATTESTATION_REPORTS, MAX_LOG_MESSAGE_SIZE,
+        MEASUREMENT_REGISTER_SIZE, WORKLOAD_MEASUREMENT_REGISTER,
+    },
+    Errno, Syscall,
+};
+use spinning_top::Spinlock;
+use x86_64::VirtAddr;
+
+use super::{
+    channel::{enable_log_level_ioctl, ChannelDescriptor},
+    check_user_buffer, check_user_context, dispatch,
+    fd::{self, copy_max_slice, FileDescriptor},
+    is_stack_aligned,
+    payload_log::PAYLOAD_LOG_TARGET,
+    stats, INTERRUPTED_STACK_ALIGNMENT, KERNEL_STACK_ALIGNMENT,
+};
+use crate::attestation::measurement_registers;
 
 #[test]
 fn shorter_dst_copy() {
@@ -42,3 +74,413 @@ fn empty_copy() {
     assert_eq!(length, 0);
     assert_eq!(dst, &[1; 5])
 }
+
+fn syscall_counts() -> [u64; stats::NUM_SYSCALLS] {
+    let mut buf = [SyscallStats::default(); stats::NUM_SYSCALLS];
+    assert_eq!(stats::copy_stats(&mut buf), stats::NUM_SYSCALLS);
+    buf.map(|entry| entry.count)
+}
+
+#[test]
+fn syscall_stats_slots() {
+    for (slot, syscall) in stats::SYSCALL_NUMBERS.iter().enumerate() {
+        assert_eq!(stats::slot(&Syscall::from_repr(*syscall).unwrap()), slot);
+    }
+}
+
+#[test]
+fn syscall_stats_counted_on_dispatch() {
+    let fsync = stats::slot(&Syscall::Fsync);
+    let write = stats::slot(&Syscall::Write);
+    let get_stats = stats::slot(&Syscall::UnstableGetSyscallStats);
+    let before = syscall_counts();
+
+    // None of these file descriptors are registered, so the calls fail, but they
+    // still count as dispatched.
+    let buf = [0u8; 4];
+    assert_eq!(dispatch(Syscall::Fsync as usize, 0xdead, 0, 0, 0, 0, 0), Errno::EBADF as isize);
+    assert_eq!(dispatch(Syscall::Fsync as usize, 0xbeef, 0, 0, 0, 0, 0), Errno::EBADF as isize);
+    assert_eq!(
+        dispatch(Syscall::Write as usize, 0xdead, buf.as_ptr() as usize, buf.len(), 0, 0, 0),
+        Errno::EBADF as isize
+    );
+    // The stats syscall has not been enabled.
+    assert_eq!(
+        dispatch(Syscall::UnstableGetSyscallStats as usize, 0, 0, 0, 0, 0, 0),
+        Errno::ENOSYS as isize
+    );
+    // Unknown syscalls are not counted.
+    assert_eq!(dispatch(0xffff, 0, 0, 0, 0, 0, 0), Errno::ENOSYS as isize);
+
+    let after = syscall_counts();
+    assert_eq!(after[fsync] - before[fsync], 2);
+    assert_eq!(after[write] - before[write], 1);
+    assert_eq!(after[get_stats] - before[get_stats], 1);
+}
+
+#[test]
+fn user_context_accepted() {
+    assert!(check_user_context(VirtAddr::new(0x20_1000), VirtAddr::new(0x7FFF_FFDF_FFF8), 0x202)
+        .is_ok());
+}
+
+#[test]
+fn kernel_context_rejected() {
+    // Kernel mode instruction pointer.
+    assert!(check_user_context(
+        VirtAddr::new(0xFFFF_FFFF_8020_1000),
+        VirtAddr::new(0x7FFF_FFDF_FFF8),
+        0x202
+    )
+    .is_err());
+    // Kernel mode stack pointer.
+    assert!(check_user_context(
+        VirtAddr::new(0x20_1000),
+        VirtAddr::new(0xFFFF_C900_0020_0000),
+        0x202
+    )
+    .is_err());
+    // IOPL 3.
+    assert!(check_user_context(VirtAddr::new(0x20_1000), VirtAddr::new(0x7FFF_FFDF_FFF8), 0x3202)
+        .is_err());
+}
+
+#[test]
+fn user_buffers_checked() {
+    let buf = 0x20_1000 as *const c_void;
+    assert!(check_user_buffer::<u64>(buf, 512).is_ok());
+    // Null, misaligned, in kernel space, or overflowing the address space.
+    assert_eq!(check_user_buffer::<u64>(core::ptr::null(), 1), Err(Errno::EFAULT));
+    assert_eq!(check_user_buffer::<u64>(0x20_1004 as *const c_void, 1), Err(Errno::EFAULT));
+    assert_eq!(
+        check_user_buffer::<u8>(0xFFFF_FFFF_8020_1000 as *const c_void, 1),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(
+        check_user_buffer::<u8>(0x7FFF_FFFF_F000 as *const c_void, 0x2000),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(check_user_buffer::<u64>(buf, usize::MAX / 4), Err(Errno::EFAULT));
+    // The buffer may end right at the end of user space.
+    assert!(check_user_buffer::<u8>(0x7FFF_FFFF_F000 as *const c_void, 0x1000).is_ok());
+}
+
+#[test]
+fn fd_buffers_outside_user_space_rejected() {
+    let kernel_buf = 0xFFFF_FFFF_8020_1000 as *mut c_void;
+    assert_eq!(fd::syscall_read(0xdead, kernel_buf, 4), Errno::EFAULT as isize);
+    assert_eq!(fd::syscall_write(0xdead, kernel_buf, 4), Errno::EFAULT as isize);
+    // A valid buffer gets as far as looking up the file descriptor.
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        fd::syscall_read(0xdead, buf.as_mut_ptr() as *mut c_void, buf.len()),
+        Errno::EBADF as isize
+    );
+}
+
+#[test]
+fn stack_alignment() {
+    assert!(is_stack_aligned(VirtAddr::new(0x7FFF_FFDF_FFF0), KERNEL_STACK_ALIGNMENT));
+    assert!(!is_stack_aligned(VirtAddr::new(0x7FFF_FFDF_FFF8), KERNEL_STACK_ALIGNMENT));
+    assert!(is_stack_aligned(VirtAddr::new(0x7FFF_FFDF_FFF8), INTERRUPTED_STACK_ALIGNMENT));
+    assert!(!is_stack_aligned(VirtAddr::new(0x7FFF_FFDF_FFFC), INTERRUPTED_STACK_ALIGNMENT));
+    assert!(is_stack_aligned(VirtAddr::zero(), KERNEL_STACK_ALIGNMENT));
+}
+
+/// Logger that keeps the records it receives, as (target, level, message).
+struct CapturingLogger {
+    records: Spinlock<Vec<(String, log::Level, String)>>,
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.records.lock().push((
+            record.target().to_string(),
+            record.level(),
+            record.args().to_string(),
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger { records: Spinlock::new(Vec::new()) };
+
+/// Returns the messages logged by the payload so far.
+fn payload_records() -> Vec<(log::Level, String)> {
+    LOGGER
+        .records
+        .lock()
+        .iter()
+        .filter(|(target, _, _)| target == PAYLOAD_LOG_TARGET)
+        .map(|(_, level, message)| (*level, message.clone()))
+        .collect()
+}
+
+#[test]
+fn payload_log_syscall() {
+    // This is the only test that installs a logger.
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let message = "hello from the payload";
+    assert_eq!(
+        dispatch(
+            Syscall::UnstableLog as usize,
+            LogLevel::Warn as usize,
+            message.as_ptr() as usize,
+            message.len(),
+            0,
+            0,
+            0
+        ),
+        message.len() as isize
+    );
+    assert!(payload_records().contains(&(log::Level::Warn, message.to_string())));
+
+    // Overly long messages are truncated.
+    let long_message = "x".repeat(MAX_LOG_MESSAGE_SIZE + 10);
+    assert_eq!(
+        dispatch(
+            Syscall::UnstableLog as usize,
+            LogLevel::Info as usize,
+            long_message.as_ptr() as usize,
+            long_message.len(),
+            0,
+            0,
+            0
+        ),
+        MAX_LOG_MESSAGE_SIZE as isize
+    );
+    assert!(payload_records()
+        .contains(&(log::Level::Info, long_message[..MAX_LOG_MESSAGE_SIZE].to_string())));
+
+    // Invalid levels and buffers are rejected without logging anything.
+    let count = payload_records().len();
+    assert_eq!(
+        dispatch(
+            Syscall::UnstableLog as usize,
+            0,
+            message.as_ptr() as usize,
+            message.len(),
+            0,
+            0,
+            0
+        ),
+        Errno::EINVAL as isize
+    );
+    assert_eq!(
+        dispatch(Syscall::UnstableLog as usize, LogLevel::Info as usize, 0, 4, 0, 0, 0),
+        Errno::EFAULT as isize
+    );
+    assert_eq!(
+        dispatch(
+            Syscall::UnstableLog as usize,
+            LogLevel::Info as usize,
+            0xFFFF_FFFF_8000_0000,
+            4,
+            0,
+            0,
+            0
+        ),
+        Errno::EFAULT as isize
+    );
+    assert_eq!(payload_records().len(), count);
+}
+
+/// Device-specific request understood by `MockChannel` that doubles its
+/// argument.
+const MOCK_DOUBLE: u64 = 0x1000;
+
+/// Device-specific request understood by `MockChannel` that requires a
+/// non-zero argument.
+const MOCK_NONZERO: u64 = 0x1001;
+
+struct MockChannel {
+    flushes: Arc<AtomicUsize>,
+}
+
+impl Read for MockChannel {
+    fn read_exact(&mut self, _data: &mut [u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for MockChannel {
+    fn write_all(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn max_write_size(&self) -> usize {
+        4096
+    }
+
+    fn control(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError> {
+        match request {
+            MOCK_DOUBLE => Ok(arg * 2),
+            MOCK_NONZERO if arg == 0 => Err(ChannelError::InvalidArgument),
+            MOCK_NONZERO => Ok(0),
+            _ => Err(ChannelError::Unsupported),
+        }
+    }
+}
+
+#[test]
+fn channel_ioctl_dispatch() {
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let mut descriptor = ChannelDescriptor::new(Box::new(MockChannel { flushes: flushes.clone() }));
+
+    // Requests every channel supports.
+    assert_eq!(descriptor.ioctl(IOCTL_FLUSH, 0), Ok(0));
+    assert_eq!(flushes.load(Ordering::Relaxed), 1);
+    assert_eq!(descriptor.ioctl(IOCTL_MAX_MESSAGE_SIZE, 0), Ok(4096));
+
+    // Device-specific requests are passed on to the channel.
+    assert_eq!(descriptor.ioctl(MOCK_DOUBLE, 21), Ok(42));
+    assert_eq!(descriptor.ioctl(MOCK_NONZERO, 1), Ok(0));
+    assert_eq!(descriptor.ioctl(MOCK_NONZERO, 0), Err(Errno::EINVAL));
+    assert_eq!(descriptor.ioctl(0x9999, 0), Err(Errno::ENOTTY));
+}
+
+#[test]
+fn set_log_level_ioctl() {
+    assert_eq!(crate::logging::level_filter_from_repr(0), Some(log::LevelFilter::Off));
+    assert_eq!(
+        crate::logging::level_filter_from_repr(LogLevel::Trace as usize),
+        Some(log::LevelFilter::Trace)
+    );
+    assert_eq!(crate::logging::level_filter_from_repr(6), None);
+
+    let mut descriptor =
+        ChannelDescriptor::new(Box::new(MockChannel { flushes: Arc::new(AtomicUsize::new(0)) }));
+    // The request is refused unless the kernel arg allowed it.
+    assert_eq!(descriptor.ioctl(IOCTL_SET_LOG_LEVEL, LogLevel::Trace as usize), Err(Errno::EPERM));
+    enable_log_level_ioctl();
+    assert_eq!(descriptor.ioctl(IOCTL_SET_LOG_LEVEL, 6), Err(Errno::EINVAL));
+    // Other tests rely on the most verbose level, so that's the one we switch to.
+    assert!(descriptor.ioctl(IOCTL_SET_LOG_LEVEL, LogLevel::Trace as usize).is_ok());
+    assert_eq!(log::max_level(), log::LevelFilter::Trace);
+    assert_eq!(
+        descriptor.ioctl(IOCTL_SET_LOG_LEVEL, LogLevel::Trace as usize),
+        Ok(LogLevel::Trace as isize)
+    );
+}
+
+#[test]
+fn ioctl_on_unknown_fd() {
+    assert_eq!(
+        dispatch(Syscall::Ioctl as usize, 0xdead, IOCTL_FLUSH as usize, 0, 0, 0, 0),
+        Errno::EBADF as isize
+    );
+}
+
+#[test]
+fn vsock_guest_cid_without_vsock() {
+    // The vsock device is never configured in tests.
+    assert_eq!(
+        dispatch(Syscall::UnstableGetVsockGuestCid as usize, 0, 0, 0, 0, 0, 0),
+        Errno::ENODEV as isize
+    );
+}
+
+#[test]
+fn attestation_reports_syscall_checks_arguments() {
+    let get_reports = |report_datas: *const u8, buf: *mut u8, count: usize| {
+        dispatch(
+            Syscall::UnstableGetAttestationReports as usize,
+            report_datas as usize,
+            buf as usize,
+            count,
+            0,
+            0,
+            0,
+        )
+    };
+    let report_datas = [[0u8; EVIDENCE_REPORT_DATA_SIZE]; MAX_ATTESTATION_REPORTS + 1];
+    let mut buf = vec![[0u8; ATTESTATION_REPORT_SIZE]; MAX_ATTESTATION_REPORTS + 1];
+    let report_datas_ptr = report_datas.as_ptr() as *const u8;
+    let buf_ptr = buf.as_mut_ptr() as *mut u8;
+
+    assert_eq!(
+        get_reports(report_datas_ptr, buf_ptr, MAX_ATTESTATION_REPORTS + 1),
+        Errno::EINVAL as isize
+    );
+    assert_eq!(
+        get_reports(report_datas_ptr, 0xFFFF_FFFF_8020_1000 as *mut u8, 2),
+        Errno::EFAULT as isize
+    );
+    assert_eq!(get_reports(core::ptr::null(), buf_ptr, 2), Errno::EFAULT as isize);
+    // Valid arguments get as far as the guest request, but there is no Secure
+    // Processor to talk to in tests.
+    assert_eq!(get_reports(report_datas_ptr, buf_ptr, 2), Errno::EIO as isize);
+}
+
+#[test]
+fn extend_measurement_register_syscall() {
+    let extend = |index: usize, digest: *const u8| {
+        dispatch(
+            Syscall::UnstableExtendMeasurementRegister as usize,
+            index,
+            digest as usize,
+            0,
+            0,
+            0,
+            0,
+        )
+    };
+    let digest = [0xA5u8; MEASUREMENT_REGISTER_SIZE];
+
+    assert_eq!(
+        extend(measurement_registers::APPLICATION_REGISTER, digest.as_ptr()),
+        Errno::EPERM as isize
+    );
+    assert_eq!(
+        extend(measurement_registers::NUM_MEASUREMENT_REGISTERS, digest.as_ptr()),
+        Errno::EINVAL as isize
+    );
+    assert_eq!(extend(WORKLOAD_MEASUREMENT_REGISTER, core::ptr::null()), Errno::EFAULT as isize);
+
+    let mut expected = measurement_registers::snapshot();
+    assert_eq!(extend(WORKLOAD_MEASUREMENT_REGISTER, digest.as_ptr()), 0);
+    expected.extend(WORKLOAD_MEASUREMENT_REGISTER, &digest).unwrap();
+    assert_eq!(measurement_registers::snapshot(), expected);
+}
+
+/// A channel whose peer has closed the connection.
+struct ClosedChannel;
+
+impl Read for ClosedChannel {
+    fn read_exact(&mut self, _data: &mut [u8]) -> anyhow::Result<()> {
+        Err(anyhow::Error::msg(PeerClosed))
+    }
+
+    fn peer_closed(&self) -> bool {
+        true
+    }
+}
+
+impl Write for ClosedChannel {
+    fn write_all(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn channel_read_after_peer_closed_is_eof() {
+    let mut descriptor = ChannelDescriptor::new(Box::new(ClosedChannel));
+    let mut buf = [0u8; 8];
+    assert_eq!(descriptor.read(&mut buf), Ok(0));
+}
@@ -0,0 +1,29 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Information about the vsock connection to the host.
+
+use core::ffi::c_ssize_t;
+
+use oak_restricted_kernel_interface::Errno;
+
+pub fn syscall_unstable_get_vsock_guest_cid() -> c_ssize_t {
+    match crate::virtio::vsock_guest_cid() {
+        // CIDs are 32 bits wide, so this never turns into a negative value.
+        Some(cid) => cid as isize,
+        None => Errno::ENODEV as isize,
+    }
+}
@@ -0,0 +1,154 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Mirroring of the data sent over the host channel to a second channel.
+//!
+//! This is meant for bring-up: with `tee_channel=serial`, for example,
+//! everything the kernel sends over the real channel also shows up on the
+//! serial port. The secondary channel never affects the primary one: errors
+//! writing to it are ignored, and it is never read from.
+
+use alloc::boxed::Box;
+
+use oak_channel::{Channel, ChannelError, Read, Write};
+
+/// Kernel argument that names the type of the channel that sent data is
+/// mirrored to.
+pub const TEE_CHANNEL_ARG: &str = "tee_channel";
+
+/// A channel that mirrors writes to a secondary channel.
+pub struct TeeChannel<'a> {
+    primary: Box<dyn Channel + 'a>,
+    secondary: Box<dyn Channel + 'a>,
+}
+
+impl<'a> TeeChannel<'a> {
+    pub fn new(primary: Box<dyn Channel + 'a>, secondary: Box<dyn Channel + 'a>) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl Read for TeeChannel<'_> {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        self.primary.read_exact(data)
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.primary.peer_closed()
+    }
+}
+
+impl Write for TeeChannel<'_> {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.primary.write_all(data)?;
+        // The mirror is best-effort only.
+        let _ = self.secondary.write_all(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.primary.flush()?;
+        let _ = self.secondary.flush();
+        Ok(())
+    }
+
+    fn max_write_size(&self) -> usize {
+        self.primary.max_message_size()
+    }
+
+    fn control(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError> {
+        self.primary.ioctl(request, arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use spinning_top::Spinlock;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(u8),
+        Write(u8, Vec<u8>),
+    }
+
+    /// Channel that records reads and writes, tagged with its id.
+    struct MockChannel {
+        id: u8,
+        fail_writes: bool,
+        events: Arc<Spinlock<Vec<Event>>>,
+    }
+
+    impl Read for MockChannel {
+        fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+            self.events.lock().push(Event::Read(self.id));
+            data.fill(self.id);
+            Ok(())
+        }
+    }
+
+    impl Write for MockChannel {
+        fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            if self.fail_writes {
+                anyhow::bail!("write failed");
+            }
+            self.events.lock().push(Event::Write(self.id, data.to_vec()));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn channels(fail_secondary: bool) -> (TeeChannel<'static>, Arc<Spinlock<Vec<Event>>>) {
+        let events = Arc::new(Spinlock::new(Vec::new()));
+        let primary = Box::new(MockChannel { id: 1, fail_writes: false, events: events.clone() });
+        let secondary =
+            Box::new(MockChannel { id: 2, fail_writes: fail_secondary, events: events.clone() });
+        (TeeChannel::new(primary, secondary), events)
+    }
+
+    #[test]
+    fn send_hits_both_channels() {
+        let (mut channel, events) = channels(false);
+        channel.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(
+            *events.lock(),
+            [Event::Write(1, vec![1, 2, 3]), Event::Write(2, vec![1, 2, 3])]
+        );
+    }
+
+    #[test]
+    fn recv_hits_only_primary() {
+        let (mut channel, events) = channels(false);
+        let mut data = [0; 4];
+        channel.read_exact(&mut data).unwrap();
+        assert_eq!(data, [1; 4]);
+        assert_eq!(*events.lock(), [Event::Read(1)]);
+    }
+
+    #[test]
+    fn secondary_errors_are_ignored() {
+        let (mut channel, events) = channels(true);
+        channel.write_all(&[1, 2, 3]).unwrap();
+        channel.flush().unwrap();
+        assert_eq!(*events.lock(), [Event::Write(1, vec![1, 2, 3])]);
+    }
+}
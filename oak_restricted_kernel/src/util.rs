@@ -0,0 +1,149 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Small helpers shared by diagnostic code paths.
+
+use core::fmt::{self, Write};
+
+/// Number of bytes shown on every line of a hexdump.
+const BYTES_PER_LINE: usize = 16;
+
+/// Writes `bytes` to `writer` in the canonical `offset  hex  |ascii|` format,
+/// as produced by `hexdump -C`.
+///
+/// Every line is terminated with a newline. Nothing is allocated, so this can
+/// be used before the kernel heap has been set up.
+pub fn hexdump<W: Write>(writer: &mut W, bytes: &[u8]) -> fmt::Result {
+    for (line, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        write!(writer, "{:08x} ", line * BYTES_PER_LINE)?;
+        for i in 0..BYTES_PER_LINE {
+            if i % 8 == 0 {
+                writer.write_char(' ')?;
+            }
+            match chunk.get(i) {
+                Some(byte) => write!(writer, "{:02x} ", byte)?,
+                None => writer.write_str("   ")?,
+            }
+        }
+        writer.write_str(" |")?;
+        for &byte in chunk {
+            let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            writer.write_char(c)?;
+        }
+        writer.write_str("|\n")?;
+    }
+    Ok(())
+}
+
+/// Wrapper that formats a byte slice as a hexdump when displayed, for use in
+/// log messages.
+pub struct HexDump<'a>(pub &'a [u8]);
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hexdump(f, self.0)
+    }
+}
+
+/// Fixed-size buffer that text can be formatted into without allocating.
+///
+/// Text that doesn't fit is dropped, cutting at a character boundary, and the
+/// buffer is marked as truncated.
+pub struct FixedBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> FixedBuffer<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0, truncated: false }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // We only ever copy whole characters into the buffer.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+
+    /// Returns whether some of the text written to the buffer didn't fit.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<const N: usize> Write for FixedBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() {
+            self.truncated = true;
+            // Stop formatting, as nothing more would fit anyway.
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    #[test]
+    fn formats_known_bytes() {
+        let bytes: [u8; 32] = core::array::from_fn(|i| (i as u8) * 4 + 0x20);
+        let mut out = String::new();
+        hexdump(&mut out, &bytes).unwrap();
+        assert_eq!(
+            out,
+            "00000000  20 24 28 2c 30 34 38 3c  40 44 48 4c 50 54 58 5c  | $(,048<@DHLPTX\\|\n\
+             00000010  60 64 68 6c 70 74 78 7c  80 84 88 8c 90 94 98 9c  |`dhlptx|........|\n"
+        );
+    }
+
+    #[test]
+    fn pads_partial_line() {
+        let mut out = String::new();
+        hexdump(&mut out, b"Oak\x00").unwrap();
+        assert_eq!(out, "00000000  4f 61 6b 00                                       |Oak.|\n");
+    }
+
+    #[test]
+    fn fixed_buffer_fits() {
+        let mut buf = FixedBuffer::<32>::new();
+        write!(buf, "PANIC: {} at {}", "oops", 42).unwrap();
+        assert_eq!(buf.as_str(), "PANIC: oops at 42");
+        assert!(!buf.truncated());
+    }
+
+    #[test]
+    fn fixed_buffer_truncates() {
+        let mut buf = FixedBuffer::<16>::new();
+        assert!(write!(buf, "PANIC: {}", "message that is too long").is_err());
+        assert_eq!(buf.as_str(), "PANIC: message t");
+        assert!(buf.truncated());
+
+        // Multi-byte characters are never split.
+        let mut buf = FixedBuffer::<8>::new();
+        assert!(write!(buf, "PANIC: \u{e9}").is_err());
+        assert_eq!(buf.as_str(), "PANIC: ");
+    }
+}
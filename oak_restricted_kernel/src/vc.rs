@@ -0,0 +1,379 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Emulation of instructions that raise a VMM Communication (`#VC`) exception
+//! under SEV-ES and SEV-SNP.
+//!
+//! The hardware does not tell us anything beyond the exit reason, so we decode
+//! the faulting instruction ourselves, forward the request to the hypervisor
+//! and write the results back into the saved registers.
+//!
+//! See section 4.1 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>.
+
+use core::fmt;
+
+use oak_sev_guest::{
+    cpuid::{CpuidInput, CpuidOutput},
+    ghcb::{Ghcb, GhcbProtocol},
+    interrupts::MutableInterruptStackFrame,
+};
+
+/// Exit code for the CPUID instruction.
+pub const SVM_EXIT_CPUID: u64 = 0x72;
+/// Exit code for the IN and OUT instructions.
+pub const SVM_EXIT_IOIO: u64 = 0x7B;
+/// Exit code for the RDMSR and WRMSR instructions.
+pub const SVM_EXIT_MSR: u64 = 0x7C;
+
+const CPUID_INSTRUCTION: [u8; 2] = [0x0f, 0xa2];
+const RDMSR_INSTRUCTION: [u8; 2] = [0x0f, 0x32];
+const WRMSR_INSTRUCTION: [u8; 2] = [0x0f, 0x30];
+const OPERAND_SIZE_OVERRIDE_PREFIX: u8 = 0x66;
+const LOWER_32_BITS: u64 = 0xFFFF_FFFF;
+
+/// The width of a port I/O access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoSize {
+    Byte,
+    Word,
+    DoubleWord,
+}
+
+impl IoSize {
+    fn mask(self) -> u64 {
+        match self {
+            IoSize::Byte => 0xFF,
+            IoSize::Word => 0xFFFF,
+            IoSize::DoubleWord => LOWER_32_BITS,
+        }
+    }
+}
+
+/// The ways in which the hypervisor can emulate an instruction on our behalf.
+pub trait VmmCommunication {
+    fn cpuid(&mut self, input: CpuidInput) -> Result<CpuidOutput, &'static str>;
+    fn msr_read(&mut self, msr: u32) -> Result<u64, &'static str>;
+    fn msr_write(&mut self, msr: u32, value: u64) -> Result<(), &'static str>;
+    fn io_read(&mut self, port: u16, size: IoSize) -> Result<u32, &'static str>;
+    fn io_write(&mut self, port: u16, size: IoSize, value: u32) -> Result<(), &'static str>;
+}
+
+impl<G> VmmCommunication for GhcbProtocol<'_, G>
+where
+    G: AsMut<Ghcb> + AsRef<Ghcb> + ?Sized,
+{
+    fn cpuid(&mut self, input: CpuidInput) -> Result<CpuidOutput, &'static str> {
+        self.get_cpuid(input)
+    }
+
+    fn msr_read(&mut self, msr: u32) -> Result<u64, &'static str> {
+        GhcbProtocol::msr_read(self, msr)
+    }
+
+    fn msr_write(&mut self, msr: u32, value: u64) -> Result<(), &'static str> {
+        GhcbProtocol::msr_write(self, msr, value)
+    }
+
+    fn io_read(&mut self, port: u16, size: IoSize) -> Result<u32, &'static str> {
+        match size {
+            IoSize::Byte => self.io_read_u8(port).map(u32::from),
+            IoSize::Word => self.io_read_u16(port).map(u32::from),
+            IoSize::DoubleWord => self.io_read_u32(port),
+        }
+    }
+
+    fn io_write(&mut self, port: u16, size: IoSize, value: u32) -> Result<(), &'static str> {
+        match size {
+            IoSize::Byte => self.io_write_u8(port, value as u8),
+            IoSize::Word => self.io_write_u16(port, value as u16),
+            IoSize::DoubleWord => self.io_write_u32(port, value),
+        }
+    }
+}
+
+/// Reasons why a `#VC` exception could not be emulated.
+#[derive(Debug, Eq, PartialEq)]
+pub enum VcError {
+    /// We don't know how to emulate this exit reason.
+    UnsupportedExitCode(u64),
+    /// The faulting instruction does not match the exit reason, or is a form of
+    /// the instruction we don't emulate (e.g. string I/O).
+    UnexpectedInstruction(u64),
+    /// The hypervisor failed to emulate the instruction.
+    Hypervisor(&'static str),
+}
+
+impl fmt::Display for VcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VcError::UnsupportedExitCode(exit_code) => {
+                write!(f, "unsupported exit code {:#x}", exit_code)
+            }
+            VcError::UnexpectedInstruction(exit_code) => {
+                write!(f, "unexpected instruction for exit code {:#x}", exit_code)
+            }
+            VcError::Hypervisor(message) => write!(f, "hypervisor failed to emulate: {}", message),
+        }
+    }
+}
+
+/// A decoded port I/O instruction.
+#[derive(Debug, Eq, PartialEq)]
+struct IoInstruction {
+    port: u16,
+    size: IoSize,
+    is_write: bool,
+    length: u64,
+}
+
+/// Decodes the IN or OUT instruction whose bytes are returned by `fetch`.
+///
+/// Only the non-string forms are supported, with an optional operand size
+/// override prefix.
+fn decode_io(fetch: &impl Fn(usize) -> u8, dx: u16) -> Option<IoInstruction> {
+    let (prefix_length, wide_size) = if fetch(0) == OPERAND_SIZE_OVERRIDE_PREFIX {
+        (1, IoSize::Word)
+    } else {
+        (0, IoSize::DoubleWord)
+    };
+    let opcode = fetch(prefix_length);
+    let size = if opcode & 1 == 0 { IoSize::Byte } else { wide_size };
+    let (port, is_write, operand_length) = match opcode {
+        // IN AL/AX/EAX, imm8
+        0xE4 | 0xE5 => (fetch(prefix_length + 1) as u16, false, 1),
+        // OUT imm8, AL/AX/EAX
+        0xE6 | 0xE7 => (fetch(prefix_length + 1) as u16, true, 1),
+        // IN AL/AX/EAX, DX
+        0xEC | 0xED => (dx, false, 0),
+        // OUT DX, AL/AX/EAX
+        0xEE | 0xEF => (dx, true, 0),
+        _ => return None,
+    };
+    Some(IoInstruction {
+        port,
+        size,
+        is_write,
+        length: (prefix_length + 1 + operand_length) as u64,
+    })
+}
+
+fn fetch_two(fetch: &impl Fn(usize) -> u8) -> [u8; 2] {
+    [fetch(0), fetch(1)]
+}
+
+/// Emulates the instruction that caused a `#VC` exception with the given exit
+/// code, updating the register state and advancing the instruction pointer
+/// past the instruction on success.
+///
+/// `fetch` returns the instruction byte at the given offset from the faulting
+/// instruction pointer; it is only called for as many bytes as the decoding
+/// needs.
+pub fn emulate<V: VmmCommunication + ?Sized>(
+    exit_code: u64,
+    stack_frame: &mut MutableInterruptStackFrame,
+    fetch: impl Fn(usize) -> u8,
+    vmm: &mut V,
+) -> Result<(), VcError> {
+    let length = match exit_code {
+        SVM_EXIT_CPUID => {
+            if fetch_two(&fetch) != CPUID_INSTRUCTION {
+                return Err(VcError::UnexpectedInstruction(exit_code));
+            }
+            let output =
+                vmm.cpuid(CpuidInput::from(&mut *stack_frame)).map_err(VcError::Hypervisor)?;
+            stack_frame.rax = output.eax as u64;
+            stack_frame.rbx = output.ebx as u64;
+            stack_frame.rcx = output.ecx as u64;
+            stack_frame.rdx = output.edx as u64;
+            CPUID_INSTRUCTION.len() as u64
+        }
+        SVM_EXIT_MSR => {
+            let instruction = fetch_two(&fetch);
+            let msr = stack_frame.rcx as u32;
+            if instruction == RDMSR_INSTRUCTION {
+                let value = vmm.msr_read(msr).map_err(VcError::Hypervisor)?;
+                stack_frame.rax = value & LOWER_32_BITS;
+                stack_frame.rdx = value >> 32;
+            } else if instruction == WRMSR_INSTRUCTION {
+                let value = (stack_frame.rdx << 32) | (stack_frame.rax & LOWER_32_BITS);
+                vmm.msr_write(msr, value).map_err(VcError::Hypervisor)?;
+            } else {
+                return Err(VcError::UnexpectedInstruction(exit_code));
+            }
+            instruction.len() as u64
+        }
+        SVM_EXIT_IOIO => {
+            let io = decode_io(&fetch, stack_frame.rdx as u16)
+                .ok_or(VcError::UnexpectedInstruction(exit_code))?;
+            let mask = io.size.mask();
+            if io.is_write {
+                vmm.io_write(io.port, io.size, (stack_frame.rax & mask) as u32)
+                    .map_err(VcError::Hypervisor)?;
+            } else {
+                let value = vmm.io_read(io.port, io.size).map_err(VcError::Hypervisor)? as u64;
+                stack_frame.rax = match io.size {
+                    // Writes to 32-bit registers clear the upper half of the 64-bit register.
+                    IoSize::DoubleWord => value & mask,
+                    // Writes to 8-bit and 16-bit registers leave the rest untouched.
+                    _ => (stack_frame.rax & !mask) | (value & mask),
+                };
+            }
+            io.length
+        }
+        _ => return Err(VcError::UnsupportedExitCode(exit_code)),
+    };
+    stack_frame.rip += length;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use x86_64::VirtAddr;
+
+    use super::*;
+
+    const START_RIP: u64 = 0x1000;
+
+    #[derive(Default)]
+    struct MockGhcb {
+        cpuid_requests: Vec<(u32, u32)>,
+        msr_writes: Vec<(u32, u64)>,
+        io_writes: Vec<(u16, IoSize, u32)>,
+        io_reads: Vec<(u16, IoSize)>,
+    }
+
+    impl VmmCommunication for MockGhcb {
+        fn cpuid(&mut self, input: CpuidInput) -> Result<CpuidOutput, &'static str> {
+            self.cpuid_requests.push((input.eax, input.ecx));
+            Ok(CpuidOutput { eax: 0x11, ebx: 0x22, ecx: 0x33, edx: 0x44 })
+        }
+
+        fn msr_read(&mut self, msr: u32) -> Result<u64, &'static str> {
+            Ok(0xAAAA_BBBB_0000_0000 | msr as u64)
+        }
+
+        fn msr_write(&mut self, msr: u32, value: u64) -> Result<(), &'static str> {
+            self.msr_writes.push((msr, value));
+            Ok(())
+        }
+
+        fn io_read(&mut self, port: u16, size: IoSize) -> Result<u32, &'static str> {
+            self.io_reads.push((port, size));
+            Ok(0x1234_5678)
+        }
+
+        fn io_write(&mut self, port: u16, size: IoSize, value: u32) -> Result<(), &'static str> {
+            self.io_writes.push((port, size, value));
+            Ok(())
+        }
+    }
+
+    fn stack_frame(rax: u64, rcx: u64, rdx: u64) -> MutableInterruptStackFrame {
+        MutableInterruptStackFrame {
+            rax,
+            rbx: 0,
+            rcx,
+            rdx,
+            rdi: 0,
+            rsi: 0,
+            rip: VirtAddr::new(START_RIP),
+            cs: 0,
+            rflags: 0,
+            rsp: VirtAddr::new(0),
+            ss: 0,
+        }
+    }
+
+    fn fetcher(bytes: &[u8]) -> impl Fn(usize) -> u8 + '_ {
+        |offset| bytes[offset]
+    }
+
+    #[test]
+    fn cpuid_exit_uses_ghcb() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(0x8000_0001, 0x2, 0);
+        emulate(SVM_EXIT_CPUID, &mut frame, fetcher(&CPUID_INSTRUCTION), &mut ghcb).unwrap();
+
+        assert_eq!(ghcb.cpuid_requests, [(0x8000_0001, 0x2)]);
+        assert_eq!((frame.rax, frame.rbx, frame.rcx, frame.rdx), (0x11, 0x22, 0x33, 0x44));
+        assert_eq!(frame.rip.as_u64(), START_RIP + 2);
+    }
+
+    #[test]
+    fn cpuid_exit_rejects_other_instructions() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(1, 0, 0);
+        assert_eq!(
+            emulate(SVM_EXIT_CPUID, &mut frame, fetcher(&RDMSR_INSTRUCTION), &mut ghcb),
+            Err(VcError::UnexpectedInstruction(SVM_EXIT_CPUID))
+        );
+        assert!(ghcb.cpuid_requests.is_empty());
+        assert_eq!(frame.rip.as_u64(), START_RIP);
+    }
+
+    #[test]
+    fn msr_exits() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(0xFFFF_FFFF_0000_0001, 0xC001_0130, 0x2);
+        emulate(SVM_EXIT_MSR, &mut frame, fetcher(&WRMSR_INSTRUCTION), &mut ghcb).unwrap();
+        assert_eq!(ghcb.msr_writes, [(0xC001_0130, 0x2_0000_0001)]);
+
+        emulate(SVM_EXIT_MSR, &mut frame, fetcher(&RDMSR_INSTRUCTION), &mut ghcb).unwrap();
+        assert_eq!((frame.rax, frame.rdx), (0xC001_0130, 0xAAAA_BBBB));
+        assert_eq!(frame.rip.as_u64(), START_RIP + 4);
+    }
+
+    #[test]
+    fn io_exits() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(0xFFFF_FFFF_FFFF_FF41, 0, 0x3F8);
+        // out dx, al
+        emulate(SVM_EXIT_IOIO, &mut frame, fetcher(&[0xEE]), &mut ghcb).unwrap();
+        // in ax, 0x71
+        emulate(SVM_EXIT_IOIO, &mut frame, fetcher(&[0x66, 0xE5, 0x71]), &mut ghcb).unwrap();
+        assert_eq!(frame.rax, 0xFFFF_FFFF_FFFF_5678);
+        // in eax, dx
+        emulate(SVM_EXIT_IOIO, &mut frame, fetcher(&[0xED]), &mut ghcb).unwrap();
+        assert_eq!(frame.rax, 0x1234_5678);
+
+        assert_eq!(ghcb.io_writes, [(0x3F8, IoSize::Byte, 0x41)]);
+        assert_eq!(ghcb.io_reads, [(0x71, IoSize::Word), (0x3F8, IoSize::DoubleWord)]);
+        assert_eq!(frame.rip.as_u64(), START_RIP + 1 + 3 + 1);
+    }
+
+    #[test]
+    fn string_io_is_not_emulated() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(0, 0, 0x3F8);
+        // outsb
+        assert_eq!(
+            emulate(SVM_EXIT_IOIO, &mut frame, fetcher(&[0x6E]), &mut ghcb),
+            Err(VcError::UnexpectedInstruction(SVM_EXIT_IOIO))
+        );
+    }
+
+    #[test]
+    fn unsupported_exit_code() {
+        let mut ghcb = MockGhcb::default();
+        let mut frame = stack_frame(0, 0, 0);
+        assert_eq!(
+            emulate(0x7F, &mut frame, fetcher(&[0x0F, 0x01, 0xD9]), &mut ghcb),
+            Err(VcError::UnsupportedExitCode(0x7F))
+        );
+    }
+}
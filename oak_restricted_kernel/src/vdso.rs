@@ -0,0 +1,180 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Page shared read-only with the application, so that it can read the time
+//! and get random bytes without a system call.
+//!
+//! See <oak_restricted_kernel_interface::vdso> for the layout. We don't program
+//! a timer interrupt, so the page is refreshed whenever we return from a system
+//! call instead; applications extrapolate the time from the TSC in between.
+
+use oak_core::{sync::OnceCell, timer::rdtsc};
+use oak_restricted_kernel_interface::vdso::{VdsoData, VDSO_ADDR, VDSO_RANDOM_WORDS};
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame, Size2MiB},
+    VirtAddr,
+};
+
+use crate::{
+    clock,
+    mm::{self, Translator},
+    rng, FRAME_ALLOCATOR, PAGE_TABLES,
+};
+
+struct Vdso {
+    /// Physical frame backing the page.
+    frame: PhysFrame<Size2MiB>,
+    /// The page, as seen through the kernel's direct mapping.
+    data: &'static VdsoData,
+}
+
+static VDSO: OnceCell<Vdso> = OnceCell::new();
+
+/// Number of random words generated at once, enough for this many updates of
+/// the page.
+const POOL_UPDATES: usize = 8;
+
+/// Random words that have been generated, but not yet published in the page.
+///
+/// The page is updated on every return from a system call, so rather than
+/// asking the random number source for a handful of words every time (every
+/// request to the DRBG costs at least a full ChaCha20 block), we generate them
+/// in bulk and only refill the pool once it has been used up.
+struct RandomPool {
+    words: [u64; VDSO_RANDOM_WORDS * POOL_UPDATES],
+    /// Index of the first word that hasn't been published yet.
+    next: usize,
+}
+
+impl RandomPool {
+    const fn new() -> Self {
+        Self {
+            words: [0; VDSO_RANDOM_WORDS * POOL_UPDATES],
+            next: VDSO_RANDOM_WORDS * POOL_UPDATES,
+        }
+    }
+
+    /// Moves the next `dst.len()` words out of the pool, calling `refill` first
+    /// if there aren't enough of them left.
+    fn take<F: FnOnce(&mut [u64]) -> Result<(), &'static str>>(
+        &mut self,
+        dst: &mut [u64],
+        refill: F,
+    ) -> Result<(), &'static str> {
+        if self.words.len() - self.next < dst.len() {
+            refill(&mut self.words)?;
+            self.next = 0;
+        }
+        let words = &mut self.words[self.next..self.next + dst.len()];
+        dst.copy_from_slice(words);
+        // Published words are no longer secret, but there's no point in keeping them.
+        words.fill(0);
+        self.next += dst.len();
+        Ok(())
+    }
+}
+
+static RANDOM_POOL: Spinlock<RandomPool> = Spinlock::new(RandomPool::new());
+
+/// Allocates and initializes the shared page.
+pub fn init() -> Result<(), &'static str> {
+    let frame: PhysFrame<Size2MiB> =
+        FRAME_ALLOCATOR.lock().allocate_frame().ok_or("couldn't allocate a frame for the vDSO")?;
+    let page = {
+        let pt_guard = PAGE_TABLES.lock();
+        let pt = pt_guard.get().ok_or("page tables not initialized")?;
+        pt.translate_physical_frame(frame).ok_or("couldn't translate the vDSO frame")?
+    };
+    let data = page.start_address().as_mut_ptr::<VdsoData>();
+    // Safety: we've just allocated the frame, so nobody else has a reference to
+    // it, and the direct mapping makes it accessible to us.
+    let data: &'static VdsoData = unsafe {
+        data.write(VdsoData::new());
+        &*data
+    };
+    VDSO.set(Vdso { frame, data }).map_err(|_| "vDSO already initialized")?;
+    update();
+    Ok(())
+}
+
+/// Maps the shared page into the address space of the current process at
+/// `VDSO_ADDR`, read-only.
+pub fn map_into_current_process() -> Result<(), &'static str> {
+    let vdso = VDSO.get().ok_or("vDSO not initialized")?;
+    mm::map_user_read_only(VirtAddr::new(VDSO_ADDR), vdso.frame)
+}
+
+/// Publishes the current time and fresh random words in the shared page.
+///
+/// Does nothing if the page hasn't been set up.
+pub fn update() {
+    let Some(vdso) = VDSO.get() else {
+        return;
+    };
+    let mut random = [0; VDSO_RANDOM_WORDS];
+    RANDOM_POOL
+        .lock()
+        .take(&mut random, rng::fill_u64)
+        .expect("couldn't get random words for the vDSO");
+    let tsc_frequency_hz = clock::tsc_frequency().map_or(0, |frequency| frequency.hz);
+    vdso.data.update(rdtsc(), tsc_frequency_hz, &random);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn pool_is_refilled_only_when_empty() {
+        let mut pool = RandomPool::new();
+        let refills = Cell::new(0u64);
+        let refill = |words: &mut [u64]| {
+            refills.set(refills.get() + 1);
+            for (i, word) in words.iter_mut().enumerate() {
+                *word = (refills.get() << 32) | i as u64;
+            }
+            Ok(())
+        };
+
+        let mut published = alloc::vec::Vec::new();
+        for _ in 0..2 * POOL_UPDATES {
+            let mut random = [0; VDSO_RANDOM_WORDS];
+            pool.take(&mut random, refill).unwrap();
+            published.extend_from_slice(&random);
+        }
+        assert_eq!(refills.get(), 2);
+        // No word was published twice.
+        published.sort_unstable();
+        published.dedup();
+        assert_eq!(published.len(), 2 * POOL_UPDATES * VDSO_RANDOM_WORDS);
+    }
+
+    #[test]
+    fn failed_refill_is_retried() {
+        let mut pool = RandomPool::new();
+        let mut random = [0; VDSO_RANDOM_WORDS];
+        assert!(pool.take(&mut random, |_| Err("no randomness")).is_err());
+        let refill = |words: &mut [u64]| {
+            words.fill(1);
+            Ok(())
+        };
+        assert!(pool.take(&mut random, refill).is_ok());
+        assert_eq!(random, [1; VDSO_RANDOM_WORDS]);
+    }
+}
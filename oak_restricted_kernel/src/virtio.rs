@@ -17,26 +17,50 @@
 use core::alloc::Allocator;
 
 use log::info;
-use oak_channel::{Read, Write};
+use oak_channel::{ChannelError, Read, Write};
+use oak_core::sync::OnceCell;
 use rust_hypervisor_firmware_virtio::pci::VirtioPciTransport;
 use x86_64::{PhysAddr, VirtAddr};
 
-use crate::mm::Translator;
+use crate::{memory::debug_assert_shared, mm::Translator};
 
 // The virtio vsock port on which to listen.
 #[cfg(feature = "vsock_channel")]
 const VSOCK_PORT: u32 = 1024;
 
+/// The context id assigned to the guest, if we communicate over vsock.
+static VSOCK_GUEST_CID: OnceCell<u64> = OnceCell::new();
+
+/// Returns the context id assigned to the guest on vsock, or `None` if the
+/// vsock device hasn't been set up (e.g. because a different channel is used).
+pub fn vsock_guest_cid() -> Option<u64> {
+    VSOCK_GUEST_CID.get().copied()
+}
+
 pub struct Channel<T> {
     inner: T,
 }
 
+/// Turns the driver's end-of-stream error into the one the channel users
+/// expect.
+fn convert_peer_closed(err: anyhow::Error) -> anyhow::Error {
+    if err.is::<oak_virtio::PeerClosed>() {
+        anyhow::Error::msg(oak_channel::PeerClosed)
+    } else {
+        err
+    }
+}
+
 impl<T> Read for Channel<T>
 where
     T: oak_virtio::Read,
 {
     fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
-        self.inner.read_exact(data)
+        self.inner.read_exact(data).map_err(convert_peer_closed)
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.inner.peer_closed()
     }
 }
 
@@ -45,11 +69,21 @@ where
     T: oak_virtio::Write,
 {
     fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        self.inner.write_all(data)
+        self.inner.write_all(data).map_err(convert_peer_closed)
     }
     fn flush(&mut self) -> anyhow::Result<()> {
         self.inner.flush()
     }
+    fn max_write_size(&self) -> usize {
+        self.inner.max_write_size()
+    }
+    fn control(&mut self, request: u64, arg: usize) -> Result<usize, ChannelError> {
+        self.inner.control(request, arg).map_err(|err| match err {
+            oak_virtio::ControlError::Unsupported => ChannelError::Unsupported,
+            oak_virtio::ControlError::InvalidArgument => ChannelError::InvalidArgument,
+            oak_virtio::ControlError::Io => ChannelError::Io,
+        })
+    }
 }
 
 #[cfg(feature = "vsock_channel")]
@@ -59,12 +93,54 @@ pub fn get_vsock_channel<A: Allocator>(
     use crate::PAGE_TABLES;
     let pt_guard = PAGE_TABLES.lock();
     let vsock = oak_virtio::vsock::VSock::find_and_configure_device(
-        |vaddr: VirtAddr| pt_guard.get().unwrap().translate_virtual(vaddr),
+        |vaddr: VirtAddr| {
+            // Only memory shared with the host is ever handed to the device.
+            debug_assert_shared(vaddr, 1);
+            pt_guard.get().unwrap().translate_virtual(vaddr)
+        },
         |paddr: PhysAddr| pt_guard.get().unwrap().translate_physical(paddr),
         alloc,
     )
     .expect("couldn't configure PCI virtio vsock device");
     info!("Socket device status: {}", vsock.get_status());
+    info!("Guest CID: {}", vsock.guest_cid());
+    if VSOCK_GUEST_CID.set(vsock.guest_cid()).is_err() {
+        log::warn!("vsock device was configured more than once");
+    }
     let listener = oak_virtio::vsock::socket::SocketListener::new(vsock, VSOCK_PORT);
     Channel { inner: listener.accept().expect("couldn't accept connection") }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device that only knows about request 1, which echoes its argument.
+    struct MockDevice;
+
+    impl oak_virtio::Write for MockDevice {
+        fn write_all(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn control(&mut self, request: u64, arg: usize) -> Result<usize, oak_virtio::ControlError> {
+            match request {
+                1 if arg == 0 => Err(oak_virtio::ControlError::InvalidArgument),
+                1 => Ok(arg),
+                _ => Err(oak_virtio::ControlError::Unsupported),
+            }
+        }
+    }
+
+    #[test]
+    fn control_is_forwarded_to_device() {
+        let mut channel = Channel { inner: MockDevice };
+        assert_eq!(channel.control(1, 42), Ok(42));
+        assert_eq!(channel.control(1, 0), Err(ChannelError::InvalidArgument));
+        assert_eq!(channel.control(2, 42), Err(ChannelError::Unsupported));
+    }
+}
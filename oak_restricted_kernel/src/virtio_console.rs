@@ -14,19 +14,15 @@
 // limitations under the License.
 //
 
-use alloc::{string::String, vec::Vec};
 use core::{
     alloc::{Allocator, Layout},
     ptr::NonNull,
 };
 
-use aml::{
-    resource::{MemoryRangeDescriptor, Resource},
-    AmlContext,
-};
 use anyhow::anyhow;
 use log::info;
 use oak_channel::{Read, Write};
+use oak_restricted_kernel_interface::syscalls::AcpiDeviceKind;
 use spinning_top::Spinlock;
 use virtio_drivers::{
     device::console::VirtIOConsole,
@@ -36,9 +32,7 @@ use virtio_drivers::{
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::{
-    acpi::{Acpi, AcpiDevice, VIRTIO_MMIO},
-    mm::Translator,
-    GUEST_HOST_HEAP, PAGE_TABLES,
+    acpi::Acpi, memory::debug_assert_shared, mm::Translator, GUEST_HOST_HEAP, PAGE_TABLES,
 };
 
 struct OakHal;
@@ -54,6 +48,7 @@ unsafe impl Hal for OakHal {
             .allocate(Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap())
             .expect("Failed to allocate memory for virtio MMIO")
             .cast::<u8>();
+        debug_assert_shared(VirtAddr::from_ptr(vaddr.as_ptr()), pages * PAGE_SIZE);
         let phys_addr = PAGE_TABLES
             .lock()
             .get()
@@ -98,6 +93,7 @@ unsafe impl Hal for OakHal {
     ) -> virtio_drivers::PhysAddr {
         // No additional work needed for sharing as the buffer was allocated from the
         // guest-host allocator.
+        debug_assert_shared(VirtAddr::from_ptr(buffer.cast::<u8>().as_ptr()), buffer.len());
         PAGE_TABLES
             .lock()
             .get()
@@ -162,45 +158,22 @@ impl Write for MmioConsoleChannel {
     }
 }
 
-fn find_memory_range(device: &AcpiDevice, ctx: &mut AmlContext) -> Option<(PhysAddr, PhysAddr)> {
-    for resource in device.crs(ctx).unwrap().unwrap() {
-        match resource {
-            Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
-                is_writable: _,
-                base_address,
-                range_length,
-            }) => {
-                return Some((
-                    PhysAddr::new(base_address as u64),
-                    PhysAddr::new((base_address + range_length) as u64),
-                ));
-            }
-            _ => continue,
-        }
-    }
-    None
-}
-
 pub fn get_console_channel(acpi: &mut Acpi) -> MmioConsoleChannel {
     let devices = acpi.devices().unwrap();
 
-    let virtio_devices: Vec<&AcpiDevice> = devices
-        .iter()
-        .filter(|device| {
-            device.hid(&mut acpi.aml).ok().flatten() == Some(String::from(VIRTIO_MMIO))
-        })
-        .collect();
+    let virtio_devices = devices.iter().filter(|device| device.kind == AcpiDeviceKind::VirtioMmio);
 
     for device in virtio_devices {
         let header = PAGE_TABLES
             .lock()
             .get()
             .unwrap()
-            .translate_physical(
-                find_memory_range(device, &mut acpi.aml)
+            .translate_physical(PhysAddr::new(
+                device
+                    .memory_range()
                     .expect("unable to determine physical memory range for virtio MMIO device")
                     .0,
-            )
+            ))
             .unwrap();
 
         let transport =
@@ -26,16 +26,26 @@ use strum::{Display, FromRepr};
 #[repr(isize)]
 #[non_exhaustive]
 pub enum Errno {
+    /// Operation not permitted
+    EPERM = -1,
     /// Input/output error
     EIO = -5,
     /// Bad file descriptor
     EBADF = -9,
+    /// Resource temporarily unavailable
+    EAGAIN = -11,
     /// Cannot allocate memory
     ENOMEM = -12,
     /// Bad address
     EFAULT = -14,
+    /// No such device
+    ENODEV = -19,
     /// Invalid argument
     EINVAL = -22,
+    /// Inappropriate ioctl for device
+    ENOTTY = -25,
+    /// Numerical result out of range
+    ERANGE = -34,
     /// Function not implemented
     ENOSYS = -38,
 }
@@ -21,6 +21,7 @@ pub mod errno;
 mod raw_syscall;
 pub mod syscall;
 pub mod syscalls;
+pub mod vdso;
 
 pub use errno::Errno;
 pub use syscalls::Syscall;
@@ -33,3 +34,40 @@ pub const DERIVED_KEY_FD: i32 = 0x21;
 
 /// Predefined file descriptor for reading the dice attestation data.
 pub const DICE_DATA_FD: i32 = 0x42;
+
+/// Auxiliary vector entry type holding the entry point of an additional payload
+/// image.
+///
+/// If the kernel loads several payload images, control is transferred to the
+/// first one; the entry points of the others are passed in one entry of this
+/// type each, in the order in which the images were loaded.
+pub const AT_OAK_PAYLOAD_IMAGE_ENTRY: u64 = 0x4f41_4b00;
+
+/// Auxiliary vector entry type holding the address of the attestation report
+/// that the kernel requested at boot.
+///
+/// The report is a raw SEV-SNP attestation report, mapped read-only. Its
+/// report-data contains the SHA2-256 digest of the certificate for the key the
+/// kernel hands to the application. The entry is missing if no report is
+/// available, e.g. if the kernel is not running under SEV-SNP.
+pub const AT_OAK_ATTESTATION_REPORT: u64 = 0x4f41_4b01;
+
+/// Virtual address at which the attestation report requested at boot is
+/// mapped; see `AT_OAK_ATTESTATION_REPORT`.
+pub const ATTESTATION_REPORT_ADDR: u64 = 0x7FFF_FF40_0000;
+
+/// Auxiliary vector entry type holding the address of the data ramdisk that
+/// the VMM staged for the application, e.g. to pass in model weights.
+///
+/// The ramdisk is mapped read-only, and its size is in the
+/// `AT_OAK_RAMDISK_SIZE` entry. Both entries are missing if there is no
+/// ramdisk, or if the kernel loaded the application itself from the ramdisk.
+pub const AT_OAK_RAMDISK: u64 = 0x4f41_4b02;
+
+/// Auxiliary vector entry type holding the size of the data ramdisk, in bytes;
+/// see `AT_OAK_RAMDISK`.
+pub const AT_OAK_RAMDISK_SIZE: u64 = 0x4f41_4b03;
+
+/// Virtual address at which the data ramdisk is mapped; see `AT_OAK_RAMDISK`.
+/// The rest of the last 2 MiB page after the ramdisk reads as zero.
+pub const RAMDISK_ADDR: u64 = 0x7F00_0000_0000;
@@ -18,7 +18,10 @@ use core::ffi::{c_int, c_size_t, c_ssize_t, c_void};
 
 use crate::{
     syscall,
-    syscalls::{MmapFlags, MmapProtection},
+    syscalls::{
+        AcpiDeviceInfo, LogLevel, MemoryStats, MmapFlags, MmapProtection, SyscallStats,
+        ATTESTATION_REPORT_SIZE, EVIDENCE_REPORT_DATA_SIZE, MEASUREMENT_REGISTER_SIZE,
+    },
     Errno, Syscall,
 };
 
@@ -71,6 +74,40 @@ pub fn fsync(fd: i32) -> Result<(), Errno> {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sys_mlock(addr: *const c_void, len: c_size_t) -> c_ssize_t {
+    unsafe { syscall!(Syscall::Mlock, addr, len) }
+}
+
+#[inline]
+pub fn mlock(buf: &[u8]) -> Result<(), Errno> {
+    let ret = sys_mlock(buf.as_ptr() as *const c_void, buf.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from mlock syscall: {}", ret)))
+    } else {
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_munlock(addr: *const c_void, len: c_size_t) -> c_ssize_t {
+    unsafe { syscall!(Syscall::Munlock, addr, len) }
+}
+
+#[inline]
+pub fn munlock(buf: &[u8]) -> Result<(), Errno> {
+    let ret = sys_munlock(buf.as_ptr() as *const c_void, buf.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from munlock syscall: {}", ret)))
+    } else {
+        Ok(())
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sys_mmap(
     addr: *const c_void,
@@ -110,6 +147,41 @@ pub fn mmap(
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sys_brk(addr: *const c_void) -> isize {
+    unsafe { syscall!(Syscall::Brk, addr) }
+}
+
+/// Sets the program break to `addr`, returning the new break.
+///
+/// Passing a null pointer queries the current break.
+#[inline]
+pub fn brk(addr: *const c_void) -> Result<*const c_void, Errno> {
+    let ret = sys_brk(addr) as *const c_void;
+    if !addr.is_null() && ret != addr {
+        Err(Errno::ENOMEM)
+    } else {
+        Ok(ret)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_ioctl(fd: c_int, request: u64, arg: usize) -> c_ssize_t {
+    unsafe { syscall!(Syscall::Ioctl, fd, request, arg) }
+}
+
+#[inline]
+pub fn ioctl(fd: i32, request: u64, arg: usize) -> Result<usize, Errno> {
+    let ret = sys_ioctl(fd, request, arg);
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from ioctl syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sys_exit(status: c_int) {
     unsafe { syscall!(Syscall::Exit, status) };
@@ -130,6 +202,200 @@ pub fn unstable_switch_proccess(buf: &[u8]) -> ! {
     unreachable!();
 }
 
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_syscall_stats(
+    buf: *mut SyscallStats,
+    count: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetSyscallStats, buf, count) }
+}
+
+pub fn unstable_get_syscall_stats(buf: &mut [SyscallStats]) -> Result<usize, Errno> {
+    let ret = sys_unstable_get_syscall_stats(buf.as_mut_ptr(), buf.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from get_syscall_stats syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_memory_stats(buf: *mut MemoryStats) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetMemoryStats, buf) }
+}
+
+pub fn unstable_get_memory_stats() -> Result<MemoryStats, Errno> {
+    let mut stats = MemoryStats::default();
+    let ret = sys_unstable_get_memory_stats(&mut stats);
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from get_memory_stats syscall: {}", ret)))
+    } else {
+        Ok(stats)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_evidence_bundle(
+    report_data: *const c_void,
+    buf: *mut c_void,
+    count: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetEvidenceBundle, report_data, buf, count) }
+}
+
+pub fn unstable_get_evidence_bundle(
+    report_data: &[u8; EVIDENCE_REPORT_DATA_SIZE],
+    buf: &mut [u8],
+) -> Result<usize, Errno> {
+    let ret = sys_unstable_get_evidence_bundle(
+        report_data.as_ptr() as *const c_void,
+        buf.as_mut_ptr() as *mut c_void,
+        buf.len(),
+    );
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret).unwrap_or_else(|| {
+            panic!("unexpected error from get_evidence_bundle syscall: {}", ret)
+        }))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_acpi_devices(
+    buf: *mut AcpiDeviceInfo,
+    count: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetAcpiDevices, buf, count) }
+}
+
+pub fn unstable_get_acpi_devices(buf: &mut [AcpiDeviceInfo]) -> Result<usize, Errno> {
+    let ret = sys_unstable_get_acpi_devices(buf.as_mut_ptr(), buf.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from get_acpi_devices syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_vsock_guest_cid() -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetVsockGuestCid) }
+}
+
+pub fn unstable_get_vsock_guest_cid() -> Result<u64, Errno> {
+    let ret = sys_unstable_get_vsock_guest_cid();
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret).unwrap_or_else(|| {
+            panic!("unexpected error from get_vsock_guest_cid syscall: {}", ret)
+        }))
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_interrupt_counts(buf: *mut u64, count: c_size_t) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetInterruptCounts, buf, count) }
+}
+
+pub fn unstable_get_interrupt_counts(buf: &mut [u64]) -> Result<usize, Errno> {
+    let ret = sys_unstable_get_interrupt_counts(buf.as_mut_ptr(), buf.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret).unwrap_or_else(|| {
+            panic!("unexpected error from get_interrupt_counts syscall: {}", ret)
+        }))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_get_attestation_reports(
+    report_datas: *const c_void,
+    buf: *mut c_void,
+    count: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableGetAttestationReports, report_datas, buf, count) }
+}
+
+/// Requests an attestation report for each entry in `report_datas`, writing
+/// them to the start of `buf`.
+pub fn unstable_get_attestation_reports(
+    report_datas: &[[u8; EVIDENCE_REPORT_DATA_SIZE]],
+    buf: &mut [[u8; ATTESTATION_REPORT_SIZE]],
+) -> Result<usize, Errno> {
+    if buf.len() < report_datas.len() {
+        return Err(Errno::EINVAL);
+    }
+    let ret = sys_unstable_get_attestation_reports(
+        report_datas.as_ptr() as *const c_void,
+        buf.as_mut_ptr() as *mut c_void,
+        report_datas.len(),
+    );
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret).unwrap_or_else(|| {
+            panic!("unexpected error from get_attestation_reports syscall: {}", ret)
+        }))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_extend_measurement_register(
+    index: c_size_t,
+    digest: *const c_void,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableExtendMeasurementRegister, index, digest) }
+}
+
+/// Extends measurement register `index` with `digest`.
+pub fn unstable_extend_measurement_register(
+    index: usize,
+    digest: &[u8; MEASUREMENT_REGISTER_SIZE],
+) -> Result<(), Errno> {
+    let ret = sys_unstable_extend_measurement_register(index, digest.as_ptr() as *const c_void);
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret).unwrap_or_else(|| {
+            panic!("unexpected error from extend_measurement_register syscall: {}", ret)
+        }))
+    } else {
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unstable_log(
+    level: c_size_t,
+    buf: *const c_void,
+    count: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::UnstableLog, level, buf, count) }
+}
+
+pub fn unstable_log(level: LogLevel, message: &str) -> Result<usize, Errno> {
+    let ret = sys_unstable_log(level as usize, message.as_ptr() as *const c_void, message.len());
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from log syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
 // Note that these tests are not being executed against Restricted Kernel, but
 // rather the Linux kernel of the machine cargo is running on!
 #[cfg(test)]
@@ -174,6 +440,13 @@ mod tests {
         assert!(mem.is_ok());
     }
 
+    #[test]
+    fn test_mlock() {
+        let buf = [0u8; 16];
+        assert_eq!(mlock(&buf), Ok(()));
+        assert_eq!(munlock(&buf), Ok(()));
+    }
+
     #[test]
     fn test_mmap_error() {
         let mem = mmap(
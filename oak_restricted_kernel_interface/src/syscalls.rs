@@ -80,6 +80,35 @@ pub enum Syscall {
     ///   - We do not support PROT_NONE; PROT_READ is always implied.
     Mmap = 9,
 
+    /// Sets the end of the data segment (the program break).
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): requested new program break
+    /// Returns:
+    ///   the new program break on success; the current program break on
+    ///   failure.
+    /// Oak Restricted Kernel considerations:
+    ///   - the heap starts at the first 2 MiB boundary after the highest
+    ///     loadable segment, and memory is mapped in 2 MiB pages.
+    ///   - the heap and the loadable segments together may not exceed the
+    ///     payload memory limit (`max_payload_mib`).
+    ///   - shrinking the break unmaps and frees the pages above it.
+    Brk = 12,
+
+    /// Performs a device-specific operation on a file descriptor.
+    ///
+    /// Arguments:
+    ///   - arg0 (c_int): file descriptor number
+    ///   - arg1 (u64): request code; see [`IOCTL_FLUSH`],
+    ///     [`IOCTL_MAX_MESSAGE_SIZE`] and [`IOCTL_SET_LOG_LEVEL`] for the
+    ///     requests every channel supports. The meaning of other requests
+    ///     depends on the channel transport.
+    ///   - arg2 (usize): request-specific argument
+    /// Returns:
+    ///   a value of <errno::Errno> on failure (ENOTTY if the request is not
+    ///   supported); a request-specific non-negative value, otherwise.
+    Ioctl = 16,
+
     /// Terminates he calling process.
     /// Arguments:
     ///   - arg0 (c_int): error code
@@ -96,6 +125,29 @@ pub enum Syscall {
     ///   a value of <errno::Errno> on failure; 0, otherwise.
     Fsync = 74,
 
+    /// Locks pages in memory.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): start address of the range
+    ///   - arg1 (c_size_t): length of the range
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; 0, otherwise.
+    /// Oak Restricted Kernel considerations:
+    ///   - the range is extended to 2 MiB page boundaries.
+    ///   - all pages in the range must be mapped (ENOMEM otherwise). Copy-on-
+    ///     write pages are replaced by private copies right away, so accessing
+    ///     locked memory never causes a page fault.
+    Mlock = 149,
+
+    /// Unlocks pages previously locked with `Mlock`.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): start address of the range
+    ///   - arg1 (c_size_t): length of the range
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; 0, otherwise.
+    Munlock = 150,
+
     /// Terminates the calling process and executes the supplied ELF binary
     /// instead.
     ///
@@ -105,6 +157,324 @@ pub enum Syscall {
     /// Returns:
     ///   a value of <errno::Errno> on failure; 0, otherwise.
     UnstableSwitchProcess = UNSTABLE_SYSCALL_SPACE + 1,
+
+    /// Retrieves per-syscall invocation statistics collected by the kernel.
+    ///
+    /// This is a debugging aid that is only available if the kernel was booted
+    /// with the `debug_syscall_stats` argument.
+    ///
+    /// Arguments:
+    ///   - arg0 (*mut SyscallStats): pointer to the buffer to be filled
+    ///   - arg1 (c_size_t): number of `SyscallStats` entries that fit in the
+    ///     buffer
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the number of
+    /// entries written.
+    UnstableGetSyscallStats = UNSTABLE_SYSCALL_SPACE + 2,
+
+    /// Emits a log message through the kernel's logger.
+    ///
+    /// The message is interleaved with the kernel's own logs and is subject to
+    /// the same log level filter. Messages longer than `MAX_LOG_MESSAGE_SIZE`
+    /// bytes are truncated; invalid UTF-8 is replaced.
+    ///
+    /// Arguments:
+    ///   - arg0 (c_size_t): log level, a value of <LogLevel>
+    ///   - arg1 (*const c_void): pointer to the buffer containing the message
+    ///   - arg2 (c_size_t): size of the buffer
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the number of bytes
+    /// of the message that were logged.
+    UnstableLog = UNSTABLE_SYSCALL_SPACE + 3,
+
+    /// Retrieves a snapshot of the kernel's memory usage.
+    ///
+    /// This is a debugging aid that is only available if the kernel was booted
+    /// with the `diagnostics` argument.
+    ///
+    /// Arguments:
+    ///   - arg0 (*mut MemoryStats): pointer to the struct to be filled
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; 0, otherwise.
+    UnstableGetMemoryStats = UNSTABLE_SYSCALL_SPACE + 4,
+
+    /// Requests a fresh attestation report and returns it as an evidence
+    /// bundle, together with the certificate chain, the measured-boot event
+    /// log and the measurement registers; see <EvidenceBundleHeader> for the
+    /// layout.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): pointer to the `EVIDENCE_REPORT_DATA_SIZE`
+    ///     bytes of report-data to bind to the report
+    ///   - arg1 (*mut c_void): pointer to the buffer to write the bundle to
+    ///   - arg2 (c_size_t): size of the buffer
+    /// Returns:
+    ///   a value of <errno::Errno> on failure (`ERANGE` if the bundle doesn't
+    /// fit in the buffer); otherwise, the size of the bundle.
+    UnstableGetEvidenceBundle = UNSTABLE_SYSCALL_SPACE + 5,
+
+    /// Retrieves the devices described in the ACPI namespace, e.g. to find the
+    /// addresses of virtio MMIO devices.
+    ///
+    /// Arguments:
+    ///   - arg0 (*mut AcpiDeviceInfo): pointer to the buffer to be filled
+    ///   - arg1 (c_size_t): number of `AcpiDeviceInfo` entries that fit in the
+    ///     buffer
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the number of
+    /// entries written.
+    UnstableGetAcpiDevices = UNSTABLE_SYSCALL_SPACE + 6,
+
+    /// Retrieves the context id (CID) assigned to the guest on vsock, e.g. for
+    /// payloads that open further vsock connections.
+    ///
+    /// Arguments: none.
+    /// Returns:
+    ///   `ENODEV` if the kernel doesn't communicate over vsock; otherwise, the
+    /// guest CID.
+    UnstableGetVsockGuestCid = UNSTABLE_SYSCALL_SPACE + 7,
+
+    /// Retrieves the number of times the kernel has handled each interrupt
+    /// vector, e.g. for spotting interrupt storms.
+    ///
+    /// This is a debugging aid that is only available if the kernel was booted
+    /// with the `diagnostics` argument.
+    ///
+    /// Arguments:
+    ///   - arg0 (*mut u64): pointer to the buffer to be filled, indexed by
+    ///     vector
+    ///   - arg1 (c_size_t): number of counts that fit in the buffer; at most
+    ///     `INTERRUPT_VECTORS` are written
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the number of
+    /// entries written.
+    UnstableGetInterruptCounts = UNSTABLE_SYSCALL_SPACE + 8,
+
+    /// Requests one fresh attestation report for each of several report-data
+    /// values, e.g. to bind several keys, in a single batch of back-to-back
+    /// guest requests.
+    ///
+    /// Unlike the report in an evidence bundle, the report-data of each report
+    /// is exactly the report-data that was passed in.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): pointer to the report-data values,
+    ///     `EVIDENCE_REPORT_DATA_SIZE` bytes each
+    ///   - arg1 (*mut c_void): pointer to the buffer to be filled with the
+    ///     reports, `ATTESTATION_REPORT_SIZE` bytes each
+    ///   - arg2 (c_size_t): number of report-data values; at most
+    ///     `MAX_ATTESTATION_REPORTS`
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the number of reports
+    /// written.
+    UnstableGetAttestationReports = UNSTABLE_SYSCALL_SPACE + 9,
+
+    /// Extends a measurement register with a digest, so that its new value is
+    /// the SHA-384 digest of its old value followed by the digest, e.g. to
+    /// record the configuration the payload loaded in later evidence bundles.
+    ///
+    /// The payload may only extend `WORKLOAD_MEASUREMENT_REGISTER`; the other
+    /// registers record what the kernel loaded.
+    ///
+    /// Arguments:
+    ///   - arg0 (c_size_t): index of the register to extend
+    ///   - arg1 (*const c_void): pointer to the `MEASUREMENT_REGISTER_SIZE`
+    ///     bytes of the digest
+    /// Returns:
+    ///   a value of <errno::Errno> on failure (`EPERM` for a register the
+    /// payload may not extend); otherwise, 0.
+    UnstableExtendMeasurementRegister = UNSTABLE_SYSCALL_SPACE + 10,
+}
+
+/// Maximum size of a message logged via `Syscall::UnstableLog`, in bytes.
+pub const MAX_LOG_MESSAGE_SIZE: usize = 1024;
+
+/// `Syscall::Ioctl` request that flushes the channel. Returns 0.
+pub const IOCTL_FLUSH: u64 = 0x4f43_0001;
+
+/// `Syscall::Ioctl` request that returns the maximum size of a single message
+/// on the channel.
+pub const IOCTL_MAX_MESSAGE_SIZE: u64 = 0x4f43_0002;
+
+/// `Syscall::Ioctl` request that sets the maximum level of the kernel's log, so
+/// that verbosity can be raised while debugging without rebooting.
+///
+/// The argument is the value of a [`LogLevel`], or 0 to turn logging off.
+/// Returns the previous level, in the same encoding.
+///
+/// Only the payload can make this request; the peer at the other end of the
+/// channel can't, so it is up to the payload to decide who may change the
+/// level (e.g. only an authenticated operator on a control stream).
+///
+/// The kernel only honours the request if it was booted with the
+/// `log_level_ioctl` argument; otherwise, the request fails with EPERM.
+pub const IOCTL_SET_LOG_LEVEL: u64 = 0x4f43_0003;
+
+/// Size of the report-data passed to `Syscall::UnstableGetEvidenceBundle`.
+pub const EVIDENCE_REPORT_DATA_SIZE: usize = 64;
+
+/// Size of a SEV-SNP attestation report, as returned by
+/// `Syscall::UnstableGetAttestationReports`.
+pub const ATTESTATION_REPORT_SIZE: usize = 1184;
+
+/// Maximum number of reports that can be requested with a single
+/// `Syscall::UnstableGetAttestationReports`.
+pub const MAX_ATTESTATION_REPORTS: usize = 16;
+
+/// Size of the value of a measurement register, and of the digests passed to
+/// `Syscall::UnstableExtendMeasurementRegister`.
+pub const MEASUREMENT_REGISTER_SIZE: usize = 48;
+
+/// The measurement register that the payload can extend with
+/// `Syscall::UnstableExtendMeasurementRegister`.
+pub const WORKLOAD_MEASUREMENT_REGISTER: usize = 3;
+
+/// Magic number at the start of an evidence bundle ("OEVB" in ASCII, when
+/// stored little-endian).
+pub const EVIDENCE_BUNDLE_MAGIC: u32 = u32::from_le_bytes(*b"OEVB");
+
+/// Version of the evidence bundle layout described by <EvidenceBundleHeader>.
+pub const EVIDENCE_BUNDLE_VERSION: u32 = 2;
+
+/// Header of an evidence bundle, as returned by
+/// `Syscall::UnstableGetEvidenceBundle`.
+///
+/// The header is followed by the sections it describes, back to back and in
+/// this order:
+///   - the SEV-SNP attestation report;
+///   - the VCEK, ASK and ARK certificates, as returned by an extended guest
+///     request (empty if not available);
+///   - the measured-boot event log, i.e. the DICE evidence of the boot chain as
+///     the raw `oak_dice::evidence` structures;
+///   - the values of the measurement registers that the kernel extends at
+///     runtime, `MEASUREMENT_REGISTER_SIZE` bytes each.
+///
+/// SEV-SNP has no runtime measurement registers of its own, so the kernel
+/// keeps them in guest memory. To attest them, the report-data of the report is
+/// not the report-data the payload asked for, but the SHA-384 digest of that
+/// report-data followed by the register values, padded with zeroes.
+///
+/// All fields are little-endian.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EvidenceBundleHeader {
+    /// Always `EVIDENCE_BUNDLE_MAGIC`.
+    pub magic: u32,
+    /// Always `EVIDENCE_BUNDLE_VERSION`.
+    pub version: u32,
+    /// Size of the attestation report, in bytes.
+    pub report_size: u32,
+    /// Size of the certificate chain, in bytes.
+    pub cert_chain_size: u32,
+    /// Size of the event log, in bytes.
+    pub event_log_size: u32,
+    /// Size of the measurement register values, in bytes.
+    pub measurement_registers_size: u32,
+    /// Reserved, must be zero.
+    pub reserved: u32,
+}
+
+/// Well-known types of ACPI devices, identified by their hardware ID.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, Eq, FromRepr, PartialEq)]
+pub enum AcpiDeviceKind {
+    /// A device with a hardware ID we don't know about, or without one.
+    #[default]
+    Unknown = 0,
+    GenericEventDevice = 1,
+    VirtioMmio = 2,
+    SerialPort = 3,
+    PciBus = 4,
+    PcieBus = 5,
+    PowerButton = 6,
+    QemuFwCfg = 7,
+}
+
+/// Size of the `name` field of <AcpiDeviceInfo>.
+pub const ACPI_DEVICE_NAME_SIZE: usize = 32;
+
+/// Size of the `hid` field of <AcpiDeviceInfo>.
+pub const ACPI_DEVICE_HID_SIZE: usize = 8;
+
+/// Value of `AcpiDeviceInfo::irq` for devices that don't use an interrupt.
+pub const ACPI_DEVICE_NO_IRQ: u32 = u32::MAX;
+
+/// A device described in the ACPI namespace, as returned by
+/// `Syscall::UnstableGetAcpiDevices`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AcpiDeviceInfo {
+    /// Full path of the device in the ACPI namespace, NUL-padded. Longer paths
+    /// are truncated.
+    pub name: [u8; ACPI_DEVICE_NAME_SIZE],
+    /// Hardware ID (`_HID`) of the device, NUL-padded; all zeroes if the device
+    /// has none.
+    pub hid: [u8; ACPI_DEVICE_HID_SIZE],
+    /// Type of the device, as a value of <AcpiDeviceKind>.
+    pub kind: u32,
+    /// First interrupt used by the device, or `ACPI_DEVICE_NO_IRQ`.
+    pub irq: u32,
+    /// Physical address of the first fixed memory range used by the device.
+    pub mmio_base: u64,
+    /// Size of the memory range in `mmio_base`; zero if the device doesn't use
+    /// one.
+    pub mmio_size: u64,
+}
+
+/// Log levels for `Syscall::UnstableLog`.
+///
+/// The values match the ones used by the `log` crate.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, Eq, FromRepr, PartialEq)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Number of interrupt vectors reported by
+/// `Syscall::UnstableGetInterruptCounts`.
+pub const INTERRUPT_VECTORS: usize = 256;
+
+/// Invocation statistics for a single system call, as returned by
+/// `Syscall::UnstableGetSyscallStats`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyscallStats {
+    /// System call number.
+    pub syscall: usize,
+    /// Number of times the system call was invoked.
+    pub count: u64,
+    /// Cumulative time spent handling the system call, in TSC ticks.
+    pub ticks: u64,
+}
+
+/// Snapshot of the kernel's memory usage, as returned by
+/// `Syscall::UnstableGetMemoryStats`.
+///
+/// Physical memory is tracked in 2 MiB frames; 4 KiB frames are carved out of
+/// (and counted as) allocated 2 MiB frames.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// Number of usable 2 MiB physical frames.
+    pub total_frames: u64,
+    /// Number of 2 MiB physical frames that have not been allocated.
+    pub free_frames: u64,
+    /// Length of the longest run of contiguous free 2 MiB frames.
+    pub largest_free_run: u64,
+    /// Bytes allocated from the kernel heap.
+    pub kernel_heap_used: u64,
+    /// Bytes still available in the kernel heap without growing it.
+    pub kernel_heap_free: u64,
+    /// Bytes allocated from the heap shared with the host.
+    pub guest_host_heap_used: u64,
+    /// Bytes still available in the heap shared with the host.
+    pub guest_host_heap_free: u64,
+    /// Total size of the memory mapped by the payload via `Syscall::Mmap`.
+    pub payload_mapped_bytes: u64,
 }
 
 bitflags! {
@@ -0,0 +1,227 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Layout of the read-only page that the kernel shares with the application.
+//!
+//! The page lets the application read the time, and obtain random bytes,
+//! without the cost of a system call. The kernel maps it at `VDSO_ADDR`,
+//! read-only to the application, and refreshes it before returning from every
+//! system call.
+//!
+//! Updates are published with a sequence lock: the sequence number is odd
+//! while an update is in progress, and readers retry until they have seen a
+//! consistent snapshot.
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// Virtual address of the shared page in the application's address space.
+///
+/// This is one 2 MiB page below the application stack, leaving an unmapped page
+/// in between.
+pub const VDSO_ADDR: u64 = 0x7FFF_FF80_0000;
+
+/// Number of 64-bit words in the random buffer.
+pub const VDSO_RANDOM_WORDS: usize = 8;
+
+/// Contents of the shared page.
+#[repr(C)]
+pub struct VdsoData {
+    /// Sequence number; odd while the kernel is updating the page.
+    sequence: AtomicU64,
+    /// Number of updates the kernel has made to the page.
+    updates: AtomicU64,
+    /// TSC frequency in Hz, or zero if it's not known.
+    tsc_frequency_hz: AtomicU64,
+    /// Value of the TSC when the page was last updated.
+    tsc: AtomicU64,
+    /// Time since boot, in nanoseconds, when the page was last updated.
+    time_ns: AtomicU64,
+    /// Random words, refilled on every update.
+    random: [AtomicU64; VDSO_RANDOM_WORDS],
+}
+
+/// A consistent copy of the shared page.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VdsoSnapshot {
+    /// Number of updates the kernel has made to the page.
+    ///
+    /// The random words change with every update, so an application that
+    /// doesn't want to consume the same random bytes twice has to wait for this
+    /// to change.
+    pub updates: u64,
+    pub tsc_frequency_hz: u64,
+    pub tsc: u64,
+    pub time_ns: u64,
+    pub random: [u64; VDSO_RANDOM_WORDS],
+}
+
+impl VdsoSnapshot {
+    /// Returns the time since boot, in nanoseconds, at the given TSC value.
+    ///
+    /// Returns `None` if the TSC frequency is not known, or if `tsc` is from
+    /// before the snapshot was taken.
+    pub fn time_ns_at(&self, tsc: u64) -> Option<u64> {
+        if self.tsc_frequency_hz == 0 {
+            return None;
+        }
+        let elapsed =
+            tsc.checked_sub(self.tsc)? as u128 * 1_000_000_000 / self.tsc_frequency_hz as u128;
+        self.time_ns.checked_add(u64::try_from(elapsed).ok()?)
+    }
+}
+
+impl VdsoData {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            updates: AtomicU64::new(0),
+            tsc_frequency_hz: AtomicU64::new(0),
+            tsc: AtomicU64::new(0),
+            time_ns: AtomicU64::new(0),
+            random: [Self::ZERO; VDSO_RANDOM_WORDS],
+        }
+    }
+
+    /// Publishes a new timestamp and new random words.
+    ///
+    /// Only meant to be called by the kernel, which has to ensure there is only
+    /// one writer at a time.
+    pub fn update(&self, tsc: u64, tsc_frequency_hz: u64, random: &[u64; VDSO_RANDOM_WORDS]) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        // Make sure the odd sequence number is visible before any of the data.
+        fence(Ordering::Release);
+
+        let time_ns = if tsc_frequency_hz == 0 {
+            0
+        } else {
+            (tsc as u128 * 1_000_000_000 / tsc_frequency_hz as u128) as u64
+        };
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        self.tsc_frequency_hz.store(tsc_frequency_hz, Ordering::Relaxed);
+        self.tsc.store(tsc, Ordering::Relaxed);
+        self.time_ns.store(time_ns, Ordering::Relaxed);
+        for (word, value) in self.random.iter().zip(random) {
+            word.store(*value, Ordering::Relaxed);
+        }
+
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Returns a consistent snapshot of the page, retrying while an update is
+    /// in progress.
+    pub fn read(&self) -> VdsoSnapshot {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let mut snapshot = VdsoSnapshot {
+                updates: self.updates.load(Ordering::Relaxed),
+                tsc_frequency_hz: self.tsc_frequency_hz.load(Ordering::Relaxed),
+                tsc: self.tsc.load(Ordering::Relaxed),
+                time_ns: self.time_ns.load(Ordering::Relaxed),
+                random: [0; VDSO_RANDOM_WORDS],
+            };
+            for (value, word) in snapshot.random.iter_mut().zip(&self.random) {
+                *value = word.load(Ordering::Relaxed);
+            }
+            // Make sure all the data has been read before checking the sequence number
+            // again.
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return snapshot;
+            }
+        }
+    }
+}
+
+impl Default for VdsoData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a snapshot of the page shared by the kernel.
+///
+/// # Safety
+///
+/// Only valid in an application running under Oak Restricted Kernel, which
+/// maps the page at `VDSO_ADDR`.
+pub unsafe fn read() -> VdsoSnapshot {
+    (*(VDSO_ADDR as *const VdsoData)).read()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    const FREQUENCY_HZ: u64 = 2_000_000_000;
+
+    #[test]
+    fn reader_observes_update() {
+        let data = VdsoData::new();
+        assert_eq!(data.read(), VdsoSnapshot::default());
+
+        data.update(4_000_000_000, FREQUENCY_HZ, &[7; VDSO_RANDOM_WORDS]);
+        let snapshot = data.read();
+        assert_eq!(snapshot.updates, 1);
+        assert_eq!(snapshot.tsc, 4_000_000_000);
+        assert_eq!(snapshot.time_ns, 2_000_000_000);
+        assert_eq!(snapshot.random, [7; VDSO_RANDOM_WORDS]);
+        assert_eq!(snapshot.time_ns_at(4_000_000_002), Some(2_000_000_001));
+        assert_eq!(snapshot.time_ns_at(3_000_000_000), None);
+    }
+
+    #[test]
+    fn unknown_frequency() {
+        let data = VdsoData::new();
+        data.update(1_000, 0, &[0; VDSO_RANDOM_WORDS]);
+        assert_eq!(data.read().time_ns_at(2_000), None);
+    }
+
+    #[test]
+    fn concurrent_reader_sees_monotonic_consistent_snapshots() {
+        let data = Arc::new(VdsoData::new());
+        let writer = {
+            let data = data.clone();
+            thread::spawn(move || {
+                for tsc in 1..=10_000u64 {
+                    data.update(tsc * 1_000, FREQUENCY_HZ, &[tsc; VDSO_RANDOM_WORDS]);
+                }
+            })
+        };
+
+        let mut last = VdsoSnapshot::default();
+        while last.updates < 10_000 {
+            let snapshot = data.read();
+            assert!(snapshot.time_ns >= last.time_ns);
+            assert!(snapshot.updates >= last.updates);
+            // All fields come from the same update.
+            assert_eq!(snapshot.tsc, snapshot.updates * 1_000);
+            assert_eq!(snapshot.random, [snapshot.updates; VDSO_RANDOM_WORDS]);
+            last = snapshot;
+        }
+        writer.join().unwrap();
+    }
+}
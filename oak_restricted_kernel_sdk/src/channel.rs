@@ -21,18 +21,20 @@ use alloc::boxed::Box;
 
 use anyhow::{anyhow, Context};
 use oak_channel::Channel;
-pub use oak_channel::{Read, Write};
+pub use oak_channel::{PeerClosed, Read, Write};
 use oak_core::samplestore::SampleStore;
 use oak_restricted_kernel_interface::OAK_CHANNEL_FD;
 
 /// Channel that communicates over a file descriptor.
 pub struct FileDescriptorChannel {
     fd: i32,
+    /// Whether a read has hit the end of the file.
+    closed: bool,
 }
 
 impl FileDescriptorChannel {
     pub fn new(fd: i32) -> Self {
-        Self { fd }
+        Self { fd, closed: false }
     }
 }
 
@@ -50,15 +52,28 @@ impl Read for FileDescriptorChannel {
         let mut remaining = data.len();
 
         while remaining > 0 {
-            remaining -= oak_restricted_kernel_interface::syscall::read(
+            let read = oak_restricted_kernel_interface::syscall::read(
                 self.fd,
                 &mut data[len - remaining..],
             )
             .map_err(|err| anyhow!("read failure: {}", err))?;
+            // Reading nothing means the peer has closed the channel.
+            if read == 0 {
+                self.closed = true;
+                if remaining < len {
+                    anyhow::bail!("peer closed the channel during read; partial data discarded");
+                }
+                return Err(anyhow::Error::msg(PeerClosed));
+            }
+            remaining -= read;
         }
 
         Ok(())
     }
+
+    fn peer_closed(&self) -> bool {
+        self.closed
+    }
 }
 
 impl Write for FileDescriptorChannel {
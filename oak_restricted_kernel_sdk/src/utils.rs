@@ -16,12 +16,15 @@
 
 //! Various utilities like loggers, allocators, timers, etc.
 
-use core::fmt::Write;
+use core::{fmt::Write, time::Duration};
 
 pub use log;
 pub use oak_core::*;
 pub use oak_enclave_runtime_support::heap;
-use oak_restricted_kernel_interface::syscall::{fsync, write};
+use oak_restricted_kernel_interface::{
+    syscall::{fsync, write},
+    vdso,
+};
 
 pub struct Stderr {}
 
@@ -65,6 +68,18 @@ impl log::Log for StderrLogger {
     }
 }
 
+/// Returns the time since boot.
+///
+/// The time is read from the page the kernel shares with the application and
+/// extrapolated using the TSC, so it doesn't cost a system call. Returns `None`
+/// if the kernel doesn't know the TSC frequency.
+pub fn time_since_boot() -> Option<Duration> {
+    // Safety: enclave apps run under Oak Restricted Kernel, which maps the page.
+    let snapshot = unsafe { vdso::read() };
+    // Read the TSC after the snapshot, so that it's never behind the snapshot.
+    snapshot.time_ns_at(oak_core::timer::rdtsc()).map(Duration::from_nanos)
+}
+
 /// Provides a default implementation for [`alloc_error_handler`] attribute.
 ///
 /// This handler is declared implicitly when using the [`crate::entrypoint`]
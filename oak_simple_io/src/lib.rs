@@ -52,6 +52,31 @@ pub const OUTPUT_BUFFER_LENGTH: usize = 4096;
 /// The length of the buffer that will be used for input messages.
 pub const INPUT_BUFFER_LENGTH: usize = 4096;
 
+/// Value the host reports on the input length port once it has closed the
+/// channel, meaning that no more input will follow.
+pub const CLOSED_SENTINEL: u32 = u32::MAX;
+
+/// What the host reported on the input length port.
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+    /// There is no input available at the moment.
+    Empty,
+    /// The input buffer holds this many bytes.
+    Bytes(usize),
+    /// The host has closed the channel.
+    Closed,
+}
+
+impl Input {
+    fn from_length(length: u32) -> Self {
+        match length {
+            0 => Self::Empty,
+            CLOSED_SENTINEL => Self::Closed,
+            length => Self::Bytes(length as usize),
+        }
+    }
+}
+
 // TODO(#3394): Move to a shared crate.
 /// Memory address translation function.
 pub trait Translator: Fn(VirtAddr) -> Option<PhysAddr> {}
@@ -63,6 +88,8 @@ pub struct SimpleIo<'a, A: Allocator> {
     input_buffer: Vec<u8, &'a A>,
     output_length_port: PortWrapper<u32>,
     input_length_port: PortWrapper<u32>,
+    /// Whether the host has sent `CLOSED_SENTINEL`.
+    closed: bool,
 }
 
 impl<'a, A: Allocator> SimpleIo<'a, A> {
@@ -95,7 +122,13 @@ impl<'a, A: Allocator> SimpleIo<'a, A> {
             input.buffer_lsb_port,
         )?;
 
-        Ok(Self { output_buffer, input_buffer, output_length_port, input_length_port })
+        Ok(Self {
+            output_buffer,
+            input_buffer,
+            output_length_port,
+            input_length_port,
+            closed: false,
+        })
     }
 
     pub fn new_with_defaults<VP: Translator>(
@@ -114,17 +147,30 @@ impl<'a, A: Allocator> SimpleIo<'a, A> {
 
     /// Reads the next available bytes from the input buffer, if any are
     /// available.
+    ///
+    /// Always returns `None` once the host has closed the channel.
     pub fn read_bytes(&mut self) -> Option<VecDeque<u8>> {
+        if self.closed {
+            return None;
+        }
+
         // Safety: we read the value as a u32 and validate it before using it.
-        let length = unsafe { self.input_length_port.try_read().ok()? } as usize;
+        let input = Input::from_length(unsafe { self.input_length_port.try_read().ok()? });
 
         // Use a memory fence to ensure the read from the device happens before the read
-        // from the buffer.
+        // from the buffer. The host fills the buffer before it completes the port
+        // read, so this pairs with the port access itself; the fence mostly stops
+        // the compiler from reading the buffer early.
         core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
 
-        if length == 0 {
-            return None;
-        }
+        let length = match input {
+            Input::Empty => return None,
+            Input::Closed => {
+                self.closed = true;
+                return None;
+            }
+            Input::Bytes(length) => length,
+        };
 
         // A length larger than the buffer size indicates a corrupt or malicious VMM
         // device implementation. This is probably not recoverable, so panic.
@@ -135,6 +181,11 @@ impl<'a, A: Allocator> SimpleIo<'a, A> {
         Some(result)
     }
 
+    /// Whether the host has closed the channel.
+    pub fn peer_closed(&self) -> bool {
+        self.closed
+    }
+
     /// Writes the data to the output buffer and notifies the host.
     ///
     /// Returns the number of bytes written, if any.
@@ -147,7 +198,7 @@ impl<'a, A: Allocator> SimpleIo<'a, A> {
         self.output_buffer[..length].copy_from_slice(&data[..length]);
 
         // Use a memory fence to ensure that the data is written to the buffer before we
-        // notify the VMM.
+        // notify the VMM, which reads the buffer as soon as it handles the port write.
         core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
 
         // Safety: this usage is safe, as we as only write an uninterpreted u32 value to
@@ -186,3 +237,25 @@ fn write_address(
         io_port_factory.new_writer(lsb_port).try_write(address_lsb)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_from_length() {
+        assert_eq!(Input::from_length(0), Input::Empty);
+        assert_eq!(Input::from_length(17), Input::Bytes(17));
+        assert_eq!(
+            Input::from_length(INPUT_BUFFER_LENGTH as u32),
+            Input::Bytes(INPUT_BUFFER_LENGTH)
+        );
+        assert_eq!(Input::from_length(CLOSED_SENTINEL), Input::Closed);
+        // Other out of range lengths are still reported as such, and rejected when the
+        // buffer is read.
+        assert_eq!(
+            Input::from_length(CLOSED_SENTINEL - 1),
+            Input::Bytes(CLOSED_SENTINEL as usize - 1)
+        );
+    }
+}
@@ -19,14 +19,14 @@ use core::alloc::Allocator;
 
 use anyhow::Context;
 use rust_hypervisor_firmware_virtio::{
-    device::VirtioBaseDevice,
+    device::{VirtioBaseDevice, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_PACKED},
     pci::{find_device, VirtioPciTransport},
     virtio::VirtioTransport,
 };
 
 use crate::{
     queue::{DeviceWriteOnlyQueue, DriverWriteOnlyQueue},
-    InverseTranslator, Read, Translator, Write,
+    InverseTranslator, PeerClosed, Read, Translator, Write,
 };
 
 /// The number of buffer descriptors in each of the queues.
@@ -51,6 +51,16 @@ const DEVICE_ID: u16 = 3;
 /// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1020002>.
 const PCI_DEVICE_ID: u16 = 0x1040 + DEVICE_ID;
 
+/// Device status bit indicating that the driver is set up and the device is
+/// live.
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+/// Device status bit the device sets if it has stopped working and has to be
+/// reset.
+///
+/// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-100001>.
+const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
 /// Simple driver implementation for a virtio serial/console device that only
 /// supports a single port and no configuration.
 ///
@@ -145,10 +155,23 @@ where
         translate: VP,
         inverse: PV,
     ) -> anyhow::Result<()> {
-        self.device
-            .start_init(DEVICE_ID as u32, inverse)
+        let features = self
+            .device
+            .start_init_with_features(
+                DEVICE_ID as u32,
+                inverse,
+                VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_RING_PACKED,
+            )
             .map_err(|error| anyhow::anyhow!("virtio error: {:?}", error))
             .context("couldn't initialize the PCI device")?;
+        if features & VIRTIO_F_RING_EVENT_IDX != 0 {
+            self.rx_queue.inner.enable_event_idx();
+            self.tx_queue.inner.enable_event_idx();
+        }
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            self.rx_queue.inner.enable_packed_ring();
+            self.tx_queue.inner.enable_packed_ring();
+        }
         self.device
             .configure_queue(
                 RX_QUEUE_ID,
@@ -225,11 +248,30 @@ where
         let len = data.len();
         let mut count = 0;
         while count < len {
-            count += self.read_partial(&mut data[count..]).unwrap_or(0);
+            match self.read_partial(&mut data[count..]) {
+                Some(read) => count += read,
+                // We only ask the device about its state if it has nothing for us, so
+                // that data received before the port went away is still delivered.
+                None if self.peer_closed() => {
+                    if count > 0 {
+                        anyhow::bail!("console port went away during read; partial data discarded");
+                    }
+                    return Err(anyhow::Error::msg(PeerClosed));
+                }
+                None => {}
+            }
         }
 
         Ok(())
     }
+
+    /// As we only support a single port, the port going away shows up as the
+    /// device no longer being live: either it was reset (or unplugged, which
+    /// makes the status read as all ones), or it needs to be reset.
+    fn peer_closed(&self) -> bool {
+        let status = self.get_status();
+        status & VIRTIO_STATUS_DRIVER_OK == 0 || status & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0
+    }
 }
 
 impl<'a, T, A: Allocator> Write for Console<'a, T, A>
@@ -252,6 +294,11 @@ where
         // write, and provide an actual flush implementation here.
         Ok(())
     }
+
+    /// Writes of up to one buffer go out in a single descriptor.
+    fn max_write_size(&self) -> usize {
+        DATA_BUFFER_SIZE
+    }
 }
 
 #[cfg(test)]
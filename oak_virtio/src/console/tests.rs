@@ -66,6 +66,26 @@ fn test_device_init() {
     }
 }
 
+#[test]
+fn test_event_idx_negotiated() {
+    let transport = new_valid_transport();
+    transport.config.lock().unwrap().features |= VIRTIO_F_RING_EVENT_IDX;
+    let config = transport.config.clone();
+    let device = VirtioBaseDevice::new(transport);
+    let mut console = Console::new(device, identity_map, &Global);
+    console.init(identity_map, inverse_identity_map).unwrap();
+    assert_eq!(config.lock().unwrap().features, VIRTIO_F_VERSION_1 | VIRTIO_F_RING_EVENT_IDX);
+
+    // The device's `avail_event` is 0, so it wants to be notified about the first
+    // buffer only.
+    let tx_notified = || config.lock().unwrap().queues.get(&TX_QUEUE_ID).unwrap().notified;
+    assert_eq!(console.write_bytes(&[1]), Some(1));
+    assert!(tx_notified());
+    config.lock().unwrap().queues.get_mut(&TX_QUEUE_ID).unwrap().notified = false;
+    assert_eq!(console.write_bytes(&[2]), Some(1));
+    assert!(!tx_notified());
+}
+
 #[test]
 fn test_read_bytes() {
     let data = vec![2, 4, 6];
@@ -123,3 +143,49 @@ fn test_write_all() {
     assert_eq!(&data[..DATA_BUFFER_SIZE], &first[..]);
     assert_eq!(&data[DATA_BUFFER_SIZE..], &second[..]);
 }
+
+#[test]
+fn test_max_write_size() {
+    let data = vec![13; DATA_BUFFER_SIZE];
+    let transport = new_valid_transport();
+    let device = VirtioBaseDevice::new(transport.clone());
+    let mut console = Console::new(device, identity_map, &Global);
+    console.init(identity_map, inverse_identity_map).unwrap();
+    assert_eq!(console.max_write_size(), DATA_BUFFER_SIZE);
+    // A write of the maximum size fits into a single buffer.
+    assert!(console.write_all(&data[..]).is_ok());
+    let bytes = transport.device_read_once_from_queue::<QUEUE_SIZE>(1).unwrap();
+    assert_eq!(data, bytes);
+    assert!(transport.device_read_once_from_queue::<QUEUE_SIZE>(1).is_none());
+}
+
+#[test]
+fn test_read_exact_after_device_reset() {
+    let transport = new_valid_transport();
+    let device = VirtioBaseDevice::new(transport.clone());
+    let mut console = Console::new(device, identity_map, &Global);
+    console.init(identity_map, inverse_identity_map).unwrap();
+    assert!(!console.peer_closed());
+    let data = [5; 4];
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, &data[..]);
+    // The host resets the device, e.g. because the port was unplugged.
+    transport.config.lock().unwrap().status = 0;
+    assert!(console.peer_closed());
+
+    // Data that arrived before the port went away is still delivered.
+    let mut buffer = [0; 4];
+    assert!(console.read_exact(&mut buffer).is_ok());
+    assert_eq!(data, buffer);
+    assert!(console.read_exact(&mut buffer).unwrap_err().is::<PeerClosed>());
+}
+
+#[test]
+fn test_read_exact_when_device_needs_reset() {
+    let transport = new_valid_transport();
+    let device = VirtioBaseDevice::new(transport.clone());
+    let mut console = Console::new(device, identity_map, &Global);
+    console.init(identity_map, inverse_identity_map).unwrap();
+    transport.config.lock().unwrap().status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+    let mut buffer = [0; 4];
+    assert!(console.read_exact(&mut buffer).unwrap_err().is::<PeerClosed>());
+}
@@ -41,7 +41,28 @@ pub mod vsock;
 /// is pared down to a minimum and works in a `no_std` environment.
 pub trait Read {
     /// Read bytes until `data` has been filled.
+    ///
+    /// Fails with [`PeerClosed`] if the peer went away before any of the data
+    /// arrived.
     fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()>;
+
+    /// Whether the peer has gone away, so no more data will arrive.
+    ///
+    /// Defaults to `false`, for implementations that can't detect this.
+    fn peer_closed(&self) -> bool {
+        false
+    }
+}
+
+/// Error returned by [`Read::read_exact`] if the peer went away before any of
+/// the requested data arrived.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerClosed;
+
+impl core::fmt::Display for PeerClosed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("the peer closed the connection")
+    }
 }
 
 /// Write bytes to a source.
@@ -54,6 +75,36 @@ pub trait Write {
 
     /// Flush any output buffers, if they exist.
     fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// The maximum number of bytes that can be written in a single call to
+    /// `write_all`.
+    ///
+    /// This is a limit of the transport, such as the size of a shared buffer or
+    /// of the peer's receive buffer, not the space currently available:
+    /// flow-controlled implementations (e.g. vsock) wait for the peer instead.
+    /// Defaults to `usize::MAX`, for implementations that don't have a limit.
+    fn max_write_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Performs a device-specific operation, identified by `request`.
+    ///
+    /// Defaults to rejecting all requests, for devices that don't have any such
+    /// operations.
+    fn control(&mut self, _request: u64, _arg: usize) -> Result<usize, ControlError> {
+        Err(ControlError::Unsupported)
+    }
+}
+
+/// Reasons why a device-specific operation failed; see [`Write::control`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlError {
+    /// The device doesn't know about the request.
+    Unsupported,
+    /// The request is known, but the argument is not valid for it.
+    InvalidArgument,
+    /// The request was valid, but the device failed to carry it out.
+    Io,
 }
 
 /// The vendor ID for virtio PCI devices.
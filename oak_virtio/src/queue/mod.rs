@@ -15,13 +15,15 @@
 //
 
 use alloc::{boxed::Box, collections::vec_deque::VecDeque, vec::Vec};
-use core::{alloc::Allocator, num::Wrapping};
+use core::{alloc::Allocator, num::Wrapping, ptr};
 
+use packed::PackedRing;
 use virtq::{AvailRing, Desc, DescFlags, RingFlags, UsedElem, UsedRing, VirtQueue};
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::Translator;
 
+pub mod packed;
 pub mod virtq;
 
 /// A queue where the descriptor buffers are only writable by the driver.
@@ -138,6 +140,23 @@ pub struct Queue<'a, const QUEUE_SIZE: usize, const BUFFER_SIZE: usize, A: Alloc
 
     /// The last index that was used when popping elements from the used ring.
     last_used_idx: Wrapping<u16>,
+
+    /// Whether VIRTIO_F_RING_EVENT_IDX has been negotiated for the device.
+    event_idx: bool,
+
+    /// The value of the available ring index the last time we checked whether
+    /// the device must be notified.
+    notified_avail_idx: Wrapping<u16>,
+
+    /// The packed ring shared with the device, if VIRTIO_F_RING_PACKED has been
+    /// negotiated.
+    ///
+    /// The descriptor table of `virt_queue` still keeps track of the buffer of
+    /// each descriptor, but its rings are not used.
+    packed: Option<PackedRing<'a, QUEUE_SIZE, A>>,
+
+    /// The allocator used for the rings and buffers.
+    alloc: &'a A,
 }
 
 impl<'a, const QUEUE_SIZE: usize, const BUFFER_SIZE: usize, A: Allocator>
@@ -163,29 +182,103 @@ impl<'a, const QUEUE_SIZE: usize, const BUFFER_SIZE: usize, A: Allocator>
             },
             alloc,
         );
-        Self { virt_queue, buffer, base_offset, last_used_idx: Wrapping(0) }
+        Self {
+            virt_queue,
+            buffer,
+            base_offset,
+            last_used_idx: Wrapping(0),
+            event_idx: false,
+            notified_avail_idx: Wrapping(0),
+            packed: None,
+            alloc,
+        }
+    }
+
+    /// Switches the queue to the packed ring layout.
+    ///
+    /// Must only be called if VIRTIO_F_RING_PACKED has been negotiated, and
+    /// before the queue is configured on the device. Descriptors that were
+    /// already made available are moved over to the packed ring.
+    pub fn enable_packed_ring(&mut self) {
+        let mut packed = PackedRing::new(self.alloc);
+        while self.last_used_idx != self.virt_queue.avail.idx {
+            let id = self.virt_queue.avail.ring[self.last_used_idx.0 as usize % QUEUE_SIZE];
+            let desc = &self.virt_queue.desc[id as usize];
+            packed.add_available(
+                id,
+                desc.addr,
+                desc.length,
+                desc.flags.contains(DescFlags::VIRTQ_DESC_F_WRITE),
+            );
+            self.last_used_idx += 1;
+        }
+        self.packed = Some(packed);
     }
 
-    /// Gets the address of the descriptor table.
+    /// Uses the device's `avail_event` index rather than the `NO_NOTIFY` flag
+    /// to decide whether the device must be notified.
+    ///
+    /// Must only be called if VIRTIO_F_RING_EVENT_IDX has been negotiated, and
+    /// before the device is first notified about the queue.
+    pub fn enable_event_idx(&mut self) {
+        self.event_idx = true;
+        self.notified_avail_idx = self.virt_queue.avail.idx;
+    }
+
+    /// Gets the address of the descriptor table (or of the descriptor ring,
+    /// for a packed queue).
     pub fn get_desc_addr(&self) -> VirtAddr {
-        VirtAddr::from_ptr(self.virt_queue.desc.as_ptr())
+        match self.packed {
+            Some(ref packed) => packed.get_desc_addr(),
+            None => VirtAddr::from_ptr(self.virt_queue.desc.as_ptr()),
+        }
     }
 
-    /// Gets the address of the available ring.
+    /// Gets the address of the available ring (or of the driver event
+    /// suppression structure, for a packed queue).
     pub fn get_avail_addr(&self) -> VirtAddr {
-        VirtAddr::from_ptr(&self.virt_queue.avail as *const _)
+        match self.packed {
+            Some(ref packed) => packed.get_driver_event_addr(),
+            None => VirtAddr::from_ptr(&self.virt_queue.avail as *const _),
+        }
     }
 
-    /// Gets the address of the used ring.
+    /// Gets the address of the used ring (or of the device event suppression
+    /// structure, for a packed queue).
     pub fn get_used_addr(&self) -> VirtAddr {
-        VirtAddr::from_ptr(&self.virt_queue.used as *const _)
+        match self.packed {
+            Some(ref packed) => packed.get_device_event_addr(),
+            None => VirtAddr::from_ptr(&self.virt_queue.used as *const _),
+        }
     }
 
     /// Checks whether the device wants to be notified of queue changes.
-    pub fn must_notify_device(&self) -> bool {
-        // Memory fence so that we read a fresh value from the device-owned section.
-        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
-        !self.virt_queue.used.flags.contains(RingFlags::NO_NOTIFY)
+    ///
+    /// With VIRTIO_F_RING_EVENT_IDX, this only returns true if the device's
+    /// `avail_event` index was passed by the descriptors made available since
+    /// the last call, so callers should check once per batch of descriptors.
+    pub fn must_notify_device(&mut self) -> bool {
+        if let Some(ref packed) = self.packed {
+            return packed.must_notify_device();
+        }
+        // Memory fence so that the device sees our available ring updates before we
+        // read a fresh value from the device-owned section. This is a store-load
+        // ordering, which only a full fence provides; it pairs with the device's
+        // fence between updating the notification fields and checking the available
+        // index.
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        if self.event_idx {
+            let new = self.virt_queue.avail.idx;
+            let old = core::mem::replace(&mut self.notified_avail_idx, new);
+            // Safety: the reference is valid; the read is volatile as the device may
+            // change the value at any time.
+            let avail_event = unsafe { ptr::read_volatile(&self.virt_queue.used.avail_event) };
+            need_event(Wrapping(avail_event), new, old)
+        } else {
+            // Safety: as above.
+            let flags = unsafe { ptr::read_volatile(&self.virt_queue.used.flags) };
+            !flags.contains(RingFlags::NO_NOTIFY)
+        }
     }
 
     /// Gets a mutable slice into the buffer based on the index and length.
@@ -201,30 +294,60 @@ impl<'a, const QUEUE_SIZE: usize, const BUFFER_SIZE: usize, A: Allocator>
     /// If an unseen used descriptor is found, this also advances the last used
     /// index by one.
     fn get_next_used_element(&mut self) -> Option<UsedElem> {
-        // Memory fence so that we read a fresh value from the device-owned section.
-        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        if let Some(ref mut packed) = self.packed {
+            return packed.pop_used();
+        }
         let next_used = self.last_used_idx;
-        if next_used == self.virt_queue.used.idx {
-            None
-        } else {
-            self.last_used_idx += 1;
-            Some(self.virt_queue.used.ring[next_used.0 as usize % QUEUE_SIZE])
+        // Safety: the reference is valid; the read is volatile so that the compiler
+        // doesn't reuse a stale value, as the device may change it at any time.
+        let used_idx = unsafe { ptr::read_volatile(&self.virt_queue.used.idx) };
+        if next_used == used_idx {
+            return None;
         }
+        // Memory fence so that neither the used ring entry nor the buffer it refers to
+        // are read before the index. This pairs with the device's write barrier
+        // between writing the entry and incrementing the index.
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        self.last_used_idx += 1;
+        // Safety: as above.
+        Some(unsafe {
+            ptr::read_volatile(&self.virt_queue.used.ring[next_used.0 as usize % QUEUE_SIZE])
+        })
     }
 
     /// Adds a descriptor to the available ring.
     fn add_available_descriptor(&mut self, index: u16) {
+        if let Some(ref mut packed) = self.packed {
+            let desc = &self.virt_queue.desc[index as usize];
+            packed.add_available(
+                index,
+                desc.addr,
+                desc.length,
+                desc.flags.contains(DescFlags::VIRTQ_DESC_F_WRITE),
+            );
+            return;
+        }
         // Add the descriptor index to the available ring at the next location.
         self.virt_queue.avail.ring[self.virt_queue.avail.idx.0 as usize % QUEUE_SIZE] = index;
         // Increment the available ring index to use next time.
         let next = self.virt_queue.avail.idx + Wrapping(1);
         let idx = &mut self.virt_queue.avail.idx;
         // Memory fence to ensure the device will not see the index update before the
-        // available ring entry update.
+        // available ring entry update (or the buffer contents). This pairs with the
+        // device's read barrier between reading the index and reading the entry.
         core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
-        *idx = next;
+        // Safety: the reference is valid; the write is volatile so that the compiler
+        // can't elide it, as only the device ever reads the index.
+        unsafe { ptr::write_volatile(idx, next) };
     }
 }
 
+/// Checks whether moving an index from `old` to `new` passed `event`.
+///
+/// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-440008>.
+fn need_event(event: Wrapping<u16>, new: Wrapping<u16>, old: Wrapping<u16>) -> bool {
+    new - event - Wrapping(1) < new - old
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,233 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Packed virtqueue layout.
+//!
+//! Instead of a descriptor table with separate available and used rings, a
+//! packed virtqueue has a single ring of descriptors that both the driver and
+//! the device write to. Which side owns a descriptor is tracked with the
+//! `AVAIL` and `USED` flags, relative to a wrap counter on each side that flips
+//! every time the side wraps around the ring.
+//!
+//! See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-610007>.
+
+use alloc::boxed::Box;
+use core::alloc::Allocator;
+
+use bitflags::bitflags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::virtq::UsedElem;
+
+bitflags! {
+    /// Flags about a packed descriptor.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PackedDescFlags: u16 {
+        /// This marks a buffer as device write-only (otherwise device read-only).
+        const VIRTQ_DESC_F_WRITE = 2;
+        /// Set by the driver to the value of its wrap counter when making the
+        /// descriptor available.
+        const VIRTQ_DESC_F_AVAIL = 1 << 7;
+        /// Set by the driver to the inverse of its wrap counter when making the
+        /// descriptor available, and by the device to the value of its wrap
+        /// counter when marking the descriptor as used.
+        const VIRTQ_DESC_F_USED = 1 << 15;
+    }
+}
+
+/// A descriptor in a packed virtqueue.
+///
+/// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-720008>.
+#[repr(C, align(16))]
+#[derive(Debug)]
+pub struct PackedDesc {
+    /// The guest-physical address of the buffer.
+    pub addr: PhysAddr,
+    /// The length of the buffer, or, once used, the number of bytes the device
+    /// wrote to it.
+    pub length: u32,
+    /// The buffer id, which the device copies to the used descriptor.
+    pub id: u16,
+    /// Flags providing more info about this descriptor.
+    pub flags: PackedDescFlags,
+}
+
+impl Default for PackedDesc {
+    fn default() -> Self {
+        Self { addr: PhysAddr::zero(), length: 0, id: 0, flags: PackedDescFlags::empty() }
+    }
+}
+
+/// Values of `EventSuppress::flags`.
+pub const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+pub const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+pub const RING_EVENT_FLAGS_DESC: u16 = 2;
+
+/// An event suppression structure, which tells the other side whether (and
+/// when) to send notifications.
+///
+/// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-720008>.
+#[repr(C, align(4))]
+#[derive(Debug)]
+pub struct EventSuppress {
+    /// Descriptor ring offset and wrap counter to notify at; only used with
+    /// `RING_EVENT_FLAGS_DESC`.
+    pub off_wrap: u16,
+    /// One of the `RING_EVENT_FLAGS_*` values.
+    pub flags: u16,
+}
+
+/// The memory shared with the device for a packed virtqueue.
+#[repr(C, align(64))]
+pub struct PackedVirtQueue<const QUEUE_SIZE: usize> {
+    /// The descriptor ring.
+    pub desc: [PackedDesc; QUEUE_SIZE],
+    /// Event suppression controlled by the driver.
+    pub driver_event: EventSuppress,
+    /// Event suppression controlled by the device.
+    pub device_event: EventSuppress,
+}
+
+/// The driver side of a packed virtqueue.
+///
+/// Every buffer is described by a single descriptor, as we don't support
+/// chaining.
+pub struct PackedRing<'a, const QUEUE_SIZE: usize, A: Allocator> {
+    /// The ring shared with the device.
+    pub ring: Box<PackedVirtQueue<QUEUE_SIZE>, &'a A>,
+    /// The position at which the next descriptor will be made available.
+    next_avail: usize,
+    /// The driver's wrap counter for making descriptors available.
+    avail_wrap_counter: bool,
+    /// The position of the next descriptor we expect the device to use.
+    next_used: usize,
+    /// The wrap counter the device will use when marking the descriptor at
+    /// `next_used` as used.
+    used_wrap_counter: bool,
+}
+
+impl<'a, const QUEUE_SIZE: usize, A: Allocator> PackedRing<'a, QUEUE_SIZE, A> {
+    pub fn new(alloc: &'a A) -> Self {
+        let ring = Box::new_in(
+            PackedVirtQueue {
+                desc: [(); QUEUE_SIZE].map(|_| PackedDesc::default()),
+                // We implement all drivers via polling, so don't want notifications.
+                driver_event: EventSuppress { off_wrap: 0, flags: RING_EVENT_FLAGS_DISABLE },
+                device_event: EventSuppress { off_wrap: 0, flags: RING_EVENT_FLAGS_ENABLE },
+            },
+            alloc,
+        );
+        // Both wrap counters start at 1.
+        Self {
+            ring,
+            next_avail: 0,
+            avail_wrap_counter: true,
+            next_used: 0,
+            used_wrap_counter: true,
+        }
+    }
+
+    /// Gets the address of the descriptor ring.
+    pub fn get_desc_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ring.desc.as_ptr())
+    }
+
+    /// Gets the address of the driver event suppression structure.
+    pub fn get_driver_event_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(&self.ring.driver_event as *const _)
+    }
+
+    /// Gets the address of the device event suppression structure.
+    pub fn get_device_event_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(&self.ring.device_event as *const _)
+    }
+
+    /// Makes a buffer available to the device.
+    ///
+    /// The caller must make sure that no more than `QUEUE_SIZE` buffers are
+    /// outstanding at any time.
+    pub fn add_available(&mut self, id: u16, addr: PhysAddr, length: u32, write: bool) {
+        let mut flags = if self.avail_wrap_counter {
+            PackedDescFlags::VIRTQ_DESC_F_AVAIL
+        } else {
+            PackedDescFlags::VIRTQ_DESC_F_USED
+        };
+        flags.set(PackedDescFlags::VIRTQ_DESC_F_WRITE, write);
+
+        let desc = &mut self.ring.desc[self.next_avail];
+        desc.addr = addr;
+        desc.length = length;
+        desc.id = id;
+        // Memory fence to ensure the device will not see the flags update (which hands
+        // the descriptor over) before the rest of the descriptor. This pairs with the
+        // device's read barrier between reading the flags and the rest of the
+        // descriptor.
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        // Safety: the reference is valid; the write is volatile so that the compiler
+        // can't elide or reorder it, as only the device reads it.
+        unsafe { core::ptr::write_volatile(&mut desc.flags, flags) };
+
+        self.next_avail += 1;
+        if self.next_avail == QUEUE_SIZE {
+            self.next_avail = 0;
+            self.avail_wrap_counter = !self.avail_wrap_counter;
+        }
+    }
+
+    /// Tries to get the next buffer the device has used, if any.
+    pub fn pop_used(&mut self) -> Option<UsedElem> {
+        let desc = &self.ring.desc[self.next_used];
+        // Safety: the reference is valid; the read is volatile so that the compiler
+        // doesn't reuse a stale value, as the device may change it at any time.
+        let flags = unsafe { core::ptr::read_volatile(&desc.flags) };
+        // A descriptor is used once both flags match the device's wrap counter.
+        let avail = flags.contains(PackedDescFlags::VIRTQ_DESC_F_AVAIL);
+        let used = flags.contains(PackedDescFlags::VIRTQ_DESC_F_USED);
+        if avail != used || used != self.used_wrap_counter {
+            return None;
+        }
+        // Memory fence so that we don't read the rest of the descriptor (or the buffer
+        // it refers to) before the flags. This pairs with the device's write barrier
+        // between writing the descriptor and its flags.
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        // Safety: as above.
+        let (id, len) =
+            unsafe { (core::ptr::read_volatile(&desc.id), core::ptr::read_volatile(&desc.length)) };
+        let element = UsedElem { id: id as u32, len };
+
+        self.next_used += 1;
+        if self.next_used == QUEUE_SIZE {
+            self.next_used = 0;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+        Some(element)
+    }
+
+    /// Checks whether the device wants to be notified of queue changes.
+    ///
+    /// If the device asks to be notified at a specific descriptor we notify it
+    /// every time, which is always allowed.
+    pub fn must_notify_device(&self) -> bool {
+        // Memory fence so that the device sees our descriptor updates before we read a
+        // fresh value from the device-owned section. This is a store-load ordering,
+        // which only a full fence provides.
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        // Safety: the reference is valid; the read is volatile as the device may change
+        // the value at any time.
+        unsafe { core::ptr::read_volatile(&self.ring.device_event.flags) }
+            != RING_EVENT_FLAGS_DISABLE
+    }
+}
@@ -16,7 +16,10 @@
 
 use alloc::{alloc::Global, vec};
 
-use super::*;
+use super::{
+    packed::{PackedDesc, PackedDescFlags, PackedRing, RING_EVENT_FLAGS_DISABLE},
+    *,
+};
 use crate::test::identity_map;
 
 const QUEUE_SIZE: usize = 4;
@@ -197,6 +200,139 @@ fn test_many_echos() {
     }
 }
 
+#[test]
+fn test_notify_without_event_idx() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.write_buffer(&[0]).unwrap();
+    assert!(queue.inner.must_notify_device());
+
+    queue.inner.virt_queue.used.flags = RingFlags::NO_NOTIFY;
+    queue.write_buffer(&[0]).unwrap();
+    assert!(!queue.inner.must_notify_device());
+}
+
+#[test]
+fn test_notify_with_event_idx() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.inner.enable_event_idx();
+    // The flag must be ignored once event indices are in use.
+    queue.inner.virt_queue.used.flags = RingFlags::NO_NOTIFY;
+
+    // The device wants to be notified once descriptor 0 has been made available.
+    queue.inner.virt_queue.used.avail_event = 0;
+    queue.write_buffer(&[0]).unwrap();
+    assert!(queue.inner.must_notify_device());
+
+    // The device hasn't updated `avail_event`, so it's still draining the queue and
+    // doesn't need another kick.
+    queue.write_buffer(&[1]).unwrap();
+    assert!(!queue.inner.must_notify_device());
+
+    // The device asks to be notified after descriptor 3; adding descriptor 2 isn't
+    // enough, but a batch that includes 3 is.
+    queue.inner.virt_queue.used.avail_event = 3;
+    queue.write_buffer(&[2]).unwrap();
+    assert!(!queue.inner.must_notify_device());
+    queue.write_buffer(&[3]).unwrap();
+    assert!(queue.inner.must_notify_device());
+}
+
+#[test]
+fn test_event_idx_wrapping() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.inner.virt_queue.avail.idx = Wrapping(u16::MAX);
+    queue.inner.virt_queue.used.idx = Wrapping(u16::MAX);
+    queue.inner.last_used_idx = Wrapping(u16::MAX);
+    queue.inner.enable_event_idx();
+
+    queue.inner.virt_queue.used.avail_event = u16::MAX;
+    queue.write_buffer(&[0]).unwrap();
+    queue.write_buffer(&[1]).unwrap();
+    assert_eq!(queue.inner.virt_queue.avail.idx.0, 1);
+    assert!(queue.inner.must_notify_device());
+}
+
+#[test]
+fn test_packed_write_wraps() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.inner.enable_packed_ring();
+    let mut device = PackedDevice::default();
+
+    // Go around the ring a few times, so that both wrap counters flip repeatedly.
+    for i in 0..(QUEUE_SIZE * 3) as u8 {
+        assert_eq!(Some(2), queue.write_buffer(&[i, i + 1]));
+        assert_eq!(Some(vec![i, i + 1]), device.read_once(queue.inner.packed.as_mut().unwrap()));
+        assert_eq!(None, device.read_once(queue.inner.packed.as_mut().unwrap()));
+    }
+    // The split rings are not used at all.
+    assert_eq!(queue.inner.virt_queue.avail.idx.0, 0);
+}
+
+#[test]
+fn test_packed_driver_queue_exhaustion() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.inner.enable_packed_ring();
+    let mut device = PackedDevice::default();
+
+    for i in 0..QUEUE_SIZE as u8 {
+        assert_eq!(Some(1), queue.write_buffer(&[i]));
+    }
+    assert_eq!(None, queue.write_buffer(&[0xff]));
+
+    // Once the device has used two buffers, we can write twice more, wrapping
+    // around the ring.
+    assert_eq!(Some(vec![0]), device.read_once(queue.inner.packed.as_mut().unwrap()));
+    assert_eq!(Some(vec![1]), device.read_once(queue.inner.packed.as_mut().unwrap()));
+    assert_eq!(Some(1), queue.write_buffer(&[4]));
+    assert_eq!(Some(1), queue.write_buffer(&[5]));
+    assert_eq!(None, queue.write_buffer(&[0xff]));
+    for i in 2..6 {
+        assert_eq!(Some(vec![i]), device.read_once(queue.inner.packed.as_mut().unwrap()));
+    }
+    assert_eq!(None, device.read_once(queue.inner.packed.as_mut().unwrap()));
+}
+
+#[test]
+fn test_packed_read_wraps() {
+    let mut queue =
+        DeviceWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    // All the descriptors were made available when the queue was created; they have
+    // to be moved to the packed ring.
+    queue.inner.enable_packed_ring();
+    let mut device = PackedDevice::default();
+
+    assert_eq!(None, queue.read_next_used_buffer());
+    for i in 0..(QUEUE_SIZE * 3) as u8 {
+        // Fill the queue, then drain it.
+        if i as usize % QUEUE_SIZE == 0 {
+            for j in 0..QUEUE_SIZE as u8 {
+                assert_eq!(Some(1), device.write(queue.inner.packed.as_mut().unwrap(), &[i + j]));
+            }
+            assert_eq!(None, device.write(queue.inner.packed.as_mut().unwrap(), &[0xff]));
+        }
+        assert_eq!(Some(vec![i]), queue.read_next_used_buffer());
+    }
+    assert_eq!(None, queue.read_next_used_buffer());
+}
+
+#[test]
+fn test_packed_notify() {
+    let mut queue =
+        DriverWriteOnlyQueue::<QUEUE_SIZE, BUFFER_SIZE, Global>::new(identity_map, &Global);
+    queue.inner.enable_packed_ring();
+    queue.write_buffer(&[0]).unwrap();
+    assert!(queue.inner.must_notify_device());
+
+    queue.inner.packed.as_mut().unwrap().ring.device_event.flags = RING_EVENT_FLAGS_DISABLE;
+    queue.write_buffer(&[1]).unwrap();
+    assert!(!queue.inner.must_notify_device());
+}
+
 fn device_read_once<const QUEUE_SIZE: usize>(
     virt_queue: &mut VirtQueue<QUEUE_SIZE>,
 ) -> Option<Vec<u8>> {
@@ -250,3 +386,85 @@ fn device_write<const QUEUE_SIZE: usize>(
     buffer.copy_from_slice(data);
     Some(len)
 }
+
+/// The device side of a packed virtqueue.
+struct PackedDevice {
+    /// The position of the next descriptor the device expects to be available.
+    next: usize,
+    /// The device's wrap counter.
+    wrap_counter: bool,
+}
+
+impl Default for PackedDevice {
+    fn default() -> Self {
+        Self { next: 0, wrap_counter: true }
+    }
+}
+
+impl PackedDevice {
+    /// Takes the next available descriptor, if there is one, and marks it as
+    /// used with the given length.
+    fn use_next<const QUEUE_SIZE: usize, A: Allocator>(
+        &mut self,
+        packed: &mut PackedRing<'_, QUEUE_SIZE, A>,
+        write: bool,
+        len: impl FnOnce(&PackedDesc) -> u32,
+    ) -> Option<(PhysAddr, u32)> {
+        let desc = &mut packed.ring.desc[self.next];
+        let avail = desc.flags.contains(PackedDescFlags::VIRTQ_DESC_F_AVAIL);
+        let used = desc.flags.contains(PackedDescFlags::VIRTQ_DESC_F_USED);
+        if avail != self.wrap_counter || used == self.wrap_counter {
+            return None;
+        }
+        assert_eq!(desc.flags.contains(PackedDescFlags::VIRTQ_DESC_F_WRITE), write);
+        let len = len(desc);
+        let result = (desc.addr, len);
+        desc.length = len;
+        desc.flags = if self.wrap_counter {
+            PackedDescFlags::VIRTQ_DESC_F_AVAIL | PackedDescFlags::VIRTQ_DESC_F_USED
+        } else {
+            PackedDescFlags::empty()
+        };
+
+        self.next += 1;
+        if self.next == QUEUE_SIZE {
+            self.next = 0;
+            self.wrap_counter = !self.wrap_counter;
+        }
+        Some(result)
+    }
+
+    fn read_once<const QUEUE_SIZE: usize, A: Allocator>(
+        &mut self,
+        packed: &mut PackedRing<'_, QUEUE_SIZE, A>,
+    ) -> Option<Vec<u8>> {
+        let mut length = 0;
+        let (addr, _) = self.use_next(packed, false, |desc| {
+            length = desc.length;
+            0
+        })?;
+        // Safety: we purposely use unsafe code to simulate the way the the device/VMM
+        // will interact with the memory. We treat the contents of the slice as data
+        // only and ensure we only pass valid addresses and sizes from the tests.
+        let buffer = unsafe {
+            alloc::slice::from_raw_parts(addr.as_u64() as usize as *const u8, length as usize)
+        };
+        Some(buffer.to_vec())
+    }
+
+    fn write<const QUEUE_SIZE: usize, A: Allocator>(
+        &mut self,
+        packed: &mut PackedRing<'_, QUEUE_SIZE, A>,
+        data: &[u8],
+    ) -> Option<usize> {
+        let len = core::cmp::min(data.len(), BUFFER_SIZE);
+        let (addr, _) = self.use_next(packed, true, |_| len as u32)?;
+        // Safety: we purposely use unsafe code to simulate the way the the device/VMM
+        // will interact with the memory. We treat the contents of the slice as data
+        // only and ensure we only pass valid addresses and lengths from the tests.
+        let buffer =
+            unsafe { alloc::slice::from_raw_parts_mut(addr.as_u64() as usize as *mut u8, len) };
+        buffer.copy_from_slice(&data[..len]);
+        Some(len)
+    }
+}
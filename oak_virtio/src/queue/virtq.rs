@@ -100,9 +100,10 @@ pub struct AvailRing<const QUEUE_SIZE: usize> {
     /// The ring-buffer containing indices of the heads of available descriptor
     /// chains.
     pub ring: [u16; QUEUE_SIZE],
-    /// Event details. Only used if VIRTIO_F_EVENT_IDX has been negotiated,
-    /// which we don't currently support. The field is only added to act as
-    /// padding for now.
+    /// The used ring index after which the driver wants to be notified. Only
+    /// used if VIRTIO_F_RING_EVENT_IDX has been negotiated.
+    ///
+    /// We implement all drivers via polling, so this is never updated.
     pub used_event: u16,
 }
 
@@ -134,9 +135,8 @@ pub struct UsedRing<const QUEUE_SIZE: usize> {
     pub idx: Wrapping<u16>,
     /// The ring-buffer containing the used elements.
     pub ring: [UsedElem; QUEUE_SIZE],
-    /// Event details. Only used if VIRTIO_F_EVENT_IDX has been negotiated,
-    /// which we don't currently support. The field is only added to act as
-    /// padding for now.
+    /// The available ring index after which the device wants to be notified.
+    /// Only used if VIRTIO_F_RING_EVENT_IDX has been negotiated.
     pub avail_event: u16,
 }
 
@@ -19,7 +19,7 @@ use core::alloc::Allocator;
 use anyhow::Context;
 use packet::Packet;
 use rust_hypervisor_firmware_virtio::{
-    device::VirtioBaseDevice,
+    device::{VirtioBaseDevice, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_PACKED},
     pci::{find_device, VirtioPciTransport},
     virtio::VirtioTransport,
 };
@@ -186,6 +186,12 @@ where
         self.device.get_status()
     }
 
+    /// Gets the context id assigned to this VM, as read from the device config
+    /// during initialization.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
     fn new<VP: Translator>(device: VirtioBaseDevice<T>, translate: VP, alloc: &'a A) -> Self {
         let tx_queue = DriverWriteOnlyQueue::new(&translate, alloc);
         let rx_queue = DeviceWriteOnlyQueue::new(&translate, alloc);
@@ -199,10 +205,25 @@ where
         translate: VP,
         inverse: PV,
     ) -> anyhow::Result<()> {
-        self.device
-            .start_init(DEVICE_ID as u32, inverse)
+        let features = self
+            .device
+            .start_init_with_features(
+                DEVICE_ID as u32,
+                inverse,
+                VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_RING_PACKED,
+            )
             .map_err(|error| anyhow::anyhow!("virtio error: {:?}", error))
             .context("couldn't initialize the PCI device")?;
+        if features & VIRTIO_F_RING_EVENT_IDX != 0 {
+            self.rx_queue.inner.enable_event_idx();
+            self.tx_queue.inner.enable_event_idx();
+            self.event_queue.inner.enable_event_idx();
+        }
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            self.rx_queue.inner.enable_packed_ring();
+            self.tx_queue.inner.enable_packed_ring();
+            self.event_queue.inner.enable_packed_ring();
+        }
         // We have to configure the event queue before the receive queue, otherwise the
         // event queue's configuration interferes with the receiver queue. This
         // seems to be related to something specific in the Linux kernel vhost
@@ -23,7 +23,7 @@ use super::{
     packet::{Packet, VSockFlags, VSockOp, HEADER_SIZE},
     VSock, DATA_BUFFER_SIZE,
 };
-use crate::{Read, Write};
+use crate::{PeerClosed, Read, Write};
 
 /// The maximum buffer size used by the socket.
 ///
@@ -44,6 +44,21 @@ const CREDIT_UPDATE_LIMIT: Wrapping<u32> = Wrapping(32 * 1024);
 /// single buffer in the queue.
 const MAX_PAYLOAD_SIZE: usize = DATA_BUFFER_SIZE - HEADER_SIZE;
 
+/// The maximum number of times we try to re-establish a connection after the
+/// host disconnected before giving up.
+const MAX_RECONNECT_ATTEMPTS: usize = 3;
+
+/// The number of times we poll the receive queue for a handshake packet during
+/// a single reconnection attempt.
+///
+/// Since we don't yet support timeouts this bounds each attempt by the number
+/// of polls rather than by time.
+const RECONNECT_POLL_LIMIT: usize = 100_000;
+
+/// The number of times we poll the receive queue for a credit update while
+/// the peer's stream buffer is full before giving up on a write.
+const CREDIT_POLL_LIMIT: usize = 100_000;
+
 /// Connector to initiate a connection to a listener on the host.
 pub struct SocketConnector<'a, T: VirtioTransport, A: Allocator> {
     /// The socket configuration.
@@ -64,29 +79,8 @@ where
     /// respone. If the connection is refused, or it receives an unexpected
     /// packet, it will return an error.
     pub fn connect(mut self) -> anyhow::Result<Socket<'a, T, A>> {
-        let mut packet =
-            Packet::new_control(self.config.local_port, self.config.host_port, VSockOp::Request)?;
-        // Set credit info.
-        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
-        packet.set_fwd_cnt(0);
-        self.config.vsock.write_packet(&mut packet);
-        let src_port = self.config.host_port;
-        let dst_port = self.config.local_port;
-        let peer_buffer_size;
-        loop {
-            if let Some(packet) = self.config.vsock.read_filtered_packet(
-                |packet| packet.get_dst_port() == dst_port && packet.get_src_port() == src_port,
-                true,
-            ) {
-                if packet.get_op()? == VSockOp::Response {
-                    peer_buffer_size = packet.get_buf_alloc();
-                    break;
-                } else {
-                    anyhow::bail!("invalid response to connection request: {}", packet.get_op()?);
-                }
-            }
-        }
-        Ok(Socket::new(self.config, peer_buffer_size))
+        let peer_buffer_size = self.config.connect_handshake(None)?;
+        Ok(Socket::new(self.config, HandshakeRole::Connector, peer_buffer_size))
     }
 }
 
@@ -110,32 +104,8 @@ where
     /// connection request. If it receives an unexpected packet (anything
     /// other than a connection request) it will return an error.
     pub fn accept(mut self) -> anyhow::Result<Socket<'a, T, A>> {
-        let dst_port = self.config.local_port;
-        let peer_buffer_size;
-        loop {
-            if let Some(packet) = self
-                .config
-                .vsock
-                .read_filtered_packet(|packet| packet.get_dst_port() == dst_port, true)
-            {
-                if packet.get_op()? == VSockOp::Request {
-                    self.config.host_port = packet.get_src_port();
-                    peer_buffer_size = packet.get_buf_alloc();
-                    break;
-                } else {
-                    anyhow::bail!("invalid connection request: {}", packet.get_op()?);
-                }
-            }
-        }
-
-        let mut packet =
-            Packet::new_control(self.config.local_port, self.config.host_port, VSockOp::Response)?;
-        // Set credit info.
-        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
-        packet.set_fwd_cnt(0);
-        self.config.vsock.write_packet(&mut packet);
-
-        Ok(Socket::new(self.config, peer_buffer_size))
+        let peer_buffer_size = self.config.accept_handshake(None)?;
+        Ok(Socket::new(self.config, HandshakeRole::Listener, peer_buffer_size))
     }
 }
 
@@ -143,6 +113,9 @@ where
 pub struct Socket<'a, T: VirtioTransport, A: Allocator> {
     /// The socket configuration.
     config: SocketConfiguration<'a, T, A>,
+    /// How the connection was established, and therefore how to re-establish
+    /// it after the host disconnects.
+    role: HandshakeRole,
     /// The current state of the connection.
     connection_state: ConnectionState,
     /// The number of payload bytes we have processed.
@@ -170,9 +143,14 @@ impl<'a, T, A: Allocator> Socket<'a, T, A>
 where
     T: VirtioTransport,
 {
-    fn new(config: SocketConfiguration<'a, T, A>, peer_buffer_size: u32) -> Self {
+    fn new(
+        config: SocketConfiguration<'a, T, A>,
+        role: HandshakeRole,
+        peer_buffer_size: u32,
+    ) -> Self {
         Self {
             config,
+            role,
             connection_state: ConnectionState::Connected,
             processed_bytes: Wrapping(0),
             previous_processed_bytes: Wrapping(0),
@@ -202,6 +180,57 @@ where
         }
     }
 
+    /// Whether the connection to the host is currently established.
+    pub fn is_connected(&self) -> bool {
+        self.connection_state == ConnectionState::Connected
+    }
+
+    /// Tries to re-establish the connection after the host disconnected.
+    ///
+    /// All state related to the previous connection, including any data that
+    /// was received but not yet read, is discarded. A listener waits for a new
+    /// connection request on the same local port, while a connector sends a
+    /// new connection request to the same host port. If the handshake does
+    /// not succeed after `MAX_RECONNECT_ATTEMPTS` attempts an error is
+    /// returned and the socket stays disconnected.
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.pending_data = None;
+        let mut last_error = None;
+        for _ in 0..MAX_RECONNECT_ATTEMPTS {
+            let result = match self.role {
+                HandshakeRole::Listener => self.config.accept_handshake(Some(RECONNECT_POLL_LIMIT)),
+                HandshakeRole::Connector => {
+                    self.config.connect_handshake(Some(RECONNECT_POLL_LIMIT))
+                }
+            };
+            match result {
+                Ok(peer_buffer_size) => {
+                    self.connection_state = ConnectionState::Connected;
+                    self.processed_bytes = Wrapping(0);
+                    self.previous_processed_bytes = Wrapping(0);
+                    self.sent_bytes = Wrapping(0);
+                    self.peer_processed_bytes = Wrapping(0);
+                    self.peer_buffer_size = Wrapping(peer_buffer_size);
+                    return Ok(());
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(anyhow::anyhow!(
+            "stream disconnected and reconnection failed after {} attempts: {:?}",
+            MAX_RECONNECT_ATTEMPTS,
+            last_error
+        ))
+    }
+
+    /// Reconnects if the host has disconnected.
+    fn ensure_connected(&mut self) -> anyhow::Result<()> {
+        if !self.is_connected() {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
     /// Whether we should send an unsolicited credit update.
     fn must_send_credit_update(&self) -> bool {
         STREAM_BUFFER_LENGTH - (self.processed_bytes - self.previous_processed_bytes)
@@ -210,18 +239,56 @@ where
 
     /// Sends a control packet with the specified op to the host.
     fn send_control_packet(&mut self, op: VSockOp) -> anyhow::Result<()> {
-        // For now we panic if we are disconnected.
-        assert!(self.connection_state == ConnectionState::Connected);
+        if !self.is_connected() {
+            anyhow::bail!("stream disconnected");
+        }
         let mut packet = Packet::new_control(self.config.local_port, self.config.host_port, op)?;
         self.set_credit_info(&mut packet);
         self.config.vsock.write_packet(&mut packet);
         Ok(())
     }
 
+    /// The number of bytes the peer has told us it can currently accept.
+    ///
+    /// The counters are free-running, so the bytes in flight are computed with
+    /// wrapping arithmetic. A peer whose buffer is smaller than that (e.g.
+    /// because it shrank it) has no space left, rather than almost 4 GiB.
+    fn peer_free_space(&self) -> usize {
+        let in_flight = self.sent_bytes - self.peer_processed_bytes;
+        self.peer_buffer_size.0.saturating_sub(in_flight.0) as usize
+    }
+
+    /// Waits until the peer can accept more data, and returns how many bytes it
+    /// can accept.
+    ///
+    /// We only learn about the peer's credit from the packets it sends us, so
+    /// any data that arrives while we wait is kept for the next read.
+    fn wait_for_credit(&mut self) -> anyhow::Result<usize> {
+        let mut requested = false;
+        for _ in 0..CREDIT_POLL_LIMIT {
+            let free = self.peer_free_space();
+            if free > 0 {
+                return Ok(free);
+            }
+            if !requested {
+                self.send_control_packet(VSockOp::CreditRequest)?;
+                requested = true;
+            }
+            if let Some(data) = self.read_data() {
+                self.pending_data.get_or_insert_with(VecDeque::new).extend(data);
+            }
+            if !self.is_connected() {
+                anyhow::bail!("stream disconnected while waiting for credit");
+            }
+        }
+        anyhow::bail!("peer's stream buffer is full")
+    }
+
     /// Sends a data packet to the host.
     fn send_data_packet(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        // For now we panic if we are disconnected.
-        assert!(self.connection_state == ConnectionState::Connected, "stream disconnected");
+        if !self.is_connected() {
+            anyhow::bail!("stream disconnected");
+        }
         let data_len = data.len();
         assert!(
             data_len <= MAX_PAYLOAD_SIZE,
@@ -230,12 +297,11 @@ where
             MAX_PAYLOAD_SIZE
         );
 
-        let data_len = Wrapping(data_len as u32);
-        if data_len > self.peer_buffer_size - (self.sent_bytes - self.peer_processed_bytes) {
+        if data_len > self.peer_free_space() {
             anyhow::bail!("peer's stream buffer is full");
         }
 
-        self.sent_bytes += data_len;
+        self.sent_bytes += Wrapping(data_len as u32);
         let mut packet = Packet::new_data(data, self.config.local_port, self.config.host_port)?;
         self.set_credit_info(&mut packet);
         self.config.vsock.write_packet(&mut packet);
@@ -251,9 +317,12 @@ where
 
     /// Reads the payload of the next available data packet, if any are
     /// available.
+    ///
+    /// Returns `None` if no data is available or the host disconnected.
     fn read_data(&mut self) -> Option<VecDeque<u8>> {
-        // For now we panic if we are disconnected.
-        assert!(self.connection_state == ConnectionState::Connected, "stream disconnected");
+        if !self.is_connected() {
+            return None;
+        }
         let src_port = self.config.host_port;
         let dst_port = self.config.local_port;
         loop {
@@ -329,10 +398,24 @@ where
     T: VirtioTransport,
 {
     fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        // If the host reset the connection and doesn't connect again, we have reached
+        // the end of the stream.
+        self.ensure_connected().map_err(|_| anyhow::Error::msg(PeerClosed))?;
         let len = data.len();
         let mut count = 0;
         while count < len {
             count += self.read_partial(&mut data[count..]).unwrap_or(0);
+            if !self.is_connected() {
+                // Bytes already read from the previous connection can't be combined with data
+                // from a new one, so a partial read fails, but we still try to recover the
+                // connection so that subsequent reads can succeed.
+                let reconnected = self.ensure_connected();
+                if count > 0 {
+                    reconnected?;
+                    anyhow::bail!("stream disconnected during read; partial data discarded");
+                }
+                reconnected.map_err(|_| anyhow::Error::msg(PeerClosed))?;
+            }
         }
 
         self.processed_bytes += Wrapping(count as u32);
@@ -344,17 +427,51 @@ where
 
         Ok(())
     }
+
+    /// The host reset or shut down the connection, and we haven't been able to
+    /// re-establish it (yet).
+    fn peer_closed(&self) -> bool {
+        !self.is_connected()
+    }
 }
 
 impl<'a, T, A: Allocator> Write for Socket<'a, T, A>
 where
     T: VirtioTransport,
 {
+    /// Sends all of `data`, waiting for credit from the peer as needed.
+    ///
+    /// We learn about disconnects from the packets the host sends us, so the
+    /// connection can be lost while we wait for credit part way through the
+    /// data. The bytes sent until then went to the old connection and are
+    /// lost; we don't send the rest on a new connection, where it would be
+    /// missing its start, but still try to reconnect so that later writes can
+    /// succeed.
     fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        // If the host reset the connection and doesn't connect again, the peer is gone.
+        self.ensure_connected().map_err(|_| anyhow::Error::msg(PeerClosed))?;
         let mut start = 0;
         let data_len = data.len();
         while start < data_len {
-            let end = core::cmp::min(data_len, start + MAX_PAYLOAD_SIZE);
+            let available = match self.wait_for_credit() {
+                Ok(available) => available,
+                Err(_) if !self.is_connected() => {
+                    let reconnected = self.ensure_connected();
+                    if start > 0 {
+                        reconnected?;
+                        anyhow::bail!(
+                            "stream disconnected during write; {} of {} bytes were lost",
+                            start,
+                            data_len
+                        );
+                    }
+                    // Nothing was sent yet, so we can start over on the new connection.
+                    reconnected.map_err(|_| anyhow::Error::msg(PeerClosed))?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let end = core::cmp::min(data_len, start + core::cmp::min(MAX_PAYLOAD_SIZE, available));
             self.send_data_packet(&data[start..end])?;
             start = end;
         }
@@ -367,6 +484,13 @@ where
         // write, and provide an actual flush implementation here.
         Ok(())
     }
+
+    /// The size of the peer's stream buffer, i.e. the most credit it can ever
+    /// give us. `write_all` copes with larger writes by waiting for credit,
+    /// but the peer can't hold more than this at once.
+    fn max_write_size(&self) -> usize {
+        self.peer_buffer_size.0 as usize
+    }
 }
 
 /// The state of the connection.
@@ -376,6 +500,15 @@ enum ConnectionState {
     Disconnected,
 }
 
+/// The side that initiated the connection handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandshakeRole {
+    /// We listened for a connection request from the host.
+    Listener,
+    /// We sent a connection request to the host.
+    Connector,
+}
+
 /// The configuration information for the socket.
 struct SocketConfiguration<'a, T: VirtioTransport, A: Allocator> {
     /// The vsock device driver.
@@ -396,6 +529,74 @@ where
     fn new(vsock: VSock<'a, T, A>, local_port: u32, host_port: u32) -> Self {
         Self { vsock, local_port, host_port }
     }
+
+    /// Reads the next packet that matches the filter.
+    ///
+    /// If `poll_limit` is `None` it waits indefinitely, otherwise it gives up
+    /// after polling the receive queue the specified number of times.
+    fn read_handshake_packet<F: Fn(&Packet) -> bool>(
+        &mut self,
+        filter: F,
+        poll_limit: Option<usize>,
+    ) -> anyhow::Result<Packet> {
+        let mut polls = 0;
+        loop {
+            if let Some(packet) = self.vsock.read_filtered_packet(&filter, true) {
+                return Ok(packet);
+            }
+            polls += 1;
+            if poll_limit.is_some_and(|limit| polls >= limit) {
+                anyhow::bail!("no handshake packet received");
+            }
+        }
+    }
+
+    /// Sends a connection request to the host and waits for the response.
+    ///
+    /// If the connection is refused, or it receives an unexpected packet, it
+    /// will return an error. Otherwise it returns the size of the peer's
+    /// stream buffer.
+    fn connect_handshake(&mut self, poll_limit: Option<usize>) -> anyhow::Result<u32> {
+        let mut packet = Packet::new_control(self.local_port, self.host_port, VSockOp::Request)?;
+        // Set credit info.
+        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
+        packet.set_fwd_cnt(0);
+        self.vsock.write_packet(&mut packet);
+        let src_port = self.host_port;
+        let dst_port = self.local_port;
+        let packet = self.read_handshake_packet(
+            |packet| packet.get_dst_port() == dst_port && packet.get_src_port() == src_port,
+            poll_limit,
+        )?;
+        if packet.get_op()? != VSockOp::Response {
+            anyhow::bail!("invalid response to connection request: {}", packet.get_op()?);
+        }
+        Ok(packet.get_buf_alloc())
+    }
+
+    /// Waits for a connection request from the host on the local port and
+    /// accepts it.
+    ///
+    /// If it receives an unexpected packet (anything other than a connection
+    /// request) it will return an error. Otherwise it returns the size of the
+    /// peer's stream buffer.
+    fn accept_handshake(&mut self, poll_limit: Option<usize>) -> anyhow::Result<u32> {
+        let dst_port = self.local_port;
+        let packet =
+            self.read_handshake_packet(|packet| packet.get_dst_port() == dst_port, poll_limit)?;
+        if packet.get_op()? != VSockOp::Request {
+            anyhow::bail!("invalid connection request: {}", packet.get_op()?);
+        }
+        self.host_port = packet.get_src_port();
+        let peer_buffer_size = packet.get_buf_alloc();
+
+        let mut packet = Packet::new_control(self.local_port, self.host_port, VSockOp::Response)?;
+        // Set credit info.
+        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
+        packet.set_fwd_cnt(0);
+        self.vsock.write_packet(&mut packet);
+        Ok(peer_buffer_size)
+    }
 }
 
 #[cfg(test)]
@@ -147,6 +147,156 @@ fn test_many_echos() {
     }
 }
 
+#[test]
+fn test_reconnect_after_reset() {
+    const NEW_HOST_PORT: u32 = 3333;
+    let (mut socket, transport) = new_socket_and_transport();
+    // The host resets the connection and then connects again from a new port.
+    let mut reset = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::Rst).unwrap();
+    set_packet_cids_host_to_guest(&mut reset);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, reset.as_slice());
+    let mut request = Packet::new_control(NEW_HOST_PORT, GUEST_PORT, VSockOp::Request).unwrap();
+    set_packet_cids_host_to_guest(&mut request);
+    request.set_buf_alloc(10000);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, request.as_slice());
+    let data = [7; 5];
+    let mut packet = Packet::new_data(&data[..], NEW_HOST_PORT, GUEST_PORT).unwrap();
+    set_packet_cids_host_to_guest(&mut packet);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, packet.as_slice());
+
+    let mut buffer = [0; 5];
+    assert!(socket.read_exact(&mut buffer).is_ok());
+    assert!(socket.is_connected());
+    assert_eq!(&data[..], &buffer[..]);
+    // The reconnection attempt must have accepted the new request.
+    let response =
+        Packet::new(transport.device_read_once_from_queue::<QUEUE_SIZE>(1).unwrap()).unwrap();
+    assert_eq!(response.get_op().unwrap(), VSockOp::Response);
+    assert_eq!(response.get_dst_port(), NEW_HOST_PORT);
+
+    // Writes go to the new connection.
+    assert!(socket.write_all(&data[..]).is_ok());
+    let output =
+        Packet::new(transport.device_read_once_from_queue::<QUEUE_SIZE>(1).unwrap()).unwrap();
+    assert_eq!(output.get_dst_port(), NEW_HOST_PORT);
+    assert_eq!(output.get_payload(), &data[..]);
+}
+
+#[test]
+fn test_reconnect_exhausted() {
+    let (mut socket, transport) = new_socket_and_transport();
+    let mut reset = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::Rst).unwrap();
+    set_packet_cids_host_to_guest(&mut reset);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, reset.as_slice());
+
+    let mut buffer = [0; 5];
+    assert!(socket.read_exact(&mut buffer).is_err());
+    assert!(!socket.is_connected());
+    assert!(socket.peer_closed());
+    // In-flight sends fail explicitly rather than panicking, and report that the
+    // peer is gone.
+    assert!(socket.write_all(&buffer[..]).unwrap_err().is::<PeerClosed>());
+    assert!(transport.device_read_once_from_queue::<QUEUE_SIZE>(1).is_none());
+}
+
+#[test]
+fn test_reset_is_end_of_stream() {
+    let (mut socket, transport) = new_socket_and_transport();
+    let mut reset = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::Rst).unwrap();
+    set_packet_cids_host_to_guest(&mut reset);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, reset.as_slice());
+
+    let mut buffer = [0; 5];
+    assert!(socket.read_exact(&mut buffer).unwrap_err().is::<PeerClosed>());
+    // Later reads keep reporting the end of the stream while the host stays away.
+    assert!(socket.read_exact(&mut buffer).unwrap_err().is::<PeerClosed>());
+}
+
+#[test]
+fn test_reset_during_read_is_not_end_of_stream() {
+    let (mut socket, transport) = new_socket_and_transport();
+    let data = [9; 3];
+    let mut packet = Packet::new_data(&data[..], HOST_PORT, GUEST_PORT).unwrap();
+    set_packet_cids_host_to_guest(&mut packet);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, packet.as_slice());
+    let mut reset = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::Rst).unwrap();
+    set_packet_cids_host_to_guest(&mut reset);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, reset.as_slice());
+
+    // Only part of the data arrived before the reset, so this is an error rather
+    // than a clean close.
+    let mut buffer = [0; 5];
+    let err = socket.read_exact(&mut buffer).unwrap_err();
+    assert!(!err.is::<PeerClosed>());
+    assert!(socket.peer_closed());
+}
+
+#[test]
+fn test_write_all_waits_for_credit() {
+    // The peer reported a buffer of 10000 bytes during the handshake, so the last
+    // 2000 bytes have to wait until it has processed some of the data.
+    let data = [7; 12000];
+    let (mut socket, transport) = new_socket_and_transport();
+    let mut update = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::CreditUpdate).unwrap();
+    set_packet_cids_host_to_guest(&mut update);
+    update.set_buf_alloc(10000);
+    update.set_fwd_cnt(6000);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, update.as_slice());
+    // The limit is the size of the peer's buffer, not the credit that's left.
+    assert_eq!(socket.max_write_size(), 10000);
+    assert!(socket.write_all(&data[..]).is_ok());
+
+    let mut sent = 0;
+    let mut credit_requests = 0;
+    while let Some(buffer) = transport.device_read_once_from_queue::<QUEUE_SIZE>(1) {
+        let packet = Packet::new(buffer).unwrap();
+        match packet.get_op().unwrap() {
+            VSockOp::Rw => {
+                // We never send more than the peer has room for.
+                assert!(credit_requests > 0 || sent + packet.get_payload().len() <= 10000);
+                sent += packet.get_payload().len();
+            }
+            VSockOp::CreditRequest => {
+                assert_eq!(sent, 10000);
+                credit_requests += 1;
+            }
+            op => panic!("unexpected packet: {:?}", op),
+        }
+    }
+    assert_eq!(sent, data.len());
+    assert_eq!(credit_requests, 1);
+}
+
+#[test]
+fn test_reset_during_write_is_not_end_of_stream() {
+    let (mut socket, transport) = new_socket_and_transport();
+    // We only see the reset once the peer's buffer is full and we wait for credit.
+    let mut reset = Packet::new_control(HOST_PORT, GUEST_PORT, VSockOp::Rst).unwrap();
+    set_packet_cids_host_to_guest(&mut reset);
+    transport.device_write_to_queue::<QUEUE_SIZE>(0, reset.as_slice());
+
+    let err = socket.write_all(&[7; 12000]).unwrap_err();
+    assert!(!err.is::<PeerClosed>());
+    assert!(socket.peer_closed());
+}
+
+#[test]
+fn test_peer_free_space_saturates() {
+    let (mut socket, _transport) = new_socket_and_transport();
+    // The peer shrank its buffer below the data that is still in flight.
+    socket.sent_bytes = Wrapping(6000);
+    socket.peer_buffer_size = Wrapping(4000);
+    assert_eq!(socket.peer_free_space(), 0);
+    assert!(socket.send_data_packet(&[7; 1]).is_err());
+}
+
+#[test]
+fn test_write_all_without_credit() {
+    let (mut socket, _transport) = new_socket_and_transport();
+    // The peer never reports that it has processed any of the data.
+    assert!(socket.write_all(&[7; 10001]).is_err());
+}
+
 fn set_packet_cids_host_to_guest(packet: &mut Packet) {
     packet.set_dst_cid(GUEST_CID);
     packet.set_src_cid(HOST_CID);
@@ -56,6 +56,7 @@ fn test_device_init() {
     assert!(status.contains(DeviceStatus::VIRTIO_STATUS_FEATURES_OK));
     assert!(!status.contains(DeviceStatus::VIRTIO_STATUS_FAILED));
     assert_eq!(vsock.guest_cid, GUEST_CID);
+    assert_eq!(vsock.guest_cid(), GUEST_CID);
 
     let queues = &config.queues;
     assert_eq!(queues.len(), 3);
@@ -27,6 +27,14 @@ use crate::{
 // See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-4100006>.
 const VIRTIO_F_VERSION_1: u64 = 1 << 32;
 
+// Feature bit for the used_event and avail_event notification suppression fields.
+// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-4100006>.
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
+
+// Feature bit for the packed virtqueue layout.
+// See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-4100006>.
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
 // Status fields.
 // See <https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-100001>.
 const VIRTIO_STATUS_RESET: u32 = 0;
@@ -59,6 +67,19 @@ where
         device_type: u32,
         translate: X,
     ) -> Result<(), VirtioError> {
+        self.start_init_with_features(device_type, translate, 0).map(|_| ())
+    }
+
+    /// Start Initialising the device, additionally accepting any of `optional_features` that
+    /// the device offers.
+    ///
+    /// Returns the negotiated features.
+    pub fn start_init_with_features<X: InverseTranslator>(
+        &mut self,
+        device_type: u32,
+        translate: X,
+        optional_features: u64,
+    ) -> Result<u64, VirtioError> {
         // Initialise the transport.
         self.transport.init(device_type, translate)?;
 
@@ -79,19 +100,19 @@ where
             return Err(VirtioError::LegacyOnly);
         }
 
-        // Don't support any advanced features for now.
-        let supported_features = VIRTIO_F_VERSION_1;
+        // Only accept the advanced features the driver asked for.
+        let supported_features = VIRTIO_F_VERSION_1 | optional_features;
+        let features = device_features & supported_features;
 
         // Report driver features.
-        self.transport
-            .set_features(device_features & supported_features);
+        self.transport.set_features(features);
 
         self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
         if self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK != VIRTIO_STATUS_FEATURES_OK {
             self.transport.add_status(VIRTIO_STATUS_FAILED);
             return Err(VirtioError::FeatureNegotiationFailed);
         }
-        Ok(())
+        Ok(features)
     }
 
     /// Sets the configuration for a queue and enables it.